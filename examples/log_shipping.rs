@@ -0,0 +1,20 @@
+use diskomap::Cfg;
+use diskomap::log_shipper::{DirLogShipper, LogShipping};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let file_name = "db/db.txt";
+
+    // Each segment of the history file will be written to the next numbered file in "db/shipped".
+    // Instead of DirLogShipper can be used own implementation of LogShipper for uploading to object storage.
+    let mut shipping = LogShipping::new(DirLogShipper::new("db/shipped")?);
+    shipping.overflow_callback = Some(Box::new(|dropped_bytes| {
+        eprintln!("Dropped from shipping {} bytes", dropped_bytes);
+    }));
+
+    let mut cfg = Cfg::default();
+    cfg.log_shipping = Some(shipping);
+    let mut map = diskomap::BTreeMap::open_or_create(file_name, cfg)?;
+    map.insert(8, "Dasha".to_string())?;
+
+    Ok(())
+}
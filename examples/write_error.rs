@@ -4,9 +4,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let file_name = "db/db.txt";
 
     let mut cfg = Cfg::default();
-    cfg.write_error_callback = Some(Box::new(|err| {
+    cfg.write_error_context_callback = Some(Box::new(|context| {
         // This closure will be called on the background thread if there is an error writing to the file.
-        dbg!(err);
+        eprintln!("Write to file {:?} error: {}, operation: {:?}, bytes: {}", context.file_path, context.error, context.operation, context.bytes);
         std::process::exit(3);
     }));
     let mut map = diskomap::BTreeMap::open_or_create(file_name, cfg)?;
@@ -1,10 +1,18 @@
-use crate::format::{MapOperation, blockchain_sha1, blockchain_sha256, IntegrityError};
+use crate::format::{ItemOperation, LoadedOperation, MapOperation, MetaOperation, RawMeta, SetOperation, blockchain_sha1, blockchain_sha256, blockchain_hmac_sha256, IntegrityError, LoadedTail, TransactionBuffer, TransactionMarker, check_load_cancel, catch_callback_panic, with_record_meta, LoadLimits, LoadStats, RecordExtent, RecordKind, LoadRecovery, Recovered, RecoveredRecords, recover_record};
+#[cfg(feature = "blake3")]
+use crate::format::blockchain_blake3;
 use crate::map_trait::MapTrait;
 use serde::de::DeserializeOwned;
 use crate::{LoadFileError, Integrity};
-use std::io::{BufReader, Read};
+use crate::cfg::{version_for_migration, IntegrityKind, MigrationError, RawValue, ValueMigrator};
+use crate::replay_check::ReplayChecker;
+use crate::value_compression::{bin_value_data, decompress};
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader, Read};
 use serde::Serialize;
 use crc::crc32;
+use crypto::util::fixed_time_eq;
+use xxhash_rust::xxh64::xxh64;
 
 /// 1 byte for block length if right 2 bits of first byte of block is 0b00.
 const U8_LEN: u8 = 0b00;
@@ -19,6 +27,34 @@ const U64_LEN: u8 = 0b11;
 const INSERT: u8 = 0;
 /// Code of remove from map operation.
 const REMOVE: u8 = 1;
+/// Code of insert to map operation with version of schema of the value in 4 bytes little endian after code.
+const INSERT_VERSIONED: u8 = 2;
+/// Code of begin of transaction.
+const TRANSACTION_BEGIN: u8 = 3;
+/// Code of end of transaction.
+const TRANSACTION_END: u8 = 4;
+/// Code of abort of incomplete transaction.
+const TRANSACTION_ABORT: u8 = 5;
+/// Code of batch of operations applied atomically, followed by count of operations in 4 bytes little endian and
+/// data of each operation (same as data of insert or remove block) with its length in 4 bytes little endian before.
+const BATCH: u8 = 6;
+/// Code of push of items to collection value of 'MultiMap', followed by key and items.
+const PUSH_ITEMS: u8 = 7;
+/// Code of remove of items from collection value of 'MultiMap', followed by key and items.
+const REMOVE_ITEMS: u8 = 8;
+/// Code of add of element to set, followed by element.
+const SET_ADD: u8 = 9;
+/// Code of delete of element from set, followed by element.
+const SET_DELETE: u8 = 10;
+/// Code of increment of counter value, followed by key and delta.
+const INCREMENT: u8 = 11;
+/// Code of metadata of record, followed by length of metadata in 4 bytes little endian, metadata
+/// and data of insert or remove block.
+const RECORD_META: u8 = 12;
+/// Code of schema fingerprint, followed by UTF-8 bytes of fingerprint.
+const SCHEMA: u8 = 13;
+/// Flag of insert operation code, value after key is compressed by zstd.
+const COMPRESSED_VALUE: u8 = 0x80;
 
 /// Make data block with insert operation for write to file.
 pub fn bin_file_block_of_insert<Key, Value>(key: &Key, value: Value, integrity: &mut Option<Integrity>)
@@ -27,28 +63,173 @@ where
     Key: Serialize,
     Value: Serialize
 {
-    let key_val_bin_data = bincode2::serialize(&(&key, &value))?;
-    let mut data = vec![INSERT];
-    data.extend_from_slice(&key_val_bin_data);
-    post_process_file_bin_block(&mut data, integrity);
-    let mut res = bin_block_len(data.len());
-    res.extend_from_slice(&data);
-    Ok(res)
+    bin_file_block_of_versioned_insert(key, value, None, integrity)
+}
+
+/// Make data block with insert operation for write to file.
+/// If 'value_schema_version' is set, then it's written after operation code.
+pub fn bin_file_block_of_versioned_insert<Key, Value>(key: &Key, value: Value, value_schema_version: Option<u32>, integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    bin_file_block_of_compressible_insert(key, value, value_schema_version, None, integrity)
+}
+
+/// Same as 'bin_file_block_of_versioned_insert' but value longer than 'compress_values_over' is compressed.
+pub(crate) fn bin_file_block_of_compressible_insert<Key, Value>(key: &Key, value: Value, value_schema_version: Option<u32>, compress_values_over: Option<usize>, integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let data = bin_block_data_of_insert(key, value, value_schema_version, compress_values_over)?;
+    Ok(finish_bin_block(data, integrity))
 }
 
 /// Make data block with remove operation for write to file.
 pub fn bin_file_block_of_remove<Key>(key: &Key, integrity: &mut Option<Integrity>)
     -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize
+{
+    let data = bin_block_data_of_remove(key)?;
+    Ok(finish_bin_block(data, integrity))
+}
+
+/// Make data block with transaction marker for write to file.
+pub(crate) fn bin_file_block_of_transaction_marker(marker: TransactionMarker, integrity: &mut Option<Integrity>) -> Vec<u8> {
+    let code = match marker {
+        TransactionMarker::Begin => TRANSACTION_BEGIN,
+        TransactionMarker::End => TRANSACTION_END,
+        TransactionMarker::Abort => TRANSACTION_ABORT,
+    };
+    finish_bin_block(vec![code], integrity)
+}
+
+/// Make data block with schema fingerprint for write to file.
+pub(crate) fn bin_file_block_of_schema(schema_fingerprint: &str, integrity: &mut Option<Integrity>) -> Vec<u8> {
+    let mut block_data = vec![SCHEMA];
+    block_data.extend_from_slice(schema_fingerprint.as_bytes());
+    finish_bin_block(block_data, integrity)
+}
+
+/// Make data block with batch of operations for write to file.
+pub(crate) fn bin_file_block_of_batch<Key, Value>(operations: &[MapOperation<Key, Value>], value_schema_version: Option<u32>, compress_values_over: Option<usize>, integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let mut data = vec![BATCH];
+    data.extend_from_slice(&(operations.len() as u32).to_le_bytes());
+    for map_operation in operations {
+        let operation_data = match map_operation {
+            MapOperation::Insert(key, value) => bin_block_data_of_insert(key, value, value_schema_version, compress_values_over)?,
+            MapOperation::Remove(key) => bin_block_data_of_remove(key)?,
+        };
+        data.extend_from_slice(&(operation_data.len() as u32).to_le_bytes());
+        data.extend_from_slice(&operation_data);
+    }
+    Ok(finish_bin_block(data, integrity))
+}
+
+/// Make data block with operation with items of collection value for write to file.
+pub(crate) fn bin_file_block_of_item_operation<Key, Item>(item_operation: ItemOperation, key: &Key, items: &[Item], integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize,
+    Item: Serialize
+{
+    let mut data = match item_operation {
+        ItemOperation::Push => vec![PUSH_ITEMS],
+        ItemOperation::Remove => vec![REMOVE_ITEMS],
+    };
+    data.extend_from_slice(&bincode2::serialize(&(key, items))?);
+    Ok(finish_bin_block(data, integrity))
+}
+
+/// Make data block with operation with element of set for write to file.
+pub(crate) fn bin_file_block_of_set_operation<Key>(set_operation: SetOperation, key: &Key, integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize
+{
+    let mut data = match set_operation {
+        SetOperation::Add => vec![SET_ADD],
+        SetOperation::Delete => vec![SET_DELETE],
+    };
+    data.extend_from_slice(&bincode2::serialize(key)?);
+    Ok(finish_bin_block(data, integrity))
+}
+
+/// Make data block with increment of counter value for write to file.
+pub(crate) fn bin_file_block_of_increment<Key>(key: &Key, delta: i64, integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize
+{
+    let mut data = vec![INCREMENT];
+    data.extend_from_slice(&bincode2::serialize(&(key, delta))?);
+    Ok(finish_bin_block(data, integrity))
+}
+
+/// Make data block with metadata of record for write to file, 'data' is data of insert or remove block.
+pub(crate) fn bin_file_block_with_meta<Meta>(meta: &Meta, data: &[u8], integrity: &mut Option<Integrity>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Meta: Serialize
+{
+    let meta_bin_data = bincode2::serialize(meta)?;
+    let mut block_data = vec![RECORD_META];
+    block_data.extend_from_slice(&(meta_bin_data.len() as u32).to_le_bytes());
+    block_data.extend_from_slice(&meta_bin_data);
+    block_data.extend_from_slice(data);
+    Ok(finish_bin_block(block_data, integrity))
+}
+
+/// Make data of block with insert operation without integrity and block length.
+/// Value longer than 'compress_values_over' is compressed and operation code is flagged.
+pub(crate) fn bin_block_data_of_insert<Key, Value>(key: &Key, value: Value, value_schema_version: Option<u32>, compress_values_over: Option<usize>)
+    -> Result<Vec<u8>, bincode2::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let key_bin_data = bincode2::serialize(&key)?;
+    let (value_bin_data, compressed) = bin_value_data(&value, compress_values_over)?;
+    let flag = if compressed { COMPRESSED_VALUE } else { 0 };
+    let mut data = match value_schema_version {
+        Some(version) => {
+            let mut data = vec![INSERT_VERSIONED | flag];
+            data.extend_from_slice(&version.to_le_bytes());
+            data
+        },
+        None => vec![INSERT | flag],
+    };
+    data.extend_from_slice(&key_bin_data);
+    data.extend_from_slice(&value_bin_data);
+    Ok(data)
+}
+
+/// Make data of block with remove operation without integrity and block length.
+pub(crate) fn bin_block_data_of_remove<Key>(key: &Key) -> Result<Vec<u8>, bincode2::Error>
 where
     Key: Serialize
 {
     let key_bin_data = bincode2::serialize(&key)?;
     let mut data = vec![REMOVE];
     data.extend_from_slice(&key_bin_data);
+    Ok(data)
+}
+
+/// Add integrity and block length to the data of block.
+pub(crate) fn finish_bin_block(mut data: Vec<u8>, integrity: &mut Option<Integrity>) -> Vec<u8> {
     post_process_file_bin_block(&mut data, integrity);
     let mut res = bin_block_len(data.len());
     res.extend_from_slice(&data);
-    Ok(res)
+    res
 }
 
 /// Load from binary format file all operations and make actual map.
@@ -61,7 +242,7 @@ pub fn map_from_bin_file<Map, Key, Value, ReadCallback, Reader>(
         Key: std::cmp::Ord + DeserializeOwned,
         Value: DeserializeOwned,
         Map: MapTrait<Key, Value> + Default,
-        ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>>,
+        ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
         Reader: std::io::Read,
 {
     let mut map = Map::default();
@@ -81,60 +262,532 @@ pub fn map_from_bin_file<Map, Key, Value, ReadCallback, Reader>(
 pub fn load_from_bin_file<Key, Value, ReadCallback, ProcessedCallback, Reader>(
     file: &mut Reader,
     integrity: &mut Option<Integrity>,
-    mut after_read_callback: Option<ReadCallback>,
-    mut processed_callback: ProcessedCallback
+    after_read_callback: Option<ReadCallback>,
+    processed_callback: ProcessedCallback
     ) -> Result<(), LoadFileError>
 where
     Key: DeserializeOwned,
     Value: DeserializeOwned,
     ProcessedCallback: FnMut(MapOperation<Key, Value>) -> Result<(), ()>,
-    ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>>,
+    ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
     Reader: std::io::Read,
 {
-    let mut reader = BufReader::new(file);
-    let mut block_num = 1;
+    load_from_bin_file_migrating(file, integrity, after_read_callback, None, None, processed_callback)
+}
+
+/// Same as 'load_from_bin_file' but values of records with version older than 'value_schema_version'
+/// are converted by 'value_migrator' before deserialization.
+pub fn load_from_bin_file_migrating<Key, Value, ReadCallback, ProcessedCallback, Reader>(
+    file: &mut Reader,
+    integrity: &mut Option<Integrity>,
+    after_read_callback: Option<ReadCallback>,
+    value_schema_version: Option<u32>,
+    value_migrator: Option<&mut ValueMigrator>,
+    processed_callback: ProcessedCallback
+    ) -> Result<(), LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+    ProcessedCallback: FnMut(MapOperation<Key, Value>) -> Result<(), ()>,
+    ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
+    Reader: std::io::Read,
+{
+    load_bin_file_records(file, integrity, after_read_callback, value_schema_version, value_migrator, LoadLimits::default(), processed_callback)?;
+    Ok(())
+}
+
+/// Same as 'load_from_bin_file' but metadata of records written by 'MapWithFile::insert_with_meta'
+/// and 'MapWithFile::remove_with_meta' is passed to 'processed_callback', None for records without metadata.
+pub fn load_from_bin_file_with_meta<Key, Value, Meta, ReadCallback, ProcessedCallback, Reader>(
+    file: &mut Reader,
+    integrity: &mut Option<Integrity>,
+    after_read_callback: Option<ReadCallback>,
+    mut processed_callback: ProcessedCallback
+    ) -> Result<(), LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+    Meta: DeserializeOwned,
+    ProcessedCallback: FnMut(MapOperation<Key, Value>, Option<Meta>) -> Result<(), ()>,
+    ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
+    Reader: std::io::Read,
+{
+    load_bin_file_records(file, integrity, after_read_callback, None, None, LoadLimits::default(), |operation: MetaOperation<Key, Value, Meta>| {
+        processed_callback(operation.map_operation, operation.meta)
+    })?;
+    Ok(())
+}
+
+/// Same as 'load_from_bin_file_migrating' but returns information about incomplete transaction at the end of the file.
+/// Records of transaction are passed to 'processed_callback' only after end marker of transaction.
+/// Loading stops with 'LoadFileError::Cancelled' when cancel flag of 'limits' is set
+/// and with 'LoadFileError::RecordTooLarge' on record longer than max record size of 'limits'.
+/// Item blocks (codes 7, 8) and set blocks (codes 9, 10) are passed only if 'Op' supports them,
+/// otherwise it's 'LoadFileError::UnexpectedItemOperation' or 'LoadFileError::UnexpectedSetOperation',
+/// same for increment blocks (code 11) and 'LoadFileError::UnexpectedIncrementOperation'.
+/// Schema block (code 13) is checked by 'limits' and not passed.
+pub(crate) fn load_bin_file_records<Key, Value, Op, ReadCallback, ProcessedCallback, Reader>(
+    file: &mut Reader,
+    integrity: &mut Option<Integrity>,
+    mut after_read_callback: Option<ReadCallback>,
+    value_schema_version: Option<u32>,
+    mut value_migrator: Option<&mut ValueMigrator>,
+    mut limits: LoadLimits,
+    mut processed_callback: ProcessedCallback
+    ) -> Result<LoadedTail, LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+    Op: LoadedOperation<Key, Value>,
+    ProcessedCallback: FnMut(Op) -> Result<(), ()>,
+    ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
+    Reader: std::io::Read,
+{
+    let mut reader = BinRecordReader::new(file);
+    let mut transaction = TransactionBuffer::new();
+    let mut replay = limits.replay_check.map(ReplayChecker::new);
+    let mut load_stats = LoadStats::default();
+    let mut recovered = RecoveredRecords::default();
+    let mut recovery = LoadRecovery::new(&limits, integrity);
     loop {
-        let block_len = read_bin_block_len(&mut reader)?;
-        if block_len == 0 {
-            return Ok(())
+        let (record_num, record_offset) = reader.position();
+        // record is loaded by closure for recovery from its error by 'LoadLimits::recovery'
+        let loaded = (|| -> Result<bool, LoadFileError> {
+            let record = match reader.next_record(integrity, &mut after_read_callback, &limits)? {
+                Some(record) => record,
+                None => return Ok(false),
+            };
+            let BinRecord { extent, integrity_before_marker, meta, data: data_block, integrity_len } = record;
+            let block_num = extent.num;
+            limits.set_record_end(&extent);
+
+            if let Some(kind) = bin_record_kind(data_block) {
+                let key = || limits.render_bin_key.and_then(|render| render(data_block));
+                load_stats.add(&extent, kind, integrity_len, key);
+            }
+
+            if let Some(replay) = &mut replay {
+                replay_bin_record::<Key>(replay, data_block, meta.is_some(), block_num)?;
+            }
+
+            match data_block[0] {
+                TRANSACTION_BEGIN => transaction.marker(TransactionMarker::Begin, &integrity_before_marker, &mut processed_callback)?,
+                TRANSACTION_END => transaction.marker(TransactionMarker::End, &integrity_before_marker, &mut processed_callback)?,
+                TRANSACTION_ABORT => transaction.marker(TransactionMarker::Abort, &integrity_before_marker, &mut processed_callback)?,
+                BATCH => {
+                    // all operations are deserialized before applying
+                    let mut operations = Vec::new();
+                    for data in bin_batch_operations_data(data_block).ok_or(LoadFileError::WrongBatch { line_num: block_num })? {
+                        let map_operation = bin_operation(data, block_num, value_schema_version, &mut value_migrator)?
+                            .ok_or(LoadFileError::WrongBatch { line_num: block_num })?;
+                        operations.push(map_operation);
+                    }
+                    for map_operation in operations {
+                        transaction.push(Op::from_map_operation(map_operation), &mut processed_callback)?;
+                    }
+                },
+                PUSH_ITEMS | REMOVE_ITEMS => {
+                    let item_operation = if data_block[0] == PUSH_ITEMS { ItemOperation::Push } else { ItemOperation::Remove };
+                    let (key, items) = bincode2::deserialize(&data_block[1..]).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+                    let operation = Op::from_item_operation(item_operation, key, items).ok_or(LoadFileError::UnexpectedItemOperation { line_num: block_num })?;
+                    transaction.push(operation, &mut processed_callback)?;
+                },
+                INCREMENT => {
+                    let (key, delta) = bincode2::deserialize(&data_block[1..]).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+                    let operation = Op::from_increment(key, delta).ok_or(LoadFileError::UnexpectedIncrementOperation { line_num: block_num })?;
+                    transaction.push(operation, &mut processed_callback)?;
+                },
+                SET_ADD | SET_DELETE => {
+                    let set_operation = if data_block[0] == SET_ADD { SetOperation::Add } else { SetOperation::Delete };
+                    let key = bincode2::deserialize(&data_block[1..]).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+                    let operation = Op::from_set_operation(set_operation, key).ok_or(LoadFileError::UnexpectedSetOperation { line_num: block_num })?;
+                    transaction.push(operation, &mut processed_callback)?;
+                },
+                SCHEMA => limits.check_schema(&String::from_utf8_lossy(&data_block[1..]))?,
+                _ => {
+                    let insert_key = || match data_block[0] & !COMPRESSED_VALUE {
+                        INSERT | INSERT_VERSIONED => bincode2::deserialize_from(bin_insert_data(data_block).ok()?).ok(),
+                        _ => None,
+                    };
+                    let map_operation = match bin_operation(data_block, block_num, value_schema_version, &mut value_migrator) {
+                        Ok(map_operation) => map_operation,
+                        Err(err) => recover_record::<Key, Value, Op>(Err(err), limits.deserialize_policy, insert_key, &mut recovered)?,
+                    };
+                    if let Some(map_operation) = map_operation {
+                        transaction.push(with_record_meta(Op::from_map_operation(map_operation), meta, block_num)?, &mut processed_callback)?;
+                    }
+                },
+            }
+
+            transaction.record_end(extent.end());
+            Ok(true)
+        })();
+
+        match loaded {
+            Ok(true) => recovery.record_end(integrity),
+            Ok(false) => break,
+            Err(err) => {
+                let at_end = reader.at_end();
+                let recovered = recovery.recover(err, at_end, record_num, record_offset, integrity)
+                    .map_err(|err| limits.record_error(err, record_num, record_offset))?;
+                match recovered {
+                    Recovered::Skipped { resync_chain } => {
+                        if resync_chain {
+                            reader.resync_chain(integrity);
+                        }
+                        recovery.record_skipped(integrity);
+                    },
+                    Recovered::Truncated => break,
+                }
+            },
         }
 
-        let mut data_block = vec![0; block_len];
-        reader.read_exact(&mut data_block[..])?;
+        limits.report_progress(reader.position(), false)?;
+    }
+    limits.report_progress(reader.position(), true)?;
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(records = reader.block_num - 1, "binary history file loaded");
+
+    let mut loaded_tail = transaction.finish();
+    loaded_tail.replay_anomalies = replay.map(ReplayChecker::into_anomalies).unwrap_or_default();
+    loaded_tail.load_stats = load_stats;
+    loaded_tail.recovered = recovered;
+    recovery.finish(&mut loaded_tail);
+    Ok(loaded_tail)
+}
+
+/// Check block by shadow state of the map for 'Cfg::strict_replay', keys and values are compared as bincode data.
+fn replay_bin_record<Key>(replay: &mut ReplayChecker, data_block: &[u8], has_meta: bool, block_num: usize) -> Result<(), LoadFileError>
+where
+    Key: DeserializeOwned,
+{
+    match data_block[0] & !COMPRESSED_VALUE {
+        TRANSACTION_BEGIN => replay.marker(TransactionMarker::Begin, block_num)?,
+        TRANSACTION_END => replay.marker(TransactionMarker::End, block_num)?,
+        TRANSACTION_ABORT => replay.marker(TransactionMarker::Abort, block_num)?,
+        INSERT | INSERT_VERSIONED => {
+            let (key, value) = split_bin_key::<Key>(bin_insert_data(data_block)?, block_num)?;
+            if has_meta {
+                replay.apply(key, Some(Some(value.to_vec())));
+            } else {
+                replay.insert(key, value.to_vec(), block_num)?;
+            }
+        },
+        REMOVE => replay.remove(split_bin_key::<Key>(&data_block[1..], block_num)?.0, block_num)?,
+        PUSH_ITEMS | REMOVE_ITEMS | SET_ADD | INCREMENT => replay.apply(split_bin_key::<Key>(&data_block[1..], block_num)?.0, Some(None)),
+        SET_DELETE => replay.apply(split_bin_key::<Key>(&data_block[1..], block_num)?.0, None),
+        BATCH => {
+            for data in bin_batch_operations_data(data_block).ok_or(LoadFileError::WrongBatch { line_num: block_num })? {
+                match data[0] & !COMPRESSED_VALUE {
+                    INSERT | INSERT_VERSIONED => {
+                        let (key, value) = split_bin_key::<Key>(bin_insert_data(data)?, block_num)?;
+                        replay.apply(key, Some(Some(value.to_vec())));
+                    },
+                    REMOVE => replay.apply(split_bin_key::<Key>(&data[1..], block_num)?.0, None),
+                    _ => return Err(LoadFileError::WrongBatch { line_num: block_num }),
+                }
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+/// Returns data of insert block after code and version of schema of the value.
+fn bin_insert_data(data_block: &[u8]) -> Result<&[u8], LoadFileError> {
+    if data_block[0] & !COMPRESSED_VALUE == INSERT_VERSIONED {
+        data_block.get(5..).ok_or(LoadFileError::WrongMinBinBlockLen)
+    } else {
+        Ok(&data_block[1..])
+    }
+}
 
-        if let Some(callback) = &mut after_read_callback {
-            callback(&mut data_block)
-               .map_err(|err| LoadFileError::InterruptedWithBeforeReadCallback(err))?;
+/// Returns serialized key from the start of data and data after the key.
+fn split_bin_key<Key>(data: &[u8], block_num: usize) -> Result<(Vec<u8>, &[u8]), LoadFileError>
+where
+    Key: DeserializeOwned,
+{
+    // length of serialized key is known only after deserialization
+    let mut rest = data;
+    bincode2::deserialize_from::<_, Key>(&mut rest).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+    Ok((data[..data.len() - rest.len()].to_vec(), rest))
+}
+
+/// Block of binary file read by 'BinRecordReader'.
+pub(crate) struct BinRecord<'a> {
+    /// Position of the block with its length in the file.
+    pub extent: RecordExtent,
+    /// Integrity state before the block if it's transaction marker, needed if transaction is incomplete.
+    pub integrity_before_marker: Option<Integrity>,
+    /// Metadata of the record if block has it.
+    pub meta: Option<RawMeta<'a>>,
+    /// Data of the block without integrity and metadata, not empty.
+    pub data: &'a [u8],
+    /// Length of integrity of the block.
+    pub integrity_len: u64,
+}
+
+/// Reads blocks of binary file one by one with checks of integrity, size and cancel flag.
+/// Used by all loading functions of the binary format.
+pub(crate) struct BinRecordReader<Reader> {
+    /// Buffered file with count of read bytes.
+    reader: CountingReader<BufReader<Reader>>,
+    /// Buffer of the current block.
+    data_block: Vec<u8>,
+    /// Number of the next block, starts from 1.
+    block_num: usize,
+}
+
+impl<Reader: std::io::Read> BinRecordReader<Reader> {
+    /// Reader from the start of the file.
+    pub fn new(file: Reader) -> Self {
+        BinRecordReader { reader: CountingReader { reader: BufReader::new(file), read_len: 0 }, data_block: Vec::new(), block_num: 1 }
+    }
+
+    /// Returns the next block or None at the end of file.
+    pub fn next_record<ReadCallback>(&mut self, integrity: &mut Option<Integrity>, after_read_callback: &mut Option<ReadCallback>, limits: &LoadLimits)
+        -> Result<Option<BinRecord<'_>>, LoadFileError>
+    where
+        ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
+    {
+        let (extent, data_block) = match self.next_block(after_read_callback, limits)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let block_num = extent.num;
+
+        // integrity state before transaction begin is needed if transaction is incomplete
+        let integrity_before_marker = if data_block[0] == TRANSACTION_BEGIN { integrity.clone() } else { None };
+
+        // checksum of other kind at the end of the first block is mismatch of integrity configuration
+        if block_num == 1 {
+            if let Some(found) = bin_block_integrity(data_block).filter(|found| integrity.as_ref().map(Integrity::kind) != Some(*found)) {
+                return Err(IntegrityError::FileHasUnexpectedHashSuffix { line_num: block_num, found }.into());
+            }
         }
 
+        let data_block_len = data_block.len();
         let data_block = if let Some(integrity) = integrity {
-            process_block_integrity(&mut data_block, integrity, block_num)?
+            process_block_integrity(data_block, integrity, block_num)?
         } else {
             &data_block[..]
         };
 
-        match data_block[0] {
-            INSERT => {
-                let (key, val) = bincode2::deserialize(&data_block[1..]).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
-                processed_callback(MapOperation::Insert(key, val)).map_err(|()| LoadFileError::Interrupted)?;
-            }
-            REMOVE => {
-                let key = bincode2::deserialize(&data_block[1..]).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
-                processed_callback(MapOperation::Remove(key)).map_err(|()| LoadFileError::Interrupted)?;
+        let integrity_len = (data_block_len - data_block.len()) as u64;
+        let (meta, data) = split_bin_record_meta(data_block)?;
+        Ok(Some(BinRecord { extent, integrity_before_marker, meta, data, integrity_len }))
+    }
+
+    /// Returns the next block with integrity without check of integrity, or None at the end of file.
+    pub fn next_block<ReadCallback>(&mut self, after_read_callback: &mut Option<ReadCallback>, limits: &LoadLimits)
+        -> Result<Option<(RecordExtent, &mut [u8])>, LoadFileError>
+    where
+        ReadCallback: FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>,
+    {
+        let block_num = self.block_num;
+        check_load_cancel(limits.cancel.as_deref(), block_num)?;
+
+        let offset = self.reader.read_len;
+        let block_len = read_bin_block_len(&mut self.reader)?;
+        if block_len == 0 {
+            return Ok(None);
+        }
+        self.block_num += 1;
+
+        // checked before reading of the block
+        limits.check_size(bin_block_len(block_len).len() + block_len, block_num)?;
+
+        self.data_block.clear();
+        self.data_block.resize(block_len, 0);
+        self.reader.read_exact(&mut self.data_block[..])?;
+        let extent = RecordExtent { num: block_num, offset, len: self.reader.read_len - offset };
+
+        if let Some(callback) = after_read_callback {
+            catch_callback_panic(|| callback(&mut self.data_block))
+                .ok_or(LoadFileError::CallbackPanicked { line_num: block_num })?
+                .map_err(|err| LoadFileError::InterruptedWithBeforeReadCallback(err))?;
+        }
+
+        Ok(Some((extent, &mut self.data_block[..])))
+    }
+
+    /// Returns true if all bytes of the file are read, false on error of reading.
+    pub fn at_end(&mut self) -> bool {
+        self.reader.reader.fill_buf().map(|buf| buf.is_empty()).unwrap_or(false)
+    }
+
+    /// Returns number of the next block and count of read bytes.
+    pub fn position(&self) -> (usize, u64) {
+        (self.block_num, self.reader.read_len)
+    }
+
+    /// Continue chain of integrity from hash of the current block without check, used after skipped block
+    /// of 'RecoveryMode::SkipBadRecords', so the next block is checked by hash written with the skipped one.
+    pub fn resync_chain(&self, integrity: &mut Option<Integrity>) {
+        if let Some(integrity) = integrity {
+            let hash_len = bin_integrity_len(integrity);
+            if self.data_block.len() > hash_len {
+                integrity.resync_chain(&self.data_block[self.data_block.len() - hash_len..]);
             }
-            _ => {
+        }
+    }
+}
+
+/// Returns kind of the record and its key for 'records_in_range', key is None for records without one key.
+pub(crate) fn bin_record_kind_and_key<Key>(data_block: &[u8], block_num: usize) -> Result<(RecordKind, Option<Key>), LoadFileError>
+where
+    Key: DeserializeOwned,
+{
+    let kind = bin_record_kind(data_block).ok_or(LoadFileError::NoLineDefinition { line_num: block_num })?;
+    let key_data = match kind {
+        RecordKind::Insert if data_block[0] & !COMPRESSED_VALUE == INSERT_VERSIONED => data_block.get(5..).ok_or(LoadFileError::WrongMinBinBlockLen)?,
+        RecordKind::Batch | RecordKind::Schema | RecordKind::TransactionBegin | RecordKind::TransactionEnd | RecordKind::TransactionAbort => return Ok((kind, None)),
+        _ => &data_block[1..],
+    };
+
+    // key is the first field of data of all records with key
+    let key = bincode2::deserialize_from(key_data).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+    Ok((kind, Some(key)))
+}
+
+/// Returns kind of the record by code of data of block, None for unknown code.
+pub(crate) fn bin_record_kind(data_block: &[u8]) -> Option<RecordKind> {
+    match data_block[0] & !COMPRESSED_VALUE {
+        INSERT | INSERT_VERSIONED => Some(RecordKind::Insert),
+        REMOVE => Some(RecordKind::Remove),
+        PUSH_ITEMS => Some(RecordKind::PushItems),
+        REMOVE_ITEMS => Some(RecordKind::RemoveItems),
+        SET_ADD => Some(RecordKind::SetAdd),
+        SET_DELETE => Some(RecordKind::SetDelete),
+        INCREMENT => Some(RecordKind::Increment),
+        TRANSACTION_BEGIN => Some(RecordKind::TransactionBegin),
+        TRANSACTION_END => Some(RecordKind::TransactionEnd),
+        TRANSACTION_ABORT => Some(RecordKind::TransactionAbort),
+        BATCH => Some(RecordKind::Batch),
+        SCHEMA => Some(RecordKind::Schema),
+        _ => None,
+    }
+}
+
+/// Returns key of block data as JSON for 'LoadStats::largest_records', None if the record has no one key.
+pub(crate) fn render_bin_key<Key>(data_block: &[u8]) -> Option<String>
+where
+    Key: Serialize + DeserializeOwned,
+{
+    let key = bin_record_kind_and_key::<Key>(data_block, 0).ok()?.1?;
+    serde_json::to_string(&key).ok()
+}
+
+/// Returns serialized value of insert block data, None for other records or if key can't be deserialized.
+pub(crate) fn bin_insert_value_data<Key>(data_block: &[u8]) -> Option<&[u8]>
+where
+    Key: Serialize + DeserializeOwned,
+{
+    let key_data = match data_block[0] & !COMPRESSED_VALUE {
+        INSERT => &data_block[1..],
+        INSERT_VERSIONED => data_block.get(5..)?,
+        _ => return None,
+    };
+    let key: Key = bincode2::deserialize_from(key_data).ok()?;
+    let key_len = bincode2::serialized_size(&key).ok()? as usize;
+    key_data.get(key_len..)
+}
+
+/// Returns metadata and data of the record if block has code of metadata, otherwise data of block without metadata.
+pub(crate) fn split_bin_record_meta(data_block: &[u8]) -> Result<(Option<RawMeta<'_>>, &[u8]), LoadFileError> {
+    if data_block[0] != RECORD_META {
+        return Ok((None, data_block));
+    }
+
+    if data_block.len() < 5 {
+        return Err(LoadFileError::WrongMinBinBlockLen);
+    }
+    let mut meta_len = [0u8; 4];
+    meta_len.copy_from_slice(&data_block[1..5]);
+    let meta_end = 5 + u32::from_le_bytes(meta_len) as usize;
+    if data_block.len() <= meta_end {
+        return Err(LoadFileError::WrongMinBinBlockLen);
+    }
+
+    Ok((Some(RawMeta::Bin(&data_block[5..meta_end])), &data_block[meta_end..]))
+}
+
+/// Deserialize insert or remove operation from data of block. Returns None for other codes of operation.
+fn bin_operation<Key, Value>(data_block: &[u8], block_num: usize, value_schema_version: Option<u32>, value_migrator: &mut Option<&mut ValueMigrator>)
+    -> Result<Option<MapOperation<Key, Value>>, LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+{
+    match data_block[0] & !COMPRESSED_VALUE {
+        INSERT | INSERT_VERSIONED => {
+            let (record_version, data) = if data_block[0] & !COMPRESSED_VALUE == INSERT_VERSIONED {
+                if data_block.len() < 5 {
+                    return Err(LoadFileError::WrongMinBinBlockLen);
+                }
+                let mut version = [0u8; 4];
+                version.copy_from_slice(&data_block[1..5]);
+                (Some(u32::from_le_bytes(version)), &data_block[5..])
+            } else {
+                (None, &data_block[1..])
+            };
+
+            let migration = match (version_for_migration(record_version, value_schema_version), value_migrator) {
+                (Some(record_version), Some(migrator)) => Some((record_version, migrator)),
+                _ => None,
+            };
+            let compressed = data_block[0] & COMPRESSED_VALUE != 0;
+            if !compressed && migration.is_none() {
+                let (key, val) = bincode2::deserialize(data).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+                return Ok(Some(MapOperation::Insert(key, val)));
             }
+
+            let mut data = data;
+            let key = bincode2::deserialize_from(&mut data).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+            let value_data = if compressed {
+                decompress(data).map_err(|err| LoadFileError::ValueDecompressionError { err, line_num: block_num })?
+            } else {
+                data.to_vec()
+            };
+            let val = match migration {
+                Some((record_version, migrator)) => match migrator(record_version, RawValue::Bin(value_data)).map_err(|err| LoadFileError::MigrationError { err, line_num: block_num })? {
+                    RawValue::Bin(val) => bincode2::deserialize(&val).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?,
+                    RawValue::Json(_) => return Err(LoadFileError::MigrationError { err: MigrationError("json value returned for the binary format".to_string()), line_num: block_num }),
+                },
+                None => bincode2::deserialize(&value_data).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?,
+            };
+            Ok(Some(MapOperation::Insert(key, val)))
         }
+        REMOVE => {
+            let key = bincode2::deserialize(&data_block[1..]).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num })?;
+            Ok(Some(MapOperation::Remove(key)))
+        }
+        _ => Ok(None),
+    }
+}
 
-        block_num += 1;
+/// Returns data of operations of batch block, None if structure of block is wrong.
+fn bin_batch_operations_data(data_block: &[u8]) -> Option<Vec<&[u8]>> {
+    let count = u32::from_le_bytes(data_block.get(1..5)?.try_into().ok()?);
+    let mut data = &data_block[5..];
+    let mut operations_data = Vec::new();
+    for _ in 0..count {
+        let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        if len == 0 {
+            return None;
+        }
+        operations_data.push(data.get(4..4 + len)?);
+        data = &data[4 + len..];
     }
+    Some(operations_data)
 }
 
 /// Check data integrity after read from file.
 pub fn process_block_integrity<'a>(data_block: &'a mut [u8], integrity: &mut Integrity, block_num: usize) -> Result<&'a [u8], IntegrityError> {
     match integrity {
         Integrity::Crc32 => {
-            if data_block.len() < 6 {
+            if data_block.len() < 5 {
                 return Err(IntegrityError::Crc32Error { line_num: block_num });
             }
             let crc = crc32::checksum_ieee(&data_block[..data_block.len() - 4]);
@@ -145,6 +798,18 @@ pub fn process_block_integrity<'a>(data_block: &'a mut [u8], integrity: &mut Int
             }
             Ok(&data_block[..data_block.len() - 4])
         },
+        Integrity::XxHash64 => {
+            if data_block.len() < 9 {
+                return Err(IntegrityError::XxHash64Error { line_num: block_num });
+            }
+            let hash = xxh64(&data_block[..data_block.len() - 8], 0);
+            let mut hash_in_file = [0u8; 8];
+            hash_in_file.clone_from_slice(&data_block[data_block.len() - 8..]);
+            if hash != u64::from_le_bytes(hash_in_file) {
+                return Err(IntegrityError::XxHash64Error { line_num: block_num });
+            }
+            Ok(&data_block[..data_block.len() - 8])
+        },
         Integrity::Sha1Chain(hash_of_prev) => {
             const HASH_LEN: usize = 20;
             if data_block.len() < HASH_LEN + 1 {
@@ -175,6 +840,66 @@ pub fn process_block_integrity<'a>(data_block: &'a mut [u8], integrity: &mut Int
             *hash_of_prev = current_hash;
             Ok(data)
         },
+        Integrity::HmacSha256Chain { key, prev } => {
+            const TAG_LEN: usize = 32;
+            if data_block.len() < TAG_LEN + 1 {
+                return Err(IntegrityError::HmacError { line_num: block_num });
+            }
+            let data = &data_block[..data_block.len() - TAG_LEN];
+            let mut current_tag: [u8; TAG_LEN] = [0; TAG_LEN];
+            blockchain_hmac_sha256(key, &prev[..], data, &mut current_tag);
+            // tag is compared in constant time, so it can't be guessed by time of check
+            let tag_in_file = &data_block[data_block.len() - TAG_LEN..];
+            if !fixed_time_eq(&current_tag, tag_in_file) {
+                return Err(IntegrityError::HmacError { line_num: block_num });
+            }
+            *prev = current_tag;
+            Ok(data)
+        },
+        #[cfg(feature = "blake3")]
+        Integrity::Blake3Chain(hash_of_prev) => {
+            const HASH_LEN: usize = 32;
+            if data_block.len() < HASH_LEN + 1 {
+                return Err(IntegrityError::Blake3ChainError { line_num: block_num });
+            }
+            let data = &data_block[..data_block.len() - HASH_LEN];
+            let mut current_hash: [u8; HASH_LEN] = [0; HASH_LEN];
+            blockchain_blake3(&hash_of_prev[..], data, &mut current_hash);
+            let hash_in_file = &data_block[data_block.len() - HASH_LEN..];
+            if current_hash != hash_in_file {
+                return Err(IntegrityError::Blake3ChainError { line_num: block_num });
+            }
+            *hash_of_prev = current_hash;
+            Ok(data)
+        },
+    }
+}
+
+/// Returns kind of correct checksum at the end of the block, None if there is no one.
+/// Hashes of chain can't be told apart from data without the previous hash.
+pub(crate) fn bin_block_integrity(data_block: &[u8]) -> Option<IntegrityKind> {
+    let checksum_is_correct = |len: usize, checksum: &dyn Fn(&[u8]) -> Vec<u8>| {
+        data_block.len() > len && checksum(&data_block[..data_block.len() - len]) == data_block[data_block.len() - len..]
+    };
+    if checksum_is_correct(4, &|data| crc32::checksum_ieee(data).to_le_bytes().to_vec()) {
+        Some(IntegrityKind::Crc32)
+    } else if checksum_is_correct(8, &|data| xxh64(data, 0).to_le_bytes().to_vec()) {
+        Some(IntegrityKind::XxHash64)
+    } else {
+        None
+    }
+}
+
+/// Length of checksum or hash at the end of the block.
+pub(crate) fn bin_integrity_len(integrity: &Integrity) -> usize {
+    match integrity {
+        Integrity::Crc32 => 4,
+        Integrity::XxHash64 => 8,
+        Integrity::Sha1Chain(_) => 20,
+        Integrity::Sha256Chain(_) => 32,
+        Integrity::HmacSha256Chain { .. } => 32,
+        #[cfg(feature = "blake3")]
+        Integrity::Blake3Chain(_) => 32,
     }
 }
 
@@ -241,6 +966,45 @@ pub fn read_bin_block_len<Reader>(reader: &mut Reader) -> Result<usize, LoadFile
     Ok(len)
 }
 
+/// Returns length of the beginning of 'data' that contains only complete blocks.
+/// Incomplete block at the end, such as being written at the moment, is not counted.
+/// Errors if data contains wrong block length.
+pub(crate) fn complete_bin_blocks_len(data: &[u8]) -> Result<usize, LoadFileError> {
+    let mut complete_len = 0;
+    loop {
+        let mut reader = &data[complete_len..];
+        let block_len = match read_bin_block_len(&mut reader) {
+            Ok(0) => return Ok(complete_len),
+            Ok(block_len) => block_len,
+            Err(LoadFileError::FileError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(complete_len),
+            Err(err) => return Err(err),
+        };
+
+        if reader.len() < block_len {
+            return Ok(complete_len);
+        }
+
+        let len_of_block_len = data.len() - complete_len - reader.len();
+        complete_len += len_of_block_len + block_len;
+    }
+}
+
+/// Reader that counts read bytes.
+pub(crate) struct CountingReader<Reader> {
+    /// Wrapped reader.
+    pub reader: Reader,
+    /// Count of read bytes.
+    pub read_len: u64,
+}
+
+impl<Reader: Read> Read for CountingReader<Reader> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.read_len += len as u64;
+        Ok(len)
+    }
+}
+
 /// Depending on the settings in 'cfg', it adds a checksum, calculates the blockchain, compresses, encrypts, etc.
 pub fn post_process_file_bin_block(bin_block: &mut Vec<u8>, integrity: &mut Option<Integrity>) {
     if let Some(integrity) = integrity {
@@ -249,6 +1013,10 @@ pub fn post_process_file_bin_block(bin_block: &mut Vec<u8>, integrity: &mut Opti
                 let crc = crc32::checksum_ieee(bin_block);
                 bin_block.extend_from_slice(&crc.to_le_bytes());
             },
+            Integrity::XxHash64 => {
+                let hash = xxh64(bin_block, 0);
+                bin_block.extend_from_slice(&hash.to_le_bytes());
+            },
             Integrity::Sha1Chain(prev_hash) => {
                 let mut hash: [u8; 20] = [0; 20];
                 blockchain_sha1(&prev_hash[..], bin_block, &mut hash);
@@ -261,6 +1029,19 @@ pub fn post_process_file_bin_block(bin_block: &mut Vec<u8>, integrity: &mut Opti
                 bin_block.extend_from_slice(&hash);
                 *prev_hash = hash;
             },
+            Integrity::HmacSha256Chain { key, prev } => {
+                let mut tag: [u8; 32] = [0; 32];
+                blockchain_hmac_sha256(key, prev, bin_block, &mut tag);
+                bin_block.extend_from_slice(&tag);
+                *prev = tag;
+            },
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(prev_hash) => {
+                let mut hash: [u8; 32] = [0; 32];
+                blockchain_blake3(prev_hash, bin_block, &mut hash);
+                bin_block.extend_from_slice(&hash);
+                *prev_hash = hash;
+            },
         }
     }
 }
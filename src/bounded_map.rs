@@ -0,0 +1,174 @@
+use crate::cfg::Cfg;
+use crate::format::MapOperation;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Callback for receive entries evicted from 'BoundedMap'.
+pub type EvictionCallback<Key, Value> = Box<dyn FnMut(Key, Value) + Send>;
+
+/// File based map with max count of entries from 'Cfg::max_entries', for example for cache.
+/// When insert exceeds the max count, least recently used entries are evicted
+/// and their removes are written to the file like usual removes, so the file restores the bounded state.
+/// Recency is changed by insert and 'get' but it's process-local: access by 'get' is not written to the file,
+/// after reopening the order is restored only from inserts of the file.
+/// Without 'max_entries' the map is not bounded.
+pub struct BoundedMap<Key, Value>
+where Key: Ord {
+    /// Wrapped map.
+    inner: MapWithFile<Key, Value, BTreeMap<Key, Value>>,
+    /// Max count of entries.
+    max_entries: usize,
+    /// Order of use of keys.
+    recency: Recency<Key>,
+    /// Callback for evicted entries.
+    eviction_callback: Option<EvictionCallback<Key, Value>>,
+}
+
+impl<Key, Value: 'static> BoundedMap<Key, Value>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone {
+
+    /// Open/create file and loads the entire history of changes, same as 'MapWithFile::open_or_create'.
+    /// If the file contains more entries than 'max_entries' (when max count is decreased),
+    /// least recently inserted entries are evicted.
+    pub fn open_or_create(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let max_entries = cfg.max_entries.unwrap_or(usize::MAX);
+        let mut recency = Recency::default();
+        let inner = MapWithFile::open_with(file_path.as_ref(), cfg, |map: &mut BTreeMap<Key, Value>, map_operation| {
+            match map_operation {
+                MapOperation::Insert(key, value) => {
+                    recency.touch(&key);
+                    map.insert(key, value);
+                },
+                MapOperation::Remove(key) => {
+                    recency.forget(&key);
+                    map.remove(&key);
+                },
+            }
+        })?;
+
+        let mut bounded_map = BoundedMap { inner, max_entries, recency, eviction_callback: None };
+        bounded_map.evict_over_max()
+            .map_err(LoadFileError::EvictionError)?;
+
+        Ok(bounded_map)
+    }
+
+    /// Set callback for receive entries evicted by insert.
+    pub fn on_eviction(&mut self, callback: impl FnMut(Key, Value) + Send + 'static) {
+        self.eviction_callback = Some(Box::new(callback));
+    }
+
+    /// Inserts a key-value pair into the map, the key becomes most recently used.
+    /// If count of entries exceeds max, least recently used entries are evicted with writing of remove to the file.
+    pub fn insert(&mut self, key: Key, value: Value) -> Result<Option<Value>, SerializedError> {
+        let old_value = self.inner.insert(key.clone(), value)?;
+        self.recency.touch(&key);
+        self.evict_over_max()?;
+        Ok(old_value)
+    }
+
+    /// Remove value by key.
+    pub fn remove(&mut self, key: &Key) -> Result<Option<Value>, SerializedError> {
+        let old_value = self.inner.remove(key)?;
+        self.recency.forget(key);
+        Ok(old_value)
+    }
+
+    /// Returns a reference to the value corresponding to the key, the key becomes most recently used.
+    /// Nothing writing to the file.
+    pub fn get(&mut self, key: &Key) -> Option<&Value> {
+        if self.inner.get(key).is_some() {
+            self.recency.touch(key);
+        }
+        self.inner.get(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key without change of recency.
+    pub fn peek(&self, key: &Key) -> Option<&Value> {
+        self.inner.get(key)
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.inner.map.len()
+    }
+
+    /// Returns true if the map contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.inner.map.is_empty()
+    }
+
+    /// Returns reference to the wrapped file based map.
+    pub fn inner(&self) -> &MapWithFile<Key, Value, BTreeMap<Key, Value>> {
+        &self.inner
+    }
+
+    /// Waits until all changes made before are written to the file.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+
+    /// Remove least recently used entries while count of entries is greater than max.
+    fn evict_over_max(&mut self) -> Result<(), SerializedError> {
+        while self.inner.map.len() > self.max_entries {
+            let key = match self.recency.oldest() {
+                Some(key) => key,
+                None => break,
+            };
+
+            let value = self.inner.remove(&key)?;
+            self.recency.forget(&key);
+            if let (Some(value), Some(callback)) = (value, &mut self.eviction_callback) {
+                callback(key, value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Order of use of keys.
+struct Recency<Key> {
+    /// Tick of the next use.
+    next_tick: u64,
+    /// Tick of last use of each key.
+    ticks: BTreeMap<Key, u64>,
+    /// Keys by tick of last use.
+    order: BTreeMap<u64, Key>,
+}
+
+impl<Key: Ord + Clone> Recency<Key> {
+    /// Make key most recently used.
+    fn touch(&mut self, key: &Key) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(old_tick) = self.ticks.insert(key.clone(), tick) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(tick, key.clone());
+    }
+
+    /// Remove key from the order.
+    fn forget(&mut self, key: &Key) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Returns least recently used key.
+    fn oldest(&self) -> Option<Key> {
+        self.order.values().next().cloned()
+    }
+}
+
+impl<Key> Default for Recency<Key> {
+    fn default() -> Self {
+        Recency { next_tick: 0, ticks: BTreeMap::new(), order: BTreeMap::new() }
+    }
+}
@@ -1,19 +1,353 @@
+use crate::clock::{Clock, SystemClock};
+use crate::format::RecordKind;
+use crate::log_shipper::LogShipping;
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
 /// Config of file based map.
+/// All callbacks are Send, so the map can be moved to other thread, and the map is Sync because callbacks are called only with exclusive access to it.
 pub struct Cfg {
     /// Format of stored data, binary or text.
     pub format: Format,
     /// Method of controlling the integrity of stored data in a history file.
+    /// Opening of the file written with other integrity fails with 'IntegrityError::FileHasUnexpectedHashSuffix'
+    /// or 'IntegrityError::FileMissingHashes' by its first record, see 'verify::detect_integrity'.
     pub integrity: Option<Integrity>,
     /// Callback for receive a file write error.
     /// If the callback from the callback is None, then errors are ignored..
+    /// Not used if 'write_error_context_callback' is set.
+    #[deprecated(note = "use 'write_error_context_callback' that receives error with context")]
     pub write_error_callback: Option<Box<dyn FnMut(std::io::Error) + Send>>,
+    /// Callback for receive a file write error with context of the failed write.
+    /// Called on the background thread, or on the calling thread with 'WriteMode::Sync'. If the callback is None, then errors are ignored.
+    pub write_error_context_callback: Option<WriteErrorCallback>,
+    /// Callback for receive confirmation of each successful write to the file,
+    /// for example for audit log or external index of offsets of records.
+    /// Called on the background thread, or on the calling thread with 'WriteMode::Sync'.
+    pub write_ack_callback: Option<WriteAckCallback>,
+    /// Additional writer where each record is written after writing to the file,
+    /// for example for shipping records to a remote log collector.
+    /// Records are written to the sink in the same order as to the file.
+    pub secondary_sink: Option<Box<dyn std::io::Write + Send>>,
+    /// Callback for receive a secondary sink write error.
+    /// Errors of the sink don't affect writing to the file. If the callback is None, then errors are ignored.
+    pub secondary_sink_error_callback: Option<Box<dyn FnMut(std::io::Error) + Send>>,
+    /// Shipping of records written to the file by 'LogShipper' in a separate thread
+    /// with batching and retries, for example to object storage or replication endpoint.
+    pub log_shipping: Option<LogShipping>,
+    /// Callback for receive failure of the background thread of writing, for example panic of the write error callback
+    /// or not stopped thread in 'stop_timeout'. Called on the thread dropping or closing the map.
+    pub on_worker_failure: Option<WorkerFailureCallback>,
+    /// Current version of schema of the value. If set, then each insert record is written
+    /// with this version, as "insV3 " in the text format or after operation code in the binary format.
+    /// Records without version are treated as version 0.
+    pub value_schema_version: Option<u32>,
+    /// Called when loading for values of records with version older than 'value_schema_version',
+    /// returns value converted to the current version. Records at the current version are not passed.
+    /// Records are rewritten at the current version when the file is rewritten, for example by 'convert'.
+    pub value_migrator: Option<ValueMigrator>,
+    /// If true, then insert of value serialized the same as current value of the key
+    /// is not written to the file.
+    pub skip_identical_inserts: bool,
+    /// Implementation of the channel to the background thread writing to the file.
+    pub write_channel: WriteChannel,
+    /// Max count of operations sent to the background thread and not yet written to the file, queue of the thread
+    /// is bounded by it and writing methods wait when it's full, so memory doesn't grow when changes are faster than the disk.
+    /// 'MapWithFile::try_insert' returns 'TryOutcome::Pending' instead of waiting. Queue is not bounded if it's None.
+    pub max_pending_writes: Option<usize>,
+    /// Flag for cancel of loading of the file, for example on shutdown signal while huge file is loading.
+    /// Checked by loading functions every 'LOAD_CANCEL_CHECK_INTERVAL' records,
+    /// when it's set, loading stops with 'LoadFileError::Cancelled'.
+    pub load_cancel: Option<Arc<AtomicBool>>,
+    /// Callback of progress of loading of the file when opening, for example for progress bar of huge file.
+    /// Called every 'load_progress_interval' records and once at the end of loading,
+    /// if it returns error, loading stops with 'LoadFileError::Interrupted'.
+    pub load_progress_callback: Option<LoadProgressCallback>,
+    /// Count of records between calls of 'load_progress_callback', 10000 by default.
+    pub load_progress_interval: usize,
+    /// Max count of entries of 'BoundedMap', least recently used entries are evicted when it's exceeded.
+    /// Not used by other maps.
+    pub max_entries: Option<usize>,
+    /// If set, then values longer than this count of bytes after serialization are compressed by zstd,
+    /// in the text format the value is replaced with {"__dkmz":"<base64>"}, in the binary format
+    /// the operation code is flagged. Compressed values are decompressed when loading regardless of this setting.
+    pub compress_values_over: Option<usize>,
+    /// Max size in bytes of record with value in the file (line with end of line of the text format or
+    /// block with its length of the binary format, after before write callback).
+    /// Insert or batch with longer record returns 'SerializedError::ValueTooLarge' and nothing is changed.
+    /// Loading of the file with longer record fails with 'LoadFileError::RecordTooLarge'.
+    pub max_value_size: Option<usize>,
+    /// If true, then insert with record the same as the last written record of the map
+    /// (without integrity) is not written to the file, so duplicates of operation don't grow the file
+    /// and integrity chain. Any other record written after makes the next insert written again.
+    pub dedupe_consecutive: bool,
+    /// Name and version of key and value types, for example "user_id:u64/user:v3".
+    /// Written as the first record of created file and compared with the record when the file is opened,
+    /// different fingerprint returns 'LoadFileError::SchemaMismatch'. Files without the record are loaded.
+    pub schema_fingerprint: Option<String>,
+    /// If true, then loading checks records against state of the map loaded before them and fails
+    /// with 'LoadFileError::ReplayAnomaly' on record impossible for the map, for example remove of absent key.
+    /// Useful for detection of records damaged by format callbacks. See 'ReplayAnomalyKind'.
+    pub strict_replay: bool,
+    /// If true and 'strict_replay' is not set, then anomalies of records are collected when loading
+    /// and returned by 'MapWithFile::replay_anomalies' instead of error.
+    pub collect_replay_anomalies: bool,
+    /// If true, then marker file "<file>.dirty" with pid and time is written when the map is opened and removed
+    /// when the map is dropped. If the marker exists when opening, the previous process didn't close the map and
+    /// 'OpenWarning::UncleanShutdown' is returned by 'MapWithFile::open_warnings'.
+    pub dirty_marker: bool,
+    /// If true and integrity is chained, then head of the chain after the last written record is kept in the file "<file>.head",
+    /// so removed records at the end of the file are detected. The head file is written by the thread of writing after
+    /// written data is synced to disk, so it's never ahead of the file after crash, and every write is synced regardless of 'fsync_policy'.
+    /// Opening fails with 'IntegrityError::ChainTruncated' if the record at the length of the head file doesn't end with its hash.
+    pub chain_head_file: bool,
+    /// Names of operations of the text format accepted when loading in addition to the canonical names, with kind of record,
+    /// for loading of files written by other tools, for example ("insert", RecordKind::Insert) or ("remove", RecordKind::Remove).
+    /// Alias is replaced with the canonical name after check of integrity, so it can replace canonical name of other operation.
+    /// Records are always written with the canonical names.
+    pub extra_text_ops: Vec<(String, RecordKind)>,
+    /// Recovery from insert and remove records which key or value can't be deserialized when loading,
+    /// for example after adding of required field to the value type. Skipped and recovered records are counted
+    /// by warnings of 'MapWithFile::open_warnings'. Other records fail loading with any policy.
+    pub on_deserialize_error: DeserializePolicy,
+    /// Writing to the file in the background thread or in the calling thread, see 'WriteMode'.
+    pub write_mode: WriteMode,
+    /// When written data is synced to disk by 'File::sync_data', so it's not lost with the OS page cache on power loss.
+    /// Error of sync is passed to the write error callback and returned by the next flush as error of writing.
+    pub fsync_policy: FsyncPolicy,
+    /// Repeating of failed writes to the file before the error is passed to the write error callback.
+    /// Later operations wait while the failed write is repeated, so the order of records is kept. Not repeated if it's None.
+    pub write_retry: Option<RetryPolicy>,
+    /// Max time of waiting of writing of pending changes and stop of the background thread when the map is dropped or closed,
+    /// so stuck disk doesn't hang the destructor. The thread is left to finish writing itself after the timeout
+    /// and 'WorkerFailure::StopTimeout' is passed to 'on_worker_failure'. Waits without limit if it's None.
+    pub stop_timeout: Option<Duration>,
+    /// Compaction of the file by 'MapWithFile::compact' when most of records are dead, checked after each change of the map.
+    /// Compaction waits of writing of pending changes and rewrites the file in the calling thread.
+    /// Error of compaction is returned by 'MapWithFile::take_last_write_error', next compaction is tried after 'AutoCompact::min_ops' operations.
+    pub auto_compact: Option<AutoCompact>,
+    /// Snapshot file with entries of the map, the file of the map is the log of operations after the snapshot,
+    /// so opening loads the snapshot and replays only the log. 'MapWithFile::checkpoint' writes the map to the new snapshot
+    /// and clears the log. Path of the snapshot is the path of the file with ".snapshot", see 'snapshot::snapshot_path'.
+    /// The snapshot is not loaded if it's None, so it must be set for all openings of the file.
+    pub snapshot: Option<SnapshotCfg>,
+    /// What to do when opening finds invalid record, for example written partially by crashed process, see 'RecoveryMode'.
+    /// Recovery is applied only by opening of the map, other ways of reading of the file fail with error of the record.
+    pub recovery: RecoveryMode,
+    /// Source of current time and waiting for expiry of 'TtlMap', 'FsyncPolicy::Interval', delays of 'RetryPolicy'
+    /// and deadlines of log shipping, system time by default. Can be replaced by 'testing::ManualClock' for tests.
+    pub clock: Arc<dyn Clock>,
+}
+
+/// When data written to the file is synced to disk, see 'Cfg::fsync_policy'.
+/// With any policy the file is synced when the map is dropped or closed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Data is synced by OS.
+    #[default]
+    Never,
+    /// After each write to the file, operations waiting in the queue of the background thread are written by one write.
+    EveryWrite,
+    /// After each count of operations, checked after each write to the file.
+    EveryNOps(usize),
+    /// Not later than the duration after the first not synced write.
+    /// With 'WriteMode::Sync' the time is checked only by writes.
+    Interval(Duration),
+}
+
+/// Repeating of failed writes to the file, see 'Cfg::write_retry'.
+/// Partially written data of the failed write is truncated before each repeat.
+/// With 'WriteMode::Sync' the calling thread waits of repeats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Max count of repeats after the first failed write.
+    pub attempts: usize,
+    /// Delay before the first repeat, each next delay is doubled.
+    pub backoff: Duration,
+}
+
+/// Threshold of automatic compaction, see 'Cfg::auto_compact'.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoCompact {
+    /// Min count of operations in the file, see 'MapWithFile::ops_logged', small files are not compacted.
+    pub min_ops: usize,
+    /// Min part of dead operations in the file from 0 to 1, for example 0.8 for compaction when 80% of records
+    /// don't affect the map. Operations are dead if they are not entries of the map, see 'MapWithFile::live_len'.
+    pub garbage_ratio: f64,
+}
+
+// ratio is checked by 'CfgBuilder::build', so it's not NaN
+impl Eq for AutoCompact {}
+
+/// Snapshot file of the map, see 'Cfg::snapshot'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotCfg {
+    /// Count of operations in the log after which 'MapWithFile::checkpoint' is called after changes of the map,
+    /// see 'MapWithFile::ops_logged'. Error of checkpoint is returned by 'MapWithFile::take_last_write_error'
+    /// and next checkpoint is tried after the same count of operations. Only manual checkpoints if it's None.
+    pub checkpoint_ops: Option<usize>,
+}
+
+/// Way of writing of changes to the file, see 'Cfg::write_mode'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Changes are sent to the background thread and written later, the map is changed immediately.
+    /// Errors of writing are passed to the write error callback and returned by the next flush.
+    #[default]
+    Background,
+    /// Changes are written to the locked file in the calling thread before the map and indexes are changed,
+    /// the background thread is not spawned. If writing fails, partially written record is truncated,
+    /// 'SerializedError::WriteError' is returned and the map is not changed, the error is passed to the write error callback too.
+    /// If truncate fails too, next writes fail until the record is truncated, so records are not written after it.
+    /// Data is written to the file by the operation, but not synced to disk.
+    Sync,
+}
+
+/// What to do when key or value of record can't be deserialized when loading, see 'Cfg::on_deserialize_error'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeserializePolicy {
+    /// Loading fails with deserialization error.
+    #[default]
+    Fail,
+    /// Record is ignored.
+    Skip,
+    /// Value of insert record is replaced with 'Value::default()' if key is deserialized, other records are ignored.
+    /// Used only by 'MapWithFile::open_or_create_with_default_values' because it requires 'Value: Default',
+    /// it's the same as 'Fail' for other ways of loading.
+    Default,
+}
+
+/// What to do when opening finds invalid record, see 'Cfg::recovery'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Opening fails with error of loading.
+    #[default]
+    Fail,
+    /// Invalid last record of the file is truncated from the file and opening continues with records before it,
+    /// for example line without end or with wrong checksum written partially by crashed process.
+    /// Count of truncated bytes is returned by warning of 'MapWithFile::open_warnings'.
+    /// Invalid record followed by other records fails opening as with 'Fail'.
+    TruncateAtError,
+    /// Invalid records are skipped and opening continues with the next records, for example for forensic reading of damaged file.
+    /// Skipped records are left in the file, their numbers and errors are returned by warnings of 'MapWithFile::open_warnings'.
+    /// Chain of integrity is continued from hash written with skipped record without check.
+    /// Invalid last record is truncated as by 'TruncateAtError', so records appended after opening are not merged with it.
+    SkipBadRecords,
+}
+
+/// Called with progress of loading of the file, see 'Cfg::load_progress_callback'.
+pub type LoadProgressCallback = Box<dyn FnMut(LoadProgress) -> Result<(), ()> + Send>;
+
+/// Progress of loading of the file, see 'Cfg::load_progress_callback'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+    /// Count of read records, line or block count.
+    pub records: usize,
+    /// Count of read bytes from the start of the file.
+    pub bytes_read: u64,
+    /// Length of the file when opening.
+    pub total_bytes: u64,
+}
+
+/// Called on the background thread when writing to the file fails.
+pub type WriteErrorCallback = Box<dyn FnMut(WriteErrorContext) + Send>;
+
+/// Error of writing to the file with context of the failed write.
+#[derive(Debug)]
+pub struct WriteErrorContext {
+    /// Error of writing.
+    pub error: std::io::Error,
+    /// Path of the file.
+    pub file_path: PathBuf,
+    /// Number of the write since opening of the map, starts from 1, failed writes are counted too.
+    pub sequence: u64,
+    /// Operation of the map which records are not written.
+    pub operation: WriteOperation,
+    /// Size of not written data in bytes.
+    pub bytes: usize,
+    /// Count of failed writes in a row including this one, reset by successful write.
+    pub consecutive_failures: u64,
+    /// Not written data, so it can be saved elsewhere. Empty for 'WriteOperation::Fsync'.
+    pub data: Vec<u8>,
+}
+
+/// Called when the background thread of writing failed, see 'Cfg::on_worker_failure'.
+pub type WorkerFailureCallback = Box<dyn FnMut(WorkerFailure) + Send>;
+
+/// Failure of the background thread of writing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkerFailure {
+    /// The thread panicked, for example in callback of config, with message of the panic.
+    /// Changes sent after the panic are not written.
+    Panicked(String),
+    /// The thread didn't stop in 'Cfg::stop_timeout'.
+    StopTimeout,
+}
+
+/// Called on the background thread after successful write to the file.
+pub type WriteAckCallback = Box<dyn FnMut(WriteAck) + Send>;
+
+/// Confirmation of successful write to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteAck {
+    /// Number of the write since opening of the map, starts from 1, failed writes are counted too.
+    pub sequence: u64,
+    /// Operation of the map which records are written.
+    pub operation: WriteOperation,
+    /// Size of written data in bytes.
+    pub bytes: usize,
+    /// Offset of written data in the file. Offsets are not correct after failed write
+    /// because part of data of failed write can be written.
+    pub offset: u64,
+}
+
+/// Operation of the map written to the file by one write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOperation {
+    /// Insert record.
+    Insert,
+    /// Remove record.
+    Remove,
+    /// Several records, as batch of 'apply_batch' or inserts of 'try_extend'.
+    Batch,
+    /// Records of transaction with begin and end markers.
+    Transaction,
+    /// Abort marker of incomplete transaction written when opening.
+    TransactionAbort,
+    /// Push of item to collection value of 'MultiMap'.
+    PushItem,
+    /// Remove of item from collection value of 'MultiMap'.
+    RemoveItem,
+    /// Increment of counter value by 'MapWithFile::fetch_add'.
+    Increment,
+    /// Schema fingerprint record written when file is created.
+    Schema,
+    /// Flush and sync of written data to disk by 'Cfg::fsync_policy' or when the map is dropped or closed,
+    /// passed only to the write error callback.
+    Fsync,
+    /// Write of the head file of 'Cfg::chain_head_file' after sync of written data, passed only to the write error callback.
+    ChainHead,
+}
+
+/// Implementation of the channel to the background thread writing to the file.
+/// Order of writes, flush and stop of the thread are the same for all implementations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteChannel {
+    /// std::sync::mpsc channel.
+    Std,
+    /// crossbeam_channel channel, has less contention when many threads write to the map.
+    #[cfg(feature = "crossbeam")]
+    Crossbeam,
 }
 
 /// Format of stored data, binary or text.
 pub enum Format {
     /// Text format.
     /// Each changing map operation is recorded as one line ending with '\n'.
-    /// The line starts with operation name as "ins " or "remove ",
+    /// The line starts with operation name as "ins " or "rem ",
     /// followed by data serialized with serde::json, for "ins " key and value serialized as
     /// tuple (key, value).
     /// Next, optionally can be data integrity, after ' '.
@@ -25,6 +359,18 @@ pub enum Format {
     /// Or with checksum example:
     /// ins [8,"a"] 2212816791
     /// rem 8 3024193484
+    ///
+    /// Or with version of schema of the value example:
+    /// insV2 [8,"a"]
+    ///
+    /// Batch of operations applied atomically is one line with "bat " and JSON array of operations:
+    /// bat [["ins",[1,"a"]],["rem",2]]
+    ///
+    /// Records of transaction are between "txb" and "txe" lines, "txa" line aborts incomplete transaction:
+    /// txb
+    /// ins [1,"a"]
+    /// rem 2
+    /// txe
     Text(Option<BeforeWriteTxtCallback>, Option<AfterReadTxtCallback>),
 
     /// Binary format.
@@ -36,7 +382,12 @@ pub enum Format {
     /// After block data where first byte of block data is
     /// code of operation as 'insert' or 'remove'. After operation code followed
     /// code arguments of operation such as key value serialized with bincode2
+    /// (for insert with version of schema of the value, version in 4 bytes little endian before arguments)
     /// and after, optionally can be data integrity.
+    /// Records of transaction are between blocks with codes 3 (begin) and 4 (end),
+    /// block with code 5 aborts incomplete transaction.
+    /// Batch of operations applied atomically is block with code 6, count of operations in 4 bytes little endian
+    /// and data of each operation (as data of insert or remove block) with its length in 4 bytes little endian before.
     Bin(Option<BeforeWriteBinCallback>, Option<AfterReadBinCallback>),
 }
 
@@ -45,44 +396,406 @@ pub enum Format {
 /// or for sending data to a third-party storage.
 /// Source string ends with '\n' and transformed string need so ends with '\n'
 /// and no contains other '\n' because reading from file will line by line.
-pub type BeforeWriteTxtCallback = Box<dyn FnMut(&mut String)>;
+pub type BeforeWriteTxtCallback = Box<dyn FnMut(&mut String) + Send>;
 
 /// Called when data of insert or remove read from file.
 /// This may be needed for the necessary transformation of data written to a file
 /// or for sending data to a third-party storage.
-pub type AfterReadTxtCallback = Box<dyn FnMut(&mut String) -> Result<(), Box<dyn std::error::Error>>>;
+pub type AfterReadTxtCallback = Box<dyn FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>> + Send>;
 
 /// Called when data of insert or remove prepared for writing to the file.
 /// This may be needed for data transformation before write to the file
 /// or for sending data to a third-party storage.
-pub type BeforeWriteBinCallback = Box<dyn FnMut(&mut Vec<u8>)>;
+pub type BeforeWriteBinCallback = Box<dyn FnMut(&mut Vec<u8>) + Send>;
 
 /// Called when data of insert or remove read from file.
 /// This may be needed for the necessary transformation of data written to a file
 /// or for sending data to a third-party storage.
-pub type AfterReadBinCallback = Box<dyn FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error>>>;
+pub type AfterReadBinCallback = Box<dyn FnMut(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>> + Send>;
+
+/// Called when loading for value of record with older schema version.
+/// Receives version of record and raw value, returns raw value at the current version.
+pub type ValueMigrator = Box<dyn FnMut(u32, RawValue) -> Result<RawValue, MigrationError> + Send>;
+
+/// Not deserialized value of the record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RawValue {
+    /// Value of the text format.
+    Json(serde_json::Value),
+    /// Value of the binary format serialized with bincode2.
+    Bin(Vec<u8>),
+}
+
+/// Error of migration of value returned from 'value_migrator'.
+#[derive(Debug)]
+pub struct MigrationError(pub String);
+
+impl std::error::Error for MigrationError {}
 
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Returns version of record for passing to the migrator or None if migration not needed.
+pub(crate) fn version_for_migration(record_version: Option<u32>, current_version: Option<u32>) -> Option<u32> {
+    let record_version = record_version.unwrap_or(0);
+    match current_version {
+        Some(current_version) if record_version < current_version => Some(record_version),
+        _ => None,
+    }
+}
 
 /// Method of controlling the integrity of stored data in a history file.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Integrity {
     /// crc32 (ieee) checksum of operation and data for each line in the operations history file.
     Crc32,
+    /// xxHash64 checksum of operation and data for each line in the operations history file,
+    /// stronger than crc32 and much faster than hash chains, but not cryptographic.
+    XxHash64,
     /// For Sha1 blockchain. Each line in the history file will contain
     /// the sum of the hash of the previous line with the operation + data hash of the current line.
     Sha1Chain([u8; 20]),
     /// For Sha256 blockchain. Each line in the history file will contain
     /// the sum of the hash of the previous line with the operation + data hash of the current line.
     Sha256Chain([u8; 32]),
+    /// For BLAKE3 blockchain, it's faster than Sha256 blockchain. Each line in the history file will contain
+    /// the sum of the hash of the previous line with the operation + data hash of the current line.
+    #[cfg(feature = "blake3")]
+    Blake3Chain([u8; 32]),
+    /// For HMAC-SHA256 blockchain with secret 'key'. Each line in the history file will contain
+    /// HMAC of the tag of the previous line with the operation + data of the current line,
+    /// so the file can't be changed and signed again without the key. 'prev' is the tag before the first line.
+    /// The key is never written to the file and it's not printed by Debug.
+    HmacSha256Chain { key: Vec<u8>, prev: [u8; 32] },
+}
+
+impl Integrity {
+    /// Name of method of controlling the integrity.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Integrity::Crc32 => "crc32",
+            Integrity::XxHash64 => "xxhash64",
+            Integrity::Sha1Chain(_) => "sha1_chain",
+            Integrity::Sha256Chain(_) => "sha256_chain",
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(_) => "blake3_chain",
+            Integrity::HmacSha256Chain { .. } => "hmac_sha256_chain",
+        }
+    }
+
+    /// Kind of checksum or hash written to the file, see 'IntegrityKind'.
+    pub fn kind(&self) -> IntegrityKind {
+        match self {
+            Integrity::Crc32 => IntegrityKind::Crc32,
+            Integrity::XxHash64 => IntegrityKind::XxHash64,
+            Integrity::Sha1Chain(_) => IntegrityKind::Sha1Chain,
+            Integrity::Sha256Chain(_) | Integrity::HmacSha256Chain { .. } => IntegrityKind::Hash256Chain,
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(_) => IntegrityKind::Hash256Chain,
+        }
+    }
+
+    /// Continue chain from 'hash' written with the record without check of the record, wrong length of the hash is ignored.
+    pub(crate) fn resync_chain(&mut self, hash: &[u8]) {
+        match self {
+            Integrity::Crc32 | Integrity::XxHash64 => {},
+            Integrity::Sha1Chain(hash_of_prev) => if let Ok(hash) = hash.try_into() { *hash_of_prev = hash },
+            Integrity::Sha256Chain(hash_of_prev) => if let Ok(hash) = hash.try_into() { *hash_of_prev = hash },
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(hash_of_prev) => if let Ok(hash) = hash.try_into() { *hash_of_prev = hash },
+            Integrity::HmacSha256Chain { prev, .. } => if let Ok(hash) = hash.try_into() { *prev = hash },
+        }
+    }
 }
 
 impl Default for Cfg {
     /// Default config of file based map.
+    #[allow(deprecated)]
     fn default() -> Self {
         Cfg {
             integrity: None,
             write_error_callback: None,
+            write_error_context_callback: None,
+            write_ack_callback: None,
+            secondary_sink: None,
+            secondary_sink_error_callback: None,
+            log_shipping: None,
+            on_worker_failure: None,
+            value_schema_version: None,
+            value_migrator: None,
+            skip_identical_inserts: false,
+            write_channel: WriteChannel::Std,
+            max_pending_writes: None,
+            load_cancel: None,
+            load_progress_callback: None,
+            load_progress_interval: 10000,
+            max_entries: None,
+            compress_values_over: None,
+            max_value_size: None,
+            dedupe_consecutive: false,
+            schema_fingerprint: None,
+            strict_replay: false,
+            collect_replay_anomalies: false,
+            dirty_marker: false,
+            chain_head_file: false,
+            extra_text_ops: Vec::new(),
+            on_deserialize_error: DeserializePolicy::default(),
+            write_mode: WriteMode::default(),
+            fsync_policy: FsyncPolicy::default(),
+            write_retry: None,
+            stop_timeout: None,
+            auto_compact: None,
+            snapshot: None,
+            recovery: RecoveryMode::default(),
+            clock: Arc::new(SystemClock),
             format: Format::Text(None, None),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Format of stored data without callbacks, see 'CfgDescription'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatKind {
+    /// 'Format::Text'.
+    Text,
+    /// 'Format::Bin'.
+    Bin,
+}
+
+/// Kind of checksum or hash of records found in the file, see 'Integrity::kind' and 'verify::detect_integrity'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityKind {
+    /// Checksum of 'Integrity::Crc32'.
+    Crc32,
+    /// Checksum of 'Integrity::XxHash64'.
+    XxHash64,
+    /// Hash of 'Integrity::Sha1Chain'.
+    Sha1Chain,
+    /// 32 bytes hash of 'Integrity::Sha256Chain', 'Integrity::Blake3Chain' or tag of 'Integrity::HmacSha256Chain',
+    /// they can't be told apart without the previous hash.
+    Hash256Chain,
+}
+
+/// Settings of 'Cfg' without callbacks, sinks and shipping, returned by 'Cfg::describe'.
+/// Can be compared and stored in application config, and converted back to 'Cfg' without callbacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgDescription {
+    /// Format of stored data.
+    pub format: FormatKind,
+    /// Method of controlling the integrity.
+    pub integrity: Option<Integrity>,
+    /// Current version of schema of the value.
+    pub value_schema_version: Option<u32>,
+    /// Skip inserts of identical values.
+    pub skip_identical_inserts: bool,
+    /// Implementation of the channel to the background thread writing to the file.
+    pub write_channel: WriteChannel,
+    /// Max count of not yet written operations, writing methods wait when it's reached.
+    pub max_pending_writes: Option<usize>,
+    /// Count of records between calls of callback of progress of loading.
+    pub load_progress_interval: usize,
+    /// Max count of entries of 'BoundedMap'.
+    pub max_entries: Option<usize>,
+    /// Min size of compressed values.
+    pub compress_values_over: Option<usize>,
+    /// Max size of record with value.
+    pub max_value_size: Option<usize>,
+    /// Skip consecutive duplicates of inserts.
+    pub dedupe_consecutive: bool,
+    /// Fingerprint of key and value types.
+    pub schema_fingerprint: Option<String>,
+    /// Fail loading on records impossible for the map.
+    pub strict_replay: bool,
+    /// Collect records impossible for the map when loading.
+    pub collect_replay_anomalies: bool,
+    /// Write marker file of unclean shutdown while the map is open.
+    pub dirty_marker: bool,
+    /// Keep head of chained integrity in the head file.
+    pub chain_head_file: bool,
+    /// Aliases of names of operations of the text format.
+    pub extra_text_ops: Vec<(String, RecordKind)>,
+    /// Recovery from records which can't be deserialized.
+    pub on_deserialize_error: DeserializePolicy,
+    /// Writing in the background thread or in the calling thread.
+    pub write_mode: WriteMode,
+    /// When written data is synced to disk.
+    pub fsync_policy: FsyncPolicy,
+    /// Repeating of failed writes.
+    pub write_retry: Option<RetryPolicy>,
+    /// Max time of waiting of stop of writing.
+    pub stop_timeout: Option<Duration>,
+    /// Threshold of automatic compaction.
+    pub auto_compact: Option<AutoCompact>,
+    /// Snapshot file of the map.
+    pub snapshot: Option<SnapshotCfg>,
+    /// Recovery from invalid records when opening.
+    pub recovery: RecoveryMode,
+}
+
+impl Cfg {
+    /// Returns settings of config except callbacks, sinks and shipping.
+    pub fn describe(&self) -> CfgDescription {
+        CfgDescription {
+            format: match self.format {
+                Format::Text(..) => FormatKind::Text,
+                Format::Bin(..) => FormatKind::Bin,
+            },
+            integrity: self.integrity.clone(),
+            value_schema_version: self.value_schema_version,
+            skip_identical_inserts: self.skip_identical_inserts,
+            write_channel: self.write_channel,
+            max_pending_writes: self.max_pending_writes,
+            load_progress_interval: self.load_progress_interval,
+            max_entries: self.max_entries,
+            compress_values_over: self.compress_values_over,
+            max_value_size: self.max_value_size,
+            dedupe_consecutive: self.dedupe_consecutive,
+            schema_fingerprint: self.schema_fingerprint.clone(),
+            strict_replay: self.strict_replay,
+            collect_replay_anomalies: self.collect_replay_anomalies,
+            dirty_marker: self.dirty_marker,
+            chain_head_file: self.chain_head_file,
+            extra_text_ops: self.extra_text_ops.clone(),
+            on_deserialize_error: self.on_deserialize_error,
+            write_mode: self.write_mode,
+            fsync_policy: self.fsync_policy,
+            write_retry: self.write_retry,
+            stop_timeout: self.stop_timeout,
+            auto_compact: self.auto_compact,
+            snapshot: self.snapshot,
+            recovery: self.recovery,
+        }
+    }
+
+    /// Take callback of write errors, deprecated 'write_error_callback' is used only if there is no callback with context.
+    #[allow(deprecated)]
+    pub(crate) fn take_write_error_callback(&mut self) -> Option<WriteErrorCallback> {
+        let error_callback = self.write_error_callback.take();
+        match self.write_error_context_callback.take() {
+            Some(callback) => Some(callback),
+            None => error_callback.map(|mut error_callback| Box::new(move |context: WriteErrorContext| error_callback(context.error)) as WriteErrorCallback),
+        }
+    }
+
+    /// Returns copy of config where callbacks, secondary sink and log shipping are not set
+    /// because they can't be cloned. Flag 'load_cancel' and 'clock' are shared with the copy.
+    pub fn clone_without_callbacks(&self) -> Cfg {
+        let mut cfg = Cfg::from(self.describe());
+        cfg.load_cancel = self.load_cancel.clone();
+        cfg.clock = self.clock.clone();
+        cfg
+    }
+}
+
+impl From<CfgDescription> for Cfg {
+    /// Config with settings of description, other settings are default.
+    fn from(description: CfgDescription) -> Self {
+        Cfg {
+            format: match description.format {
+                FormatKind::Text => Format::Text(None, None),
+                FormatKind::Bin => Format::Bin(None, None),
+            },
+            integrity: description.integrity,
+            value_schema_version: description.value_schema_version,
+            skip_identical_inserts: description.skip_identical_inserts,
+            write_channel: description.write_channel,
+            max_pending_writes: description.max_pending_writes,
+            load_progress_interval: description.load_progress_interval,
+            max_entries: description.max_entries,
+            compress_values_over: description.compress_values_over,
+            max_value_size: description.max_value_size,
+            dedupe_consecutive: description.dedupe_consecutive,
+            schema_fingerprint: description.schema_fingerprint,
+            strict_replay: description.strict_replay,
+            collect_replay_anomalies: description.collect_replay_anomalies,
+            dirty_marker: description.dirty_marker,
+            chain_head_file: description.chain_head_file,
+            extra_text_ops: description.extra_text_ops,
+            on_deserialize_error: description.on_deserialize_error,
+            write_mode: description.write_mode,
+            fsync_policy: description.fsync_policy,
+            write_retry: description.write_retry,
+            stop_timeout: description.stop_timeout,
+            auto_compact: description.auto_compact,
+            snapshot: description.snapshot,
+            recovery: description.recovery,
+            ..Cfg::default()
+        }
+    }
+}
+
+impl std::fmt::Debug for Cfg {
+    /// Callbacks, sinks and shipping are printed only as presence.
+    #[allow(deprecated)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cfg")
+            .field("format", &self.format)
+            .field("integrity", &self.integrity)
+            .field("write_error_callback", &self.write_error_callback.is_some())
+            .field("write_error_context_callback", &self.write_error_context_callback.is_some())
+            .field("write_ack_callback", &self.write_ack_callback.is_some())
+            .field("secondary_sink", &self.secondary_sink.is_some())
+            .field("secondary_sink_error_callback", &self.secondary_sink_error_callback.is_some())
+            .field("log_shipping", &self.log_shipping.is_some())
+            .field("on_worker_failure", &self.on_worker_failure.is_some())
+            .field("value_schema_version", &self.value_schema_version)
+            .field("value_migrator", &self.value_migrator.is_some())
+            .field("skip_identical_inserts", &self.skip_identical_inserts)
+            .field("write_channel", &self.write_channel)
+            .field("max_pending_writes", &self.max_pending_writes)
+            .field("load_cancel", &self.load_cancel)
+            .field("load_progress_callback", &self.load_progress_callback.is_some())
+            .field("load_progress_interval", &self.load_progress_interval)
+            .field("max_entries", &self.max_entries)
+            .field("compress_values_over", &self.compress_values_over)
+            .field("max_value_size", &self.max_value_size)
+            .field("dedupe_consecutive", &self.dedupe_consecutive)
+            .field("schema_fingerprint", &self.schema_fingerprint)
+            .field("strict_replay", &self.strict_replay)
+            .field("collect_replay_anomalies", &self.collect_replay_anomalies)
+            .field("dirty_marker", &self.dirty_marker)
+            .field("chain_head_file", &self.chain_head_file)
+            .field("extra_text_ops", &self.extra_text_ops)
+            .field("on_deserialize_error", &self.on_deserialize_error)
+            .field("write_mode", &self.write_mode)
+            .field("fsync_policy", &self.fsync_policy)
+            .field("write_retry", &self.write_retry)
+            .field("stop_timeout", &self.stop_timeout)
+            .field("auto_compact", &self.auto_compact)
+            .field("snapshot", &self.snapshot)
+            .field("recovery", &self.recovery)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Format {
+    /// Callbacks are printed only as presence.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, before_write, after_read) = match self {
+            Format::Text(before_write, after_read) => ("Text", before_write.is_some(), after_read.is_some()),
+            Format::Bin(before_write, after_read) => ("Bin", before_write.is_some(), after_read.is_some()),
+        };
+        f.debug_struct(name)
+            .field("before_write_callback", &before_write)
+            .field("after_read_callback", &after_read)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Integrity {
+    /// Hash of chain is printed in hex, key of HMAC is not printed.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Integrity::Crc32 => f.write_str("Crc32"),
+            Integrity::XxHash64 => f.write_str("XxHash64"),
+            Integrity::Sha1Chain(hash) => f.debug_tuple("Sha1Chain").field(&hex::encode(hash)).finish(),
+            Integrity::Sha256Chain(hash) => f.debug_tuple("Sha256Chain").field(&hex::encode(hash)).finish(),
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(hash) => f.debug_tuple("Blake3Chain").field(&hex::encode(hash)).finish(),
+            Integrity::HmacSha256Chain { prev, .. } => f.debug_struct("HmacSha256Chain").field("key", &"<hidden>").field("prev", &hex::encode(prev)).finish(),
+        }
+    }
+}
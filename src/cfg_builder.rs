@@ -0,0 +1,380 @@
+use crate::cfg::{AfterReadBinCallback, AfterReadTxtCallback, AutoCompact, BeforeWriteBinCallback, BeforeWriteTxtCallback, Cfg, DeserializePolicy, Format, FsyncPolicy, Integrity, LoadProgress, RecoveryMode, RetryPolicy, SnapshotCfg, ValueMigrator, WorkerFailure, WriteAck, WriteChannel, WriteErrorContext, WriteMode};
+use crate::clock::Clock;
+use crate::format::RecordKind;
+use crate::log_shipper::LogShipping;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Builder of 'Cfg' with validation of combinations of settings, see 'Cfg::builder'.
+/// Default settings are the same as 'Cfg::default'.
+pub struct CfgBuilder {
+    cfg: Cfg,
+}
+
+/// Errors of validation of config by 'CfgBuilder::build'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfgError {
+    /// Only one of 'before write' and 'after read' callbacks of the format is set.
+    /// Records transformed before write can't be read without reverse transform after read
+    /// (and integrity is checked after 'after read' callback), so both or none of them must be set.
+    UnpairedFormatCallbacks,
+    /// Value migrator is set without 'value_schema_version', so it would never be called.
+    ValueMigratorWithoutSchemaVersion,
+    /// Callback of errors of secondary sink is set without secondary sink.
+    SinkErrorCallbackWithoutSink,
+    /// Log shipping has zero 'max_batch_bytes' or 'min_retry_delay' is greater than 'max_retry_delay'.
+    WrongLogShippingLimits,
+    /// Max count of entries is zero.
+    ZeroMaxEntries,
+    /// Alias of name of operation of the text format is empty, contains whitespace or is "met" of metadata.
+    WrongTextOpAlias,
+    /// 'FsyncPolicy::EveryNOps' has zero count or 'FsyncPolicy::Interval' has zero duration.
+    WrongFsyncPolicy,
+    /// Max count of not yet written operations is zero.
+    ZeroMaxPendingWrites,
+    /// Repeating of failed writes has zero count of attempts.
+    ZeroRetryAttempts,
+    /// 'AutoCompact::garbage_ratio' is not greater than 0 or greater than 1.
+    WrongAutoCompactRatio,
+    /// 'SnapshotCfg::checkpoint_ops' is 0.
+    ZeroCheckpointOps,
+    /// Count of records between calls of callback of progress of loading is zero.
+    ZeroLoadProgressInterval,
+}
+
+impl std::error::Error for CfgError {}
+
+impl std::fmt::Display for CfgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Cfg {
+    /// Returns builder of config with validation of combinations of settings.
+    /// Config can be still constructed directly by fields without validation.
+    pub fn builder() -> CfgBuilder {
+        CfgBuilder { cfg: Cfg::default() }
+    }
+}
+
+impl CfgBuilder {
+    /// Text format without callbacks.
+    pub fn text_format(self) -> Self {
+        self.format(Format::Text(None, None))
+    }
+
+    /// Binary format without callbacks.
+    pub fn bin_format(self) -> Self {
+        self.format(Format::Bin(None, None))
+    }
+
+    /// Text format with callbacks for transformation of records, both or none must be set.
+    pub fn text_format_with_callbacks(self, before_write: Option<BeforeWriteTxtCallback>, after_read: Option<AfterReadTxtCallback>) -> Self {
+        self.format(Format::Text(before_write, after_read))
+    }
+
+    /// Binary format with callbacks for transformation of records, both or none must be set.
+    pub fn bin_format_with_callbacks(self, before_write: Option<BeforeWriteBinCallback>, after_read: Option<AfterReadBinCallback>) -> Self {
+        self.format(Format::Bin(before_write, after_read))
+    }
+
+    /// Crc32 checksum of each record.
+    pub fn crc32(self) -> Self {
+        self.integrity(Some(Integrity::Crc32))
+    }
+
+    /// xxHash64 checksum of each record.
+    pub fn xxhash64(self) -> Self {
+        self.integrity(Some(Integrity::XxHash64))
+    }
+
+    /// Sha1 blockchain of records beginning with 'genesis' hash.
+    pub fn sha1_chain(self, genesis: [u8; 20]) -> Self {
+        self.integrity(Some(Integrity::Sha1Chain(genesis)))
+    }
+
+    /// Sha256 blockchain of records beginning with 'genesis' hash.
+    pub fn sha256_chain(self, genesis: [u8; 32]) -> Self {
+        self.integrity(Some(Integrity::Sha256Chain(genesis)))
+    }
+
+    /// HMAC-SHA256 blockchain of records with secret 'key' beginning with 'genesis' tag.
+    pub fn hmac_sha256_chain(self, key: Vec<u8>, genesis: [u8; 32]) -> Self {
+        self.integrity(Some(Integrity::HmacSha256Chain { key, prev: genesis }))
+    }
+
+    /// BLAKE3 blockchain of records beginning with 'genesis' hash.
+    #[cfg(feature = "blake3")]
+    pub fn blake3_chain(self, genesis: [u8; 32]) -> Self {
+        self.integrity(Some(Integrity::Blake3Chain(genesis)))
+    }
+
+    /// Method of controlling the integrity, None for records without integrity.
+    pub fn integrity(mut self, integrity: Option<Integrity>) -> Self {
+        self.cfg.integrity = integrity;
+        self
+    }
+
+    /// Callback for receive a file write error with context of the failed write.
+    pub fn write_error(mut self, callback: impl FnMut(WriteErrorContext) + Send + 'static) -> Self {
+        self.cfg.write_error_context_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Callback for receive confirmation of each successful write to the file.
+    pub fn write_ack(mut self, callback: impl FnMut(WriteAck) + Send + 'static) -> Self {
+        self.cfg.write_ack_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Additional writer where each record is written after writing to the file.
+    pub fn secondary_sink(mut self, sink: impl std::io::Write + Send + 'static) -> Self {
+        self.cfg.secondary_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Callback for receive a secondary sink write error, requires 'secondary_sink'.
+    pub fn secondary_sink_error(mut self, callback: impl FnMut(std::io::Error) + Send + 'static) -> Self {
+        self.cfg.secondary_sink_error_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Shipping of records written to the file.
+    pub fn log_shipping(mut self, log_shipping: LogShipping) -> Self {
+        self.cfg.log_shipping = Some(log_shipping);
+        self
+    }
+
+    /// Callback for receive failure of the background thread of writing.
+    pub fn on_worker_failure(mut self, callback: impl FnMut(WorkerFailure) + Send + 'static) -> Self {
+        self.cfg.on_worker_failure = Some(Box::new(callback));
+        self
+    }
+
+    /// Current version of schema of the value.
+    pub fn value_schema_version(mut self, version: u32) -> Self {
+        self.cfg.value_schema_version = Some(version);
+        self
+    }
+
+    /// Converter of values of records with older version, requires 'value_schema_version'.
+    pub fn value_migrator(mut self, migrator: ValueMigrator) -> Self {
+        self.cfg.value_migrator = Some(migrator);
+        self
+    }
+
+    /// Don't write insert of value serialized the same as current value of the key.
+    pub fn skip_identical_inserts(mut self, skip: bool) -> Self {
+        self.cfg.skip_identical_inserts = skip;
+        self
+    }
+
+    /// Implementation of the channel to the background thread writing to the file.
+    pub fn write_channel(mut self, write_channel: WriteChannel) -> Self {
+        self.cfg.write_channel = write_channel;
+        self
+    }
+
+    /// Max count of not yet written operations, writing methods wait when it's reached and 'MapWithFile::try_insert' returns pending.
+    pub fn max_pending_writes(mut self, max_pending_writes: usize) -> Self {
+        self.cfg.max_pending_writes = Some(max_pending_writes);
+        self
+    }
+
+    /// Flag for cancel of loading of the file.
+    pub fn load_cancel(mut self, load_cancel: Arc<AtomicBool>) -> Self {
+        self.cfg.load_cancel = Some(load_cancel);
+        self
+    }
+
+    /// Callback of progress of loading of the file called every 'interval' records, loading is interrupted if it returns error.
+    pub fn load_progress(mut self, interval: usize, callback: impl FnMut(LoadProgress) -> Result<(), ()> + Send + 'static) -> Self {
+        self.cfg.load_progress_callback = Some(Box::new(callback));
+        self.cfg.load_progress_interval = interval;
+        self
+    }
+
+    /// Max count of entries of 'BoundedMap'.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.cfg.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Compress values longer than 'max_len' bytes after serialization.
+    pub fn compress_values_over(mut self, max_len: usize) -> Self {
+        self.cfg.compress_values_over = Some(max_len);
+        self
+    }
+
+    /// Max size in bytes of record with value in the file.
+    pub fn max_value_size(mut self, max_size: usize) -> Self {
+        self.cfg.max_value_size = Some(max_size);
+        self
+    }
+
+    /// Skip inserts with record the same as the last written record.
+    pub fn dedupe_consecutive(mut self, dedupe: bool) -> Self {
+        self.cfg.dedupe_consecutive = dedupe;
+        self
+    }
+
+    /// Fingerprint of key and value types written to created file and checked when opening.
+    pub fn schema_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.cfg.schema_fingerprint = Some(fingerprint.into());
+        self
+    }
+
+    /// Fail loading on records impossible for the map.
+    pub fn strict_replay(mut self, strict: bool) -> Self {
+        self.cfg.strict_replay = strict;
+        self
+    }
+
+    /// Collect records impossible for the map when loading instead of error.
+    pub fn collect_replay_anomalies(mut self, collect: bool) -> Self {
+        self.cfg.collect_replay_anomalies = collect;
+        self
+    }
+
+    /// Write marker file of unclean shutdown while the map is open.
+    pub fn dirty_marker(mut self, dirty_marker: bool) -> Self {
+        self.cfg.dirty_marker = dirty_marker;
+        self
+    }
+
+    /// Keep head of chained integrity in the head file for detection of removed records at the end of the file.
+    pub fn chain_head_file(mut self, chain_head_file: bool) -> Self {
+        self.cfg.chain_head_file = chain_head_file;
+        self
+    }
+
+    /// Accept 'name' of operation of the text format as alias of the canonical name of 'kind' when loading.
+    pub fn extra_text_op(mut self, name: &str, kind: RecordKind) -> Self {
+        self.cfg.extra_text_ops.push((name.to_string(), kind));
+        self
+    }
+
+    /// Recovery from records which key or value can't be deserialized when loading.
+    pub fn on_deserialize_error(mut self, policy: DeserializePolicy) -> Self {
+        self.cfg.on_deserialize_error = policy;
+        self
+    }
+
+    /// What to do when opening finds invalid record.
+    pub fn recovery(mut self, recovery: RecoveryMode) -> Self {
+        self.cfg.recovery = recovery;
+        self
+    }
+
+    /// Writing to the file in the background thread or in the calling thread.
+    pub fn write_mode(mut self, write_mode: WriteMode) -> Self {
+        self.cfg.write_mode = write_mode;
+        self
+    }
+
+    /// When written data is synced to disk.
+    pub fn fsync_policy(mut self, fsync_policy: FsyncPolicy) -> Self {
+        self.cfg.fsync_policy = fsync_policy;
+        self
+    }
+
+    /// Repeating of failed writes to the file before the error is passed to the write error callback.
+    pub fn write_retry(mut self, write_retry: RetryPolicy) -> Self {
+        self.cfg.write_retry = Some(write_retry);
+        self
+    }
+
+    /// Max time of waiting of stop of writing when the map is dropped or closed.
+    pub fn stop_timeout(mut self, stop_timeout: Duration) -> Self {
+        self.cfg.stop_timeout = Some(stop_timeout);
+        self
+    }
+
+    /// Source of current time and waiting, for example 'testing::ManualClock'.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.cfg.clock = Arc::new(clock);
+        self
+    }
+
+    /// Compaction of the file when most of records are dead.
+    pub fn auto_compact(mut self, auto_compact: AutoCompact) -> Self {
+        self.cfg.auto_compact = Some(auto_compact);
+        self
+    }
+
+    /// Snapshot file of the map with the log of operations after it.
+    pub fn snapshot(mut self, snapshot: SnapshotCfg) -> Self {
+        self.cfg.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Returns config if combination of settings is correct.
+    pub fn build(self) -> Result<Cfg, CfgError> {
+        let cfg = self.cfg;
+
+        let callbacks_paired = match &cfg.format {
+            Format::Text(before_write, after_read) => before_write.is_some() == after_read.is_some(),
+            Format::Bin(before_write, after_read) => before_write.is_some() == after_read.is_some(),
+        };
+        if !callbacks_paired {
+            return Err(CfgError::UnpairedFormatCallbacks);
+        }
+
+        if cfg.value_migrator.is_some() && cfg.value_schema_version.is_none() {
+            return Err(CfgError::ValueMigratorWithoutSchemaVersion);
+        }
+
+        if cfg.secondary_sink_error_callback.is_some() && cfg.secondary_sink.is_none() {
+            return Err(CfgError::SinkErrorCallbackWithoutSink);
+        }
+
+        if let Some(log_shipping) = &cfg.log_shipping {
+            if log_shipping.max_batch_bytes == 0 || log_shipping.min_retry_delay > log_shipping.max_retry_delay {
+                return Err(CfgError::WrongLogShippingLimits);
+            }
+        }
+
+        if cfg.max_entries == Some(0) {
+            return Err(CfgError::ZeroMaxEntries);
+        }
+
+        if cfg.max_pending_writes == Some(0) {
+            return Err(CfgError::ZeroMaxPendingWrites);
+        }
+
+        // alias is the first word of the line, metadata is also at the start of the line
+        if cfg.extra_text_ops.iter().any(|(name, _)| name.is_empty() || name.contains(char::is_whitespace) || name == "met") {
+            return Err(CfgError::WrongTextOpAlias);
+        }
+
+        if matches!(cfg.fsync_policy, FsyncPolicy::EveryNOps(0)) || cfg.fsync_policy == FsyncPolicy::Interval(Duration::ZERO) {
+            return Err(CfgError::WrongFsyncPolicy);
+        }
+
+        if matches!(cfg.write_retry, Some(RetryPolicy { attempts: 0, .. })) {
+            return Err(CfgError::ZeroRetryAttempts);
+        }
+
+        if matches!(cfg.auto_compact, Some(auto_compact) if !(auto_compact.garbage_ratio > 0.0 && auto_compact.garbage_ratio <= 1.0)) {
+            return Err(CfgError::WrongAutoCompactRatio);
+        }
+
+        if matches!(cfg.snapshot, Some(SnapshotCfg { checkpoint_ops: Some(0) })) {
+            return Err(CfgError::ZeroCheckpointOps);
+        }
+
+        if cfg.load_progress_interval == 0 {
+            return Err(CfgError::ZeroLoadProgressInterval);
+        }
+
+        Ok(cfg)
+    }
+
+    /// Set format.
+    fn format(mut self, format: Format) -> Self {
+        self.cfg.format = format;
+        self
+    }
+}
@@ -0,0 +1,159 @@
+use crate::cfg::Integrity;
+use std::convert::TryFrom;
+use std::path::Path;
+
+/// Genesis or head hash of chained integrity with its algorithm,
+/// for storing the genesis of a new file or the head of the chain after the last record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainAnchor {
+    /// Hash of 'Integrity::Sha1Chain'.
+    Sha1Chain([u8; 20]),
+    /// Hash of 'Integrity::Sha256Chain'.
+    Sha256Chain([u8; 32]),
+    /// Hash of 'Integrity::Blake3Chain'.
+    #[cfg(feature = "blake3")]
+    Blake3Chain([u8; 32]),
+    /// Tag of 'Integrity::HmacSha256Chain', without the key.
+    HmacSha256Chain([u8; 32]),
+}
+
+/// Error of parsing, loading or using of 'ChainAnchor'.
+#[derive(Debug)]
+pub enum ChainAnchorError {
+    /// When hash is not valid hex.
+    HexError(hex::FromHexError),
+    /// When hash length in bytes doesn't match any algorithm or the algorithm of the anchor file.
+    WrongLength { len: usize, },
+    /// When algorithm of the anchor file is unknown.
+    UnknownAlgorithm(String),
+    /// When algorithm of the anchor is not the expected one.
+    WrongAlgorithm { expected: &'static str, found: &'static str, },
+    /// When anchor file has no algorithm and hash.
+    WrongFileFormat,
+    /// Read or write anchor file error.
+    FileError(std::io::Error),
+}
+
+impl std::error::Error for ChainAnchorError {}
+
+impl std::fmt::Display for ChainAnchorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl ChainAnchor {
+    /// Name of algorithm, the same as 'Integrity::name'.
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            ChainAnchor::Sha1Chain(_) => "sha1_chain",
+            ChainAnchor::Sha256Chain(_) => "sha256_chain",
+            #[cfg(feature = "blake3")]
+            ChainAnchor::Blake3Chain(_) => "blake3_chain",
+            ChainAnchor::HmacSha256Chain(_) => "hmac_sha256_chain",
+        }
+    }
+
+    /// Returns hash bytes.
+    pub fn hash(&self) -> &[u8] {
+        match self {
+            ChainAnchor::Sha1Chain(hash) => hash,
+            ChainAnchor::Sha256Chain(hash) => hash,
+            #[cfg(feature = "blake3")]
+            ChainAnchor::Blake3Chain(hash) => hash,
+            ChainAnchor::HmacSha256Chain(hash) => hash,
+        }
+    }
+
+    /// Hash in lowercase hex.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.hash())
+    }
+
+    /// Anchor from hex of hash, algorithm is chosen by length: 20 bytes for Sha1, 32 bytes for Sha256,
+    /// so hash of BLAKE3 and tag of HMAC are parsed as Sha256 by it, file of 'save' keeps the algorithm.
+    pub fn from_hex(hex: &str) -> Result<Self, ChainAnchorError> {
+        let bytes = hex::decode(hex.trim()).map_err(ChainAnchorError::HexError)?;
+        if let Ok(hash) = <[u8; 20]>::try_from(bytes.as_slice()) {
+            return Ok(ChainAnchor::Sha1Chain(hash));
+        }
+        <[u8; 32]>::try_from(bytes.as_slice())
+            .map(ChainAnchor::Sha256Chain)
+            .map_err(|_| ChainAnchorError::WrongLength { len: bytes.len() })
+    }
+
+    /// Write anchor file with one line "<algorithm> <hex>".
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ChainAnchorError> {
+        std::fs::write(path, format!("{} {}\n", self.algorithm(), self.to_hex()))
+            .map_err(ChainAnchorError::FileError)
+    }
+
+    /// Read anchor file written by 'save', length of hash must match the algorithm.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ChainAnchorError> {
+        let content = std::fs::read_to_string(path).map_err(ChainAnchorError::FileError)?;
+        let (algorithm, hex) = content.trim().split_once(' ')
+            .ok_or(ChainAnchorError::WrongFileFormat)?;
+        let anchor = ChainAnchor::from_hex(hex)?;
+        match algorithm {
+            "sha1_chain" | "sha256_chain" if algorithm == anchor.algorithm() => Ok(anchor),
+            #[cfg(feature = "blake3")]
+            "blake3_chain" => match anchor {
+                ChainAnchor::Sha256Chain(hash) => Ok(ChainAnchor::Blake3Chain(hash)),
+                _ => Err(ChainAnchorError::WrongLength { len: anchor.hash().len() }),
+            },
+            "hmac_sha256_chain" => match anchor {
+                ChainAnchor::Sha256Chain(hash) => Ok(ChainAnchor::HmacSha256Chain(hash)),
+                _ => Err(ChainAnchorError::WrongLength { len: anchor.hash().len() }),
+            },
+            "sha1_chain" | "sha256_chain" => Err(ChainAnchorError::WrongLength { len: anchor.hash().len() }),
+            _ => Err(ChainAnchorError::UnknownAlgorithm(algorithm.to_string())),
+        }
+    }
+}
+
+impl Integrity {
+    /// Sha1 blockchain beginning with hash of 'anchor'.
+    pub fn sha1_from_anchor(anchor: &ChainAnchor) -> Result<Self, ChainAnchorError> {
+        match anchor {
+            ChainAnchor::Sha1Chain(hash) => Ok(Integrity::Sha1Chain(*hash)),
+            _ => Err(ChainAnchorError::WrongAlgorithm { expected: "sha1_chain", found: anchor.algorithm() }),
+        }
+    }
+
+    /// Sha256 blockchain beginning with hash of 'anchor'.
+    pub fn sha256_from_anchor(anchor: &ChainAnchor) -> Result<Self, ChainAnchorError> {
+        match anchor {
+            ChainAnchor::Sha256Chain(hash) => Ok(Integrity::Sha256Chain(*hash)),
+            _ => Err(ChainAnchorError::WrongAlgorithm { expected: "sha256_chain", found: anchor.algorithm() }),
+        }
+    }
+
+    /// HMAC-SHA256 blockchain with secret 'key' beginning with tag of 'anchor'.
+    pub fn hmac_sha256_from_anchor(key: Vec<u8>, anchor: &ChainAnchor) -> Result<Self, ChainAnchorError> {
+        match anchor {
+            ChainAnchor::HmacSha256Chain(tag) => Ok(Integrity::HmacSha256Chain { key, prev: *tag }),
+            _ => Err(ChainAnchorError::WrongAlgorithm { expected: "hmac_sha256_chain", found: anchor.algorithm() }),
+        }
+    }
+
+    /// BLAKE3 blockchain beginning with hash of 'anchor'.
+    #[cfg(feature = "blake3")]
+    pub fn blake3_from_anchor(anchor: &ChainAnchor) -> Result<Self, ChainAnchorError> {
+        match anchor {
+            ChainAnchor::Blake3Chain(hash) => Ok(Integrity::Blake3Chain(*hash)),
+            _ => Err(ChainAnchorError::WrongAlgorithm { expected: "blake3_chain", found: anchor.algorithm() }),
+        }
+    }
+
+    /// Current hash of chained integrity, None for 'Integrity::Crc32' and 'Integrity::XxHash64'.
+    pub fn anchor(&self) -> Option<ChainAnchor> {
+        match self {
+            Integrity::Crc32 | Integrity::XxHash64 => None,
+            Integrity::Sha1Chain(hash) => Some(ChainAnchor::Sha1Chain(*hash)),
+            Integrity::Sha256Chain(hash) => Some(ChainAnchor::Sha256Chain(*hash)),
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(hash) => Some(ChainAnchor::Blake3Chain(*hash)),
+            Integrity::HmacSha256Chain { prev, .. } => Some(ChainAnchor::HmacSha256Chain(*prev)),
+        }
+    }
+}
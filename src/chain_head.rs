@@ -0,0 +1,144 @@
+use crate::cfg::{Cfg, Format, Integrity};
+use crate::chain_anchor::ChainAnchor;
+use crate::format::IntegrityError;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::LoadFileError;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Head file "<file>.head" of 'Cfg::chain_head_file' with one line "<algorithm> <hex of hash> <length of the file>",
+/// the hash is at the end of the record ending at the length, it's hash of chained integrity after this record.
+pub(crate) struct ChainHeadFile {
+    /// Path of the head file.
+    path: PathBuf,
+    /// Name of algorithm of chained integrity.
+    algorithm: &'static str,
+    /// Length of hash in bytes.
+    hash_len: usize,
+    /// Hash is in hex before end of line in the text format and as is at the end of block in the binary format.
+    text: bool,
+}
+
+impl ChainHeadFile {
+    /// Head file of the file of the map, None if 'Cfg::chain_head_file' is not set or integrity is not chained.
+    pub(crate) fn of(file_path: &Path, cfg: &Cfg) -> Option<Self> {
+        if !cfg.chain_head_file {
+            return None;
+        }
+        let integrity = cfg.integrity.as_ref()?;
+        let anchor = integrity.anchor()?;
+
+        let mut path = file_path.as_os_str().to_owned();
+        path.push(".head");
+        Some(ChainHeadFile {
+            path: PathBuf::from(path),
+            algorithm: integrity.name(),
+            hash_len: anchor.hash().len(),
+            text: matches!(cfg.format, Format::Text(..)),
+        })
+    }
+
+    /// Save hash at the end of 'data' written to the file, length of the file is 'file_len' after it.
+    pub(crate) fn save_after_write(&self, data: &[u8], file_len: u64) -> std::io::Result<()> {
+        let hash = self.hash_at_end(data)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no hash of chain at the end of written record"))?;
+        self.save(&hash, file_len)
+    }
+
+    /// Replace the head file via tmp file, so it has the old or the new head after crash.
+    pub(crate) fn save(&self, hash: &[u8], file_len: u64) -> std::io::Result<()> {
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut tmp_file = fs::File::create(&tmp_path)?;
+        tmp_file.write_all(format!("{} {} {}\n", self.algorithm, hex::encode(hash), file_len).as_bytes())?;
+        tmp_file.sync_data()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    /// Save hash at the end of the file of 'file_len' at 'file_path', 'genesis' is saved for the empty file.
+    pub(crate) fn save_of_file(&self, file_path: &Path, file_len: u64, genesis: Option<ChainAnchor>) -> std::io::Result<()> {
+        let hash = match file_len {
+            0 => genesis.map(|genesis| genesis.hash().to_vec()),
+            _ => self.hash_of_record_ending_at(&fs::File::open(file_path)?, file_len)?,
+        };
+        let hash = hash.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no hash of chain at the end of the file"))?;
+        self.save(&hash, file_len)
+    }
+
+    /// Check that 'valid_len' of the file is not less than the length in the head file and the record at this length ends with the hash,
+    /// 'genesis' is expected if the head is saved for the empty file. Nothing is checked if the head file doesn't exist.
+    /// 'records' is count of loaded records for the error. Position of 'file' is not changed.
+    pub(crate) fn check(&self, file: &fs::File, valid_len: u64, genesis: Option<ChainAnchor>, records: usize) -> Result<(), LoadFileError> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(LoadFileError::FileError(err)),
+        };
+        let (hash, head_len) = self.parse(&content)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("wrong head file {}", self.path.display())))?;
+
+        let truncated = LoadFileError::IntegrityError(IntegrityError::ChainTruncated { line_num: records });
+        if head_len > valid_len {
+            return Err(truncated);
+        }
+        let hash_in_file = match head_len {
+            0 => genesis.map(|genesis| genesis.hash().to_vec()),
+            _ => self.hash_of_record_ending_at(file, head_len)?,
+        };
+
+        match hash_in_file {
+            Some(hash_in_file) if hash_in_file == hash => Ok(()),
+            _ => Err(truncated),
+        }
+    }
+
+    /// Returns hash and length of the file from content of the head file, None if it's wrong or of other algorithm.
+    fn parse(&self, content: &str) -> Option<(Vec<u8>, u64)> {
+        let mut parts = content.split_whitespace();
+        let (algorithm, hash, len) = (parts.next()?, parts.next()?, parts.next()?);
+        if algorithm != self.algorithm || parts.next().is_some() {
+            return None;
+        }
+        let hash = hex::decode(hash).ok().filter(|hash| hash.len() == self.hash_len)?;
+        Some((hash, len.parse().ok()?))
+    }
+
+    /// Returns hash at the end of the record ending at 'len' of the file, None if there is no hash. Position of 'file' is not changed.
+    fn hash_of_record_ending_at(&self, mut file: &fs::File, len: u64) -> std::io::Result<Option<Vec<u8>>> {
+        let record_end_len = if self.text { self.hash_len * 2 + 1 } else { self.hash_len };
+        let start = match len.checked_sub(record_end_len as u64) {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let mut record_end = vec![0; record_end_len];
+        let position = file.stream_position()?;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut record_end)?;
+        file.seek(SeekFrom::Start(position))?;
+        Ok(self.hash_at_end(&record_end))
+    }
+
+    /// Returns hash at the end of the record, None if there is no hash.
+    fn hash_at_end(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if self.text {
+            let line = data.strip_suffix(b"\n")?;
+            let hex = line.get(line.len().checked_sub(self.hash_len * 2)?..)?;
+            hex::decode(hex).ok()
+        } else {
+            data.get(data.len().checked_sub(self.hash_len)?..).map(<[u8]>::to_vec)
+        }
+    }
+}
+
+impl<Key, Value, Map> MapWithFile<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Save head of the file of 'file_len' replaced by compaction or checkpoint to the head file if 'Cfg::chain_head_file' is set.
+    pub(crate) fn save_chain_head_of_replaced_file(&self, file_len: u64) -> std::io::Result<()> {
+        match ChainHeadFile::of(&self.file_path, &self.cfg.lock()) {
+            Some(chain_head) => chain_head.save_of_file(&self.file_path, file_len, self.integrity_at_file_start.as_ref().and_then(Integrity::anchor)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::time::{Duration, SystemTime};
+
+/// Source of current time and waiting for time-dependent code, see 'Cfg::clock':
+/// expiry of 'TtlMap', 'FsyncPolicy::Interval', delays of 'RetryPolicy' and deadlines of log shipping.
+/// Can be replaced by 'testing::ManualClock' for tests.
+pub trait Clock: Send + Sync {
+    /// Returns current time.
+    fn now(&self) -> SystemTime;
+
+    /// Wait for 'duration', for example before repeat of failed write. Sleeps the calling thread by default.
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    /// Time elapsed since 'earlier', zero if 'earlier' is later than current time.
+    fn elapsed(&self, earlier: SystemTime) -> Duration {
+        self.now().duration_since(earlier).unwrap_or_default()
+    }
+}
+
+/// Clock of system time, default clock of 'Cfg'.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,432 @@
+use crate::cfg::{Cfg, Format, Integrity};
+use crate::format::{create_dirs_to_path_if_not_exist, file_record_of_insert, file_record_of_remove, file_record_of_schema};
+use crate::map_trait::{CloneableMapTrait, MapTrait};
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::mirror::{MirrorId, MirrorSink};
+use crate::snapshot::CheckpointError;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Error of 'MapWithFile::compact' and 'MapWithFile::compact_to'.
+#[derive(Debug)]
+pub enum CompactError {
+    /// Error of writing of changes made before compaction, the file is not compacted.
+    PendingWriteError(std::io::Error),
+    /// Error of serialization of key or value.
+    SerializeError(SerializedError),
+    /// When write error to the tmp file or to the target file.
+    WriteToFileError(std::io::Error),
+    /// Error of creating tmp file or of replacing the file with it.
+    TmpFileError,
+    /// When can't create or open the target file of 'MapWithFile::compact_to'.
+    OpenFileError(std::io::Error),
+    /// When can't exclusive lock the target file of 'MapWithFile::compact_to'.
+    LockFileError,
+    /// Background compaction started by 'MapWithFile::compact_in_background' is not finished yet.
+    InProgress,
+    /// Background compaction is not possible with before write callback of the format,
+    /// because it can't be called from other thread, with 'Cfg::snapshot' and for the map created by 'MapWithFile::in_memory'.
+    BackgroundNotSupported,
+    /// The map is opened by 'MapWithFile::open_read_only', the file is not compacted.
+    ReadOnly,
+}
+
+/// Callback receiving result of background compaction, see 'MapWithFile::compact_in_background'.
+pub type CompactCallback = Box<dyn FnOnce(Result<(), CompactError>) + Send>;
+
+/// Compaction running in the background thread, see 'MapWithFile::compact_in_background'.
+pub(crate) struct BackgroundCompaction<Key> {
+    /// Path of the tmp file written by the thread, it's removed if compaction is not finished.
+    tmp_file_path: PathBuf,
+    /// Result of writing of the tmp file, locked only for making the map Sync.
+    result_receiver: Mutex<Receiver<Result<CompactedFile, CompactError>>>,
+    /// Keys changed after start of compaction, their records are appended to the tmp file before it replaces the file.
+    touched_keys: Arc<Mutex<BTreeSet<Key>>>,
+    /// Mirror of the map filling 'touched_keys'.
+    mirror_id: MirrorId,
+    /// Callback of result, taken when it's called, locked only for making the map Sync.
+    on_complete: Mutex<Option<CompactCallback>>,
+}
+
+/// Tmp file with entries of the map written by the thread of background compaction.
+struct CompactedFile {
+    /// Locked tmp file opened for appending.
+    file: fs::File,
+    /// Length of written data.
+    len: u64,
+    /// Integrity state after the last written record.
+    integrity: Option<Integrity>,
+}
+
+/// Mirror remembering keys changed while background compaction runs.
+struct TouchedKeys<Key>(Arc<Mutex<BTreeSet<Key>>>);
+
+impl<Key: Ord + Clone, Value> MirrorSink<Key, Value> for TouchedKeys<Key> {
+    fn on_insert(&mut self, key: &Key, _: &Value, _: Option<&Value>) {
+        lock_touched_keys(&self.0).insert(key.clone());
+    }
+
+    fn on_remove(&mut self, key: &Key, _: &Value) {
+        lock_touched_keys(&self.0).insert(key.clone());
+    }
+}
+
+impl<Key> Drop for BackgroundCompaction<Key> {
+    fn drop(&mut self) {
+        // not finished compaction leaves nothing, renamed tmp file doesn't exist already
+        let _ = fs::remove_file(&self.tmp_file_path);
+    }
+}
+
+impl std::error::Error for CompactError {}
+
+impl std::fmt::Display for CompactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Rewrite the file with insert records of the current entries only, in order of keys, so history of changes is dropped.
+    /// Changes made before are written first. The file is rewritten via tmp file in the same directory in the format of config
+    /// with chained integrity started again from the hash of config, synced to disk and renamed over the file,
+    /// so crash leaves the old or the new file. The tmp file is locked before renaming and next changes are appended to it.
+    /// Counts of operations of 'metrics' are counted from compaction. Nothing is changed if error is returned before renaming.
+    /// If 'Cfg::snapshot' is set, it's 'checkpoint', because entries of the snapshot are not in the file.
+    /// Data of the map created by 'in_memory' is rewritten in the memory.
+    /// Returns 'CompactError::InProgress' while background compaction is not finished.
+    pub fn compact(&mut self) -> Result<(), CompactError> {
+        if self.background_compaction.is_some() {
+            return Err(CompactError::InProgress);
+        }
+        if self.file_worker.is_read_only() {
+            return Err(CompactError::ReadOnly);
+        }
+        if self.cfg.get_mut().snapshot.is_some() {
+            return self.checkpoint().map_err(|err| match err {
+                CheckpointError::PendingWriteError(err) => CompactError::PendingWriteError(err),
+                CheckpointError::SerializeError(err) => CompactError::SerializeError(err),
+                CheckpointError::WriteToFileError(err) => CompactError::WriteToFileError(err),
+                CheckpointError::NoSnapshotCfg | CheckpointError::TmpFileError => CompactError::TmpFileError,
+                CheckpointError::ReadOnly => CompactError::ReadOnly,
+            });
+        }
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("compact", path = %self.file_path.display()).entered();
+
+        self.flush().map_err(CompactError::PendingWriteError)?;
+        let _file_len = match self.memory_file {
+            Some(_) => self.compact_memory_file()?,
+            None => self.compact_file()?,
+        };
+
+        self.records_loaded = self.map.len() as u64;
+        self.dead_records_loaded = 0;
+        self.operations_since_open = 0;
+        self.last_record = None;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(entries = self.map.len(), file_len = _file_len, "history file compacted");
+
+        Ok(())
+    }
+
+    /// Rewrite the file via tmp file with insert records of the current entries, see 'compact'. Returns length of the new file.
+    fn compact_file(&mut self) -> Result<u64, CompactError> {
+        // tmp file is in the same directory for renaming, it's locked before it replaces the file
+        let mut tmp_file_path = self.file_path.as_os_str().to_owned();
+        tmp_file_path.push(format!(".{}.tmp", Uuid::new_v4()));
+        let tmp_file = fs::OpenOptions::new().read(true).append(true).create_new(true).open(&tmp_file_path)
+            .map_err(|_| CompactError::TmpFileError)?;
+        tmp_file.lock_exclusive().map_err(|_| CompactError::TmpFileError)?;
+
+        let integrity = std::mem::replace(&mut self.cfg.get_mut().integrity, self.integrity_at_file_start.clone());
+        let written = write_entries(&self.map, self.cfg.get_mut(), &tmp_file)
+            .and_then(|len| tmp_file.sync_data().map(|()| len).map_err(CompactError::WriteToFileError))
+            .and_then(|len| fs::rename(&tmp_file_path, &self.file_path).map(|()| len).map_err(|_| CompactError::TmpFileError));
+        let file_len = match written {
+            Ok(file_len) => file_len,
+            Err(err) => {
+                self.cfg.get_mut().integrity = integrity;
+                let _ = fs::remove_file(&tmp_file_path);
+                return Err(err);
+            },
+        };
+
+        // the old file is unlocked when it's replaced
+        self.file_worker.replace_file(tmp_file, file_len).map_err(CompactError::WriteToFileError)?;
+        self.save_chain_head_of_replaced_file(file_len).map_err(CompactError::WriteToFileError)?;
+        Ok(file_len)
+    }
+
+    /// Compact the file if it's time by 'Cfg::auto_compact', error is kept for 'take_last_write_error'.
+    pub(crate) fn compact_if_due(&mut self) {
+        let auto_compact = match self.cfg.get_mut().auto_compact {
+            Some(auto_compact) if self.background_compaction.is_none() => auto_compact,
+            _ => return,
+        };
+        let ops_logged = self.ops_logged();
+        let dead_ops = ops_logged.saturating_sub(self.live_len() as u64);
+        if ops_logged < auto_compact.min_ops as u64 || ops_logged < self.auto_compact_not_before || (dead_ops as f64) < ops_logged as f64 * auto_compact.garbage_ratio {
+            return;
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ops_logged, live_len = self.live_len(), "automatic compaction");
+        if let Err(err) = self.compact() {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %err, "automatic compaction error");
+            let error = match err {
+                CompactError::PendingWriteError(error) | CompactError::WriteToFileError(error) => error,
+                err => std::io::Error::other(err.to_string()),
+            };
+            self.file_worker.counters().set_last_write_error(&error);
+            self.auto_compact_not_before = ops_logged + auto_compact.min_ops as u64;
+        }
+    }
+
+    /// Same as 'compact' but entries are written to the tmp file in the background thread, so map operations are not blocked.
+    /// The map is copied by 'CloneableMapTrait::snapshot' at the call (O(1) for persistent backends), then keys changed
+    /// by next operations are remembered. When the thread is done, the next 'insert', 'remove' or 'poll_compaction'
+    /// appends records of current values of changed keys to the tmp file, syncs it and renames it over the file.
+    /// The file is replaced after writing of all operations sent before, so order of writing is kept,
+    /// and operations written to the old file in the meantime are in the new file too.
+    /// 'on_complete' receives result when compaction is finished or failed, it's called in the thread finishing compaction.
+    /// If the map is dropped before, compaction is discarded with the tmp file and 'on_complete' is not called.
+    /// Returns error only if compaction can't be started, the file is not changed if compaction fails.
+    pub fn compact_in_background(&mut self, on_complete: impl FnOnce(Result<(), CompactError>) + Send + 'static) -> Result<(), CompactError>
+    where
+        Key: Send,
+        Value: Send + 'static,
+        Map: CloneableMapTrait<Key, Value> + Send + 'static,
+    {
+        if self.background_compaction.is_some() {
+            return Err(CompactError::InProgress);
+        }
+        if self.file_worker.is_read_only() {
+            return Err(CompactError::ReadOnly);
+        }
+        if self.cfg.get_mut().snapshot.is_some() || self.memory_file.is_some() || matches!(self.cfg.get_mut().format, Format::Text(Some(_), _) | Format::Bin(Some(_), _)) {
+            return Err(CompactError::BackgroundNotSupported);
+        }
+
+        let mut tmp_file_path = self.file_path.as_os_str().to_owned();
+        tmp_file_path.push(format!(".{}.tmp", Uuid::new_v4()));
+        let tmp_file_path = PathBuf::from(tmp_file_path);
+        let tmp_file = fs::OpenOptions::new().read(true).append(true).create_new(true).open(&tmp_file_path)
+            .map_err(|_| CompactError::TmpFileError)?;
+        tmp_file.lock_exclusive().map_err(|_| CompactError::TmpFileError)?;
+
+        // config without callbacks serializes the same as config of the map without before write callback
+        let mut cfg = self.cfg.get_mut().clone_without_callbacks();
+        cfg.integrity = self.integrity_at_file_start.clone();
+        let map = self.map.snapshot();
+        let touched_keys = Arc::new(Mutex::new(BTreeSet::new()));
+        let mirror_id = self.attach_mirror(Box::new(TouchedKeys(touched_keys.clone())));
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(path = %self.file_path.display(), entries = map.len(), "background compaction started");
+
+        let (result_sender, result_receiver) = channel();
+        std::thread::spawn(move || {
+            let written = write_entries(&map, &mut cfg, &tmp_file)
+                .and_then(|len| tmp_file.sync_data().map(|()| len).map_err(CompactError::WriteToFileError));
+            // owner can stop waiting of result by drop of the map
+            let _ = result_sender.send(written.map(|len| CompactedFile { file: tmp_file, len, integrity: cfg.integrity }));
+        });
+
+        self.background_compaction = Some(BackgroundCompaction {
+            tmp_file_path,
+            result_receiver: Mutex::new(result_receiver),
+            touched_keys,
+            mirror_id,
+            on_complete: Mutex::new(Some(Box::new(on_complete))),
+        });
+        Ok(())
+    }
+
+    /// Returns true if background compaction is started and the file is not yet replaced.
+    pub fn is_compacting(&self) -> bool {
+        self.background_compaction.is_some()
+    }
+
+    /// Replace the file if the thread of background compaction is done, returns true if compaction is still in progress.
+    /// It's called by every change of the map, so it's needed if the map is not changed.
+    pub fn poll_compaction(&mut self) -> bool {
+        let received = match &self.background_compaction {
+            Some(compaction) => compaction.result_receiver.lock()
+                .unwrap_or_else(|err| err.into_inner()) // no code with possible panic under this lock
+                .try_recv(),
+            None => return false,
+        };
+        let result = match received {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Disconnected) => Err(CompactError::WriteToFileError(std::io::Error::other("background compaction thread panicked"))),
+        };
+        self.finish_background_compaction(result);
+        false
+    }
+
+    /// Wait of the thread of background compaction and replace the file, nothing if compaction is not started.
+    pub fn wait_compaction(&mut self) {
+        let received = match &self.background_compaction {
+            Some(compaction) => compaction.result_receiver.lock()
+                .unwrap_or_else(|err| err.into_inner()) // no code with possible panic under this lock
+                .recv(),
+            None => return,
+        };
+        let result = received
+            .unwrap_or_else(|_| Err(CompactError::WriteToFileError(std::io::Error::other("background compaction thread panicked"))));
+        self.finish_background_compaction(result);
+    }
+
+    /// Append records of changed keys to the compacted tmp file and replace the file with it, result is passed to the callback.
+    fn finish_background_compaction(&mut self, result: Result<CompactedFile, CompactError>) {
+        let mut compaction = match self.background_compaction.take() {
+            Some(compaction) => compaction,
+            None => return,
+        };
+        self.detach_mirror(compaction.mirror_id);
+        let touched_keys = std::mem::take(&mut *lock_touched_keys(&compaction.touched_keys));
+
+        let result = result.and_then(|compacted| self.replace_with_compacted(compacted, &compaction, &touched_keys));
+
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(()) => tracing::info!(path = %self.file_path.display(), entries = self.map.len(), touched_keys = touched_keys.len(), "background compaction finished"),
+            Err(err) => tracing::warn!(path = %self.file_path.display(), error = %err, "background compaction error"),
+        }
+
+        let on_complete = compaction.on_complete.get_mut()
+            .unwrap_or_else(|err| err.into_inner()) // never poisoned, the callback is called only here
+            .take();
+        if let Some(on_complete) = on_complete {
+            on_complete(result);
+        }
+    }
+
+    /// Append records of current values of 'touched_keys' to the compacted file, rename it over the file and write next records to it.
+    fn replace_with_compacted(&mut self, compacted: CompactedFile, compaction: &BackgroundCompaction<Key>, touched_keys: &BTreeSet<Key>) -> Result<(), CompactError> {
+        let CompactedFile { file, mut len, integrity: compacted_integrity } = compacted;
+
+        let integrity = std::mem::replace(&mut self.cfg.get_mut().integrity, compacted_integrity);
+        let written = append_current_state(&self.map, self.cfg.get_mut(), &file, touched_keys)
+            .and_then(|appended_len| file.sync_data().map(|()| appended_len).map_err(CompactError::WriteToFileError))
+            .and_then(|appended_len| fs::rename(&compaction.tmp_file_path, &self.file_path).map(|()| appended_len).map_err(|_| CompactError::TmpFileError));
+        match written {
+            Ok(appended_len) => len += appended_len,
+            Err(err) => {
+                self.cfg.get_mut().integrity = integrity;
+                return Err(err);
+            },
+        }
+
+        // the old file is replaced after writing of all operations sent before, they are in the new file too
+        self.file_worker.replace_file(file, len).map_err(CompactError::WriteToFileError)?;
+        self.save_chain_head_of_replaced_file(len).map_err(CompactError::WriteToFileError)?;
+        self.records_loaded = (self.map.len() + touched_keys.len()) as u64;
+        self.dead_records_loaded = self.records_loaded.saturating_sub(self.map.len() as u64);
+        self.operations_since_open = 0;
+        self.last_record = None;
+        Ok(())
+    }
+
+    /// Write the current entries to other file as 'compact' does, for example for backup, the file of the map is not changed.
+    /// The file is written in the format and with integrity of 'cfg', directories to it are created as by 'open_or_create'.
+    /// If the file exists, it's replaced. It's exclusive locked while writing, synced to disk and unlocked after it.
+    /// Changes not yet written by the background thread are in the copy, because the map is written as it is at the call.
+    pub fn compact_to(&self, file_path: impl AsRef<Path>, mut cfg: Cfg) -> Result<(), CompactError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("compact_to", path = %file_path.as_ref().display()).entered();
+
+        let file_path = file_path.as_ref();
+        create_dirs_to_path_if_not_exist(file_path).map_err(CompactError::OpenFileError)?;
+        // truncated after locking, so the file is not cleared while other process holds it
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(file_path)
+            .map_err(CompactError::OpenFileError)?;
+        file.lock_exclusive().map_err(|_| CompactError::LockFileError)?;
+        file.set_len(0).map_err(CompactError::WriteToFileError)?;
+
+        let _file_len = write_entries(&self.map, &mut cfg, &file)?;
+        file.sync_data().map_err(CompactError::WriteToFileError)?;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(entries = self.map.len(), file_len = _file_len, "compacted copy written");
+
+        Ok(())
+    }
+}
+
+/// Write schema record and insert records of entries of the map in order of keys in the format of 'cfg', returns length of written data.
+pub(crate) fn write_entries<Key, Value, Map>(map: &Map, cfg: &mut Cfg, file: impl Write) -> Result<u64, CompactError>
+where
+    Key: Serialize + Ord + Clone,
+    Value: Serialize,
+    Map: MapTrait<Key, Value>,
+{
+    // references can't be collected from 'MapTrait::for_each', so keys are cloned for sorting
+    let mut keys = Vec::with_capacity(map.len());
+    map.for_each(|key, _| keys.push(key.clone()));
+    keys.sort();
+
+    let mut writer = BufWriter::new(file);
+    let mut len = 0;
+    if let Some(record) = file_record_of_schema(cfg).map_err(CompactError::SerializeError)? {
+        writer.write_all(&record).map_err(CompactError::WriteToFileError)?;
+        len += record.len() as u64;
+    }
+
+    for key in &keys {
+        let value = map.get(key)
+            .unwrap_or_else(|| unreachable!()); // unreachable because keys are taken from the map
+        let record = file_record_of_insert(key, value, cfg).map_err(CompactError::SerializeError)?;
+        writer.write_all(&record).map_err(CompactError::WriteToFileError)?;
+        len += record.len() as u64;
+    }
+
+    writer.flush().map_err(CompactError::WriteToFileError)?;
+    Ok(len)
+}
+
+/// Write insert records of current values of 'keys' or remove records of keys not in the map, returns length of written data.
+fn append_current_state<Key, Value, Map>(map: &Map, cfg: &mut Cfg, file: &fs::File, keys: &BTreeSet<Key>) -> Result<u64, CompactError>
+where
+    Key: Serialize,
+    Value: Serialize,
+    Map: MapTrait<Key, Value>,
+{
+    let mut writer = BufWriter::new(file);
+    let mut len = 0;
+    for key in keys {
+        let record = match map.get(key) {
+            Some(value) => file_record_of_insert(key, value, cfg),
+            None => file_record_of_remove(key, cfg),
+        }.map_err(CompactError::SerializeError)?;
+        writer.write_all(&record).map_err(CompactError::WriteToFileError)?;
+        len += record.len() as u64;
+    }
+
+    writer.flush().map_err(CompactError::WriteToFileError)?;
+    Ok(len)
+}
+
+/// Lock keys changed while background compaction runs.
+fn lock_touched_keys<Key>(touched_keys: &Mutex<BTreeSet<Key>>) -> std::sync::MutexGuard<'_, BTreeSet<Key>> {
+    touched_keys.lock()
+        .unwrap_or_else(|err| err.into_inner()) // keys are changed by one call, so they are correct after panic too
+}
@@ -0,0 +1,240 @@
+use crate::bin_format::{bin_block_data_of_insert, bin_block_data_of_remove, finish_bin_block, load_bin_file_records};
+use crate::cfg::{Cfg, Format, WriteOperation};
+use crate::file_worker::{worker_panicked_error, FileWorker, FileWorkerCfg};
+use crate::format::{check_write, create_dirs_to_path_if_not_exist, file_record_of_schema, integrity_before_record, file_record_of_transaction_marker, LoadLimits, MapOperation, TransactionMarker, unlock_after_error};
+use crate::map_with_file::SerializedError;
+use crate::text_format::{load_text_file_records, post_process_text_file_line, text_line_data_of_insert, text_line_data_of_remove};
+use crate::LoadFileError;
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::Ref;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Concurrent map with storing all changes history to the file.
+/// Based on dashmap::DashMap.
+pub type DashMap<Key, Value> = ConcurrentMapWithFile<Key, Value>;
+
+/// File based map for concurrent changing from many threads.
+/// In-memory map is dashmap::DashMap with sharded locks, all changes are written
+/// to the file by one background thread.
+///
+/// Ordering of records in the file:
+/// - key and value are serialized before taking any lock;
+/// - after serialization the shard lock of the key is taken, record is completed (integrity,
+///   before write callback) and sent to the file under short global lock, then the map is changed;
+/// - so records are logged in the order their serialization completes, and records of the same key
+///   are logged in the same order as changes of the key are applied to the map, and replay of the file
+///   gives the same state as in memory.
+///
+/// Indexes and 'skip_identical_inserts' of config are not supported.
+pub struct ConcurrentMapWithFile<Key, Value>
+where Key: Hash + Eq {
+    /// Wrapped concurrent map.
+    map: dashmap::DashMap<Key, Value>,
+    /// Config with integrity state of the last record, lock of it orders records.
+    cfg: Mutex<Cfg>,
+    /// For append map changes to the file in background thread.
+    file_worker: FileWorker,
+    /// Copy of format kind and version from config for serialization without lock.
+    serialization: Serialization,
+}
+
+impl<Key, Value> ConcurrentMapWithFile<Key, Value>
+where
+    Key: Serialize + DeserializeOwned + Hash + Eq + Clone,
+    Value: Serialize + DeserializeOwned {
+
+    /// Constructs file based concurrent map.
+    /// If file is exist then load map from file. If file not is not exist then create new file.
+    pub fn open_or_create(file_path: impl AsRef<Path>, mut cfg: Cfg) -> Result<Self, LoadFileError> {
+        let file_path = file_path.as_ref();
+        create_dirs_to_path_if_not_exist(file_path)?;
+
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(file_path)?;
+        file.lock_exclusive().map_err(LoadFileError::LockError)?;
+
+        let map = dashmap::DashMap::new();
+        let apply_map_operation = |map_operation| {
+            match map_operation {
+                MapOperation::Insert(key, value) => { map.insert(key, value); },
+                MapOperation::Remove(key) => { map.remove(&key); },
+            };
+            Ok(())
+        };
+
+        let limits = LoadLimits::of(&cfg);
+        let loaded_tail = match &mut cfg.format {
+            Format::Text(_, after_read_callback) => {
+                load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.take(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, apply_map_operation)
+            },
+            Format::Bin(_, after_read_callback) => {
+                load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.take(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, apply_map_operation)
+            },
+        }.map_err(|err| unlock_after_error(&file, err))?;
+
+        let file_len = file.metadata()?.len();
+        let file_worker = FileWorker::new(file, FileWorkerCfg::take_from(&mut cfg, PathBuf::from(file_path), file_len));
+
+        // records appended after incomplete transaction must not be treated as part of it
+        if loaded_tail.incomplete_transaction_integrity.is_some() {
+            let record = file_record_of_transaction_marker(TransactionMarker::Abort, &mut cfg).map_err(LoadFileError::WriteRecordError)?;
+            file_worker.write_bytes(record, WriteOperation::TransactionAbort)?;
+        }
+
+        // schema fingerprint is the first record of created file
+        if file_len == 0 {
+            if let Some(record) = file_record_of_schema(&mut cfg).map_err(LoadFileError::WriteRecordError)? {
+                file_worker.write_bytes(record, WriteOperation::Schema)?;
+            }
+        }
+
+        let serialization = Serialization {
+            text: matches!(cfg.format, Format::Text(..)),
+            value_schema_version: cfg.value_schema_version,
+            compress_values_over: cfg.compress_values_over,
+        };
+
+        Ok(ConcurrentMapWithFile { map, cfg: Mutex::new(cfg), file_worker, serialization })
+    }
+
+    /// Inserts a key-value pair into the map.
+    /// Insert into the map will immediately, and to disk later in a background thread.
+    pub fn insert(&self, key: Key, value: Value) -> Result<Option<Value>, SerializedError> {
+        let record = self.serialization.insert(&key, &value)?;
+
+        match self.map.entry(key) {
+            Entry::Occupied(mut entry) => {
+                self.write(record, WriteOperation::Insert)?;
+                Ok(Some(entry.insert(value)))
+            },
+            Entry::Vacant(entry) => {
+                self.write(record, WriteOperation::Insert)?;
+                entry.insert(value);
+                Ok(None)
+            },
+        }
+    }
+
+    /// Remove value by key.
+    /// Remove from the map will immediately, and to disk later in a background thread.
+    pub fn remove(&self, key: &Key) -> Result<Option<Value>, SerializedError> {
+        let record = self.serialization.remove(key)?;
+
+        match self.map.entry(key.clone()) {
+            Entry::Occupied(entry) => {
+                self.write(record, WriteOperation::Remove)?;
+                Ok(Some(entry.remove()))
+            },
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+
+    /// Returns guard of the value corresponding to the key.
+    /// Guard holds read lock of the shard, so don't change the map while holding it.
+    pub fn get(&self, key: &Key) -> Option<Ref<'_, Key, Value>> {
+        self.map.get(key)
+    }
+
+    /// Returns clone of the value corresponding to the key.
+    pub fn get_cloned(&self, key: &Key) -> Option<Value> where Value: Clone {
+        self.map.get(key).map(|value| value.clone())
+    }
+
+    /// Returns count of elements in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns reference to the used map. Changes of it are not written to the file.
+    pub fn map(&self) -> &dashmap::DashMap<Key, Value> {
+        &self.map
+    }
+
+    /// Waits until all changes made before are written to the file.
+    /// Returns the first error of writing since the previous flush.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.file_worker.flush().recv()
+            .unwrap_or_else(|_| Err(worker_panicked_error())) // result is not received only if worker thread panicked
+    }
+
+    /// Complete record with integrity and before write callback and send it to the file.
+    /// Called under shard lock of the key, so records of the key are written in order of changes of the map.
+    /// Returns 'SerializedError::WriteError' if writing fails with 'WriteMode::Sync', the map must not be changed then.
+    fn write(&self, record: RecordData, operation: WriteOperation) -> Result<(), SerializedError> {
+        let mut cfg = self.lock_cfg();
+        let cfg = &mut *cfg;
+        let integrity_before = integrity_before_record(cfg);
+        let written = match (record, &mut cfg.format) {
+            (RecordData::Text(mut line), Format::Text(before_write_callback, _)) => {
+                post_process_text_file_line(&mut line, &mut cfg.integrity);
+                if let Some(f) = before_write_callback {
+                    f(&mut line);
+                }
+                self.file_worker.write_string(line, operation)
+            },
+            (RecordData::Bin(data), Format::Bin(before_write_callback, _)) => {
+                let mut block = finish_bin_block(data, &mut cfg.integrity);
+                if let Some(f) = before_write_callback {
+                    f(&mut block);
+                }
+                self.file_worker.write_bytes(block, operation)
+            },
+            _ => unreachable!(), // unreachable because format of config is not changed after opening
+        };
+        check_write(written, &mut cfg.integrity, integrity_before)
+    }
+
+    /// Lock config.
+    fn lock_cfg(&self) -> std::sync::MutexGuard<'_, Cfg> {
+        self.cfg.lock()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in callback of config, config is still usable in this case
+    }
+}
+
+/// Serialized operation without integrity.
+enum RecordData {
+    /// Line of the text format without integrity and '\n'.
+    Text(String),
+    /// Data of block of the binary format without integrity and block length.
+    Bin(Vec<u8>),
+}
+
+/// Settings of serialization that are not changed after opening.
+struct Serialization {
+    /// Text or binary format.
+    text: bool,
+    /// Version of schema of the value from config.
+    value_schema_version: Option<u32>,
+    /// Min size of compressed values from config.
+    compress_values_over: Option<usize>,
+}
+
+impl Serialization {
+    /// Serialize insert operation.
+    fn insert<Key: Serialize, Value: Serialize>(&self, key: &Key, value: &Value) -> Result<RecordData, SerializedError> {
+        Ok(if self.text {
+            RecordData::Text(text_line_data_of_insert(key, value, self.value_schema_version, self.compress_values_over)?)
+        } else {
+            RecordData::Bin(bin_block_data_of_insert(key, value, self.value_schema_version, self.compress_values_over)?)
+        })
+    }
+
+    /// Serialize remove operation.
+    fn remove<Key: Serialize>(&self, key: &Key) -> Result<RecordData, SerializedError> {
+        Ok(if self.text {
+            RecordData::Text(text_line_data_of_remove(key)?)
+        } else {
+            RecordData::Bin(bin_block_data_of_remove(key)?)
+        })
+    }
+}
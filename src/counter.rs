@@ -0,0 +1,89 @@
+use crate::cfg::{Cfg, WriteOperation};
+use crate::format::{check_write, file_record_of_increment, integrity_before_record, ItemOperation, LoadedOperation, MapOperation, RawMeta, SetOperation};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+/// Numeric value of counter that can be changed by 'MapWithFile::fetch_add'.
+pub trait CounterValue: Copy + Default {
+    /// Returns value changed by 'delta'.
+    /// Implementations for integers are saturating, result is clamped by min and max of the type,
+    /// so the same result is restored from the file regardless of value range.
+    fn add_delta(self, delta: i64) -> Self;
+}
+
+macro_rules! impl_counter_value {
+    ($($t:ty),*) => {
+        $(
+            impl CounterValue for $t {
+                fn add_delta(self, delta: i64) -> Self {
+                    (self as i128 + delta as i128).clamp(<$t>::MIN as i128, <$t>::MAX as i128) as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_counter_value!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone + CounterValue,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Same as 'open_or_create' but file can contain increment records written by 'fetch_add'.
+    /// Increments are applied in order with inserts and removes, missing key is treated as zero.
+    pub fn open_or_create_with_counters(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        Self::open_with(file_path.as_ref(), cfg, |map: &mut Map, operation| {
+            match operation {
+                CounterRecord::Map(MapOperation::Insert(key, value)) => { map.insert(key, value); },
+                CounterRecord::Map(MapOperation::Remove(key)) => { map.remove(&key); },
+                CounterRecord::Increment(key, delta) => {
+                    match map.get_mut(&key) {
+                        Some(value) => *value = value.add_delta(delta),
+                        None => { map.insert(key, Value::default().add_delta(delta)); },
+                    }
+                },
+            }
+        })
+    }
+
+    /// Adds 'delta' to the value of the key and returns previous value, missing key is treated as zero.
+    /// Only the key and delta are written to the file ("inc" line or block with code 11) instead of the whole value.
+    /// Overflow is saturating, see 'CounterValue'.
+    /// File with increment records must be opened by 'open_or_create_with_counters'.
+    pub fn fetch_add(&mut self, key: &Key, delta: i64) -> Result<Value, SerializedError> {
+        let integrity_before = integrity_before_record(self.cfg.get_mut());
+        let record = file_record_of_increment(key, delta, self.cfg.get_mut())?;
+        check_write(self.file_worker.write_bytes(record, WriteOperation::Increment), &mut self.cfg.get_mut().integrity, integrity_before)?;
+        self.operations_since_open += 1;
+
+        let old_value = self.map.get(key).copied();
+        let new_value = old_value.unwrap_or_default().add_delta(delta);
+        self.map.insert(key.clone(), new_value);
+        self.update_index_when_insert(key, &new_value, &old_value);
+        self.after_write();
+
+        Ok(old_value.unwrap_or_default())
+    }
+}
+
+/// Operation loaded from the file of map with counters.
+enum CounterRecord<Key, Value> {
+    /// Insert or remove.
+    Map(MapOperation<Key, Value>),
+    /// Increment of value of the key by delta.
+    Increment(Key, i64),
+}
+
+impl<Key, Value> LoadedOperation<Key, Value> for CounterRecord<Key, Value> {
+    fn from_map_operation(map_operation: MapOperation<Key, Value>) -> Self { CounterRecord::Map(map_operation) }
+    fn from_item_operation(_: ItemOperation, _: Key, _: Value) -> Option<Self> { None }
+    fn from_set_operation(_: SetOperation, _: Key) -> Option<Self> { None }
+    fn from_increment(key: Key, delta: i64) -> Option<Self> { Some(CounterRecord::Increment(key, delta)) }
+    fn with_meta(self, _: RawMeta<'_>, _: usize) -> Result<Self, LoadFileError> { Ok(self) }
+}
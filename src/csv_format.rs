@@ -0,0 +1,125 @@
+use crate::Cfg;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::format::{create_dirs_to_path_if_not_exist, file_record_of_insert};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Write;
+use std::fs;
+use std::path::Path;
+use fs2::FileExt;
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Write current state of the map to the CSV as 'key,value' rows.
+    /// Intended for maps with scalar keys and values such as numbers and strings,
+    /// values containing commas, quotes or newlines are quoted.
+    /// If 'headers' is true, then first row will be 'key,value'.
+    pub fn export_csv<Writer: Write>(&self, w: Writer, headers: bool) -> Result<(), csv::Error> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+        if headers {
+            writer.write_record(["key", "value"])?;
+        }
+
+        let mut result = Ok(());
+        self.map().for_each(|key, value| {
+            if result.is_ok() {
+                result = writer.serialize((key, value));
+            }
+        });
+        result?;
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Create new history file from CSV with 'key,value' rows.
+/// Each row is parsed by 'parse' callback and written as insert operation
+/// in the format and integrity from 'dst_cfg'. Rows are read one by one, so the CSV
+/// is not loaded into memory entirely.
+/// If 'headers' is true, then first row is skipped.
+/// Returns count of imported rows.
+pub fn import_csv<Key, Value, Reader, ParseError, F>(
+    r: Reader,
+    headers: bool,
+    dst_file_path: impl AsRef<Path>,
+    mut dst_cfg: Cfg,
+    parse: F
+) -> Result<usize, ImportCsvError<ParseError>>
+where
+    Key: Serialize,
+    Value: Serialize,
+    Reader: std::io::Read,
+    F: Fn(&str, &str) -> Result<(Key, Value), ParseError>,
+{
+    let dst_file_path = dst_file_path.as_ref();
+    create_dirs_to_path_if_not_exist(dst_file_path)
+        .map_err(ImportCsvError::OpenDstFileError)?;
+
+    let mut dst_file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(dst_file_path)
+        .map_err(ImportCsvError::OpenDstFileError)?;
+
+    dst_file.lock_exclusive()
+        .map_err(|_| ImportCsvError::LockDstFileError)?;
+
+    dst_file.set_len(0).map_err(ImportCsvError::ClearDstFileError)?;
+
+    let mut reader = csv::ReaderBuilder::new().has_headers(headers).flexible(true).from_reader(r);
+
+    let mut count = 0;
+    for record in reader.records() {
+        let record = record.map_err(ImportCsvError::CsvError)?;
+        let line_num = record.position().map(|pos| pos.line() as usize).unwrap_or(0);
+
+        if record.len() != 2 {
+            return Err(ImportCsvError::WrongFieldsCount { line_num });
+        }
+
+        let (key, value) = parse(&record[0], &record[1])
+            .map_err(|err| ImportCsvError::ParseError { err, line_num })?;
+
+        let data = file_record_of_insert(&key, &value, &mut dst_cfg)
+            .map_err(ImportCsvError::SerializeError)?;
+
+        dst_file.write_all(&data)
+            .map_err(ImportCsvError::WriteToFileError)?;
+
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Error of import history file from CSV.
+#[derive(Debug)]
+pub enum ImportCsvError<ParseError> {
+    /// When can't open or create target file where to save.
+    OpenDstFileError(std::io::Error),
+    /// When can't exclusive lock opened target file.
+    LockDstFileError,
+    /// When can't clear target file before import.
+    ClearDstFileError(std::io::Error),
+    /// Error of reading CSV.
+    CsvError(csv::Error),
+    /// Row of CSV contains not two fields 'key,value'.
+    WrongFieldsCount { line_num: usize },
+    /// Error returned from parse callback with line number of CSV.
+    ParseError { err: ParseError, line_num: usize },
+    /// Error of serialization of parsed key or value.
+    SerializeError(SerializedError),
+    /// When write error to the target file.
+    WriteToFileError(std::io::Error),
+}
+
+impl<ParseError: std::fmt::Debug> std::error::Error for ImportCsvError<ParseError> {}
+
+impl<ParseError: std::fmt::Debug> std::fmt::Display for ImportCsvError<ParseError> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
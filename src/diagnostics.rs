@@ -0,0 +1,96 @@
+use crate::file_worker::FileWorkerCounters;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+
+/// Diagnostics of one open map, returned by 'snapshot'.
+/// Plain data for rendering by admin endpoint.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct MapDiagnostics {
+    /// Path of the file.
+    pub path: PathBuf,
+    /// Type name of the wrapped map container.
+    pub backend: &'static str,
+    /// Time when the map was opened.
+    pub opened_at: SystemTime,
+    /// Count of operations sent to the background thread and not yet written to the file.
+    pub pending_writes: usize,
+    /// Count of bytes written to the file after opening.
+    pub bytes_written: u64,
+    /// Count of errors of writing to the file after opening.
+    pub write_errors: u64,
+    /// Time when all pending operations were last written to the file.
+    /// None if nothing written after opening.
+    pub last_flush_at: Option<SystemTime>,
+}
+
+/// Returns diagnostics of all maps open in the process, in order of opening.
+pub fn snapshot() -> Vec<MapDiagnostics> {
+    let registry = REGISTRY.lock()
+        .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under this lock
+
+    registry.iter()
+        .filter_map(|entry| {
+            let counters = entry.counters.upgrade()?;
+            let last_flush_at = *counters.last_flush_at.lock()
+                .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under this lock
+            Some(MapDiagnostics {
+                path: entry.path.clone(),
+                backend: entry.backend,
+                opened_at: entry.opened_at,
+                pending_writes: counters.pending_writes.load(Ordering::Acquire),
+                bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+                write_errors: counters.write_errors.load(Ordering::Relaxed),
+                last_flush_at,
+            })
+        })
+        .collect()
+}
+
+/// Registration of the open map in the global registry, deregisters the map when dropped.
+pub(crate) struct Registration {
+    /// Id of the entry of the registry.
+    id: u64,
+}
+
+impl Registration {
+    /// Registers the map with the file 'path' and counters of its file worker.
+    pub(crate) fn register(path: PathBuf, backend: &'static str, counters: &Arc<FileWorkerCounters>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let entry = RegistryEntry { id, path, backend, opened_at: SystemTime::now(), counters: Arc::downgrade(counters) };
+        REGISTRY.lock()
+            .unwrap_or_else(|err| unreachable!(err)) // unreachable because no code with possible panic under this lock
+            .push(entry);
+        Registration { id }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        REGISTRY.lock()
+            .unwrap_or_else(|err| unreachable!(err)) // unreachable because no code with possible panic under this lock
+            .retain(|entry| entry.id != self.id);
+    }
+}
+
+/// Open map in the registry.
+struct RegistryEntry {
+    /// Id for deregistration.
+    id: u64,
+    /// Path of the file.
+    path: PathBuf,
+    /// Type name of the wrapped map container.
+    backend: &'static str,
+    /// Time of opening.
+    opened_at: SystemTime,
+    /// Counters of the file worker, not kept alive by the registry.
+    counters: Weak<FileWorkerCounters>,
+}
+
+/// All open maps of the process.
+static REGISTRY: Mutex<Vec<RegistryEntry>> = Mutex::new(Vec::new());
+
+/// Id of the next registered map.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -0,0 +1,78 @@
+use crate::open_report::OpenWarning;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marker file "<file>.dirty" existing while the map is open, removed when dropped.
+pub(crate) struct DirtyMarker {
+    /// Path of the marker file.
+    path: PathBuf,
+}
+
+impl DirtyMarker {
+    /// Check marker of the previous process and write marker of this process.
+    /// Returns None if marker can't be written, warnings are pushed to 'warnings'.
+    pub(crate) fn mark(file_path: &Path, warnings: &mut Vec<OpenWarning>) -> Option<Self> {
+        let mut path = file_path.as_os_str().to_owned();
+        path.push(".dirty");
+        let path = PathBuf::from(path);
+
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                if let Some(warning) = parse_marker(&content) {
+                    warnings.push(warning);
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+            Err(err) => warnings.push(OpenWarning::DirtyMarkerError(err)),
+        }
+
+        // marker of the previous process is overwritten, so it's reported only once
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let marker = format!("{} {}\n", std::process::id(), at);
+        match write_marker(&path, &marker) {
+            Ok(()) => Some(DirtyMarker { path }),
+            Err(err) => {
+                warnings.push(OpenWarning::DirtyMarkerError(err));
+                None
+            },
+        }
+    }
+}
+
+impl Drop for DirtyMarker {
+    fn drop(&mut self) {
+        // if marker can't be removed, for example because of permissions of the directory,
+        // it's overwritten so the next opening doesn't report unclean shutdown
+        if fs::remove_file(&self.path).is_err() {
+            let _ = write_marker(&self.path, CLEAN_MARKER);
+        }
+    }
+}
+
+/// Content of the marker left after clean close when it can't be removed.
+const CLEAN_MARKER: &str = "clean\n";
+
+/// Returns warning of the content of existing marker, None if it was closed cleanly.
+fn parse_marker(content: &str) -> Option<OpenWarning> {
+    if content == CLEAN_MARKER {
+        return None;
+    }
+
+    let parsed = content.strip_suffix('\n')
+        .and_then(|content| content.split_once(' '))
+        .and_then(|(pid, at)| Some((pid.parse::<u32>().ok()?, at.parse::<u64>().ok()?)));
+
+    match parsed {
+        Some((pid, at)) => Some(OpenWarning::UncleanShutdown { pid, at: UNIX_EPOCH + Duration::from_millis(at) }),
+        None => Some(OpenWarning::UnreadableDirtyMarker),
+    }
+}
+
+/// Replace content of the marker and sync it, so it's not lost by crash of the system.
+fn write_marker(path: &Path, content: &str) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_data()
+}
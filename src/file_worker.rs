@@ -1,79 +1,1039 @@
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, SystemTime};
+use crate::chain_head::ChainHeadFile;
+use crate::clock::Clock;
+use crate::log_shipper::{LogShipping, ShippingWorker};
+use crate::cfg::{Cfg, FsyncPolicy, RetryPolicy, WorkerFailure, WorkerFailureCallback, WriteAck, WriteAckCallback, WriteChannel, WriteErrorCallback, WriteErrorContext, WriteMode, WriteOperation};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::PathBuf;
 
-/// For write to the file in background thread.
+/// Max size of data of write tasks waiting in the queue that are written to the file by one write,
+/// so many small records take one system call. Single bigger task is written as is.
+const COALESCED_WRITE_BYTES: usize = 64 * 1024;
+/// Max count of errors of the last failed writes kept for 'FileWorkerCounters::wait_for_write'.
+pub(crate) const MAX_COMPLETED_WRITE_ERRORS: usize = 1024;
+
+/// For write to the file in background thread, or in the calling thread with 'WriteMode::Sync'.
 pub(crate) struct FileWorker {
-    task_sender: Sender<FileWorkerTask>,
-    join_handle: Option<JoinHandle<()>>,
+    /// Background thread or state of writing in the calling thread.
+    mode: WorkerMode,
+    /// Counters shared with the worker thread.
+    counters: Arc<FileWorkerCounters>,
+    /// Count of sent writes, it's sequence number of the last sent write.
+    sent_writes: AtomicU64,
+    /// Callback for receive failure of the background thread, locked only for making the map Sync.
+    failure_callback: Mutex<Option<WorkerFailureCallback>>,
+    /// Max time of waiting of stop of the background thread.
+    stop_timeout: Option<Duration>,
+    /// Max count of not yet processed tasks.
+    #[cfg(feature = "tracing")]
+    queue_high_water: AtomicUsize,
+}
+
+/// Where 'FileWorker' writes, selected by 'WriteMode'.
+enum WorkerMode {
+    /// Tasks are sent to the background thread.
+    Background {
+        task_sender: TaskSender,
+        join_handle: JoinHandle<()>,
+    },
+    /// Data is written in the calling thread.
+    Sync(Box<Mutex<FileWriting>>),
+    /// Nothing is written, the file is kept open only for its shared lock, see 'MapWithFile::open_read_only'.
+    ReadOnly { _locked_file: std::fs::File },
+    /// Writing is stopped by 'FileWorker::close'.
+    Stopped,
+}
+
+/// File written by 'FileWorker'.
+pub(crate) trait WorkerFile: std::io::Write + Send {
+    /// Truncate the file to 'len' bytes, used for removing of partially written record with 'WriteMode::Sync'.
+    fn truncate(&mut self, len: u64) -> std::io::Result<()>;
+    /// Sync written data to disk by 'FsyncPolicy'.
+    fn sync_data(&mut self) -> std::io::Result<()>;
+}
+
+impl WorkerFile for File {
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.set_len(len)
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Callbacks and settings of writing of 'FileWorker'.
+pub(crate) struct FileWorkerCfg {
+    /// Path of the file for context of errors.
+    pub file_path: PathBuf,
+    /// Length of the file before the first write of the worker.
+    pub file_len: u64,
+    /// Callback for receive errors of writing to the file.
+    pub error_callback: Option<WriteErrorCallback>,
+    /// Callback for receive successful writes to the file.
+    pub ack_callback: Option<WriteAckCallback>,
+    /// Additional writer where data is written after the file.
+    pub sink: Option<Box<dyn std::io::Write + Send>>,
+    /// Callback for receive errors of writing to the sink.
+    pub sink_error_callback: Option<Box<dyn FnMut(std::io::Error) + Send>>,
+    /// Settings of shipping written data in separate thread.
+    pub log_shipping: Option<LogShipping>,
+    /// Implementation of the channel of tasks.
+    pub write_channel: WriteChannel,
+    /// Capacity of the channel of tasks, unbounded if None.
+    pub max_pending_writes: Option<usize>,
+    /// Writing in the background thread or in the calling thread.
+    pub write_mode: WriteMode,
+    /// When written data is synced to disk.
+    pub fsync_policy: FsyncPolicy,
+    /// Repeating of failed writes.
+    pub write_retry: Option<RetryPolicy>,
+    /// Callback for receive failure of the background thread.
+    pub failure_callback: Option<WorkerFailureCallback>,
+    /// Max time of waiting of stop of the background thread.
+    pub stop_timeout: Option<Duration>,
+    /// Head file of chained integrity saved after each write.
+    pub chain_head: Option<ChainHeadFile>,
+    /// Source of time for 'FsyncPolicy::Interval', delays of 'RetryPolicy' and log shipping.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl FileWorkerCfg {
+    /// Take callbacks and settings of writing from config of the map.
+    pub fn take_from(cfg: &mut Cfg, file_path: PathBuf, file_len: u64) -> Self {
+        FileWorkerCfg {
+            chain_head: ChainHeadFile::of(&file_path, cfg),
+            file_path,
+            file_len,
+            error_callback: cfg.take_write_error_callback(),
+            ack_callback: cfg.write_ack_callback.take(),
+            sink: cfg.secondary_sink.take(),
+            sink_error_callback: cfg.secondary_sink_error_callback.take(),
+            log_shipping: cfg.log_shipping.take(),
+            write_channel: cfg.write_channel,
+            max_pending_writes: cfg.max_pending_writes,
+            write_mode: cfg.write_mode,
+            fsync_policy: cfg.fsync_policy,
+            write_retry: cfg.write_retry,
+            failure_callback: cfg.on_worker_failure.take(),
+            stop_timeout: cfg.stop_timeout,
+            clock: cfg.clock.clone(),
+        }
+    }
+}
+
+/// Counters of the worker thread that can be read from the owner thread.
+#[derive(Default)]
+pub(crate) struct FileWorkerCounters {
+    /// Count of sent and not yet written data, always 0 with 'WriteMode::Sync'.
+    pub pending_writes: AtomicUsize,
+    /// Count of bytes successfully written to the file.
+    pub bytes_written: AtomicU64,
+    /// Length of the file after successful writes, it's changed by replacing of the file too.
+    pub file_len: AtomicU64,
+    /// Count of errors of writing to the file.
+    pub write_errors: AtomicU64,
+    /// Time when all sent data was last written to the file.
+    pub last_flush_at: Mutex<Option<SystemTime>>,
+    /// The last error of writing, flush or sync not yet taken by the owner.
+    last_write_error: Mutex<Option<std::io::Error>>,
+    /// Completed writes for 'FileWorkerCounters::wait_for_write'.
+    completed_writes: Mutex<CompletedWrites>,
+    /// Notified when writes are completed.
+    writes_completed: Condvar,
+}
+
+/// Sequence number of the last completed write and errors of failed writes.
+#[derive(Default)]
+struct CompletedWrites {
+    sequence: u64,
+    /// Errors by sequence number of failed write, only the last 'MAX_COMPLETED_WRITE_ERRORS' are kept.
+    errors: BTreeMap<u64, std::io::Error>,
+    /// The background thread panicked, so next writes will never be completed.
+    worker_panicked: bool,
+}
+
+/// State of writing to the file and after it to the sink and shipping.
+struct FileWriting {
+    file: Box<dyn WorkerFile>,
+    /// Path of the file for context of errors.
+    file_path: PathBuf,
+    error_callback: Option<WriteErrorCallback>,
+    ack_callback: Option<WriteAckCallback>,
+    sink: Option<Box<dyn std::io::Write + Send>>,
+    sink_error_callback: Option<Box<dyn FnMut(std::io::Error) + Send>>,
+    shipping_worker: Option<ShippingWorker>,
+    consecutive_failures: u64,
+    /// Number of the last write.
+    sequence: u64,
+    /// Length of the file after successful writes.
+    offset: u64,
+    /// The first error of writing since the last flush, it's returned by the next flush.
+    error_since_flush: Option<std::io::Error>,
+    counters: Arc<FileWorkerCounters>,
+    fsync_policy: FsyncPolicy,
+    /// Count of successful writes not yet synced to disk.
+    unsynced_writes: usize,
+    /// Count of bytes not yet synced to disk.
+    unsynced_bytes: usize,
+    /// Time of the first write not yet synced to disk.
+    unsynced_since: Option<SystemTime>,
+    write_retry: Option<RetryPolicy>,
+    /// Error of truncate of partially written data, the file has the data at the end and next records
+    /// would be unreadable after it, so writes fail until the data is truncated.
+    truncate_error: Option<std::io::Error>,
+    chain_head: Option<ChainHeadFile>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileWorkerCounters {
+    /// Set time when all sent data was written to the file.
+    fn set_flushed_at(&self, now: SystemTime) {
+        *self.last_flush_at.lock()
+            .unwrap_or_else(|err| unreachable!(err)) = Some(now); // unreachable because no code with possible panic under this lock
+    }
+
+    /// Take the last error of writing, flush or sync, the next call returns None until the next error.
+    pub fn take_last_write_error(&self) -> Option<std::io::Error> {
+        self.last_write_error.lock()
+            .unwrap_or_else(|err| err.into_inner()) // error is replaced by one assignment, so it's correct after panic too
+            .take()
+    }
+
+    /// Wait until the write with sequence number is written to the file and synced by 'FsyncPolicy',
+    /// returns error of the write if it failed or 'worker_panicked_error' if the background thread panicked before the write.
+    /// Errors of only the last 'MAX_COMPLETED_WRITE_ERRORS' failed writes are known, older failed writes are not reported.
+    pub fn wait_for_write(&self, sequence: u64) -> std::io::Result<()> {
+        let mut completed_writes = self.completed_writes.lock()
+            .unwrap_or_else(|err| err.into_inner()); // completed writes are changed without code with possible panic, so they are correct after panic too
+        while completed_writes.sequence < sequence {
+            if completed_writes.worker_panicked {
+                return Err(worker_panicked_error());
+            }
+            completed_writes = self.writes_completed.wait(completed_writes)
+                .unwrap_or_else(|err| err.into_inner()); // completed writes are changed without code with possible panic, so they are correct after panic too
+        }
+        match completed_writes.errors.get(&sequence) {
+            Some(error) => Err(std::io::Error::new(error.kind(), error.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Set sequence number of the last completed write and wake waiting of writes.
+    fn complete_writes(&self, sequence: u64, errors: Vec<(u64, std::io::Error)>) {
+        let mut completed_writes = self.completed_writes.lock()
+            .unwrap_or_else(|err| err.into_inner()); // completed writes are changed without code with possible panic, so they are correct after panic too
+        completed_writes.sequence = sequence;
+        completed_writes.errors.extend(errors);
+        while completed_writes.errors.len() > MAX_COMPLETED_WRITE_ERRORS {
+            completed_writes.errors.pop_first();
+        }
+        self.writes_completed.notify_all();
+    }
+
+    /// Remember that the background thread panicked and wake waiting of writes.
+    fn set_worker_panicked(&self) {
+        self.completed_writes.lock()
+            .unwrap_or_else(|err| err.into_inner()) // completed writes are changed without code with possible panic, so they are correct after panic too
+            .worker_panicked = true;
+        self.writes_completed.notify_all();
+    }
+
+    /// Remember the last error of writing, flush or sync.
+    pub(crate) fn set_last_write_error(&self, error: &std::io::Error) {
+        *self.last_write_error.lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(std::io::Error::new(error.kind(), error.to_string())); // error is replaced by one assignment, so it's correct after panic too
+    }
+}
+
+impl FileWriting {
+    /// Write data of operations to the file by one write, acks and errors are counted and reported
+    /// for each operation as for separate writes. Failed write is repeated by 'RetryPolicy'.
+    /// Returns contexts of error for each operation if the write and its repeats failed,
+    /// partially written data is truncated then, if truncate fails, next writes fail until the data is truncated.
+    fn write_file(&mut self, writes: &[(&[u8], WriteOperation)]) -> Result<(), Vec<WriteErrorContext>> {
+        let data = match writes {
+            [(data, _)] => std::borrow::Cow::Borrowed(*data),
+            _ => std::borrow::Cow::Owned(writes.iter().map(|(data, _)| *data).collect::<Vec<_>>().concat()),
+        };
+
+        let mut result = match self.truncate_partial_write() {
+            Ok(()) => self.file.write_all(&data),
+            Err(error) => Err(error),
+        };
+        if let Some(RetryPolicy { attempts, backoff }) = self.write_retry.filter(|_| self.truncate_error.is_none()) {
+            let mut delay = backoff;
+            for _attempt in 1..=attempts {
+                let _error = match &result {
+                    Ok(()) => break,
+                    Err(error) => error,
+                };
+                #[cfg(feature = "tracing")]
+                tracing::warn!(bytes = data.len(), attempt = _attempt, error = %_error, "write to file error, repeating");
+                // repeat after partially written data would duplicate it
+                if let Err(err) = self.file.truncate(self.offset) {
+                    #[cfg(feature = "tracing")]
+                    tracing::error!(offset = self.offset, error = %err, "truncate of partially written data before repeat error");
+                    self.truncate_error = Some(err);
+                    break;
+                }
+                self.clock.sleep(delay);
+                delay = delay.saturating_mul(2);
+                result = self.file.write_all(&data);
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                self.consecutive_failures = 0;
+                for (data, operation) in writes {
+                    self.sequence += 1;
+                    if let Some(callback) = &mut self.ack_callback {
+                        callback(WriteAck { sequence: self.sequence, operation: *operation, bytes: data.len(), offset: self.offset });
+                    }
+                    self.offset += data.len() as u64;
+                    self.unsynced_writes += 1;
+                    self.unsynced_bytes += data.len();
+                    self.counters.bytes_written.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(bytes = data.len(), "written to file");
+                }
+                self.counters.file_len.store(self.offset, Ordering::Release);
+                let clock = &self.clock;
+                self.unsynced_since.get_or_insert_with(|| clock.now());
+                if let Some((data, _)) = writes.last().filter(|_| self.chain_head.is_some()) {
+                    self.save_chain_head(data);
+                }
+                Ok(())
+            },
+            Err(error) => {
+                // next records would be unreadable after partially written data, so next writes fail until it's truncated
+                if self.truncate_error.is_none() {
+                    if let Err(err) = self.file.truncate(self.offset) {
+                        #[cfg(feature = "tracing")]
+                        tracing::error!(offset = self.offset, error = %err, "truncate of partially written data error");
+                        self.truncate_error = Some(err);
+                    }
+                }
+                let mut error = Some(error);
+                let contexts = writes.iter().enumerate().map(|(i, (data, operation))| {
+                    self.sequence += 1;
+                    self.consecutive_failures += 1;
+                    self.counters.write_errors.fetch_add(1, Ordering::Relaxed);
+                    // the last operation gets the error, others get copies because io::Error can't be cloned
+                    let error = match &error {
+                        Some(err) if i + 1 < writes.len() => std::io::Error::new(err.kind(), err.to_string()),
+                        _ => error.take().unwrap_or_else(|| unreachable!()), // unreachable because the error is taken only for the last operation
+                    };
+                    #[cfg(feature = "tracing")]
+                    tracing::error!(bytes = data.len(), error = %error, "write to file error");
+                    WriteErrorContext { error, file_path: self.file_path.clone(), sequence: self.sequence, operation: *operation, bytes: data.len(), consecutive_failures: self.consecutive_failures, data: data.to_vec() }
+                }).collect();
+                Err(contexts)
+            },
+        }
+    }
+
+    /// Truncate partially written data left by failed truncate, returns copy of the error of truncate
+    /// if it fails again because io::Error can't be cloned.
+    fn truncate_partial_write(&mut self) -> std::io::Result<()> {
+        if self.truncate_error.is_some() {
+            match self.file.truncate(self.offset) {
+                Ok(()) => self.truncate_error = None,
+                Err(err) => self.truncate_error = Some(err),
+            }
+        }
+        match &self.truncate_error {
+            Some(err) => Err(std::io::Error::new(err.kind(), format!("partially written data is not truncated: {}", err))),
+            None => Ok(()),
+        }
+    }
+
+    /// Pass error of writing to the error callback and keep it for 'FileWorkerCounters::take_last_write_error', returns copy of the error because io::Error can't be cloned.
+    fn report_error(&mut self, context: WriteErrorContext) -> std::io::Error {
+        let error = std::io::Error::new(context.error.kind(), context.error.to_string());
+        self.counters.set_last_write_error(&error);
+        if let Some(callback) = &mut self.error_callback {
+            callback(context);
+        }
+        error
+    }
+
+    /// Sync written data to disk if it's time by 'FsyncPolicy', error of sync is passed to the error callback
+    /// and returned by the next flush as error of writing.
+    fn sync_if_due(&mut self) {
+        let due = match (self.fsync_policy, self.unsynced_since) {
+            (_, None) | (FsyncPolicy::Never, _) => false,
+            (FsyncPolicy::EveryWrite, _) => true,
+            (FsyncPolicy::EveryNOps(count), _) => self.unsynced_writes >= count,
+            (FsyncPolicy::Interval(interval), Some(unsynced_since)) => self.clock.elapsed(unsynced_since) >= interval,
+        };
+        if due {
+            self.sync();
+        }
+    }
+
+    /// Time until written data must be synced by 'FsyncPolicy::Interval', None if it's not used or all data is synced.
+    fn time_to_sync(&self) -> Option<Duration> {
+        match (self.fsync_policy, self.unsynced_since) {
+            (FsyncPolicy::Interval(interval), Some(unsynced_since)) => Some(interval.saturating_sub(self.clock.elapsed(unsynced_since))),
+            _ => None,
+        }
+    }
+
+    /// Sync not yet synced data to disk, returns false if sync failed.
+    fn sync(&mut self) -> bool {
+        let bytes = std::mem::take(&mut self.unsynced_bytes);
+        self.unsynced_writes = 0;
+        self.unsynced_since = None;
+        match self.file.sync_data() {
+            Ok(()) => {
+                #[cfg(feature = "tracing")]
+                tracing::trace!(bytes, "synced to disk");
+                true
+            },
+            Err(error) => {
+                self.report_sync_error(error, bytes);
+                false
+            },
+        }
+    }
+
+    /// Sync written data and save the head of the chain at the end of 'data' of the last write to the head file,
+    /// the head is saved only after the data is on disk, so after crash the head file is never ahead of the file.
+    /// Error is passed to the error callback and returned by the next flush as error of writing.
+    fn save_chain_head(&mut self, data: &[u8]) {
+        if !self.sync() {
+            return;
+        }
+        let saved = match &self.chain_head {
+            Some(chain_head) => chain_head.save_after_write(data, self.offset),
+            None => return,
+        };
+        if let Err(error) = saved {
+            self.consecutive_failures += 1;
+            self.counters.write_errors.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "tracing")]
+            tracing::error!(error = %error, "write of chain head file error");
+            let context = WriteErrorContext { error, file_path: self.file_path.clone(), sequence: self.sequence, operation: WriteOperation::ChainHead, bytes: 0, consecutive_failures: self.consecutive_failures, data: Vec::new() };
+            let error = self.report_error(context);
+            self.error_since_flush.get_or_insert(error);
+        }
+    }
+
+    /// Pass error of flush or sync to the error callback, it's returned by the next flush as error of writing.
+    fn report_sync_error(&mut self, error: std::io::Error, bytes: usize) {
+        self.consecutive_failures += 1;
+        self.counters.write_errors.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        tracing::error!(bytes, error = %error, "sync to disk error");
+        let context = WriteErrorContext { error, file_path: self.file_path.clone(), sequence: self.sequence, operation: WriteOperation::Fsync, bytes, consecutive_failures: self.consecutive_failures, data: Vec::new() };
+        let error = self.report_error(context);
+        self.error_since_flush.get_or_insert(error);
+    }
+
+    /// Write data written to the file to the sink and shipping.
+    fn write_after_file(&mut self, data: &[u8]) {
+        // sink errors are reported separately and don't affect writing to the file
+        if let Some(Err(err)) = self.sink.as_mut().map(|sink| sink.write_all(data)) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(bytes = data.len(), error = %err, "write to secondary sink error");
+            if let Some(callback) = &mut self.sink_error_callback { callback(err); }
+        }
+
+        if let Some(shipping_worker) = &self.shipping_worker {
+            shipping_worker.ship(data.to_vec());
+        }
+    }
+
+    /// Flush the file, the first error of writing since the previous flush is returned instead of result of flush.
+    fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.file.flush();
+        match self.error_since_flush.take() {
+            Some(error) => Err(error),
+            None => result,
+        }
+    }
+
+    /// Replace the file with other locked file of 'file_len', next data is written to it.
+    /// Not yet synced data of the old file is not synced, the old file is closed and unlocked.
+    /// Returns the first error of writing since the previous flush, the file is replaced anyway.
+    fn replace_file(&mut self, file: Box<dyn WorkerFile>, file_len: u64) -> std::io::Result<()> {
+        self.file = file;
+        self.offset = file_len;
+        self.counters.file_len.store(file_len, Ordering::Release);
+        self.unsynced_writes = 0;
+        self.unsynced_bytes = 0;
+        self.unsynced_since = None;
+        match self.error_since_flush.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Flush the file and sync not yet synced data with any 'FsyncPolicy', flush the sink and wait of shipping of remaining data.
+    /// Returns the first error of writing since the previous flush or error of flush or sync of the file,
+    /// errors are passed to the error callback too.
+    fn stop(&mut self) -> std::io::Result<()> {
+        if let Err(error) = self.file.flush() {
+            let bytes = self.unsynced_bytes;
+            self.report_sync_error(error, bytes);
+        }
+        if self.unsynced_since.is_some() {
+            self.sync();
+        }
+        if let Some(Err(err)) = self.sink.as_mut().map(|sink| sink.flush()) {
+            if let Some(callback) = &mut self.sink_error_callback { callback(err); }
+        }
+        // ships remaining data and waits of shipping thread
+        self.shipping_worker.take();
+        #[cfg(feature = "tracing")]
+        tracing::debug!("file worker stopped");
+
+        match self.error_since_flush.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
 }
 
 impl FileWorker {
-    /// Constructs 'FileWorker' for write to the file in background thread.
+    /// Constructs 'FileWorker' for write to the file in background thread or in the calling thread by 'FileWorkerCfg::write_mode'.
     /// Writes in the order of queue.
     /// Parameter 'file' is opened and exclusive locked file.
-    /// Parameter 'error_callback' callback for receive errors or writing to the file.
-    pub fn new<Writer>(
-        mut file: Writer,
-        mut error_callback: Option<Box<dyn FnMut(std::io::Error) + Send>>
-    ) -> Self
-    where
-        Writer: std::io::Write + Send + 'static
-    {
-        let (tasks_sender, task_receiver) = channel();
-
-        let join_handle = Some(spawn(move || 'thread_loop: loop {
-            let task = task_receiver.recv()
-                .unwrap_or_else(|err| unreachable!(err)); // unreachable because owner thread will join this thread handle after send FileWorkerTask::Stop and only after will disconnect channel
+    /// Parameter 'cfg' callbacks and settings of writing.
+    pub fn new(file: impl WorkerFile + 'static, cfg: FileWorkerCfg) -> Self {
+        let FileWorkerCfg { file_path, file_len, error_callback, ack_callback, sink, sink_error_callback, log_shipping, write_channel, max_pending_writes, write_mode, fsync_policy, write_retry, failure_callback, stop_timeout, chain_head, clock } = cfg;
+        let counters = Arc::new(FileWorkerCounters { file_len: AtomicU64::new(file_len), ..FileWorkerCounters::default() });
+        let mut writing = FileWriting {
+            file: Box::new(file),
+            file_path,
+            error_callback,
+            ack_callback,
+            sink,
+            sink_error_callback,
+            shipping_worker: log_shipping.map(|log_shipping| ShippingWorker::new(log_shipping, clock.clone())),
+            consecutive_failures: 0,
+            sequence: 0,
+            offset: file_len,
+            error_since_flush: None,
+            counters: counters.clone(),
+            fsync_policy,
+            unsynced_writes: 0,
+            unsynced_bytes: 0,
+            unsynced_since: None,
+            write_retry,
+            truncate_error: None,
+            chain_head,
+            clock,
+        };
+
+        if write_mode == WriteMode::Sync {
+            return FileWorker {
+                mode: WorkerMode::Sync(Box::new(Mutex::new(writing))),
+                counters,
+                sent_writes: AtomicU64::new(0),
+                failure_callback: Mutex::new(failure_callback),
+                stop_timeout,
+                #[cfg(feature = "tracing")]
+                queue_high_water: AtomicUsize::new(0),
+            };
+        }
+
+        let (tasks_sender, task_receiver) = task_channel(write_channel, max_pending_writes);
+        // events of the worker thread go to the subscriber of the thread that created the worker
+        #[cfg(feature = "tracing")]
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+
+        // task received while write tasks were taken from the queue
+        let mut next_task = None;
+        // taken write tasks are pending too, so bounded queue keeps its bound of memory
+        let max_coalesced_tasks = max_pending_writes.unwrap_or(usize::MAX);
+        let mut thread_loop = move || 'thread_loop: loop {
+            let task = match next_task.take() {
+                Some(task) => task,
+                None => match writing.time_to_sync() {
+                    Some(timeout) => match task_receiver.recv_task_timeout(timeout) {
+                        Ok(task) => task,
+                        Err(RecvTimeoutError::Timeout) => {
+                            writing.sync_if_due();
+                            continue 'thread_loop;
+                        },
+                        Err(RecvTimeoutError::Disconnected) => unreachable!(), // unreachable because owner thread will join this thread handle after send FileWorkerTask::Stop and only after will disconnect channel
+                    },
+                    None => task_receiver.recv_task()
+                        .unwrap_or_else(|| unreachable!()), // unreachable because owner thread will join this thread handle after send FileWorkerTask::Stop and only after will disconnect channel
+                },
+            };
 
             match task {
-                FileWorkerTask::WriteString(data) => {
-                    if let Err(err) = file.write_all(data.as_bytes()) {
-                        if let Some(callback) = &mut error_callback { callback(err); }
-                    }
+                FileWorkerTask::Flush(result_sender) => {
+                    // owner can stop waiting of result, so error of sending is not important
+                    let _ = result_sender.send(writing.flush());
+                    continue 'thread_loop;
                 },
-                FileWorkerTask::WriteBytes(data) => {
-                    if let Err(err) = file.write_all(&data) {
-                        if let Some(callback) = &mut error_callback { callback(err); }
-                    }
+                FileWorkerTask::ReplaceFile(file, file_len, result_sender) => {
+                    // owner waits of result
+                    let _ = result_sender.send(writing.replace_file(file, file_len));
+                    continue 'thread_loop;
                 },
-                FileWorkerTask::Stop => {
+                FileWorkerTask::Stop(result_sender) => {
+                    // owner waits of result after join of the thread
+                    let _ = result_sender.send(writing.stop());
                     break 'thread_loop;
                 },
+                FileWorkerTask::WriteString(..) | FileWorkerTask::WriteBytes(..) => {},
+            }
+
+            // write tasks waiting in the queue are written with this one, the next other task is processed after them
+            let mut tasks = vec![task];
+            let mut bytes = tasks[0].write().map_or(0, |(data, _)| data.len());
+            while bytes < COALESCED_WRITE_BYTES && tasks.len() < max_coalesced_tasks {
+                match task_receiver.try_recv_task() {
+                    Some(task) => match task.write() {
+                        Some((data, _)) => {
+                            bytes += data.len();
+                            tasks.push(task);
+                        },
+                        None => {
+                            next_task = Some(task);
+                            break;
+                        },
+                    },
+                    None => break,
+                }
             }
-        }));
+            let writes = tasks.iter().filter_map(FileWorkerTask::write).collect::<Vec<_>>();
 
-        FileWorker { task_sender: tasks_sender, join_handle }
+            let mut write_errors = Vec::new();
+            if let Err(contexts) = writing.write_file(&writes) {
+                for context in contexts {
+                    let sequence = context.sequence;
+                    let error = writing.report_error(context);
+                    write_errors.push((sequence, std::io::Error::new(error.kind(), error.to_string())));
+                    writing.error_since_flush.get_or_insert(error);
+                }
+            }
+            for (data, _) in &writes {
+                writing.write_after_file(data);
+            }
+            writing.sync_if_due();
+            writing.counters.complete_writes(writing.sequence, write_errors);
+
+            if writing.counters.pending_writes.fetch_sub(writes.len(), Ordering::AcqRel) == writes.len() {
+                writing.counters.set_flushed_at(writing.clock.now());
+            }
+        };
+
+        // waiting of writes is woken with error if the thread panics
+        let thread_counters = counters.clone();
+        let thread_loop = move || {
+            let _panic_guard = WorkerPanicGuard(thread_counters);
+            thread_loop()
+        };
+
+        #[cfg(feature = "tracing")]
+        let join_handle = spawn(move || tracing::dispatcher::with_default(&dispatch, thread_loop));
+        #[cfg(not(feature = "tracing"))]
+        let join_handle = spawn(thread_loop);
+
+        FileWorker {
+            mode: WorkerMode::Background { task_sender: tasks_sender, join_handle },
+            counters,
+            sent_writes: AtomicU64::new(0),
+            failure_callback: Mutex::new(failure_callback),
+            stop_timeout,
+            #[cfg(feature = "tracing")]
+            queue_high_water: AtomicUsize::new(0),
+        }
+    }
+
+    /// Constructs 'FileWorker' of the map opened read-only, the thread of writing is not spawned
+    /// and all writes return 'read_only_error'. Parameter 'file' is opened for reading and shared locked file of 'file_len'.
+    pub fn read_only(file: std::fs::File, file_len: u64) -> Self {
+        FileWorker {
+            mode: WorkerMode::ReadOnly { _locked_file: file },
+            counters: Arc::new(FileWorkerCounters { file_len: AtomicU64::new(file_len), ..FileWorkerCounters::default() }),
+            sent_writes: AtomicU64::new(0),
+            failure_callback: Mutex::new(None),
+            stop_timeout: None,
+            #[cfg(feature = "tracing")]
+            queue_high_water: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns true if the worker is constructed by 'read_only'.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.mode, WorkerMode::ReadOnly { .. })
+    }
+
+    /// Write data of the operation to the file in the background thread, error only if the thread panicked,
+    /// or in the calling thread with 'WriteMode::Sync', then error of writing is returned.
+    pub fn write_string(&self, data: String, operation: WriteOperation) -> std::io::Result<()> {
+        if self.is_read_only() {
+            return Err(read_only_error());
+        }
+        self.sent_writes.fetch_add(1, Ordering::Relaxed);
+        match &self.mode {
+            WorkerMode::Background { task_sender, .. } => {
+                self.send_write(task_sender, FileWorkerTask::WriteString(data, operation))
+            },
+            WorkerMode::Sync(writing) => self.write_sync(writing, data.as_bytes(), operation),
+            // unreachable because read-only worker is checked above and worker is stopped only by 'close' consuming it and by drop
+            WorkerMode::ReadOnly { .. } | WorkerMode::Stopped => unreachable!(),
+        }
+    }
+
+    /// Write data of the operation to the file in the background thread, error only if the thread panicked,
+    /// or in the calling thread with 'WriteMode::Sync', then error of writing is returned.
+    pub fn write_bytes(&self, data: Vec<u8>, operation: WriteOperation) -> std::io::Result<()> {
+        if self.is_read_only() {
+            return Err(read_only_error());
+        }
+        self.sent_writes.fetch_add(1, Ordering::Relaxed);
+        match &self.mode {
+            WorkerMode::Background { task_sender, .. } => {
+                self.send_write(task_sender, FileWorkerTask::WriteBytes(data, operation))
+            },
+            WorkerMode::Sync(writing) => self.write_sync(writing, &data, operation),
+            // unreachable because read-only worker is checked above and worker is stopped only by 'close' consuming it and by drop
+            WorkerMode::ReadOnly { .. } | WorkerMode::Stopped => unreachable!(),
+        }
+    }
+
+    /// Request to flush the file after writing all data sent before.
+    /// Result of flush or the first error of writing since the previous flush will be sent to the returned receiver.
+    pub fn flush(&self) -> Receiver<std::io::Result<()>> {
+        let (result_sender, result_receiver) = channel();
+        match &self.mode {
+            WorkerMode::Background { task_sender, .. } => {
+                let (error_sender, error_receiver) = channel();
+                // channel of tasks is disconnected only if the thread panicked
+                if task_sender.send_task(FileWorkerTask::Flush(result_sender)).is_none() {
+                    let _ = error_sender.send(Err(worker_panicked_error()));
+                    return error_receiver;
+                }
+            },
+            WorkerMode::Sync(writing) => {
+                // receiver is not dropped yet
+                let _ = result_sender.send(lock_writing(writing).flush());
+            },
+            WorkerMode::ReadOnly { .. } => {
+                // nothing is written, so there is nothing to flush
+                let _ = result_sender.send(Ok(()));
+            },
+            WorkerMode::Stopped => unreachable!(), // unreachable because worker is stopped only by 'close' consuming it and by drop
+        }
+        result_receiver
+    }
+
+    /// Replace the file with other opened for appending and exclusive locked file of 'file_len' after writing all data sent before,
+    /// next data is written to it. Returns the first error of writing since the previous flush, the file is replaced anyway.
+    pub fn replace_file(&self, file: impl WorkerFile + 'static, file_len: u64) -> std::io::Result<()> {
+        match &self.mode {
+            WorkerMode::Background { task_sender, .. } => {
+                let (result_sender, result_receiver) = channel();
+                task_sender.send_task(FileWorkerTask::ReplaceFile(Box::new(file), file_len, result_sender))
+                    .ok_or_else(worker_panicked_error)?; // channel of tasks is disconnected only if the thread panicked
+                result_receiver.recv()
+                    .unwrap_or_else(|_| Err(worker_panicked_error())) // result is not received only if the thread panicked
+            },
+            WorkerMode::Sync(writing) => lock_writing(writing).replace_file(Box::new(file), file_len),
+            WorkerMode::ReadOnly { .. } => Err(read_only_error()),
+            WorkerMode::Stopped => unreachable!(), // unreachable because worker is stopped only by 'close' consuming it and by drop
+        }
     }
 
-    /// Write data to the file in the background thread.
-    pub fn write_string(&self, data: String) {
-        let task = FileWorkerTask::WriteString(data);
-        self.task_sender.send(task)
-            .unwrap_or_else(|err| unreachable!(err)); // unreachable because channel receiver will drop only after out of thread and thread can't stop while FileWorkerTask::Stop is not received
+    /// Sequence number of the last sent write, 0 if nothing was sent. Writes are numbered as 'WriteAck::sequence'.
+    pub fn last_write_sequence(&self) -> u64 {
+        self.sent_writes.load(Ordering::Relaxed)
     }
 
-    /// Write data to the file in the background thread.
-    pub fn write_bytes(&self, data: Vec<u8>) {
-        let task = FileWorkerTask::WriteBytes(data);
-        self.task_sender.send(task)
-            .unwrap_or_else(|err| unreachable!(err)); // unreachable because channel receiver will drop only after out of thread and thread can't stop while FileWorkerTask::Stop is not received
+    /// Counters of the worker thread.
+    pub fn counters(&self) -> &FileWorkerCounters {
+        &self.counters
+    }
+
+    /// Counters of the worker thread for reading from other thread.
+    pub fn shared_counters(&self) -> Arc<FileWorkerCounters> {
+        self.counters.clone()
+    }
+
+    /// Write all sent data, flush and sync the file with any 'FsyncPolicy' and stop writing, the file is unlocked after it.
+    /// Returns the first error of writing since the previous flush or error of flush or sync, errors are passed to the error callback too.
+    pub fn close(mut self) -> std::io::Result<()> {
+        self.stop()
+    }
+
+    /// Stop writing, see 'close'.
+    fn stop(&mut self) -> std::io::Result<()> {
+        match std::mem::replace(&mut self.mode, WorkerMode::Stopped) {
+            WorkerMode::Background { task_sender, join_handle } => {
+                let (result_sender, result_receiver) = channel();
+                // channel of tasks is disconnected if the thread panicked, then result is not received too
+                let _ = task_sender.send_task(FileWorkerTask::Stop(result_sender));
+                let result = match self.stop_timeout {
+                    Some(stop_timeout) => result_receiver.recv_timeout(stop_timeout),
+                    None => result_receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match result {
+                    Ok(result) => {
+                        let _ = join_handle.join();
+                        result
+                    },
+                    Err(RecvTimeoutError::Timeout) => {
+                        // the thread is detached and finishes writing itself
+                        #[cfg(feature = "tracing")]
+                        tracing::error!("file worker thread is not stopped in time");
+                        self.report_failure(WorkerFailure::StopTimeout);
+                        Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "file worker thread is not stopped in time"))
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        let message = match join_handle.join() {
+                            Err(payload) => panic_message(payload.as_ref()),
+                            Ok(()) => String::new(),
+                        };
+                        #[cfg(feature = "tracing")]
+                        tracing::error!(message = %message, "file worker thread panicked");
+                        self.counters.set_worker_panicked();
+                        self.report_failure(WorkerFailure::Panicked(message));
+                        Err(worker_panicked_error())
+                    },
+                }
+            },
+            WorkerMode::Sync(writing) => {
+                writing.into_inner()
+                    .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in callback of config, writing is still usable in this case
+                    .stop()
+            },
+            WorkerMode::ReadOnly { .. } | WorkerMode::Stopped => Ok(()),
+        }
+    }
+
+    /// Write data in the calling thread, partially written data is truncated if writing fails,
+    /// so the file is not changed. If truncate fails, next writes fail until the data is truncated.
+    /// Error is passed to the error callback too.
+    fn write_sync(&self, writing: &Mutex<FileWriting>, data: &[u8], operation: WriteOperation) -> std::io::Result<()> {
+        let mut writing = lock_writing(writing);
+        if let Err(contexts) = writing.write_file(&[(data, operation)]) {
+            let context = contexts.into_iter().next()
+                .unwrap_or_else(|| unreachable!()); // unreachable because context is returned for each operation
+            let sequence = context.sequence;
+            let error = writing.report_error(context);
+            self.counters.complete_writes(sequence, vec![(sequence, std::io::Error::new(error.kind(), error.to_string()))]);
+            return Err(error);
+        }
+        writing.write_after_file(data);
+        writing.sync_if_due();
+        self.counters.complete_writes(writing.sequence, Vec::new());
+        self.counters.set_flushed_at(writing.clock.now());
+        Ok(())
+    }
+
+    /// Pass failure of the background thread to the callback.
+    fn report_failure(&mut self, failure: WorkerFailure) {
+        let failure_callback = self.failure_callback.get_mut()
+            .unwrap_or_else(|err| err.into_inner()); // poisoned only by panic of the callback, it's called again then
+        if let Some(callback) = failure_callback {
+            callback(failure);
+        }
+    }
+
+    /// Send write task to the worker thread, waits if the channel is bounded by 'Cfg::max_pending_writes' and full.
+    /// Returns error if the thread panicked.
+    fn send_write(&self, task_sender: &TaskSender, task: FileWorkerTask) -> std::io::Result<()> {
+        self.counters.pending_writes.fetch_add(1, Ordering::AcqRel);
+
+        #[cfg(feature = "tracing")]
+        {
+            let pending_writes = self.counters.pending_writes.load(Ordering::Relaxed);
+            let prev_high_water = self.queue_high_water.fetch_max(pending_writes, Ordering::Relaxed);
+            // report only when high-water mark reaches next power of two for avoid flood of events
+            if pending_writes > prev_high_water && pending_writes.is_power_of_two() {
+                tracing::debug!(pending_writes, "file worker queue high-water mark");
+            }
+        }
+
+        // channel of tasks is disconnected only if the thread panicked
+        task_sender.send_task(task).ok_or_else(|| {
+            self.counters.pending_writes.fetch_sub(1, Ordering::AcqRel);
+            worker_panicked_error()
+        })
+    }
+}
+
+/// Marker of error of writing to the map opened read-only, see 'read_only_error'.
+#[derive(Debug)]
+struct ReadOnlyMap;
+
+impl std::fmt::Display for ReadOnlyMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "map is opened read-only")
+    }
+}
+
+impl std::error::Error for ReadOnlyMap {}
+
+/// Error of writing to the map opened by 'MapWithFile::open_read_only'.
+pub(crate) fn read_only_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, ReadOnlyMap)
+}
+
+/// Returns true if it's 'read_only_error'.
+pub(crate) fn is_read_only_error(err: &std::io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<ReadOnlyMap>())
+}
+
+/// Marks the background thread as panicked in counters when dropped by unwinding of the thread.
+struct WorkerPanicGuard(Arc<FileWorkerCounters>);
+
+impl Drop for WorkerPanicGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.set_worker_panicked();
+        }
+    }
+}
+
+/// Error of writing after panic of the background thread.
+pub(crate) fn worker_panicked_error() -> std::io::Error {
+    std::io::Error::other("file worker thread panicked")
+}
+
+/// Message of panic passed to 'panic!' as string.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
     }
 }
 
 impl Drop for FileWorker {
+    /// Errors of stop are passed to the error callback.
     fn drop(&mut self) {
-        self.task_sender.send(FileWorkerTask::Stop)
-            .unwrap_or_else(|err| unreachable!(err)); // unreachable because thread can't stop while FileWorkerTask::Stop is not received
-        self.join_handle.take().map(JoinHandle::join);
+        let _ = self.stop();
+    }
+}
+
+/// Lock state of writing in the calling thread.
+fn lock_writing(writing: &Mutex<FileWriting>) -> std::sync::MutexGuard<'_, FileWriting> {
+    writing.lock()
+        .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in callback of config, writing is still usable in this case
+}
+
+impl FileWorkerTask {
+    /// Data and operation of write task, None for other tasks.
+    fn write(&self) -> Option<(&[u8], WriteOperation)> {
+        match self {
+            FileWorkerTask::WriteString(data, operation) => Some((data.as_bytes(), *operation)),
+            FileWorkerTask::WriteBytes(data, operation) => Some((&data[..], *operation)),
+            FileWorkerTask::Flush(_) | FileWorkerTask::Stop(_) | FileWorkerTask::ReplaceFile(..) => None,
+        }
     }
 }
 
 /// Task for sending to worker thread.
 enum FileWorkerTask {
     /// Write line to the file in the background thread.
-    WriteString(String),
+    WriteString(String, WriteOperation),
     /// Write data block to the file in the background thread.
-    WriteBytes(Vec<u8>),
-    /// Stop worker.
-    Stop,
+    WriteBytes(Vec<u8>, WriteOperation),
+    /// Flush the file and send result, error of writing since the previous flush is sent instead of result of flush.
+    Flush(Sender<std::io::Result<()>>),
+    /// Flush and sync the file, send result and stop worker.
+    Stop(Sender<std::io::Result<()>>),
+    /// Replace the file with other file of the length and send result.
+    ReplaceFile(Box<dyn WorkerFile>, u64, Sender<std::io::Result<()>>),
+}
+
+/// Sending side of the channel of tasks of the selected 'WriteChannel'.
+enum TaskSender {
+    /// Unbounded std channel.
+    Std(Sender<FileWorkerTask>),
+    /// Bounded std channel.
+    StdBounded(SyncSender<FileWorkerTask>),
+    /// Bounded or unbounded crossbeam channel.
+    #[cfg(feature = "crossbeam")]
+    Crossbeam(crossbeam_channel::Sender<FileWorkerTask>),
+}
+
+/// Receiving side of the channel of tasks of the selected 'WriteChannel'.
+enum TaskReceiver {
+    /// Bounded or unbounded std channel.
+    Std(Receiver<FileWorkerTask>),
+    /// Bounded or unbounded crossbeam channel.
+    #[cfg(feature = "crossbeam")]
+    Crossbeam(crossbeam_channel::Receiver<FileWorkerTask>),
+}
+
+impl TaskSender {
+    /// Send task to the worker thread, returns None if the receiver is dropped.
+    fn send_task(&self, task: FileWorkerTask) -> Option<()> {
+        match self {
+            TaskSender::Std(sender) => sender.send(task).ok(),
+            TaskSender::StdBounded(sender) => sender.send(task).ok(),
+            #[cfg(feature = "crossbeam")]
+            TaskSender::Crossbeam(sender) => sender.send(task).ok(),
+        }
+    }
+}
+
+impl TaskReceiver {
+    /// Wait for the next task, returns None if all senders are dropped.
+    fn recv_task(&self) -> Option<FileWorkerTask> {
+        match self {
+            TaskReceiver::Std(receiver) => receiver.recv().ok(),
+            #[cfg(feature = "crossbeam")]
+            TaskReceiver::Crossbeam(receiver) => receiver.recv().ok(),
+        }
+    }
+
+    /// Wait for the next task not longer than 'timeout'.
+    fn recv_task_timeout(&self, timeout: Duration) -> Result<FileWorkerTask, RecvTimeoutError> {
+        match self {
+            TaskReceiver::Std(receiver) => receiver.recv_timeout(timeout),
+            #[cfg(feature = "crossbeam")]
+            TaskReceiver::Crossbeam(receiver) => receiver.recv_timeout(timeout)
+                .map_err(|err| if err.is_timeout() { RecvTimeoutError::Timeout } else { RecvTimeoutError::Disconnected }),
+        }
+    }
+
+    /// Returns the next task without waiting, None if there are no tasks.
+    fn try_recv_task(&self) -> Option<FileWorkerTask> {
+        match self {
+            TaskReceiver::Std(receiver) => receiver.try_recv().ok(),
+            #[cfg(feature = "crossbeam")]
+            TaskReceiver::Crossbeam(receiver) => receiver.try_recv().ok(),
+        }
+    }
+}
+
+/// Returns channel of tasks of the selected implementation, bounded by 'capacity' if it's set.
+/// Sending waits when bounded channel is full.
+fn task_channel(write_channel: WriteChannel, capacity: Option<usize>) -> (TaskSender, TaskReceiver) {
+    match (write_channel, capacity) {
+        (WriteChannel::Std, None) => {
+            let (sender, receiver) = channel();
+            (TaskSender::Std(sender), TaskReceiver::Std(receiver))
+        },
+        (WriteChannel::Std, Some(capacity)) => {
+            let (sender, receiver) = sync_channel(capacity);
+            (TaskSender::StdBounded(sender), TaskReceiver::Std(receiver))
+        },
+        #[cfg(feature = "crossbeam")]
+        (WriteChannel::Crossbeam, None) => {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            (TaskSender::Crossbeam(sender), TaskReceiver::Crossbeam(receiver))
+        },
+        #[cfg(feature = "crossbeam")]
+        (WriteChannel::Crossbeam, Some(capacity)) => {
+            let (sender, receiver) = crossbeam_channel::bounded(capacity);
+            (TaskSender::Crossbeam(sender), TaskReceiver::Crossbeam(receiver))
+        },
+    }
 }
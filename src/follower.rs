@@ -0,0 +1,369 @@
+use crate::bin_format::{complete_bin_blocks_len, load_bin_file_records};
+use crate::cfg::{Cfg, Format};
+use crate::format::{LoadLimits, MapOperation};
+use crate::index::{Index, MakeIndexKey, UpdateIndex};
+use crate::map_trait::MapTrait;
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "watch")]
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+/// Interval of checking of the file lock by 'wait_for_writer_release'.
+const WAIT_FOR_WRITER_RELEASE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Read-only follower of the history file written by other process or other map.
+/// Based on std::collections::BTreeMap.
+pub type FollowerBTreeMap<Key, Value> = FollowerMap<Key, Value, std::collections::BTreeMap<Key, Value>>;
+
+/// Read-only follower of the history file written by other process or other map.
+/// Based on std::collections::HashMap.
+pub type FollowerHashMap<Key, Value> = FollowerMap<Key, Value, std::collections::HashMap<Key, Value>>;
+
+/// Read-only map that follows the history file written by other process or other map.
+/// Loads the file when opening and then reads only the newly appended records
+/// applying them to own map and indexes.
+/// The file is read under shared lock if it's possible, but if the writer holds
+/// exclusive lock, the file is read without lock because the writer only appends to the file.
+/// Incomplete record at the end of the file (the writer is writing it at the moment)
+/// is not treated as corruption and will be read by the next refresh.
+pub struct FollowerMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Followed map container.
+    map: Map,
+    /// Config. Integrity of config contains state of the last applied record.
+    cfg: Cfg,
+    /// Opened for reading history file.
+    file: File,
+    /// Count of bytes of the file that already applied to the map.
+    offset: u64,
+    /// Interval for 'poll'.
+    poll_interval: Duration,
+    /// Time of last refresh.
+    last_refresh: Instant,
+    /// Created indexes.
+    indexes: Vec<Box<dyn UpdateIndex<Key, Value>>>,
+    /// Path of followed file.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    file_path: PathBuf,
+    /// Callback called after each refresh that changed the map.
+    update_callback: Option<UpdateCallback<Key>>,
+    /// Watcher of the file modifications, if None then file is polled by interval.
+    #[cfg(feature = "watch")]
+    watcher: Option<FileWatcher>,
+}
+
+/// Callback called after refresh with changed keys.
+type UpdateCallback<Key> = Box<dyn FnMut(&MapChangeBatch<Key>)>;
+
+/// Keys changed in the map by one refresh in order of records in the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapChangeBatch<Key> {
+    /// Changes of keys.
+    pub changes: Vec<KeyChange<Key>>,
+}
+
+/// Change of key of the map.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyChange<Key> {
+    /// Key inserted or value of key updated.
+    Inserted(Key),
+    /// Key removed.
+    Removed(Key),
+}
+
+impl<Key, Value, Map> FollowerMap<Key, Value, Map>
+where
+    Key: DeserializeOwned + Ord + Clone + 'static,
+    Value: DeserializeOwned + Clone + 'static,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Open existing history file for following and loads all complete records from it.
+    /// 'poll_interval' is min interval between reading of the file by 'poll'.
+    pub fn open(file_path: impl AsRef<Path>, cfg: Cfg, poll_interval: Duration) -> Result<Self, LoadFileError> {
+        let file = OpenOptions::new().read(true).open(&file_path)?;
+
+        let mut follower = FollowerMap {
+            map: Map::default(),
+            cfg,
+            file,
+            offset: 0,
+            poll_interval,
+            last_refresh: Instant::now(),
+            indexes: Vec::new(),
+            file_path: file_path.as_ref().to_path_buf(),
+            update_callback: None,
+            #[cfg(feature = "watch")]
+            watcher: None,
+        };
+
+        follower.refresh()?;
+
+        Ok(follower)
+    }
+
+    /// Read records appended to the file since last refresh and apply them to the map and indexes.
+    /// Returns count of applied records.
+    /// Records read by one refresh are applied all or nothing, so on error the map stays unchanged
+    /// and next refresh will try to read the same records again.
+    /// Line or block numbers in errors are counted from the first record read by this refresh.
+    pub fn refresh(&mut self) -> Result<usize, LoadFileError> {
+        self.last_refresh = Instant::now();
+
+        // the writer holds exclusive lock, in this case read without lock
+        let locked = FileExt::try_lock_shared(&self.file).is_ok();
+        let result = self.read_appended_records();
+        if locked {
+            FileExt::unlock(&self.file)?;
+        }
+
+        result
+    }
+
+    /// Same as 'refresh' but only if 'poll_interval' elapsed since last refresh, otherwise returns 0.
+    /// If the file is watched, then refresh is done only after modification of the file
+    /// and 'debounce' elapsed since last modification.
+    pub fn poll(&mut self) -> Result<usize, LoadFileError> {
+        #[cfg(feature = "watch")]
+        {
+            let mut watcher_is_broken = false;
+            if let Some(watcher) = &mut self.watcher {
+                match watcher.is_time_to_refresh() {
+                    Some(true) => return self.refresh(),
+                    Some(false) => return Ok(0),
+                    None => watcher_is_broken = true,
+                }
+            }
+            if watcher_is_broken {
+                // fallback to polling by interval
+                self.watcher = None;
+            }
+        }
+
+        if self.last_refresh.elapsed() < self.poll_interval {
+            return Ok(0);
+        }
+
+        self.refresh()
+    }
+
+    /// Watch modifications of the file instead of polling by interval, see 'poll'.
+    /// 'debounce' is min time after last modification before reading the file,
+    /// so burst of modifications is read by one refresh.
+    /// If the watcher can't be started, then error is returned and the file is still polled by interval.
+    #[cfg(feature = "watch")]
+    pub fn watch(&mut self, debounce: Duration) -> Result<(), notify::Error> {
+        use notify::Watcher;
+
+        let (events_sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if event.is_ok() {
+                // receiver is dropped with the watcher, so error of sending is not important
+                let _ = events_sender.send(());
+            }
+        })?;
+        watcher.watch(&self.file_path, notify::RecursiveMode::NonRecursive)?;
+
+        self.watcher = Some(FileWatcher { _watcher: watcher, events, last_event: None, debounce });
+
+        Ok(())
+    }
+
+    /// Returns true if the file is watched, false if polled by interval.
+    #[cfg(feature = "watch")]
+    pub fn is_watching(&self) -> bool {
+        self.watcher.is_some()
+    }
+
+    /// Set callback that will be called after each refresh that changed the map with changed keys.
+    pub fn on_update(&mut self, f: impl FnMut(&MapChangeBatch<Key>) + 'static) {
+        self.update_callback = Some(Box::new(f));
+    }
+
+    /// Wait until no process holds lock of the file (the writer is closed), but not longer than 'timeout'.
+    /// Returns true if the file is free, after that the file can be reopened with 'LockRole::Writer'.
+    /// Other reader can take the file first, in this case opening with 'LockRole::Writer' waits of it.
+    pub fn wait_for_writer_release(&self, timeout: Duration) -> Result<bool, std::io::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match FileExt::try_lock_exclusive(&self.file) {
+                Ok(()) => {
+                    FileExt::unlock(&self.file)?;
+                    return Ok(true);
+                },
+                Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {},
+                Err(err) => return Err(err),
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+
+            std::thread::sleep((deadline - now).min(WAIT_FOR_WRITER_RELEASE_INTERVAL));
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.map.get(key)
+    }
+
+    /// Returns reference to the used map.
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    /// Returns count of bytes of the file that already applied to the map.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Create index by value based on std::collections::BTreeMap.
+    /// Index is updated by each refresh.
+    pub fn create_btree_index<IndexKey>(&mut self, make_index_key_callback: fn(&Value) -> IndexKey)
+        -> Index<IndexKey, Key, Value, std::collections::BTreeMap<IndexKey, Arc<BTreeSet<Key>>>>
+    where IndexKey: Clone + Ord + 'static {
+        self.create_index::<IndexKey, std::collections::BTreeMap<IndexKey, Arc<BTreeSet<Key>>>>(make_index_key_callback)
+    }
+
+    /// Create index by value based on std::collections::HashMap.
+    /// Index is updated by each refresh.
+    pub fn create_hashmap_index<IndexKey>(&mut self, make_index_key_callback: fn(&Value) -> IndexKey)
+        -> Index<IndexKey, Key, Value, std::collections::HashMap<IndexKey, Arc<BTreeSet<Key>>>>
+    where IndexKey: Clone + Hash + Eq + 'static {
+        self.create_index::<IndexKey, std::collections::HashMap<IndexKey, Arc<BTreeSet<Key>>>>(make_index_key_callback)
+    }
+
+    /// Create index by value.
+    /// Index is updated by each refresh.
+    pub fn create_index<IndexKey, MapOfIndex>(&mut self, make_index_key_callback: fn(&Value) -> IndexKey)
+        -> Index<IndexKey, Key, Value, MapOfIndex>
+    where
+        IndexKey: Clone + Eq + 'static,
+        MapOfIndex: MapTrait<IndexKey, Arc<BTreeSet<Key>>> + Default + Sized + 'static,
+    {
+        let index = Index::from_owner_map(&self.map, MakeIndexKey::ByValue(make_index_key_callback));
+        self.indexes.push(Box::new(index.clone()));
+
+        index
+    }
+
+    /// Read complete records appended to the file after 'offset' and apply them.
+    fn read_appended_records(&mut self) -> Result<usize, LoadFileError> {
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        let mut data = Vec::new();
+        self.file.read_to_end(&mut data)?;
+
+        let complete_len = match &self.cfg.format {
+            Format::Text(..) => data.iter().rposition(|byte| *byte == b'\n').map(|pos| pos + 1).unwrap_or(0),
+            Format::Bin(..) => complete_bin_blocks_len(&data)?,
+        };
+
+        if complete_len == 0 {
+            return Ok(0);
+        }
+
+        // integrity state and map are changed only if all records are correct
+        let mut integrity = self.cfg.integrity.clone();
+        let mut operations = Vec::new();
+        let collect_map_operation = |map_operation| {
+            operations.push(map_operation);
+            Ok(())
+        };
+
+        let mut reader = &data[..complete_len];
+        // records are read by parts, so previous records are unknown for check of replay
+        let limits = LoadLimits { replay_check: None, file_path: Some(self.file_path.clone()), file_offset: self.offset, ..LoadLimits::of(&self.cfg) };
+        let loaded_tail = match &mut self.cfg.format {
+            Format::Text(_, after_read_callback) => {
+                load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut integrity, after_read_callback.as_mut(), self.cfg.value_schema_version, self.cfg.value_migrator.as_mut(), limits, collect_map_operation)?
+            },
+            Format::Bin(_, after_read_callback) => {
+                load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut integrity, after_read_callback.as_mut(), self.cfg.value_schema_version, self.cfg.value_migrator.as_mut(), limits, collect_map_operation)?
+            },
+        };
+
+        let count = operations.len();
+        let changes = operations.into_iter()
+            .filter_map(|map_operation| self.apply(map_operation))
+            .collect::<Vec<_>>();
+
+        // incomplete transaction at the end is read again by the next refresh
+        self.cfg.integrity = loaded_tail.incomplete_transaction_integrity.unwrap_or(integrity);
+        self.offset += loaded_tail.committed_len;
+
+        if !changes.is_empty() {
+            if let Some(callback) = &mut self.update_callback {
+                callback(&MapChangeBatch { changes });
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Apply operation read from the file to the map and indexes.
+    /// Returns None if map is not changed.
+    fn apply(&mut self, map_operation: MapOperation<Key, Value>) -> Option<KeyChange<Key>> {
+        match map_operation {
+            MapOperation::Insert(key, value) => {
+                let old_value = self.map.insert(key.clone(), value.clone());
+                for index in self.indexes.iter() {
+                    index.on_insert(key.clone(), value.clone(), old_value.clone());
+                }
+                Some(KeyChange::Inserted(key))
+            },
+            MapOperation::Remove(key) => {
+                let old_value = self.map.remove(&key)?;
+                for index in self.indexes.iter() {
+                    index.on_remove(&key, &old_value);
+                }
+                Some(KeyChange::Removed(key))
+            },
+        }
+    }
+}
+
+/// Watcher of the file modifications.
+#[cfg(feature = "watch")]
+struct FileWatcher {
+    /// Stops watching when dropped.
+    _watcher: notify::RecommendedWatcher,
+    /// Events of the file modifications.
+    events: Receiver<()>,
+    /// Time of last not yet processed modification.
+    last_event: Option<Instant>,
+    /// Min time after last modification before reading the file.
+    debounce: Duration,
+}
+
+#[cfg(feature = "watch")]
+impl FileWatcher {
+    /// Returns true if file was modified and debounce time elapsed after last modification.
+    /// Returns None if the watcher stopped working.
+    fn is_time_to_refresh(&mut self) -> Option<bool> {
+        loop {
+            match self.events.try_recv() {
+                Ok(()) => self.last_event = Some(Instant::now()),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+
+        match self.last_event {
+            Some(last_event) if last_event.elapsed() >= self.debounce => {
+                self.last_event = None;
+                Some(true)
+            },
+            _ => Some(false),
+        }
+    }
+}
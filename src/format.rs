@@ -1,18 +1,32 @@
-use crate::cfg::Format;
+use crate::cfg::{DeserializePolicy, Format, IntegrityKind, LoadProgress, LoadProgressCallback, MigrationError, RecoveryMode, WriteMode};
 use crate::Cfg;
+use std::convert::TryInto;
 use std::io::Write;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use crypto::sha1::Sha1;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use fs2::FileExt;
 use uuid::Uuid;
-use crate::text_format::{text_file_line_of_insert, file_line_of_remove, load_from_text_file};
-use crate::bin_format::load_from_bin_file;
+use crate::text_format::{text_record_kind_and_key, TextOps, TextRecordReader, text_file_line_of_compressible_insert, file_line_of_remove, load_text_file_records, text_file_line_of_batch, text_file_line_of_increment, text_file_line_of_item_operation, text_file_line_of_schema, text_file_line_of_set_operation, text_file_line_of_transaction_marker, text_file_line_with_meta, text_line_data_of_insert, text_line_data_of_remove};
+use crate::bin_format::{bin_record_kind_and_key, BinRecordReader, load_bin_file_records, bin_file_block_of_compressible_insert, bin_file_block_of_batch, bin_file_block_of_increment, bin_file_block_of_item_operation, bin_file_block_of_remove, bin_file_block_of_schema, bin_file_block_of_set_operation, bin_file_block_of_transaction_marker, bin_file_block_with_meta, bin_block_data_of_insert, bin_block_data_of_remove};
+use crate::Integrity;
+use crate::replay_check::{ReplayAnomaly, ReplayAnomalyKind, ReplayCheck};
+use crate::map_with_file::SerializedError;
+pub use crate::redact::{redact, RedactError, RedactReport, Redaction};
+pub use crate::size_estimate::{estimate_record_size, SizeEstimate, SizeStats};
+#[cfg(feature = "sqlite")]
+pub use crate::sqlite::{export_history_sqlite, export_history_sqlite_to};
 
 /// Record about operation on map in history file.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MapOperation<Key, Value> {
     /// Insert operation.
     Insert(Key, Value),
@@ -20,12 +34,687 @@ pub enum MapOperation<Key, Value> {
     Remove(Key),
 }
 
+/// Marker of transaction in history file.
+/// Records between begin and end markers are applied only if end marker is present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransactionMarker {
+    /// Begin of transaction, "txb" line or block with code 3.
+    Begin,
+    /// End of transaction, "txe" line or block with code 4.
+    End,
+    /// Abort of incomplete transaction, "txa" line or block with code 5.
+    /// Written when map is opened with incomplete transaction at the end of the file,
+    /// so records appended after it are not treated as part of the transaction.
+    Abort,
+}
+
+/// Kind of record in history file, see 'records_in_range'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// "ins" or "insV" line, block with code 0 or 2.
+    Insert,
+    /// "rem" line, block with code 1.
+    Remove,
+    /// "bat" line, block with code 6.
+    Batch,
+    /// "txb" line, block with code 3.
+    TransactionBegin,
+    /// "txe" line, block with code 4.
+    TransactionEnd,
+    /// "txa" line, block with code 5.
+    TransactionAbort,
+    /// "psh" line, block with code 7.
+    PushItems,
+    /// "rmi" line, block with code 8.
+    RemoveItems,
+    /// "add" line, block with code 9.
+    SetAdd,
+    /// "del" line, block with code 10.
+    SetDelete,
+    /// "inc" line, block with code 11.
+    Increment,
+    /// "sch" line, block with code 13.
+    Schema,
+}
+
+impl From<TransactionMarker> for RecordKind {
+    fn from(marker: TransactionMarker) -> Self {
+        match marker {
+            TransactionMarker::Begin => RecordKind::TransactionBegin,
+            TransactionMarker::End => RecordKind::TransactionEnd,
+            TransactionMarker::Abort => RecordKind::TransactionAbort,
+        }
+    }
+}
+
+/// Sizes of records of the file counted when loading, see 'MapWithFile::load_stats'.
+/// Sizes are of records as they are in the file, with integrity, metadata and end of line or length of block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadStats {
+    /// Size of all records, it's size of the file.
+    pub total_bytes: u64,
+    /// Size of insert records.
+    pub insert_bytes: u64,
+    /// Size of remove records.
+    pub remove_bytes: u64,
+    /// Size of other records: batches, transaction markers, schema and operations of items, sets and counters.
+    pub other_bytes: u64,
+    /// Size of checksums and hashes of integrity in all records (with separator in the text format).
+    pub integrity_bytes: u64,
+    /// Largest records in descending order of size, at most 'LoadStats::LARGEST_RECORDS_COUNT'.
+    pub largest_records: Vec<LargeRecord>,
+}
+
+impl LoadStats {
+    /// Max count of 'LoadStats::largest_records'.
+    pub const LARGEST_RECORDS_COUNT: usize = 10;
+
+    /// Count record, 'key' is called only if the record is one of the largest.
+    pub(crate) fn add(&mut self, extent: &RecordExtent, kind: RecordKind, integrity_len: u64, key: impl FnOnce() -> Option<String>) {
+        self.total_bytes += extent.len;
+        match kind {
+            RecordKind::Insert => self.insert_bytes += extent.len,
+            RecordKind::Remove => self.remove_bytes += extent.len,
+            _ => self.other_bytes += extent.len,
+        }
+        self.integrity_bytes += integrity_len;
+
+        // records of the same size keep order of the file
+        let is_largest = self.largest_records.len() < Self::LARGEST_RECORDS_COUNT
+            || self.largest_records.last().map(|smallest| extent.len > smallest.size).unwrap_or(true);
+        if is_largest {
+            let index = self.largest_records.partition_point(|record| record.size >= extent.len);
+            self.largest_records.insert(index, LargeRecord { line_num: extent.num, kind, size: extent.len, key: key() });
+            self.largest_records.truncate(Self::LARGEST_RECORDS_COUNT);
+        }
+    }
+}
+
+/// One of the largest records of 'LoadStats'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LargeRecord {
+    /// Line or block number, starts from 1.
+    pub line_num: usize,
+    /// Kind of the record.
+    pub kind: RecordKind,
+    /// Size of the record.
+    pub size: u64,
+    /// Key of the record as JSON, None for records without one key
+    /// or in the binary format if the file is loaded not by 'MapWithFile'.
+    pub key: Option<String>,
+}
+
+/// Renders key of block data as JSON for 'LoadStats::largest_records', set by loading with known type of key.
+pub(crate) type BinKeyRenderer = fn(&[u8]) -> Option<String>;
+
+/// Records selected by 'records_in_range'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordRange {
+    /// Records with index in the range, index of the first record of the file is 0.
+    Index(std::ops::Range<usize>),
+}
+
+/// Position of record in history file with kind and key of the record, returned by 'records_in_range'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordLocation {
+    /// Index of the record in the file, the first record is 0.
+    pub index: usize,
+    /// Offset of the record from the start of the file in bytes.
+    pub offset: u64,
+    /// Length of the record in bytes, line with end of line of the text format or block with its length of the binary format.
+    pub len: u64,
+    /// Kind of the record, for record with metadata it's kind of the inner record.
+    pub kind: RecordKind,
+    /// Key of the record serialized to JSON, None for batch, transaction markers and schema.
+    pub key: Option<String>,
+}
+
+/// Operation with items of collection value of 'MultiMap'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ItemOperation {
+    /// Push items to the end of collection, "psh" line or block with code 7.
+    Push,
+    /// Remove first equal item from collection for each item, "rmi" line or block with code 8.
+    Remove,
+}
+
+/// Operation with element of set, see 'SetWithFile'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SetOperation {
+    /// Add element, "add" line or block with code 9.
+    Add,
+    /// Delete element, "del" line or block with code 10.
+    Delete,
+}
+
+/// Operation passed by loading functions to callback.
+pub(crate) trait LoadedOperation<Key, Value>: Sized {
+    /// Operation of insert or remove record.
+    fn from_map_operation(map_operation: MapOperation<Key, Value>) -> Self;
+    /// Operation of item record, value is collection with items of record.
+    /// Returns None if item records are not supported.
+    fn from_item_operation(item_operation: ItemOperation, key: Key, items: Value) -> Option<Self>;
+    /// Operation of set record, key is element of set.
+    /// Returns None if set records are not supported.
+    fn from_set_operation(set_operation: SetOperation, key: Key) -> Option<Self>;
+    /// Operation of increment record of counter value.
+    /// Returns None if increment records are not supported.
+    fn from_increment(key: Key, delta: i64) -> Option<Self>;
+    /// Attach metadata of the record to the operation of insert or remove record.
+    /// Operations without support of metadata skip it.
+    fn with_meta(self, meta: RawMeta<'_>, line_num: usize) -> Result<Self, LoadFileError>;
+    /// Value for insert record which value can't be deserialized with 'DeserializePolicy::Default'.
+    /// Returns None if default values are not supported, then the policy is the same as 'DeserializePolicy::Fail'.
+    fn default_value() -> Option<Value> { None }
+}
+
+impl<Key, Value> LoadedOperation<Key, Value> for MapOperation<Key, Value> {
+    fn from_map_operation(map_operation: MapOperation<Key, Value>) -> Self { map_operation }
+    fn from_item_operation(_: ItemOperation, _: Key, _: Value) -> Option<Self> { None }
+    fn from_set_operation(_: SetOperation, _: Key) -> Option<Self> { None }
+    fn from_increment(_: Key, _: i64) -> Option<Self> { None }
+    fn with_meta(self, _: RawMeta<'_>, _: usize) -> Result<Self, LoadFileError> { Ok(self) }
+}
+
+/// Serialized metadata of record written by 'MapWithFile::insert_with_meta' or 'MapWithFile::remove_with_meta'.
+pub(crate) enum RawMeta<'a> {
+    /// JSON of the text format.
+    Json(&'a str),
+    /// Bincode data of the binary format.
+    Bin(&'a [u8]),
+}
+
+/// Insert or remove operation with metadata of its record, None for records without metadata.
+pub(crate) struct MetaOperation<Key, Value, Meta> {
+    /// Operation.
+    pub map_operation: MapOperation<Key, Value>,
+    /// Metadata of the record.
+    pub meta: Option<Meta>,
+}
+
+impl<Key, Value, Meta: DeserializeOwned> LoadedOperation<Key, Value> for MetaOperation<Key, Value, Meta> {
+    fn from_map_operation(map_operation: MapOperation<Key, Value>) -> Self { MetaOperation { map_operation, meta: None } }
+    fn from_item_operation(_: ItemOperation, _: Key, _: Value) -> Option<Self> { None }
+    fn from_set_operation(_: SetOperation, _: Key) -> Option<Self> { None }
+    fn from_increment(_: Key, _: i64) -> Option<Self> { None }
+    fn with_meta(mut self, meta: RawMeta<'_>, line_num: usize) -> Result<Self, LoadFileError> {
+        self.meta = Some(match meta {
+            RawMeta::Json(json) => serde_json::from_str(json).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?,
+            RawMeta::Bin(data) => bincode2::deserialize(data).map_err(|err| LoadFileError::DeserializeBincodeError { err, block_num: line_num })?,
+        });
+        Ok(self)
+    }
+}
+
+/// Insert or remove operation of loading with 'DeserializePolicy::Default', see 'MapWithFile::open_or_create_with_default_values'.
+pub(crate) struct DefaultingOperation<Key, Value>(pub MapOperation<Key, Value>);
+
+impl<Key, Value: Default> LoadedOperation<Key, Value> for DefaultingOperation<Key, Value> {
+    fn from_map_operation(map_operation: MapOperation<Key, Value>) -> Self { DefaultingOperation(map_operation) }
+    fn from_item_operation(_: ItemOperation, _: Key, _: Value) -> Option<Self> { None }
+    fn from_set_operation(_: SetOperation, _: Key) -> Option<Self> { None }
+    fn from_increment(_: Key, _: i64) -> Option<Self> { None }
+    fn with_meta(self, _: RawMeta<'_>, _: usize) -> Result<Self, LoadFileError> { Ok(self) }
+    fn default_value() -> Option<Value> { Some(Value::default()) }
+}
+
+/// Counts of records which key or value can't be deserialized, recovered by 'Cfg::on_deserialize_error'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RecoveredRecords {
+    /// Count of ignored records.
+    pub skipped: u64,
+    /// Count of insert records with default value.
+    pub defaulted: u64,
+}
+
+/// Apply 'policy' to result of deserialization of insert or remove record.
+/// On deserialization error returns insert with default value if policy is 'DeserializePolicy::Default' and 'insert_key' returns key,
+/// None if the record is skipped, the error if policy is 'DeserializePolicy::Fail'. Other errors are returned with any policy.
+pub(crate) fn recover_record<Key, Value, Op>(
+    result: Result<MapOperation<Key, Value>, LoadFileError>,
+    policy: DeserializePolicy,
+    insert_key: impl FnOnce() -> Option<Key>,
+    recovered: &mut RecoveredRecords
+) -> Result<Option<MapOperation<Key, Value>>, LoadFileError>
+where Op: LoadedOperation<Key, Value> {
+    let err = match result {
+        Ok(map_operation) => return Ok(Some(map_operation)),
+        Err(err) => err,
+    };
+    if !matches!(err, LoadFileError::DeserializeJsonError { .. } | LoadFileError::DeserializeBincodeError { .. }) {
+        return Err(err);
+    }
+
+    let default_value = match policy {
+        DeserializePolicy::Fail => return Err(err),
+        DeserializePolicy::Skip => None,
+        DeserializePolicy::Default => Some(Op::default_value().ok_or(err)?),
+    };
+
+    // remove and insert with not deserialized key can only be skipped
+    match default_value.and_then(|value| Some((insert_key()?, value))) {
+        Some((key, value)) => {
+            recovered.defaulted += 1;
+            Ok(Some(MapOperation::Insert(key, value)))
+        },
+        None => {
+            recovered.skipped += 1;
+            Ok(None)
+        },
+    }
+}
+
+/// Attach metadata of the record to the operation if the record contains it.
+pub(crate) fn with_record_meta<Key, Value, Op>(operation: Op, meta: Option<RawMeta<'_>>, line_num: usize) -> Result<Op, LoadFileError>
+where Op: LoadedOperation<Key, Value> {
+    match meta {
+        Some(meta) => operation.with_meta(meta, line_num),
+        None => Ok(operation),
+    }
+}
+
+/// Buffer of records of transaction used by loading functions.
+pub(crate) struct TransactionBuffer<Op> {
+    /// Records of the current transaction, None if no transaction is started.
+    operations: Option<Vec<Op>>,
+    /// Integrity state before begin marker of the current transaction.
+    integrity_at_begin: Option<Integrity>,
+    /// Count of read bytes up to the end of last record not inside transaction.
+    committed_len: u64,
+}
+
+/// Result of loading of records with transactions.
+pub(crate) struct LoadedTail {
+    /// Count of read bytes up to the end of last record not inside incomplete transaction at the end.
+    pub committed_len: u64,
+    /// Integrity state at 'committed_len' if there is incomplete transaction at the end, otherwise None.
+    pub incomplete_transaction_integrity: Option<Option<Integrity>>,
+    /// Anomalies found by check of replay if it's not strict.
+    pub replay_anomalies: Vec<ReplayAnomaly>,
+    /// Sizes of all read records.
+    pub load_stats: LoadStats,
+    /// Records recovered by 'Cfg::on_deserialize_error'.
+    pub recovered: RecoveredRecords,
+    /// Count of read bytes up to the start of invalid last record if loading is stopped by 'Cfg::recovery'.
+    pub truncated_at: Option<u64>,
+    /// Records skipped by 'RecoveryMode::SkipBadRecords'.
+    pub bad_records: Vec<BadRecord>,
+    /// Count of loaded lines or blocks, skipped and truncated ones are not counted.
+    pub records: usize,
+    /// Error of invalid record at 'truncated_at'.
+    pub truncation_error: Option<LoadFileError>,
+}
+
+impl<Op> TransactionBuffer<Op> {
+    /// Buffer without transaction.
+    pub fn new() -> Self {
+        TransactionBuffer { operations: None, integrity_at_begin: None, committed_len: 0 }
+    }
+
+    /// Pass operation to 'processed_callback' or keep it until end of transaction.
+    pub fn push<ProcessedCallback>(&mut self, map_operation: Op, processed_callback: &mut ProcessedCallback) -> Result<(), LoadFileError>
+    where ProcessedCallback: FnMut(Op) -> Result<(), ()> {
+        match &mut self.operations {
+            Some(operations) => operations.push(map_operation),
+            None => processed_callback(map_operation).map_err(|()| LoadFileError::Interrupted)?,
+        }
+        Ok(())
+    }
+
+    /// Process transaction marker. 'integrity_before_marker' is integrity state before checking of the marker record.
+    /// Begin inside transaction discards the previous incomplete transaction, end without begin is ignored.
+    pub fn marker<ProcessedCallback>(&mut self, marker: TransactionMarker, integrity_before_marker: &Option<Integrity>, processed_callback: &mut ProcessedCallback) -> Result<(), LoadFileError>
+    where ProcessedCallback: FnMut(Op) -> Result<(), ()> {
+        match marker {
+            TransactionMarker::Begin => {
+                self.operations = Some(Vec::new());
+                self.integrity_at_begin = integrity_before_marker.clone();
+            },
+            TransactionMarker::End => {
+                for map_operation in self.operations.take().unwrap_or_default() {
+                    processed_callback(map_operation).map_err(|()| LoadFileError::Interrupted)?;
+                }
+            },
+            TransactionMarker::Abort => {
+                self.operations = None;
+            },
+        }
+        Ok(())
+    }
+
+    /// Called after each record with count of read bytes.
+    pub fn record_end(&mut self, read_len: u64) {
+        if self.operations.is_none() {
+            self.committed_len = read_len;
+        }
+    }
+
+    /// Discard incomplete transaction at the end.
+    pub fn finish(self) -> LoadedTail {
+        let integrity_at_begin = self.integrity_at_begin;
+        LoadedTail {
+            committed_len: self.committed_len,
+            incomplete_transaction_integrity: self.operations.map(|_| integrity_at_begin),
+            replay_anomalies: Vec::new(),
+            load_stats: LoadStats::default(),
+            recovered: RecoveredRecords::default(),
+            truncated_at: None,
+            bad_records: Vec::new(),
+            records: 0,
+            truncation_error: None,
+        }
+    }
+}
+
+/// Record which can't be loaded skipped by 'RecoveryMode::SkipBadRecords'.
+#[derive(Debug)]
+pub(crate) struct BadRecord {
+    /// Line or block number, starts from 1.
+    pub num: usize,
+    /// Count of read bytes up to the start of the record.
+    pub offset: u64,
+    /// Error of loading of the record.
+    pub error: LoadFileError,
+}
+
+/// Result of recovery from invalid record, see 'LoadRecovery::recover'.
+pub(crate) enum Recovered {
+    /// Record is skipped and loading continues. Chain of integrity must be continued from hash of the record
+    /// by 'resync_chain' of the reader if 'resync_chain' is true, because the hash can't be calculated.
+    Skipped { resync_chain: bool },
+    /// Record and everything after it are truncated, loading is stopped.
+    Truncated,
+}
+
+/// Recovery from invalid records used by loading functions, see 'LoadLimits::recovery'.
+pub(crate) struct LoadRecovery {
+    /// Recovery of loading.
+    mode: RecoveryMode,
+    /// See 'LoadLimits::truncate_at_any_error'.
+    truncate_at_any_error: bool,
+    /// Count of loaded records.
+    records: usize,
+    /// Integrity state after the last loaded or skipped record, it's kept only if recovery is enabled.
+    integrity: Option<Integrity>,
+    /// Records skipped by 'RecoveryMode::SkipBadRecords'.
+    bad_records: Vec<BadRecord>,
+    /// Count of read bytes up to the start of truncated invalid last record and its error.
+    truncated: Option<(u64, LoadFileError)>,
+}
+
+impl LoadRecovery {
+    /// Recovery from the start of loading with integrity state at the start.
+    pub fn new(limits: &LoadLimits, integrity: &Option<Integrity>) -> Self {
+        let mode = limits.recovery;
+        let integrity = if mode == RecoveryMode::Fail { None } else { integrity.clone() };
+        LoadRecovery { mode, truncate_at_any_error: limits.truncate_at_any_error, records: 0, integrity, bad_records: Vec::new(), truncated: None }
+    }
+
+    /// Called after each loaded record with integrity state after the record.
+    pub fn record_end(&mut self, integrity: &Option<Integrity>) {
+        self.records += 1;
+        if self.mode != RecoveryMode::Fail {
+            self.integrity.clone_from(integrity);
+        }
+    }
+
+    /// Recovery from error of the record 'num' which starts after 'offset' read bytes, 'at_end' is true if it's the last record of the file.
+    /// Invalid last record is truncated and integrity state is restored to its start. Returns the error if it can't be recovered.
+    pub fn recover(&mut self, error: LoadFileError, at_end: bool, num: usize, offset: u64, integrity: &mut Option<Integrity>) -> Result<Recovered, LoadFileError> {
+        if self.mode == RecoveryMode::Fail || !error.is_invalid_record() {
+            return Err(error);
+        }
+
+        if at_end || self.truncate_at_any_error {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %error, offset, "invalid last record is truncated");
+
+            *integrity = self.integrity.clone();
+            self.truncated = Some((offset, error));
+            return Ok(Recovered::Truncated);
+        }
+
+        // end of binary block with wrong first byte is unknown
+        if self.mode != RecoveryMode::SkipBadRecords || matches!(error, LoadFileError::WrongFirstByte) {
+            return Err(error);
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::warn!(error = %error, record = num, offset, "invalid record is skipped");
+
+        // integrity state is not changed by record with wrong hash of chain
+        let resync_chain = matches!(error, LoadFileError::IntegrityError(_));
+        self.bad_records.push(BadRecord { num, offset, error });
+        Ok(Recovered::Skipped { resync_chain })
+    }
+
+    /// Called after skipped record with integrity state after it.
+    pub fn record_skipped(&mut self, integrity: &Option<Integrity>) {
+        self.integrity.clone_from(integrity);
+    }
+
+    /// Move results of recovery to the result of loading.
+    pub fn finish(self, loaded_tail: &mut LoadedTail) {
+        loaded_tail.records = self.records;
+        loaded_tail.bad_records = self.bad_records;
+        if let Some((offset, error)) = self.truncated {
+            loaded_tail.truncated_at = Some(offset);
+            loaded_tail.truncation_error = Some(error);
+        }
+    }
+}
+
+/// Position of record in the file, line of the text format or block of the binary format.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordExtent {
+    /// Line or block number, starts from 1.
+    pub num: usize,
+    /// Offset of the record from the start of the file.
+    pub offset: u64,
+    /// Length of the record with end of line or length of block.
+    pub len: u64,
+}
+
+impl RecordExtent {
+    /// Offset after the record.
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// Count of records between checks of 'Cfg::load_cancel' by loading functions.
+pub const LOAD_CANCEL_CHECK_INTERVAL: usize = 1024;
+
+/// Limits and checks of loading of the file from config.
+#[derive(Default)]
+pub(crate) struct LoadLimits {
+    /// Flag for cancel of loading, see 'Cfg::load_cancel'.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Max size of record, see 'Cfg::max_value_size'.
+    pub max_record_size: Option<usize>,
+    /// Expected fingerprint of schema record, see 'Cfg::schema_fingerprint'.
+    pub schema_fingerprint: Option<String>,
+    /// Check of records against state of the map, see 'Cfg::strict_replay'.
+    pub replay_check: Option<ReplayCheck>,
+    /// Renders keys of the largest records of 'LoadStats' in the binary format.
+    pub render_bin_key: Option<BinKeyRenderer>,
+    /// Names of operations of the text format, see 'Cfg::extra_text_ops'.
+    pub text_ops: TextOps,
+    /// Recovery from records which can't be deserialized, see 'Cfg::on_deserialize_error'.
+    pub deserialize_policy: DeserializePolicy,
+    /// Set to offset after the current record before its operations are passed to callback, see 'MapWithFile::records_since'.
+    pub record_end: Option<Arc<AtomicU64>>,
+    /// Recovery from invalid records, 'Cfg::recovery' is used only by opening.
+    pub recovery: RecoveryMode,
+    /// Invalid record is truncated with all records after it even if it's not the last one, see 'repair::repair_file'.
+    pub truncate_at_any_error: bool,
+    /// Path of the file for 'LoadFileError::AtRecord', None if records are not read from the file.
+    pub file_path: Option<PathBuf>,
+    /// Offset in the file of the start of reading, it's added to offsets of records in errors.
+    pub file_offset: u64,
+    /// Reporting of progress of loading, 'Cfg::load_progress_callback' is used only by opening.
+    pub progress: Option<ProgressReport>,
+}
+
+/// Callback of progress of loading with its settings, see 'Cfg::load_progress_callback'.
+pub(crate) struct ProgressReport {
+    /// Callback taken from config.
+    pub callback: LoadProgressCallback,
+    /// Count of records between calls of the callback.
+    pub interval: usize,
+    /// Length of the file when opening.
+    pub total_bytes: u64,
+}
+
+impl LoadLimits {
+    /// Store offset after the current record to 'record_end'.
+    pub(crate) fn set_record_end(&self, extent: &RecordExtent) {
+        if let Some(record_end) = &self.record_end {
+            record_end.store(extent.end(), Ordering::Relaxed);
+        }
+    }
+
+    /// Make limits from config, fields are copied because config is borrowed by loading.
+    pub(crate) fn of(cfg: &Cfg) -> Self {
+        LoadLimits {
+            cancel: cfg.load_cancel.clone(),
+            max_record_size: cfg.max_value_size,
+            schema_fingerprint: cfg.schema_fingerprint.clone(),
+            replay_check: ReplayCheck::of(cfg),
+            render_bin_key: None,
+            text_ops: TextOps::of(cfg),
+            deserialize_policy: cfg.on_deserialize_error,
+            record_end: None,
+            recovery: RecoveryMode::Fail,
+            truncate_at_any_error: false,
+            file_path: None,
+            file_offset: 0,
+            progress: None,
+        }
+    }
+
+    /// Pass progress to 'Cfg::load_progress_callback' after each 'Cfg::load_progress_interval' records and at the end of loading,
+    /// 'position' is number of the next record and count of read bytes. Returns 'LoadFileError::Interrupted' if the callback returns error.
+    pub(crate) fn report_progress(&mut self, position: (usize, u64), end: bool) -> Result<(), LoadFileError> {
+        let file_offset = self.file_offset;
+        let report = match &mut self.progress {
+            Some(report) => report,
+            None => return Ok(()),
+        };
+
+        let (next_record_num, read_len) = position;
+        let records = next_record_num - 1;
+        if !end && (records == 0 || records.checked_rem(report.interval) != Some(0)) {
+            return Ok(());
+        }
+
+        let progress = LoadProgress { records, bytes_read: file_offset + read_len, total_bytes: report.total_bytes };
+        (report.callback)(progress).map_err(|_| LoadFileError::Interrupted)
+    }
+
+    /// Returns error of the record 'line_num' after 'offset' read bytes with path of the file and position of the record,
+    /// errors of interruption and cancel of loading are returned as is.
+    pub(crate) fn record_error(&self, err: LoadFileError, line_num: usize, offset: u64) -> LoadFileError {
+        match err {
+            LoadFileError::Interrupted | LoadFileError::InterruptedWithBeforeReadCallback(_) | LoadFileError::Cancelled => err,
+            err => LoadFileError::AtRecord { path: self.file_path.clone(), line_num, offset: self.file_offset + offset, err: Box::new(err) },
+        }
+    }
+
+    /// Returns 'LoadFileError::RecordTooLarge' if size of the record is greater than max.
+    pub(crate) fn check_size(&self, size: usize, line_num: usize) -> Result<(), LoadFileError> {
+        match self.max_record_size {
+            Some(limit) if size > limit => Err(LoadFileError::RecordTooLarge { size, limit, line_num }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns 'LoadFileError::SchemaMismatch' if fingerprint of schema record is not expected one.
+    pub(crate) fn check_schema(&self, found: &str) -> Result<(), LoadFileError> {
+        match &self.schema_fingerprint {
+            Some(expected) if expected != found => Err(LoadFileError::SchemaMismatch { expected: expected.clone(), found: found.to_string() }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns 'LoadFileError::Cancelled' if cancel flag is set, flag is checked only
+/// for the first record and every 'LOAD_CANCEL_CHECK_INTERVAL' records after.
+pub(crate) fn check_load_cancel(load_cancel: Option<&AtomicBool>, record_num: usize) -> Result<(), LoadFileError> {
+    match load_cancel {
+        Some(load_cancel) if (record_num - 1) % LOAD_CANCEL_CHECK_INTERVAL == 0 && load_cancel.load(Ordering::Relaxed) => Err(LoadFileError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Integrity state before making of record for restoring by 'check_record_size', 'apply_before_write' and 'check_write',
+/// cloned only if 'Cfg::max_value_size', before write callback or 'WriteMode::Sync' is set.
+pub(crate) fn integrity_before_record(cfg: &Cfg) -> Option<Integrity> {
+    let has_before_write = match &cfg.format {
+        Format::Text(before_write_callback, _) => before_write_callback.is_some(),
+        Format::Bin(before_write_callback, _) => before_write_callback.is_some(),
+    };
+    if cfg.max_value_size.is_some() || has_before_write || cfg.write_mode == WriteMode::Sync { cfg.integrity.clone() } else { None }
+}
+
+/// Returns 'SerializedError::WriteError' if writing of the record failed, it's possible only with 'WriteMode::Sync' or after panic of the background thread,
+/// or 'SerializedError::ReadOnly' if the map is opened read-only.
+/// Integrity state changed by making of the record is restored to 'integrity_before' in this case,
+/// so the next record continues the chain of the file.
+pub(crate) fn check_write(result: std::io::Result<()>, integrity: &mut Option<Integrity>, integrity_before: Option<Integrity>) -> Result<(), SerializedError> {
+    result.map_err(|err| {
+        *integrity = integrity_before;
+        SerializedError::of_write_error(err)
+    })
+}
+
+/// Call user callback, None if the callback panicked.
+/// Caller must discard everything the callback could change, so it's not observed in broken state.
+pub(crate) fn catch_callback_panic<R>(callback: impl FnOnce() -> R) -> Option<R> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(callback)).ok()
+}
+
+/// Before write callback of the text or binary format.
+pub(crate) type BeforeWriteCallback<Record> = Box<dyn FnMut(&mut Record) + Send>;
+
+/// Apply before write callback of the format to the record.
+/// Panic of the callback is 'SerializedError::CallbackPanicked', integrity state changed by making of the record
+/// is restored to 'integrity_before' in this case, so the next record continues the chain of the file.
+pub(crate) fn apply_before_write<Record>(callback: &mut Option<BeforeWriteCallback<Record>>, record: &mut Record, integrity: &mut Option<Integrity>, integrity_before: &Option<Integrity>) -> Result<(), SerializedError> {
+    if let Some(f) = callback {
+        if catch_callback_panic(|| f(record)).is_none() {
+            *integrity = integrity_before.clone();
+            return Err(SerializedError::CallbackPanicked);
+        }
+    }
+    Ok(())
+}
+
+/// Returns 'SerializedError::ValueTooLarge' if size of the record is greater than 'max_value_size'.
+/// Integrity state changed by making of the record is restored to 'integrity_before' in this case,
+/// so the next record continues the chain of the file.
+pub(crate) fn check_record_size(size: usize, max_value_size: Option<usize>, integrity: &mut Option<Integrity>, integrity_before: Option<Integrity>) -> Result<(), SerializedError> {
+    match max_value_size {
+        Some(limit) if size > limit => {
+            *integrity = integrity_before;
+            Err(SerializedError::ValueTooLarge { size, limit })
+        },
+        _ => Ok(()),
+    }
+}
+
+pub use crate::support_bundle::export_support_bundle;
+
 /// Convert history file for other config or key-values types.
+/// Reading of the source file can be cancelled by 'load_cancel' of 'src_cfg'.
+/// Schema record of the source is checked by 'src_cfg', destination gets schema record of 'dst_cfg'.
+/// Integrity of the source is checked by 'src_cfg' and destination is signed again by 'dst_cfg',
+/// so HMAC chain can be moved to a new key.
 // If 'src_file_path' and 'dst_file_path' is equal, then file will rewritten via tmp file.
 pub fn convert<SrcKey, SrcValue, DstKey, DstValue, F>(
-    src_file_path: &str,
+    src_file_path: impl AsRef<Path>,
     mut src_cfg: Cfg,
-    dst_file_path: &str,
+    dst_file_path: impl AsRef<Path>,
     mut dst_cfg: Cfg, f: F
 ) -> Result<(), ConvertError>
 where
@@ -35,6 +724,10 @@ where
     DstValue: Serialize,
     F: Fn(MapOperation<SrcKey, SrcValue>) -> MapOperation<DstKey, DstValue>
 {
+    let (src_file_path, dst_file_path) = (src_file_path.as_ref(), dst_file_path.as_ref());
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("convert", src = %src_file_path.display(), dst = %dst_file_path.display()).entered();
+
     let mut src_file = fs::OpenOptions::new().read(true).open(src_file_path)
         .map_err(ConvertError::OpenSrcFileError)?;
     src_file.lock_exclusive()
@@ -43,12 +736,9 @@ where
     let file_is_same = src_file_path == dst_file_path;
 
     let dst_file_path = if file_is_same {
-        let tempdir = std::env::temp_dir()
-            .to_str().ok_or(ConvertError::TmpFileError)?
-            .to_string();
-        format!("{}/{}.txt", tempdir, Uuid::new_v4())
+        PathBuf::from(tmp_file_path().ok_or(ConvertError::TmpFileError)?)
     } else {
-        dst_file_path.to_string()
+        dst_file_path.to_path_buf()
     };
 
     let mut dst_file = fs::OpenOptions::new().write(true).create(true).open(&dst_file_path)
@@ -59,12 +749,18 @@ where
     dst_file.lock_exclusive()
         .map_err(|_| ConvertError::LockDstFileError)?;
 
+    // schema record of the destination is the first record, record of the source is checked and not copied
+    if let Some(schema_fingerprint) = &dst_cfg.schema_fingerprint {
+        let line = text_file_line_of_schema(schema_fingerprint, &mut dst_cfg.integrity);
+        dst_file.write_all(line.as_bytes()).map_err(ConvertError::WriteToFileError)?;
+    }
+
     let mut write_err: Option<ConvertError> = None;
 
     let process_map_operation = |map_operation| {
         match f(map_operation) {
             MapOperation::Insert(key, value) => {
-                match text_file_line_of_insert(&key, &value, &mut dst_cfg.integrity) {
+                match text_file_line_of_compressible_insert(&key, &value, dst_cfg.value_schema_version, dst_cfg.compress_values_over, &mut dst_cfg.integrity) {
                     Ok(line) => {
                         if let Err(err) = dst_file.write_all(line.as_bytes()) {
                             write_err = Some(ConvertError::WriteToFileError(err));
@@ -96,13 +792,14 @@ where
         Ok(())
     };
 
+    let limits = LoadLimits::of(&src_cfg);
     match src_cfg.format {
         Format::Text(_, after_read_callback) => {
-            load_from_text_file::<SrcKey, SrcValue, _, _, _>(&mut src_file, &mut src_cfg.integrity, after_read_callback, process_map_operation)
+            load_text_file_records::<SrcKey, SrcValue, MapOperation<SrcKey, SrcValue>, _, _, _>(&mut src_file, &mut src_cfg.integrity, after_read_callback, src_cfg.value_schema_version, src_cfg.value_migrator.as_mut(), limits, process_map_operation)
                 .map_err(ConvertError::LoadFileError)?;
         },
         Format::Bin(_, after_read_callback) => {
-            load_from_bin_file::<SrcKey, SrcValue, _, _, _>(&mut src_file, &mut src_cfg.integrity, after_read_callback, process_map_operation)
+            load_bin_file_records::<SrcKey, SrcValue, MapOperation<SrcKey, SrcValue>, _, _, _>(&mut src_file, &mut src_cfg.integrity, after_read_callback, src_cfg.value_schema_version, src_cfg.value_migrator.as_mut(), limits, process_map_operation)
                 .map_err(ConvertError::LoadFileError)?;
         },
     };
@@ -110,23 +807,326 @@ where
     if file_is_same {
         drop(src_file);
         drop(dst_file);
-        fs::rename(&dst_file_path, &src_file_path)
+        fs::rename(&dst_file_path, src_file_path)
             .map_err(|_| ConvertError::TmpFileError)?;
     }
 
+    #[cfg(feature = "tracing")]
+    tracing::info!("history file converted");
+
     Ok(())
 }
 
-/// Create dirs to path if not exist.
-pub(crate) fn create_dirs_to_path_if_not_exist(path_to_file: &str) -> Result<(), std::io::Error> {
-    if let Some(index) = path_to_file.rfind('/') {
-        let dir_path = &path_to_file[..index];
-        if !std::path::Path::new(dir_path).exists() {
-            fs::create_dir_all(&path_to_file[..index])?;
-        }
+/// Path of new file in temp dir for rewriting of history file, None if path of temp dir is not UTF-8.
+pub(crate) fn tmp_file_path() -> Option<String> {
+    let tempdir = std::env::temp_dir();
+    Some(format!("{}/{}.txt", tempdir.to_str()?, Uuid::new_v4()))
+}
+
+/// Returns positions of records of history file in 'range' with their kinds and keys,
+/// for external tools extracting or redacting records.
+/// All records are listed, including records of incomplete and aborted transactions.
+/// Integrity is checked from the start of the file and after read callback of the format is applied.
+pub fn records_in_range<Key>(file_path: impl AsRef<Path>, mut cfg: Cfg, range: RecordRange) -> Result<Vec<RecordLocation>, LoadFileError>
+where
+    Key: DeserializeOwned + Serialize,
+{
+    let RecordRange::Index(range) = range;
+    let mut file = fs::File::open(file_path)?;
+    let limits = LoadLimits::of(&cfg);
+    let mut locations = Vec::new();
+
+    let record_location = |extent: RecordExtent, kind: RecordKind, key: Option<Key>| {
+        let key = match key {
+            Some(key) => Some(serde_json::to_string(&key).map_err(|err| LoadFileError::KeyToJsonError { err, line_num: extent.num })?),
+            None => None,
+        };
+        Ok::<_, LoadFileError>(RecordLocation { index: extent.num - 1, offset: extent.offset, len: extent.len, kind, key })
+    };
+
+    match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            let mut reader = TextRecordReader::new(&mut file);
+            while let Some(record) = reader.next_record(&mut cfg.integrity, after_read_callback, &limits)? {
+                let index = record.extent.num - 1;
+                if index >= range.end {
+                    break;
+                }
+                if index >= range.start {
+                    let (kind, key) = text_record_kind_and_key::<Key>(record.data, record.extent.num)?;
+                    locations.push(record_location(record.extent, kind, key)?);
+                }
+            }
+        },
+        Format::Bin(_, after_read_callback) => {
+            let mut reader = BinRecordReader::new(&mut file);
+            while let Some(record) = reader.next_record(&mut cfg.integrity, after_read_callback, &limits)? {
+                let index = record.extent.num - 1;
+                if index >= range.end {
+                    break;
+                }
+                if index >= range.start {
+                    let (kind, key) = bin_record_kind_and_key::<Key>(record.data, record.extent.num)?;
+                    locations.push(record_location(record.extent, kind, key)?);
+                }
+            }
+        },
     }
 
-    Ok(())
+    Ok(locations)
+}
+
+/// Make record with insert operation in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+/// Record longer than 'Cfg::max_value_size' is 'SerializedError::ValueTooLarge'.
+pub(crate) fn file_record_of_insert<Key, Value>(key: &Key, value: &Value, cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_compressible_insert(key, value, cfg.value_schema_version, cfg.compress_values_over, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(line.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_compressible_insert(key, value, cfg.value_schema_version, cfg.compress_values_over, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(block.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with insert or remove operation with metadata in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+/// Record longer than 'Cfg::max_value_size' is 'SerializedError::ValueTooLarge'.
+pub(crate) fn file_record_of_meta_operation<Key, Value, Meta>(map_operation: MapOperation<&Key, &Value>, meta: &Meta, cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize,
+    Value: Serialize,
+    Meta: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let data = match map_operation {
+                MapOperation::Insert(key, value) => text_line_data_of_insert(key, value, cfg.value_schema_version, cfg.compress_values_over)?,
+                MapOperation::Remove(key) => text_line_data_of_remove(key)?,
+            };
+            let mut line = text_file_line_with_meta(meta, &data, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(line.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let data = match map_operation {
+                MapOperation::Insert(key, value) => bin_block_data_of_insert(key, value, cfg.value_schema_version, cfg.compress_values_over)?,
+                MapOperation::Remove(key) => bin_block_data_of_remove(key)?,
+            };
+            let mut block = bin_file_block_with_meta(meta, &data, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(block.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with remove operation in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+pub(crate) fn file_record_of_remove<Key>(key: &Key, cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = file_line_of_remove(key, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_remove(key, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with operation with items of collection value in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+/// Record longer than 'Cfg::max_value_size' is 'SerializedError::ValueTooLarge'.
+pub(crate) fn file_record_of_item_operation<Key, Item>(item_operation: ItemOperation, key: &Key, items: &[Item], cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize,
+    Item: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_item_operation(item_operation, key, items, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(line.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_item_operation(item_operation, key, items, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(block.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with operation with element of set in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+pub(crate) fn file_record_of_set_operation<Key>(set_operation: SetOperation, key: &Key, cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_set_operation(set_operation, key, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_set_operation(set_operation, key, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with increment of counter value in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+pub(crate) fn file_record_of_increment<Key>(key: &Key, delta: i64, cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_increment(key, delta, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_increment(key, delta, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with batch of operations in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+/// Record longer than 'Cfg::max_value_size' is 'SerializedError::ValueTooLarge', so limit is for the whole batch.
+pub(crate) fn file_record_of_batch<Key, Value>(operations: &[MapOperation<Key, Value>], cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_batch(operations, cfg.value_schema_version, cfg.compress_values_over, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(line.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_batch(operations, cfg.value_schema_version, cfg.compress_values_over, &mut cfg.integrity)?;
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(block.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with transaction marker in the format from 'cfg' for write to file.
+/// Before write callback of the format is applied to the record.
+pub(crate) fn file_record_of_transaction_marker(marker: TransactionMarker, cfg: &mut Cfg) -> Result<Vec<u8>, SerializedError> {
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_transaction_marker(marker, &mut cfg.integrity);
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            Ok(line.into_bytes())
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_transaction_marker(marker, &mut cfg.integrity);
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            Ok(block)
+        },
+    }
+}
+
+/// Make record with 'Cfg::schema_fingerprint' in the format from 'cfg' for write to file,
+/// None if fingerprint is not set. Before write callback of the format is applied to the record.
+pub(crate) fn file_record_of_schema(cfg: &mut Cfg) -> Result<Option<Vec<u8>>, SerializedError> {
+    let integrity_before = integrity_before_record(cfg);
+    let schema_fingerprint = match cfg.schema_fingerprint.as_deref() {
+        Some(schema_fingerprint) => schema_fingerprint,
+        None => return Ok(None),
+    };
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_file_line_of_schema(schema_fingerprint, &mut cfg.integrity);
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            Ok(Some(line.into_bytes()))
+        },
+        Format::Bin(before_write_callback, _) => {
+            let mut block = bin_file_block_of_schema(schema_fingerprint, &mut cfg.integrity);
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            Ok(Some(block))
+        },
+    }
+}
+
+/// Create dirs to path if not exist, nothing is created for the file name without parent directory.
+/// Path is split by separators of the platform. Returns error of kind 'NotADirectory' with the path
+/// if the nearest existing ancestor of the file is not a directory, instead of trying to create dirs over it.
+pub(crate) fn create_dirs_to_path_if_not_exist(path_to_file: &Path) -> Result<(), std::io::Error> {
+    let dir_path = match path_to_file.parent() {
+        Some(dir_path) if !dir_path.as_os_str().is_empty() => dir_path,
+        _ => return Ok(()),
+    };
+
+    let existing_ancestor = dir_path.ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .find(|ancestor| ancestor.exists());
+    match existing_ancestor {
+        Some(ancestor) if ancestor == dir_path && ancestor.is_dir() => Ok(()),
+        Some(ancestor) if !ancestor.is_dir() => Err(std::io::Error::new(
+            std::io::ErrorKind::NotADirectory,
+            format!("'{}' is not a directory, can't create directories to '{}'", ancestor.display(), path_to_file.display()),
+        )),
+        _ => fs::create_dir_all(dir_path),
+    }
+}
+
+/// Unlock the file after error of opening of map, so the file can be opened again at once,
+/// lock is not left to closing of the file. Error of unlock is ignored because the file is closed after it anyway.
+pub(crate) fn unlock_after_error(file: &fs::File, err: LoadFileError) -> LoadFileError {
+    let _ = FileExt::unlock(file);
+    err
+}
+
+/// Continue chained integrity from the hash in the file after integrity error of the record.
+pub(crate) fn continue_chain(integrity: &mut Integrity, hash_in_file: &[u8]) {
+    match integrity {
+        Integrity::Crc32 | Integrity::XxHash64 => {},
+        Integrity::Sha1Chain(hash) => if let Ok(hash_in_file) = hash_in_file.try_into() { *hash = hash_in_file },
+        Integrity::Sha256Chain(hash) => if let Ok(hash_in_file) = hash_in_file.try_into() { *hash = hash_in_file },
+        #[cfg(feature = "blake3")]
+        Integrity::Blake3Chain(hash) => if let Ok(hash_in_file) = hash_in_file.try_into() { *hash = hash_in_file },
+        Integrity::HmacSha256Chain { prev, .. } => if let Ok(hash_in_file) = hash_in_file.try_into() { *prev = hash_in_file },
+    }
 }
 
 /// Returns hash of significant data of current record of file (hash of sum of prev hash and hash of current line data).
@@ -157,6 +1157,24 @@ pub fn blockchain_sha256(prev_hash: &[u8], data: &[u8], out: &mut [u8]) {
     hasher.result(out);
 }
 
+/// Returns tag of current record of file, HMAC-SHA256 with 'key' of sum of tag of previous record and current line data.
+pub fn blockchain_hmac_sha256(key: &[u8], prev_tag: &[u8], data: &[u8], out: &mut [u8]) {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(prev_tag);
+    hmac.input(data);
+    hmac.raw_result(out);
+}
+
+/// Returns hash of significant data of current record of file (hash of sum of prev hash and hash of current line data).
+#[cfg(feature = "blake3")]
+pub fn blockchain_blake3(prev_hash: &[u8], data: &[u8], out: &mut [u8]) {
+    let current_hash = blake3::hash(data);
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(prev_hash);
+    hasher.update(current_hash.as_bytes());
+    out.copy_from_slice(hasher.finalize().as_bytes());
+}
+
 /// Possible errors of 'load_from_file'.
 #[derive(Debug)]
 pub enum LoadFileError {
@@ -170,6 +1188,13 @@ pub enum LoadFileError {
     WrongFirstByte,
     /// Open, create or read file error.
     FileError(std::io::Error),
+    /// The file doesn't exist, returned by 'MapWithFile::open' instead of creating of the file.
+    FileNotFound,
+    /// The file already exists, returned by 'MapWithFile::create_new' instead of reading of the file.
+    FileAlreadyExists,
+    /// Exclusive lock of the file failed, the file is not read. Unlike 'FileError' it's not error of reading of the file,
+    /// for example the file is locked by another process on file system where locks can't be waited.
+    LockError(std::io::Error),
     /// Error of integrity.
     IntegrityError(IntegrityError),
     /// Json error with line number in operations log file.
@@ -178,10 +1203,50 @@ pub enum LoadFileError {
     DeserializeBincodeError { err: bincode2::Error, block_num: usize },
     /// Line in operations log file no contains operation name as "ins" or "rem".
     NoLineDefinition { line_num: usize, },
+    /// Name of operation at the start of the line is unknown and not an alias of 'Cfg::extra_text_ops'.
+    UnknownOperation { name: String, line_num: usize },
+    /// Offset passed to 'MapWithFile::records_since' is not the end of a record or is after the written data.
+    NotRecordBoundary { offset: u64 },
     /// Load file function is manually interrupted.
     Interrupted,
     /// Load file function is manually interrupted with 'after_read_callback'.
-    InterruptedWithBeforeReadCallback(Box<dyn std::error::Error>),
+    InterruptedWithBeforeReadCallback(Box<dyn std::error::Error + Send>),
+    /// Error returned from 'value_migrator' with line or block number.
+    MigrationError { err: MigrationError, line_num: usize },
+    /// Batch record with wrong structure, line or block number.
+    WrongBatch { line_num: usize },
+    /// Loading is cancelled by 'load_cancel' flag of config.
+    Cancelled,
+    /// Item record ("psh", "rmi") in the file of map that is not 'MultiMap', line or block number.
+    UnexpectedItemOperation { line_num: usize },
+    /// Set record ("add", "del") in the file of map that is not set, line or block number.
+    UnexpectedSetOperation { line_num: usize },
+    /// Increment record ("inc") in the file opened without support of counters, line or block number.
+    /// Such file must be opened by 'MapWithFile::open_or_create_with_counters'.
+    UnexpectedIncrementOperation { line_num: usize },
+    /// Error of serialization of removes of entries evicted when opening 'BoundedMap'.
+    EvictionError(SerializedError),
+    /// Error of decompression of value compressed because of 'Cfg::compress_values_over', line or block number.
+    ValueDecompressionError { err: std::io::Error, line_num: usize },
+    /// Size of record is greater than 'Cfg::max_value_size', line or block number.
+    RecordTooLarge { size: usize, limit: usize, line_num: usize },
+    /// Fingerprint of schema record of the file is not 'Cfg::schema_fingerprint'.
+    SchemaMismatch { expected: String, found: String },
+    /// Record impossible for the map found by 'Cfg::strict_replay', line or block number.
+    ReplayAnomaly { line_num: usize, kind: ReplayAnomalyKind },
+    /// Key of record can't be serialized to JSON by 'records_in_range', line or block number.
+    KeyToJsonError { err: serde_json::Error, line_num: usize },
+    /// After read callback of the format panicked on the record, line or block number.
+    CallbackPanicked { line_num: usize },
+    /// Error of making of record written when opening, for example panic of before write callback
+    /// on transaction abort marker or schema record.
+    WriteRecordError(SerializedError),
+    /// Snapshot file of 'Cfg::snapshot' doesn't start with header written by 'MapWithFile::checkpoint'.
+    WrongSnapshotHeader,
+    /// Error 'err' of the record with line or block number 'line_num' at 'offset' of the file 'path',
+    /// path is None if records are not read from the file, for example from a sink.
+    /// Errors of loading of records are returned in it by opening and other reading of records.
+    AtRecord { path: Option<PathBuf>, line_num: usize, offset: u64, err: Box<LoadFileError> },
 }
 
 /// Errors of integrity.
@@ -191,10 +1256,49 @@ pub enum IntegrityError {
     NoExpectedHash { line_num: usize },
     /// Wrong crc32 of log file line data when crc32 integrity used.
     Crc32Error { line_num: usize, },
+    /// Wrong xxHash64 of log file line data when xxHash64 integrity used.
+    XxHash64Error { line_num: usize, },
     /// Wrong Sha1 of log file line data when Sha256 blockchain integrity used.
     Sha1ChainError { line_num: usize, },
     /// Wrong Sha256 of log file line data when Sha256 blockchain integrity used.
     Sha256ChainError { line_num: usize, },
+    /// Wrong BLAKE3 hash of log file line data when BLAKE3 blockchain integrity used.
+    #[cfg(feature = "blake3")]
+    Blake3ChainError { line_num: usize, },
+    /// Wrong HMAC tag of log file line data when HMAC-SHA256 blockchain integrity used,
+    /// the file is changed or signed by other key.
+    HmacError { line_num: usize, },
+    /// Head of chained integrity after the last record 'line_num' is not the expected one, for example
+    /// the last records are removed, see 'Cfg::chain_head_file' and 'MapWithFile::open_or_create_expecting'.
+    ChainTruncated { line_num: usize, },
+    /// The first record ends with checksum or hash of 'found' kind that is not of 'Cfg::integrity',
+    /// for example the file is written with 'Integrity::Crc32' and opened without integrity.
+    FileHasUnexpectedHashSuffix { line_num: usize, found: IntegrityKind },
+    /// The first record has no checksum or hash of 'Cfg::integrity', the file is written without integrity.
+    /// Detected only in the text format, in the binary format end of the block can't be told apart from data.
+    FileMissingHashes { line_num: usize, },
+}
+
+impl LoadFileError {
+    /// Returns true if the error is caused by content of the record, so it can be invalid record written partially.
+    pub(crate) fn is_invalid_record(&self) -> bool {
+        match self {
+            LoadFileError::FileError(err) => matches!(err.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData),
+            LoadFileError::LastLineWithoutEndLine { .. }
+            | LoadFileError::FileLineLengthLessThenMinimum { .. }
+            | LoadFileError::WrongMinBinBlockLen
+            | LoadFileError::WrongFirstByte
+            | LoadFileError::DeserializeJsonError { .. }
+            | LoadFileError::DeserializeBincodeError { .. }
+            | LoadFileError::NoLineDefinition { .. }
+            | LoadFileError::UnknownOperation { .. }
+            | LoadFileError::WrongBatch { .. }
+            | LoadFileError::ValueDecompressionError { .. } => true,
+            // mismatch of integrity configuration is not invalid record, file must not be truncated by recovery
+            LoadFileError::IntegrityError(err) => !matches!(err, IntegrityError::FileHasUnexpectedHashSuffix { .. } | IntegrityError::FileMissingHashes { .. }),
+            _ => false,
+        }
+    }
 }
 
 impl From<IntegrityError> for LoadFileError {
@@ -210,12 +1314,159 @@ impl From<std::io::Error> for LoadFileError {
 }
 
 impl std::fmt::Display for LoadFileError {
+    /// Message with line or block number, 'AtRecord' starts with path of the file, for example "db/users.txt:1042 (offset 58311): invalid JSON".
+    /// Wrapped errors are not included, they are returned from 'source'.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            LoadFileError::AtRecord { path: Some(path), line_num, offset, .. } => write!(f, "{}:{} (offset {}): ", path.display(), line_num, offset)?,
+            LoadFileError::AtRecord { path: None, line_num, offset, .. } => write!(f, "record {} (offset {}): ", line_num, offset)?,
+            _ => {
+                if let Some(line_num) = self.line_num() {
+                    write!(f, "record {}: ", line_num)?;
+                }
+            },
+        }
+        self.fmt_message(f)
+    }
+}
+
+impl std::error::Error for LoadFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadFileError::FileError(err) => Some(err),
+            LoadFileError::LockError(err) => Some(err),
+            LoadFileError::DeserializeJsonError { err, .. } => Some(err),
+            LoadFileError::DeserializeBincodeError { err, .. } => Some(err),
+            LoadFileError::InterruptedWithBeforeReadCallback(err) => Some(err.as_ref()),
+            LoadFileError::MigrationError { err, .. } => Some(err),
+            LoadFileError::EvictionError(err) => Some(err),
+            LoadFileError::ValueDecompressionError { err, .. } => Some(err),
+            LoadFileError::KeyToJsonError { err, .. } => Some(err),
+            LoadFileError::WriteRecordError(err) => Some(err),
+            // message of the error of the record is part of the message, so its source is returned
+            LoadFileError::AtRecord { err, .. } => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl LoadFileError {
+    /// Returns error of the record without path and position of 'AtRecord', other errors are returned as is.
+    pub fn into_inner(self) -> LoadFileError {
+        match self {
+            LoadFileError::AtRecord { err, .. } => *err,
+            err => err,
+        }
+    }
+
+    /// Returns line or block number of the record with error if it's known.
+    pub fn line_num(&self) -> Option<usize> {
+        match self {
+            LoadFileError::LastLineWithoutEndLine { line_num }
+            | LoadFileError::FileLineLengthLessThenMinimum { line_num }
+            | LoadFileError::DeserializeJsonError { line_num, .. }
+            | LoadFileError::NoLineDefinition { line_num }
+            | LoadFileError::UnknownOperation { line_num, .. }
+            | LoadFileError::MigrationError { line_num, .. }
+            | LoadFileError::WrongBatch { line_num }
+            | LoadFileError::UnexpectedItemOperation { line_num }
+            | LoadFileError::UnexpectedSetOperation { line_num }
+            | LoadFileError::UnexpectedIncrementOperation { line_num }
+            | LoadFileError::ValueDecompressionError { line_num, .. }
+            | LoadFileError::RecordTooLarge { line_num, .. }
+            | LoadFileError::ReplayAnomaly { line_num, .. }
+            | LoadFileError::KeyToJsonError { line_num, .. }
+            | LoadFileError::CallbackPanicked { line_num }
+            | LoadFileError::AtRecord { line_num, .. } => Some(*line_num),
+            LoadFileError::DeserializeBincodeError { block_num, .. } => Some(*block_num),
+            LoadFileError::IntegrityError(err) => Some(err.line_num()),
+            _ => None,
+        }
+    }
+
+    /// Write message of the error without line number.
+    fn fmt_message(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadFileError::LastLineWithoutEndLine { .. } => write!(f, "last line without end of line"),
+            LoadFileError::FileLineLengthLessThenMinimum { .. } => write!(f, "line is shorter than minimum"),
+            LoadFileError::WrongMinBinBlockLen => write!(f, "binary block is shorter than minimum"),
+            LoadFileError::WrongFirstByte => write!(f, "wrong first byte of length of binary block"),
+            LoadFileError::FileError(_) => write!(f, "file error"),
+            LoadFileError::LockError(_) => write!(f, "file can't be locked"),
+            LoadFileError::FileNotFound => write!(f, "file not found"),
+            LoadFileError::FileAlreadyExists => write!(f, "file already exists"),
+            LoadFileError::IntegrityError(err) => err.fmt_message(f),
+            LoadFileError::DeserializeJsonError { .. } => write!(f, "invalid JSON"),
+            LoadFileError::DeserializeBincodeError { .. } => write!(f, "invalid bincode"),
+            LoadFileError::NoLineDefinition { .. } => write!(f, "record without operation"),
+            LoadFileError::UnknownOperation { name, .. } => write!(f, "unknown operation {:?}", name),
+            LoadFileError::NotRecordBoundary { offset } => write!(f, "offset {} is not end of record", offset),
+            LoadFileError::Interrupted => write!(f, "loading is interrupted"),
+            LoadFileError::InterruptedWithBeforeReadCallback(_) => write!(f, "loading is interrupted by after read callback"),
+            LoadFileError::MigrationError { .. } => write!(f, "migration of value failed"),
+            LoadFileError::WrongBatch { .. } => write!(f, "wrong batch record"),
+            LoadFileError::Cancelled => write!(f, "loading is cancelled"),
+            LoadFileError::UnexpectedItemOperation { .. } => write!(f, "item operation in file of map that is not multimap"),
+            LoadFileError::UnexpectedSetOperation { .. } => write!(f, "set operation in file of map that is not set"),
+            LoadFileError::UnexpectedIncrementOperation { .. } => write!(f, "increment operation in file opened without counters"),
+            LoadFileError::EvictionError(_) => write!(f, "eviction of entries failed"),
+            LoadFileError::ValueDecompressionError { .. } => write!(f, "decompression of value failed"),
+            LoadFileError::RecordTooLarge { size, limit, .. } => write!(f, "record size {} is greater than limit {}", size, limit),
+            LoadFileError::SchemaMismatch { expected, found } => write!(f, "schema fingerprint {:?} is not expected {:?}", found, expected),
+            LoadFileError::ReplayAnomaly { kind, .. } => write!(f, "record impossible for the map: {:?}", kind),
+            LoadFileError::KeyToJsonError { .. } => write!(f, "key can't be serialized to JSON"),
+            LoadFileError::CallbackPanicked { .. } => write!(f, "after read callback panicked"),
+            LoadFileError::WriteRecordError(_) => write!(f, "writing of record when opening failed"),
+            LoadFileError::WrongSnapshotHeader => write!(f, "wrong header of snapshot file"),
+            LoadFileError::AtRecord { err, .. } => err.fmt_message(f),
+        }
+    }
+}
+
+impl IntegrityError {
+    /// Returns line or block number of the record with error.
+    pub fn line_num(&self) -> usize {
+        match self {
+            IntegrityError::NoExpectedHash { line_num }
+            | IntegrityError::Crc32Error { line_num }
+            | IntegrityError::XxHash64Error { line_num }
+            | IntegrityError::Sha1ChainError { line_num }
+            | IntegrityError::Sha256ChainError { line_num }
+            | IntegrityError::HmacError { line_num }
+            | IntegrityError::ChainTruncated { line_num }
+            | IntegrityError::FileHasUnexpectedHashSuffix { line_num, .. }
+            | IntegrityError::FileMissingHashes { line_num } => *line_num,
+            #[cfg(feature = "blake3")]
+            IntegrityError::Blake3ChainError { line_num } => *line_num,
+        }
+    }
+
+    /// Write message of the error without line number.
+    fn fmt_message(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::NoExpectedHash { .. } => write!(f, "no checksum or hash of integrity"),
+            IntegrityError::Crc32Error { .. } => write!(f, "wrong crc32 checksum"),
+            IntegrityError::XxHash64Error { .. } => write!(f, "wrong xxhash64 checksum"),
+            IntegrityError::Sha1ChainError { .. } => write!(f, "wrong sha1 hash of chain"),
+            IntegrityError::Sha256ChainError { .. } => write!(f, "wrong sha256 hash of chain"),
+            IntegrityError::HmacError { .. } => write!(f, "wrong hmac tag of chain"),
+            IntegrityError::ChainTruncated { .. } => write!(f, "chain ends before the expected head, records at the end are removed"),
+            IntegrityError::FileHasUnexpectedHashSuffix { found, .. } => write!(f, "file has {:?} integrity not matching the configured one", found),
+            IntegrityError::FileMissingHashes { .. } => write!(f, "file is written without integrity but it's configured"),
+            #[cfg(feature = "blake3")]
+            IntegrityError::Blake3ChainError { .. } => write!(f, "wrong blake3 hash of chain"),
+        }
+    }
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "record {}: ", self.line_num())?;
+        self.fmt_message(f)
     }
 }
 
-impl std::error::Error for LoadFileError {}
+impl std::error::Error for IntegrityError {}
 
 /// Error convertation of operations history file.
 #[derive(Debug)]
@@ -0,0 +1,74 @@
+use crate::bin_format::load_bin_file_records;
+use crate::cfg::{Cfg, Format};
+use crate::format::{LoadLimits, MapOperation};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::spawn;
+
+/// Max count of operations read ahead by the reading thread of 'History'.
+const READ_AHEAD_OPERATIONS: usize = 1024;
+
+/// Iterator over operations of history file, see 'MapWithFile::history'.
+/// Operations are read in the background thread a little ahead of iteration.
+/// If dropped before the end, the thread stops reading at the next operation.
+pub struct History<Key, Value> {
+    /// Receiver of operations from reading thread.
+    operation_receiver: Receiver<Result<MapOperation<Key, Value>, LoadFileError>>,
+}
+
+impl<Key, Value, Map> MapWithFile<Key, Value, Map>
+where
+    Key: DeserializeOwned + Send + 'static,
+    Value: DeserializeOwned + Send + 'static,
+    Map: MapTrait<Key, Value> {
+
+    /// Returns iterator over insert and remove operations of history file in order of the file, for replay or audit without map.
+    /// Records are read as by 'open_or_create' with integrity, after read callback and other settings of 'cfg', the file is not locked.
+    /// Operations of batches are passed separately, operations of transactions are passed when the transaction is committed,
+    /// records of aborted and incomplete transactions are not passed. Error of loading is the last item of iteration.
+    /// Returns error if the file can't be opened.
+    pub fn history(file_path: impl AsRef<Path>, mut cfg: Cfg) -> Result<History<Key, Value>, LoadFileError> {
+        let mut reader = BufReader::new(File::open(&file_path)?);
+        let file_path = file_path.as_ref().to_path_buf();
+
+        let (operation_sender, operation_receiver) = sync_channel(READ_AHEAD_OPERATIONS);
+        spawn(move || {
+            // sending fails if the iterator is dropped, loading is interrupted by error of callback then
+            let send_map_operation = |map_operation| operation_sender.send(Ok(map_operation)).map_err(|_| ());
+            let limits = LoadLimits { file_path: Some(file_path), ..LoadLimits::of(&cfg) };
+            let loaded = match &mut cfg.format {
+                Format::Text(_, after_read_callback) => {
+                    load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, send_map_operation)
+                },
+                Format::Bin(_, after_read_callback) => {
+                    load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, send_map_operation)
+                },
+            };
+
+            if let Err(err) = loaded {
+                if !matches!(err, LoadFileError::Interrupted) {
+                    // iterator can be dropped already
+                    let _ = operation_sender.send(Err(err));
+                }
+            }
+        });
+
+        Ok(History { operation_receiver })
+    }
+}
+
+impl<Key, Value> Iterator for History<Key, Value> {
+    type Item = Result<MapOperation<Key, Value>, LoadFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // channel is disconnected when the thread finishes reading
+        self.operation_receiver.recv().ok()
+    }
+}
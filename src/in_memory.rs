@@ -0,0 +1,131 @@
+use crate::cfg::{Cfg, WriteOperation};
+use crate::compaction::{write_entries, CompactError};
+use crate::file_worker::{FileWorker, FileWorkerCfg, WorkerFile};
+use crate::format::file_record_of_schema;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Data written instead of the file by the map created by 'MapWithFile::in_memory', shared with the writing thread.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryFile(Arc<Mutex<Vec<u8>>>);
+
+impl MemoryFile {
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.0.lock()
+            .unwrap_or_else(|err| err.into_inner()) // data is changed by one call, so it's correct after panic too
+    }
+
+    /// Returns the data without copying if the writing thread is stopped.
+    fn into_data(self) -> Vec<u8> {
+        match Arc::try_unwrap(self.0) {
+            Ok(data) => data.into_inner().unwrap_or_else(|err| err.into_inner()),
+            Err(data) => MemoryFile(data).lock().clone(),
+        }
+    }
+}
+
+impl std::io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WorkerFile for MemoryFile {
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.lock().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Constructs empty map without file, for tests and ephemeral caches. Records are written to the memory
+    /// by the same format, integrity, callbacks and writing mode as to the file, nothing is loaded or locked.
+    /// Written data is returned by 'bytes' and 'into_bytes', it can be saved and opened as the file of the map.
+    /// 'Cfg::snapshot', 'Cfg::dirty_marker' and 'Cfg::chain_head_file' are not used, 'compact' rewrites the data in the memory,
+    /// background compaction returns 'CompactError::BackgroundNotSupported',
+    /// methods reading the file by path, such as 'records_since', return error of opening of the file.
+    pub fn in_memory(mut cfg: Cfg) -> Result<Self, LoadFileError> {
+        cfg.snapshot = None;
+        cfg.dirty_marker = false;
+        cfg.chain_head_file = false;
+
+        let integrity_at_file_start = cfg.integrity.clone();
+        let memory_file = MemoryFile::default();
+        let file_worker = FileWorker::new(memory_file.clone(), FileWorkerCfg::take_from(&mut cfg, PathBuf::new(), 0));
+        if let Some(record) = file_record_of_schema(&mut cfg).map_err(LoadFileError::WriteRecordError)? {
+            file_worker.write_bytes(record, WriteOperation::Schema)?;
+        }
+
+        let mut map_with_file = Self::with_file_worker(Map::default(), cfg, file_worker, PathBuf::new(), integrity_at_file_start);
+        map_with_file.memory_file = Some(memory_file);
+        Ok(map_with_file)
+    }
+
+    /// Returns data of the file after writing of all changes made before, or the data written instead of the file
+    /// by the map created by 'in_memory'. Returns error of writing as 'flush' or error of reading of the file.
+    pub fn bytes(&self) -> Result<Vec<u8>, std::io::Error> {
+        self.flush()?;
+
+        match &self.memory_file {
+            Some(memory_file) => Ok(memory_file.lock().clone()),
+            None => {
+                let len = self.file_worker.counters().file_len.load(Ordering::Acquire);
+                let mut data = Vec::new();
+                File::open(&self.file_path)?.take(len).read_to_end(&mut data)?;
+                Ok(data)
+            },
+        }
+    }
+
+    /// Same as 'bytes' but the map is closed by 'close' before, so data of the map created by 'in_memory' is not copied.
+    pub fn into_bytes(self) -> Result<Vec<u8>, std::io::Error> {
+        let MapWithFile { file_worker, file_path, memory_file, .. } = self;
+        file_worker.close()?;
+
+        match memory_file {
+            Some(memory_file) => Ok(memory_file.into_data()),
+            None => std::fs::read(file_path),
+        }
+    }
+
+    /// Rewrite the data of the map created by 'in_memory' with insert records of the current entries, see 'compact'.
+    /// Returns length of the new data.
+    pub(crate) fn compact_memory_file(&mut self) -> Result<u64, CompactError> {
+        let integrity = std::mem::replace(&mut self.cfg.get_mut().integrity, self.integrity_at_file_start.clone());
+        let mut data = Vec::new();
+        let file_len = match write_entries(&self.map, self.cfg.get_mut(), &mut data) {
+            Ok(file_len) => file_len,
+            Err(err) => {
+                self.cfg.get_mut().integrity = integrity;
+                return Err(err);
+            },
+        };
+
+        let memory_file = MemoryFile(Arc::new(Mutex::new(data)));
+        self.file_worker.replace_file(memory_file.clone(), file_len).map_err(CompactError::WriteToFileError)?;
+        self.memory_file = Some(memory_file);
+        Ok(file_len)
+    }
+}
@@ -1,23 +1,54 @@
 use std::collections::BTreeSet;
 use std::sync::{Arc, RwLock};
+use crate::format::catch_callback_panic;
 use crate::map_trait::MapTrait;
 use std::marker::PhantomData;
 
 /// The index for getting indexes of the owner map by parts of value.
+/// Owner keys of each index key are kept in shared set, changes of the index copy the set
+/// only if it's still used by snapshot returned from 'get_arc'.
 pub struct Index<IndexKey, OwnerKey, OwnerValue, SelfMap>
-where SelfMap: MapTrait<IndexKey, BTreeSet<OwnerKey>> {
+where SelfMap: MapTrait<IndexKey, Arc<BTreeSet<OwnerKey>>> {
     /// Indexes of owner map by index keys.
     map: Arc<RwLock<SelfMap>>,
     /// Make index callback.
-    make_index_key_callback: fn(&OwnerValue) -> IndexKey,
+    make_index_key: MakeIndexKey<OwnerKey, OwnerValue, IndexKey>,
     /// Need for avoid "unused parameter" compile error.
     _phantom: PhantomData<OwnerKey>,
 }
 
+/// Callback for making index key from element of the owner map.
+pub(crate) enum MakeIndexKey<OwnerKey, OwnerValue, IndexKey> {
+    /// Index key is made from the value.
+    ByValue(fn(&OwnerValue) -> IndexKey),
+    /// Index key is made from the key, for sets where value is empty.
+    ByKey(fn(&OwnerKey) -> IndexKey),
+}
+
+impl<OwnerKey, OwnerValue, IndexKey> MakeIndexKey<OwnerKey, OwnerValue, IndexKey> {
+    /// Returns index key of the element.
+    fn make(&self, key: &OwnerKey, value: &OwnerValue) -> IndexKey {
+        match self {
+            MakeIndexKey::ByValue(callback) => callback(value),
+            MakeIndexKey::ByKey(callback) => callback(key),
+        }
+    }
+}
+
+impl<OwnerKey, OwnerValue, IndexKey> Clone for MakeIndexKey<OwnerKey, OwnerValue, IndexKey> {
+    /// Manually clone because #[derive(Clone)] requires Clone of all parameters
+    fn clone(&self) -> Self {
+        match self {
+            MakeIndexKey::ByValue(callback) => MakeIndexKey::ByValue(*callback),
+            MakeIndexKey::ByKey(callback) => MakeIndexKey::ByKey(*callback),
+        }
+    }
+}
+
 impl<IndexKey, OwnerKey, OwnerValue, SelfMap> Index<IndexKey, OwnerKey, OwnerValue, SelfMap>
 where
     OwnerKey: Ord + Clone,
-    SelfMap: MapTrait<IndexKey, BTreeSet<OwnerKey>> {
+    SelfMap: MapTrait<IndexKey, Arc<BTreeSet<OwnerKey>>> {
 
     /// Owner keys by custom index. Empty vec if no so index.
     pub fn get(&self, key: &IndexKey) -> Vec<OwnerKey> {
@@ -26,75 +57,109 @@ where
             .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under lock of this map
 
         if let Some(btree_keys) = map.get(key) {
-            vec = (*btree_keys).iter().cloned().collect();
+            vec = (**btree_keys).iter().cloned().collect();
         }
 
         vec
     }
 
+    /// Owner keys by custom index without cloning of keys. None if no so index.
+    /// Returned set is snapshot, it's not changed by later changes of the owner map.
+    pub fn get_arc(&self, key: &IndexKey) -> Option<Arc<BTreeSet<OwnerKey>>> {
+        let map = self.map.read()
+            .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under lock of this map
+
+        map.get(key).cloned()
+    }
+
     /// Constructs new Index from custom map and make index callback.
-    pub(crate) fn new(indexes: SelfMap, make_index_key_callback: fn(&OwnerValue) -> IndexKey) -> Self {
+    pub(crate) fn new(indexes: SelfMap, make_index_key: MakeIndexKey<OwnerKey, OwnerValue, IndexKey>) -> Self {
         Index {
             map: Arc::new(RwLock::new(indexes)),
-            make_index_key_callback,
+            make_index_key,
             _phantom: PhantomData,
         }
     }
+
+    /// Constructs new Index for all elements of the owner map.
+    pub(crate) fn from_owner_map<OwnerMap>(owner_map: &OwnerMap, make_index_key: MakeIndexKey<OwnerKey, OwnerValue, IndexKey>) -> Self
+    where
+        OwnerMap: MapTrait<OwnerKey, OwnerValue>,
+        SelfMap: Default,
+    {
+        let mut index_map = SelfMap::default();
+
+        owner_map.for_each(|key, val| {
+            let index_key = make_index_key.make(key, val);
+            match index_map.get_mut(&index_key) {
+                Some(keys) => {
+                    Arc::make_mut(keys).insert(key.clone());
+                }
+                None => {
+                    let mut set = BTreeSet::new();
+                    set.insert(key.clone());
+                    index_map.insert(index_key, Arc::new(set));
+                }
+            }
+        });
+
+        Index::new(index_map, make_index_key)
+    }
 }
 
+/// Change of the index with already made index keys, see 'UpdateIndex::prepare_insert'.
+pub(crate) type IndexUpdate<'a> = Box<dyn FnOnce() + 'a>;
+
 /// Trait for update the index when the owner map content changes.
 pub(crate) trait UpdateIndex<OwnerKey, OwnerValue> {
     /// Updates index when insert or update operation on map.
     fn on_insert(&self, key: OwnerKey, value: OwnerValue, old_value: Option<OwnerValue>);
+    /// Makes index keys of insert or update operation before the owner map is changed,
+    /// returned update changes the index. None if make index key callback panicked, the index is not changed.
+    fn prepare_insert<'a>(&'a self, key: &OwnerKey, value: &OwnerValue, old_value: Option<&OwnerValue>) -> Option<IndexUpdate<'a>>;
     /// Updates index when remove operation on map.
     fn on_remove(&self, key: &OwnerKey, value: &OwnerValue);
 }
 
 impl<IndexKey, OwnerKey: Ord, OwnerValue, SelfMap> UpdateIndex<OwnerKey, OwnerValue> for Index<IndexKey, OwnerKey, OwnerValue, SelfMap>
 where
-    OwnerKey: Ord,
-    SelfMap: MapTrait<IndexKey, BTreeSet<OwnerKey>> {
+    OwnerKey: Ord + Clone,
+    SelfMap: MapTrait<IndexKey, Arc<BTreeSet<OwnerKey>>> {
 
     /// Implementation of updating of index when insert operation on owner map.
     fn on_insert(&self, btree_key: OwnerKey, value: OwnerValue, old_value: Option<OwnerValue>) {
-        let index_key = (self.make_index_key_callback)(&value);
+        let index_key = self.make_index_key.make(&btree_key, &value);
         let old_value_index_key = if let Some(old_value) = old_value {
-            Some((self.make_index_key_callback)(&old_value))
+            Some(self.make_index_key.make(&btree_key, &old_value))
         } else {
             None
         };
 
-        let mut map = self.map.write()
-            .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under lock of this map
+        self.insert_owner_key(btree_key, index_key, old_value_index_key);
+    }
 
-        if let Some(old_value_index_key) = old_value_index_key {
-            if let Some(keys) = map.get_mut(&old_value_index_key) {
-                keys.remove(&btree_key);
-            }
-        }
+    /// Implementation of making of index keys before insert operation on owner map.
+    fn prepare_insert<'a>(&'a self, btree_key: &OwnerKey, value: &OwnerValue, old_value: Option<&OwnerValue>) -> Option<IndexUpdate<'a>> {
+        let (index_key, old_value_index_key) = catch_callback_panic(|| {
+            let index_key = self.make_index_key.make(btree_key, value);
+            let old_value_index_key = old_value.map(|old_value| self.make_index_key.make(btree_key, old_value));
+            (index_key, old_value_index_key)
+        })?;
 
-        match map.get_mut(&index_key) {
-            Some(keys) => {
-                keys.insert(btree_key);
-            }
-            None => {
-                let mut set = BTreeSet::new();
-                set.insert(btree_key);
-                map.insert(index_key, set);
-            }
-        }
+        let btree_key = btree_key.clone();
+        Some(Box::new(move || self.insert_owner_key(btree_key, index_key, old_value_index_key)))
     }
 
     /// Implementation of updating of index when remove operation on owner map.
     fn on_remove(&self, key: &OwnerKey, value: &OwnerValue) {
-        let index_key = (self.make_index_key_callback)(&value);
+        let index_key = self.make_index_key.make(key, value);
 
         let mut map = self.map.write()
             .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under lock of this map
 
         let mut need_remove_index = false;
         if let Some(keys) = map.get_mut(&index_key) {
-            keys.remove(key);
+            Arc::make_mut(keys).remove(key);
             if keys.is_empty() {
                 need_remove_index = true;
             }
@@ -105,14 +170,48 @@ where
     }
 }
 
+impl<IndexKey, OwnerKey, OwnerValue, SelfMap> Index<IndexKey, OwnerKey, OwnerValue, SelfMap>
+where
+    OwnerKey: Ord + Clone,
+    SelfMap: MapTrait<IndexKey, Arc<BTreeSet<OwnerKey>>> {
+
+    /// Move owner key from index key of the old value to index key of the new value.
+    fn insert_owner_key(&self, btree_key: OwnerKey, index_key: IndexKey, old_value_index_key: Option<IndexKey>) {
+        let mut map = self.map.write()
+            .unwrap_or_else(|err| unreachable!(err)); // unreachable because no code with possible panic under lock of this map
+
+        if let Some(old_value_index_key) = old_value_index_key {
+            // set shared with snapshot is copied by make_mut, so it's changed only if needed
+            if let Some(keys) = map.get_mut(&old_value_index_key) {
+                if keys.contains(&btree_key) {
+                    Arc::make_mut(keys).remove(&btree_key);
+                }
+            }
+        }
+
+        match map.get_mut(&index_key) {
+            Some(keys) => {
+                if !keys.contains(&btree_key) {
+                    Arc::make_mut(keys).insert(btree_key);
+                }
+            }
+            None => {
+                let mut set = BTreeSet::new();
+                set.insert(btree_key);
+                map.insert(index_key, Arc::new(set));
+            }
+        }
+    }
+}
+
 impl<IndexKey, OwnerKey, OwnerValue, SelfMap> Clone for Index<IndexKey, OwnerKey, OwnerValue, SelfMap>
-    where SelfMap: MapTrait<IndexKey, BTreeSet<OwnerKey>> {
+    where SelfMap: MapTrait<IndexKey, Arc<BTreeSet<OwnerKey>>> {
 
     /// Manually clone because #[derive(Clone)] can't work with PhantomData
     fn clone(&self) -> Self {
         Index {
             map: self.map.clone(),
-            make_index_key_callback: self.make_index_key_callback,
+            make_index_key: self.make_index_key.clone(),
             _phantom: PhantomData,
         }
     }
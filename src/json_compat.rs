@@ -0,0 +1,434 @@
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+/// Name of the only field of JSON object written instead of map with non-string keys,
+/// value of the field is array of [key,value] pairs.
+const PAIRS_MARKER: &str = "__dkmp";
+
+/// Wrapper for storing values containing maps with non-string keys (for example 'HashMap<(u32, u32), T>')
+/// in the text format. JSON object can have only string keys, so serde_json fails on such values
+/// with 'SerializedError::Json'. In human readable formats the wrapped value is written with maps as JSON objects
+/// only if all keys are strings, other maps are written as {"__dkmp":[[key,value],...]}.
+/// In the binary format the wrapped value is written as is.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JsonCompat<T>(pub T);
+
+impl<T: Serialize> Serialize for JsonCompat<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            self.0.serialize(CompatSerializer)
+                .map_err(ser::Error::custom)?
+                .serialize(serializer)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for JsonCompat<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let value = Value::deserialize(deserializer)?;
+            T::deserialize(CompatDeserializer(value))
+                .map(JsonCompat)
+                .map_err(de::Error::custom)
+        } else {
+            T::deserialize(deserializer).map(JsonCompat)
+        }
+    }
+}
+
+/// Serializer to JSON value same as 'serde_json::value::Serializer' except maps with non-string keys.
+struct CompatSerializer;
+
+macro_rules! forward_to_json_value_serializer {
+    ($($method:ident($t:ty)),*) => {
+        $(
+            fn $method(self, v: $t) -> Result<Value, serde_json::Error> {
+                serde_json::value::Serializer.$method(v)
+            }
+        )*
+    };
+}
+
+impl Serializer for CompatSerializer {
+    type Ok = Value;
+    type Error = serde_json::Error;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeVariant<SerializeArray>;
+    type SerializeMap = SerializeEntries;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeVariant<SerializeObject>;
+
+    forward_to_json_value_serializer!(
+        serialize_bool(bool), serialize_i8(i8), serialize_i16(i16), serialize_i32(i32), serialize_i64(i64), serialize_i128(i128),
+        serialize_u8(u8), serialize_u16(u16), serialize_u32(u32), serialize_u64(u64), serialize_u128(u128),
+        serialize_f32(f32), serialize_f64(f64), serialize_char(char), serialize_str(&str), serialize_bytes(&[u8])
+    );
+
+    fn serialize_none(self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value, serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str) -> Result<Value, serde_json::Error> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<Value, serde_json::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _variant_index: u32, variant: &'static str, value: &T)
+        -> Result<Value, serde_json::Error> {
+        Ok(object_of_variant(variant, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, serde_json::Error> {
+        Ok(SerializeArray(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, serde_json::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeArray, serde_json::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, len: usize)
+        -> Result<SerializeVariant<SerializeArray>, serde_json::Error> {
+        Ok(SerializeVariant { variant, inner: SerializeArray(Vec::with_capacity(len)) })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeEntries, serde_json::Error> {
+        Ok(SerializeEntries { entries: Vec::with_capacity(len.unwrap_or(0)), key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeObject, serde_json::Error> {
+        Ok(SerializeObject(Map::new()))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, _len: usize)
+        -> Result<SerializeVariant<SerializeObject>, serde_json::Error> {
+        Ok(SerializeVariant { variant, inner: SerializeObject(Map::new()) })
+    }
+}
+
+/// Serialization of sequence or tuple to JSON array.
+struct SerializeArray(Vec<Value>);
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        self.0.push(value.serialize(CompatSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Array(self.0))
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Serialization of struct to JSON object.
+struct SerializeObject(Map<String, Value>);
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), serde_json::Error> {
+        self.0.insert(key.to_string(), value.serialize(CompatSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Object(self.0))
+    }
+}
+
+/// Serialization of tuple or struct variant of enum to JSON object with the name of the variant as the only field.
+struct SerializeVariant<Inner> {
+    /// Name of the variant.
+    variant: &'static str,
+    /// Serialization of content of the variant.
+    inner: Inner,
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeArray> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        Ok(object_of_variant(self.variant, ser::SerializeSeq::end(self.inner)?))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeObject> {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), serde_json::Error> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        Ok(object_of_variant(self.variant, ser::SerializeStruct::end(self.inner)?))
+    }
+}
+
+/// Serialization of map, to JSON object if all keys are strings, otherwise to object with array of pairs.
+struct SerializeEntries {
+    /// Serialized entries.
+    entries: Vec<(Value, Value)>,
+    /// Key waiting for its value.
+    key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeEntries {
+    type Ok = Value;
+    type Error = serde_json::Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), serde_json::Error> {
+        self.key = Some(key.serialize(CompatSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        let key = self.key.take().ok_or_else(|| ser::Error::custom("value of map is serialized before key"))?;
+        self.entries.push((key, value.serialize(CompatSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, serde_json::Error> {
+        if self.entries.iter().all(|(key, _)| key.is_string()) {
+            let object = self.entries.into_iter()
+                .map(|(key, value)| match key {
+                    Value::String(key) => (key, value),
+                    _ => unreachable!(), // unreachable because all keys are checked above
+                })
+                .collect();
+            return Ok(Value::Object(object));
+        }
+
+        let pairs = self.entries.into_iter()
+            .map(|(key, value)| Value::Array(vec![key, value]))
+            .collect();
+        Ok(object_of_variant(PAIRS_MARKER, Value::Array(pairs)))
+    }
+}
+
+/// Returns JSON object with one field, same as serde_json writes enum variant with content.
+fn object_of_variant(name: &str, value: Value) -> Value {
+    let mut object = Map::new();
+    object.insert(name.to_string(), value);
+    Value::Object(object)
+}
+
+/// Deserializer from JSON value written by 'CompatSerializer'.
+struct CompatDeserializer(Value);
+
+impl<'de> Deserializer<'de> for CompatDeserializer {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde_json::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(v) => visitor.visit_bool(v),
+            Value::Number(v) => v.deserialize_any(visitor),
+            Value::String(v) => visitor.visit_string(v),
+            Value::Array(values) => visitor.visit_seq(CompatSeqAccess(values.into_iter())),
+            Value::Object(object) => visitor.visit_map(CompatMapAccess { entries: entries_of_object(object)?.into_iter(), value: None }),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde_json::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, serde_json::Error> {
+        match self.0 {
+            Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Object(object) if object.len() == 1 => {
+                let (variant, value) = object.into_iter().next()
+                    .unwrap_or_else(|| unreachable!()); // unreachable because length is checked above
+                visitor.visit_enum(CompatEnumAccess { variant, value })
+            },
+            _ => Err(de::Error::custom("string or object with one field expected for enum")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Returns entries of JSON object, object with only field of pairs is replaced with the pairs.
+fn entries_of_object(mut object: Map<String, Value>) -> Result<Vec<(Value, Value)>, serde_json::Error> {
+    if object.len() == 1 {
+        if let Some(pairs) = object.remove(PAIRS_MARKER) {
+            return pairs_of_array(pairs);
+        }
+    }
+
+    Ok(object.into_iter().map(|(key, value)| (Value::String(key), value)).collect())
+}
+
+/// Returns entries from array of [key,value] pairs.
+fn pairs_of_array(pairs: Value) -> Result<Vec<(Value, Value)>, serde_json::Error> {
+    match pairs {
+        Value::Array(pairs) => pairs.into_iter()
+            .map(|pair| match pair {
+                Value::Array(mut pair) if pair.len() == 2 => {
+                    let value = pair.remove(1);
+                    let key = pair.remove(0);
+                    Ok((key, value))
+                },
+                _ => Err(de::Error::custom("array of key and value expected for map entry")),
+            })
+            .collect(),
+        _ => Err(de::Error::custom("array of pairs expected for map with non-string keys")),
+    }
+}
+
+/// Access to elements of JSON array.
+struct CompatSeqAccess(std::vec::IntoIter<Value>);
+
+impl<'de> de::SeqAccess<'de> for CompatSeqAccess {
+    type Error = serde_json::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, serde_json::Error> {
+        match self.0.next() {
+            Some(value) => seed.deserialize(CompatDeserializer(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// Access to entries of map.
+struct CompatMapAccess {
+    /// Remaining entries.
+    entries: std::vec::IntoIter<(Value, Value)>,
+    /// Value of the last key.
+    value: Option<Value>,
+}
+
+impl<'de> de::MapAccess<'de> for CompatMapAccess {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, serde_json::Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(CompatDeserializer(key)).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, serde_json::Error> {
+        let value = self.value.take().ok_or_else(|| de::Error::custom("value of map is deserialized before key"))?;
+        seed.deserialize(CompatDeserializer(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// Access to variant of enum written as JSON object with one field.
+struct CompatEnumAccess {
+    /// Name of the variant.
+    variant: String,
+    /// Content of the variant.
+    value: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for CompatEnumAccess {
+    type Error = serde_json::Error;
+    type Variant = CompatDeserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, CompatDeserializer), serde_json::Error> {
+        let variant = seed.deserialize(IntoDeserializer::<'de, serde_json::Error>::into_deserializer(self.variant))?;
+        Ok((variant, CompatDeserializer(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for CompatDeserializer {
+    type Error = serde_json::Error;
+
+    fn unit_variant(self) -> Result<(), serde_json::Error> {
+        Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, serde_json::Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, serde_json::Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, serde_json::Error> {
+        self.deserialize_any(visitor)
+    }
+}
@@ -0,0 +1,173 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Encoding of keys to compact string for 'CodedKey', usually implemented for zero-sized type.
+pub trait KeyCodec<Key> {
+    /// Encode key to string.
+    fn encode(key: &Key) -> String;
+    /// Decode key from string returned by 'encode', error is description of the wrong string.
+    fn decode(encoded: &str) -> Result<Key, String>;
+}
+
+/// Key wrapper written in the text format as JSON string encoded by 'Codec' instead of serialization of the key,
+/// for example for 'SystemTime' keys serialized by serde as JSON object.
+/// In the binary format the wrapped key is written as is.
+/// Order, equality and hash are of the wrapped key, not of the encoded string.
+pub struct CodedKey<Key, Codec> {
+    /// Wrapped key.
+    key: Key,
+    /// Codec of key, fn pointer is used for Send and Sync regardless of the codec type.
+    codec: PhantomData<fn() -> Codec>,
+}
+
+impl<Key, Codec> CodedKey<Key, Codec> {
+    /// Wrap key.
+    pub fn new(key: Key) -> Self {
+        CodedKey { key, codec: PhantomData }
+    }
+
+    /// Returns reference to the wrapped key.
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Returns the wrapped key.
+    pub fn into_key(self) -> Key {
+        self.key
+    }
+}
+
+impl<Key, Codec> From<Key> for CodedKey<Key, Codec> {
+    fn from(key: Key) -> Self {
+        CodedKey::new(key)
+    }
+}
+
+impl<Key: Clone, Codec> Clone for CodedKey<Key, Codec> {
+    fn clone(&self) -> Self {
+        CodedKey::new(self.key.clone())
+    }
+}
+
+impl<Key: std::fmt::Debug, Codec> std::fmt::Debug for CodedKey<Key, Codec> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+impl<Key: PartialEq, Codec> PartialEq for CodedKey<Key, Codec> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<Key: Eq, Codec> Eq for CodedKey<Key, Codec> {}
+
+impl<Key: PartialOrd, Codec> PartialOrd for CodedKey<Key, Codec> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.key.partial_cmp(&other.key)
+    }
+}
+
+impl<Key: Ord, Codec> Ord for CodedKey<Key, Codec> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<Key: Hash, Codec> Hash for CodedKey<Key, Codec> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state)
+    }
+}
+
+impl<Key: Serialize, Codec: KeyCodec<Key>> Serialize for CodedKey<Key, Codec> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&Codec::encode(&self.key))
+        } else {
+            self.key.serialize(serializer)
+        }
+    }
+}
+
+impl<'de, Key: Deserialize<'de>, Codec: KeyCodec<Key>> Deserialize<'de> for CodedKey<Key, Codec> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            Codec::decode(&encoded)
+                .map(CodedKey::new)
+                .map_err(de::Error::custom)
+        } else {
+            Key::deserialize(deserializer).map(CodedKey::new)
+        }
+    }
+}
+
+/// Codec of 'SystemTime' as milliseconds since UNIX epoch (negative before it) and of 'Duration' as milliseconds.
+/// Precision less than millisecond is lost, so keys differing only in it become the same key after reopening.
+/// In the binary format 'SystemTime' is written by serde, which can't write time before UNIX epoch.
+pub struct MillisCodec;
+
+impl KeyCodec<SystemTime> for MillisCodec {
+    fn encode(key: &SystemTime) -> String {
+        match key.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_millis().to_string(),
+            Err(err) => format!("-{}", err.duration().as_millis()),
+        }
+    }
+
+    fn decode(encoded: &str) -> Result<SystemTime, String> {
+        let (before_epoch, millis) = match encoded.strip_prefix('-') {
+            Some(millis) => (true, millis),
+            None => (false, encoded),
+        };
+        let duration = <Self as KeyCodec<Duration>>::decode(millis)?;
+        let time = if before_epoch { UNIX_EPOCH.checked_sub(duration) } else { UNIX_EPOCH.checked_add(duration) };
+        time.ok_or_else(|| format!("time out of range: {}", encoded))
+    }
+}
+
+impl KeyCodec<Duration> for MillisCodec {
+    fn encode(key: &Duration) -> String {
+        key.as_millis().to_string()
+    }
+
+    fn decode(encoded: &str) -> Result<Duration, String> {
+        encoded.parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|err| format!("wrong milliseconds {}: {}", encoded, err))
+    }
+}
+
+/// Codec of 'Uuid' as hyphenated lowercase string.
+pub struct UuidCodec;
+
+impl KeyCodec<Uuid> for UuidCodec {
+    fn encode(key: &Uuid) -> String {
+        key.to_hyphenated().to_string()
+    }
+
+    fn decode(encoded: &str) -> Result<Uuid, String> {
+        Uuid::parse_str(encoded).map_err(|err| format!("wrong uuid {}: {}", encoded, err))
+    }
+}
+
+/// Codec of 'IpAddr' as usual string of IPv4 or IPv6 address.
+pub struct IpAddrCodec;
+
+impl KeyCodec<IpAddr> for IpAddrCodec {
+    fn encode(key: &IpAddr) -> String {
+        key.to_string()
+    }
+
+    fn decode(encoded: &str) -> Result<IpAddr, String> {
+        encoded.parse().map_err(|err| format!("wrong ip address {}: {}", encoded, err))
+    }
+}
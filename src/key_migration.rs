@@ -0,0 +1,219 @@
+use crate::bin_format::{bin_record_kind_and_key, load_bin_file_records, BinRecordReader};
+use crate::cfg::{Cfg, Format};
+use crate::format::{create_dirs_to_path_if_not_exist, file_record_of_insert, file_record_of_remove, file_record_of_schema, LoadLimits, MapOperation, RecordKind};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::text_format::{load_text_file_records, text_record_kind_and_key, TextRecordReader};
+use crate::LoadFileError;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use uuid::Uuid;
+
+/// Format of keys of the file for 'MapWithFile::open_migrating_from'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// Detect by the first record with key, see 'MapWithFile::open_migrating'.
+    Detect,
+    /// Keys of the file are old keys, the file is migrated.
+    Old,
+    /// Keys of the file are new keys, the file is opened as is.
+    New,
+}
+
+/// Error of 'MapWithFile::open_migrating'.
+#[derive(Debug)]
+pub enum OpenMigratingError {
+    /// First record with key can be read with both old and new key, or it's a batch,
+    /// format of keys must be passed to 'MapWithFile::open_migrating_from', line or block number.
+    AmbiguousKeyFormat { line_num: usize },
+    /// Error of reading of the file, with old keys when migrating.
+    LoadFileError(LoadFileError),
+    /// Error of serialization of migrated operation.
+    SerializeError(SerializedError),
+    /// When write error to the tmp file.
+    WriteToFileError(std::io::Error),
+    /// Error of creating tmp file or of replacing the file with it.
+    TmpFileError,
+}
+
+impl std::error::Error for OpenMigratingError {}
+
+impl std::fmt::Display for OpenMigratingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<LoadFileError> for OpenMigratingError {
+    fn from(err: LoadFileError) -> Self {
+        OpenMigratingError::LoadFileError(err)
+    }
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Same as 'open_or_create' but the file with keys of type 'OldKey' is migrated to keys of the map by 'migrate' before opening.
+    /// Format of keys is detected by the first record with key: if it can be read only with old key, the file is migrated,
+    /// if only with new key, the file is opened as is. If it can be read with both keys (for example u32 and u64)
+    /// or it's a batch, 'OpenMigratingError::AmbiguousKeyFormat' is returned and format must be passed to 'open_migrating_from'.
+    /// Empty or new file is opened as is.
+    pub fn open_migrating<OldKey>(file_path: impl AsRef<Path>, cfg: Cfg, migrate: impl Fn(OldKey) -> Key) -> Result<Self, OpenMigratingError>
+    where OldKey: DeserializeOwned {
+        Self::open_migrating_from(file_path, cfg, KeyFormat::Detect, migrate)
+    }
+
+    /// Same as 'open_migrating' but format of keys of the file is passed by 'key_format'.
+    /// The file is rewritten via tmp file in the same directory in the format of 'cfg' as by 'redact', and is exclusive locked
+    /// from detection to the end of opening, so other writer can't append records with old keys after migration.
+    pub fn open_migrating_from<OldKey>(file_path: impl AsRef<Path>, mut cfg: Cfg, key_format: KeyFormat, migrate: impl Fn(OldKey) -> Key) -> Result<Self, OpenMigratingError>
+    where OldKey: DeserializeOwned {
+        let file_path = file_path.as_ref();
+        create_dirs_to_path_if_not_exist(file_path).map_err(LoadFileError::FileError)?;
+
+        let mut file = fs::OpenOptions::new().read(true).append(true).create(true).open(file_path)
+            .map_err(LoadFileError::FileError)?;
+        file.lock_exclusive().map_err(LoadFileError::LockError)?;
+
+        let key_format = match key_format {
+            KeyFormat::Detect => {
+                let key_format = detect_key_format::<OldKey, Key>(&mut file, &mut cfg)?;
+                file.seek(SeekFrom::Start(0)).map_err(LoadFileError::FileError)?;
+                key_format
+            },
+            key_format => key_format,
+        };
+
+        if key_format == KeyFormat::New {
+            return Ok(Self::open_with_operations(file, file_path, cfg)?);
+        }
+
+        // operations are loaded before writing because integrity state of 'cfg' is changed by loading
+        let integrity_at_start = cfg.integrity.clone();
+        let mut operations = Vec::new();
+        let collect_map_operation = |map_operation| {
+            operations.push(map_operation);
+            Ok(())
+        };
+        let limits = LoadLimits::of(&cfg);
+        match &mut cfg.format {
+            Format::Text(_, after_read_callback) => {
+                load_text_file_records::<OldKey, Value, MapOperation<OldKey, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, collect_map_operation)
+            },
+            Format::Bin(_, after_read_callback) => {
+                load_bin_file_records::<OldKey, Value, MapOperation<OldKey, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, collect_map_operation)
+            },
+        }?;
+        cfg.integrity = integrity_at_start.clone();
+
+        // tmp file is in the same directory for renaming, it's locked before it replaces the file
+        let mut tmp_file_path = file_path.as_os_str().to_owned();
+        tmp_file_path.push(format!(".{}.tmp", Uuid::new_v4()));
+        let mut tmp_file = fs::OpenOptions::new().read(true).append(true).create_new(true).open(&tmp_file_path)
+            .map_err(|_| OpenMigratingError::TmpFileError)?;
+        tmp_file.lock_exclusive().map_err(|_| OpenMigratingError::TmpFileError)?;
+        let written = write_migrated(&mut tmp_file, &mut cfg, operations, migrate)
+            .and_then(|()| tmp_file.seek(SeekFrom::Start(0)).map(|_| ()).map_err(OpenMigratingError::WriteToFileError))
+            .and_then(|()| fs::rename(&tmp_file_path, file_path).map_err(|_| OpenMigratingError::TmpFileError));
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp_file_path);
+            return Err(err);
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(path = %file_path.display(), "keys of history file migrated");
+
+        // migrated file is loaded as usual from the start of integrity chain, the source file is unlocked after it
+        cfg.integrity = integrity_at_start;
+        let map = Self::open_with_operations(tmp_file, file_path, cfg)?;
+        drop(file);
+        Ok(map)
+    }
+
+    /// Open the locked file with insert and remove records as 'open_or_create'.
+    fn open_with_operations(file: fs::File, file_path: &Path, cfg: Cfg) -> Result<Self, LoadFileError> {
+        Self::open_locked_with(file, file_path, cfg, |map: &mut Map, map_operation| {
+            match map_operation {
+                MapOperation::Insert(key, value) => map.insert(key, value),
+                MapOperation::Remove(key) => map.remove(&key),
+            };
+        }, None)
+    }
+}
+
+/// Returns 'KeyFormat::Old' or 'KeyFormat::New' by the first record with key of the file,
+/// 'KeyFormat::New' if there are no such records. Integrity of 'cfg' is not changed.
+fn detect_key_format<OldKey, NewKey>(file: &mut fs::File, cfg: &mut Cfg) -> Result<KeyFormat, OpenMigratingError>
+where
+    OldKey: DeserializeOwned,
+    NewKey: DeserializeOwned,
+{
+    let mut integrity = cfg.integrity.clone();
+    let limits = LoadLimits::of(cfg);
+    let key_format = |new_key: Result<(RecordKind, Option<NewKey>), LoadFileError>, old_key: Result<(RecordKind, Option<OldKey>), LoadFileError>, line_num| {
+        match (new_key, old_key) {
+            (Ok((_, Some(_))), Err(_)) => Ok(Some(KeyFormat::New)),
+            (Err(_), Ok((_, Some(_)))) => Ok(Some(KeyFormat::Old)),
+            (Ok((RecordKind::Schema, _)), _) | (Ok((RecordKind::TransactionBegin, _)), _) | (Ok((RecordKind::TransactionEnd, _)), _) | (Ok((RecordKind::TransactionAbort, _)), _) => Ok(None),
+            (Err(err), Err(_)) => Err(OpenMigratingError::LoadFileError(err)),
+            _ => Err(OpenMigratingError::AmbiguousKeyFormat { line_num }),
+        }
+    };
+
+    match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            let mut reader = TextRecordReader::new(file);
+            while let Some(record) = reader.next_record(&mut integrity, after_read_callback, &limits)? {
+                let line_num = record.extent.num;
+                let new_key = text_record_kind_and_key::<NewKey>(record.data, line_num);
+                let old_key = text_record_kind_and_key::<OldKey>(record.data, line_num);
+                if let Some(key_format) = key_format(new_key, old_key, line_num)? {
+                    return Ok(key_format);
+                }
+            }
+        },
+        Format::Bin(_, after_read_callback) => {
+            let mut reader = BinRecordReader::new(file);
+            while let Some(record) = reader.next_record(&mut integrity, after_read_callback, &limits)? {
+                let block_num = record.extent.num;
+                let new_key = bin_record_kind_and_key::<NewKey>(record.data, block_num);
+                let old_key = bin_record_kind_and_key::<OldKey>(record.data, block_num);
+                if let Some(key_format) = key_format(new_key, old_key, block_num)? {
+                    return Ok(key_format);
+                }
+            }
+        },
+    }
+
+    Ok(KeyFormat::New)
+}
+
+/// Write schema record and operations with keys changed by 'migrate' in the format of 'cfg'.
+fn write_migrated<OldKey, Key, Value>(file: &mut fs::File, cfg: &mut Cfg, operations: Vec<MapOperation<OldKey, Value>>, migrate: impl Fn(OldKey) -> Key) -> Result<(), OpenMigratingError>
+where
+    Key: Serialize,
+    Value: Serialize,
+{
+    let mut writer = BufWriter::new(file);
+
+    if let Some(record) = file_record_of_schema(cfg).map_err(OpenMigratingError::SerializeError)? {
+        writer.write_all(&record).map_err(OpenMigratingError::WriteToFileError)?;
+    }
+
+    for map_operation in operations {
+        let record = match map_operation {
+            MapOperation::Insert(key, value) => file_record_of_insert(&migrate(key), &value, cfg),
+            MapOperation::Remove(key) => file_record_of_remove(&migrate(key), cfg),
+        }.map_err(OpenMigratingError::SerializeError)?;
+        writer.write_all(&record).map_err(OpenMigratingError::WriteToFileError)?;
+    }
+
+    writer.flush().map_err(OpenMigratingError::WriteToFileError)
+}
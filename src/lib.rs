@@ -2,17 +2,86 @@
 
 pub mod map_with_file;
 pub mod cfg;
+pub mod cfg_builder;
 pub mod format;
 pub mod index;
 pub mod map_trait;
 pub mod bin_format;
 pub mod text_format;
+pub mod csv_format;
+pub mod metrics;
+pub mod follower;
+pub mod lock_role;
+pub mod log_shipper;
+pub mod log_writer;
+pub mod log_reader;
+pub mod shared_map;
+pub mod opening_map;
+pub mod subscription;
+pub mod mirror;
+pub mod transaction;
+pub mod verify;
+pub mod multi_map;
+pub mod set_with_file;
+pub mod counter;
+pub mod bounded_map;
+pub mod ttl_map;
+pub mod json_compat;
+pub mod ordered_by;
+pub mod key_codec;
+pub mod key_migration;
+pub mod redact;
+pub mod support_bundle;
+pub mod replay_check;
+pub mod size_estimate;
+pub mod open_all;
+pub mod chain_anchor;
+pub mod dirty_marker;
+pub mod open_report;
+pub mod ship_cursor;
+pub mod compaction;
+pub mod snapshot;
+pub mod history;
+pub mod repair;
+pub mod clock;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+#[cfg(feature = "lock_free_reader")]
+pub mod map_reader;
+#[cfg(feature = "dashmap")]
+pub mod concurrent_map;
 mod file_worker;
+mod in_memory;
+mod chain_head;
+mod value_compression;
 mod tests;
 
 pub use map_with_file::BTreeMap;
 pub use map_with_file::HashMap;
+pub use map_with_file::BTreeMapOrderedBy;
+#[cfg(feature = "persistent")]
+pub use map_with_file::PersistentBTreeMap;
+#[cfg(feature = "persistent")]
+pub use map_with_file::PersistentHashMap;
+pub use shared_map::SharedBTreeMap;
+pub use shared_map::SharedHashMap;
+pub use multi_map::MultiMap;
+pub use set_with_file::BTreeSet;
+pub use set_with_file::HashSet;
+pub use bounded_map::BoundedMap;
+pub use ttl_map::TtlMap;
+pub use json_compat::JsonCompat;
+pub use ordered_by::{KeyOrder, OrderedBy};
+pub use key_codec::{CodedKey, KeyCodec};
+#[cfg(feature = "dashmap")]
+pub use concurrent_map::DashMap;
 pub use cfg::Cfg;
 pub use cfg::Format;
 pub use cfg::Integrity;
+pub use chain_anchor::ChainAnchor;
 pub use format::LoadFileError;
+pub use open_all::{open_all, open_all_with};
@@ -0,0 +1,75 @@
+use crate::cfg::Cfg;
+use crate::follower::FollowerMap;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// Role of the process in the single writer / multiple readers model of one history file.
+///
+/// The writer holds exclusive lock of the file while the map is opened and never takes shared lock,
+/// so it never releases exclusive lock for a moment as it would be with downgrade to shared lock
+/// and no other writer can take the file while the writer is alive.
+/// Readers follow the file (see 'FollowerMap'), take shared lock only while reading and never block the writer
+/// for longer than one read. A reader can wait for writer release by 'wait_for_writer_release'
+/// and then be promoted to the writer by reopening of the file with 'LockRole::Writer'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockRole {
+    /// Exclusive owner of the file that writes changes.
+    Writer,
+    /// Read-only follower of the file.
+    Reader,
+}
+
+/// Map opened with 'LockRole'.
+pub enum RoleMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Opened with 'LockRole::Writer'.
+    Writer(Box<MapWithFile<Key, Value, Map>>),
+    /// Opened with 'LockRole::Reader'.
+    Reader(Box<FollowerMap<Key, Value, Map>>),
+}
+
+impl<Key, Value: 'static, Map> RoleMap<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Open map with the role.
+    /// Writer is opened by 'MapWithFile::open_or_create' and waits while other writer holds the file.
+    /// Reader is opened by 'FollowerMap::open', the file must exist, 'poll_interval' is used only by reader.
+    pub fn open(file_path: impl AsRef<Path>, cfg: Cfg, role: LockRole, poll_interval: Duration) -> Result<Self, LoadFileError> {
+        match role {
+            LockRole::Writer => Ok(RoleMap::Writer(Box::new(MapWithFile::open_or_create(file_path, cfg)?))),
+            LockRole::Reader => Ok(RoleMap::Reader(Box::new(FollowerMap::open(file_path, cfg, poll_interval)?))),
+        }
+    }
+
+    /// Returns role of the map.
+    pub fn role(&self) -> LockRole {
+        match self {
+            RoleMap::Writer(_) => LockRole::Writer,
+            RoleMap::Reader(_) => LockRole::Reader,
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        match self {
+            RoleMap::Writer(map) => map.get(key),
+            RoleMap::Reader(map) => map.get(key),
+        }
+    }
+
+    /// Returns reference to the used map.
+    pub fn map(&self) -> &Map {
+        match self {
+            RoleMap::Writer(map) => map.map(),
+            RoleMap::Reader(map) => map.map(),
+        }
+    }
+}
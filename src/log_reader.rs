@@ -0,0 +1,185 @@
+use crate::bin_format::{bin_integrity_len, bin_record_kind, process_block_integrity, split_bin_record_meta, BinRecordReader};
+use crate::cfg::{AfterReadBinCallback, AfterReadTxtCallback, Cfg, Format, Integrity};
+use crate::format::{continue_chain, IntegrityError, LoadLimits, RawMeta, RecordExtent, RecordKind};
+use crate::text_format::{process_line_integrity, split_text_record_meta, text_record_kind, TextOps, TextRecordReader};
+use crate::LoadFileError;
+use std::fs::File;
+use std::path::Path;
+
+/// Reader of records of history file without types of keys and values, for tools like viewers, shippers and converters.
+/// Records are read by the same code as by loading of the map, with after read callback and max record size of 'cfg'.
+/// Integrity error doesn't stop reading: the record is returned with 'RecordIntegrity::Failed'
+/// and chained integrity continues from the hash in the file. Reading stops after the first error.
+pub struct LogReader {
+    /// Reader of the format with after read callback.
+    records: Records,
+    /// Integrity state before the next record.
+    integrity: Option<Integrity>,
+    /// Limits of records from config.
+    limits: LoadLimits,
+    /// True after error, nothing is read after it.
+    stopped: bool,
+}
+
+/// Record of the file returned by 'LogReader'.
+#[derive(Debug)]
+pub struct RawRecord {
+    /// Line or block number, starts from 1.
+    pub index: usize,
+    /// Offset of the record from the start of the file.
+    pub offset: u64,
+    /// Length of the record in the file with integrity and end of line or length of block.
+    pub len: u64,
+    /// Kind of the record, None if it's unknown.
+    pub op_kind: Option<RecordKind>,
+    /// Metadata of the record written by 'MapWithFile::insert_with_meta' or 'MapWithFile::remove_with_meta'.
+    pub meta: Option<RawPayload>,
+    /// Data of the record without integrity and metadata, starting with code of operation.
+    pub payload: RawPayload,
+    /// Result of check of integrity of the record.
+    pub integrity: RecordIntegrity,
+}
+
+/// Data of 'RawRecord'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawPayload {
+    /// Line data of the text format, without end of line.
+    Text(String),
+    /// Block data of the binary format.
+    Bin(Vec<u8>),
+}
+
+/// Integrity of 'RawRecord'.
+#[derive(Debug)]
+pub enum RecordIntegrity {
+    /// Checksum or hash of the record is correct.
+    Ok,
+    /// Checksum or hash of the record is wrong or missing.
+    Failed(IntegrityError),
+    /// Integrity is not used by config.
+    Absent,
+}
+
+/// Reader of the format of config.
+enum Records {
+    Text(TextRecordReader<File>, Option<AfterReadTxtCallback>),
+    Bin(BinRecordReader<File>, Option<AfterReadBinCallback>),
+}
+
+impl LogReader {
+    /// Open the file for reading in the format and with integrity of 'cfg', the file is not locked.
+    pub fn open(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let file = File::open(file_path)?;
+        let limits = LoadLimits::of(&cfg);
+        let records = match cfg.format {
+            Format::Text(_, after_read_callback) => Records::Text(TextRecordReader::new(file), after_read_callback),
+            Format::Bin(_, after_read_callback) => Records::Bin(BinRecordReader::new(file), after_read_callback),
+        };
+        Ok(LogReader { records, integrity: cfg.integrity, limits, stopped: false })
+    }
+
+    /// Returns the next record or None at the end of file.
+    fn next_record(&mut self) -> Result<Option<RawRecord>, LoadFileError> {
+        match &mut self.records {
+            Records::Text(reader, after_read_callback) => {
+                match reader.next_line(after_read_callback, &self.limits)? {
+                    Some((extent, line)) => text_raw_record(extent, line, &mut self.integrity, &self.limits.text_ops).map(Some),
+                    None => Ok(None),
+                }
+            },
+            Records::Bin(reader, after_read_callback) => {
+                match reader.next_block(after_read_callback, &self.limits)? {
+                    Some((extent, block)) => bin_raw_record(extent, block, &mut self.integrity).map(Some),
+                    None => Ok(None),
+                }
+            },
+        }
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<RawRecord, LoadFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stopped {
+            return None;
+        }
+
+        let record = self.next_record().transpose();
+        self.stopped = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// Record of the line with end of line.
+/// Kind of the record is known by alias of 'Cfg::extra_text_ops' too, payload contains name of operation as in the file.
+fn text_raw_record(extent: RecordExtent, line: &str, integrity: &mut Option<Integrity>, text_ops: &TextOps) -> Result<RawRecord, LoadFileError> {
+    let line_num = extent.num;
+    let (line_data, record_integrity) = match integrity {
+        Some(integrity) => match process_line_integrity(line, integrity, line_num) {
+            Ok(line_data) => (line_data, RecordIntegrity::Ok),
+            Err(err) => {
+                let line = line.trim_end_matches('\n');
+                let line_data = match line.rsplit_once(' ') {
+                    Some((line_data, hash_in_file)) => {
+                        continue_chain(integrity, &hex::decode(hash_in_file).unwrap_or_default());
+                        line_data
+                    },
+                    None => line,
+                };
+                (line_data, RecordIntegrity::Failed(err))
+            },
+        },
+        None => (line.trim_end_matches('\n'), RecordIntegrity::Absent),
+    };
+
+    let (meta, data) = split_text_record_meta(line_data, line_num)?;
+    Ok(RawRecord {
+        index: line_num,
+        offset: extent.offset,
+        len: extent.len,
+        op_kind: match text_ops.canonical(data) {
+            Some(canonical_data) => text_record_kind(&canonical_data),
+            None => text_record_kind(data),
+        },
+        meta: meta.map(RawPayload::from),
+        payload: RawPayload::Text(data.to_string()),
+        integrity: record_integrity,
+    })
+}
+
+/// Record of the block.
+fn bin_raw_record(extent: RecordExtent, block: &mut [u8], integrity: &mut Option<Integrity>) -> Result<RawRecord, LoadFileError> {
+    let (data_len, record_integrity) = match integrity {
+        Some(integrity) => match process_block_integrity(block, integrity, extent.num) {
+            Ok(data_block) => (data_block.len(), RecordIntegrity::Ok),
+            Err(err) => {
+                let data_len = block.len().saturating_sub(bin_integrity_len(integrity));
+                continue_chain(integrity, &block[data_len..]);
+                (data_len, RecordIntegrity::Failed(err))
+            },
+        },
+        None => (block.len(), RecordIntegrity::Absent),
+    };
+
+    let data_block = &block[..data_len];
+    let (meta, data) = if data_block.is_empty() { (None, data_block) } else { split_bin_record_meta(data_block)? };
+    Ok(RawRecord {
+        index: extent.num,
+        offset: extent.offset,
+        len: extent.len,
+        op_kind: if data.is_empty() { None } else { bin_record_kind(data) },
+        meta: meta.map(RawPayload::from),
+        payload: RawPayload::Bin(data.to_vec()),
+        integrity: record_integrity,
+    })
+}
+
+impl From<RawMeta<'_>> for RawPayload {
+    fn from(meta: RawMeta<'_>) -> Self {
+        match meta {
+            RawMeta::Json(json) => RawPayload::Text(json.to_string()),
+            RawMeta::Bin(data) => RawPayload::Bin(data.to_vec()),
+        }
+    }
+}
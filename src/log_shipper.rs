@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use crate::clock::Clock;
+
+/// Receiver of the records written to the history file for storing them somewhere else,
+/// for example for uploading to object storage or for sending to replication endpoint.
+/// Called in a separate shipping thread, so slow shipping doesn't slow down writing to the file.
+pub trait LogShipper: Send {
+    /// Ship segment of the history file. Segment contains one or more complete records
+    /// in the same order as in the file. If error is returned, the same segment will be shipped again later.
+    fn ship(&mut self, segment: &[u8]) -> Result<(), Box<dyn std::error::Error>>;
+    /// Called when all received segments are shipped.
+    fn flush(&mut self);
+}
+
+/// Called in the shipping thread when shipping of segment failed.
+pub type ShippingErrorCallback = Box<dyn FnMut(Box<dyn std::error::Error>) + Send>;
+
+/// Settings of shipping records of history file by 'LogShipper'.
+pub struct LogShipping {
+    /// Receiver of records.
+    pub shipper: Box<dyn LogShipper>,
+    /// Max size of segment, records are collected until this size.
+    /// Segment can be bigger only if it contains one record bigger than this size.
+    pub max_batch_bytes: usize,
+    /// Max time of collecting records into segment.
+    pub max_batch_delay: Duration,
+    /// Max size of not shipped segments in memory. When exceeded the oldest segments are dropped
+    /// from shipping (but not from the file).
+    pub max_spill_bytes: usize,
+    /// Delay before first retry of failed shipping, each next retry delay is doubled.
+    pub min_retry_delay: Duration,
+    /// Max delay between retries of failed shipping.
+    pub max_retry_delay: Duration,
+    /// Callback for receive errors of shipping.
+    pub error_callback: Option<ShippingErrorCallback>,
+    /// Callback called with count of dropped bytes when not shipped segments exceeded 'max_spill_bytes'.
+    pub overflow_callback: Option<Box<dyn FnMut(usize) + Send>>,
+}
+
+impl LogShipping {
+    /// Settings with default limits: segments up to 1 MiB collected up to 1 second,
+    /// up to 64 MiB of not shipped segments, retries from 100 ms to 1 minute.
+    pub fn new(shipper: impl LogShipper + 'static) -> Self {
+        LogShipping {
+            shipper: Box::new(shipper),
+            max_batch_bytes: 1024 * 1024,
+            max_batch_delay: Duration::from_secs(1),
+            max_spill_bytes: 64 * 1024 * 1024,
+            min_retry_delay: Duration::from_millis(100),
+            max_retry_delay: Duration::from_secs(60),
+            error_callback: None,
+            overflow_callback: None,
+        }
+    }
+}
+
+/// Example of 'LogShipper' writing each segment to the next numbered file in the directory
+/// as '000001.log', '000002.log' etc.
+pub struct DirLogShipper {
+    /// Directory of segments files.
+    dir: std::path::PathBuf,
+    /// Number of the next segment file.
+    next_num: u64,
+}
+
+impl DirLogShipper {
+    /// Create shipper to the directory, directory is created if not exists.
+    /// Numbering continues after the last existing segment file.
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut last_num = 0;
+        for entry in std::fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let num = name.to_str()
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|num| num.parse::<u64>().ok());
+            if let Some(num) = num {
+                last_num = last_num.max(num);
+            }
+        }
+
+        Ok(DirLogShipper { dir, next_num: last_num + 1 })
+    }
+}
+
+impl LogShipper for DirLogShipper {
+    fn ship(&mut self, segment: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(self.dir.join(format!("{:06}.log", self.next_num)), segment)?;
+        self.next_num += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) {}
+}
+
+/// For ship records in background thread.
+pub(crate) struct ShippingWorker {
+    task_sender: Sender<ShippingTask>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl ShippingWorker {
+    /// Starts thread of shipping, deadlines of segments and retries are measured by 'clock'.
+    pub fn new(mut shipping: LogShipping, clock: Arc<dyn Clock>) -> Self {
+        let (task_sender, task_receiver) = channel();
+
+        let join_handle = spawn(move || {
+            let mut state = ShippingState::new(clock);
+
+            loop {
+                let timeout = state.next_deadline(&shipping)
+                    .map(|deadline| deadline.duration_since(state.clock.now()).unwrap_or_default())
+                    .unwrap_or(Duration::from_secs(3600));
+
+                match task_receiver.recv_timeout(timeout) {
+                    Ok(ShippingTask::Ship(data)) => state.push(data, &mut shipping),
+                    Ok(ShippingTask::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                        state.seal(&mut shipping);
+                        // last attempt without waiting of retry delay
+                        state.retry_at = None;
+                        state.ship(&mut shipping);
+                        break;
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                }
+
+                if state.batch_started.map(|started| state.clock.elapsed(started) >= shipping.max_batch_delay).unwrap_or(false) {
+                    state.seal(&mut shipping);
+                }
+
+                state.ship(&mut shipping);
+            }
+        });
+
+        ShippingWorker { task_sender, join_handle: Some(join_handle) }
+    }
+
+    /// Ship data in the background thread.
+    pub fn ship(&self, data: Vec<u8>) {
+        self.task_sender.send(ShippingTask::Ship(data))
+            .unwrap_or_else(|err| unreachable!(err)); // unreachable because channel receiver will drop only after out of thread and thread can't stop while ShippingTask::Stop is not received
+    }
+}
+
+impl Drop for ShippingWorker {
+    fn drop(&mut self) {
+        self.task_sender.send(ShippingTask::Stop)
+            .unwrap_or_else(|err| unreachable!(err)); // unreachable because thread can't stop while ShippingTask::Stop is not received
+        self.join_handle.take().map(JoinHandle::join);
+    }
+}
+
+/// Task for sending to shipping thread.
+enum ShippingTask {
+    /// Ship records.
+    Ship(Vec<u8>),
+    /// Ship all remaining and stop.
+    Stop,
+}
+
+/// State of the shipping thread.
+struct ShippingState {
+    /// Collecting segment.
+    batch: Vec<u8>,
+    /// Time when first record was added to collecting segment.
+    batch_started: Option<SystemTime>,
+    /// Collected and not yet shipped segments.
+    spill: VecDeque<Vec<u8>>,
+    /// Size of 'spill' in bytes.
+    spill_bytes: usize,
+    /// Time of next retry after failed shipping.
+    retry_at: Option<SystemTime>,
+    /// Current retry delay.
+    retry_delay: Duration,
+    /// Source of current time.
+    clock: Arc<dyn Clock>,
+}
+
+impl ShippingState {
+    /// State without records.
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        ShippingState {
+            batch: Vec::new(),
+            batch_started: None,
+            spill: VecDeque::new(),
+            spill_bytes: 0,
+            retry_at: None,
+            retry_delay: Duration::default(),
+            clock,
+        }
+    }
+
+    /// Add records to collecting segment.
+    fn push(&mut self, data: Vec<u8>, shipping: &mut LogShipping) {
+        if !self.batch.is_empty() && self.batch.len() + data.len() > shipping.max_batch_bytes {
+            self.seal(shipping);
+        }
+
+        if self.batch.is_empty() {
+            self.batch_started = Some(self.clock.now());
+        }
+        self.batch.extend_from_slice(&data);
+
+        if self.batch.len() >= shipping.max_batch_bytes {
+            self.seal(shipping);
+        }
+    }
+
+    /// Move collecting segment to not shipped segments, dropping the oldest segments if limit exceeded.
+    fn seal(&mut self, shipping: &mut LogShipping) {
+        if self.batch.is_empty() {
+            return;
+        }
+
+        self.batch_started = None;
+        self.spill_bytes += self.batch.len();
+        self.spill.push_back(std::mem::take(&mut self.batch));
+
+        let mut dropped_bytes = 0;
+        while self.spill_bytes > shipping.max_spill_bytes {
+            match self.spill.pop_front() {
+                Some(segment) => {
+                    self.spill_bytes -= segment.len();
+                    dropped_bytes += segment.len();
+                },
+                None => break,
+            }
+        }
+
+        if dropped_bytes > 0 {
+            if let Some(callback) = &mut shipping.overflow_callback { callback(dropped_bytes); }
+        }
+    }
+
+    /// Ship not shipped segments if it's not time of waiting of retry.
+    fn ship(&mut self, shipping: &mut LogShipping) {
+        if self.retry_at.map(|retry_at| self.clock.now() < retry_at).unwrap_or(false) {
+            return;
+        }
+        self.retry_at = None;
+
+        let mut shipped = false;
+        while let Some(segment) = self.spill.front() {
+            match shipping.shipper.ship(segment) {
+                Ok(()) => {
+                    self.spill_bytes -= segment.len();
+                    self.spill.pop_front();
+                    self.retry_delay = Duration::default();
+                    shipped = true;
+                },
+                Err(err) => {
+                    self.retry_delay = (self.retry_delay * 2).max(shipping.min_retry_delay).min(shipping.max_retry_delay);
+                    self.retry_at = Some(self.clock.now() + self.retry_delay);
+                    if let Some(callback) = &mut shipping.error_callback { callback(err); }
+                    return;
+                },
+            }
+        }
+
+        if shipped {
+            shipping.shipper.flush();
+        }
+    }
+
+    /// Time when the thread should wake up without new records.
+    fn next_deadline(&self, shipping: &LogShipping) -> Option<SystemTime> {
+        let batch_deadline = self.batch_started.map(|started| started + shipping.max_batch_delay);
+        match (batch_deadline, self.retry_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::bin_format::load_bin_file_records;
+use crate::cfg::{Cfg, Format};
+use crate::chain_anchor::ChainAnchor;
+use crate::format::{create_dirs_to_path_if_not_exist, file_record_of_insert, file_record_of_remove, file_record_of_schema, file_record_of_transaction_marker, LoadLimits, MapOperation, TransactionMarker};
+use crate::map_with_file::SerializedError;
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// Writer of history file which can be opened by 'MapWithFile' later, without map in memory and background thread.
+/// Records are written in the calling thread in the format and with integrity of 'cfg', the file is exclusive locked while the writer exists.
+/// Records are buffered, buffer is written to the file by 'flush', 'finish' or when the writer is dropped.
+pub struct LogWriter<Key, Value> {
+    /// Locked file.
+    file: BufWriter<File>,
+    /// Config with current integrity state.
+    cfg: Cfg,
+    phantom: PhantomData<fn(&Key, &Value)>,
+}
+
+/// Error of 'LogWriter'.
+#[derive(Debug)]
+pub enum LogWriterError {
+    /// Error of opening or loading the file.
+    LoadFileError(LoadFileError),
+    /// File already contains records, returned by 'LogWriter::create'.
+    FileNotEmpty,
+    /// Error of serialization of the record.
+    SerializeError(SerializedError),
+    /// When write error to the file.
+    WriteToFileError(std::io::Error),
+}
+
+impl std::error::Error for LogWriterError {}
+
+impl std::fmt::Display for LogWriterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<LoadFileError> for LogWriterError {
+    fn from(err: LoadFileError) -> Self {
+        LogWriterError::LoadFileError(err)
+    }
+}
+
+impl From<SerializedError> for LogWriterError {
+    fn from(err: SerializedError) -> Self {
+        LogWriterError::SerializeError(err)
+    }
+}
+
+impl<Key, Value> LogWriter<Key, Value>
+where
+    Key: Serialize + DeserializeOwned,
+    Value: Serialize + DeserializeOwned,
+{
+    /// Create writer of the new file, the file is created if not exists.
+    /// Returns 'LogWriterError::FileNotEmpty' if the file already contains data.
+    pub fn create(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LogWriterError> {
+        let file = Self::open_locked(file_path.as_ref())?;
+        if file.metadata().map_err(LoadFileError::FileError)?.len() > 0 {
+            return Err(LogWriterError::FileNotEmpty);
+        }
+
+        Self::start(file, cfg, true)
+    }
+
+    /// Create writer appending to the existing file, the file is created if not exists.
+    /// Records of the file are loaded with check of integrity as by 'MapWithFile::open_or_create',
+    /// so chained integrity continues from the last record and incomplete transaction at the end is aborted.
+    pub fn append(file_path: impl AsRef<Path>, mut cfg: Cfg) -> Result<Self, LogWriterError> {
+        let mut file = Self::open_locked(file_path.as_ref())?;
+
+        let skip_operation = |_: MapOperation<Key, Value>| Ok(());
+        let limits = LoadLimits::of(&cfg);
+        let loaded_tail = match &mut cfg.format {
+            Format::Text(_, after_read_callback) => {
+                load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, skip_operation)?
+            },
+            Format::Bin(_, after_read_callback) => {
+                load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, skip_operation)?
+            },
+        };
+
+        let created = file.metadata().map_err(LoadFileError::FileError)?.len() == 0;
+        let mut writer = Self::start(file, cfg, created)?;
+
+        // records appended after incomplete transaction must not be treated as part of it
+        if loaded_tail.incomplete_transaction_integrity.is_some() {
+            let record = file_record_of_transaction_marker(TransactionMarker::Abort, &mut writer.cfg)?;
+            writer.write_record(&record)?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Write insert record.
+    pub fn insert(&mut self, key: &Key, value: &Value) -> Result<(), LogWriterError> {
+        let record = file_record_of_insert(key, value, &mut self.cfg)?;
+        self.write_record(&record)
+    }
+
+    /// Write remove record.
+    pub fn remove(&mut self, key: &Key) -> Result<(), LogWriterError> {
+        let record = file_record_of_remove(key, &mut self.cfg)?;
+        self.write_record(&record)
+    }
+
+    /// Write buffered records to the file.
+    pub fn flush(&mut self) -> Result<(), LogWriterError> {
+        self.file.flush().map_err(LogWriterError::WriteToFileError)
+    }
+
+    /// Write buffered records to the file and unlock it.
+    /// Returns head of chained integrity after the last record for opening of the next file or for storing by 'ChainAnchor::save',
+    /// None if integrity is not chained.
+    pub fn finish(mut self) -> Result<Option<ChainAnchor>, LogWriterError> {
+        self.flush()?;
+        Ok(self.cfg.integrity.as_ref().and_then(|integrity| integrity.anchor()))
+    }
+
+    /// Open and exclusive lock the file, create it with directories if not exists.
+    fn open_locked(file_path: &Path) -> Result<File, LoadFileError> {
+        create_dirs_to_path_if_not_exist(file_path)?;
+        let file = OpenOptions::new().read(true).append(true).create(true).open(file_path)?;
+        file.lock_exclusive().map_err(LoadFileError::LockError)?;
+        Ok(file)
+    }
+
+    /// Writer of the locked file, schema fingerprint is the first record of created file.
+    fn start(file: File, mut cfg: Cfg, created: bool) -> Result<Self, LogWriterError> {
+        let schema_record = if created { file_record_of_schema(&mut cfg)? } else { None };
+        let mut writer = LogWriter { file: BufWriter::new(file), cfg, phantom: PhantomData };
+        if let Some(record) = schema_record {
+            writer.write_record(&record)?;
+        }
+        Ok(writer)
+    }
+
+    /// Append record to the buffer of the file.
+    fn write_record(&mut self, record: &[u8]) -> Result<(), LogWriterError> {
+        self.file.write_all(record).map_err(LogWriterError::WriteToFileError)
+    }
+}
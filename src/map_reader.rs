@@ -0,0 +1,112 @@
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+
+/// Snapshot of the map for readers, persistent map with structural sharing,
+/// so the writer republishes it after each change without copying of entire map.
+pub type MapSnapshot<Key, Value> = im::OrdMap<Key, Value>;
+
+/// Cheap handle for reading of file based map from other threads without locks.
+///
+/// Strategy: the owner map keeps a persistent 'im::OrdMap' copy of own content (only after first
+/// call of 'reader') and after each change publishes new version of it by atomic swap of pointer.
+/// So the writer never waits of readers and readers never wait of the writer.
+/// It costs O(log n) additional time and memory for changed nodes on each change.
+///
+/// Consistency model:
+/// - reader sees state of the map after some completed change, never a partially applied change;
+/// - reader may lag behind the owner map by changes being applied at the moment of reading;
+/// - visibility is monotonic, after a change is seen by the reader, all previous changes are seen too
+///   and next reads never see older state;
+/// - visible change may be not yet written to the file, writing is done in the background thread.
+pub struct MapReader<Key, Value> {
+    /// Last published snapshot.
+    snapshot: Arc<ArcSwap<MapSnapshot<Key, Value>>>,
+}
+
+impl<Key, Value> MapReader<Key, Value>
+where Key: Ord + Clone, Value: Clone {
+    /// Returns clone of the value corresponding to the key in the last published snapshot.
+    pub fn get(&self, key: &Key) -> Option<Value> {
+        self.snapshot.load().get(key).cloned()
+    }
+
+    /// Returns last published snapshot of the map.
+    /// Use it for several consistent reads.
+    pub fn snapshot(&self) -> Arc<MapSnapshot<Key, Value>> {
+        self.snapshot.load_full()
+    }
+
+    /// Returns count of elements in the last published snapshot.
+    pub fn len(&self) -> usize {
+        self.snapshot.load().len()
+    }
+
+    /// Returns true if the last published snapshot contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.snapshot.load().is_empty()
+    }
+}
+
+impl<Key, Value> Clone for MapReader<Key, Value>
+where Key: Ord + Clone, Value: Clone {
+    fn clone(&self) -> Self {
+        MapReader { snapshot: self.snapshot.clone() }
+    }
+}
+
+impl<Key, Value, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Ord + Clone,
+    Value: Clone,
+    Map: MapTrait<Key, Value> {
+
+    /// Returns handle for reading of the map from other threads without locks, see 'MapReader'.
+    /// First call makes snapshot of entire map, after that each change of the map publishes new snapshot.
+    pub fn reader(&self) -> MapReader<Key, Value> {
+        self.snapshot_publisher
+            .get_or_init(|| {
+                let mut snapshot = MapSnapshot::new();
+                self.map.for_each(|key, value| {
+                    snapshot.insert(key.clone(), value.clone());
+                });
+                SnapshotPublisher::new(snapshot)
+            })
+            .reader()
+    }
+}
+
+/// Publisher of snapshots owned by the map.
+pub(crate) struct SnapshotPublisher<Key, Value> {
+    /// Last published snapshot shared with readers.
+    snapshot: Arc<ArcSwap<MapSnapshot<Key, Value>>>,
+}
+
+impl<Key, Value> SnapshotPublisher<Key, Value>
+where Key: Ord + Clone, Value: Clone {
+    /// Constructs publisher with initial snapshot.
+    pub fn new(snapshot: MapSnapshot<Key, Value>) -> Self {
+        SnapshotPublisher { snapshot: Arc::new(ArcSwap::from_pointee(snapshot)) }
+    }
+
+    /// New reader of published snapshots.
+    pub fn reader(&self) -> MapReader<Key, Value> {
+        MapReader { snapshot: self.snapshot.clone() }
+    }
+
+    /// Publish snapshot with inserted key-value.
+    /// Only owner map publishes, so snapshot can't be changed between load and store.
+    pub fn on_insert(&self, key: &Key, value: &Value) {
+        let mut snapshot = MapSnapshot::clone(&self.snapshot.load());
+        snapshot.insert(key.clone(), value.clone());
+        self.snapshot.store(Arc::new(snapshot));
+    }
+
+    /// Publish snapshot without removed key.
+    pub fn on_remove(&self, key: &Key) {
+        let mut snapshot = MapSnapshot::clone(&self.snapshot.load());
+        snapshot.remove(key);
+        self.snapshot.store(Arc::new(snapshot));
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
+use std::ops::ControlFlow;
 
 /// Trait of map.
 /// Needed for generalize maps, such as 'BTreeMap', 'HashMap', and use custom maps.
@@ -14,6 +15,25 @@ pub trait MapTrait<Key, Value> {
     fn remove(&mut self, key: &Key) -> Option<Value>;
     /// Iterate over all elements and call callback for each.
     fn for_each(&self, f: impl FnMut(&Key, &Value));
+    /// Iterate over elements and call callback for each until callback returns 'ControlFlow::Break'.
+    /// Default implementation iterates over all elements by 'for_each' but doesn't call callback after break.
+    fn try_for_each(&self, mut f: impl FnMut(&Key, &Value) -> ControlFlow<()>) -> ControlFlow<()> {
+        let mut result = ControlFlow::Continue(());
+        self.for_each(|key, value| {
+            if result.is_continue() {
+                result = f(key, value);
+            }
+        });
+        result
+    }
+    /// Returns the number of elements in the map. Default implementation iterates over all elements.
+    fn len(&self) -> usize {
+        let mut len = 0;
+        self.for_each(|_, _| len += 1);
+        len
+    }
+    /// Returns true if the map contains no elements.
+    fn is_empty(&self) -> bool { self.len() == 0 }
 }
 
 impl<Key: Ord, Value>  MapTrait<Key, Value> for BTreeMap<Key, Value>  {
@@ -22,6 +42,8 @@ impl<Key: Ord, Value>  MapTrait<Key, Value> for BTreeMap<Key, Value>  {
     fn insert(&mut self, key: Key, value: Value) -> Option<Value> { self.insert(key, value) }
     fn remove(&mut self, key: &Key) -> Option<Value> { self.remove(key) }
     fn for_each(&self, mut f: impl FnMut(&Key, &Value)) { for (key, val) in self.iter() { f(key, val) } }
+    fn try_for_each(&self, mut f: impl FnMut(&Key, &Value) -> ControlFlow<()>) -> ControlFlow<()> { self.iter().try_for_each(|(key, val)| f(key, val)) }
+    fn len(&self) -> usize { self.len() }
 }
 
 impl<Key: Hash + Eq, Value>  MapTrait<Key, Value>  for HashMap<Key, Value>  {
@@ -30,4 +52,54 @@ impl<Key: Hash + Eq, Value>  MapTrait<Key, Value>  for HashMap<Key, Value>  {
     fn insert(&mut self, key: Key, value: Value)  -> Option<Value> { self.insert(key, value) }
     fn remove(&mut self, key: &Key) -> Option<Value> { self.remove(key) }
     fn for_each(&self, mut f: impl FnMut(&Key, &Value)) { for (key, val) in self.iter() { f(key, val) } }
+    fn try_for_each(&self, mut f: impl FnMut(&Key, &Value) -> ControlFlow<()>) -> ControlFlow<()> { self.iter().try_for_each(|(key, val)| f(key, val)) }
+    fn len(&self) -> usize { self.len() }
+}
+
+/// Map that can make a copy of itself for a point-in-time snapshot.
+/// For persistent maps (im::OrdMap, im::HashMap) snapshot is O(1) because of structural sharing,
+/// for std maps it's a full clone of all elements.
+pub trait CloneableMapTrait<Key, Value>: MapTrait<Key, Value> {
+    /// Returns copy of the map, later changes of the map don't affect the copy.
+    fn snapshot(&self) -> Self;
+}
+
+impl<Key: Ord + Clone, Value: Clone> CloneableMapTrait<Key, Value> for BTreeMap<Key, Value> {
+    fn snapshot(&self) -> Self { self.clone() }
+}
+
+impl<Key: Hash + Eq + Clone, Value: Clone> CloneableMapTrait<Key, Value> for HashMap<Key, Value> {
+    fn snapshot(&self) -> Self { self.clone() }
+}
+
+#[cfg(feature = "persistent")]
+impl<Key: Ord + Clone, Value: Clone> MapTrait<Key, Value> for im::OrdMap<Key, Value> {
+    fn get(&self, key: &Key) -> Option<&Value> { self.get(key) }
+    fn get_mut(&mut self, key: &Key) -> Option<&mut Value> { self.get_mut(key) }
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value> { self.insert(key, value) }
+    fn remove(&mut self, key: &Key) -> Option<Value> { self.remove(key) }
+    fn for_each(&self, mut f: impl FnMut(&Key, &Value)) { for (key, val) in self.iter() { f(key, val) } }
+    fn try_for_each(&self, mut f: impl FnMut(&Key, &Value) -> ControlFlow<()>) -> ControlFlow<()> { self.iter().try_for_each(|(key, val)| f(key, val)) }
+    fn len(&self) -> usize { self.len() }
+}
+
+#[cfg(feature = "persistent")]
+impl<Key: Hash + Eq + Clone, Value: Clone> MapTrait<Key, Value> for im::HashMap<Key, Value> {
+    fn get(&self, key: &Key) -> Option<&Value> { self.get(key) }
+    fn get_mut(&mut self, key: &Key) -> Option<&mut Value> { self.get_mut(key) }
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value> { self.insert(key, value) }
+    fn remove(&mut self, key: &Key) -> Option<Value> { self.remove(key) }
+    fn for_each(&self, mut f: impl FnMut(&Key, &Value)) { for (key, val) in self.iter() { f(key, val) } }
+    fn try_for_each(&self, mut f: impl FnMut(&Key, &Value) -> ControlFlow<()>) -> ControlFlow<()> { self.iter().try_for_each(|(key, val)| f(key, val)) }
+    fn len(&self) -> usize { self.len() }
+}
+
+#[cfg(feature = "persistent")]
+impl<Key: Ord + Clone, Value: Clone> CloneableMapTrait<Key, Value> for im::OrdMap<Key, Value> {
+    fn snapshot(&self) -> Self { self.clone() }
+}
+
+#[cfg(feature = "persistent")]
+impl<Key: Hash + Eq + Clone, Value: Clone> CloneableMapTrait<Key, Value> for im::HashMap<Key, Value> {
+    fn snapshot(&self) -> Self { self.clone() }
 }
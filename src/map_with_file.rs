@@ -1,17 +1,61 @@
 use fs2::FileExt;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::BTreeSet;
+use std::sync::Arc;
 use std::fs::OpenOptions;
 use std::hash::Hash;
-use crate::index::{UpdateIndex, Index};
-use crate::file_worker::FileWorker;
-use crate::format::create_dirs_to_path_if_not_exist;
-use crate::map_trait::MapTrait;
-use crate::cfg::{Cfg, Format};
+use std::io::{Seek, SeekFrom};
+use std::convert::TryFrom;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use crate::index::{Index, IndexUpdate, MakeIndexKey, UpdateIndex};
+use crate::file_worker::{is_read_only_error, worker_panicked_error, FileWorker, FileWorkerCfg};
+use crate::replay_check::ReplayAnomaly;
+use crate::dirty_marker::DirtyMarker;
+use crate::open_report::{OpenReport, OpenWarning};
+use crate::chain_anchor::ChainAnchor;
+use crate::chain_head::ChainHeadFile;
+use crate::snapshot::{load_snapshot, skip_folded_log};
+use crate::compaction::BackgroundCompaction;
+use crate::in_memory::MemoryFile;
+use crate::format::{create_dirs_to_path_if_not_exist, file_record_of_batch, file_record_of_insert, file_record_of_meta_operation, file_record_of_schema, file_record_of_transaction_marker, integrity_before_record, apply_before_write, check_record_size, check_write, DefaultingOperation, IntegrityError, LoadLimits, LoadStats, ProgressReport, unlock_after_error, LoadedOperation, MapOperation, MetaOperation, TransactionMarker};
+use crate::metrics::{Metrics, Stats};
+use crate::subscription::{ChangeEvent, Subscribers};
+use crate::mirror::Mirrors;
+#[cfg(feature = "lock_free_reader")]
+use crate::map_reader::SnapshotPublisher;
+use crate::map_trait::{CloneableMapTrait, MapTrait};
+use crate::cfg::{Cfg, Format, WriteMode, WriteOperation};
+use crate::cfg::Integrity;
 use crate::LoadFileError;
-use crate::text_format::{map_from_text_file, text_file_line_of_insert, file_line_of_remove};
-use crate::bin_format::{map_from_bin_file, bin_file_block_of_insert, bin_file_block_of_remove};
+use crate::text_format::{load_text_file_records, text_line_data_of_insert, post_process_text_file_line, file_line_of_remove};
+use crate::ordered_by::OrderedBy;
+use crate::bin_format::{load_bin_file_records, render_bin_key, bin_block_data_of_insert, finish_bin_block, bin_file_block_of_remove};
+
+/// Makes canonical form of key, see 'MapWithFile::set_key_canonicalizer'.
+pub type KeyCanonicalizer<Key> = Box<dyn Fn(&Key) -> Key + Send + Sync>;
+
+/// Insert record remembered for 'Cfg::dedupe_consecutive'.
+pub(crate) struct LastRecord {
+    /// Data of the record without integrity.
+    payload: Vec<u8>,
+    /// Count of operations written after opening including the record, any next record changes it.
+    operations_since_open: u64,
+}
+
+impl LastRecord {
+    /// Returns true if record with 'payload' repeats this record and there are no other records after it.
+    fn is_repeated(&self, payload: &[u8], operations_since_open: u64) -> bool {
+        self.operations_since_open == operations_since_open && self.payload == payload
+    }
+}
+
+/// Min size of batch of records written to the file at once by 'try_extend' and similar.
+const WRITE_BATCH_BYTES: usize = 64 * 1024;
 
 /// Map with storing all changes history to the file.
 /// Restores own state from the file when creating.
@@ -23,19 +67,101 @@ pub type BTreeMap<Key, Value> = MapWithFile<Key, Value, std::collections::BTreeM
 /// Based on std::collections::HashMap.
 pub type HashMap<Key, Value> = MapWithFile<Key, Value, std::collections::HashMap<Key, Value>>;
 
+/// Map with storing all changes history to the file.
+/// Restores own state from the file when creating.
+/// Based on std::collections::BTreeMap with keys ordered by 'Order', see 'OrderedBy'.
+pub type BTreeMapOrderedBy<Key, Value, Order> = MapWithFile<OrderedBy<Key, Order>, Value, std::collections::BTreeMap<OrderedBy<Key, Order>, Value>>;
+
+/// Map with storing all changes history to the file.
+/// Restores own state from the file when creating.
+/// Based on im::OrdMap, so 'snapshot' is O(1).
+#[cfg(feature = "persistent")]
+pub type PersistentBTreeMap<Key, Value> = MapWithFile<Key, Value, im::OrdMap<Key, Value>>;
+
+/// Map with storing all changes history to the file.
+/// Restores own state from the file when creating.
+/// Based on im::HashMap, so 'snapshot' is O(1).
+#[cfg(feature = "persistent")]
+pub type PersistentHashMap<Key, Value> = MapWithFile<Key, Value, im::HashMap<Key, Value>>;
+
 /// File based map.
 /// Wrapper of map container with storing all changes history to the file.
 /// Restores own state from the file when creating.
+/// When the map is dropped, all changes are written and the file is flushed and synced to disk before it's unlocked,
+/// errors are passed to the write error callback, use 'close' for getting of the error.
 pub struct MapWithFile<Key, Value, Map>
 where Map: MapTrait<Key, Value>  {
     /// Wrapped map container.
-    map: Map,
+    pub(crate) map: Map,
     /// Config.
-    cfg: Cfg,
+    pub(crate) cfg: MapCfg,
     // For append map changes to the file in background thread.
-    file_worker: FileWorker,
+    pub(crate) file_worker: FileWorker,
+    /// Marker of 'Cfg::dirty_marker', declared after the file worker so it's removed after writing of all operations.
+    _dirty_marker: Option<DirtyMarker>,
+    /// Not fatal problems found when opening, locked only for making the map Sync because errors of after read callback are only Send.
+    open_warnings: std::sync::Mutex<Vec<OpenWarning>>,
     /// Created indexes.
-    indexes: Vec<Box<dyn UpdateIndex<Key, Value>>>,
+    pub(crate) indexes: Vec<Box<dyn UpdateIndex<Key, Value> + Send + Sync>>,
+    /// Count of records loaded from the file when opening.
+    pub(crate) records_loaded: u64,
+    /// Count of operations written to the file after opening.
+    pub(crate) operations_since_open: u64,
+    /// Count of loaded operations that don't affect the map after loading.
+    pub(crate) dead_records_loaded: u64,
+    /// Path of the file, empty for the map created by 'in_memory'.
+    pub(crate) file_path: PathBuf,
+    /// Data written instead of the file by the map created by 'in_memory', None for the map with file.
+    pub(crate) memory_file: Option<MemoryFile>,
+    /// Integrity state before the first record of the file, needed for verification of the file.
+    pub(crate) integrity_at_file_start: Option<Integrity>,
+    /// Subscribers of changes, see 'subscribe' and 'watch'.
+    pub(crate) subscribers: std::sync::Mutex<Subscribers<Key, Value>>,
+    /// Mirrors of changes, see 'attach_mirror'.
+    pub(crate) mirrors: std::sync::Mutex<Mirrors<Key, Value>>,
+    /// Canonicalizer of keys of insert, remove and lookups.
+    key_canonicalizer: Option<KeyCanonicalizer<Key>>,
+    /// Last written insert record if 'Cfg::dedupe_consecutive' is set.
+    pub(crate) last_record: Option<LastRecord>,
+    /// Count of operations in the file before which automatic compaction is not tried again after failed compaction.
+    pub(crate) auto_compact_not_before: u64,
+    /// Compaction started by 'compact_in_background' and not yet finished.
+    pub(crate) background_compaction: Option<BackgroundCompaction<Key>>,
+    /// Count of operations in the log before which automatic checkpoint is not tried again after failed checkpoint.
+    pub(crate) checkpoint_not_before: u64,
+    /// Anomalies of records found when opening if 'Cfg::collect_replay_anomalies' is set.
+    replay_anomalies: Vec<ReplayAnomaly>,
+    /// Sizes of records of the file counted when opening, boxed because it's rarely used.
+    load_stats: Box<LoadStats>,
+    /// Publisher of snapshots for lock-free readers, created by first call of 'reader'.
+    #[cfg(feature = "lock_free_reader")]
+    pub(crate) snapshot_publisher: std::sync::OnceLock<SnapshotPublisher<Key, Value>>,
+    /// Registration of the map in the registry of 'diagnostics::snapshot' while the map is open.
+    #[cfg(feature = "diagnostics")]
+    _diagnostics_registration: crate::diagnostics::Registration,
+}
+
+/// Config of the map behind mutex, so the map is Sync with callbacks of 'Cfg' that are only Send.
+/// Callbacks are called only by changing operations that have exclusive access to the map, they don't lock the mutex.
+pub(crate) struct MapCfg(std::sync::Mutex<Cfg>);
+
+impl MapCfg {
+    /// Config of the map owning 'cfg'.
+    pub(crate) fn new(cfg: Cfg) -> Self {
+        MapCfg(std::sync::Mutex::new(cfg))
+    }
+
+    /// Returns config without locking.
+    pub(crate) fn get_mut(&mut self) -> &mut Cfg {
+        self.0.get_mut()
+            .unwrap_or_else(|err| err.into_inner()) // config is poisoned only by panic in callback of config, it's still usable in this case
+    }
+
+    /// Lock for reading of config by not changing operations.
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, Cfg> {
+        self.0.lock()
+            .unwrap_or_else(|err| err.into_inner()) // config is poisoned only by panic in callback of config, it's still usable in this case
+    }
 }
 
 impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
@@ -48,78 +174,656 @@ where
     /// Open/create file and loads the entire history of
     /// changes from file restoring the last state of the map.
     /// If file is exist then load map from file. If file not is not exist then create new file.
-    pub fn open_or_create(file_path: &str, mut cfg: Cfg) -> Result<Self, LoadFileError> {
+    pub fn open_or_create(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        Self::open_with(file_path.as_ref(), cfg, Self::apply_map_operation)
+    }
+
+    /// Same as 'open_or_create' but fails with 'IntegrityError::ChainTruncated' if head of chained integrity after the last record
+    /// is not 'expected_head', so removed records at the end of the file are detected. The head is returned by 'integrity_head',
+    /// the application keeps it elsewhere after 'flush' or 'close'. The error is returned for the file without chained integrity too.
+    pub fn open_or_create_expecting(file_path: impl AsRef<Path>, cfg: Cfg, expected_head: &ChainAnchor) -> Result<Self, LoadFileError> {
+        Self::open_expecting_with(file_path.as_ref(), cfg, Self::apply_map_operation, Some(expected_head))
+    }
+
+    /// Same as 'open_or_create' but the file must exist, otherwise 'LoadFileError::FileNotFound' is returned
+    /// and nothing is created, so the map is not silently opened empty by wrong path.
+    pub fn open(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let file_path = file_path.as_ref();
+        let file = OpenOptions::new().read(true).append(true).open(file_path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => LoadFileError::FileNotFound,
+                _ => LoadFileError::FileError(err),
+            })?;
+        file.lock_exclusive().map_err(LoadFileError::LockError)?;
+
+        Self::open_locked_with(file, file_path, cfg, Self::apply_map_operation, None)
+    }
+
+    /// Same as 'open_or_create' but the file must not exist, otherwise 'LoadFileError::FileAlreadyExists' is returned
+    /// and the file is not read. Directories of the path are created if not exist.
+    pub fn create_new(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let file_path = file_path.as_ref();
         create_dirs_to_path_if_not_exist(file_path)?;
 
-        let mut file = OpenOptions::new().read(true).write(true).append(true).create(true).open(file_path)?;
-        file.lock_exclusive()?;
+        let file = OpenOptions::new().read(true).append(true).create_new(true).open(file_path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::AlreadyExists => LoadFileError::FileAlreadyExists,
+                _ => LoadFileError::FileError(err),
+            })?;
+        file.lock_exclusive().map_err(LoadFileError::LockError)?;
+
+        Self::open_locked_with(file, file_path, cfg, Self::apply_map_operation, None)
+    }
+
+    /// Apply loaded insert or remove to the map.
+    fn apply_map_operation(map: &mut Map, map_operation: MapOperation<Key, Value>) {
+        match map_operation {
+            MapOperation::Insert(key, value) => map.insert(key, value),
+            MapOperation::Remove(key) => map.remove(&key),
+        };
+    }
+
+    /// Opens the map for reading only, for example by reporting processes while other process owns the file.
+    /// The file must exist, it's opened with read access and shared lock, so several processes can open it read-only,
+    /// but if other process has it opened for writing, then 'LoadFileError::LockError' is returned without waiting.
+    /// Opening for writing waits while the file is opened read-only. The thread of writing is not spawned,
+    /// changes of the map return 'SerializedError::ReadOnly', compaction returns 'CompactError::ReadOnly'.
+    /// The file is not changed: abort marker is not written after incomplete transaction,
+    /// invalid tail found by 'Cfg::recovery' is skipped but not truncated and dirty marker is not written.
+    pub fn open_read_only(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let file_path = file_path.as_ref();
+        let file = std::fs::File::open(file_path)?;
+        FileExt::try_lock_shared(&file).map_err(LoadFileError::LockError)?;
+
+        let unlock_handle = file.try_clone()?;
+        Self::load_locked_with(file, file_path, cfg, Self::apply_map_operation, None, true)
+            .map_err(|err| unlock_after_error(&unlock_handle, err))
+    }
+
+    /// Same as 'open_or_create' but report of opening is returned with the map.
+    /// Warnings are moved to the report, so 'open_warnings' of the returned map is empty.
+    pub fn open_or_create_with_report(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<(Self, OpenReport), LoadFileError> {
+        let start_time = std::time::Instant::now();
+        let mut map = Self::open_or_create(file_path, cfg)?;
+        let report = OpenReport {
+            warnings: std::mem::take(map.open_warnings.get_mut().unwrap_or_else(|err| err.into_inner())),
+            load_duration: start_time.elapsed(),
+            records_loaded: map.records_loaded,
+            load_stats: map.load_stats().clone(),
+        };
+        Ok((map, report))
+    }
+
+    /// Same as 'open_or_create' but each loaded operation is applied to the map by 'apply'.
+    /// Operation type defines which records can be in the file.
+    pub(crate) fn open_with<Op>(file_path: &Path, cfg: Cfg, apply: impl FnMut(&mut Map, Op)) -> Result<Self, LoadFileError>
+    where Op: LoadedOperation<Key, Value> {
+        Self::open_expecting_with(file_path, cfg, apply, None)
+    }
+
+    /// Same as 'open_with' but head of chained integrity after loading must be 'expected_head' if it's passed.
+    fn open_expecting_with<Op>(file_path: &Path, cfg: Cfg, apply: impl FnMut(&mut Map, Op), expected_head: Option<&ChainAnchor>) -> Result<Self, LoadFileError>
+    where Op: LoadedOperation<Key, Value> {
+        create_dirs_to_path_if_not_exist(file_path)?;
+
+        let file = OpenOptions::new().read(true).write(true).append(true).create(true).open(file_path)?;
+        file.lock_exclusive().map_err(LoadFileError::LockError)?;
+
+        Self::open_locked_with(file, file_path, cfg, apply, expected_head)
+    }
+
+    /// Same as 'open_expecting_with' but with the file already opened for reading and appending and exclusive locked,
+    /// the file is read from the current position. The file is unlocked if error is returned.
+    pub(crate) fn open_locked_with<Op>(file: std::fs::File, file_path: &Path, cfg: Cfg, apply: impl FnMut(&mut Map, Op), expected_head: Option<&ChainAnchor>) -> Result<Self, LoadFileError>
+    where Op: LoadedOperation<Key, Value> {
+        // handle of the same open file for unlock, the file itself is moved to loading
+        let unlock_handle = file.try_clone()?;
+        Self::load_locked_with(file, file_path, cfg, apply, expected_head, false)
+            .map_err(|err| unlock_after_error(&unlock_handle, err))
+    }
+
+    /// Load the map from the locked file and start writing to it, see 'open_locked_with',
+    /// or without writing and any change of the file if 'read_only', see 'open_read_only'.
+    fn load_locked_with<Op>(mut file: std::fs::File, file_path: &Path, mut cfg: Cfg, mut apply: impl FnMut(&mut Map, Op), expected_head: Option<&ChainAnchor>, read_only: bool) -> Result<Self, LoadFileError>
+    where Op: LoadedOperation<Key, Value> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("open_or_create", path = %file_path.display(), integrity = cfg.integrity.as_ref().map(Integrity::name)).entered();
+        #[cfg(feature = "tracing")]
+        let start_time = std::time::Instant::now();
 
         // load current map from history file
-        let map = match &mut cfg.format {
+        let integrity_at_file_start = cfg.integrity.clone();
+        let mut map = Map::default();
+        if cfg.snapshot.is_some() {
+            let log_len = file.metadata()?.len();
+            let folded_log_len = load_snapshot(&mut map, file_path, &mut cfg, log_len)?;
+            if folded_log_len > 0 {
+                cfg.integrity = skip_folded_log(&mut file, &cfg, folded_log_len)?;
+            }
+        }
+        let mut records_loaded: u64 = 0;
+        let apply_map_operation = |operation| {
+            apply(&mut map, operation);
+            records_loaded += 1;
+            Ok(())
+        };
+
+        let load_start = file.stream_position()?;
+        let total_bytes = file.metadata()?.len();
+        let limits = LoadLimits {
+            render_bin_key: Some(render_bin_key::<Key>),
+            recovery: cfg.recovery,
+            file_path: Some(PathBuf::from(file_path)),
+            file_offset: load_start,
+            progress: cfg.load_progress_callback.take().map(|callback| ProgressReport { callback, interval: cfg.load_progress_interval, total_bytes }),
+            ..LoadLimits::of(&cfg)
+        };
+        let loaded_tail = match &mut cfg.format {
             Format::Text(_, after_read_callback) => {
                 let mut callback = None;
                 std::mem::swap(after_read_callback, &mut callback);
-                map_from_text_file::<Map, Key, Value, _, _>(&mut file, &mut cfg.integrity, callback)?
+                load_text_file_records::<Key, Value, Op, _, _, _>(&mut file, &mut cfg.integrity, callback, cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, apply_map_operation)?
             },
             Format::Bin(_,  after_read_callback) => {
                 let mut callback = None;
                 std::mem::swap(after_read_callback, &mut callback);
-                map_from_bin_file::<Map, Key, Value, _, _>(&mut file, &mut cfg.integrity, callback)?
+                load_bin_file_records::<Key, Value, Op, _, _, _>(&mut file, &mut cfg.integrity, callback, cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, apply_map_operation)?
             },
         };
 
-        Ok(MapWithFile {
+        // removed records at the end are detected before invalid tail is truncated
+        if let Some(expected_head) = expected_head {
+            if cfg.integrity.as_ref().and_then(Integrity::anchor).as_ref() != Some(expected_head) {
+                return Err(LoadFileError::IntegrityError(IntegrityError::ChainTruncated { line_num: loaded_tail.records }));
+            }
+        }
+        if let Some(chain_head) = ChainHeadFile::of(file_path, &cfg) {
+            let valid_len = loaded_tail.truncated_at.map_or(total_bytes, |truncated_at| load_start + truncated_at);
+            chain_head.check(&file, valid_len, integrity_at_file_start.as_ref().and_then(Integrity::anchor), loaded_tail.records)?;
+        }
+
+        let mut open_warnings = Vec::new();
+        for bad_record in loaded_tail.bad_records {
+            open_warnings.push(OpenWarning::SkippedBadRecord { record_num: bad_record.num, offset: load_start + bad_record.offset, error: bad_record.error });
+        }
+        if let Some(truncated_at) = loaded_tail.truncated_at.filter(|_| !read_only) {
+            // appending continues from the end of the last valid record
+            let len = load_start + truncated_at;
+            let dropped_bytes = file.metadata()?.len() - len;
+            file.set_len(len)?;
+            file.seek(SeekFrom::Start(len))?;
+            open_warnings.push(OpenWarning::TruncatedTail { len, dropped_bytes });
+        }
+        if loaded_tail.recovered.skipped > 0 {
+            open_warnings.push(OpenWarning::SkippedRecords { count: loaded_tail.recovered.skipped });
+        }
+        if loaded_tail.recovered.defaulted > 0 {
+            open_warnings.push(OpenWarning::DefaultValues { count: loaded_tail.recovered.defaulted });
+        }
+        let dirty_marker = if cfg.dirty_marker && !read_only {
+            DirtyMarker::mark(file_path, &mut open_warnings)
+        } else {
+            None
+        };
+
+        let dead_records_loaded = records_loaded.saturating_sub(map.len() as u64);
+        let file_len_at_open = file.metadata()?.len();
+        let file_worker = if read_only {
+            FileWorker::read_only(file, file_len_at_open)
+        } else {
+            FileWorker::new(file, FileWorkerCfg::take_from(&mut cfg, PathBuf::from(file_path), file_len_at_open))
+        };
+
+        // records appended after incomplete transaction must not be treated as part of it
+        if loaded_tail.incomplete_transaction_integrity.is_some() && !read_only {
+            let record = file_record_of_transaction_marker(TransactionMarker::Abort, &mut cfg).map_err(LoadFileError::WriteRecordError)?;
+            file_worker.write_bytes(record, WriteOperation::TransactionAbort)?;
+        }
+
+        // schema fingerprint is the first record of created file
+        if file_len_at_open == 0 && !read_only {
+            if let Some(record) = file_record_of_schema(&mut cfg).map_err(LoadFileError::WriteRecordError)? {
+                file_worker.write_bytes(record, WriteOperation::Schema)?;
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(records_loaded, duration_ms = start_time.elapsed().as_millis() as u64, "map opened");
+
+        let mut map_with_file = Self::with_file_worker(map, cfg, file_worker, PathBuf::from(file_path), integrity_at_file_start);
+        map_with_file._dirty_marker = dirty_marker;
+        map_with_file.open_warnings = std::sync::Mutex::new(open_warnings);
+        map_with_file.records_loaded = records_loaded;
+        map_with_file.dead_records_loaded = dead_records_loaded;
+        map_with_file.replay_anomalies = loaded_tail.replay_anomalies;
+        map_with_file.load_stats = Box::new(loaded_tail.load_stats);
+        Ok(map_with_file)
+    }
+
+    /// Map with state of loading of the empty file, writing by 'file_worker', see 'load_locked_with' and 'in_memory'.
+    pub(crate) fn with_file_worker(map: Map, cfg: Cfg, file_worker: FileWorker, file_path: PathBuf, integrity_at_file_start: Option<Integrity>) -> Self {
+        #[cfg(feature = "diagnostics")]
+        let diagnostics_registration = crate::diagnostics::Registration::register(file_path.clone(), std::any::type_name::<Map>(), &file_worker.shared_counters());
+
+        MapWithFile {
             map,
-            file_worker: FileWorker::new(file, cfg.write_error_callback.take()),
+            file_worker,
+            _dirty_marker: None,
+            open_warnings: std::sync::Mutex::new(Vec::new()),
             indexes: Vec::new(),
-            cfg,
+            cfg: MapCfg::new(cfg),
+            records_loaded: 0,
+            operations_since_open: 0,
+            dead_records_loaded: 0,
+            file_path,
+            memory_file: None,
+            integrity_at_file_start,
+            subscribers: std::sync::Mutex::new(Subscribers::default()),
+            mirrors: std::sync::Mutex::new(Mirrors::default()),
+            key_canonicalizer: None,
+            last_record: None,
+            auto_compact_not_before: 0,
+            checkpoint_not_before: 0,
+            background_compaction: None,
+            replay_anomalies: Vec::new(),
+            load_stats: Box::default(),
+            #[cfg(feature = "lock_free_reader")]
+            snapshot_publisher: std::sync::OnceLock::new(),
+            #[cfg(feature = "diagnostics")]
+            _diagnostics_registration: diagnostics_registration,
+        }
+    }
+
+    /// Same as 'open_or_create' but 'on_record' is called for each loaded insert and remove
+    /// with metadata written by 'insert_with_meta' or 'remove_with_meta', None for records without metadata.
+    pub fn open_or_create_with_meta<Meta>(file_path: impl AsRef<Path>, cfg: Cfg, mut on_record: impl FnMut(&MapOperation<Key, Value>, Option<Meta>)) -> Result<Self, LoadFileError>
+    where Meta: DeserializeOwned {
+        Self::open_with(file_path.as_ref(), cfg, |map: &mut Map, operation: MetaOperation<Key, Value, Meta>| {
+            on_record(&operation.map_operation, operation.meta);
+            match operation.map_operation {
+                MapOperation::Insert(key, value) => map.insert(key, value),
+                MapOperation::Remove(key) => map.remove(&key),
+            };
+        })
+    }
+
+    /// Same as 'open_or_create' but 'observer' is called for each insert and remove loaded from the file
+    /// as it's applied to the map, with index of the operation starting from 1, for building of external projections
+    /// without second reading of the file. Operations are passed after integrity check of the record,
+    /// operations of transactions are passed when the transaction is committed, aborted operations are not passed.
+    pub fn open_or_create_with_observer(file_path: impl AsRef<Path>, cfg: Cfg, mut observer: impl FnMut(usize, &MapOperation<Key, Value>)) -> Result<Self, LoadFileError> {
+        let mut index = 0;
+        Self::open_with(file_path.as_ref(), cfg, |map: &mut Map, map_operation: MapOperation<Key, Value>| {
+            index += 1;
+            observer(index, &map_operation);
+            match map_operation {
+                MapOperation::Insert(key, value) => map.insert(key, value),
+                MapOperation::Remove(key) => map.remove(&key),
+            };
+        })
+    }
+
+    /// Same as 'open_or_create' but with support of 'DeserializePolicy::Default' of 'Cfg::on_deserialize_error',
+    /// insert records which value can't be deserialized are loaded with 'Value::default()'.
+    /// It's a recovery mode for files written before incompatible change of the value type, loaded values are not written back.
+    pub fn open_or_create_with_default_values(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError>
+    where Value: Default {
+        Self::open_with(file_path.as_ref(), cfg, |map: &mut Map, operation: DefaultingOperation<Key, Value>| {
+            match operation.0 {
+                MapOperation::Insert(key, value) => map.insert(key, value),
+                MapOperation::Remove(key) => map.remove(&key),
+            };
         })
     }
 
+    /// Constructs file based map from the map container writing all its entries to the new file.
+    /// If file is not exist then it's created. Returns error if file already contains records.
+    pub fn create_from_map(file_path: impl AsRef<Path>, cfg: Cfg, map: Map) -> Result<Self, CreateError> {
+        let mut map_with_file = Self::create_empty(file_path, cfg)?;
+
+        let mut batch = Vec::new();
+        let mut result = Ok(());
+        map.for_each(|key, value| {
+            if result.is_ok() {
+                result = map_with_file.push_insert_record(&mut batch, key, value);
+            }
+        });
+        let written = map_with_file.write_batch(batch);
+        result.and(written).map_err(CreateError::SerializeError)?;
+
+        map_with_file.map = map;
+        Ok(map_with_file)
+    }
+
+    /// Constructs file based map with the new file from key-value pairs of iterator.
+    /// Pairs are written to the file in iteration order, for duplicate keys the last value wins.
+    /// If file is not exist then it's created. Returns error if file already contains records.
+    pub fn from_iter_new<Iter>(file_path: impl AsRef<Path>, cfg: Cfg, iter: Iter) -> Result<Self, CreateError>
+    where Iter: IntoIterator<Item = (Key, Value)> {
+        let mut map_with_file = Self::create_empty(file_path, cfg)?;
+        map_with_file.try_extend(iter)
+            .map_err(CreateError::SerializeError)?;
+
+        Ok(map_with_file)
+    }
+
+    /// Inserts all key-value pairs from iterator in iteration order.
+    /// Records are written to the file by big batches instead of one by one.
+    /// Stops on first serialization error, in this case the pairs before error remain inserted.
+    /// Returns count of written records, it's less than count of pairs
+    /// if 'skip_identical_inserts' of config is set and some values are not changed.
+    pub fn try_extend<Iter>(&mut self, iter: Iter) -> Result<usize, SerializedError>
+    where Iter: IntoIterator<Item = (Key, Value)> {
+        let mut batch = Vec::new();
+        let mut count = 0;
+        let mut result = Ok(());
+        for (key, value) in iter {
+            let key = self.canonical_key(&key).into_owned();
+            if self.cfg.get_mut().skip_identical_inserts && self.is_identical(&key, &value) {
+                continue;
+            }
+
+            if let Err(err) = self.push_insert_record(&mut batch, &key, &value) {
+                result = Err(err);
+                break;
+            }
+
+            let old_value = self.map.insert(key.clone(), value.clone());
+            self.update_index_when_insert(&key, &value, &old_value);
+            count += 1;
+        }
+
+        let written = self.write_batch(batch);
+        self.after_write();
+        result.and(written).map(|()| count)
+    }
+
+    /// Returns clone of the wrapped map container.
+    pub fn clone_inner(&self) -> Map where Map: Clone {
+        self.map.clone()
+    }
+
+    /// Returns point-in-time copy of the wrapped map container, later changes of the map don't affect it.
+    /// It's O(1) for persistent backends ('PersistentBTreeMap', 'PersistentHashMap').
+    pub fn snapshot(&self) -> Map where Map: CloneableMapTrait<Key, Value> {
+        self.map.snapshot()
+    }
+
+    /// Open or create file that must not contain records.
+    fn create_empty(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, CreateError> {
+        let map_with_file = Self::open_or_create(file_path, cfg)
+            .map_err(CreateError::LoadFileError)?;
+
+        if map_with_file.records_loaded > 0 || !map_with_file.map.is_empty() {
+            return Err(CreateError::FileNotEmpty);
+        }
+
+        Ok(map_with_file)
+    }
+
+    /// Append insert record to the 'batch' without changing the map.
+    /// Batch is written to the file when it's big enough, with 'WriteMode::Sync' each record is written
+    /// immediately, so the map is changed by caller only after the record is in the file.
+    fn push_insert_record(&mut self, batch: &mut Vec<u8>, key: &Key, value: &Value) -> Result<(), SerializedError> {
+        // map is changed by caller before the batch is written in background
+        if self.file_worker.is_read_only() {
+            return Err(SerializedError::ReadOnly);
+        }
+        let integrity_before = integrity_before_record(self.cfg.get_mut());
+        let record = file_record_of_insert(key, value, self.cfg.get_mut())?;
+        batch.extend_from_slice(&record);
+        if batch.len() >= WRITE_BATCH_BYTES || self.cfg.get_mut().write_mode == WriteMode::Sync {
+            let written = self.file_worker.write_bytes(std::mem::take(batch), WriteOperation::Batch);
+            check_write(written, &mut self.cfg.get_mut().integrity, integrity_before)?;
+        }
+        self.operations_since_open += 1;
+        Ok(())
+    }
+
+    /// Write rest of batch to the file, it's always empty with 'WriteMode::Sync'.
+    fn write_batch(&self, batch: Vec<u8>) -> Result<(), SerializedError> {
+        if !batch.is_empty() {
+            self.file_worker.write_bytes(batch, WriteOperation::Batch).map_err(SerializedError::of_write_error)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the map contains the key with value serialized the same as 'value'.
+    pub(crate) fn is_identical(&self, key: &Key, value: &Value) -> bool {
+        let old_value = match self.map.get(key) {
+            Some(old_value) => old_value,
+            None => return false,
+        };
+
+        match &self.cfg.lock().format {
+            Format::Text(..) => matches!((serde_json::to_vec(old_value), serde_json::to_vec(value)), (Ok(a), Ok(b)) if a == b),
+            Format::Bin(..) => matches!((bincode2::serialize(old_value), bincode2::serialize(value)), (Ok(a), Ok(b)) if a == b),
+        }
+    }
+
     /// Inserts a key-value pair into the map.
     /// Insert into the map will immediately, and to disk later in a background thread.
+    /// With 'WriteMode::Sync' of config the record is written to the file before insert into the map.
     ///
     /// # Errors
     ///
     /// Error can by returned only if serde_json::to_string() return error:
     /// Serialization can fail if 'Key' or 'Value' s implementation of `Serialize` decides to
     /// fail, or if 'Key' or 'Value' contains a map with non-string keys.
+    /// If record is longer than 'Cfg::max_value_size', then 'SerializedError::ValueTooLarge'
+    /// is returned and the map, the file and indexes are not changed.
+    /// If before write callback of the format or make index key callback of any index panics,
+    /// then 'SerializedError::CallbackPanicked' is returned and nothing is changed too.
+    /// If writing to the file fails with 'WriteMode::Sync', then 'SerializedError::WriteError' is returned and nothing is changed.
     ///
     pub fn insert(&mut self, key: Key, value: Value) -> Result<Option<Value>, SerializedError> {
-        match & mut self.cfg.format {
-            Format::Text(before_write_callback, _) => {
-                let mut line = text_file_line_of_insert(&key, &value, &mut self.cfg.integrity)?;
-                let old_value = self.map.insert(key.clone(), value.clone());
-                if let Some(f) = before_write_callback {
-                    f(&mut line);
-                }
-                self.file_worker.write_string(line);
-                self.update_index_when_insert(&key, &value, &old_value);
-                Ok(old_value)
-            },
-            Format::Bin(before_write_callback, _) => {
-                let mut block = bin_file_block_of_insert(&key, &value, &mut self.cfg.integrity)?;
-                let old_value = self.map.insert(key.clone(), value.clone());
-                if let Some(f) = before_write_callback {
-                    f(&mut block);
-                }
-                if let Some(f) = before_write_callback {
-                    f(&mut block);
+        let key = match &self.key_canonicalizer {
+            Some(canonicalizer) => canonicalizer(&key),
+            None => key,
+        };
+
+        if self.cfg.get_mut().skip_identical_inserts && self.is_identical(&key, &value) {
+            return Ok(Some(value));
+        }
+
+        // index keys are made before any change, so panic of make index key callback changes nothing
+        let index_updates = prepare_index_insert(&self.indexes, &key, &value, self.map.get(&key))?;
+        let integrity_before = integrity_before_record(self.cfg.get_mut());
+        let record = match insert_record(&key, &value, self.cfg.get_mut(), &self.last_record, self.operations_since_open)? {
+            Some(record) => record,
+            None => return Ok(Some(value)),
+        };
+        check_write(self.file_worker.write_bytes(record.data, WriteOperation::Insert), &mut self.cfg.get_mut().integrity, integrity_before)?;
+        let old_value = self.map.insert(key.clone(), value.clone());
+        self.operations_since_open += 1;
+        index_updates.into_iter().for_each(|update| update());
+        self.remember_last_record(record.payload);
+        self.notify_when_insert(&key, &value, &old_value);
+        self.after_write();
+        Ok(old_value)
+    }
+
+    /// Same as 'insert' but if count of operations not yet written by the background thread reached 'Cfg::max_pending_writes',
+    /// nothing is changed and 'TryOutcome::Pending' is returned with the serialized record, for 'retry' later or for shedding of load.
+    /// Insert is never pending if 'Cfg::max_pending_writes' is not set.
+    pub fn try_insert(&mut self, key: Key, value: Value) -> Result<TryOutcome<Key, Value>, SerializedError> {
+        let key = match &self.key_canonicalizer {
+            Some(canonicalizer) => canonicalizer(&key),
+            None => key,
+        };
+
+        if self.cfg.get_mut().skip_identical_inserts && self.is_identical(&key, &value) {
+            return Ok(TryOutcome::Done(Some(value)));
+        }
+
+        // record is made before check of the queue, integrity state is restored until the record is written
+        let integrity_before = self.cfg.get_mut().integrity.clone();
+        let record = match insert_record(&key, &value, self.cfg.get_mut(), &self.last_record, self.operations_since_open)? {
+            Some(record) => record,
+            None => return Ok(TryOutcome::Done(Some(value))),
+        };
+        let integrity_after = std::mem::replace(&mut self.cfg.get_mut().integrity, integrity_before.clone());
+
+        self.retry(RetryToken { key, value, record, integrity_before, integrity_after, operations_since_open: self.operations_since_open })
+    }
+
+    /// Write insert of 'TryOutcome::Pending' returned by 'try_insert' or 'retry', it's pending again if the queue is still full.
+    /// The serialized record is written as is if nothing was written after it was made, otherwise it's made again.
+    pub fn retry(&mut self, token: RetryToken<Key, Value>) -> Result<TryOutcome<Key, Value>, SerializedError> {
+        if token.operations_since_open != self.operations_since_open || token.integrity_before != self.cfg.get_mut().integrity {
+            return self.try_insert(token.key, token.value);
+        }
+
+        let pending_writes = self.file_worker.counters().pending_writes.load(Ordering::Acquire);
+        if matches!(self.cfg.get_mut().max_pending_writes, Some(capacity) if pending_writes >= capacity) {
+            return Ok(TryOutcome::Pending(token));
+        }
+
+        let RetryToken { key, value, record, integrity_before, integrity_after, .. } = token;
+        let index_updates = prepare_index_insert(&self.indexes, &key, &value, self.map.get(&key))?;
+        self.cfg.get_mut().integrity = integrity_after;
+        check_write(self.file_worker.write_bytes(record.data, WriteOperation::Insert), &mut self.cfg.get_mut().integrity, integrity_before)?;
+        let old_value = self.map.insert(key.clone(), value.clone());
+        self.operations_since_open += 1;
+        index_updates.into_iter().for_each(|update| update());
+        self.remember_last_record(record.payload);
+        self.notify_when_insert(&key, &value, &old_value);
+        self.after_write();
+        Ok(TryOutcome::Done(old_value))
+    }
+
+    /// Apply several inserts and removes written to the file as one record,
+    /// so after crash all of them are restored or none of them.
+    /// Operations are applied to the map in order after serialization of all of them.
+    /// Nothing is applied if the record is longer than 'Cfg::max_value_size'
+    /// or if make index key callback of any index panics, then 'SerializedError::CallbackPanicked' is returned.
+    pub fn apply_batch(&mut self, mut operations: Vec<MapOperation<Key, Value>>) -> Result<(), SerializedError> {
+        if operations.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(canonicalizer) = &self.key_canonicalizer {
+            for map_operation in operations.iter_mut() {
+                match map_operation {
+                    MapOperation::Insert(key, _) | MapOperation::Remove(key) => *key = canonicalizer(key),
                 }
-                self.file_worker.write_bytes(block);
-                self.update_index_when_insert(&key, &value, &old_value);
-                Ok(old_value)
-            },
+            }
+        }
+
+        // index keys are made before any change, so panic of make index key callback changes nothing,
+        // old value of insert is the value after previous operations of the batch
+        let mut batch_values = std::collections::BTreeMap::new();
+        let mut index_updates = Vec::with_capacity(operations.len());
+        for map_operation in operations.iter() {
+            match map_operation {
+                MapOperation::Insert(key, value) => {
+                    let old_value = batch_values.get(key).copied().unwrap_or_else(|| self.map.get(key));
+                    index_updates.push(prepare_index_insert(&self.indexes, key, value, old_value)?);
+                    batch_values.insert(key, Some(value));
+                },
+                MapOperation::Remove(key) => {
+                    index_updates.push(Vec::new());
+                    batch_values.insert(key, None);
+                },
+            }
         }
+
+        let integrity_before = integrity_before_record(self.cfg.get_mut());
+        let record = file_record_of_batch(&operations, self.cfg.get_mut())?;
+        check_write(self.file_worker.write_bytes(record, WriteOperation::Batch), &mut self.cfg.get_mut().integrity, integrity_before)?;
+        self.operations_since_open += operations.len() as u64;
+
+        for (map_operation, index_updates) in operations.into_iter().zip(index_updates) {
+            match map_operation {
+                MapOperation::Insert(key, value) => {
+                    let old_value = self.map.insert(key.clone(), value.clone());
+                    index_updates.into_iter().for_each(|update| update());
+                    self.notify_when_insert(&key, &value, &old_value);
+                },
+                MapOperation::Remove(key) => {
+                    if let Some(old_value) = self.map.remove(&key) {
+                        self.update_index_when_remove(&key, &old_value);
+                    }
+                },
+            }
+        }
+
+        self.after_write();
+        Ok(())
     }
 
     /// Returns a reference to the value corresponding to the key. Nothing writing to the file.
     pub fn get(&self, key: &Key) -> Option<&Value> {
-        self.map.get(key)
+        self.map.get(&self.canonical_key(key))
+    }
+
+    /// Returns true if the map contains value for the key. Nothing writing to the file.
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Set function making canonical form of keys, for example trimmed and lowercase string.
+    /// Keys are canonicalized by insert, remove, lookups, 'apply_batch', 'try_extend' and transactions before serialization,
+    /// so the file contains only canonical keys and indexes get canonical owner keys.
+    /// Keys loaded from the file are not canonicalized, use 'non_canonical_keys' to check old files.
+    pub fn set_key_canonicalizer(&mut self, canonicalizer: impl Fn(&Key) -> Key + Send + Sync + 'static) {
+        self.key_canonicalizer = Some(Box::new(canonicalizer));
+    }
+
+    /// Returns keys of the map that differ from their canonical form, for example loaded from file
+    /// written before setting of canonicalizer. Empty if canonicalizer is not set.
+    pub fn non_canonical_keys(&self) -> Vec<Key> {
+        match &self.key_canonicalizer {
+            Some(canonicalizer) => self.find_keys(|key, _| canonicalizer(key) != *key),
+            None => Vec::new(),
+        }
+    }
+
+    /// Remember data of the just written insert record for 'Cfg::dedupe_consecutive'.
+    fn remember_last_record(&mut self, payload: Option<Vec<u8>>) {
+        if let Some(payload) = payload {
+            self.last_record = Some(LastRecord { payload, operations_since_open: self.operations_since_open });
+        }
+    }
+
+    /// Returns canonical form of the key, the key itself if canonicalizer is not set.
+    pub(crate) fn canonical_key<'a>(&self, key: &'a Key) -> Cow<'a, Key> {
+        match &self.key_canonicalizer {
+            Some(canonicalizer) => Cow::Owned(canonicalizer(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Returns keys of all elements for which predicate returns true.
+    /// It's O(n) iteration over all elements, use indexes ('create_btree_index' and similar) for frequent queries.
+    pub fn find_keys(&self, mut pred: impl FnMut(&Key, &Value) -> bool) -> Vec<Key> {
+        let mut keys = Vec::new();
+        self.map.for_each(|key, value| {
+            if pred(key, value) {
+                keys.push(key.clone());
+            }
+        });
+        keys
+    }
+
+    /// Returns clone of the first element for which predicate returns true, iteration stops on it.
+    /// It's O(n) in the worst case, use indexes ('create_btree_index' and similar) for frequent queries.
+    pub fn find_first(&self, mut pred: impl FnMut(&Key, &Value) -> bool) -> Option<(Key, Value)> {
+        let mut found = None;
+        let _ = self.map.try_for_each(|key, value| {
+            if pred(key, value) {
+                found = Some((key.clone(), value.clone()));
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        });
+        found
     }
 
     /// Remove value by key.
     /// Insert into the map will immediately, and to disk later in a background thread.
+    /// With 'WriteMode::Sync' of config the record is written to the file before remove from the map,
+    /// if writing fails, then 'SerializedError::WriteError' is returned and nothing is changed.
     ///
     /// # Errors
     ///
@@ -128,30 +832,90 @@ where
     /// fail, or if 'Key' or 'Value' contains a map with non-string keys.
     ///
     pub fn remove(&mut self, key: &Key) -> Result<Option<Value>, SerializedError> {
-        if let Some(old_value) = self.map.remove(&key) {
-            match &mut self.cfg.format {
-                Format::Text(before_write_callback, _) => {
-                    let mut line = file_line_of_remove(key, &mut self.cfg.integrity)?;
-                    if let Some(f) = before_write_callback {
-                        f(&mut line);
-                    }
-                    self.file_worker.write_string(line);
-                    self.update_index_when_remove(key, &old_value);
-                    return Ok(Some(old_value));
-                }
-                Format::Bin(before_write_callback, _) => {
-                    let mut block = bin_file_block_of_remove(key, &mut self.cfg.integrity)?;
-                    if let Some(f) = before_write_callback {
-                        f(&mut block);
-                    }
-                    self.file_worker.write_bytes(block);
-                    self.update_index_when_remove(key, &old_value);
-                    return Ok(Some(old_value));
-                },
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        if self.map.get(key).is_none() {
+            return Ok(None);
+        }
+
+        // record is made before removing from the map, so panic of before write callback changes nothing
+        let cfg = self.cfg.get_mut();
+        let integrity_before = integrity_before_record(cfg);
+        let written = match &mut cfg.format {
+            Format::Text(before_write_callback, _) => {
+                let mut line = file_line_of_remove(key, &mut cfg.integrity)?;
+                apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+                self.file_worker.write_string(line, WriteOperation::Remove)
             }
+            Format::Bin(before_write_callback, _) => {
+                let mut block = bin_file_block_of_remove(key, &mut cfg.integrity)?;
+                apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+                self.file_worker.write_bytes(block, WriteOperation::Remove)
+            },
+        };
+        check_write(written, &mut cfg.integrity, integrity_before)?;
+        self.operations_since_open += 1;
+
+        let old_value = self.map.remove(key);
+        if let Some(old_value) = &old_value {
+            self.update_index_when_remove(key, old_value);
         }
+        self.after_write();
+        Ok(old_value)
+    }
 
-        Ok(None)
+    /// Same as 'insert' but metadata (for example author of the change) is written to the record.
+    /// Record is written even if 'skip_identical_inserts' of config is set.
+    /// Metadata is skipped by 'open_or_create' and passed to callback by 'open_or_create_with_meta'.
+    pub fn insert_with_meta<Meta: Serialize>(&mut self, key: Key, value: Value, meta: &Meta) -> Result<Option<Value>, SerializedError> {
+        let key = self.canonical_key(&key).into_owned();
+        let index_updates = prepare_index_insert(&self.indexes, &key, &value, self.map.get(&key))?;
+        let integrity_before = integrity_before_record(self.cfg.get_mut());
+        let record = file_record_of_meta_operation(MapOperation::Insert(&key, &value), meta, self.cfg.get_mut())?;
+        check_write(self.file_worker.write_bytes(record, WriteOperation::Insert), &mut self.cfg.get_mut().integrity, integrity_before)?;
+        self.operations_since_open += 1;
+
+        let old_value = self.map.insert(key.clone(), value.clone());
+        index_updates.into_iter().for_each(|update| update());
+        self.notify_when_insert(&key, &value, &old_value);
+        self.after_write();
+        Ok(old_value)
+    }
+
+    /// Same as 'remove' but metadata (for example author of the change) is written to the record.
+    /// Nothing is written to the file if the map doesn't contain the key.
+    pub fn remove_with_meta<Meta: Serialize>(&mut self, key: &Key, meta: &Meta) -> Result<Option<Value>, SerializedError> {
+        let key = self.canonical_key(key);
+        let key = key.as_ref();
+        if self.map.get(key).is_none() {
+            return Ok(None);
+        }
+
+        let integrity_before = integrity_before_record(self.cfg.get_mut());
+        let record = file_record_of_meta_operation::<Key, Value, Meta>(MapOperation::Remove(key), meta, self.cfg.get_mut())?;
+        check_write(self.file_worker.write_bytes(record, WriteOperation::Remove), &mut self.cfg.get_mut().integrity, integrity_before)?;
+        self.operations_since_open += 1;
+
+        let old_value = self.map.remove(key);
+        if let Some(old_value) = &old_value {
+            self.update_index_when_remove(key, old_value);
+        }
+        self.after_write();
+        Ok(old_value)
+    }
+
+    /// Same as 'insert' but also returns ticket of the write for 'wait_for'.
+    /// If nothing is written by 'skip_identical_inserts', ticket of the previous write is returned.
+    pub fn insert_with_ticket(&mut self, key: Key, value: Value) -> Result<(Option<Value>, WriteTicket), SerializedError> {
+        let old_value = self.insert(key, value)?;
+        Ok((old_value, self.last_write_ticket()))
+    }
+
+    /// Same as 'remove' but also returns ticket of the write for 'wait_for'.
+    /// If nothing is written because the map doesn't contain the key, ticket of the previous write is returned.
+    pub fn remove_with_ticket(&mut self, key: &Key) -> Result<(Option<Value>, WriteTicket), SerializedError> {
+        let old_value = self.remove(key)?;
+        Ok((old_value, self.last_write_ticket()))
     }
 
     /// Create index by value based on std::collections::BTreeMap.
@@ -159,9 +923,9 @@ where
     /// Inside into callback necessary to determine the value and type of the index key
     /// in any way related to the value of the map.
     pub fn create_btree_index<IndexKey>(&mut self, make_index_key_callback: fn(&Value) -> IndexKey)
-        -> Index<IndexKey, Key, Value, std::collections::BTreeMap<IndexKey, BTreeSet<Key>>>
-    where IndexKey: Clone + Ord + 'static {
-        self.create_index::<IndexKey, std::collections::BTreeMap<IndexKey, BTreeSet<Key>>>(make_index_key_callback)
+        -> Index<IndexKey, Key, Value, std::collections::BTreeMap<IndexKey, Arc<BTreeSet<Key>>>>
+    where IndexKey: Clone + Ord + Send + Sync + 'static, Key: Send + Sync {
+        self.create_index::<IndexKey, std::collections::BTreeMap<IndexKey, Arc<BTreeSet<Key>>>>(make_index_key_callback)
     }
 
     /// Create index by value based on std::collections::HashMap.
@@ -169,9 +933,9 @@ where
     /// Inside into callback necessary to determine the value and type of the index key
     /// in any way related to the value of the map.
     pub fn create_hashmap_index<IndexKey>(&mut self, make_index_key_callback: fn(&Value) -> IndexKey)
-        -> Index<IndexKey, Key, Value, std::collections::HashMap<IndexKey, BTreeSet<Key>>>
-    where IndexKey: Clone + Hash + Eq + 'static {
-        self.create_index::<IndexKey, std::collections::HashMap<IndexKey, BTreeSet<Key>>>(make_index_key_callback)
+        -> Index<IndexKey, Key, Value, std::collections::HashMap<IndexKey, Arc<BTreeSet<Key>>>>
+    where IndexKey: Clone + Hash + Eq + Send + Sync + 'static, Key: Send + Sync {
+        self.create_index::<IndexKey, std::collections::HashMap<IndexKey, Arc<BTreeSet<Key>>>>(make_index_key_callback)
     }
 
     /// Create index by value.
@@ -182,25 +946,21 @@ where
         -> Index<IndexKey, Key, Value, MapOfIndex>
     where
         IndexKey: Clone + Eq + 'static,
-        MapOfIndex: MapTrait<IndexKey, BTreeSet<Key>> + Default + Sized + 'static,
+        MapOfIndex: MapTrait<IndexKey, Arc<BTreeSet<Key>>> + Default + Sized + Send + Sync + 'static,
+        Key: Send + Sync,
     {
-        let mut index_map = MapOfIndex::default();
-
-        self.map.for_each(|key, val| {
-            let index_key = make_index_key_callback(&val);
-            match index_map.get_mut(&index_key) {
-                Some(keys) => {
-                    keys.insert(key.clone());
-                }
-                None => {
-                    let mut set = BTreeSet::new();
-                    set.insert(key.clone());
-                    index_map.insert(index_key, set);
-                }
-            }
-        });
+        self.create_index_with(MakeIndexKey::ByValue(make_index_key_callback))
+    }
 
-        let index = Index::new(index_map, make_index_key_callback);
+    /// Create index with index key made from the key or from the value.
+    pub(crate) fn create_index_with<IndexKey, MapOfIndex>(&mut self, make_index_key: MakeIndexKey<Key, Value, IndexKey>)
+        -> Index<IndexKey, Key, Value, MapOfIndex>
+    where
+        IndexKey: Clone + Eq + 'static,
+        MapOfIndex: MapTrait<IndexKey, Arc<BTreeSet<Key>>> + Default + Sized + Send + Sync + 'static,
+        Key: Send + Sync,
+    {
+        let index = Index::from_owner_map(&self.map, make_index_key);
         self.indexes.push(Box::new(index.clone()));
 
         index
@@ -211,20 +971,371 @@ where
         &self.map
     }
 
-    /// Update a indexes when inserting into the map.
-    fn update_index_when_insert(&self, key: &Key, value: &Value, old_value: &Option<Value>) {
+    /// Waits until all changes made before are written to the file.
+    /// Returns the first error of writing since the previous flush, the error is passed to the write error callback too.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.flush_request().recv()
+            .unwrap_or_else(|_| Err(worker_panicked_error())) // result is not received only if worker thread panicked
+    }
+
+    /// Returns ticket of the last write to the file made by any writing method, for 'wait_for'.
+    pub fn last_write_ticket(&self) -> WriteTicket {
+        WriteTicket { sequence: self.file_worker.last_write_sequence() }
+    }
+
+    /// Waits until the write of the ticket is written to the file and synced by 'Cfg::fsync_policy',
+    /// without waiting of writes made after it. Returns immediately if it's already written.
+    /// Returns error of the write if it failed, the error is passed to the write error callback and returned by 'flush' too.
+    /// Errors of only the last 1024 failed writes are kept, waiting of older failed write returns Ok.
+    /// Returns error if the background thread panicked before the write.
+    pub fn wait_for(&self, ticket: WriteTicket) -> Result<(), std::io::Error> {
+        self.file_worker.counters().wait_for_write(ticket.sequence)
+    }
+
+    /// Request to flush without waiting, result of flush will be sent to the returned receiver.
+    pub(crate) fn flush_request(&self) -> Receiver<std::io::Result<()>> {
+        self.file_worker.flush()
+    }
+
+    /// Writes all changes, flushes and syncs the file to disk and unlocks it, the same as drop but with result.
+    /// Returns the first error of writing since the previous flush or error of flush or sync,
+    /// the error is passed to the write error callback too.
+    pub fn close(self) -> Result<(), std::io::Error> {
+        let MapWithFile { file_worker, .. } = self;
+        file_worker.close()
+    }
+
+    /// Returns count of operations sent to the background thread and not yet written to the file,
+    /// writing methods wait when it reaches 'Cfg::max_pending_writes'. Always 0 with 'WriteMode::Sync'.
+    pub fn pending_writes(&self) -> usize {
+        self.file_worker.counters().pending_writes.load(Ordering::Relaxed)
+    }
+
+    /// Takes the last error of writing to the file, flush or sync, so health of writing can be checked without callbacks.
+    /// Returns None if there were no errors since the previous call. Errors are still passed to the write error callback
+    /// and returned by 'flush'.
+    pub fn take_last_write_error(&self) -> Option<std::io::Error> {
+        self.file_worker.counters().take_last_write_error()
+    }
+
+    /// Returns count of operations in the file: loaded when opening and written after opening or after the last compaction.
+    /// Operations of batches and transactions are counted separately.
+    pub fn ops_logged(&self) -> u64 {
+        self.records_loaded + self.operations_since_open
+    }
+
+    /// Returns count of entries of the map, operations of the file except them are dead, see 'Cfg::auto_compact'.
+    pub fn live_len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns snapshot of counters of the map and of the background writing to the file.
+    pub fn metrics(&self) -> Metrics {
+        let entries = self.map.len();
+        let counters = self.file_worker.counters();
+        let records_in_file = self.ops_logged();
+        Metrics {
+            entries,
+            operations_since_open: self.operations_since_open,
+            dead_operation_estimate: records_in_file.saturating_sub(entries as u64),
+            pending_writes: counters.pending_writes.load(Ordering::Acquire),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+            write_errors: counters.write_errors.load(Ordering::Relaxed),
+            last_flush_at: *counters.last_flush_at.lock()
+                .unwrap_or_else(|err| unreachable!(err)), // unreachable because no code with possible panic under this lock
+            index_count: self.indexes.len(),
+        }
+    }
+
+    /// Returns counters of records of the file: loaded when opening with count of dead of them, written after opening and length of the file.
+    /// Counts are from the last compaction or checkpoint if it was. Length of the file is updated by the background thread,
+    /// so records not yet written are not counted in it.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            records_loaded: self.records_loaded,
+            dead_records_loaded: self.dead_records_loaded,
+            operations_since_open: self.operations_since_open,
+            file_len: self.file_worker.counters().file_len.load(Ordering::Acquire),
+        }
+    }
+
+    /// Returns records impossible for the map found when opening if 'Cfg::collect_replay_anomalies' is set.
+    pub fn replay_anomalies(&self) -> &[ReplayAnomaly] {
+        &self.replay_anomalies
+    }
+
+    /// Returns not fatal problems found when opening, for example unclean shutdown detected by 'Cfg::dirty_marker'.
+    /// Empty if the map is opened by 'open_or_create_with_report', warnings are in the report in this case.
+    pub fn open_warnings(&mut self) -> &[OpenWarning] {
+        self.open_warnings.get_mut()
+            .unwrap_or_else(|err| err.into_inner()) // never poisoned, warnings are not changed after opening
+    }
+
+    /// Returns sizes of records of the file counted when opening, records written after opening are not counted.
+    pub fn load_stats(&self) -> &LoadStats {
+        &self.load_stats
+    }
+
+    /// Returns count of bytes written to the file after opening, records not yet written by
+    /// the background thread are not counted, so call 'flush' before for exact value.
+    pub fn bytes_written_since_open(&self) -> u64 {
+        self.file_worker.counters().bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Returns head of chained integrity after the last record, None without chained integrity.
+    /// Records may be not yet written by the background thread, so call 'flush' before persisting the head.
+    pub fn integrity_head(&self) -> Option<ChainAnchor> {
+        self.cfg.lock().integrity.as_ref().and_then(Integrity::anchor)
+    }
+
+    /// Returns integrity of the map with hash of the chain after the last record, for the config of a new file
+    /// continuing the chain of this one, for example after archiving of this file. None without integrity.
+    /// Hash is advanced when a record is serialized, so pending writes are flushed before and the first error
+    /// of writing since the previous flush is returned, then the hash can be of the record not written to the file.
+    pub fn current_integrity_state(&self) -> Result<Option<Integrity>, std::io::Error> {
+        self.flush()?;
+        Ok(self.cfg.lock().integrity.clone())
+    }
+
+    /// Replace the file by finished background compaction, then compact and checkpoint if it's time by config.
+    /// Called after each change written to the file.
+    pub(crate) fn after_write(&mut self) {
+        self.poll_compaction();
+        self.compact_if_due();
+        self.checkpoint_if_due();
+    }
+
+    /// Update a indexes and notify subscribers when inserting into the map.
+    pub(crate) fn update_index_when_insert(&self, key: &Key, value: &Value, old_value: &Option<Value>) {
         // update in index
         for index in self.indexes.iter() {
             index.on_insert(key.clone(), value.clone(), old_value.clone());
         }
+
+        self.notify_when_insert(key, value, old_value);
+    }
+
+    /// Notify mirrors, snapshot publisher and subscribers when inserting into the map, indexes are already updated.
+    pub(crate) fn notify_when_insert(&self, key: &Key, value: &Value, old_value: &Option<Value>) {
+        self.lock_mirrors().on_insert(key, value, old_value.as_ref());
+
+        #[cfg(feature = "lock_free_reader")]
+        if let Some(publisher) = self.snapshot_publisher.get() {
+            publisher.on_insert(key, value);
+        }
+
+        self.lock_subscribers().notify(|| ChangeEvent::Inserted { key: key.clone(), new: value.clone(), old: old_value.clone() });
     }
 
-    /// Update a indexes when removing from the map.
-    fn update_index_when_remove(&self, key: &Key, old_value: &Value) {
+    /// Update a indexes and notify subscribers when removing from the map.
+    pub(crate) fn update_index_when_remove(&self, key: &Key, old_value: &Value) {
         // remove from indexes
         for index in self.indexes.iter() {
             index.on_remove(&key, &old_value);
         }
+
+        self.lock_mirrors().on_remove(key, old_value);
+
+        #[cfg(feature = "lock_free_reader")]
+        if let Some(publisher) = self.snapshot_publisher.get() {
+            publisher.on_remove(key);
+        }
+
+        self.lock_subscribers().notify(|| ChangeEvent::Removed { key: key.clone(), old: old_value.clone() });
+    }
+
+    /// Returns true if indexes, mirrors or subscribers need old value on changes.
+    pub(crate) fn has_observers(&self) -> bool {
+        !self.indexes.is_empty() || !self.lock_mirrors().is_empty() || !self.lock_subscribers().is_empty()
+    }
+
+    /// Lock mirrors of changes.
+    fn lock_mirrors(&self) -> std::sync::MutexGuard<'_, Mirrors<Key, Value>> {
+        self.mirrors.lock()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in mirror, mirrors are still usable in this case
+    }
+
+    /// Lock subscribers of changes.
+    fn lock_subscribers(&self) -> std::sync::MutexGuard<'_, Subscribers<Key, Value>> {
+        self.subscribers.lock()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in subscriber callback, subscribers are still usable in this case
+    }
+}
+
+/// Collect key-value pairs from iterator into the new file based map, same as 'MapWithFile::from_iter_new'.
+pub fn collect_into_new<Key, Value, Map, Iter>(file_path: impl AsRef<Path>, cfg: Cfg, iter: Iter) -> Result<MapWithFile<Key, Value, Map>, CreateError>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone + 'static,
+    Map: MapTrait<Key, Value> + Default,
+    Iter: IntoIterator<Item = (Key, Value)>,
+{
+    MapWithFile::from_iter_new(file_path, cfg, iter)
+}
+
+impl<Key, Value> From<&MapWithFile<Key, Value, std::collections::BTreeMap<Key, Value>>> for std::collections::BTreeMap<Key, Value>
+where Key: Ord + Clone, Value: Clone {
+    fn from(map_with_file: &MapWithFile<Key, Value, std::collections::BTreeMap<Key, Value>>) -> Self {
+        map_with_file.map.clone()
+    }
+}
+
+impl<Key, Value> From<&MapWithFile<Key, Value, std::collections::HashMap<Key, Value>>> for std::collections::HashMap<Key, Value>
+where Key: Hash + Eq + Clone, Value: Clone {
+    fn from(map_with_file: &MapWithFile<Key, Value, std::collections::HashMap<Key, Value>>) -> Self {
+        map_with_file.map.clone()
+    }
+}
+
+impl<Key, Value> TryFrom<(PathBuf, Cfg, std::collections::BTreeMap<Key, Value>)> for MapWithFile<Key, Value, std::collections::BTreeMap<Key, Value>>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone + 'static,
+{
+    type Error = CreateError;
+
+    /// Same as 'create_from_map'.
+    fn try_from((file_path, cfg, map): (PathBuf, Cfg, std::collections::BTreeMap<Key, Value>)) -> Result<Self, Self::Error> {
+        Self::create_from_map(file_path, cfg, map)
+    }
+}
+
+impl<Key, Value> TryFrom<(PathBuf, Cfg, std::collections::HashMap<Key, Value>)> for MapWithFile<Key, Value, std::collections::HashMap<Key, Value>>
+where
+    Key: Serialize + DeserializeOwned + Ord + Hash + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone + 'static,
+{
+    type Error = CreateError;
+
+    /// Same as 'create_from_map'.
+    fn try_from((file_path, cfg, map): (PathBuf, Cfg, std::collections::HashMap<Key, Value>)) -> Result<Self, Self::Error> {
+        Self::create_from_map(file_path, cfg, map)
+    }
+}
+
+/// Make index keys of insert into all indexes before the map is changed.
+/// Panic of make index key callback is 'SerializedError::CallbackPanicked', indexes are not changed in this case.
+pub(crate) fn prepare_index_insert<'a, Key, Value>(indexes: &'a [Box<dyn UpdateIndex<Key, Value> + Send + Sync>], key: &Key, value: &Value, old_value: Option<&Value>)
+    -> Result<Vec<IndexUpdate<'a>>, SerializedError> {
+    indexes.iter()
+        .map(|index| index.prepare_insert(key, value, old_value).ok_or(SerializedError::CallbackPanicked))
+        .collect()
+}
+
+/// Serialized insert record.
+struct InsertRecord {
+    /// Record with integrity.
+    data: Vec<u8>,
+    /// Record without integrity if 'Cfg::dedupe_consecutive' is set.
+    payload: Option<Vec<u8>>,
+}
+
+/// Make insert record changing integrity state of 'cfg'.
+/// Returns None if 'Cfg::dedupe_consecutive' is set and the record repeats the last record.
+fn insert_record<Key, Value>(key: &Key, value: &Value, cfg: &mut Cfg, last_record: &Option<LastRecord>, operations_since_open: u64) -> Result<Option<InsertRecord>, SerializedError>
+where
+    Key: Serialize,
+    Value: Serialize,
+{
+    let integrity_before = integrity_before_record(cfg);
+    match &mut cfg.format {
+        Format::Text(before_write_callback, _) => {
+            let mut line = text_line_data_of_insert(key, value, cfg.value_schema_version, cfg.compress_values_over)?;
+            if cfg.dedupe_consecutive && matches!(last_record, Some(last) if last.is_repeated(line.as_bytes(), operations_since_open)) {
+                return Ok(None);
+            }
+            let payload = if cfg.dedupe_consecutive { Some(line.as_bytes().to_vec()) } else { None };
+            post_process_text_file_line(&mut line, &mut cfg.integrity);
+            apply_before_write(before_write_callback, &mut line, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(line.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(Some(InsertRecord { data: line.into_bytes(), payload }))
+        },
+        Format::Bin(before_write_callback, _) => {
+            let data = bin_block_data_of_insert(key, value, cfg.value_schema_version, cfg.compress_values_over)?;
+            if cfg.dedupe_consecutive && matches!(last_record, Some(last) if last.is_repeated(&data, operations_since_open)) {
+                return Ok(None);
+            }
+            let payload = if cfg.dedupe_consecutive { Some(data.clone()) } else { None };
+            let mut block = finish_bin_block(data, &mut cfg.integrity);
+            apply_before_write(before_write_callback, &mut block, &mut cfg.integrity, &integrity_before)?;
+            check_record_size(block.len(), cfg.max_value_size, &mut cfg.integrity, integrity_before)?;
+            Ok(Some(InsertRecord { data: block, payload }))
+        },
+    }
+}
+
+/// Result of 'MapWithFile::try_insert' and 'MapWithFile::retry'.
+pub enum TryOutcome<Key, Value> {
+    /// Insert is done, replaced value if it was.
+    Done(Option<Value>),
+    /// Queue of writing is full, nothing is changed.
+    Pending(RetryToken<Key, Value>),
+}
+
+/// Serialized insert not written because queue of writing was full, for 'MapWithFile::retry'.
+pub struct RetryToken<Key, Value> {
+    key: Key,
+    value: Value,
+    /// Serialized record.
+    record: InsertRecord,
+    /// Integrity state before the record.
+    integrity_before: Option<Integrity>,
+    /// Integrity state after the record.
+    integrity_after: Option<Integrity>,
+    /// Count of written operations when the record was made, the record is valid only if nothing is written after.
+    operations_since_open: u64,
+}
+
+impl<Key, Value> RetryToken<Key, Value> {
+    /// Key of the insert.
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Value of the insert.
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Key and value of the insert, for shedding of load.
+    pub fn into_inner(self) -> (Key, Value) {
+        (self.key, self.value)
+    }
+}
+
+/// Ticket of write to the file returned by 'MapWithFile::insert_with_ticket' and 'MapWithFile::last_write_ticket',
+/// for waiting of the write by 'MapWithFile::wait_for'. Tickets of later writes are greater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WriteTicket {
+    sequence: u64,
+}
+
+impl WriteTicket {
+    /// Number of the write since opening of the map as 'WriteAck::sequence', 0 if nothing was written.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// Error of creating file based map with the new file.
+#[derive(Debug)]
+pub enum CreateError {
+    /// Error of opening or loading the file.
+    LoadFileError(LoadFileError),
+    /// File already contains records.
+    FileNotEmpty,
+    /// Path is not valid unicode, it's not returned because paths are not required to be unicode.
+    #[deprecated(note = "paths are not required to be valid unicode, so it's not returned")]
+    WrongPath,
+    /// Error of serialization of key or value.
+    SerializeError(SerializedError),
+}
+
+impl std::error::Error for CreateError {}
+
+impl std::fmt::Display for CreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
     }
 }
 
@@ -235,6 +1346,14 @@ pub enum SerializedError {
     Json(serde_json::Error),
     /// Error of data serialization if text binary used.
     Bincode(bincode2::Error),
+    /// Size of record is greater than 'Cfg::max_value_size', nothing is changed.
+    ValueTooLarge { size: usize, limit: usize },
+    /// Before write callback of the format or make index key callback panicked, nothing is changed.
+    CallbackPanicked,
+    /// Writing to the file failed with 'WriteMode::Sync' or the background thread of writing panicked, nothing is changed.
+    WriteError(std::io::Error),
+    /// The map is opened by 'MapWithFile::open_read_only', nothing is changed.
+    ReadOnly,
 }
 
 impl From<serde_json::Error> for SerializedError {
@@ -249,10 +1368,31 @@ impl From<bincode2::Error> for SerializedError {
     }
 }
 
+impl SerializedError {
+    /// Error of writing to the file, 'ReadOnly' if the map is opened read-only.
+    pub(crate) fn of_write_error(err: std::io::Error) -> Self {
+        if is_read_only_error(&err) {
+            SerializedError::ReadOnly
+        } else {
+            SerializedError::WriteError(err)
+        }
+    }
+
+    /// Returns true if it's error of writing of map with non-string keys to JSON in the text format.
+    /// Such values can be stored in the text format with 'JsonCompat' wrapper or in the binary format.
+    pub fn is_non_string_map_key(&self) -> bool {
+        matches!(self, SerializedError::Json(err) if err.to_string().starts_with("key must be a string"))
+    }
+}
+
 impl std::error::Error for SerializedError {}
 
 impl std::fmt::Display for SerializedError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        if self.is_non_string_map_key() {
+            write!(f, "{:?}, value contains map with non-string keys that can't be JSON object, wrap it with 'JsonCompat' or use the binary format", self)
+        } else {
+            write!(f, "{:?}", self)
+        }
     }
 }
\ No newline at end of file
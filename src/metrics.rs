@@ -0,0 +1,40 @@
+use std::time::SystemTime;
+
+/// Snapshot of counters of the map and of the background writing to the file.
+/// Plain data for exporting to any monitoring system.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Metrics {
+    /// Count of elements in the map.
+    pub entries: usize,
+    /// Count of operations written to the file after opening.
+    pub operations_since_open: u64,
+    /// Estimated count of records in the file that don't affect the current state of the map,
+    /// such as overwritten inserts and removes.
+    pub dead_operation_estimate: u64,
+    /// Count of operations sent to the background thread and not yet written to the file.
+    pub pending_writes: usize,
+    /// Count of bytes written to the file after opening.
+    pub bytes_written: u64,
+    /// Count of errors of writing to the file after opening.
+    pub write_errors: u64,
+    /// Time when all pending operations were last written to the file.
+    /// None if nothing written after opening.
+    pub last_flush_at: Option<SystemTime>,
+    /// Count of created indexes.
+    pub index_count: usize,
+}
+
+/// Counters of records of the file, see 'MapWithFile::stats'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Count of operations loaded from the file when opening, or written by the last compaction or checkpoint.
+    pub records_loaded: u64,
+    /// Count of loaded operations that don't affect the map after loading: removes and overwritten inserts.
+    pub dead_records_loaded: u64,
+    /// Count of operations written to the file after opening or after the last compaction or checkpoint.
+    pub operations_since_open: u64,
+    /// Length of the file after the last write of the background thread.
+    pub file_len: u64,
+}
@@ -0,0 +1,78 @@
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+
+/// Receiver of all changes of the map for keeping derived structure in sync with the map.
+/// Methods are called synchronously at the same points as updates of indexes,
+/// after change of the map and only for operations written to the file.
+/// Unlike indexes and subscribers, data is passed borrowed, so nothing is cloned for mirrors.
+pub trait MirrorSink<Key, Value> {
+    /// Called when value is inserted, 'old_value' is replaced value if it was.
+    fn on_insert(&mut self, key: &Key, value: &Value, old_value: Option<&Value>);
+    /// Called when value is removed.
+    fn on_remove(&mut self, key: &Key, old_value: &Value);
+}
+
+/// Identifier of the mirror for 'MapWithFile::detach_mirror'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MirrorId(u64);
+
+impl<Key, Value, Map> MapWithFile<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Attach mirror receiving all next changes of the map.
+    /// Current content of the map is not passed, so mirror should be filled from 'map' before if needed.
+    pub fn attach_mirror(&mut self, mirror: Box<dyn MirrorSink<Key, Value> + Send>) -> MirrorId {
+        let mirrors = self.mirrors_mut();
+        let id = MirrorId(mirrors.next_id);
+        mirrors.next_id += 1;
+        mirrors.sinks.push((id, mirror));
+        id
+    }
+
+    /// Detach mirror and return it. Returns None if there is no mirror with this id.
+    pub fn detach_mirror(&mut self, id: MirrorId) -> Option<Box<dyn MirrorSink<Key, Value> + Send>> {
+        let sinks = &mut self.mirrors_mut().sinks;
+        let pos = sinks.iter().position(|(mirror_id, _)| *mirror_id == id)?;
+        Some(sinks.remove(pos).1)
+    }
+
+    /// Mirrors without locking because of exclusive access.
+    fn mirrors_mut(&mut self) -> &mut Mirrors<Key, Value> {
+        self.mirrors.get_mut()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in mirror, mirrors are still usable in this case
+    }
+}
+
+/// Attached mirrors of the map.
+pub(crate) struct Mirrors<Key, Value> {
+    /// Id of the next mirror.
+    next_id: u64,
+    /// Mirrors from 'attach_mirror'.
+    sinks: Vec<(MirrorId, Box<dyn MirrorSink<Key, Value> + Send>)>,
+}
+
+impl<Key, Value> Mirrors<Key, Value> {
+    /// Pass insert to all mirrors.
+    pub fn on_insert(&mut self, key: &Key, value: &Value, old_value: Option<&Value>) {
+        for (_, mirror) in self.sinks.iter_mut() {
+            mirror.on_insert(key, value, old_value);
+        }
+    }
+
+    /// Pass remove to all mirrors.
+    pub fn on_remove(&mut self, key: &Key, old_value: &Value) {
+        for (_, mirror) in self.sinks.iter_mut() {
+            mirror.on_remove(key, old_value);
+        }
+    }
+
+    /// Returns true if there are no mirrors.
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl<Key, Value> Default for Mirrors<Key, Value> {
+    fn default() -> Self {
+        Mirrors { next_id: 0, sinks: Vec::new() }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::cfg::{Cfg, WriteOperation};
+use crate::format::{check_write, file_record_of_item_operation, integrity_before_record, ItemOperation, LoadedOperation, MapOperation, RawMeta, SetOperation};
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// File based map with collection of items as value.
+/// Push and remove of one item are written to the file as small records with the key and the item
+/// ("psh" and "rmi" lines of text format, codes 7 and 8 of binary format) instead of insert of the whole collection,
+/// these records are applied to the collection in place when loading.
+/// Key without items is removed from the map.
+pub struct MultiMap<Key, Item>
+where Key: Ord {
+    /// Wrapped map with collections of items.
+    inner: MapWithFile<Key, Vec<Item>, BTreeMap<Key, Vec<Item>>>,
+}
+
+impl<Key, Item> MultiMap<Key, Item>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Item: Serialize + DeserializeOwned + Clone + PartialEq + 'static {
+
+    /// Open/create file and loads the entire history of changes, same as 'MapWithFile::open_or_create'.
+    /// File can contain insert and remove records of the whole collections too.
+    pub fn open_or_create(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let inner = MapWithFile::open_with(file_path.as_ref(), cfg, |map: &mut BTreeMap<Key, Vec<Item>>, operation| {
+            match operation {
+                MultiMapOperation::Map(MapOperation::Insert(key, items)) => { map.insert(key, items); },
+                MultiMapOperation::Map(MapOperation::Remove(key)) => { map.remove(&key); },
+                MultiMapOperation::Items(ItemOperation::Push, key, items) => map.entry(key).or_default().extend(items),
+                MultiMapOperation::Items(ItemOperation::Remove, key, items) => {
+                    if let Some(collection) = map.get_mut(&key) {
+                        for item in items.iter() {
+                            remove_first_equal(collection, item);
+                        }
+                        if collection.is_empty() {
+                            map.remove(&key);
+                        }
+                    }
+                },
+            }
+        })?;
+
+        Ok(MultiMap { inner })
+    }
+
+    /// Push item to the end of collection of the key.
+    pub fn push(&mut self, key: Key, item: Item) -> Result<(), SerializedError> {
+        let integrity_before = integrity_before_record(self.inner.cfg.get_mut());
+        let record = file_record_of_item_operation(ItemOperation::Push, &key, std::slice::from_ref(&item), self.inner.cfg.get_mut())?;
+        check_write(self.inner.file_worker.write_bytes(record, WriteOperation::PushItem), &mut self.inner.cfg.get_mut().integrity, integrity_before)?;
+        self.inner.operations_since_open += 1;
+
+        let old_items = if self.inner.has_observers() { self.inner.map.get(&key).cloned() } else { None };
+        match self.inner.map.get_mut(&key) {
+            Some(items) => items.push(item),
+            None => { self.inner.map.insert(key.clone(), vec![item]); },
+        }
+
+        if let Some(items) = self.inner.map.get(&key) {
+            self.inner.update_index_when_insert(&key, items, &old_items);
+        }
+
+        Ok(())
+    }
+
+    /// Remove first item equal to 'item' from collection of the key.
+    /// Returns false and nothing is written to the file if there is no such item.
+    pub fn remove_item(&mut self, key: &Key, item: &Item) -> Result<bool, SerializedError> {
+        match self.inner.map.get(key) {
+            Some(items) if items.contains(item) => {},
+            _ => return Ok(false),
+        }
+
+        let integrity_before = integrity_before_record(self.inner.cfg.get_mut());
+        let record = file_record_of_item_operation(ItemOperation::Remove, key, std::slice::from_ref(item), self.inner.cfg.get_mut())?;
+        check_write(self.inner.file_worker.write_bytes(record, WriteOperation::RemoveItem), &mut self.inner.cfg.get_mut().integrity, integrity_before)?;
+        self.inner.operations_since_open += 1;
+
+        let old_items = if self.inner.has_observers() { self.inner.map.get(key).cloned() } else { None };
+        let items = self.inner.map.get_mut(key)
+            .unwrap_or_else(|| unreachable!()); // unreachable because the key is checked above
+        remove_first_equal(items, item);
+
+        if items.is_empty() {
+            self.inner.map.remove(key);
+            // collection contained only removed item
+            self.inner.update_index_when_remove(key, &vec![item.clone()]);
+        } else if let Some(items) = self.inner.map.get(key) {
+            self.inner.update_index_when_insert(key, items, &old_items);
+        }
+
+        Ok(true)
+    }
+
+    /// Returns items of the key, empty slice if there are no items.
+    pub fn items(&self, key: &Key) -> &[Item] {
+        self.inner.get(key).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Returns reference to the wrapped file based map.
+    pub fn inner(&self) -> &MapWithFile<Key, Vec<Item>, BTreeMap<Key, Vec<Item>>> {
+        &self.inner
+    }
+
+    /// Returns mutable reference to the wrapped file based map for insert or remove of whole collections and creating indexes.
+    pub fn inner_mut(&mut self) -> &mut MapWithFile<Key, Vec<Item>, BTreeMap<Key, Vec<Item>>> {
+        &mut self.inner
+    }
+
+    /// Waits until all changes made before are written to the file.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Operation loaded from the file of 'MultiMap'.
+enum MultiMapOperation<Key, Item> {
+    /// Insert or remove of whole collection.
+    Map(MapOperation<Key, Vec<Item>>),
+    /// Push or remove of items.
+    Items(ItemOperation, Key, Vec<Item>),
+}
+
+impl<Key, Item> LoadedOperation<Key, Vec<Item>> for MultiMapOperation<Key, Item> {
+    fn from_map_operation(map_operation: MapOperation<Key, Vec<Item>>) -> Self { MultiMapOperation::Map(map_operation) }
+    fn from_item_operation(item_operation: ItemOperation, key: Key, items: Vec<Item>) -> Option<Self> { Some(MultiMapOperation::Items(item_operation, key, items)) }
+    fn from_set_operation(_: SetOperation, _: Key) -> Option<Self> { None }
+    fn from_increment(_: Key, _: i64) -> Option<Self> { None }
+    fn with_meta(self, _: RawMeta<'_>, _: usize) -> Result<Self, LoadFileError> { Ok(self) }
+}
+
+/// Remove first item equal to 'item' from collection.
+fn remove_first_equal<Item: PartialEq>(items: &mut Vec<Item>, item: &Item) {
+    if let Some(index) = items.iter().position(|it| it == item) {
+        items.remove(index);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::cfg::Cfg;
+use crate::map_with_file::BTreeMap;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Opened map with path of its file.
+pub type OpenedMap<Key, Value> = (PathBuf, BTreeMap<Key, Value>);
+
+/// Maps opened by 'open_all_with' and files that failed to open.
+pub struct OpenAllReport<Key: Ord, Value> {
+    /// Opened maps with paths of files in order of paths.
+    pub opened: Vec<OpenedMap<Key, Value>>,
+    /// Errors of files that failed to open in order of paths.
+    pub failed: Vec<(PathBuf, LoadFileError)>,
+}
+
+/// Error of 'open_all' and 'open_all_with'.
+#[derive(Debug)]
+pub enum OpenAllError {
+    /// When can't read the directory.
+    ReadDirError(std::io::Error),
+    /// Path of the file is not valid UTF-8, it's not returned because paths are not required to be UTF-8.
+    #[deprecated(note = "paths are not required to be valid UTF-8, so it's not returned")]
+    NonUtf8Path(PathBuf),
+    /// Errors of files that failed to open by 'open_all', maps of other files are dropped.
+    OpenFilesError(Vec<(PathBuf, LoadFileError)>),
+}
+
+impl std::error::Error for OpenAllError {}
+
+impl std::fmt::Display for OpenAllError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Open all files of the directory as maps on threads by count of available parallelism.
+/// 'cfg_for' is called for each file and returns config of the map or None for skip the file.
+/// Each map has own background thread writing to the file as opened by 'open_or_create'.
+/// Returns error with all errors of files if any file failed to open, use 'open_all_with' for partial success.
+pub fn open_all<Key, Value>(dir: &Path, cfg_for: impl Fn(&Path) -> Option<Cfg> + Sync) -> Result<Vec<OpenedMap<Key, Value>>, OpenAllError>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + Send + Sync + 'static,
+    Value: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    let threads = std::thread::available_parallelism().map(usize::from).unwrap_or(1);
+    let report = open_all_with(dir, threads, cfg_for)?;
+    if !report.failed.is_empty() {
+        return Err(OpenAllError::OpenFilesError(report.failed));
+    }
+
+    Ok(report.opened)
+}
+
+/// Same as 'open_all' but on 'threads' threads (at least one) and returns opened maps
+/// together with errors of files that failed to open.
+/// Subdirectories are not opened.
+pub fn open_all_with<Key, Value>(dir: &Path, threads: usize, cfg_for: impl Fn(&Path) -> Option<Cfg> + Sync) -> Result<OpenAllReport<Key, Value>, OpenAllError>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + Send + Sync + 'static,
+    Value: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("open_all", dir = %dir.display(), threads).entered();
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(OpenAllError::ReadDirError)? {
+        let entry = entry.map_err(OpenAllError::ReadDirError)?;
+        if !entry.file_type().map_err(OpenAllError::ReadDirError)?.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        if let Some(cfg) = cfg_for(&path) {
+            files.push((path, cfg));
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // threads take files by index, results are kept in order of files
+    let results = Mutex::new(files.iter().map(|_| None).collect::<Vec<_>>());
+    let files: Vec<Mutex<Option<(PathBuf, Cfg)>>> = files.into_iter().map(|file| Mutex::new(Some(file))).collect();
+    let next_file = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..threads.max(1).min(files.len()) {
+            scope.spawn(|| loop {
+                let index = next_file.fetch_add(1, Ordering::Relaxed);
+                let file = match files.get(index) {
+                    Some(file) => file.lock().unwrap_or_else(|err| err.into_inner()).take(),
+                    None => break,
+                };
+                if let Some((path, cfg)) = file {
+                    let result = BTreeMap::open_or_create(&path, cfg);
+                    results.lock().unwrap_or_else(|err| err.into_inner())[index] = Some((path, result));
+                }
+            });
+        }
+    });
+
+    let mut report = OpenAllReport { opened: Vec::new(), failed: Vec::new() };
+    for (path, result) in results.into_inner().unwrap_or_else(|err| err.into_inner()).into_iter().flatten() {
+        match result {
+            Ok(map) => report.opened.push((path, map)),
+            Err(err) => report.failed.push((path, err)),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(opened = report.opened.len(), failed = report.failed.len(), "directory of maps opened");
+
+    Ok(report)
+}
@@ -0,0 +1,41 @@
+use std::time::{Duration, SystemTime};
+use crate::format::LoadStats;
+use crate::LoadFileError;
+
+/// Report of opening returned by 'MapWithFile::open_or_create_with_report'.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct OpenReport {
+    /// Not fatal problems found when opening.
+    pub warnings: Vec<OpenWarning>,
+    /// Duration of opening with loading of the file.
+    pub load_duration: Duration,
+    /// Count of insert and remove records applied to the map.
+    pub records_loaded: u64,
+    /// Sizes of records of the file.
+    pub load_stats: LoadStats,
+}
+
+/// Not fatal problem found when opening, returned in 'OpenReport' or by 'MapWithFile::open_warnings'.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OpenWarning {
+    /// Marker of 'Cfg::dirty_marker' was left by process 'pid' which opened the map at 'at' and didn't close it.
+    /// Records of the file are already checked by loading, so the map is consistent,
+    /// but operations not flushed by that process are lost.
+    UncleanShutdown { pid: u32, at: SystemTime },
+    /// Marker exists but its content can't be parsed, probably it was written partially by crashed process.
+    UnreadableDirtyMarker,
+    /// Marker can't be read or written, unclean shutdown of this process will not be detected.
+    DirtyMarkerError(std::io::Error),
+    /// Insert and remove records ignored by 'DeserializePolicy' of 'Cfg::on_deserialize_error'
+    /// because their key or value can't be deserialized.
+    SkippedRecords { count: u64 },
+    /// Insert records loaded with default value by 'DeserializePolicy::Default' because their value can't be deserialized.
+    DefaultValues { count: u64 },
+    /// Invalid last record was truncated by 'RecoveryMode::TruncateAtError', 'dropped_bytes' were removed
+    /// from the end of the file and its length is 'len' now.
+    TruncatedTail { len: u64, dropped_bytes: u64 },
+    /// Line or block 'record_num' at 'offset' of the file skipped by 'RecoveryMode::SkipBadRecords' because of 'error'.
+    SkippedBadRecord { record_num: usize, offset: u64, error: LoadFileError },
+}
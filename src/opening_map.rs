@@ -0,0 +1,139 @@
+use crate::cfg::Cfg;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::path::Path;
+use std::thread::{spawn, JoinHandle};
+#[cfg(feature = "async")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::task::Waker;
+
+/// Result of opening of the map in background.
+type OpeningResult<Key, Value, Map> = Result<MapWithFile<Key, Value, Map>, LoadFileError>;
+
+/// File based map being loaded in the background thread, see 'MapWithFile::open_in_background'.
+/// With feature "async" it can be awaited.
+///
+/// If dropped before loading finishes, loading is not interrupted, but the loaded map is dropped
+/// in the background thread right after loading, so the file is unlocked and the thread stops.
+pub struct OpeningMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Receiver of the result from loading thread.
+    result_receiver: Receiver<OpeningResult<Key, Value, Map>>,
+    /// Received result if it's already received by 'ready'.
+    result: Option<OpeningResult<Key, Value, Map>>,
+    /// Loading thread.
+    join_handle: Option<JoinHandle<()>>,
+    /// Waker of the task awaiting this map.
+    #[cfg(feature = "async")]
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + Send + Sync + 'static,
+    Value: Serialize + DeserializeOwned + Clone + Send + Sync,
+    Map: MapTrait<Key, Value> + Default + Send + 'static {
+
+    /// Same as 'open_or_create' but doesn't block, loading of the history is done in the background thread.
+    /// Use 'OpeningMap::wait' (or await with feature "async") for get the map when loading finishes.
+    pub fn open_in_background(file_path: impl AsRef<Path>, cfg: Cfg) -> OpeningMap<Key, Value, Map> {
+        let (result_sender, result_receiver) = channel();
+        #[cfg(feature = "async")]
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+        #[cfg(feature = "async")]
+        let thread_waker = waker.clone();
+
+        let file_path = file_path.as_ref().to_path_buf();
+        let join_handle = spawn(move || {
+            let result = Self::open_or_create(&file_path, cfg);
+            // if OpeningMap is dropped, the map is dropped here and the file is unlocked
+            let _ = result_sender.send(result);
+
+            #[cfg(feature = "async")]
+            if let Some(waker) = thread_waker.lock().unwrap_or_else(|err| err.into_inner()).take() {
+                waker.wake();
+            }
+        });
+
+        OpeningMap {
+            result_receiver,
+            result: None,
+            join_handle: Some(join_handle),
+            #[cfg(feature = "async")]
+            waker,
+        }
+    }
+}
+
+impl<Key, Value, Map> OpeningMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Returns true if loading is finished successfully or with error and 'wait' will not block.
+    pub fn ready(&mut self) -> bool {
+        self.try_receive();
+        self.result.is_some()
+    }
+
+    /// Blocks until loading finishes and returns the loaded map or error of loading.
+    /// Panic in the loading thread (for example in callback of config) is resumed here.
+    pub fn wait(mut self) -> Result<MapWithFile<Key, Value, Map>, LoadFileError> {
+        if let Some(result) = self.result.take() {
+            return result;
+        }
+
+        match self.result_receiver.recv() {
+            Ok(result) => result,
+            Err(_) => self.resume_panic(),
+        }
+    }
+
+    /// Move result from channel if it's sent.
+    fn try_receive(&mut self) {
+        if self.result.is_some() {
+            return;
+        }
+
+        match self.result_receiver.try_recv() {
+            Ok(result) => self.result = Some(result),
+            Err(TryRecvError::Empty) => {},
+            Err(TryRecvError::Disconnected) => self.resume_panic(),
+        }
+    }
+
+    /// Called when the loading thread stopped without sending result, this is possible only by panic.
+    fn resume_panic(&mut self) -> ! {
+        let join_handle = self.join_handle.take()
+            .unwrap_or_else(|| unreachable!()); // unreachable because panic is resumed only once
+        match join_handle.join() {
+            Err(panic) => std::panic::resume_unwind(panic),
+            Ok(()) => unreachable!(), // unreachable because thread always sends result before normal finish
+        }
+    }
+}
+
+// OpeningMap is never pinned structurally, the map is moved out only by value.
+impl<Key, Value, Map> Unpin for OpeningMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {}
+
+#[cfg(feature = "async")]
+impl<Key, Value, Map> std::future::Future for OpeningMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    type Output = Result<MapWithFile<Key, Value, Map>, LoadFileError>;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // waker is stored before checking of the result, so result sent after checking will wake the task
+        *this.waker.lock().unwrap_or_else(|err| err.into_inner()) = Some(cx.waker().clone());
+
+        this.try_receive();
+        match this.result.take() {
+            Some(result) => std::task::Poll::Ready(result),
+            None => std::task::Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use serde::de::{Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+/// Order of keys for 'OrderedBy', usually implemented for zero-sized type.
+pub trait KeyOrder<Key> {
+    /// Compare keys in this order.
+    fn cmp(a: &Key, b: &Key) -> Ordering;
+}
+
+/// Key wrapper ordered by 'Order' instead of own 'Ord' of the key,
+/// for example for iteration over 'BTreeMapOrderedBy' in case-insensitive order.
+/// Keys equal by 'Order' are the same key of the map.
+/// Serialized the same as the key, so the file format is not changed by the wrapper.
+pub struct OrderedBy<Key, Order> {
+    /// Wrapped key.
+    key: Key,
+    /// Order of keys, fn pointer is used for Send and Sync regardless of the order type.
+    order: PhantomData<fn() -> Order>,
+}
+
+impl<Key, Order> OrderedBy<Key, Order> {
+    /// Wrap key.
+    pub fn new(key: Key) -> Self {
+        OrderedBy { key, order: PhantomData }
+    }
+
+    /// Returns reference to the wrapped key.
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Returns the wrapped key.
+    pub fn into_key(self) -> Key {
+        self.key
+    }
+}
+
+impl<Key, Order> From<Key> for OrderedBy<Key, Order> {
+    fn from(key: Key) -> Self {
+        OrderedBy::new(key)
+    }
+}
+
+impl<Key: Clone, Order> Clone for OrderedBy<Key, Order> {
+    fn clone(&self) -> Self {
+        OrderedBy::new(self.key.clone())
+    }
+}
+
+impl<Key: std::fmt::Debug, Order> std::fmt::Debug for OrderedBy<Key, Order> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+impl<Key, Order: KeyOrder<Key>> PartialEq for OrderedBy<Key, Order> {
+    fn eq(&self, other: &Self) -> bool {
+        Order::cmp(&self.key, &other.key) == Ordering::Equal
+    }
+}
+
+impl<Key, Order: KeyOrder<Key>> Eq for OrderedBy<Key, Order> {}
+
+impl<Key, Order: KeyOrder<Key>> PartialOrd for OrderedBy<Key, Order> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Key, Order: KeyOrder<Key>> Ord for OrderedBy<Key, Order> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        Order::cmp(&self.key, &other.key)
+    }
+}
+
+impl<Key: Serialize, Order> Serialize for OrderedBy<Key, Order> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.key.serialize(serializer)
+    }
+}
+
+impl<'de, Key: Deserialize<'de>, Order> Deserialize<'de> for OrderedBy<Key, Order> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Key::deserialize(deserializer).map(OrderedBy::new)
+    }
+}
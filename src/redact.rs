@@ -0,0 +1,161 @@
+use crate::bin_format::load_bin_file_records;
+use crate::cfg::Format;
+use crate::format::{file_record_of_insert, file_record_of_remove, file_record_of_schema, tmp_file_path, LoadLimits, MapOperation};
+use crate::map_with_file::SerializedError;
+use crate::text_format::load_text_file_records;
+use crate::{Cfg, LoadFileError};
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Decision of selector of 'redact' about operation of history file.
+pub enum Redaction<Value> {
+    /// Operation is written as is.
+    Keep,
+    /// Value of insert is replaced, for remove it's the same as 'Keep'.
+    ReplaceValue(Value),
+    /// Operation is not written.
+    Drop,
+}
+
+/// Operations changed by 'redact'.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedactReport {
+    /// Indices of inserts with replaced value.
+    pub replaced: Vec<usize>,
+    /// Indices of dropped operations.
+    pub dropped: Vec<usize>,
+    /// True if dropped operations change the map loaded from the file, for example if the last insert of the key
+    /// is dropped and the key is not removed after. Values replaced by 'Redaction::ReplaceValue' are not a change.
+    pub final_state_changed: bool,
+}
+
+/// Error of 'redact'.
+#[derive(Debug)]
+pub enum RedactError {
+    /// When can't open file that need redact.
+    OpenFileError(std::io::Error),
+    /// When can't exclusive lock opened file.
+    LockFileError,
+    /// Error of reading the file.
+    LoadFileError(LoadFileError),
+    /// Error of serialization of written operation.
+    SerializeError(SerializedError),
+    /// When write error to the tmp file.
+    WriteToFileError(std::io::Error),
+    /// Error of creating tmp file or of replacing the file with it.
+    TmpFileError,
+}
+
+impl std::error::Error for RedactError {}
+
+impl std::fmt::Display for RedactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Rewrite history file with operations changed by 'selector', for example for erasure of data of one user.
+/// 'selector' is called for each operation with its index in order of loading, operations of batch are counted separately.
+/// File is rewritten via tmp file in the format of 'cfg' with integrity computed again from 'integrity' of 'cfg'.
+/// As in 'convert', operations of batches and transactions are written as separate records, metadata of records
+/// is not kept and records of incomplete transaction are discarded.
+/// Returns indices of changed operations, if dropped operations change the loaded map, then
+/// 'RedactReport::final_state_changed' is set (and warning is logged with "tracing" feature).
+pub fn redact<Key, Value, Selector>(file_path: impl AsRef<Path>, mut cfg: Cfg, selector: Selector) -> Result<RedactReport, RedactError>
+where
+    Key: Serialize + DeserializeOwned,
+    Value: Serialize + DeserializeOwned,
+    Selector: Fn(&MapOperation<Key, Value>, usize) -> Redaction<Value>,
+{
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("redact", path = %file_path.as_ref().display()).entered();
+
+    let mut file = fs::OpenOptions::new().read(true).open(&file_path)
+        .map_err(RedactError::OpenFileError)?;
+    file.lock_exclusive()
+        .map_err(|_| RedactError::LockFileError)?;
+
+    // operations are loaded before writing because integrity state of 'cfg' is changed by loading
+    let integrity_at_start = cfg.integrity.clone();
+    let mut operations = Vec::new();
+    let collect_map_operation = |map_operation| {
+        operations.push(map_operation);
+        Ok(())
+    };
+    let limits = LoadLimits::of(&cfg);
+    match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, collect_map_operation)
+        },
+        Format::Bin(_, after_read_callback) => {
+            load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, collect_map_operation)
+        },
+    }.map_err(RedactError::LoadFileError)?;
+    cfg.integrity = integrity_at_start;
+
+    let tmp_file_path = tmp_file_path().ok_or(RedactError::TmpFileError)?;
+    let tmp_file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_file_path)
+        .map_err(|_| RedactError::TmpFileError)?;
+    let mut writer = BufWriter::new(tmp_file);
+
+    if let Some(record) = file_record_of_schema(&mut cfg).map_err(RedactError::SerializeError)? {
+        writer.write_all(&record).map_err(RedactError::WriteToFileError)?;
+    }
+
+    let mut report = RedactReport::default();
+    // index of operation that made the current value of the key, in original and in written history
+    let mut original_state = HashMap::new();
+    let mut redacted_state = HashMap::new();
+    for (index, map_operation) in operations.iter().enumerate() {
+        apply_to_state(&mut original_state, map_operation, index)?;
+
+        let record = match (selector(map_operation, index), map_operation) {
+            (Redaction::Drop, _) => {
+                report.dropped.push(index);
+                continue;
+            },
+            (Redaction::ReplaceValue(value), MapOperation::Insert(key, _)) => {
+                report.replaced.push(index);
+                file_record_of_insert(key, &value, &mut cfg)
+            },
+            (_, MapOperation::Insert(key, value)) => file_record_of_insert(key, value, &mut cfg),
+            (_, MapOperation::Remove(key)) => file_record_of_remove(key, &mut cfg),
+        }.map_err(RedactError::SerializeError)?;
+
+        apply_to_state(&mut redacted_state, map_operation, index)?;
+        writer.write_all(&record).map_err(RedactError::WriteToFileError)?;
+    }
+
+    writer.flush().map_err(RedactError::WriteToFileError)?;
+    drop(writer);
+    drop(file);
+    fs::rename(&tmp_file_path, file_path)
+        .map_err(|_| RedactError::TmpFileError)?;
+
+    report.final_state_changed = original_state != redacted_state;
+
+    #[cfg(feature = "tracing")]
+    if report.final_state_changed {
+        tracing::warn!(dropped = report.dropped.len(), "redaction changed final state of the map");
+    }
+
+    Ok(report)
+}
+
+/// Apply operation to map of serialized keys to index of operation that made the current value.
+fn apply_to_state<Key, Value>(state: &mut HashMap<Vec<u8>, usize>, map_operation: &MapOperation<Key, Value>, index: usize) -> Result<(), RedactError>
+where
+    Key: Serialize,
+{
+    let serialize_key = |key| bincode2::serialize(key).map_err(|err| RedactError::SerializeError(SerializedError::Bincode(err)));
+    match map_operation {
+        MapOperation::Insert(key, _) => { state.insert(serialize_key(key)?, index); },
+        MapOperation::Remove(key) => { state.remove(&serialize_key(key)?); },
+    }
+    Ok(())
+}
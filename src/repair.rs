@@ -0,0 +1,116 @@
+use crate::bin_format::load_bin_file_records;
+use crate::cfg::{Cfg, Format, Integrity, RecoveryMode};
+use crate::chain_head::ChainHeadFile;
+use crate::format::{LoadLimits, MapOperation};
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Result of 'repair_file'.
+#[derive(Debug)]
+pub struct RepairOutcome {
+    /// Count of records before the first invalid one, they are kept in the file, line or block count.
+    pub records_kept: usize,
+    /// Count of bytes cut from the end of the file, zero if all records are valid.
+    pub bytes_cut: u64,
+    /// Error of the first invalid record, None if all records are valid.
+    pub error: Option<LoadFileError>,
+    /// Path of the file where cut bytes are appended if quarantine is requested and bytes are cut.
+    pub quarantine_path: Option<PathBuf>,
+}
+
+/// Error of 'repair_file'.
+#[derive(Debug)]
+pub enum RepairError {
+    /// Error of opening, locking, reading or truncating of the file, or error of loading not caused by invalid record,
+    /// for example records of other kind of map or error of after read callback.
+    LoadFileError(LoadFileError),
+    /// When write error to the quarantine file, the file is not truncated then.
+    QuarantineError(std::io::Error),
+}
+
+impl std::error::Error for RepairError {}
+
+impl std::fmt::Display for RepairError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<LoadFileError> for RepairError {
+    fn from(err: LoadFileError) -> Self {
+        RepairError::LoadFileError(err)
+    }
+}
+
+impl From<std::io::Error> for RepairError {
+    fn from(err: std::io::Error) -> Self {
+        RepairError::LoadFileError(LoadFileError::FileError(err))
+    }
+}
+
+/// Returns path of the quarantine file of 'repair_file' for the file of the map, it's the path with ".quarantine".
+pub fn quarantine_path(file_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = file_path.as_ref().as_os_str().to_owned();
+    path.push(".quarantine");
+    PathBuf::from(path)
+}
+
+/// Truncate the file after the last valid record, for offline repair of the file that can't be opened.
+/// Records are read from the start as by 'MapWithFile::open_or_create' with integrity, after read callback and other settings of 'cfg',
+/// the file is truncated at the start of the first record that can't be read, deserialized to 'Key' and 'Value' or fails integrity check,
+/// so all records after it are cut too. If 'quarantine' is true, cut bytes are appended to the file of 'quarantine_path'
+/// before truncation, so they can be examined later. The file is exclusive locked as by opening of the map.
+/// Incomplete transaction at the end is not cut, it's aborted by the next opening.
+/// Head file of 'Cfg::chain_head_file' is saved for the truncated file, so it can be opened with removed records.
+pub fn repair_file<Key, Value>(file_path: impl AsRef<Path>, mut cfg: Cfg, quarantine: bool) -> Result<RepairOutcome, RepairError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+{
+    let file_path = file_path.as_ref();
+    let mut file = OpenOptions::new().read(true).write(true).open(file_path)?;
+    file.lock_exclusive().map_err(LoadFileError::LockError)?;
+
+    let genesis = cfg.integrity.as_ref().and_then(Integrity::anchor);
+    let limits = LoadLimits { recovery: RecoveryMode::TruncateAtError, truncate_at_any_error: true, file_path: Some(file_path.to_path_buf()), ..LoadLimits::of(&cfg) };
+    let skip_operation = |_: MapOperation<Key, Value>| Ok(());
+    let loaded_tail = match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, skip_operation)?
+        },
+        Format::Bin(_, after_read_callback) => {
+            load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, skip_operation)?
+        },
+    };
+
+    let mut outcome = RepairOutcome { records_kept: loaded_tail.records, bytes_cut: 0, error: loaded_tail.truncation_error, quarantine_path: None };
+    let len = match loaded_tail.truncated_at {
+        Some(len) => len,
+        None => return Ok(outcome),
+    };
+
+    outcome.bytes_cut = file.metadata()?.len() - len;
+    if quarantine {
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::Start(len))?;
+        file.read_to_end(&mut tail)?;
+
+        let path = quarantine_path(file_path);
+        let mut quarantine_file = OpenOptions::new().append(true).create(true).open(&path).map_err(RepairError::QuarantineError)?;
+        quarantine_file.write_all(&tail).map_err(RepairError::QuarantineError)?;
+        quarantine_file.sync_all().map_err(RepairError::QuarantineError)?;
+        outcome.quarantine_path = Some(path);
+    }
+
+    file.set_len(len)?;
+    file.sync_all()?;
+    if let Some(chain_head) = ChainHeadFile::of(file_path, &cfg) {
+        chain_head.save_of_file(file_path, len, genesis)?;
+    }
+    Ok(outcome)
+}
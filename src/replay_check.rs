@@ -0,0 +1,156 @@
+use crate::format::TransactionMarker;
+use crate::{Cfg, LoadFileError};
+use std::collections::HashMap;
+
+/// Kind of record impossible for the map, found by check of replay, see 'Cfg::strict_replay'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayAnomalyKind {
+    /// Remove of key that is not in the map, the map writes remove only for existing key.
+    /// Removes of batch are not checked because 'apply_batch' writes them for any key.
+    RemoveOfAbsentKey,
+    /// Insert of the value equal to the current value of the key if 'Cfg::skip_identical_inserts' is set,
+    /// such inserts are not written by 'MapWithFile', so the setting must be used since creation of the file.
+    /// Inserts of batch and inserts with metadata are not checked because they are written for any value.
+    DuplicateNoOpInsert,
+    /// Transaction marker out of order: end or abort without begin, or begin inside unfinished transaction.
+    SequenceGap,
+}
+
+/// Record impossible for the map with its line or block number, see 'Cfg::collect_replay_anomalies'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayAnomaly {
+    /// Line or block number.
+    pub line_num: usize,
+    /// Kind of anomaly.
+    pub kind: ReplayAnomalyKind,
+}
+
+/// Settings of check of replay from config.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReplayCheck {
+    /// Loading fails on first anomaly.
+    pub strict: bool,
+    /// See 'Cfg::skip_identical_inserts'.
+    pub skip_identical_inserts: bool,
+}
+
+impl ReplayCheck {
+    /// Settings of check if 'Cfg::strict_replay' or 'Cfg::collect_replay_anomalies' is set.
+    pub fn of(cfg: &Cfg) -> Option<Self> {
+        if !cfg.strict_replay && !cfg.collect_replay_anomalies {
+            return None;
+        }
+
+        Some(ReplayCheck {
+            strict: cfg.strict_replay,
+            skip_identical_inserts: cfg.skip_identical_inserts,
+        })
+    }
+}
+
+/// Value of key in shadow state, None if key is present but value is unknown,
+/// for example after push of items or increment.
+type ShadowValue = Option<Vec<u8>>;
+
+/// Shadow state of the map used by loaders for check of records.
+/// Keys and values are stored as serialized in the file (JSON of the text format, bincode of the binary),
+/// so the check doesn't need types of the map.
+pub(crate) struct ReplayChecker {
+    /// Settings of check.
+    check: ReplayCheck,
+    /// Keys of the map with values.
+    state: HashMap<Vec<u8>, ShadowValue>,
+    /// Changes of the current transaction, None for removed key. Applied to 'state' at the end of transaction.
+    transaction: Option<HashMap<Vec<u8>, Option<ShadowValue>>>,
+    /// Found anomalies if check is not strict.
+    anomalies: Vec<ReplayAnomaly>,
+}
+
+impl ReplayChecker {
+    /// Checker with empty state.
+    pub fn new(check: ReplayCheck) -> Self {
+        ReplayChecker { check, state: HashMap::new(), transaction: None, anomalies: Vec::new() }
+    }
+
+    /// Check insert record.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>, line_num: usize) -> Result<(), LoadFileError> {
+        if self.check.skip_identical_inserts && matches!(self.current(&key), Some(Some(current)) if *current == value) {
+            self.anomaly(ReplayAnomalyKind::DuplicateNoOpInsert, line_num)?;
+        }
+
+        self.set(key, Some(Some(value)));
+        Ok(())
+    }
+
+    /// Check remove record.
+    pub fn remove(&mut self, key: Vec<u8>, line_num: usize) -> Result<(), LoadFileError> {
+        if self.current(&key).is_none() {
+            self.anomaly(ReplayAnomalyKind::RemoveOfAbsentKey, line_num)?;
+        }
+
+        self.set(key, None);
+        Ok(())
+    }
+
+    /// Apply operation of record that is not checked, value is None if key is removed
+    /// and Some(None) if key is present with unknown value.
+    pub fn apply(&mut self, key: Vec<u8>, value: Option<ShadowValue>) {
+        self.set(key, value);
+    }
+
+    /// Check transaction marker.
+    pub fn marker(&mut self, marker: TransactionMarker, line_num: usize) -> Result<(), LoadFileError> {
+        match marker {
+            TransactionMarker::Begin => {
+                if self.transaction.replace(HashMap::new()).is_some() {
+                    self.anomaly(ReplayAnomalyKind::SequenceGap, line_num)?;
+                }
+            },
+            TransactionMarker::End => match self.transaction.take() {
+                Some(changes) => {
+                    for (key, value) in changes {
+                        self.set(key, value);
+                    }
+                },
+                None => self.anomaly(ReplayAnomalyKind::SequenceGap, line_num)?,
+            },
+            TransactionMarker::Abort => {
+                if self.transaction.take().is_none() {
+                    self.anomaly(ReplayAnomalyKind::SequenceGap, line_num)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns found anomalies.
+    pub fn into_anomalies(self) -> Vec<ReplayAnomaly> {
+        self.anomalies
+    }
+
+    /// Returns current value of key with changes of the current transaction, None if key is absent.
+    fn current(&self, key: &[u8]) -> Option<&ShadowValue> {
+        if let Some(change) = self.transaction.as_ref().and_then(|changes| changes.get(key)) {
+            return change.as_ref();
+        }
+        self.state.get(key)
+    }
+
+    /// Set value of key in the current transaction or in the state, None for removed key.
+    fn set(&mut self, key: Vec<u8>, value: Option<ShadowValue>) {
+        match (&mut self.transaction, value) {
+            (Some(changes), value) => { changes.insert(key, value); },
+            (None, Some(value)) => { self.state.insert(key, value); },
+            (None, None) => { self.state.remove(&key); },
+        }
+    }
+
+    /// Fail with anomaly if check is strict, otherwise remember it.
+    fn anomaly(&mut self, kind: ReplayAnomalyKind, line_num: usize) -> Result<(), LoadFileError> {
+        if self.check.strict {
+            return Err(LoadFileError::ReplayAnomaly { line_num, kind });
+        }
+        self.anomalies.push(ReplayAnomaly { line_num, kind });
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use crate::cfg::{Cfg, WriteOperation};
+use crate::format::{check_write, file_record_of_set_operation, integrity_before_record, ItemOperation, LoadedOperation, MapOperation, RawMeta, SetOperation};
+use crate::index::{Index, MakeIndexKey};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeSet as StdBTreeSet;
+use std::hash::Hash;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Set with storing all changes history to the file.
+/// Restores own state from the file when creating.
+/// Based on std::collections::BTreeMap with empty values.
+pub type BTreeSet<Element> = SetWithFile<Element, std::collections::BTreeMap<Element, ()>>;
+
+/// Set with storing all changes history to the file.
+/// Restores own state from the file when creating.
+/// Based on std::collections::HashMap with empty values.
+pub type HashSet<Element> = SetWithFile<Element, std::collections::HashMap<Element, ()>>;
+
+/// File based set.
+/// Wrapper of file based map with empty values, changes are written to the file as
+/// "add" and "del" lines of text format (codes 9 and 10 of binary format) with the element only.
+/// Insert and remove records of the map with empty values are loaded too,
+/// so the file of such map can be opened as set or converted by 'convert'.
+pub struct SetWithFile<Element, Map>
+where Map: MapTrait<Element, ()> {
+    /// Wrapped map with elements as keys.
+    inner: MapWithFile<Element, (), Map>,
+}
+
+impl<Element, Map> SetWithFile<Element, Map>
+where
+    Element: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Map: MapTrait<Element, ()> + Default {
+
+    /// Open/create file and loads the entire history of changes, same as 'MapWithFile::open_or_create'.
+    pub fn open_or_create(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        let inner = MapWithFile::open_with(file_path.as_ref(), cfg, |map: &mut Map, SetRecord(map_operation)| {
+            match map_operation {
+                MapOperation::Insert(element, ()) => map.insert(element, ()),
+                MapOperation::Remove(element) => map.remove(&element),
+            };
+        })?;
+
+        Ok(SetWithFile { inner })
+    }
+
+    /// Adds element to the set. Returns false and nothing is written to the file if the set already contains it.
+    pub fn insert(&mut self, element: Element) -> Result<bool, SerializedError> {
+        if self.contains(&element) {
+            return Ok(false);
+        }
+
+        let integrity_before = integrity_before_record(self.inner.cfg.get_mut());
+        let record = file_record_of_set_operation(SetOperation::Add, &element, self.inner.cfg.get_mut())?;
+        check_write(self.inner.file_worker.write_bytes(record, WriteOperation::Insert), &mut self.inner.cfg.get_mut().integrity, integrity_before)?;
+        self.inner.operations_since_open += 1;
+        self.inner.map.insert(element.clone(), ());
+        self.inner.update_index_when_insert(&element, &(), &None);
+        Ok(true)
+    }
+
+    /// Removes element from the set. Returns false and nothing is written to the file if the set doesn't contain it.
+    pub fn remove(&mut self, element: &Element) -> Result<bool, SerializedError> {
+        if !self.contains(element) {
+            return Ok(false);
+        }
+
+        let integrity_before = integrity_before_record(self.inner.cfg.get_mut());
+        let record = file_record_of_set_operation(SetOperation::Delete, element, self.inner.cfg.get_mut())?;
+        check_write(self.inner.file_worker.write_bytes(record, WriteOperation::Remove), &mut self.inner.cfg.get_mut().integrity, integrity_before)?;
+        self.inner.operations_since_open += 1;
+        self.inner.map.remove(element);
+        self.inner.update_index_when_remove(element, &());
+        Ok(true)
+    }
+
+    /// Returns true if the set contains the element.
+    pub fn contains(&self, element: &Element) -> bool {
+        self.inner.get(element).is_some()
+    }
+
+    /// Returns the number of elements in the set.
+    pub fn len(&self) -> usize {
+        self.inner.map.len()
+    }
+
+    /// Returns true if the set contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.inner.map.is_empty()
+    }
+
+    /// Iterate over all elements and call callback for each.
+    pub fn iter_each(&self, mut f: impl FnMut(&Element)) {
+        self.inner.map.for_each(|element, _| f(element));
+    }
+
+    /// Create index by element based on std::collections::BTreeMap.
+    /// 'make_index_key_callback' will call everytime when insert or remove on set.
+    pub fn create_btree_index<IndexKey>(&mut self, make_index_key_callback: fn(&Element) -> IndexKey)
+        -> Index<IndexKey, Element, (), std::collections::BTreeMap<IndexKey, Arc<StdBTreeSet<Element>>>>
+    where IndexKey: Clone + Ord + Send + Sync + 'static, Element: Send + Sync {
+        self.create_index::<IndexKey, std::collections::BTreeMap<IndexKey, Arc<StdBTreeSet<Element>>>>(make_index_key_callback)
+    }
+
+    /// Create index by element based on std::collections::HashMap.
+    /// 'make_index_key_callback' will call everytime when insert or remove on set.
+    pub fn create_hashmap_index<IndexKey>(&mut self, make_index_key_callback: fn(&Element) -> IndexKey)
+        -> Index<IndexKey, Element, (), std::collections::HashMap<IndexKey, Arc<StdBTreeSet<Element>>>>
+    where IndexKey: Clone + Hash + Eq + Send + Sync + 'static, Element: Send + Sync {
+        self.create_index::<IndexKey, std::collections::HashMap<IndexKey, Arc<StdBTreeSet<Element>>>>(make_index_key_callback)
+    }
+
+    /// Create index by element.
+    /// 'make_index_key_callback' will call everytime when insert or remove on set.
+    /// Inside into callback necessary to determine the value and type of the index key
+    /// in any way related to the element.
+    pub fn create_index<IndexKey, MapOfIndex>(&mut self, make_index_key_callback: fn(&Element) -> IndexKey)
+        -> Index<IndexKey, Element, (), MapOfIndex>
+    where
+        IndexKey: Clone + Eq + 'static,
+        MapOfIndex: MapTrait<IndexKey, Arc<StdBTreeSet<Element>>> + Default + Sized + Send + Sync + 'static,
+        Element: Send + Sync,
+    {
+        self.inner.create_index_with(MakeIndexKey::ByKey(make_index_key_callback))
+    }
+
+    /// Returns reference to the wrapped file based map.
+    pub fn inner(&self) -> &MapWithFile<Element, (), Map> {
+        &self.inner
+    }
+
+    /// Waits until all changes made before are written to the file.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Operation loaded from the file of set, add and delete records are the same as insert and remove.
+struct SetRecord<Element>(MapOperation<Element, ()>);
+
+impl<Element> LoadedOperation<Element, ()> for SetRecord<Element> {
+    fn from_map_operation(map_operation: MapOperation<Element, ()>) -> Self { SetRecord(map_operation) }
+    fn from_item_operation(_: ItemOperation, _: Element, _: ()) -> Option<Self> { None }
+    fn from_set_operation(set_operation: SetOperation, element: Element) -> Option<Self> {
+        match set_operation {
+            SetOperation::Add => Some(SetRecord(MapOperation::Insert(element, ()))),
+            SetOperation::Delete => Some(SetRecord(MapOperation::Remove(element))),
+        }
+    }
+    fn from_increment(_: Element, _: i64) -> Option<Self> { None }
+    fn with_meta(self, _: RawMeta<'_>, _: usize) -> Result<Self, LoadFileError> { Ok(self) }
+}
@@ -0,0 +1,228 @@
+use crate::cfg::Cfg;
+use crate::file_worker::worker_panicked_error;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::{RwLock, TryLockError};
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+#[cfg(feature = "parking_lot")]
+use std::time::Duration;
+
+/// Guard of read lock of 'SharedMap', of parking_lot with feature "parking_lot".
+#[cfg(not(feature = "parking_lot"))]
+pub type ReadGuard<'a, Map> = std::sync::RwLockReadGuard<'a, Map>;
+/// Guard of read lock of 'SharedMap', of parking_lot with feature "parking_lot".
+#[cfg(feature = "parking_lot")]
+pub type ReadGuard<'a, Map> = parking_lot::RwLockReadGuard<'a, Map>;
+
+/// Guard of write lock of 'SharedMap', of parking_lot with feature "parking_lot".
+#[cfg(not(feature = "parking_lot"))]
+pub type WriteGuard<'a, Map> = std::sync::RwLockWriteGuard<'a, Map>;
+/// Guard of write lock of 'SharedMap', of parking_lot with feature "parking_lot".
+#[cfg(feature = "parking_lot")]
+pub type WriteGuard<'a, Map> = parking_lot::RwLockWriteGuard<'a, Map>;
+
+/// Thread-safe handle of file based map.
+/// Based on std::collections::BTreeMap.
+pub type SharedBTreeMap<Key, Value> = SharedMap<Key, Value, std::collections::BTreeMap<Key, Value>>;
+
+/// Thread-safe handle of file based map.
+/// Based on std::collections::HashMap.
+pub type SharedHashMap<Key, Value> = SharedMap<Key, Value, std::collections::HashMap<Key, Value>>;
+
+/// Thread-safe handle of file based map that can be cloned and used from many threads.
+/// Changing operations take write lock, reading operations take read lock.
+/// Writing to the file is done in the background thread, so write lock is held
+/// only for changing the map and serialization of the record.
+/// 'try_*' operations don't wait for the lock, with feature "parking_lot" '*_timeout' operations wait limited time.
+pub struct SharedMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Shared map.
+    inner: Arc<RwLock<MapWithFile<Key, Value, Map>>>,
+}
+
+impl<Key, Value: 'static, Map> SharedMap<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Same as 'MapWithFile::open_or_create' but returns thread-safe handle.
+    pub fn open_or_create(file_path: impl AsRef<Path>, cfg: Cfg) -> Result<Self, LoadFileError> {
+        Ok(Self::from(MapWithFile::open_or_create(file_path, cfg)?))
+    }
+
+    /// Inserts a key-value pair into the map under write lock.
+    pub fn insert(&self, key: Key, value: Value) -> Result<Option<Value>, SerializedError> {
+        self.write().insert(key, value)
+    }
+
+    /// Remove value by key under write lock.
+    pub fn remove(&self, key: &Key) -> Result<Option<Value>, SerializedError> {
+        self.write().remove(key)
+    }
+
+    /// Returns clone of the value corresponding to the key.
+    pub fn get_cloned(&self, key: &Key) -> Option<Value> {
+        self.read().get(key).cloned()
+    }
+
+    /// Call 'f' with reference to the value corresponding to the key under read lock, without cloning of the value.
+    pub fn get_with<R>(&self, key: &Key, f: impl FnOnce(Option<&Value>) -> R) -> R {
+        f(self.read().get(key))
+    }
+
+    /// Waits until all changes made before are written to the file.
+    /// Lock is not held while waiting.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        let result_receiver = self.read().flush_request();
+        result_receiver.recv()
+            .unwrap_or_else(|_| Err(worker_panicked_error())) // result is not received only if worker thread panicked, map can't be dropped while this handle exists
+    }
+
+    /// Same as 'insert' but returns 'TryChangeError::WouldBlock' without waiting if the lock is held by other thread.
+    /// Nothing is changed or written to the file in this case.
+    pub fn try_insert(&self, key: Key, value: Value) -> Result<Option<Value>, TryChangeError> {
+        self.try_write().ok_or(TryChangeError::WouldBlock)?
+            .insert(key, value).map_err(TryChangeError::SerializeError)
+    }
+
+    /// Same as 'remove' but returns 'TryChangeError::WouldBlock' without waiting if the lock is held by other thread.
+    /// Nothing is changed or written to the file in this case.
+    pub fn try_remove(&self, key: &Key) -> Result<Option<Value>, TryChangeError> {
+        self.try_write().ok_or(TryChangeError::WouldBlock)?
+            .remove(key).map_err(TryChangeError::SerializeError)
+    }
+
+    /// Same as 'get_cloned' but returns 'WouldBlock' without waiting if the write lock is held by other thread.
+    pub fn try_get_cloned(&self, key: &Key) -> Result<Option<Value>, WouldBlock> {
+        Ok(self.try_read().ok_or(WouldBlock)?.get(key).cloned())
+    }
+
+    /// Same as 'insert' but returns 'TryChangeError::WouldBlock' if the lock is not acquired during 'timeout'.
+    #[cfg(feature = "parking_lot")]
+    pub fn insert_timeout(&self, key: Key, value: Value, timeout: Duration) -> Result<Option<Value>, TryChangeError> {
+        self.inner.try_write_for(timeout).ok_or(TryChangeError::WouldBlock)?
+            .insert(key, value).map_err(TryChangeError::SerializeError)
+    }
+
+    /// Same as 'remove' but returns 'TryChangeError::WouldBlock' if the lock is not acquired during 'timeout'.
+    #[cfg(feature = "parking_lot")]
+    pub fn remove_timeout(&self, key: &Key, timeout: Duration) -> Result<Option<Value>, TryChangeError> {
+        self.inner.try_write_for(timeout).ok_or(TryChangeError::WouldBlock)?
+            .remove(key).map_err(TryChangeError::SerializeError)
+    }
+
+    /// Same as 'get_cloned' but returns 'WouldBlock' if the lock is not acquired during 'timeout'.
+    #[cfg(feature = "parking_lot")]
+    pub fn get_cloned_timeout(&self, key: &Key, timeout: Duration) -> Result<Option<Value>, WouldBlock> {
+        Ok(self.inner.try_read_for(timeout).ok_or(WouldBlock)?.get(key).cloned())
+    }
+
+    /// Lock for reading and returns guard of the wrapped map.
+    #[cfg(not(feature = "parking_lot"))]
+    pub fn read(&self) -> ReadGuard<'_, MapWithFile<Key, Value, Map>> {
+        self.inner.read()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in callback of config, map is still usable in this case
+    }
+
+    /// Lock for reading and returns guard of the wrapped map.
+    #[cfg(feature = "parking_lot")]
+    pub fn read(&self) -> ReadGuard<'_, MapWithFile<Key, Value, Map>> {
+        self.inner.read()
+    }
+
+    /// Lock for writing and returns guard of the wrapped map.
+    #[cfg(not(feature = "parking_lot"))]
+    pub fn write(&self) -> WriteGuard<'_, MapWithFile<Key, Value, Map>> {
+        self.inner.write()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in callback of config, map is still usable in this case
+    }
+
+    /// Lock for writing and returns guard of the wrapped map.
+    #[cfg(feature = "parking_lot")]
+    pub fn write(&self) -> WriteGuard<'_, MapWithFile<Key, Value, Map>> {
+        self.inner.write()
+    }
+
+    /// Lock for reading if it's not locked for writing, returns None without waiting otherwise.
+    #[cfg(not(feature = "parking_lot"))]
+    pub fn try_read(&self) -> Option<ReadGuard<'_, MapWithFile<Key, Value, Map>>> {
+        match self.inner.try_read() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()), // map is still usable as in 'read'
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    /// Lock for reading if it's not locked for writing, returns None without waiting otherwise.
+    #[cfg(feature = "parking_lot")]
+    pub fn try_read(&self) -> Option<ReadGuard<'_, MapWithFile<Key, Value, Map>>> {
+        self.inner.try_read()
+    }
+
+    /// Lock for writing if it's not locked, returns None without waiting otherwise.
+    #[cfg(not(feature = "parking_lot"))]
+    pub fn try_write(&self) -> Option<WriteGuard<'_, MapWithFile<Key, Value, Map>>> {
+        match self.inner.try_write() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()), // map is still usable as in 'write'
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    /// Lock for writing if it's not locked, returns None without waiting otherwise.
+    #[cfg(feature = "parking_lot")]
+    pub fn try_write(&self) -> Option<WriteGuard<'_, MapWithFile<Key, Value, Map>>> {
+        self.inner.try_write()
+    }
+}
+
+/// Lock of 'SharedMap' is held by other thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldBlock;
+
+impl std::error::Error for WouldBlock {}
+
+impl std::fmt::Display for WouldBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Error of changing operations of 'SharedMap' that don't wait for the lock.
+#[derive(Debug)]
+pub enum TryChangeError {
+    /// Lock is held by other thread, nothing is changed.
+    WouldBlock,
+    /// Error of serialization of the record, see 'MapWithFile::insert'.
+    SerializeError(SerializedError),
+}
+
+impl std::error::Error for TryChangeError {}
+
+impl std::fmt::Display for TryChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl<Key, Value, Map> From<MapWithFile<Key, Value, Map>> for SharedMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    fn from(map: MapWithFile<Key, Value, Map>) -> Self {
+        SharedMap { inner: Arc::new(RwLock::new(map)) }
+    }
+}
+
+impl<Key, Value, Map> Clone for SharedMap<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    fn clone(&self) -> Self {
+        SharedMap { inner: self.inner.clone() }
+    }
+}
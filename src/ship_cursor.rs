@@ -0,0 +1,203 @@
+use crate::bin_format::{bin_integrity_len, load_bin_file_records};
+use crate::cfg::{AfterReadBinCallback, AfterReadTxtCallback, Format, Integrity};
+use crate::format::{continue_chain, LoadLimits, MapOperation};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Position of shipping of records of the history file to external system, kept in a small sidecar file,
+/// so after restart shipping is resumed by 'MapWithFile::records_since' without duplicates and gaps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShipCursor {
+    /// Path of the cursor file.
+    path: PathBuf,
+    /// Offset in the history file after the last shipped record.
+    offset: u64,
+    /// Sequence number of the next record.
+    seq: u64,
+}
+
+/// Error of loading or storing of 'ShipCursor'.
+#[derive(Debug)]
+pub enum ShipCursorError {
+    /// When cursor file has no offset and sequence number.
+    WrongFileFormat,
+    /// Read or write cursor file error.
+    FileError(std::io::Error),
+}
+
+impl std::error::Error for ShipCursorError {}
+
+impl std::fmt::Display for ShipCursorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Committed records returned by 'MapWithFile::records_since'.
+pub struct ShippedRecord<Key, Value> {
+    /// Sequence number of the record.
+    pub seq: u64,
+    /// Offset in the history file after the record, the next call of 'records_since' starts from it.
+    pub end_offset: u64,
+    /// Operations of the record, several for batch or transaction.
+    pub operations: Vec<MapOperation<Key, Value>>,
+}
+
+impl ShipCursor {
+    /// Read cursor file with one line "<offset> <seq>", cursor is at the start of the history file if the cursor file doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ShipCursorError> {
+        let path = path.as_ref().to_path_buf();
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(ShipCursor { path, offset: 0, seq: 0 }),
+            Err(err) => return Err(ShipCursorError::FileError(err)),
+        };
+
+        let (offset, seq) = content.trim().split_once(' ')
+            .and_then(|(offset, seq)| Some((offset.parse().ok()?, seq.parse().ok()?)))
+            .ok_or(ShipCursorError::WrongFileFormat)?;
+        Ok(ShipCursor { path, offset, seq })
+    }
+
+    /// Move cursor after shipped record, for example to 'ShippedRecord::end_offset' and 'ShippedRecord::seq' + 1.
+    /// Cursor file is replaced by renaming of synced temporary file, so crash leaves the old or the new cursor.
+    pub fn store(&mut self, offset: u64, seq: u64) -> Result<(), ShipCursorError> {
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut file = File::create(&tmp_path).map_err(ShipCursorError::FileError)?;
+        file.write_all(format!("{} {}\n", offset, seq).as_bytes()).map_err(ShipCursorError::FileError)?;
+        file.sync_data().map_err(ShipCursorError::FileError)?;
+        std::fs::rename(&tmp_path, &self.path).map_err(ShipCursorError::FileError)?;
+
+        self.offset = offset;
+        self.seq = seq;
+        Ok(())
+    }
+
+    /// Offset in the history file after the last shipped record.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Sequence number of the next record.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+    /// Returns committed records written to the file after 'offset', numbered from 'seq',
+    /// for shipping to external system from position stored by 'ShipCursor'. Operations are flushed before reading.
+    /// Operations of transaction are returned by one record when the transaction is committed,
+    /// records of incomplete transaction at the end of the file are returned by the next call.
+    /// With chained integrity the chain is continued from the hash of the record before 'offset',
+    /// so the first record after it is checked for continuity of the chain.
+    /// Returns 'LoadFileError::NotRecordBoundary' if 'offset' is not the end of a record found by format or integrity.
+    /// Records are read as stored in the file, so it can't be used with the format callbacks that transform records.
+    pub fn records_since(&mut self, offset: u64, seq: u64) -> Result<std::vec::IntoIter<ShippedRecord<Key, Value>>, LoadFileError> {
+        self.flush()?;
+
+        let mut file = File::open(&self.file_path)?;
+        let len = self.file_worker.counters().file_len.load(Ordering::Acquire);
+        if offset > len {
+            return Err(LoadFileError::NotRecordBoundary { offset });
+        }
+
+        let text = matches!(self.cfg.get_mut().format, Format::Text(..));
+        let mut integrity = integrity_at(&mut file, text, self.integrity_at_file_start.clone(), offset)?;
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(offset))?;
+        file.take(len - offset).read_to_end(&mut data)?;
+
+        let record_end = Arc::new(AtomicU64::new(0));
+        let mut records: Vec<ShippedRecord<Key, Value>> = Vec::new();
+        let collect_map_operation = |map_operation| {
+            // operations of one batch or transaction are passed with the same end of record
+            let end_offset = offset + record_end.load(Ordering::Relaxed);
+            match records.last_mut() {
+                Some(record) if record.end_offset == end_offset => record.operations.push(map_operation),
+                _ => {
+                    let seq = seq + records.len() as u64;
+                    records.push(ShippedRecord { seq, end_offset, operations: vec![map_operation] });
+                },
+            }
+            Ok(())
+        };
+
+        // previous records are unknown for check of replay
+        let limits = LoadLimits { replay_check: None, record_end: Some(record_end.clone()), ..LoadLimits::of(self.cfg.get_mut()) };
+        let mut reader = data.as_slice();
+        if text {
+            load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut integrity, None::<AfterReadTxtCallback>, self.cfg.get_mut().value_schema_version, self.cfg.get_mut().value_migrator.as_mut(), limits, collect_map_operation)?;
+        } else {
+            load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut integrity, None::<AfterReadBinCallback>, self.cfg.get_mut().value_schema_version, self.cfg.get_mut().value_migrator.as_mut(), limits, collect_map_operation)?;
+        }
+
+        Ok(records.into_iter())
+    }
+}
+
+/// Integrity state after the record ending at 'offset', hash of chained integrity is read from the end of the record.
+pub(crate) fn integrity_at(file: &mut File, text: bool, integrity_at_file_start: Option<Integrity>, offset: u64) -> Result<Option<Integrity>, LoadFileError> {
+    let not_record_boundary = LoadFileError::NotRecordBoundary { offset };
+    if offset == 0 {
+        return Ok(integrity_at_file_start);
+    }
+
+    let mut integrity = match integrity_at_file_start {
+        Some(Integrity::Crc32) | Some(Integrity::XxHash64) | None => {
+            if text {
+                // line ends with '\n'
+                let mut last_byte = [0u8];
+                file.seek(SeekFrom::Start(offset - 1))?;
+                file.read_exact(&mut last_byte)?;
+                if last_byte[0] != b'\n' {
+                    return Err(not_record_boundary);
+                }
+            }
+            return Ok(integrity_at_file_start);
+        },
+        Some(integrity) => integrity,
+    };
+
+    // text line ends with ' ', hex of hash and '\n', block ends with hash
+    let hash_len = bin_integrity_len(&integrity);
+    let tail_len = if text { hash_len * 2 + 2 } else { hash_len } as u64;
+    if offset < tail_len {
+        return Err(not_record_boundary);
+    }
+    let mut tail = vec![0u8; tail_len as usize];
+    file.seek(SeekFrom::Start(offset - tail_len))?;
+    file.read_exact(&mut tail)?;
+
+    let hash = if text {
+        let hex = match tail.strip_prefix(b" ").and_then(|tail| tail.strip_suffix(b"\n")) {
+            Some(hex) => hex::decode(hex),
+            None => return Err(not_record_boundary),
+        };
+        match hex {
+            Ok(hash) => hash,
+            Err(_) => return Err(not_record_boundary),
+        }
+    } else {
+        tail
+    };
+
+    continue_chain(&mut integrity, &hash);
+    Ok(Some(integrity))
+}
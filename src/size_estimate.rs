@@ -0,0 +1,79 @@
+use crate::bin_format::{bin_file_block_of_compressible_insert, bin_file_block_of_remove};
+use crate::cfg::Format;
+use crate::map_with_file::SerializedError;
+use crate::text_format::{file_line_of_remove, text_file_line_of_compressible_insert};
+use crate::Cfg;
+use serde::Serialize;
+
+/// Min, median and max of sizes in bytes, median of even count is the upper one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeStats {
+    /// Min size.
+    pub min: usize,
+    /// Median size.
+    pub median: usize,
+    /// Max size.
+    pub max: usize,
+}
+
+impl SizeStats {
+    /// Stats of sizes, all zero if there are no sizes.
+    fn of(mut sizes: Vec<usize>) -> Self {
+        sizes.sort_unstable();
+        match (sizes.first(), sizes.last()) {
+            (Some(&min), Some(&max)) => SizeStats { min, median: sizes[sizes.len() / 2], max },
+            _ => SizeStats::default(),
+        }
+    }
+}
+
+/// Sizes of records of samples returned by 'estimate_record_size'.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    /// Sizes of insert records.
+    pub insert: SizeStats,
+    /// Sizes of remove records.
+    pub remove: SizeStats,
+    /// Sizes of insert records without serialized key and value (JSON for the text format, bincode for the binary),
+    /// it's name of operation, separators, integrity and end of line or length of block.
+    /// Can be 0 if values are compressed because of 'Cfg::compress_values_over'.
+    pub insert_overhead: SizeStats,
+}
+
+/// Returns sizes of insert and remove records of samples in the format and integrity of 'cfg', for planning of file growth.
+/// Records are made by the same functions as records written by the map, but nothing is written.
+/// Before write callbacks of the format are not applied because they can't be called with shared 'cfg'.
+pub fn estimate_record_size<Key, Value>(samples: &[(Key, Value)], cfg: &Cfg) -> Result<SizeEstimate, SerializedError>
+where
+    Key: Serialize,
+    Value: Serialize,
+{
+    // integrity state of the config is not changed
+    let mut integrity = cfg.integrity.clone();
+    let mut insert_sizes = Vec::with_capacity(samples.len());
+    let mut remove_sizes = Vec::with_capacity(samples.len());
+    let mut overhead_sizes = Vec::with_capacity(samples.len());
+    for (key, value) in samples {
+        let (insert_size, remove_size, payload_size) = match cfg.format {
+            Format::Text(..) => (
+                text_file_line_of_compressible_insert(key, value, cfg.value_schema_version, cfg.compress_values_over, &mut integrity)?.len(),
+                file_line_of_remove(key, &mut integrity)?.len(),
+                serde_json::to_string(key)?.len() + serde_json::to_string(value)?.len(),
+            ),
+            Format::Bin(..) => (
+                bin_file_block_of_compressible_insert(key, value, cfg.value_schema_version, cfg.compress_values_over, &mut integrity)?.len(),
+                bin_file_block_of_remove(key, &mut integrity)?.len(),
+                bincode2::serialize(key)?.len() + bincode2::serialize(value)?.len(),
+            ),
+        };
+        insert_sizes.push(insert_size);
+        remove_sizes.push(remove_size);
+        overhead_sizes.push(insert_size.saturating_sub(payload_size));
+    }
+
+    Ok(SizeEstimate {
+        insert: SizeStats::of(insert_sizes),
+        remove: SizeStats::of(remove_sizes),
+        insert_overhead: SizeStats::of(overhead_sizes),
+    })
+}
@@ -0,0 +1,227 @@
+use crate::bin_format::load_bin_file_records;
+use crate::cfg::{Cfg, Format, WriteOperation};
+use crate::compaction::write_entries;
+use crate::format::{file_record_of_schema, LoadLimits, MapOperation};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use uuid::Uuid;
+
+/// Error of 'MapWithFile::checkpoint'.
+#[derive(Debug)]
+pub enum CheckpointError {
+    /// When 'Cfg::snapshot' is not set, the log is not cleared because the snapshot would not be loaded.
+    NoSnapshotCfg,
+    /// Error of writing of changes made before checkpoint, nothing is changed.
+    PendingWriteError(std::io::Error),
+    /// Error of serialization of key or value.
+    SerializeError(SerializedError),
+    /// When write error to the snapshot file.
+    WriteToFileError(std::io::Error),
+    /// Error of creating tmp file or of replacing the snapshot or the log with it.
+    TmpFileError,
+    /// The map is opened by 'MapWithFile::open_read_only', nothing is changed.
+    ReadOnly,
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl std::fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Returns path of the snapshot file of the map with the file, see 'Cfg::snapshot'.
+pub fn snapshot_path(file_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = file_path.as_ref().as_os_str().to_owned();
+    path.push(".snapshot");
+    PathBuf::from(path)
+}
+
+/// Header of the snapshot file with length of the log which operations are in the snapshot.
+/// It has fixed length, so it's overwritten in place when the log is cleared.
+fn snapshot_header(folded_log_len: u64) -> String {
+    format!("snapshot {:020}\n", folded_log_len)
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Write the map to the new snapshot file and clear the log, see 'Cfg::snapshot'. Changes made before are written first.
+    /// The snapshot is written in the format of config via tmp file, synced to disk and renamed over the old snapshot,
+    /// then the log is replaced with empty file. The snapshot keeps length of the log which operations are in it until the log is cleared,
+    /// so after crash between them the operations are skipped by the next opening instead of being applied twice.
+    /// Counts of operations of 'metrics' are counted from checkpoint. Nothing is changed if error is returned before renaming of the snapshot.
+    pub fn checkpoint(&mut self) -> Result<(), CheckpointError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("checkpoint", path = %self.file_path.display()).entered();
+
+        if self.cfg.get_mut().snapshot.is_none() {
+            return Err(CheckpointError::NoSnapshotCfg);
+        }
+        if self.file_worker.is_read_only() {
+            return Err(CheckpointError::ReadOnly);
+        }
+        self.flush().map_err(CheckpointError::PendingWriteError)?;
+
+        let folded_log_len = self.file_worker.counters().file_len.load(Ordering::Acquire);
+        let snapshot_path = snapshot_path(&self.file_path);
+        let (tmp_file_path, tmp_file) = create_tmp_file(&snapshot_path)?;
+
+        // chain of integrity of the snapshot starts from the hash of config, as chain of the new log
+        let integrity = std::mem::replace(&mut self.cfg.get_mut().integrity, self.integrity_at_file_start.clone());
+        let written = (&tmp_file).write_all(snapshot_header(folded_log_len).as_bytes()).map_err(CheckpointError::WriteToFileError)
+            .and_then(|()| write_entries(&self.map, self.cfg.get_mut(), &tmp_file).map_err(|err| match err {
+                crate::compaction::CompactError::SerializeError(err) => CheckpointError::SerializeError(err),
+                crate::compaction::CompactError::WriteToFileError(err) => CheckpointError::WriteToFileError(err),
+                _ => unreachable!(), // only errors of serialization and writing are returned by 'write_entries'
+            }))
+            .and_then(|_| tmp_file.sync_data().map_err(CheckpointError::WriteToFileError))
+            .and_then(|()| fs::rename(&tmp_file_path, &snapshot_path).map_err(|_| CheckpointError::TmpFileError));
+        self.cfg.get_mut().integrity = self.integrity_at_file_start.clone();
+        if let Err(err) = written {
+            self.cfg.get_mut().integrity = integrity;
+            let _ = fs::remove_file(&tmp_file_path);
+            return Err(err);
+        }
+        drop(tmp_file);
+
+        // operations of the log are skipped by opening until the log is replaced and the header is cleared
+        let (tmp_log_path, tmp_log) = create_tmp_file(&self.file_path)?;
+        fs::rename(&tmp_log_path, &self.file_path).map_err(|_| {
+            let _ = fs::remove_file(&tmp_log_path);
+            CheckpointError::TmpFileError
+        })?;
+        self.file_worker.replace_file(tmp_log, 0).map_err(CheckpointError::WriteToFileError)?;
+        self.save_chain_head_of_replaced_file(0).map_err(CheckpointError::WriteToFileError)?;
+        write_snapshot_header(&snapshot_path, 0).map_err(CheckpointError::WriteToFileError)?;
+
+        // schema fingerprint is the first record of the log, written after the header is cleared, so it's never skipped
+        if let Some(record) = file_record_of_schema(self.cfg.get_mut()).map_err(CheckpointError::SerializeError)? {
+            self.file_worker.write_bytes(record, WriteOperation::Schema).map_err(CheckpointError::WriteToFileError)?;
+        }
+        self.records_loaded = 0;
+        self.dead_records_loaded = 0;
+        self.operations_since_open = 0;
+        self.last_record = None;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(entries = self.map.len(), folded_log_len, "checkpoint written");
+
+        Ok(())
+    }
+
+    /// Checkpoint if it's time by 'SnapshotCfg::checkpoint_ops', error is kept for 'take_last_write_error'.
+    pub(crate) fn checkpoint_if_due(&mut self) {
+        let checkpoint_ops = match self.cfg.get_mut().snapshot.and_then(|snapshot| snapshot.checkpoint_ops) {
+            Some(checkpoint_ops) => checkpoint_ops as u64,
+            None => return,
+        };
+        let ops_logged = self.ops_logged();
+        if ops_logged < checkpoint_ops || ops_logged < self.checkpoint_not_before {
+            return;
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ops_logged, "automatic checkpoint");
+        if let Err(err) = self.checkpoint() {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %err, "automatic checkpoint error");
+            let error = match err {
+                CheckpointError::PendingWriteError(error) | CheckpointError::WriteToFileError(error) => error,
+                err => std::io::Error::other(err.to_string()),
+            };
+            self.file_worker.counters().set_last_write_error(&error);
+            self.checkpoint_not_before = ops_logged + checkpoint_ops;
+        }
+    }
+}
+
+/// Create and lock tmp file in the directory of the file for renaming over it.
+fn create_tmp_file(file_path: &Path) -> Result<(PathBuf, fs::File), CheckpointError> {
+    let mut tmp_file_path = file_path.as_os_str().to_owned();
+    tmp_file_path.push(format!(".{}.tmp", Uuid::new_v4()));
+    let tmp_file = fs::OpenOptions::new().read(true).append(true).create_new(true).open(&tmp_file_path)
+        .map_err(|_| CheckpointError::TmpFileError)?;
+    tmp_file.lock_exclusive().map_err(|_| CheckpointError::TmpFileError)?;
+    Ok((PathBuf::from(tmp_file_path), tmp_file))
+}
+
+/// Overwrite header of the snapshot in place, it's shorter than a disk sector, so it's not torn by crash.
+fn write_snapshot_header(snapshot_path: &Path, folded_log_len: u64) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(snapshot_path)?;
+    file.write_all(snapshot_header(folded_log_len).as_bytes())?;
+    file.sync_data()
+}
+
+/// Load entries of the snapshot of 'Cfg::snapshot' to the map when opening, the log is locked by caller.
+/// Returns offset of the log after operations which are already in the snapshot because checkpoint was interrupted,
+/// 0 if the whole log is after the snapshot or there is no snapshot.
+pub(crate) fn load_snapshot<Key, Value, Map>(map: &mut Map, file_path: &Path, cfg: &mut Cfg, log_len: u64) -> Result<u64, LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+    Map: MapTrait<Key, Value>,
+{
+    let snapshot_path = snapshot_path(file_path);
+    let mut reader = match fs::File::open(&snapshot_path) {
+        Ok(file) => BufReader::new(file),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let folded_log_len: u64 = header.strip_prefix("snapshot ")
+        .and_then(|header| header.strip_suffix('\n'))
+        .and_then(|folded_log_len| folded_log_len.parse().ok())
+        .filter(|_| header.len() == snapshot_header(0).len())
+        .ok_or(LoadFileError::WrongSnapshotHeader)?;
+
+    // records of the snapshot are not checked for replay anomalies, they are entries of the map
+    let mut integrity = cfg.integrity.clone();
+    let limits = LoadLimits { replay_check: None, file_path: Some(snapshot_path.clone()), file_offset: header.len() as u64, ..LoadLimits::of(cfg) };
+    let apply_map_operation = |map_operation| {
+        match map_operation {
+            MapOperation::Insert(key, value) => map.insert(key, value),
+            MapOperation::Remove(key) => map.remove(&key),
+        };
+        Ok(())
+    };
+    match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, apply_map_operation)?;
+        },
+        Format::Bin(_, after_read_callback) => {
+            load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, apply_map_operation)?;
+        },
+    }
+
+    // the log shorter than the header is already replaced by checkpoint interrupted before clearing of the header
+    if folded_log_len > log_len {
+        write_snapshot_header(&snapshot_path, 0)?;
+        return Ok(0);
+    }
+
+    Ok(folded_log_len)
+}
+
+/// Seek the log to the offset after operations which are in the snapshot and returns integrity state at the offset.
+pub(crate) fn skip_folded_log(log: &mut fs::File, cfg: &Cfg, folded_log_len: u64) -> Result<Option<crate::cfg::Integrity>, LoadFileError> {
+    let text = matches!(cfg.format, Format::Text(..));
+    let integrity = crate::ship_cursor::integrity_at(log, text, cfg.integrity.clone(), folded_log_len)?;
+    log.seek(SeekFrom::Start(folded_log_len))?;
+    Ok(integrity)
+}
@@ -0,0 +1,234 @@
+use crate::cfg::{Cfg, Format};
+use crate::format::MapOperation;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::text_format::load_from_text_file;
+use crate::bin_format::load_from_bin_file;
+use crate::LoadFileError;
+use rusqlite::Connection;
+use rusqlite::types::Value as SqlValue;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use fs2::FileExt;
+
+/// Count of rows inserted into the SQLite table in one transaction.
+const ROWS_IN_TRANSACTION: usize = 1000;
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Write current state of the map to the table of SQLite database.
+    /// Table is created if not exists with columns 'key' and 'value', where key and value
+    /// serialized as json text for the text format or bincode blob for the binary format.
+    pub fn export_sqlite(&self, db_path: impl AsRef<Path>, table: &str) -> Result<(), ExportSqliteError> {
+        let mut conn = Connection::open(db_path)?;
+        self.export_sqlite_to(&mut conn, table)
+    }
+
+    /// Same as 'export_sqlite' but write to the already opened SQLite database.
+    pub fn export_sqlite_to(&self, conn: &mut Connection, table: &str) -> Result<(), ExportSqliteError> {
+        let cfg = self.cfg.lock();
+        let column_type = column_type(&cfg.format);
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (key {} PRIMARY KEY NOT NULL, value {} NOT NULL);",
+            quote_identifier(table), column_type, column_type
+        ))?;
+
+        let mut inserter = BatchInserter::new(conn, format!("INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)", quote_identifier(table)));
+        let mut result = Ok(());
+        self.map().for_each(|key, value| {
+            if result.is_ok() {
+                result = serialize(key, &cfg.format)
+                    .and_then(|key| Ok((key, serialize(value, &cfg.format)?)))
+                    .map_err(ExportSqliteError::SerializeError)
+                    .and_then(|(key, value)| Ok(inserter.insert(&[key, value])?));
+            }
+        });
+
+        inserter.finish(result)
+    }
+}
+
+/// Write all operations from the history file to the table of SQLite database, one row per operation.
+/// Table is created if not exists with columns 'record_index' (from 1), 'op' ("ins" or "rem"), 'key' and 'value'
+/// (null for remove), where key and value serialized as json text for the text format
+/// or bincode blob for the binary format.
+pub fn export_history_sqlite<Key, Value>(file_path: impl AsRef<Path>, cfg: Cfg, db_path: impl AsRef<Path>, table: &str) -> Result<(), ExportSqliteError>
+where
+    Key: Serialize + DeserializeOwned,
+    Value: Serialize + DeserializeOwned,
+{
+    let mut conn = Connection::open(db_path)?;
+    export_history_sqlite_to::<Key, Value>(file_path, cfg, &mut conn, table)
+}
+
+/// Same as 'export_history_sqlite' but write to the already opened SQLite database.
+pub fn export_history_sqlite_to<Key, Value>(file_path: impl AsRef<Path>, mut cfg: Cfg, conn: &mut Connection, table: &str) -> Result<(), ExportSqliteError>
+where
+    Key: Serialize + DeserializeOwned,
+    Value: Serialize + DeserializeOwned,
+{
+    let mut file = fs::OpenOptions::new().read(true).open(file_path)
+        .map_err(ExportSqliteError::OpenFileError)?;
+    FileExt::lock_shared(&file)
+        .map_err(|_| ExportSqliteError::LockFileError)?;
+
+    let column_type = column_type(&cfg.format);
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (record_index INTEGER PRIMARY KEY NOT NULL, op TEXT NOT NULL, key {} NOT NULL, value {});",
+        quote_identifier(table), column_type, column_type
+    ))?;
+
+    let mut inserter = BatchInserter::new(conn, format!("INSERT INTO {} (record_index, op, key, value) VALUES (?1, ?2, ?3, ?4)", quote_identifier(table)));
+    let mut export_err: Option<ExportSqliteError> = None;
+    let mut record_index: i64 = 0;
+
+    let format = match &cfg.format {
+        Format::Text(..) => Format::Text(None, None),
+        Format::Bin(..) => Format::Bin(None, None),
+    };
+
+    let process_map_operation = |map_operation| {
+        record_index += 1;
+        let row = match map_operation {
+            MapOperation::Insert(key, value) => {
+                serialize(&key, &format)
+                    .and_then(|key| Ok(("ins", key, serialize(&value, &format)?)))
+            },
+            MapOperation::Remove(key) => {
+                serialize(&key, &format)
+                    .map(|key| ("rem", key, SqlValue::Null))
+            },
+        };
+
+        let res = row
+            .map_err(ExportSqliteError::SerializeError)
+            .and_then(|(op, key, value)| {
+                Ok(inserter.insert(&[SqlValue::Integer(record_index), SqlValue::Text(op.to_string()), key, value])?)
+            });
+
+        if let Err(err) = res {
+            export_err = Some(err);
+            return Err(());
+        }
+
+        Ok(())
+    };
+
+    let load_result = match cfg.format {
+        Format::Text(_, after_read_callback) => {
+            load_from_text_file::<Key, Value, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback, process_map_operation)
+        },
+        Format::Bin(_, after_read_callback) => {
+            load_from_bin_file::<Key, Value, _, _, _>(&mut file, &mut cfg.integrity, after_read_callback, process_map_operation)
+        },
+    };
+
+    let result = match (load_result, export_err) {
+        (_, Some(err)) => Err(err),
+        (Err(err), None) => Err(ExportSqliteError::LoadFileError(err)),
+        (Ok(()), None) => Ok(()),
+    };
+
+    inserter.finish(result)
+}
+
+/// Inserts rows with one prepared statement, committing a transaction every 'ROWS_IN_TRANSACTION' rows.
+struct BatchInserter<'a> {
+    conn: &'a Connection,
+    sql: String,
+    rows_in_transaction: usize,
+}
+
+impl<'a> BatchInserter<'a> {
+    fn new(conn: &'a Connection, sql: String) -> Self {
+        BatchInserter { conn, sql, rows_in_transaction: 0 }
+    }
+
+    /// Insert row, opening new transaction if needed.
+    fn insert(&mut self, row: &[SqlValue]) -> Result<(), rusqlite::Error> {
+        if self.rows_in_transaction == 0 {
+            self.conn.execute_batch("BEGIN")?;
+        }
+
+        self.conn.prepare_cached(&self.sql)?
+            .execute(rusqlite::params_from_iter(row.iter()))?;
+
+        self.rows_in_transaction += 1;
+        if self.rows_in_transaction == ROWS_IN_TRANSACTION {
+            self.conn.execute_batch("COMMIT")?;
+            self.rows_in_transaction = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Commit last transaction if 'result' is ok, otherwise rollback it.
+    fn finish(self, result: Result<(), ExportSqliteError>) -> Result<(), ExportSqliteError> {
+        if self.rows_in_transaction > 0 {
+            if result.is_ok() {
+                self.conn.execute_batch("COMMIT")?;
+            } else {
+                // result already contains the error, so rollback error is ignored
+                let _ = self.conn.execute_batch("ROLLBACK");
+            }
+        }
+
+        result
+    }
+}
+
+/// Type of key and value columns depending on format of history file.
+fn column_type(format: &Format) -> &'static str {
+    match format {
+        Format::Text(..) => "TEXT",
+        Format::Bin(..) => "BLOB",
+    }
+}
+
+/// Serialize to json text for the text format or bincode blob for the binary format.
+fn serialize<T: Serialize>(data: &T, format: &Format) -> Result<SqlValue, SerializedError> {
+    Ok(match format {
+        Format::Text(..) => SqlValue::Text(serde_json::to_string(data)?),
+        Format::Bin(..) => SqlValue::Blob(bincode2::serialize(data)?),
+    })
+}
+
+/// Quote table name for using in sql query.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Error of export to SQLite database.
+#[derive(Debug)]
+pub enum ExportSqliteError {
+    /// When can't open history file.
+    OpenFileError(std::io::Error),
+    /// When can't shared lock opened history file.
+    LockFileError,
+    /// Error of reading history file.
+    LoadFileError(LoadFileError),
+    /// Error of serialization of key or value.
+    SerializeError(SerializedError),
+    /// Error of SQLite database.
+    SqliteError(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for ExportSqliteError {
+    fn from(err: rusqlite::Error) -> Self {
+        ExportSqliteError::SqliteError(err)
+    }
+}
+
+impl std::error::Error for ExportSqliteError {}
+
+impl std::fmt::Display for ExportSqliteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
@@ -0,0 +1,103 @@
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+/// Capacity of the channel returned by 'MapWithFile::watch'.
+pub const WATCH_CHANNEL_CAPACITY: usize = 1024;
+
+/// Change of the map passed to subscribers.
+/// Events are fired only for operations written to the file, in the same order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent<Key, Value> {
+    /// Value is inserted, 'old' is replaced value if it was.
+    Inserted { key: Key, new: Value, old: Option<Value> },
+    /// Value is removed.
+    Removed { key: Key, old: Value },
+}
+
+/// Identifier of the subscription for 'MapWithFile::unsubscribe'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+/// Callback of the subscription.
+type ChangeCallback<Key, Value> = Box<dyn FnMut(&ChangeEvent<Key, Value>) + Send>;
+
+impl<Key, Value, Map> MapWithFile<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Subscribe to changes of the map. Callback is called synchronously inside insert/remove
+    /// after change of the map and indexes, so it should be fast and must not block on this map.
+    pub fn subscribe(&mut self, f: impl FnMut(&ChangeEvent<Key, Value>) + Send + 'static) -> SubscriptionId {
+        let subscribers = self.subscribers_mut();
+        let id = SubscriptionId(subscribers.next_id);
+        subscribers.next_id += 1;
+        subscribers.callbacks.push((id, Box::new(f)));
+        id
+    }
+
+    /// Remove subscription. Returns false if there is no subscription with this id.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let callbacks = &mut self.subscribers_mut().callbacks;
+        let len = callbacks.len();
+        callbacks.retain(|(callback_id, _)| *callback_id != id);
+        callbacks.len() != len
+    }
+
+    /// Returns receiver of changes of the map for consuming in other thread.
+    /// Channel is bounded by 'WATCH_CHANNEL_CAPACITY' events. If receiver doesn't keep up and channel
+    /// is full, new events are dropped for this receiver (the map is never blocked by slow receiver),
+    /// so use 'subscribe' if every event is needed. Watching stops when receiver is dropped.
+    pub fn watch(&mut self) -> Receiver<ChangeEvent<Key, Value>> {
+        let (sender, receiver) = sync_channel(WATCH_CHANNEL_CAPACITY);
+        self.subscribers_mut().watchers.push(sender);
+        receiver
+    }
+
+    /// Subscribers without locking because of exclusive access.
+    fn subscribers_mut(&mut self) -> &mut Subscribers<Key, Value> {
+        self.subscribers.get_mut()
+            .unwrap_or_else(|err| err.into_inner()) // lock is poisoned only by panic in subscriber callback, subscribers are still usable in this case
+    }
+}
+
+/// Subscribers of changes of the map.
+pub(crate) struct Subscribers<Key, Value> {
+    /// Id of the next subscription.
+    next_id: u64,
+    /// Callbacks from 'subscribe'.
+    callbacks: Vec<(SubscriptionId, ChangeCallback<Key, Value>)>,
+    /// Senders of channels from 'watch'.
+    watchers: Vec<SyncSender<ChangeEvent<Key, Value>>>,
+}
+
+impl<Key, Value> Subscribers<Key, Value>
+where Key: Clone, Value: Clone {
+    /// Pass event to all subscribers, event is made only if there are subscribers.
+    pub fn notify(&mut self, make_event: impl FnOnce() -> ChangeEvent<Key, Value>) {
+        if self.is_empty() {
+            return;
+        }
+
+        let event = make_event();
+        for (_, callback) in self.callbacks.iter_mut() {
+            callback(&event);
+        }
+
+        self.watchers.retain(|sender| {
+            match sender.try_send(event.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Returns true if there are no subscribers.
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty() && self.watchers.is_empty()
+    }
+}
+
+impl<Key, Value> Default for Subscribers<Key, Value> {
+    fn default() -> Self {
+        Subscribers { next_id: 0, callbacks: Vec::new(), watchers: Vec::new() }
+    }
+}
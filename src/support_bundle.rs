@@ -0,0 +1,289 @@
+use crate::bin_format::{bin_insert_value_data, bin_integrity_len, bin_record_kind_and_key, process_block_integrity, read_bin_block_len, split_bin_record_meta, CountingReader};
+use crate::cfg::{Cfg, Format, Integrity};
+use crate::format::{continue_chain, RawMeta, RecordKind};
+use crate::text_format::{process_line_integrity, split_text_record_meta, text_insert_value, text_record_kind_and_key};
+use crate::LoadFileError;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// What is hidden in the support bundle, see 'export_support_bundle'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleRedaction {
+    /// Values, keys and metadata are copied as is.
+    None,
+    /// Values and metadata are replaced with their length and sha256 hash, keys are copied as is.
+    Values,
+    /// Values, metadata and keys are replaced with their length and sha256 hash.
+    ValuesAndKeys,
+}
+
+/// Summary of the support bundle, it's also written in the header of the bundle.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SupportBundleSummary {
+    /// Count of exported records.
+    pub records: usize,
+    /// Size of exported records in the file.
+    pub total_bytes: u64,
+    /// Number of the first record with integrity error or the record where reading stopped, line or block number.
+    pub first_failing_record: Option<usize>,
+    /// Error of the first failing record.
+    pub failure: Option<String>,
+}
+
+/// Error of 'export_support_bundle'.
+#[derive(Debug)]
+pub enum SupportBundleError {
+    /// When can't open the file.
+    OpenFileError(std::io::Error),
+    /// When write error to the bundle.
+    WriteError(std::io::Error),
+}
+
+impl std::error::Error for SupportBundleError {}
+
+impl std::fmt::Display for SupportBundleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Export history file record by record for sending with report of corruption, values can be hidden by 'redaction'.
+/// The bundle is text: header lines starting with "# " (format, integrity, count and size of records, first failing record),
+/// then one JSON object per record with its number, offset, length, kind, key, value, metadata and integrity tag as in the file.
+/// Key is needed only for the binary format, for finding of the value in block.
+/// Reading is lenient: after integrity error the chain continues from the hash in the file, so all records are exported,
+/// reading stops only if records can't be separated (for example wrong length of block or line without end).
+/// After read callback of the format is applied to the records, the file is not locked.
+pub fn export_support_bundle<Key>(file_path: impl AsRef<Path>, mut cfg: Cfg, mut out: impl Write, redaction: BundleRedaction) -> Result<SupportBundleSummary, SupportBundleError>
+where
+    Key: Serialize + DeserializeOwned,
+{
+    let file = fs::File::open(file_path).map_err(SupportBundleError::OpenFileError)?;
+    let mut summary = SupportBundleSummary::default();
+    let mut records = Vec::new();
+    let integrity_name = cfg.integrity.as_ref().map(Integrity::name).unwrap_or("none");
+
+    let format_name = match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            export_text_records(file, &mut cfg.integrity, after_read_callback, redaction, &mut summary, &mut records);
+            "text"
+        },
+        Format::Bin(_, after_read_callback) => {
+            export_bin_records::<Key>(file, &mut cfg.integrity, after_read_callback, redaction, &mut summary, &mut records);
+            "bin"
+        },
+    };
+
+    let first_failing_record = match (summary.first_failing_record, &summary.failure) {
+        (Some(num), Some(failure)) => format!("{} {}", num, failure),
+        _ => "none".to_string(),
+    };
+    let header = format!("# diskomap support bundle\n# format: {}\n# integrity: {}\n# redaction: {:?}\n# records: {}\n# total bytes: {}\n# first failing record: {}\n",
+        format_name, integrity_name, redaction, summary.records, summary.total_bytes, first_failing_record);
+    out.write_all(header.as_bytes()).map_err(SupportBundleError::WriteError)?;
+    for record in records {
+        writeln!(out, "{}", record).map_err(SupportBundleError::WriteError)?;
+    }
+    out.flush().map_err(SupportBundleError::WriteError)?;
+
+    Ok(summary)
+}
+
+/// Export lines of text file to 'records', reading stops at the first line that can't be read.
+fn export_text_records(
+    file: fs::File,
+    integrity: &mut Option<Integrity>,
+    after_read_callback: &mut Option<crate::cfg::AfterReadTxtCallback>,
+    redaction: BundleRedaction,
+    summary: &mut SupportBundleSummary,
+    records: &mut Vec<serde_json::Value>,
+) {
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut offset = 0;
+    for line_num in 1.. {
+        line.clear();
+        let line_len = match reader.read_line(&mut line) {
+            Ok(0) => return,
+            Ok(line_len) => line_len as u64,
+            Err(err) => return summary.fail(line_num, LoadFileError::FileError(err)),
+        };
+        if let Some(callback) = after_read_callback {
+            if let Err(err) = callback(&mut line) {
+                return summary.fail(line_num, LoadFileError::InterruptedWithBeforeReadCallback(err));
+            }
+        }
+        if !line.ends_with('\n') {
+            return summary.fail(line_num, LoadFileError::LastLineWithoutEndLine { line_num });
+        }
+
+        let (line_data, tag) = match integrity {
+            Some(integrity) => {
+                let tag_index = line.rfind(' ').unwrap_or(0);
+                let tag = line[tag_index..].trim();
+                if let Err(err) = process_line_integrity(&line, integrity, line_num) {
+                    summary.fail(line_num, LoadFileError::IntegrityError(err));
+                    continue_chain(integrity, &hex::decode(tag).unwrap_or_default());
+                }
+                (&line[..tag_index], Some(tag.to_string()))
+            },
+            None => (line.trim_end_matches('\n'), None),
+        };
+
+        let (meta, data) = split_text_record_meta(line_data, line_num).unwrap_or((None, line_data));
+        let record = match text_record_kind_and_key::<serde_json::Value>(data, line_num) {
+            Ok((kind, key)) => {
+                let value = match kind {
+                    RecordKind::Insert => text_insert_value(data).map(|value| redacted_or(value.to_string().as_bytes(), value, redaction)),
+                    _ => record_payload(kind, data.get(4..).unwrap_or_default().as_bytes(), redaction),
+                };
+                let key = key.map(|key| key_redacted_or(key.to_string().as_bytes(), key, redaction));
+                bundle_record(line_num, offset, line_len, &format!("{:?}", kind), key, value)
+            },
+            Err(_) => bundle_record(line_num, offset, line_len, "Unknown", None, Some(redacted_or(data.as_bytes(), json!(data), redaction))),
+        };
+        records.push(with_meta_and_tag(record, meta, tag, redaction));
+        summary.add(line_len);
+        offset += line_len;
+    }
+}
+
+/// Export blocks of binary file to 'records', reading stops at the first block that can't be read.
+fn export_bin_records<Key>(
+    file: fs::File,
+    integrity: &mut Option<Integrity>,
+    after_read_callback: &mut Option<crate::cfg::AfterReadBinCallback>,
+    redaction: BundleRedaction,
+    summary: &mut SupportBundleSummary,
+    records: &mut Vec<serde_json::Value>,
+)
+where
+    Key: Serialize + DeserializeOwned,
+{
+    let mut reader = CountingReader { reader: BufReader::new(file), read_len: 0 };
+    for block_num in 1.. {
+        let offset = reader.read_len;
+        let block_len = match read_bin_block_len(&mut reader) {
+            Ok(0) => return,
+            Ok(block_len) => block_len,
+            Err(err) => return summary.fail(block_num, err),
+        };
+        let mut block = vec![0; block_len];
+        if let Err(err) = reader.read_exact(&mut block) {
+            return summary.fail(block_num, LoadFileError::FileError(err));
+        }
+        let record_len = reader.read_len - offset;
+        if let Some(callback) = after_read_callback {
+            if let Err(err) = callback(&mut block) {
+                return summary.fail(block_num, LoadFileError::InterruptedWithBeforeReadCallback(err));
+            }
+        }
+
+        let (data_block, tag) = match integrity {
+            Some(integrity) => {
+                let data_len = block.len().saturating_sub(bin_integrity_len(integrity));
+                if let Err(err) = process_block_integrity(&mut block.clone(), integrity, block_num) {
+                    summary.fail(block_num, LoadFileError::IntegrityError(err));
+                    continue_chain(integrity, &block[data_len..]);
+                }
+                (&block[..data_len], Some(hex::encode(&block[data_len..])))
+            },
+            None => (&block[..], None),
+        };
+
+        let (meta, data) = if data_block.is_empty() { (None, data_block) } else { split_bin_record_meta(data_block).unwrap_or((None, data_block)) };
+        let record = match (data.is_empty(), bin_record_kind_and_key::<Key>(data, block_num)) {
+            (false, Ok((kind, key))) => {
+                let value = match kind {
+                    RecordKind::Insert => bin_insert_value_data::<Key>(data).map(|value| redacted_or(value, json!(hex::encode(value)), redaction)),
+                    _ => record_payload(kind, &data[1..], redaction),
+                };
+                let key = key.and_then(|key| serde_json::to_value(&key).ok())
+                    .map(|key| key_redacted_or(key.to_string().as_bytes(), key, redaction));
+                bundle_record(block_num, offset, record_len, &format!("{:?}", kind), key, value)
+            },
+            _ => bundle_record(block_num, offset, record_len, "Unknown", None, Some(redacted_or(data, json!(hex::encode(data)), redaction))),
+        };
+        records.push(with_meta_and_tag(record, meta, tag, redaction));
+        summary.add(record_len);
+    }
+}
+
+impl SupportBundleSummary {
+    /// Count exported record.
+    fn add(&mut self, len: u64) {
+        self.records += 1;
+        self.total_bytes += len;
+    }
+
+    /// Remember error of the record if it's the first error.
+    fn fail(&mut self, num: usize, err: LoadFileError) {
+        if self.first_failing_record.is_none() {
+            self.first_failing_record = Some(num);
+            self.failure = Some(err.to_string());
+        }
+    }
+}
+
+/// Returns value of record other than insert, it's data after code of operation, None for records without value.
+fn record_payload(kind: RecordKind, payload: &[u8], redaction: BundleRedaction) -> Option<serde_json::Value> {
+    match kind {
+        RecordKind::Batch | RecordKind::PushItems | RecordKind::RemoveItems | RecordKind::Increment =>
+            Some(redacted_or(payload, json!(String::from_utf8(payload.to_vec()).unwrap_or_else(|_| hex::encode(payload))), redaction)),
+        _ => None,
+    }
+}
+
+/// Returns length and hash of 'data' if values are redacted, otherwise 'value'.
+fn redacted_or(data: &[u8], value: serde_json::Value, redaction: BundleRedaction) -> serde_json::Value {
+    match redaction {
+        BundleRedaction::None => value,
+        BundleRedaction::Values | BundleRedaction::ValuesAndKeys => redacted(data),
+    }
+}
+
+/// Returns length and hash of 'data' if keys are redacted, otherwise 'key'.
+fn key_redacted_or(data: &[u8], key: serde_json::Value, redaction: BundleRedaction) -> serde_json::Value {
+    match redaction {
+        BundleRedaction::None | BundleRedaction::Values => key,
+        BundleRedaction::ValuesAndKeys => redacted(data),
+    }
+}
+
+/// Length and sha256 hash of data instead of the data.
+fn redacted(data: &[u8]) -> serde_json::Value {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    json!({ "len": data.len(), "sha256": hasher.result_str() })
+}
+
+/// JSON object of record of the bundle.
+fn bundle_record(num: usize, offset: u64, len: u64, kind: &str, key: Option<serde_json::Value>, value: Option<serde_json::Value>) -> serde_json::Value {
+    let mut record = json!({ "num": num, "offset": offset, "len": len, "kind": kind });
+    if let Some(key) = key {
+        record["key"] = key;
+    }
+    if let Some(value) = value {
+        record["value"] = value;
+    }
+    record
+}
+
+/// Add metadata (redacted as value) and integrity tag to record of the bundle.
+fn with_meta_and_tag(mut record: serde_json::Value, meta: Option<RawMeta<'_>>, tag: Option<String>, redaction: BundleRedaction) -> serde_json::Value {
+    match meta {
+        Some(RawMeta::Json(meta)) => record["meta"] = redacted_or(meta.as_bytes(), json!(meta), redaction),
+        Some(RawMeta::Bin(meta)) => record["meta"] = redacted_or(meta, json!(hex::encode(meta)), redaction),
+        None => {},
+    }
+    if let Some(tag) = tag {
+        record["integrity"] = json!(tag);
+    }
+    record
+}
@@ -0,0 +1,355 @@
+use crate::bin_format::load_bin_file_records;
+use crate::cfg::{Cfg, Format};
+use crate::file_worker::WorkerFile;
+use crate::format::{LoadLimits, MapOperation};
+use crate::text_format::load_text_file_records;
+use crate::LoadFileError;
+use serde::de::DeserializeOwned;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub use crate::clock::{Clock, SystemClock};
+
+/// Deterministic clock changed only by 'advance', 'set' and 'sleep', for example as 'Cfg::clock'. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    /// Current time shared by clones.
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    /// Clock with time 'start'.
+    pub fn new(start: SystemTime) -> Self {
+        ManualClock { now: Arc::new(Mutex::new(start)) }
+    }
+
+    /// Move time forward by 'duration'.
+    pub fn advance(&self, duration: Duration) {
+        *self.lock() += duration;
+    }
+
+    /// Set current time, it can be earlier than the current.
+    pub fn set(&self, now: SystemTime) {
+        *self.lock() = now;
+    }
+
+    /// Lock of current time.
+    fn lock(&self) -> std::sync::MutexGuard<'_, SystemTime> {
+        self.now.lock()
+            .unwrap_or_else(|err| err.into_inner()) // time is always valid, poisoned only by panic of other thread
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.lock()
+    }
+
+    /// Move time forward by 'duration' without waiting.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Switch of failures of 'FailingWriter' at any moment of the test. Clones switch the same writers.
+#[derive(Debug, Clone, Default)]
+pub struct FailSwitch {
+    /// Count of the next failing operations, 'usize::MAX' while it's on.
+    failures: Arc<AtomicUsize>,
+}
+
+impl FailSwitch {
+    /// Switch that is off.
+    pub fn new() -> Self {
+        FailSwitch::default()
+    }
+
+    /// All operations fail until 'off'.
+    pub fn on(&self) {
+        self.failures.store(usize::MAX, Ordering::Relaxed);
+    }
+
+    /// Operations don't fail.
+    pub fn off(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Only the next 'count' operations fail.
+    pub fn fail_next(&self, count: usize) {
+        self.failures.store(count, Ordering::Relaxed);
+    }
+
+    /// Count of the next failing operations, 'usize::MAX' while it's on.
+    pub fn remaining(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the operation fails and counts the failure.
+    fn fails(&self) -> bool {
+        self.failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |failures| match failures {
+            0 => None,
+            usize::MAX => Some(usize::MAX),
+            failures => Some(failures - 1),
+        }).is_ok()
+    }
+}
+
+/// Writer that fails every Nth write, writes after byte budget or writes or syncs by 'FailSwitch',
+/// for example for 'Cfg::secondary_sink' or for the file of the map in tests of this crate.
+/// Failed write writes nothing to the wrapped writer, or half of data after 'partial_writes'.
+pub struct FailingWriter<W> {
+    /// Wrapped writer.
+    inner: W,
+    /// Every Nth write fails.
+    fail_every: Option<usize>,
+    /// Writes fail when count of written bytes would exceed the budget.
+    byte_budget: Option<u64>,
+    /// Writes fail while the switch is on.
+    write_switch: Option<FailSwitch>,
+    /// Syncs of the file fail while the switch is on.
+    sync_switch: Option<FailSwitch>,
+    /// Truncates of the file fail while the switch is on.
+    truncate_switch: Option<FailSwitch>,
+    /// Failed write writes half of data before error.
+    partial_writes: bool,
+    /// Count of calls of 'write'.
+    writes: usize,
+    /// Count of bytes written to the wrapped writer.
+    written: u64,
+}
+
+impl<W: Write> FailingWriter<W> {
+    /// Every 'n'th write fails (1 for all writes), other writes go to 'inner'.
+    pub fn fail_every(inner: W, n: usize) -> Self {
+        FailingWriter { fail_every: Some(n.max(1)), ..FailingWriter::new(inner) }
+    }
+
+    /// Writes go to 'inner' until count of written bytes would exceed 'budget', then all writes fail.
+    pub fn fail_after_bytes(inner: W, budget: u64) -> Self {
+        FailingWriter { byte_budget: Some(budget), ..FailingWriter::new(inner) }
+    }
+
+    /// Writes fail by 'switch', other writes go to 'inner'.
+    pub fn fail_when(inner: W, switch: FailSwitch) -> Self {
+        FailingWriter { write_switch: Some(switch), ..FailingWriter::new(inner) }
+    }
+
+    /// Writes go to 'inner', syncs of the file fail by 'switch'.
+    pub fn fail_sync_when(inner: W, switch: FailSwitch) -> Self {
+        FailingWriter { sync_switch: Some(switch), ..FailingWriter::new(inner) }
+    }
+
+    /// Failed writes write half of data to the wrapped writer before error, as interrupted write.
+    pub fn partial_writes(self) -> Self {
+        FailingWriter { partial_writes: true, ..self }
+    }
+
+    /// Truncates of the file fail by 'switch', for example for removing of partially written data.
+    pub fn fail_truncate_when(self, switch: FailSwitch) -> Self {
+        FailingWriter { truncate_switch: Some(switch), ..self }
+    }
+
+    /// Returns the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writer without failures.
+    fn new(inner: W) -> Self {
+        FailingWriter { inner, fail_every: None, byte_budget: None, write_switch: None, sync_switch: None, truncate_switch: None, partial_writes: false, writes: 0, written: 0 }
+    }
+
+    /// Write half of data if it's set by 'partial_writes' and return error.
+    fn fail(&mut self, buf: &[u8], message: &str) -> std::io::Result<usize> {
+        if self.partial_writes {
+            self.inner.write_all(&buf[..buf.len() / 2])?;
+            self.written += (buf.len() / 2) as u64;
+        }
+        Err(std::io::Error::other(message.to_string()))
+    }
+}
+
+impl<W: Write> Write for FailingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        if matches!(self.fail_every, Some(n) if self.writes % n == 0) || self.write_switch.as_ref().is_some_and(FailSwitch::fails) {
+            return self.fail(buf, "injected write failure");
+        }
+        if matches!(self.byte_budget, Some(budget) if self.written + buf.len() as u64 > budget) {
+            return self.fail(buf, "injected write failure, byte budget is exceeded");
+        }
+
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: WorkerFile> WorkerFile for FailingWriter<W> {
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        if self.truncate_switch.as_ref().is_some_and(FailSwitch::fails) {
+            return Err(std::io::Error::other("injected truncate failure"));
+        }
+        self.inner.truncate(len)
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        if self.sync_switch.as_ref().is_some_and(FailSwitch::fails) {
+            return Err(std::io::Error::other("injected sync failure"));
+        }
+        self.inner.sync_data()
+    }
+}
+
+/// Writer that sleeps before each write, for example for tests of pending writes.
+pub struct SlowWriter<W> {
+    /// Wrapped writer.
+    inner: W,
+    /// Sleep before each write.
+    delay: Duration,
+}
+
+impl<W: Write> SlowWriter<W> {
+    /// Writes go to 'inner' after sleep for 'delay'.
+    pub fn new(inner: W, delay: Duration) -> Self {
+        SlowWriter { inner, delay }
+    }
+
+    /// Returns the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for SlowWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        std::thread::sleep(self.delay);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: WorkerFile> WorkerFile for SlowWriter<W> {
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.inner.truncate(len)
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        self.inner.sync_data()
+    }
+}
+
+/// In-memory buffer, clones write to the same buffer, for example as 'Cfg::secondary_sink'
+/// and 'SharedBuffer::reader' for reading written records without the file system.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer {
+    /// Written bytes shared by clones.
+    data: Arc<Mutex<Vec<u8>>>,
+    /// Count of syncs of the buffer used as the file of the map, shared by clones.
+    syncs: Arc<AtomicUsize>,
+}
+
+impl SharedBuffer {
+    /// Empty buffer.
+    pub fn new() -> Self {
+        SharedBuffer::default()
+    }
+
+    /// Returns copy of written bytes.
+    pub fn contents(&self) -> Vec<u8> {
+        self.lock().clone()
+    }
+
+    /// Reader of the buffer from the beginning, bytes written after creation of the reader are also read.
+    pub fn reader(&self) -> SharedBufferReader {
+        SharedBufferReader { buffer: self.clone(), pos: 0 }
+    }
+
+    /// Count of syncs of the buffer used as the file of the map by 'FsyncPolicy'.
+    pub fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Lock of written bytes.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.data.lock()
+            .unwrap_or_else(|err| err.into_inner()) // bytes are always valid, poisoned only by panic of other thread
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WorkerFile for SharedBuffer {
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.lock().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Reader of 'SharedBuffer'.
+#[derive(Debug, Clone)]
+pub struct SharedBufferReader {
+    /// Read buffer.
+    buffer: SharedBuffer,
+    /// Position of the next read byte.
+    pos: usize,
+}
+
+impl Read for SharedBufferReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.buffer.lock();
+        let remaining = data.get(self.pos..).unwrap_or_default();
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Load all operations of history from 'reader' in the format and integrity of 'cfg', for example from 'SharedBuffer'.
+/// Operations of batches are returned separately, records of incomplete transaction are discarded.
+pub fn load_operations<Key, Value>(mut reader: impl Read, mut cfg: Cfg) -> Result<Vec<MapOperation<Key, Value>>, LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+{
+    let mut operations = Vec::new();
+    let collect_map_operation = |map_operation| {
+        operations.push(map_operation);
+        Ok(())
+    };
+    let limits = LoadLimits::of(&cfg);
+    match &mut cfg.format {
+        Format::Text(_, after_read_callback) => {
+            load_text_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, collect_map_operation)
+        },
+        Format::Bin(_, after_read_callback) => {
+            load_bin_file_records::<Key, Value, MapOperation<Key, Value>, _, _, _>(&mut reader, &mut cfg.integrity, after_read_callback.as_mut(), cfg.value_schema_version, cfg.value_migrator.as_mut(), limits, collect_map_operation)
+        },
+    }?;
+
+    Ok(operations)
+}
@@ -4,20 +4,33 @@ mod tests {
     use crate::cfg::Cfg;
     use std::io::Write;
     use crate::format::{LoadFileError, MapOperation, IntegrityError};
-    use crate::map_with_file::HashMap;
+    use crate::map_with_file::{HashMap, SerializedError};
     use uuid::Uuid;
     use crate::cfg::Format;
+    use crate::cfg::WriteChannel;
+    use crate::cfg::DeserializePolicy;
+    use crate::cfg::WriteMode;
+    use crate::cfg::FsyncPolicy;
+    use crate::cfg::RecoveryMode;
 
     #[test]
     fn common() -> Result<(), Box<dyn std::error::Error>> {
+        for write_channel in write_channels() {
+            common_with_channel(write_channel)?;
+        }
+
+        Ok(())
+    }
+
+    fn common_with_channel(write_channel: WriteChannel) -> Result<(), Box<dyn std::error::Error>> {
         // new file
         let file = tmp_file()?;
-        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut map = BTreeMap::open_or_create(&file, channel_cfg(write_channel))?;
         map.insert((), ())?;
         drop(map);
 
         // after restart
-        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut map = BTreeMap::open_or_create(&file, channel_cfg(write_channel))?;
         assert_eq!(Some(&()), map.get(&()));
         map.insert((), ())?;
         assert_eq!(1, map.map().len());
@@ -32,7 +45,7 @@ mod tests {
 
         // new log file
         let file = tmp_file()?;
-        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut map = BTreeMap::open_or_create(&file, channel_cfg(write_channel))?;
         map.insert("key 1".to_string(), 1)?;
         map.insert("key 2".to_string(), 2)?;
         map.insert("key 3".to_string(), 3)?;
@@ -53,7 +66,7 @@ mod tests {
         drop(map);
 
         // after restart
-        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut map = BTreeMap::open_or_create(&file, channel_cfg(write_channel))?;
         assert_eq!(5, map.map().len());
         assert_eq!(Some(&100), map.get(&"key 1".to_string()));
         assert_eq!(None, map.get(&"key 4".to_string()));
@@ -64,7 +77,7 @@ mod tests {
         drop(map);
 
         // after restart
-        let map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let map = BTreeMap::open_or_create(&file, channel_cfg(write_channel))?;
         assert_eq!(4, map.map().len());
         assert_eq!(Some(&33), map.get(&"key 3".to_string()));
         assert_eq!(None, map.get(&"key 1".to_string()));
@@ -172,12 +185,19 @@ mod tests {
 
     #[test]
     fn crc32_integrity() -> Result<(), Box<dyn std::error::Error>> {
+        for write_channel in write_channels() {
+            crc32_integrity_with_channel(write_channel)?;
+        }
+
+        Ok(())
+    }
+
+    fn crc32_integrity_with_channel(write_channel: WriteChannel) -> Result<(), Box<dyn std::error::Error>> {
         use crate::Integrity;
         use crate::BTreeMap;
         use std::fs::OpenOptions;
-        use crate::cfg::*;
 
-        let mut cfg = Cfg::default();
+        let mut cfg = channel_cfg(write_channel);
         cfg.integrity = Some(Integrity::Crc32);
         let file = tmp_file()?;
         let mut map = BTreeMap::open_or_create(&file, cfg)?;
@@ -189,7 +209,7 @@ mod tests {
         let expected_content = "ins [0,\"a\"] 1874290170\nins [3,\"b\"] 3949308173\nins [5,\"c\"] 1023287335\n";
         assert_eq!(file_content, expected_content);
 
-        let mut cfg = Cfg::default();
+        let mut cfg = channel_cfg(write_channel);
         cfg.integrity = Some(Integrity::Crc32);
         let mut map: HashMap<i32, String> = HashMap::open_or_create(&file, cfg)?;
         map.remove(&3)?;
@@ -204,12 +224,12 @@ mod tests {
         f.write_all(bad_content.as_bytes())?;
         drop(f);
 
-        let mut cfg = Cfg::default();
+        let mut cfg = channel_cfg(write_channel);
         cfg.integrity = Some(Integrity::Crc32);
         let res: Result<BTreeMap<i32, String>, LoadFileError> = BTreeMap::open_or_create(&file, cfg);
         let mut crc_is_correct = true;
         if let Err(res) = res {
-            if let LoadFileError::IntegrityError(err) = res {
+            if let LoadFileError::IntegrityError(err) = res.into_inner() {
                 if let IntegrityError::Crc32Error { line_num } = err {
                     if line_num == 2 {
                         crc_is_correct = false;
@@ -272,7 +292,7 @@ mod tests {
         let res: Result<HashMap<i32, String>, LoadFileError> = HashMap::open_or_create(&file, cfg);
         let mut crc_is_correct = true;
         if let Err(res) = res {
-            if let LoadFileError::IntegrityError(err) = res {
+            if let LoadFileError::IntegrityError(err) = res.into_inner() {
                 if let IntegrityError::Sha1ChainError { line_num } = err {
                     if line_num == 2 {
                         crc_is_correct = false;
@@ -335,7 +355,7 @@ mod tests {
         let res: Result<HashMap<i32, String>, LoadFileError> = HashMap::open_or_create(&file, cfg);
         let mut crc_is_correct = true;
         if let Err(res) = res {
-            if let LoadFileError::IntegrityError(err) = res {
+            if let LoadFileError::IntegrityError(err) = res.into_inner() {
                 if let IntegrityError::Sha256ChainError { line_num } = err {
                     if line_num == 2 {
                         crc_is_correct = false;
@@ -348,6 +368,264 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_chain_integrity() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::Integrity;
+
+        let inital_hash = [0,2,1,234,115,141,153,20,34,56,32,115,141,153,20,34,50,1,45,6,23,34,32,1,234,141,153,20,34,50,1,45];
+        let make_cfg = || Cfg { integrity: Some(Integrity::Blake3Chain(inital_hash)), ..Cfg::default() };
+        // hash of the previous hash with the hash of the line data
+        let chain = |prev_hash: &[u8; 32], line_data: &str| -> [u8; 32] {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(prev_hash);
+            hasher.update(blake3::hash(line_data.as_bytes()).as_bytes());
+            *hasher.finalize().as_bytes()
+        };
+        let hash_1 = chain(&inital_hash, "ins [0,\"a\"]");
+        let hash_2 = chain(&hash_1, "ins [3,\"b\"]");
+        let hash_3 = chain(&hash_2, "ins [5,\"c\"]");
+        let hash_4 = chain(&hash_3, "rem 3");
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        map.insert(5, "c".to_string())?;
+        drop(map);
+
+        let file_content = std::fs::read_to_string(&file)?;
+        let expected = format!("ins [0,\"a\"] {}\nins [3,\"b\"] {}\nins [5,\"c\"] {}\n", hex::encode(hash_1), hex::encode(hash_2), hex::encode(hash_3));
+        assert_eq!(file_content, expected);
+
+        let mut map: BTreeMap<i32, String> = BTreeMap::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.get(&3), Some(&"b".to_string()));
+        map.remove(&3)?;
+        drop(map);
+        let file_content = std::fs::read_to_string(&file)?;
+        let expected = format!("{}rem 3 {}\n", expected, hex::encode(hash_4));
+        assert_eq!(file_content, expected);
+
+        // wrong hash of the middle line
+        let mut bad_hash_2 = hash_2;
+        bad_hash_2[5] ^= 1;
+        let bad_content = format!("ins [0,\"a\"] {}\nins [3,\"b\"] {}\nins [5,\"c\"] {}\n", hex::encode(hash_1), hex::encode(bad_hash_2), hex::encode(hash_3));
+        std::fs::write(&file, bad_content)?;
+
+        let res: Result<HashMap<i32, String>, LoadFileError> = HashMap::open_or_create(&file, make_cfg());
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::Blake3ChainError { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        std::fs::remove_file(&file)?;
+
+        // the same in binary format, hash is appended to the block as is
+        let file = tmp_file()?;
+        let make_cfg = || Cfg { format: Format::Bin(None, None), ..make_cfg() };
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        let head = map.integrity_head().ok_or("no head of chain")?;
+        drop(map);
+        let file_content = std::fs::read(&file)?;
+        assert!(file_content.ends_with(head.hash()));
+
+        // algorithm of the head is kept by the anchor file
+        let anchor_file = format!("{}.anchor", file);
+        head.save(&anchor_file)?;
+        let anchor = crate::chain_anchor::ChainAnchor::load(&anchor_file)?;
+        assert_eq!(anchor, head);
+        assert_eq!(anchor.algorithm(), "blake3_chain");
+        assert!(Integrity::sha256_from_anchor(&anchor).is_err());
+        assert!(matches!(Integrity::blake3_from_anchor(&anchor)?, Integrity::Blake3Chain(_)));
+        std::fs::remove_file(&anchor_file)?;
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+
+        let mut bad_content = file_content.clone();
+        let last = bad_content.len() - 1;
+        bad_content[last] ^= 1;
+        std::fs::write(&file, bad_content)?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg());
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::Blake3ChainError { .. }))));
+        std::fs::remove_file(&file)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn xxhash64_integrity() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::convert;
+        use xxhash_rust::xxh64::xxh64;
+
+        let make_cfg = |format| Cfg { format, integrity: Some(Integrity::XxHash64), ..Cfg::default() };
+        let lines = ["ins [0,\"a\"]", "ins [3,\"b\"]", "ins [5,\"c\"]", "rem 3"];
+        let expected: String = lines.iter().map(|line| format!("{} {:016x}\n", line, xxh64(line.as_bytes(), 0))).collect();
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(Format::Text(None, None)))?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        map.insert(5, "c".to_string())?;
+        map.remove(&3)?;
+        drop(map);
+        assert_eq!(std::fs::read_to_string(&file)?, expected);
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None)))?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+
+        // wrong checksum of the middle line
+        let bad_line = format!("ins [3,\"b\"] {:016x}\n", xxh64(lines[1].as_bytes(), 0) ^ 1);
+        let bad_content = expected.replacen(&expected.lines().nth(1).map(|line| format!("{}\n", line)).ok_or("no line")?, &bad_line, 1);
+        std::fs::write(&file, bad_content)?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None)));
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::XxHash64Error { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        std::fs::remove_file(&file)?;
+
+        // 8 bytes of checksum in little-endian at the end of block
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(Format::Bin(None, None)))?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        drop(map);
+        let mut content = std::fs::read(&file)?;
+        let block_len = content[1] as usize;
+        assert_eq!(content.len(), 2 * (2 + block_len));
+        let block = &content[content.len() - block_len..];
+        assert_eq!(block[block_len - 8..], xxh64(&block[..block_len - 8], 0).to_le_bytes());
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Bin(None, None)))?;
+        assert_eq!(map.get(&3), Some(&"b".to_string()));
+        drop(map);
+
+        let data_index = content.len() - 9;
+        content[data_index] ^= 1;
+        std::fs::write(&file, content)?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Bin(None, None)));
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::XxHash64Error { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        std::fs::remove_file(&file)?;
+
+        // destination integrity of conversion
+        let src_file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&src_file, Cfg::default())?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        map.insert(5, "c".to_string())?;
+        map.remove(&3)?;
+        drop(map);
+        let converted_file = tmp_file()?;
+        convert::<i32, String, i32, String, _>(&src_file, Cfg::default(), &converted_file, make_cfg(Format::Text(None, None)), |map_operation| map_operation)?;
+        assert_eq!(std::fs::read_to_string(&converted_file)?, expected);
+        let map = BTreeMap::<i32, String>::open_or_create(&converted_file, make_cfg(Format::Text(None, None)))?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+        std::fs::remove_file(&converted_file)?;
+        std::fs::remove_file(&src_file)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn hmac_sha256_chain_integrity() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{blockchain_hmac_sha256, convert};
+
+        let key = b"secret key of chain".to_vec();
+        let make_cfg = |key: &[u8], format| Cfg { format, integrity: Some(Integrity::HmacSha256Chain { key: key.to_vec(), prev: [0; 32] }), ..Cfg::default() };
+        let lines = ["ins [0,\"a\"]", "ins [3,\"b\"]", "ins [5,\"c\"]", "rem 3"];
+        let mut tags = Vec::new();
+        let mut prev = [0; 32];
+        for line in lines.iter() {
+            let mut tag = [0; 32];
+            blockchain_hmac_sha256(&key, &prev, line.as_bytes(), &mut tag);
+            tags.push(tag);
+            prev = tag;
+        }
+        let expected: String = lines.iter().zip(tags.iter()).map(|(line, tag)| format!("{} {}\n", line, hex::encode(tag))).collect();
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(&key, Format::Text(None, None)))?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        map.insert(5, "c".to_string())?;
+        map.remove(&3)?;
+        assert_eq!(map.integrity_head(), Some(crate::chain_anchor::ChainAnchor::HmacSha256Chain(tags[3])));
+        drop(map);
+        let file_content = std::fs::read_to_string(&file)?;
+        assert_eq!(file_content, expected);
+        // neither the key nor its hex is written to the file
+        assert!(!file_content.contains("secret") && !file_content.contains(&hex::encode(&key)));
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(&key, Format::Text(None, None)))?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+
+        // key is not printed by Debug
+        let debug = format!("{:?}", Integrity::HmacSha256Chain { key: key.clone(), prev: [0; 32] });
+        assert!(!debug.contains("secret") && !debug.contains(&format!("{:?}", key)));
+
+        // file of the other key
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(b"other key", Format::Text(None, None)));
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::HmacError { line_num: 1 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        // changed data of the middle line with the old tag
+        let bad_content = expected.replacen("ins [3,\"b\"]", "ins [3,\"x\"]", 1);
+        std::fs::write(&file, bad_content)?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(&key, Format::Text(None, None)));
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::HmacError { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        std::fs::remove_file(&file)?;
+
+        // tag is appended to the block as is
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(&key, Format::Bin(None, None)))?;
+        map.insert(0, "a".to_string())?;
+        map.insert(3, "b".to_string())?;
+        let head = map.integrity_head().ok_or("no head of chain")?;
+        drop(map);
+        let mut content = std::fs::read(&file)?;
+        assert!(content.ends_with(head.hash()));
+        assert!(!content.windows(key.len()).any(|window| window == &key[..]));
+        assert!(matches!(Integrity::hmac_sha256_from_anchor(key.clone(), &head)?, Integrity::HmacSha256Chain { .. }));
+        assert!(Integrity::sha256_from_anchor(&head).is_err());
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(&key, Format::Bin(None, None)))?;
+        assert_eq!(map.get(&3), Some(&"b".to_string()));
+        drop(map);
+
+        let data_index = content.len() - 33;
+        content[data_index] ^= 1;
+        std::fs::write(&file, content)?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(&key, Format::Bin(None, None)));
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::HmacError { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        std::fs::remove_file(&file)?;
+
+        // signing again by the new key
+        let src_file = tmp_file()?;
+        std::fs::write(&src_file, &expected)?;
+        let new_key = b"new key".to_vec();
+        let converted_file = tmp_file()?;
+        convert::<i32, String, i32, String, _>(&src_file, make_cfg(&key, Format::Text(None, None)), &converted_file, make_cfg(&new_key, Format::Text(None, None)), |map_operation| map_operation)?;
+        let map = BTreeMap::<i32, String>::open_or_create(&converted_file, make_cfg(&new_key, Format::Text(None, None)))?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+        let res = BTreeMap::<i32, String>::open_or_create(&converted_file, make_cfg(&key, Format::Text(None, None)));
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::HmacError { line_num: 1 }))));
+        std::fs::remove_file(&converted_file)?;
+        std::fs::remove_file(&src_file)?;
+
+        Ok(())
+    }
+
     #[test]
     fn convert() -> Result<(), Box<dyn std::error::Error>> {
         use serde::{Deserialize, Serialize};
@@ -584,8 +862,5574 @@ mod tests {
         Ok(())
     }
 
-    #[derive(Debug)]
-    struct TempDirError();
+    #[test]
+    fn csv_export_import() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::csv_format::{import_csv, ImportCsvError};
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert("plain".to_string(), "value".to_string())?;
+        map.insert("with, comma".to_string(), "say \"hello\"".to_string())?;
+        map.insert("multi\nline".to_string(), "a,\"b\"\nc".to_string())?;
+
+        let mut csv = Vec::new();
+        map.export_csv(&mut csv, true)?;
+        let expected = "key,value\n\
+                        \"multi\nline\",\"a,\"\"b\"\"\nc\"\n\
+                        plain,value\n\
+                        \"with, comma\",\"say \"\"hello\"\"\"\n";
+        assert_eq!(String::from_utf8(csv.clone())?, expected);
+
+        let dst_file = tmp_file()?;
+        let count = import_csv(&csv[..], true, &dst_file, Cfg::default(), |key, value| -> Result<_, std::convert::Infallible> {
+            Ok((key.to_string(), value.to_string()))
+        })?;
+        assert_eq!(count, 3);
+
+        let imported: BTreeMap<String, String> = BTreeMap::open_or_create(&dst_file, Cfg::default())?;
+        assert_eq!(imported.map(), map.map());
+
+        // parse failure reports line number of CSV
+        let csv = "key,value\na,1.5\nb,x\n";
+        let res = import_csv(csv.as_bytes(), true, &tmp_file()?, Cfg::default(), |key, value| {
+            value.parse::<f64>().map(|value| (key.to_string(), value))
+        });
+        match res {
+            Err(ImportCsvError::ParseError { line_num, .. }) => assert_eq!(line_num, 3),
+            _ => panic!("expected parse error"),
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_export() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::export_history_sqlite_to;
+        use rusqlite::Connection;
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..2500 {
+            map.insert(i, format!("value {}", i))?;
+        }
+        map.remove(&7)?;
+        map.insert(3, "changed".to_string())?;
+
+        let mut conn = Connection::open_in_memory()?;
+        map.export_sqlite_to(&mut conn, "state")?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM state", [], |row| row.get(0))?;
+        assert_eq!(count, 2499);
+        let value: String = conn.query_row("SELECT value FROM state WHERE key = '3'", [], |row| row.get(0))?;
+        assert_eq!(value, "\"changed\"");
+        drop(map);
+
+        export_history_sqlite_to::<i32, String>(&file, Cfg::default(), &mut conn, "history")?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM history", [], |row| row.get(0))?;
+        assert_eq!(count, 2502);
+        let (op, key, value): (String, String, Option<String>) = conn.query_row(
+            "SELECT op, key, value FROM history WHERE record_index = 2501", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        )?;
+        assert_eq!((op.as_str(), key.as_str(), value), ("rem", "7", None));
+
+        // binary format stores bincode blobs
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.format = Format::Bin(None, None);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        map.insert(1u8, 2u8)?;
+        map.export_sqlite_to(&mut conn, "bin_state")?;
+        let value: Vec<u8> = conn.query_row("SELECT value FROM bin_state", [], |row| row.get(0))?;
+        assert_eq!(value, vec![2]);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_spans_and_events() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::{Arc, Mutex};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::field::{Field, Visit};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Collects names of spans and messages of events.
+        #[derive(Clone, Default)]
+        struct Collector {
+            spans: Arc<Mutex<Vec<String>>>,
+            events: Arc<Mutex<Vec<String>>>,
+        }
+
+        struct MessageVisitor<'a>(&'a mut String);
+
+        impl Visit for MessageVisitor<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    *self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        impl Subscriber for Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool { true }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(span.metadata().name().to_string());
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut message = String::new();
+                event.record(&mut MessageVisitor(&mut message));
+                self.events.lock().unwrap().push(message);
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let collector = Collector::default();
+        tracing::subscriber::with_default(collector.clone(), || -> Result<(), Box<dyn std::error::Error>> {
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+            map.insert(1, 2)?;
+            Ok(())
+        })?;
+
+        assert!(collector.spans.lock().unwrap().iter().any(|span| span == "open_or_create"));
+        let events = collector.events.lock().unwrap();
+        assert!(events.iter().any(|event| event == "map opened"));
+        assert!(events.iter().any(|event| event == "written to file"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn metrics() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let metrics = map.metrics();
+        assert_eq!(metrics.entries, 0);
+        assert_eq!(metrics.operations_since_open, 0);
+        assert_eq!(metrics.bytes_written, 0);
+        assert_eq!(metrics.last_flush_at, None);
+
+        let _index = map.create_btree_index(|value: &String| value.len());
+        map.insert(1, "a".to_string())?;
+        map.insert(1, "b".to_string())?;
+        map.insert(2, "c".to_string())?;
+        map.remove(&2)?;
+        map.remove(&2)?; // nothing removed so nothing written
+
+        let metrics = map.metrics();
+        assert_eq!(metrics.entries, 1);
+        assert_eq!(metrics.operations_since_open, 4);
+        assert_eq!(metrics.dead_operation_estimate, 3);
+        assert_eq!(metrics.index_count, 1);
+
+        // wait background writing
+        while map.metrics().pending_writes > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let metrics = map.metrics();
+        assert_eq!(metrics.bytes_written, std::fs::metadata(&file)?.len());
+        assert_eq!(metrics.write_errors, 0);
+        assert!(metrics.last_flush_at.is_some());
+        drop(map);
+
+        // after restart loaded records are counted as dead when overwritten
+        let mut map: BTreeMap<i32, String> = BTreeMap::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.metrics().dead_operation_estimate, 3);
+        map.insert(1, "d".to_string())?;
+        let metrics = map.metrics();
+        assert_eq!(metrics.operations_since_open, 1);
+        assert_eq!(metrics.dead_operation_estimate, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.stats(), crate::metrics::Stats::default());
+        map.insert(1, 1)?;
+        map.insert(1, 2)?;
+        map.insert(2, 3)?;
+        map.remove(&2)?;
+        map.flush()?;
+        let stats = map.stats();
+        assert_eq!((stats.records_loaded, stats.dead_records_loaded, stats.operations_since_open), (0, 0, 4));
+        assert_eq!(stats.file_len, std::fs::metadata(&file)?.len());
+        drop(map);
+
+        // overwritten insert, removed insert and remove are dead
+        let mut map = BTreeMap::<i32, i32>::open_or_create(&file, Cfg::default())?;
+        let stats = map.stats();
+        assert_eq!((stats.records_loaded, stats.dead_records_loaded, stats.operations_since_open), (4, 3, 0));
+        map.insert(3, 3)?;
+        map.flush()?;
+        assert_eq!(map.stats().operations_since_open, 1);
+        assert_eq!(map.stats().file_len, std::fs::metadata(&file)?.len());
+
+        map.compact()?;
+        let stats = map.stats();
+        assert_eq!((stats.records_loaded, stats.dead_records_loaded, stats.operations_since_open), (2, 0, 0));
+        assert_eq!(stats.file_len, std::fs::metadata(&file)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn follower() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::follower::FollowerBTreeMap;
+        use std::time::Duration;
+
+        let file = tmp_file()?;
+        let cfg = || {
+            let mut cfg = Cfg::default();
+            cfg.integrity = Some(Integrity::Sha256Chain([7; 32]));
+            cfg
+        };
+
+        let mut writer = BTreeMap::open_or_create(&file, cfg())?;
+        writer.insert(1, "Masha".to_string())?;
+        writer.insert(2, "Sasha".to_string())?;
+        while writer.metrics().pending_writes > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut follower: FollowerBTreeMap<i32, String> = FollowerBTreeMap::open(&file, cfg(), Duration::from_secs(3600))?;
+        let name_index = follower.create_btree_index(|value: &String| value.clone());
+        assert_eq!(follower.map(), writer.map());
+        assert_eq!(follower.poll()?, 0); // poll interval is not elapsed
+
+        writer.insert(3, "Pasha".to_string())?;
+        writer.remove(&1)?;
+        writer.insert(2, "Natasha".to_string())?;
+        while writer.metrics().pending_writes > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(follower.refresh()?, 3);
+        assert_eq!(follower.map(), writer.map());
+        assert!(name_index.get(&"Masha".to_string()).is_empty());
+        assert_eq!(name_index.get(&"Natasha".to_string()), vec![2]);
+        assert_eq!(follower.refresh()?, 0);
+        drop(writer);
+
+        // incomplete record at the end of file is read when it's complete
+        let mut f = std::fs::OpenOptions::new().append(true).open(&file)?;
+        f.write_all(b"ins [4,\"Dash")?;
+        assert_eq!(follower.refresh()?, 0);
+        let offset = follower.offset();
+
+        // integrity error doesn't change map
+        f.write_all(b"a\"] 0000\n")?;
+        assert!(follower.refresh().is_err());
+        assert_eq!(follower.offset(), offset);
+        assert_eq!(follower.get(&4), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bin_follower() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::follower::FollowerHashMap;
+        use std::time::Duration;
+
+        let file = tmp_file()?;
+        let cfg = || {
+            let mut cfg = Cfg::default();
+            cfg.format = Format::Bin(None, None);
+            cfg.integrity = Some(Integrity::Crc32);
+            cfg
+        };
+
+        let mut writer = HashMap::open_or_create(&file, cfg())?;
+        writer.insert(1, vec![1u8; 30])?;
+        drop(writer);
+
+        let mut follower: FollowerHashMap<i32, Vec<u8>> = FollowerHashMap::open(&file, cfg(), Duration::from_millis(0))?;
+        assert_eq!(follower.get(&1), Some(&vec![1u8; 30]));
+
+        // write block by parts
+        let mut block = crate::bin_format::bin_file_block_of_insert(&2, vec![2u8; 3], &mut Some(Integrity::Crc32))?;
+        block.extend_from_slice(&crate::bin_format::bin_file_block_of_remove(&1, &mut Some(Integrity::Crc32))?);
+        let mut f = std::fs::OpenOptions::new().append(true).open(&file)?;
+        for byte in block {
+            f.write_all(&[byte])?;
+            follower.poll()?;
+        }
+
+        assert_eq!(follower.get(&1), None);
+        assert_eq!(follower.get(&2), Some(&vec![2u8; 3]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn follower_on_update() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::follower::{FollowerBTreeMap, KeyChange, MapChangeBatch};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use std::time::Duration;
+
+        let file = tmp_file()?;
+        let mut writer = BTreeMap::open_or_create(&file, Cfg::default())?;
+        writer.insert(1, "Masha".to_string())?;
+        while writer.metrics().pending_writes > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut follower: FollowerBTreeMap<i32, String> = FollowerBTreeMap::open(&file, Cfg::default(), Duration::from_millis(0))?;
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        let batches_in_callback = batches.clone();
+        follower.on_update(move |batch| batches_in_callback.borrow_mut().push(batch.clone()));
+
+        writer.insert(2, "Sasha".to_string())?;
+        writer.remove(&1)?;
+        while writer.metrics().pending_writes > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // zero poll interval, so poll always reads the file
+        assert_eq!(follower.poll()?, 2);
+        assert_eq!(follower.poll()?, 0);
+        assert_eq!(*batches.borrow(), vec![MapChangeBatch { changes: vec![KeyChange::Inserted(2), KeyChange::Removed(1)] }]);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn follower_watch() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::follower::FollowerBTreeMap;
+        use std::time::{Duration, Instant};
+
+        let file = tmp_file()?;
+        let mut writer = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut follower: FollowerBTreeMap<i32, String> = FollowerBTreeMap::open(&file, Cfg::default(), Duration::from_millis(50))?;
+        // if watcher backend is unavailable, follower falls back to polling
+        let _ = follower.watch(Duration::from_millis(10));
+
+        writer.insert(1, "Masha".to_string())?;
+        let start = Instant::now();
+        while follower.get(&1).is_none() && start.elapsed() < Duration::from_secs(10) {
+            follower.poll()?;
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(follower.get(&1), Some(&"Masha".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn secondary_sink() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::testing::{FailingWriter, SharedBuffer};
+        use std::sync::{Arc, Mutex};
+
+        for format in [Format::Text(None, None), Format::Bin(None, None)] {
+            let file = tmp_file()?;
+            let sink = SharedBuffer::new();
+            let mut cfg = Cfg::default();
+            cfg.format = format;
+            cfg.integrity = Some(Integrity::Crc32);
+            cfg.secondary_sink = Some(Box::new(sink.clone()));
+            let mut map = BTreeMap::open_or_create(&file, cfg)?;
+            map.insert(1, "Masha".to_string())?;
+            map.insert(2, "Sasha".to_string())?;
+            map.remove(&1)?;
+            drop(map);
+
+            assert_eq!(sink.contents(), std::fs::read(&file)?);
+        }
+
+        // sink error doesn't affect writing to the file
+        let file = tmp_file()?;
+        let sink_errors = Arc::new(Mutex::new(0));
+        let sink_errors_in_callback = sink_errors.clone();
+        let mut cfg = Cfg::default();
+        cfg.secondary_sink = Some(Box::new(FailingWriter::fail_every(std::io::sink(), 1)));
+        cfg.secondary_sink_error_callback = Some(Box::new(move |_| *sink_errors_in_callback.lock().unwrap() += 1));
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        map.insert(1, "Masha".to_string())?;
+        map.insert(2, "Sasha".to_string())?;
+        assert_eq!(map.metrics().write_errors, 0);
+        drop(map);
+
+        assert_eq!(*sink_errors.lock().unwrap(), 2);
+        let map: BTreeMap<i32, String> = BTreeMap::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&2), Some(&"Sasha".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_shipping() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::log_shipper::{DirLogShipper, LogShipper, LogShipping};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        #[derive(Clone, Default)]
+        struct MemShipper { segments: Arc<Mutex<Vec<Vec<u8>>>>, fail: bool }
+        impl LogShipper for MemShipper {
+            fn ship(&mut self, segment: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+                if self.fail {
+                    return Err("unavailable".into());
+                }
+                self.segments.lock().unwrap().push(segment.to_vec());
+                Ok(())
+            }
+            fn flush(&mut self) {}
+        }
+
+        // each record 'ins [N,"Masha"]\n' is 16 bytes, so segment up to 40 bytes contains 2 records
+        let file = tmp_file()?;
+        let shipper = MemShipper::default();
+        let mut shipping = LogShipping::new(shipper.clone());
+        shipping.max_batch_bytes = 40;
+        shipping.max_batch_delay = Duration::from_secs(3600);
+        let mut cfg = Cfg::default();
+        cfg.log_shipping = Some(shipping);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        for i in 1..=5 {
+            map.insert(i, "Masha".to_string())?;
+        }
+        drop(map);
+
+        let segments = shipper.segments.lock().unwrap().clone();
+        assert_eq!(segments.iter().map(|segment| segment.len()).collect::<Vec<_>>(), vec![32, 32, 16]);
+        assert_eq!(segments.concat(), std::fs::read(&file)?);
+
+        // when shipping fails, the oldest segments are dropped from shipping but not from the file
+        let file = tmp_file()?;
+        let dropped_bytes = Arc::new(Mutex::new(0));
+        let dropped_bytes_in_callback = dropped_bytes.clone();
+        let errors = Arc::new(Mutex::new(0));
+        let errors_in_callback = errors.clone();
+        let mut shipping = LogShipping::new(MemShipper { fail: true, ..MemShipper::default() });
+        shipping.max_batch_bytes = 1;
+        shipping.max_spill_bytes = 40;
+        shipping.min_retry_delay = Duration::from_secs(3600);
+        shipping.overflow_callback = Some(Box::new(move |bytes| *dropped_bytes_in_callback.lock().unwrap() += bytes));
+        shipping.error_callback = Some(Box::new(move |_| *errors_in_callback.lock().unwrap() += 1));
+        let mut cfg = Cfg::default();
+        cfg.log_shipping = Some(shipping);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        for i in 1..=5 {
+            map.insert(i, "Masha".to_string())?;
+        }
+        drop(map);
+
+        assert_eq!(*dropped_bytes.lock().unwrap(), 3 * 16);
+        assert_eq!(*errors.lock().unwrap(), 2); // first attempt and last attempt when stopping
+        let map: BTreeMap<i32, String> = BTreeMap::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.map().len(), 5);
+        drop(map);
+
+        // example shipper writes segments to numbered files
+        let file = tmp_file()?;
+        let dir = format!("{}_segments", file);
+        let mut shipping = LogShipping::new(DirLogShipper::new(&dir)?);
+        shipping.max_batch_bytes = 1;
+        let mut cfg = Cfg::default();
+        cfg.log_shipping = Some(shipping);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        map.insert(1, "Masha".to_string())?;
+        map.insert(2, "Sasha".to_string())?;
+        drop(map);
+
+        let segments = [std::fs::read(format!("{}/000001.log", dir))?, std::fs::read(format!("{}/000002.log", dir))?];
+        assert_eq!(segments.concat(), std::fs::read(&file)?);
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn value_migration() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{MigrationError, RawValue};
+        use crate::format::convert;
+        use serde::{Deserialize, Serialize};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicI32, Ordering};
+
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        struct UserV1 { name: String }
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        struct UserV2 { full_name: String }
+
+        let migrations = Arc::new(AtomicI32::new(0));
+        let cfg_v2 = |format: Format, migrations: Arc<AtomicI32>| {
+            let mut cfg = Cfg::default();
+            cfg.format = format;
+            cfg.integrity = Some(Integrity::Sha1Chain([0; 20]));
+            cfg.value_schema_version = Some(2);
+            cfg.value_migrator = Some(Box::new(move |version, raw_value| {
+                migrations.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(version, 1);
+                match raw_value {
+                    RawValue::Json(mut value) => {
+                        let name = value.get_mut("name").ok_or(MigrationError("no name".to_string()))?.take();
+                        Ok(RawValue::Json(serde_json::json!({ "full_name": name })))
+                    },
+                    RawValue::Bin(data) => {
+                        let user: UserV1 = bincode2::deserialize(&data).map_err(|err| MigrationError(err.to_string()))?;
+                        Ok(RawValue::Bin(bincode2::serialize(&UserV2 { full_name: user.name }).map_err(|err| MigrationError(err.to_string()))?))
+                    },
+                }
+            }));
+            cfg
+        };
+
+        for format in [|| Format::Text(None, None), || Format::Bin(None, None)] {
+            migrations.store(0, Ordering::Relaxed);
+            let file = tmp_file()?;
+            let mut cfg_v1 = Cfg::default();
+            cfg_v1.format = format();
+            cfg_v1.integrity = Some(Integrity::Sha1Chain([0; 20]));
+            cfg_v1.value_schema_version = Some(1);
+            let mut map = BTreeMap::open_or_create(&file, cfg_v1)?;
+            map.insert(1, UserV1 { name: "Masha".to_string() })?;
+            map.insert(2, UserV1 { name: "Sasha".to_string() })?;
+            map.remove(&2)?;
+            drop(map);
+
+            let mut map = BTreeMap::open_or_create(&file, cfg_v2(format(), migrations.clone()))?;
+            assert_eq!(migrations.load(Ordering::Relaxed), 2);
+            assert_eq!(map.get(&1), Some(&UserV2 { full_name: "Masha".to_string() }));
+            assert_eq!(map.get(&2), None);
+            map.insert(3, UserV2 { full_name: "Pasha".to_string() })?;
+            drop(map);
+
+            // records at current version are not migrated
+            let map = BTreeMap::<i32, UserV2>::open_or_create(&file, cfg_v2(format(), migrations.clone()))?;
+            assert_eq!(migrations.load(Ordering::Relaxed), 4);
+            assert_eq!(map.get(&3), Some(&UserV2 { full_name: "Pasha".to_string() }));
+            drop(map);
+        }
+
+        // rewritten file contains only current version
+        let file = tmp_file()?;
+        let mut cfg_v1 = Cfg::default();
+        cfg_v1.value_schema_version = Some(1);
+        let mut map = BTreeMap::open_or_create(&file, cfg_v1)?;
+        map.insert(1, UserV1 { name: "Masha".to_string() })?;
+        drop(map);
+        assert!(std::fs::read_to_string(&file)?.starts_with("insV1 "));
+        let mut src_cfg = cfg_v2(Format::Text(None, None), migrations.clone());
+        src_cfg.integrity = None;
+        let mut dst_cfg = Cfg::default();
+        dst_cfg.value_schema_version = Some(2);
+        convert::<i32, UserV2, i32, UserV2, _>(&file, src_cfg, &file, dst_cfg, |map_operation| map_operation)?;
+        assert_eq!(std::fs::read_to_string(&file)?, "insV2 [1,{\"full_name\":\"Masha\"}]\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn conversions_with_std_maps() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::map_with_file::{collect_into_new, CreateError, MapWithFile};
+        use std::convert::TryFrom;
+        use std::path::PathBuf;
+
+        // empty
+        let file = tmp_file()?;
+        let map = BTreeMap::<i32, String>::create_from_map(&file, Cfg::default(), std::collections::BTreeMap::new())?;
+        assert!(map.map().is_empty());
+        drop(map);
+        assert_eq!(std::fs::read(&file)?.len(), 0);
+
+        // large
+        let file = tmp_file()?;
+        let src = (0..10000).map(|i| (i, i.to_string())).collect::<std::collections::BTreeMap<i32, String>>();
+        let map = BTreeMap::try_from((PathBuf::from(&file), Cfg::default(), src.clone()))?;
+        assert_eq!(std::collections::BTreeMap::from(&map), src);
+        assert_eq!(map.clone_inner(), src);
+        drop(map);
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.map(), &src);
+        drop(map);
+
+        // file with records
+        let src = std::collections::HashMap::from([(1, 1)]);
+        assert!(matches!(HashMap::try_from((PathBuf::from(&file), Cfg::default(), src)), Err(CreateError::LoadFileError(_))));
+        let file = tmp_file()?;
+        let map = HashMap::try_from((PathBuf::from(&file), Cfg::default(), std::collections::HashMap::from([(1, 1)])))?;
+        assert_eq!(std::collections::HashMap::from(&map), std::collections::HashMap::from([(1, 1)]));
+        drop(map);
+        assert!(matches!(HashMap::<i32, i32>::create_from_map(&file, Cfg::default(), std::collections::HashMap::new()), Err(CreateError::FileNotEmpty)));
+
+        // duplicate keys, the last value wins but all records are written
+        let file = tmp_file()?;
+        let mut map: BTreeMap<i32, i32> = collect_into_new(&file, Cfg::default(), vec![(1, 1), (2, 2), (1, 3)])?;
+        assert_eq!(map.try_extend(vec![(2, 4), (3, 5)])?, 2);
+        let expected = vec![(1, 3), (2, 4), (3, 5)].into_iter().collect::<std::collections::BTreeMap<i32, i32>>();
+        assert_eq!(map.map(), &expected);
+        drop(map);
+        assert_eq!(std::fs::read_to_string(&file)?.lines().count(), 5);
+        let map = MapWithFile::<i32, i32, std::collections::BTreeMap<i32, i32>>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.map(), &expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_iter_new() -> Result<(), Box<dyn std::error::Error>> {
+        // enough records for several batches
+        let file = tmp_file()?;
+        let pairs = (0..20000).map(|i| ((i * 7919) % 1000, i)).collect::<Vec<(i32, i32)>>();
+        let map = BTreeMap::from_iter_new(&file, Cfg::default(), pairs.clone())?;
+        assert_eq!(map.map(), &pairs.iter().cloned().collect::<std::collections::BTreeMap<i32, i32>>());
+        drop(map);
+
+        let mut records = Vec::new();
+        crate::text_format::load_from_text_file::<i32, i32, fn(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>, _, _>(&mut std::fs::File::open(&file)?, &mut None, None, |map_operation| {
+            if let MapOperation::Insert(key, value) = map_operation {
+                records.push((key, value));
+            }
+            Ok(())
+        })?;
+        assert_eq!(records, pairs);
+
+        // identical values are not written if option is set
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.skip_identical_inserts = true;
+        let mut map = HashMap::from_iter_new(&file, cfg, vec![(1, "a".to_string()), (1, "a".to_string()), (1, "b".to_string())])?;
+        assert_eq!(map.try_extend(vec![(1, "b".to_string()), (2, "c".to_string())])?, 1);
+        map.insert(2, "c".to_string())?;
+        assert_eq!(map.metrics().operations_since_open, 3);
+        drop(map);
+        assert_eq!(std::fs::read_to_string(&file)?, "ins [1,\"a\"]\nins [1,\"b\"]\nins [2,\"c\"]\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_map() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::{SharedBTreeMap, SharedHashMap};
+
+        fn assert_clone_send_sync<T: Clone + Send + Sync>() {}
+        assert_clone_send_sync::<SharedBTreeMap<i32, String>>();
+        assert_clone_send_sync::<SharedHashMap<i32, String>>();
+
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+        let map = SharedBTreeMap::open_or_create(&file, cfg)?;
+        let _name_index = map.write().create_btree_index(|value: &String| value.clone());
+
+        let threads = (0..8).map(|thread_num| {
+            let map = map.clone();
+            std::thread::spawn(move || -> Result<(), SerializedError> {
+                for i in 0..500 {
+                    let key = thread_num * 1000 + i;
+                    map.insert(key, format!("{}", i))?;
+                    assert_eq!(map.get_cloned(&key), Some(format!("{}", i)));
+                    if i % 2 == 0 {
+                        map.remove(&key)?;
+                    }
+                    assert!(map.get_with(&key, |value| value.is_some() == (i % 2 == 1)));
+                }
+                Ok(())
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        map.flush()?;
+        assert_eq!(map.read().metrics().pending_writes, 0);
+        let expected = map.read().map().clone();
+        assert_eq!(expected.len(), 8 * 250);
+        drop(map);
+
+        let mut cfg = Cfg::default();
+        cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+        let map = BTreeMap::<i32, String>::open_or_create(&file, cfg)?;
+        assert_eq!(map.map(), &expected);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "lock_free_reader")]
+    #[test]
+    fn lock_free_reader() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(0, 0)?;
+        let reader = map.reader();
+        assert_eq!(reader.get(&0), Some(0));
+
+        // the writer increments the value of each key in order, readers check that values never decrease
+        // and a key is never seen with value bigger than previous key, since previous key was changed earlier
+        const KEYS: i32 = 10;
+        let stop = Arc::new(AtomicBool::new(false));
+        let readers = (0..4).map(|_| {
+            let reader = reader.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut last_seen = vec![0; KEYS as usize];
+                while !stop.load(Ordering::Relaxed) {
+                    let snapshot = reader.snapshot();
+                    let mut prev_key_value = i32::MAX;
+                    for key in 0..KEYS {
+                        let value = snapshot.get(&key).cloned().unwrap_or(0);
+                        assert!(value >= last_seen[key as usize]);
+                        assert!(value <= prev_key_value);
+                        last_seen[key as usize] = value;
+                        prev_key_value = value;
+                    }
+                }
+                last_seen
+            })
+        }).collect::<Vec<_>>();
+
+        for round in 1..=1000 {
+            for key in 0..KEYS {
+                map.insert(key, round)?;
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+
+        for reader in readers {
+            assert!(reader.join().unwrap().iter().all(|value| *value <= 1000));
+        }
+        assert_eq!(reader.len(), KEYS as usize);
+        assert_eq!(reader.get(&(KEYS - 1)), Some(1000));
+        map.remove(&0)?;
+        assert_eq!(reader.get(&0), None);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "dashmap")]
+    #[test]
+    fn dashmap() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::DashMap;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        for format in [|| Format::Text(None, None), || Format::Bin(None, None)] {
+            let file = tmp_file()?;
+            let cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = format();
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+
+            // threads change overlapping keys, so records of the same key are logged concurrently
+            let map = Arc::new(DashMap::open_or_create(&file, cfg())?);
+            let applied_operations = Arc::new(AtomicUsize::new(0));
+            let threads = (0..8).map(|thread_num| {
+                let map = map.clone();
+                let applied_operations = applied_operations.clone();
+                std::thread::spawn(move || -> Result<(), SerializedError> {
+                    for i in 0..1000 {
+                        let key = (i * 7 + thread_num) % 50;
+                        if i % 3 == 0 {
+                            if map.remove(&key)?.is_some() {
+                                applied_operations.fetch_add(1, Ordering::Relaxed);
+                            }
+                        } else {
+                            map.insert(key, thread_num * 10000 + i)?;
+                            applied_operations.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Ok(())
+                })
+            }).collect::<Vec<_>>();
+
+            for thread in threads {
+                thread.join().unwrap()?;
+            }
+
+            map.flush()?;
+            let expected = map.map().iter().map(|item| (*item.key(), *item.value())).collect::<std::collections::BTreeMap<i32, i32>>();
+            drop(map);
+
+            let mut records = 0;
+            let mut replayed = std::collections::BTreeMap::new();
+            let mut integrity = Some(Integrity::Sha256Chain([0; 32]));
+            let mut file = std::fs::File::open(&file)?;
+            let apply = |map_operation| {
+                records += 1;
+                match map_operation {
+                    MapOperation::Insert(key, value) => { replayed.insert(key, value); },
+                    MapOperation::Remove(key) => { replayed.remove(&key); },
+                }
+                Ok(())
+            };
+            match format() {
+                Format::Text(..) => crate::text_format::load_from_text_file::<i32, i32, fn(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>, _, _>(&mut file, &mut integrity, None, apply)?,
+                Format::Bin(..) => crate::bin_format::load_from_bin_file::<i32, i32, fn(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>, _, _>(&mut file, &mut integrity, None, apply)?,
+            }
+
+            assert_eq!(records, applied_operations.load(Ordering::Relaxed));
+            assert_eq!(replayed, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_in_background() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..10000 {
+            map.insert(i, format!("value {}", i))?;
+        }
+        drop(map);
+
+        // success
+        let mut opening = BTreeMap::<i32, String>::open_in_background(&file, Cfg::default());
+        while !opening.ready() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let map = opening.wait()?;
+        assert_eq!(map.map().len(), 10000);
+        assert_eq!(map.get(&9999), Some(&"value 9999".to_string()));
+        drop(map);
+
+        // dropping before completion, the file must be unlocked after loading
+        let opening = BTreeMap::<i32, String>::open_in_background(&file, Cfg::default());
+        drop(opening);
+        let map = BTreeMap::<i32, String>::open_in_background(&file, Cfg::default()).wait()?;
+        assert_eq!(map.map().len(), 10000);
+        drop(map);
+
+        // load failure
+        let mut f = std::fs::OpenOptions::new().append(true).open(&file)?;
+        f.write_all(b"wrong line\n")?;
+        drop(f);
+        let result = BTreeMap::<i32, String>::open_in_background(&file, Cfg::default()).wait();
+        assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::UnknownOperation { ref name, line_num: 10001 }) if name == "wrong"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn open_in_background_await() -> Result<(), Box<dyn std::error::Error>> {
+        use std::future::Future;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        struct ThreadWaker(std::thread::Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+            let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                match std::pin::Pin::new(&mut future).poll(&mut cx) {
+                    Poll::Ready(output) => return output,
+                    Poll::Pending => std::thread::park(),
+                }
+            }
+        }
+
+        let file = tmp_file()?;
+
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..1000 {
+            map.insert(i, i)?;
+        }
+        drop(map);
+
+        let map = block_on(BTreeMap::<i32, i32>::open_in_background(&file, Cfg::default()))?;
+        assert_eq!(map.map().len(), 1000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn subscribe() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::subscription::ChangeEvent;
+        use std::sync::{Arc, Mutex};
+
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.skip_identical_inserts = true;
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_in_callback = events.clone();
+        let id = map.subscribe(move |event: &ChangeEvent<i32, String>| events_in_callback.lock().unwrap().push(event.clone()));
+        let watch_receiver = map.watch();
+
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        map.insert(1, "c".to_string())?;
+        // not written, no events
+        map.insert(1, "c".to_string())?;
+        map.remove(&3)?;
+        map.remove(&2)?;
+        map.try_extend(vec![(4, "d".to_string()), (5, "e".to_string())])?;
+
+        assert!(map.unsubscribe(id));
+        assert!(!map.unsubscribe(id));
+        map.insert(6, "f".to_string())?;
+        map.flush()?;
+        drop(map);
+
+        let mut replayed = Vec::new();
+        let mut replayed_map = std::collections::BTreeMap::new();
+        crate::text_format::load_from_text_file::<i32, String, fn(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>, _, _>(&mut std::fs::File::open(&file)?, &mut None, None, |map_operation| {
+            match map_operation {
+                MapOperation::Insert(key, value) => {
+                    let old = replayed_map.insert(key, value.clone());
+                    replayed.push(ChangeEvent::Inserted { key, new: value, old });
+                },
+                MapOperation::Remove(key) => {
+                    let old = replayed_map.remove(&key).unwrap();
+                    replayed.push(ChangeEvent::Removed { key, old });
+                },
+            }
+            Ok(())
+        })?;
+
+        assert_eq!(replayed.len(), 7);
+        assert_eq!(*events.lock().unwrap(), replayed[..6]);
+        assert_eq!(watch_receiver.try_iter().collect::<Vec<_>>(), replayed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_role() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::lock_role::{LockRole, RoleMap};
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let file = tmp_file()?;
+
+        let (opened_sender, opened) = channel();
+        let (close_sender, close) = channel::<()>();
+        let writer_file = file.clone();
+        let writer = std::thread::spawn(move || -> Result<(), LoadFileError> {
+            let mut map = match RoleMap::<i32, String, std::collections::BTreeMap<i32, String>>::open(&writer_file, Cfg::default(), LockRole::Writer, Duration::default())? {
+                RoleMap::Writer(map) => map,
+                RoleMap::Reader(_) => unreachable!(),
+            };
+            map.insert(1, "first writer".to_string()).unwrap();
+            map.flush()?;
+            opened_sender.send(()).unwrap();
+            close.recv().unwrap();
+            Ok(())
+        });
+
+        opened.recv()?;
+        let reader = RoleMap::<i32, String, std::collections::BTreeMap<i32, String>>::open(&file, Cfg::default(), LockRole::Reader, Duration::default())?;
+        assert_eq!(reader.role(), LockRole::Reader);
+        assert_eq!(reader.get(&1), Some(&"first writer".to_string()));
+
+        let reader = match reader {
+            RoleMap::Reader(reader) => reader,
+            RoleMap::Writer(_) => unreachable!(),
+        };
+        assert!(!reader.wait_for_writer_release(Duration::from_millis(50))?);
+
+        // failover, the writer is closed and the reader is promoted
+        close_sender.send(())?;
+        writer.join().unwrap()?;
+        assert!(reader.wait_for_writer_release(Duration::from_secs(10))?);
+
+        let mut promoted = match RoleMap::<i32, String, std::collections::BTreeMap<i32, String>>::open(&file, Cfg::default(), LockRole::Writer, Duration::default())? {
+            RoleMap::Writer(map) => map,
+            RoleMap::Reader(_) => unreachable!(),
+        };
+        assert_eq!(promoted.get(&1), Some(&"first writer".to_string()));
+        promoted.insert(2, "second writer".to_string())?;
+        promoted.flush()?;
+
+        // the old reader follows the new writer
+        let mut reader = reader;
+        reader.refresh()?;
+        assert_eq!(reader.get(&2), Some(&"second writer".to_string()));
+        assert!(!reader.wait_for_writer_release(Duration::from_millis(50))?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "persistent")]
+    #[test]
+    fn persistent_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::PersistentBTreeMap;
+
+        let file = tmp_file()?;
+        let mut map = PersistentBTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..1000 {
+            map.insert(CountingKey(i), i)?;
+        }
+
+        let clones_before = key_clones();
+        let snapshot = map.snapshot();
+        assert_eq!(key_clones(), clones_before);
+
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                map.remove(&CountingKey(i))?;
+            } else {
+                map.insert(CountingKey(i), -i)?;
+            }
+        }
+        for i in 1000..2000 {
+            map.insert(CountingKey(i), i)?;
+        }
+
+        assert_eq!(snapshot.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(snapshot.get(&CountingKey(i)), Some(&i));
+        }
+        assert_eq!(map.map().len(), 1500);
+        drop(map);
+
+        let map = PersistentBTreeMap::<CountingKey, i32>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&CountingKey(1)), Some(&-1));
+        assert_eq!(map.get(&CountingKey(2)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let index = map.create_btree_index(|value: &String| value.len());
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+
+        // rollback and drop discard changes
+        let mut txn = map.transaction();
+        txn.insert(3, "c".to_string());
+        txn.rollback();
+        {
+            let mut txn = map.transaction();
+            txn.remove(&1);
+        }
+        assert_eq!(map.get(&3), None);
+        assert_eq!(map.get(&1), Some(&"a".to_string()));
+
+        let mut txn = map.transaction();
+        assert_eq!(txn.insert(1, "aa".to_string()), Some("a".to_string()));
+        assert_eq!(txn.remove(&2), Some("b".to_string()));
+        assert_eq!(txn.insert(3, "ccc".to_string()), None);
+        assert_eq!(txn.get(&1), Some(&"aa".to_string()));
+        assert_eq!(txn.get(&2), None);
+        assert_eq!(txn.remove(&4), None);
+        // indexes are changed only at commit
+        assert!(index.get(&2).is_empty());
+        txn.commit()?;
+
+        assert_eq!(map.get(&1), Some(&"aa".to_string()));
+        assert_eq!(map.get(&2), None);
+        assert_eq!(index.get(&2), vec![1]);
+        assert_eq!(index.get(&3), vec![3]);
+        drop(map);
+
+        let content = std::fs::read_to_string(&file)?;
+        assert_eq!(content, "ins [1,\"a\"]\nins [2,\"b\"]\ntxb\nins [1,\"aa\"]\nrem 2\nins [3,\"ccc\"]\ntxe\n");
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&1), Some(&"aa".to_string()));
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.get(&3), Some(&"ccc".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_crash() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::follower::FollowerBTreeMap;
+
+        for format in [|| Format::Text(None, None), || Format::Bin(None, None)] {
+            let cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = format();
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, cfg())?;
+            map.insert(1, "a".to_string())?;
+            map.insert(2, "b".to_string())?;
+            map.flush()?;
+            let base_state = map.map().clone();
+            let base_len = std::fs::metadata(&file)?.len() as usize;
+
+            let mut txn = map.transaction();
+            txn.insert(1, "aa".to_string());
+            txn.remove(&2);
+            txn.insert(3, "c".to_string());
+            txn.commit()?;
+            let full_state = map.map().clone();
+            drop(map);
+            let content = std::fs::read(&file)?;
+
+            let mut opened_cuts = 0;
+            for cut in base_len..=content.len() {
+                let cut_file = tmp_file()?;
+                std::fs::write(&cut_file, &content[..cut])?;
+
+                let map = match BTreeMap::<i32, String>::open_or_create(&cut_file, cfg()) {
+                    Ok(map) => map,
+                    // partial record at the end
+                    Err(_) => continue,
+                };
+                opened_cuts += 1;
+
+                // all or none
+                if cut == content.len() {
+                    assert_eq!(*map.map(), full_state);
+                    continue;
+                }
+                assert_eq!(*map.map(), base_state);
+
+                // records after incomplete transaction are not part of it
+                let mut map = map;
+                map.insert(4, "d".to_string())?;
+                drop(map);
+                let map = BTreeMap::<i32, String>::open_or_create(&cut_file, cfg())?;
+                let mut expected = base_state.clone();
+                expected.insert(4, "d".to_string());
+                assert_eq!(*map.map(), expected);
+                drop(map);
+
+                // follower waits for the end of transaction
+                std::fs::write(&cut_file, &content[..cut])?;
+                let mut follower = FollowerBTreeMap::<i32, String>::open(&cut_file, cfg(), std::time::Duration::default())?;
+                assert_eq!(*follower.map(), base_state);
+                let mut f = std::fs::OpenOptions::new().append(true).open(&cut_file)?;
+                f.write_all(&content[cut..])?;
+                drop(f);
+                follower.refresh()?;
+                assert_eq!(*follower.map(), full_state);
+            }
+
+            // cuts between records: before "txb", after "txb" and after each of 3 records, after "txe"
+            assert_eq!(opened_cuts, 6);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_batch() -> Result<(), Box<dyn std::error::Error>> {
+        for format in [|| Format::Text(None, None), || Format::Bin(None, None)] {
+            let cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = format();
+                cfg.integrity = Some(Integrity::Crc32);
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, cfg())?;
+            let index = map.create_btree_index(|value: &String| value.clone());
+            map.insert(1, "a".to_string())?;
+            map.insert(2, "b".to_string())?;
+            map.flush()?;
+            let base_state = map.map().clone();
+            let base_len = std::fs::metadata(&file)?.len() as usize;
+
+            map.apply_batch(vec![
+                MapOperation::Insert(3, "c".to_string()),
+                MapOperation::Remove(1),
+                MapOperation::Insert(2, "bb".to_string()),
+                MapOperation::Remove(4),
+                MapOperation::Insert(3, "cc".to_string()),
+            ])?;
+            assert_eq!(map.get(&1), None);
+            assert_eq!(map.get(&2), Some(&"bb".to_string()));
+            assert_eq!(map.get(&3), Some(&"cc".to_string()));
+            assert!(index.get(&"c".to_string()).is_empty());
+            assert_eq!(index.get(&"cc".to_string()), vec![3]);
+            let full_state = map.map().clone();
+            drop(map);
+
+            if let Format::Text(..) = format() {
+                let content = std::fs::read_to_string(&file)?;
+                assert!(content.contains("bat [[\"ins\",[3,\"c\"]],[\"rem\",1],[\"ins\",[2,\"bb\"]],[\"rem\",4],[\"ins\",[3,\"cc\"]]] "));
+            }
+
+            let map = BTreeMap::<i32, String>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), full_state);
+            drop(map);
+
+            // torn write loses the whole batch
+            let content = std::fs::read(&file)?;
+            for cut in base_len..content.len() {
+                std::fs::write(&file, &content[..cut])?;
+                if let Ok(map) = BTreeMap::<i32, String>::open_or_create(&file, cfg()) {
+                    assert_eq!(cut, base_len);
+                    assert_eq!(*map.map(), base_state);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_cancel() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{convert, ConvertError, LOAD_CANCEL_CHECK_INTERVAL};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        const RECORDS_COUNT: usize = 20 * LOAD_CANCEL_CHECK_INTERVAL;
+        const CANCEL_AT_RECORD: usize = 3 * LOAD_CANCEL_CHECK_INTERVAL + 10;
+
+        for text in [true, false] {
+            let file = tmp_file()?;
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            BTreeMap::from_iter_new(&file, cfg, (0..RECORDS_COUNT).map(|i| (i, i)))?;
+
+            // flag is set from other thread when loading reaches the record, loading waits for it
+            let load_cancel = Arc::new(AtomicBool::new(false));
+            let records_read = Arc::new(AtomicUsize::new(0));
+            let (reached_sender, reached_receiver) = std::sync::mpsc::channel();
+            let canceller = {
+                let load_cancel = load_cancel.clone();
+                std::thread::spawn(move || {
+                    if reached_receiver.recv().is_ok() {
+                        load_cancel.store(true, Ordering::Relaxed);
+                    }
+                })
+            };
+            let on_record = {
+                let load_cancel = load_cancel.clone();
+                let records_read = records_read.clone();
+                move || {
+                    if records_read.fetch_add(1, Ordering::Relaxed) + 1 == CANCEL_AT_RECORD {
+                        reached_sender.send(()).unwrap();
+                        while !load_cancel.load(Ordering::Relaxed) {
+                            std::thread::yield_now();
+                        }
+                    }
+                    Ok(())
+                }
+            };
+
+            let mut cfg = Cfg::default();
+            cfg.load_cancel = Some(load_cancel.clone());
+            cfg.format = if text {
+                Format::Text(None, Some(Box::new(move |_: &mut String| on_record())))
+            } else {
+                Format::Bin(None, Some(Box::new(move |_: &mut Vec<u8>| on_record())))
+            };
+            let res: Result<BTreeMap<usize, usize>, LoadFileError> = BTreeMap::open_or_create(&file, cfg);
+            assert!(matches!(res, Err(LoadFileError::Cancelled)));
+            assert!(records_read.load(Ordering::Relaxed) <= CANCEL_AT_RECORD + LOAD_CANCEL_CHECK_INTERVAL);
+            canceller.join().unwrap();
+
+            // lock of the file is released after cancel
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            let map: BTreeMap<usize, usize> = BTreeMap::open_or_create(&file, cfg)?;
+            assert_eq!(RECORDS_COUNT, map.map().len());
+            drop(map);
+
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            cfg.load_cancel = Some(load_cancel);
+            let res = convert::<usize, usize, usize, usize, _>(&file, cfg, &tmp_file()?, Cfg::default(), |map_operation| map_operation);
+            assert!(matches!(res, Err(ConvertError::LoadFileError(LoadFileError::Cancelled))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_progress() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::LoadProgress;
+        use std::sync::{Arc, Mutex};
+
+        for text in [true, false] {
+            let file = tmp_file()?;
+            let cfg = if text { Cfg::builder().text_format() } else { Cfg::builder().bin_format() }.build()?;
+            BTreeMap::<usize, usize>::from_iter_new(&file, cfg, (0..25).map(|i| (i, i)))?;
+            let file_len = std::fs::metadata(&file)?.len();
+
+            let reports = Arc::new(Mutex::new(Vec::new()));
+            let builder = if text { Cfg::builder().text_format() } else { Cfg::builder().bin_format() };
+            let cfg = {
+                let reports = reports.clone();
+                builder.load_progress(10, move |progress: LoadProgress| {
+                    reports.lock().unwrap().push(progress);
+                    Ok(())
+                }).build()?
+            };
+            let map: BTreeMap<usize, usize> = BTreeMap::open_or_create(&file, cfg)?;
+            assert_eq!(25, map.map().len());
+            drop(map);
+
+            let reports = reports.lock().unwrap().clone();
+            assert_eq!(vec![10, 20, 25], reports.iter().map(|progress| progress.records).collect::<Vec<_>>());
+            assert!(reports[0].bytes_read < reports[1].bytes_read);
+            assert_eq!(LoadProgress { records: 25, bytes_read: file_len, total_bytes: file_len }, reports[2]);
+
+            // error of the callback interrupts loading, the file is not locked after it
+            let builder = if text { Cfg::builder().text_format() } else { Cfg::builder().bin_format() };
+            let cfg = builder.load_progress(10, |progress| if progress.records < 20 { Ok(()) } else { Err(()) }).build()?;
+            let res: Result<BTreeMap<usize, usize>, LoadFileError> = BTreeMap::open_or_create(&file, cfg);
+            assert!(matches!(res, Err(LoadFileError::Interrupted)));
+            let cfg = if text { Cfg::builder().text_format() } else { Cfg::builder().bin_format() }.build()?;
+            let map: BTreeMap<usize, usize> = BTreeMap::open_or_create(&file, cfg)?;
+            assert_eq!(25, map.map().len());
+        }
+
+        assert!(matches!(Cfg::builder().load_progress(0, |_| Ok(())).build(), Err(crate::cfg_builder::CfgError::ZeroLoadProgressInterval)));
+        Ok(())
+    }
+
+    #[test]
+    fn index_get_arc() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let parity_index = map.create_btree_index(|value: &i32| value % 2);
+        for i in 0..1000 {
+            map.insert(CountingKey(i), i)?;
+        }
+
+        let clones_before = key_clones();
+        let even = parity_index.get_arc(&0).unwrap();
+        let odd = parity_index.get_arc(&1).unwrap();
+        assert_eq!(key_clones(), clones_before);
+        assert!(parity_index.get_arc(&2).is_none());
+        assert_eq!(even.len(), 500);
+        assert_eq!(odd.len(), 500);
+
+        // snapshots are not changed by later changes of the index
+        map.remove(&CountingKey(0))?;
+        map.insert(CountingKey(2), 3)?;
+        map.insert(CountingKey(1000), 1000)?;
+        assert_eq!(even.len(), 500);
+        assert!(even.contains(&CountingKey(0)) && even.contains(&CountingKey(2)));
+        assert!(!even.contains(&CountingKey(1000)));
+        assert_eq!(odd.len(), 500);
+        assert!(!odd.contains(&CountingKey(2)));
+
+        let even_now = parity_index.get_arc(&0).unwrap();
+        assert_eq!(even_now.len(), 499);
+        assert!(!even_now.contains(&CountingKey(0)) && !even_now.contains(&CountingKey(2)) && even_now.contains(&CountingKey(1000)));
+        assert_eq!(parity_index.get_arc(&1).unwrap().len(), 501);
+        assert_eq!(parity_index.get(&1).len(), 501);
+
+        Ok(())
+    }
+
+    #[test]
+    fn background_verify() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Seek, SeekFrom};
+        use std::time::Duration;
+
+        for text in [true, false] {
+            let file = tmp_file()?;
+            let mut cfg = Cfg::default();
+            if text {
+                cfg.integrity = Some(Integrity::Crc32);
+            } else {
+                cfg.format = Format::Bin(None, None);
+                cfg.integrity = Some(Integrity::Sha1Chain([0; 20]));
+            }
+            let mut map = BTreeMap::open_or_create(&file, cfg)?;
+            map.insert(10, "a".to_string())?;
+            map.flush()?;
+            let second_record_offset = std::fs::metadata(&file)?.len();
+            for i in 11..20 {
+                map.insert(i, "b".to_string())?;
+            }
+
+            let (error_sender, error_receiver) = std::sync::mpsc::channel();
+            let verify_handle = map.start_background_verify(Duration::from_millis(10), move |err| {
+                let _ = error_sender.send(err);
+            });
+
+            // correct file
+            map.insert(20, "c".to_string())?;
+            assert!(error_receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+            // change digit of the key of the second record in place
+            map.flush()?;
+            let corrupted_offset = second_record_offset + if text { 5 } else { 3 };
+            let mut byte = [0];
+            let mut f = std::fs::OpenOptions::new().read(true).write(true).open(&file)?;
+            f.seek(SeekFrom::Start(corrupted_offset))?;
+            f.read_exact(&mut byte)?;
+            byte[0] ^= 1;
+            f.seek(SeekFrom::Start(corrupted_offset))?;
+            f.write_all(&byte)?;
+            drop(f);
+
+            let err = error_receiver.recv_timeout(Duration::from_secs(5))?;
+            assert_eq!(err.offset, second_record_offset);
+            assert!(matches!(err.error, LoadFileError::IntegrityError(_)));
+
+            drop(verify_handle);
+            drop(map);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_builder() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::RawValue;
+        use crate::cfg_builder::CfgError;
+        use crate::log_shipper::{LogShipper, LogShipping};
+        use std::time::Duration;
+
+        struct NullShipper;
+        impl LogShipper for NullShipper {
+            fn ship(&mut self, _: &[u8]) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+            fn flush(&mut self) {}
+        }
+
+        let cfg = Cfg::builder()
+            .bin_format()
+            .sha256_chain([1; 32])
+            .write_error(|_| {})
+            .secondary_sink(std::io::sink())
+            .secondary_sink_error(|_| {})
+            .log_shipping(LogShipping::new(NullShipper))
+            .value_schema_version(2)
+            .value_migrator(Box::new(|_, raw_value: RawValue| Ok(raw_value)))
+            .skip_identical_inserts(true)
+            .build()?;
+        assert!(matches!(cfg.format, Format::Bin(None, None)));
+        assert!(matches!(cfg.integrity, Some(Integrity::Sha256Chain(hash)) if hash == [1; 32]));
+        assert!(cfg.skip_identical_inserts);
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::builder().crc32().build()?)?;
+        map.insert(1, 1)?;
+        drop(map);
+        assert_eq!(std::fs::read_to_string(&file)?, "ins [1,1] 3959466553\n");
+
+        let res = Cfg::builder().text_format_with_callbacks(Some(Box::new(|_: &mut String| {})), None).build();
+        assert_eq!(res.err(), Some(CfgError::UnpairedFormatCallbacks));
+        let res = Cfg::builder().bin_format_with_callbacks(None, Some(Box::new(|_: &mut Vec<u8>| Ok(())))).build();
+        assert_eq!(res.err(), Some(CfgError::UnpairedFormatCallbacks));
+        let res = Cfg::builder().bin_format_with_callbacks(Some(Box::new(|_: &mut Vec<u8>| {})), Some(Box::new(|_: &mut Vec<u8>| Ok(())))).build();
+        assert!(res.is_ok());
+
+        let res = Cfg::builder().value_migrator(Box::new(|_, raw_value: RawValue| Ok(raw_value))).build();
+        assert_eq!(res.err(), Some(CfgError::ValueMigratorWithoutSchemaVersion));
+
+        let res = Cfg::builder().secondary_sink_error(|_| {}).build();
+        assert_eq!(res.err(), Some(CfgError::SinkErrorCallbackWithoutSink));
+
+        let mut log_shipping = LogShipping::new(NullShipper);
+        log_shipping.max_batch_bytes = 0;
+        let res = Cfg::builder().log_shipping(log_shipping).build();
+        assert_eq!(res.err(), Some(CfgError::WrongLogShippingLimits));
+        let mut log_shipping = LogShipping::new(NullShipper);
+        log_shipping.min_retry_delay = Duration::from_secs(10);
+        log_shipping.max_retry_delay = Duration::from_secs(1);
+        let res = Cfg::builder().log_shipping(log_shipping).build();
+        assert_eq!(res.err(), Some(CfgError::WrongLogShippingLimits));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cfg_debug_and_describe() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{CfgDescription, FormatKind, WriteChannel};
+
+        let mut cfg = Cfg::default();
+        cfg.format = Format::Bin(Some(Box::new(|_| {})), None);
+        cfg.integrity = Some(Integrity::Sha1Chain([0xab; 20]));
+        cfg.write_error_context_callback = Some(Box::new(|_| {}));
+        cfg.value_schema_version = Some(3);
+        assert_eq!(
+            format!("{:?}", cfg),
+            format!("Cfg {{ format: Bin {{ before_write_callback: true, after_read_callback: false }}, integrity: Some(Sha1Chain(\"{}\")), \
+                write_error_callback: false, write_error_context_callback: true, write_ack_callback: false, secondary_sink: false, secondary_sink_error_callback: false, log_shipping: false, on_worker_failure: false, \
+                value_schema_version: Some(3), value_migrator: false, skip_identical_inserts: false, write_channel: Std, max_pending_writes: None, load_cancel: None, load_progress_callback: false, load_progress_interval: 10000, max_entries: None, compress_values_over: None, max_value_size: None, dedupe_consecutive: false, schema_fingerprint: None, strict_replay: false, collect_replay_anomalies: false, dirty_marker: false, chain_head_file: false, extra_text_ops: [], on_deserialize_error: Fail, write_mode: Background, fsync_policy: Never, write_retry: None, stop_timeout: None, auto_compact: None, snapshot: None, recovery: Fail }}", "ab".repeat(20))
+        );
+        assert_eq!(format!("{:?}", Format::Text(None, None)), "Text { before_write_callback: false, after_read_callback: false }");
+        assert_eq!(format!("{:?}", Integrity::Crc32), "Crc32");
+
+        let description = cfg.describe();
+        assert_eq!(description, CfgDescription {
+            format: FormatKind::Bin,
+            integrity: Some(Integrity::Sha1Chain([0xab; 20])),
+            value_schema_version: Some(3),
+            skip_identical_inserts: false,
+            write_channel: WriteChannel::Std,
+            max_pending_writes: None,
+            load_progress_interval: 10000,
+            max_entries: None,
+            compress_values_over: None,
+            max_value_size: None,
+            dedupe_consecutive: false,
+            schema_fingerprint: None,
+            strict_replay: false,
+            collect_replay_anomalies: false,
+            dirty_marker: false,
+            chain_head_file: false,
+            extra_text_ops: Vec::new(),
+            on_deserialize_error: DeserializePolicy::Fail,
+            write_mode: WriteMode::Background,
+            fsync_policy: FsyncPolicy::Never,
+            write_retry: None,
+            stop_timeout: None,
+            auto_compact: None,
+            snapshot: None,
+            recovery: RecoveryMode::Fail,
+        });
+        assert_eq!(Cfg::from(description.clone()).describe(), description);
+
+        let clone = cfg.clone_without_callbacks();
+        assert_eq!(clone.describe(), description);
+        assert!(matches!(clone.format, Format::Bin(None, None)));
+        assert!(clone.write_error_context_callback.is_none());
+
+        assert_ne!(Cfg::default().describe(), description);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_error_context() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{WriteErrorContext, WriteOperation};
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailSwitch, FailingWriter, SharedBuffer};
+        use std::path::PathBuf;
+        use std::sync::{Arc, Mutex};
+
+        let fail = FailSwitch::new();
+        fail.on();
+        let contexts = Arc::new(Mutex::new(Vec::new()));
+        let callback_contexts = contexts.clone();
+        let mut cfg = Cfg::default();
+        cfg.write_error_context_callback = Some(Box::new(move |context: WriteErrorContext| callback_contexts.lock().unwrap().push(context)));
+        let file_worker = FileWorker::new(FailingWriter::fail_when(SharedBuffer::new(), fail.clone()), FileWorkerCfg::take_from(&mut cfg, PathBuf::from("db/map.txt"), 0));
+
+        file_worker.write_string("ins [1,1]\n".to_string(), WriteOperation::Insert)?;
+        file_worker.write_bytes(vec![0; 4], WriteOperation::Remove)?;
+        assert_eq!(file_worker.flush().recv()?.map_err(|err| err.to_string()), Err("injected write failure".to_string()));
+        fail.off();
+        file_worker.write_bytes(vec![0; 8], WriteOperation::Transaction)?;
+        file_worker.flush().recv()??;
+        fail.on();
+        file_worker.write_bytes(vec![0; 16], WriteOperation::Batch)?;
+        drop(file_worker);
+
+        let contexts = contexts.lock().unwrap();
+        let contexts = contexts.iter()
+            .map(|context| (context.file_path.clone(), context.sequence, context.operation, context.bytes, context.consecutive_failures, context.error.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(contexts, vec![
+            (PathBuf::from("db/map.txt"), 1, WriteOperation::Insert, 10, 1, "injected write failure".to_string()),
+            (PathBuf::from("db/map.txt"), 2, WriteOperation::Remove, 4, 2, "injected write failure".to_string()),
+            (PathBuf::from("db/map.txt"), 4, WriteOperation::Batch, 16, 1, "injected write failure".to_string()),
+        ]);
+
+        // deprecated callback receives only error
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let callback_errors = errors.clone();
+        let mut cfg = Cfg::default();
+        #[allow(deprecated)]
+        {
+            cfg.write_error_callback = Some(Box::new(move |err| callback_errors.lock().unwrap().push(err.to_string())));
+        }
+        let mut error_callback = cfg.take_write_error_callback().unwrap();
+        error_callback(WriteErrorContext { error: std::io::Error::other("error"), file_path: PathBuf::new(), sequence: 1, operation: WriteOperation::Insert, bytes: 1, consecutive_failures: 1, data: vec![0] });
+        assert_eq!(*errors.lock().unwrap(), vec!["error".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_ack() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{WriteAck, WriteOperation};
+        use std::sync::{Arc, Mutex};
+
+        let file = tmp_file()?;
+        for reopen in [false, true] {
+            let initial_len = std::fs::metadata(&file).map(|metadata| metadata.len()).unwrap_or(0);
+            let acks = Arc::new(Mutex::new(Vec::<WriteAck>::new()));
+            let callback_acks = acks.clone();
+            let mut cfg = Cfg::default();
+            cfg.write_ack_callback = Some(Box::new(move |ack| callback_acks.lock().unwrap().push(ack)));
+            let mut map = BTreeMap::open_or_create(&file, cfg)?;
+            for i in 0..10 {
+                map.insert(i, i.to_string())?;
+            }
+            map.remove(&3)?;
+            map.apply_batch(vec![MapOperation::Insert(20, "a".to_string()), MapOperation::Remove(4)])?;
+            drop(map);
+
+            let acks = acks.lock().unwrap();
+            assert_eq!(acks.len(), 12);
+            assert_eq!(acks[0].offset, initial_len);
+            assert_eq!(acks[10].operation, WriteOperation::Remove);
+            assert_eq!(acks[11].operation, WriteOperation::Batch);
+            for (ack, next_ack) in acks.iter().zip(acks.iter().skip(1)) {
+                assert!(next_ack.offset > ack.offset);
+                assert_eq!(next_ack.offset, ack.offset + ack.bytes as u64);
+                assert_eq!(next_ack.sequence, ack.sequence + 1);
+            }
+            let last_ack = acks.last().unwrap();
+            assert_eq!(last_ack.offset + last_ack.bytes as u64, std::fs::metadata(&file)?.len());
+            assert!(initial_len > 0 || !reopen);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_keys_and_first() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..100 {
+            map.insert(i, if i % 10 == 3 { "ready" } else { "wait" }.to_string())?;
+        }
+
+        assert_eq!(map.find_keys(|_, status| status == "ready"), vec![3, 13, 23, 33, 43, 53, 63, 73, 83, 93]);
+        assert!(map.find_keys(|_, status| status == "done").is_empty());
+
+        // iteration stops on the first found element
+        let mut calls = 0;
+        let found = map.find_first(|_, status| {
+            calls += 1;
+            status == "ready"
+        });
+        assert_eq!(found, Some((3, "ready".to_string())));
+        assert_eq!(calls, 4);
+
+        let mut calls = 0;
+        assert_eq!(map.find_first(|_, status| { calls += 1; status == "done" }), None);
+        assert_eq!(calls, 100);
+
+        let file = tmp_file()?;
+        let mut map = HashMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, 1)?;
+        map.insert(2, 2)?;
+        let mut calls = 0;
+        assert!(map.find_first(|_, _| { calls += 1; true }).is_some());
+        assert_eq!(calls, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_map_replay() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::MultiMap;
+
+        for format in [Format::Text(None, None), Format::Bin(None, None)] {
+            let text = matches!(format, Format::Text(..));
+            let file = tmp_file()?;
+            let mut cfg = Cfg::default();
+            cfg.format = format;
+            cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+            let mut multi_map = MultiMap::<u32, u32>::open_or_create(&file, cfg)?;
+            let index = multi_map.inner_mut().create_btree_index(|items| items.len());
+            let mut model = std::collections::BTreeMap::<u32, Vec<u32>>::new();
+
+            // pseudo random sequence of push and remove
+            let mut rnd = 12345u32;
+            for _ in 0..3000 {
+                rnd = rnd.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = (rnd >> 8) % 20;
+                let item = (rnd >> 16) % 5;
+                if rnd % 3 == 0 {
+                    let model_items = model.entry(key).or_default();
+                    let expected_removed = match model_items.iter().position(|it| *it == item) {
+                        Some(i) => { model_items.remove(i); true },
+                        None => false,
+                    };
+                    if model_items.is_empty() {
+                        model.remove(&key);
+                    }
+                    assert_eq!(multi_map.remove_item(&key, &item)?, expected_removed);
+                } else {
+                    model.entry(key).or_default().push(item);
+                    multi_map.push(key, item)?;
+                }
+                assert_eq!(multi_map.items(&key), model.get(&key).map(Vec::as_slice).unwrap_or(&[]));
+            }
+            // whole collection can be replaced too
+            multi_map.inner_mut().insert(100, vec![1, 2, 3])?;
+            model.insert(100, vec![1, 2, 3]);
+            multi_map.push(100, 4)?;
+            model.get_mut(&100).unwrap().push(4);
+
+            assert_eq!(multi_map.inner().map(), &model);
+            for len in 1..10 {
+                let expected: Vec<u32> = model.iter().filter(|(_, items)| items.len() == len).map(|(key, _)| *key).collect();
+                assert_eq!(index.get(&len), expected);
+            }
+            drop(multi_map);
+
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+            let multi_map = MultiMap::<u32, u32>::open_or_create(&file, cfg)?;
+            assert_eq!(multi_map.inner().map(), &model);
+
+            // file with item records can't be loaded as usual map
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+            drop(multi_map);
+            let res = BTreeMap::<u32, Vec<u32>>::open_or_create(&file, cfg);
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::UnexpectedItemOperation { line_num: 1 })));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_with_file() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::{BTreeSet, HashSet};
+        use crate::format::convert;
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Crc32);
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut set = BTreeSet::<String>::open_or_create(&file, make_cfg())?;
+            let index = set.create_btree_index(|element| element.len());
+            assert!(set.insert("a".to_string())?);
+            assert!(set.insert("bb".to_string())?);
+            assert!(set.insert("cc".to_string())?);
+            assert!(!set.insert("a".to_string())?);
+            assert!(set.remove(&"cc".to_string())?);
+            assert!(!set.remove(&"cc".to_string())?);
+            assert!(set.contains(&"a".to_string()));
+            assert!(!set.contains(&"cc".to_string()));
+            assert_eq!(set.len(), 2);
+            assert_eq!(index.get(&1), vec!["a".to_string()]);
+            assert_eq!(index.get(&2), vec!["bb".to_string()]);
+            drop(set);
+
+            if text {
+                let content = std::fs::read_to_string(&file)?;
+                assert_eq!(content.lines().map(|line| &line[..line.rfind(' ').unwrap()]).collect::<Vec<_>>(), vec!["add \"a\"", "add \"bb\"", "add \"cc\"", "del \"cc\""]);
+            }
+
+            let set = BTreeSet::<String>::open_or_create(&file, make_cfg())?;
+            let mut elements = Vec::new();
+            set.iter_each(|element| elements.push(element.clone()));
+            assert_eq!(elements, vec!["a".to_string(), "bb".to_string()]);
+            drop(set);
+
+            let set = HashSet::<String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(set.len(), 2);
+            drop(set);
+
+            // file of set can't be loaded as usual map
+            let res = BTreeMap::<String, ()>::open_or_create(&file, make_cfg());
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::UnexpectedSetOperation { line_num: 1 })));
+        }
+
+        // map with empty values is loaded as set directly or after conversion
+        let map_file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&map_file, Cfg::default())?;
+        map.insert(1, ())?;
+        map.insert(2, ())?;
+        map.remove(&1)?;
+        drop(map);
+
+        let set = BTreeSet::<i32>::open_or_create(&map_file, Cfg::default())?;
+        assert_eq!(set.len(), 1);
+        drop(set);
+
+        let set_file = tmp_file()?;
+        convert::<i32, (), i32, (), _>(&map_file, Cfg::default(), &set_file, Cfg::default(), |map_operation| map_operation)?;
+        let mut set = BTreeSet::<i32>::open_or_create(&set_file, Cfg::default())?;
+        assert!(set.contains(&2));
+        assert!(set.insert(3)?);
+        drop(set);
+        let set = BTreeSet::<i32>::open_or_create(&set_file, Cfg::default())?;
+        assert_eq!(set.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn counters() -> Result<(), Box<dyn std::error::Error>> {
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Crc32);
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::<String, u64>::open_or_create_with_counters(&file, make_cfg())?;
+            let index = map.create_btree_index(|value| *value > 10);
+            let key = "key with spaces".to_string();
+            assert_eq!(map.fetch_add(&key, 5)?, 0);
+            assert_eq!(map.fetch_add(&key, 7)?, 5);
+            map.insert(key.clone(), 100)?;
+            assert_eq!(map.fetch_add(&key, -1)?, 100);
+            map.remove(&key)?;
+            assert_eq!(map.fetch_add(&key, 3)?, 0);
+            map.fetch_add(&"other".to_string(), 20)?;
+            assert_eq!(index.get(&true), vec!["other".to_string()]);
+
+            // saturating overflow
+            map.fetch_add(&"min".to_string(), -5)?;
+            map.insert("max".to_string(), u64::MAX - 1)?;
+            map.fetch_add(&"max".to_string(), 10)?;
+            let expected: std::collections::BTreeMap<String, u64> = vec![(key.clone(), 3), ("other".to_string(), 20), ("min".to_string(), 0), ("max".to_string(), u64::MAX)].into_iter().collect();
+            assert_eq!(map.map(), &expected);
+            drop(map);
+
+            if text {
+                assert!(std::fs::read_to_string(&file)?.starts_with("inc \"key with spaces\" 5 "));
+            }
+
+            let map = BTreeMap::<String, u64>::open_or_create_with_counters(&file, make_cfg())?;
+            assert_eq!(map.map(), &expected);
+            drop(map);
+
+            let res = BTreeMap::<String, u64>::open_or_create(&file, make_cfg());
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::UnexpectedIncrementOperation { line_num: 1 })));
+        }
+
+        let file = tmp_file()?;
+        let mut map = HashMap::<u8, i8>::open_or_create_with_counters(&file, Cfg::default())?;
+        map.fetch_add(&1, -1000)?;
+        map.fetch_add(&2, 1000)?;
+        drop(map);
+        let map = HashMap::<u8, i8>::open_or_create_with_counters(&file, Cfg::default())?;
+        assert_eq!(map.get(&1), Some(&i8::MIN));
+        assert_eq!(map.get(&2), Some(&i8::MAX));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_map() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::BoundedMap;
+        use std::sync::{Arc, Mutex};
+
+        let file = tmp_file()?;
+        let cfg = Cfg::builder().max_entries(3).build()?;
+        let mut map = BoundedMap::open_or_create(&file, cfg)?;
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let evicted_clone = evicted.clone();
+        map.on_eviction(move |key, value| evicted_clone.lock().unwrap().push((key, value)));
+
+        for i in 0..5 {
+            map.insert(i, i * 10)?;
+        }
+        assert_eq!(*evicted.lock().unwrap(), vec![(0, 0), (1, 10)]);
+        assert_eq!(map.len(), 3);
+
+        // access changes recency without writing
+        assert_eq!(map.get(&2), Some(&20));
+        map.insert(5, 50)?;
+        assert_eq!(evicted.lock().unwrap().last(), Some(&(3, 30)));
+        assert_eq!(map.peek(&2), Some(&20));
+        map.remove(&4)?;
+        map.insert(6, 60)?;
+        assert_eq!(evicted.lock().unwrap().len(), 3);
+        let survivors = map.inner().map().clone();
+        assert_eq!(survivors.keys().cloned().collect::<Vec<_>>(), vec![2, 5, 6]);
+        drop(map);
+
+        let cfg = Cfg::builder().max_entries(3).build()?;
+        let map = BoundedMap::<i32, i32>::open_or_create(&file, cfg)?;
+        assert_eq!(map.inner().map(), &survivors);
+        drop(map);
+
+        // after reopening recency is restored by inserts, access by 'get' is not persisted
+        let cfg = Cfg::builder().max_entries(2).build()?;
+        let map = BoundedMap::<i32, i32>::open_or_create(&file, cfg)?;
+        assert_eq!(map.inner().map().keys().cloned().collect::<Vec<_>>(), vec![5, 6]);
+        drop(map);
+        let map = BTreeMap::<i32, i32>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.map().keys().cloned().collect::<Vec<_>>(), vec![5, 6]);
+
+        assert!(matches!(Cfg::builder().max_entries(0).build(), Err(crate::cfg_builder::CfgError::ZeroMaxEntries)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_map() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::testing::{Clock, ManualClock};
+        use crate::TtlMap;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_millis(1_000_000));
+        let now = || clock.now();
+
+        let file = tmp_file()?;
+        let mut map = TtlMap::open_or_create_with_clock(&file, Cfg::default(), Duration::from_secs(10), clock.clone())?;
+        map.insert("a".to_string(), 1)?;
+        map.insert_with_ttl("b".to_string(), 2, Duration::from_secs(100))?;
+        map.insert("c".to_string(), 3)?;
+        assert_eq!(map.get(&"a".to_string()), Some(&1));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(map.get(&"a".to_string()), None);
+        assert_eq!(map.get(&"b".to_string()), Some(&2));
+        // insert over expired value returns None
+        assert_eq!(map.insert("c".to_string(), 30)?, None);
+        assert_eq!(map.len(), 3);
+        drop(map);
+
+        // expired at load time entries are not restored
+        let mut map = TtlMap::<String, i32>::open_or_create(&file, Cfg::builder().clock(clock.clone()).build()?, Duration::from_secs(10))?;
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&"c".to_string()), Some(&30));
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(map.get(&"c".to_string()), None);
+        assert_eq!(map.purge_expired(now())?, 1);
+        assert_eq!(map.purge_expired(now())?, 0);
+        assert_eq!(map.len(), 1);
+        drop(map);
+
+        // purged entries are removed in the file, so they are not restored with earlier clock
+        clock.set(UNIX_EPOCH);
+        let map = TtlMap::<String, i32>::open_or_create_with_clock(&file, Cfg::default(), Duration::from_secs(10), clock.clone())?;
+        assert_eq!(map.get(&"c".to_string()), None);
+        assert_eq!(map.get(&"b".to_string()), Some(&2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn value_compression() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::convert;
+
+        let big_value = "compressible ".repeat(1000);
+        for text in [true, false] {
+            let make_cfg = |compress_values_over| {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg.compress_values_over = compress_values_over;
+                cfg
+            };
+
+            let plain_file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&plain_file, make_cfg(None))?;
+            map.insert(1, big_value.clone())?;
+            map.insert(2, "small".to_string())?;
+            drop(map);
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(Some(100)))?;
+            map.insert(1, big_value.clone())?;
+            map.insert(2, "small".to_string())?;
+            map.apply_batch(vec![MapOperation::Insert(3, big_value.clone()), MapOperation::Remove(2)])?;
+            drop(map);
+
+            assert!(std::fs::metadata(&file)?.len() * 5 < std::fs::metadata(&plain_file)?.len());
+            if text {
+                let content = std::fs::read_to_string(&file)?;
+                assert!(content.starts_with("ins [1,{\"__dkmz\":\""));
+                assert!(content.lines().nth(1).unwrap().starts_with("ins [2,\"small\"] "));
+            }
+
+            // decompression doesn't depend on the setting
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(None))?;
+            assert_eq!(map.get(&1), Some(&big_value));
+            assert_eq!(map.get(&2), None);
+            assert_eq!(map.get(&3), Some(&big_value));
+            drop(map);
+
+            // converted file is compressed by settings of the destination
+            let converted_file = tmp_file()?;
+            let mut dst_cfg = Cfg::default();
+            dst_cfg.compress_values_over = Some(100);
+            convert::<i32, String, i32, String, _>(&file, make_cfg(None), &converted_file, dst_cfg, |map_operation| map_operation)?;
+            assert!(std::fs::read_to_string(&converted_file)?.contains("__dkmz"));
+            let map = BTreeMap::<i32, String>::open_or_create(&converted_file, Cfg::default())?;
+            assert_eq!(map.get(&3), Some(&big_value));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_value_size() -> Result<(), Box<dyn std::error::Error>> {
+        let value = "v".repeat(100);
+        let longer_value = "v".repeat(101);
+        for text in [true, false] {
+            let make_cfg = |max_value_size| {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg.max_value_size = max_value_size;
+                cfg
+            };
+
+            // size of one record
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(None))?;
+            map.insert(1, value.clone())?;
+            drop(map);
+            let record_size = std::fs::metadata(&file)?.len() as usize;
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(Some(record_size)))?;
+            map.insert(1, value.clone())?;
+            let res = map.insert(2, longer_value.clone());
+            assert!(matches!(res, Err(SerializedError::ValueTooLarge { size, limit }) if size == record_size + 1 && limit == record_size));
+            assert_eq!(map.get(&2), None);
+            let res = map.apply_batch(vec![MapOperation::Remove(1), MapOperation::Insert(2, value.clone())]);
+            assert!(matches!(res, Err(SerializedError::ValueTooLarge { .. })));
+            assert_eq!(map.get(&1), Some(&value));
+            map.insert(3, value.clone())?;
+            drop(map);
+            assert_eq!(std::fs::metadata(&file)?.len() as usize, record_size * 2);
+
+            // integrity chain continues after rejected records
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some(record_size)))?;
+            assert_eq!(map.get(&1), Some(&value));
+            assert_eq!(map.get(&3), Some(&value));
+            drop(map);
+
+            let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some(record_size - 1)));
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::RecordTooLarge { size, limit, line_num: 1 }) if size == record_size && limit == record_size - 1));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_compat() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::JsonCompat;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        enum Edge { Road { len: u32 }, Bridge(u32, u32), Ferry }
+
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        struct Graph {
+            name: String,
+            edges: std::collections::HashMap<(u32, u32), Edge>,
+            labels: std::collections::BTreeMap<String, Option<u8>>,
+        }
+
+        let mut graph = Graph { name: "g".to_string(), edges: Default::default(), labels: Default::default() };
+        graph.edges.insert((1, 2), Edge::Road { len: 10 });
+        graph.edges.insert((2, 3), Edge::Bridge(4, 5));
+        graph.edges.insert((3, 1), Edge::Ferry);
+        graph.labels.insert("a".to_string(), Some(1));
+        graph.labels.insert("b".to_string(), None);
+
+        // binary format stores such struct as is
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.format = Format::Bin(None, None);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        map.insert(1, graph.clone())?;
+        drop(map);
+        let mut cfg = Cfg::default();
+        cfg.format = Format::Bin(None, None);
+        let map = BTreeMap::<i32, Graph>::open_or_create(&file, cfg)?;
+        assert_eq!(map.get(&1), Some(&graph));
+        drop(map);
+
+        // text format fails with clear error
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let err = map.insert(1, graph.clone()).err().ok_or("map with tuple keys must not be serialized to JSON")?;
+        assert!(err.is_non_string_map_key());
+        assert!(err.to_string().contains("JsonCompat"));
+        assert_eq!(map.get(&1), None);
+        drop(map);
+
+        // text format with wrapper
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, JsonCompat(graph.clone()))?;
+        drop(map);
+        let content = std::fs::read_to_string(&file)?;
+        assert!(content.contains("\"edges\":{\"__dkmp\":[[["));
+        assert!(content.contains("\"labels\":{\"a\":1,\"b\":null}"));
+        let map = BTreeMap::<i32, JsonCompat<Graph>>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&1), Some(&JsonCompat(graph.clone())));
+        drop(map);
+
+        // wrapper in binary format
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.format = Format::Bin(None, None);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        map.insert(1, JsonCompat(graph.clone()))?;
+        drop(map);
+        let mut cfg = Cfg::default();
+        cfg.format = Format::Bin(None, None);
+        let map = BTreeMap::<i32, JsonCompat<Graph>>::open_or_create(&file, cfg)?;
+        assert_eq!(map.get(&1), Some(&JsonCompat(graph)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_by() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::{BTreeMapOrderedBy, KeyOrder, OrderedBy};
+
+        struct CaseInsensitive;
+        impl KeyOrder<String> for CaseInsensitive {
+            fn cmp(a: &String, b: &String) -> std::cmp::Ordering {
+                a.to_lowercase().cmp(&b.to_lowercase())
+            }
+        }
+
+        let keys = |map: &BTreeMapOrderedBy<String, u32, CaseInsensitive>| map.map().keys().map(|key| key.key().clone()).collect::<Vec<_>>();
+
+        let file = tmp_file()?;
+        let mut map = BTreeMapOrderedBy::<String, u32, CaseInsensitive>::open_or_create(&file, Cfg::default())?;
+        let index = map.create_btree_index(|value| *value % 2);
+        map.insert(OrderedBy::new("Banana".to_string()), 1)?;
+        map.insert("cherry".to_string().into(), 2)?;
+        map.insert("apple".to_string().into(), 3)?;
+        map.insert("Cherry".to_string().into(), 5)?;
+        assert_eq!(keys(&map), vec!["apple", "Banana", "cherry"]);
+        assert_eq!(map.get(&"CHERRY".to_string().into()), Some(&5));
+        // index contains key of the last insert, map keeps the first of equal keys
+        assert_eq!(index.get(&1).into_iter().map(OrderedBy::into_key).collect::<Vec<_>>(), vec!["apple", "Banana", "Cherry"]);
+        drop(map);
+
+        // file contains plain keys
+        assert!(std::fs::read_to_string(&file)?.starts_with("ins [\"Banana\",1]"));
+
+        let map = BTreeMapOrderedBy::<String, u32, CaseInsensitive>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(keys(&map), vec!["apple", "Banana", "cherry"]);
+        let range = map.map().range(OrderedBy::new("b".to_string())..OrderedBy::new("C".to_string()))
+            .map(|(key, _)| key.key().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(range, vec!["Banana"]);
+        let range = map.map().range(OrderedBy::new("B".to_string())..)
+            .map(|(key, value)| (key.key().as_str(), *value))
+            .collect::<Vec<_>>();
+        assert_eq!(range, vec![("Banana", 1), ("cherry", 5)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_canonicalizer() -> Result<(), Box<dyn std::error::Error>> {
+        let canonicalize = |key: &String| key.trim().to_lowercase();
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::<String, u32>::open_or_create(&file, Cfg::default())?;
+        map.insert("  Old ".to_string(), 0)?;
+        map.set_key_canonicalizer(canonicalize);
+        let index = map.create_btree_index(|value| *value);
+        map.insert("  Foo ".to_string(), 1)?;
+        assert_eq!(map.get(&"foo".to_string()), Some(&1));
+        assert!(map.contains_key(&"FOO".to_string()));
+        map.insert("FOO".to_string(), 2)?;
+        assert_eq!(map.map().len(), 2);
+        assert_eq!(index.get(&2), vec!["foo".to_string()]);
+        assert_eq!(map.non_canonical_keys(), vec!["  Old ".to_string()]);
+        map.apply_batch(vec![MapOperation::Insert(" Bar".to_string(), 3), MapOperation::Remove(" foo ".to_string())])?;
+        assert_eq!(map.get(&"bar".to_string()), Some(&3));
+        assert_eq!(map.remove(&"BAR".to_string())?, Some(3));
+        let mut txn = map.transaction();
+        assert_eq!(txn.insert(" Baz ".to_string(), 4), None);
+        assert_eq!(txn.get(&"BAZ".to_string()), Some(&4));
+        assert_eq!(txn.insert("QUX".to_string(), 5), None);
+        assert_eq!(txn.remove(&" qux".to_string()), Some(5));
+        txn.commit()?;
+        assert_eq!(map.get(&"baz".to_string()), Some(&4));
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+
+        let content = std::fs::read_to_string(&file)?;
+        assert_eq!(content, "ins [\"  Old \",0]\nins [\"foo\",1]\nins [\"foo\",2]\nbat [[\"ins\",[\"bar\",3]],[\"rem\",\"foo\"]]\nrem \"bar\"\ntxb\nins [\"baz\",4]\ntxe\n");
+
+        // loading doesn't canonicalize
+        let mut map = BTreeMap::<String, u32>::open_or_create(&file, Cfg::default())?;
+        map.set_key_canonicalizer(canonicalize);
+        assert_eq!(map.non_canonical_keys(), vec!["  Old ".to_string()]);
+        assert!(!map.contains_key(&"  Old ".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn record_meta() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::bin_format::load_from_bin_file_with_meta;
+        use crate::text_format::load_from_text_file_with_meta;
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            map.insert(1, "a".to_string())?;
+            map.insert_with_meta(2, "b".to_string(), &"alice smith".to_string())?;
+            assert_eq!(map.remove_with_meta(&1, &"bob".to_string())?, Some("a".to_string()));
+            assert_eq!(map.remove_with_meta(&5, &"bob".to_string())?, None);
+            map.insert(3, "c".to_string())?;
+            drop(map);
+
+            if text {
+                let content = std::fs::read_to_string(&file)?;
+                assert!(content.lines().nth(1).unwrap().starts_with("met \"alice smith\" ins [2,\"b\"] "));
+            }
+
+            // metadata is skipped by plain loading
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&1), None);
+            assert_eq!(map.get(&2), Some(&"b".to_string()));
+            assert_eq!(map.get(&3), Some(&"c".to_string()));
+            drop(map);
+
+            let expected = vec![(1, None), (2, Some("alice smith".to_string())), (-1, Some("bob".to_string())), (3, None)];
+            let mut records = Vec::new();
+            let map = BTreeMap::<i32, String>::open_or_create_with_meta(&file, make_cfg(), |map_operation, meta: Option<String>| {
+                match map_operation {
+                    MapOperation::Insert(key, _) => records.push((*key, meta)),
+                    MapOperation::Remove(key) => records.push((-key, meta)),
+                }
+            })?;
+            assert_eq!(records, expected);
+            assert_eq!(map.get(&2), Some(&"b".to_string()));
+            drop(map);
+
+            let mut records = Vec::new();
+            let collect = |map_operation: MapOperation<i32, String>, meta: Option<String>| {
+                match map_operation {
+                    MapOperation::Insert(key, _) => records.push((key, meta)),
+                    MapOperation::Remove(key) => records.push((-key, meta)),
+                }
+                Ok(())
+            };
+            let mut history = std::fs::File::open(&file)?;
+            let mut integrity = Some(Integrity::Sha256Chain([0; 32]));
+            if text {
+                load_from_text_file_with_meta(&mut history, &mut integrity, None::<fn(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>>, collect)?;
+            } else {
+                load_from_bin_file_with_meta(&mut history, &mut integrity, None::<fn(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>>, collect)?;
+            }
+            assert_eq!(records, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_consecutive() -> Result<(), Box<dyn std::error::Error>> {
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg.dedupe_consecutive = true;
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            for _ in 0..5 {
+                map.insert(1, "a".to_string())?;
+            }
+            for _ in 0..3 {
+                map.insert(2, "b".to_string())?;
+            }
+            // not consecutive duplicate because of remove between
+            map.remove(&2)?;
+            map.remove(&2)?;
+            map.insert(2, "b".to_string())?;
+            map.insert(2, "b".to_string())?;
+            map.insert(1, "a".to_string())?;
+            assert_eq!(map.metrics().operations_since_open, 5);
+            drop(map);
+
+            let mut records = 0;
+            let map = BTreeMap::<i32, String>::open_or_create_with_meta(&file, make_cfg(), |_, _: Option<()>| records += 1)?;
+            assert_eq!(records, 5);
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            assert_eq!(map.get(&2), Some(&"b".to_string()));
+            if text {
+                assert_eq!(std::fs::read_to_string(&file)?.lines().count(), 5);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn schema_fingerprint() -> Result<(), Box<dyn std::error::Error>> {
+        for text in [true, false] {
+            let make_cfg = |fingerprint: Option<&str>| {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg.schema_fingerprint = fingerprint.map(str::to_string);
+                cfg
+            };
+
+            // match
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(Some("id:i32/name:v1")))?;
+            map.insert(1, "a".to_string())?;
+            drop(map);
+            if text {
+                let content = std::fs::read_to_string(&file)?;
+                assert!(content.lines().next().unwrap().starts_with("sch \"id:i32/name:v1\" "));
+            }
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some("id:i32/name:v1")))?;
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            drop(map);
+
+            // record is not written again when file is opened
+            let len = std::fs::metadata(&file)?.len();
+            drop(BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some("id:i32/name:v1")))?);
+            assert_eq!(std::fs::metadata(&file)?.len(), len);
+
+            // config without fingerprint doesn't check the record
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(None))?;
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            drop(map);
+
+            // mismatch
+            let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some("id:i32/name:v2")));
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::SchemaMismatch { expected, found }) if expected == "id:i32/name:v2" && found == "id:i32/name:v1"));
+
+            // absent record
+            let old_file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&old_file, make_cfg(None))?;
+            map.insert(2, "b".to_string())?;
+            drop(map);
+            let len = std::fs::metadata(&old_file)?.len();
+            let map = BTreeMap::<i32, String>::open_or_create(&old_file, make_cfg(Some("id:i32/name:v1")))?;
+            assert_eq!(map.get(&2), Some(&"b".to_string()));
+            drop(map);
+            assert_eq!(std::fs::metadata(&old_file)?.len(), len);
+
+            // convert writes record of the destination
+            let dst_file = tmp_file()?;
+            let mut dst_cfg = make_cfg(Some("id:i64/name:v1"));
+            dst_cfg.format = Format::Text(None, None);
+            crate::format::convert::<i32, String, i64, String, _>(&file, make_cfg(Some("id:i32/name:v1")), &dst_file, dst_cfg, |map_operation| {
+                match map_operation {
+                    MapOperation::Insert(key, value) => MapOperation::Insert(key as i64, value),
+                    MapOperation::Remove(key) => MapOperation::Remove(key as i64),
+                }
+            })?;
+            let mut dst_cfg = make_cfg(Some("id:i64/name:v1"));
+            dst_cfg.format = Format::Text(None, None);
+            let map = BTreeMap::<i64, String>::open_or_create(&dst_file, dst_cfg)?;
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            drop(map);
+            let mut dst_cfg = make_cfg(Some("id:i32/name:v1"));
+            dst_cfg.format = Format::Text(None, None);
+            let res = BTreeMap::<i32, String>::open_or_create(&dst_file, dst_cfg);
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::SchemaMismatch { .. })));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_in_range() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{records_in_range, RecordKind, RecordRange};
+        use crate::bin_format::load_from_bin_file;
+        use crate::text_format::load_from_text_file;
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Crc32);
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            map.insert("a b".to_string(), 1)?;
+            map.insert_with_meta("c".to_string(), 2, &"alice".to_string())?;
+            map.apply_batch(vec![MapOperation::Insert("d".to_string(), 3), MapOperation::Remove("a b".to_string())])?;
+            let mut txn = map.transaction();
+            txn.insert("e".to_string(), 4);
+            txn.commit()?;
+            map.remove(&"c".to_string())?;
+            drop(map);
+
+            let locations = records_in_range::<String>(&file, make_cfg(), RecordRange::Index(0..100))?;
+            let kinds: Vec<_> = locations.iter().map(|location| (location.index, location.kind, location.key.clone())).collect();
+            assert_eq!(kinds, vec![
+                (0, RecordKind::Insert, Some("\"a b\"".to_string())),
+                (1, RecordKind::Insert, Some("\"c\"".to_string())),
+                (2, RecordKind::Batch, None),
+                (3, RecordKind::TransactionBegin, None),
+                (4, RecordKind::Insert, Some("\"e\"".to_string())),
+                (5, RecordKind::TransactionEnd, None),
+                (6, RecordKind::Remove, Some("\"c\"".to_string())),
+            ]);
+
+            // records are contiguous and cover the file
+            let content = std::fs::read(&file)?;
+            assert_eq!(locations[0].offset, 0);
+            for pair in locations.windows(2) {
+                assert_eq!(pair[0].offset + pair[0].len, pair[1].offset);
+            }
+            assert_eq!(locations.last().map(|location| location.offset + location.len), Some(content.len() as u64));
+
+            // slices of insert and remove records are loaded standalone
+            for location in locations.iter().filter(|location| location.index != 3 && location.index != 5) {
+                let mut slice = &content[location.offset as usize..(location.offset + location.len) as usize];
+                let mut keys = Vec::new();
+                let collect = |map_operation: MapOperation<String, i32>| {
+                    match map_operation {
+                        MapOperation::Insert(key, _) => keys.push(key),
+                        MapOperation::Remove(key) => keys.push(key),
+                    }
+                    Ok(())
+                };
+                if text {
+                    load_from_text_file(&mut slice, &mut Some(Integrity::Crc32), None::<fn(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>>, collect)?;
+                } else {
+                    load_from_bin_file(&mut slice, &mut Some(Integrity::Crc32), None::<fn(&mut Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send>>>, collect)?;
+                }
+                match &location.key {
+                    Some(key) => assert_eq!(keys, vec![serde_json::from_str::<String>(key)?]),
+                    None => assert_eq!(keys, vec!["d".to_string(), "a b".to_string()]),
+                }
+            }
+
+            let locations = records_in_range::<String>(&file, make_cfg(), RecordRange::Index(4..6))?;
+            assert_eq!(locations.iter().map(|location| location.index).collect::<Vec<_>>(), vec![4, 5]);
+            assert!(records_in_range::<String>(&file, make_cfg(), RecordRange::Index(10..20))?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn redact() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{redact, Redaction};
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([7; 32]));
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            map.insert("alice".to_string(), "alice@example.com".to_string())?;
+            map.insert("bob".to_string(), "bob@example.com".to_string())?;
+            map.insert("carol".to_string(), "carol@example.com".to_string())?;
+            map.remove(&"carol".to_string())?;
+            map.insert("alice".to_string(), "alice@example.org".to_string())?;
+            drop(map);
+
+            // replace values of one user, chain of hashes is computed again
+            let report = redact(&file, make_cfg(), |map_operation: &MapOperation<String, String>, _| {
+                match map_operation {
+                    MapOperation::Insert(key, _) if key == "alice" => Redaction::ReplaceValue("<erased>".to_string()),
+                    _ => Redaction::Keep,
+                }
+            })?;
+            assert_eq!(report.replaced, vec![0, 4]);
+            assert!(report.dropped.is_empty());
+            assert!(!report.final_state_changed);
+            let content = std::fs::read(&file)?;
+            assert!(!String::from_utf8_lossy(&content).contains("alice@example"));
+            let map = BTreeMap::<String, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&"alice".to_string()), Some(&"<erased>".to_string()));
+            assert_eq!(map.get(&"bob".to_string()), Some(&"bob@example.com".to_string()));
+            assert_eq!(map.map().len(), 2);
+            drop(map);
+
+            // drop of insert removed later doesn't change the final state
+            let report = redact(&file, make_cfg(), |map_operation: &MapOperation<String, String>, _| {
+                match map_operation {
+                    MapOperation::Insert(key, _) if key == "carol" => Redaction::Drop,
+                    _ => Redaction::Keep,
+                }
+            })?;
+            assert_eq!(report.dropped, vec![2]);
+            assert!(!report.final_state_changed);
+            let map = BTreeMap::<String, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&"carol".to_string()), None);
+            assert_eq!(map.map().len(), 2);
+            drop(map);
+
+            // drop of the last insert of the key is reported
+            let report = redact(&file, make_cfg(), |_: &MapOperation<String, String>, index| {
+                if index == 1 { Redaction::Drop } else { Redaction::Keep }
+            })?;
+            assert_eq!(report.dropped, vec![1]);
+            assert!(report.final_state_changed);
+            let map = BTreeMap::<String, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&"bob".to_string()), None);
+            drop(map);
+
+            // other initial hash of the chain doesn't match rewritten file
+            let mut cfg = make_cfg();
+            cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+            assert!(matches!(BTreeMap::<String, String>::open_or_create(&file, cfg).map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(_))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_record_size() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::estimate_record_size;
+
+        for text in [true, false] {
+            for integrity in [None, Some(Integrity::Crc32), Some(Integrity::Sha256Chain([0; 32]))] {
+                let make_cfg = || {
+                    let mut cfg = Cfg::default();
+                    cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                    cfg.integrity = integrity.clone();
+                    cfg
+                };
+
+                let samples: Vec<_> = (0..10).map(|i| (format!("key{}", i), format!("value of {}", i))).collect();
+                let estimate = estimate_record_size(&samples, &make_cfg())?;
+                // checksum of the text format is decimal number of variable length
+                if !(text && integrity == Some(Integrity::Crc32)) {
+                    assert_eq!(estimate.insert.min, estimate.insert.max);
+                    assert_eq!(estimate.remove.min, estimate.remove.max);
+                }
+                assert!(estimate.remove.max < estimate.insert.min);
+                assert!(estimate.insert_overhead.max < estimate.insert.min);
+
+                let file = tmp_file()?;
+                let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+                for (key, value) in &samples {
+                    map.insert(key.clone(), value.clone())?;
+                }
+                for (key, _) in &samples {
+                    map.remove(key)?;
+                }
+                map.flush()?;
+
+                let growth = map.bytes_written_since_open();
+                assert_eq!(std::fs::metadata(&file)?.len(), growth);
+                assert!(growth >= (samples.len() * (estimate.insert.min + estimate.remove.min)) as u64);
+                assert!(growth <= (samples.len() * (estimate.insert.max + estimate.remove.max)) as u64);
+                if estimate.insert.min == estimate.insert.max {
+                    assert_eq!(growth, (samples.len() * (estimate.insert.median + estimate.remove.median)) as u64);
+                }
+            }
+        }
+
+        let estimate = estimate_record_size(&[(1, "a".to_string()), (2, "bbb".to_string()), (3, "bb".to_string())], &Cfg::default())?;
+        assert_eq!((estimate.insert.min, estimate.insert.median, estimate.insert.max), (12, 13, 14));
+        assert_eq!(estimate.insert_overhead.median, 8);
+        assert_eq!(estimate_record_size::<i32, i32>(&[], &Cfg::default())?.insert.max, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_replay() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::bin_format::{bin_file_block_of_insert, bin_file_block_of_remove, bin_file_block_of_transaction_marker};
+        use crate::format::TransactionMarker;
+        use crate::replay_check::{ReplayAnomaly, ReplayAnomalyKind};
+        use crate::text_format::{file_line_of_remove, text_file_line_of_insert, text_file_line_of_transaction_marker};
+
+        for text in [true, false] {
+            let make_cfg = |strict: bool| {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.skip_identical_inserts = true;
+                cfg.strict_replay = strict;
+                cfg.collect_replay_anomalies = !strict;
+                cfg
+            };
+
+            // file written by the map has no anomalies
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(true))?;
+            map.insert(1, "a".to_string())?;
+            map.insert(1, "a".to_string())?;
+            map.remove(&2)?;
+            map.apply_batch(vec![MapOperation::Remove(3), MapOperation::Insert(1, "a".to_string())])?;
+            let mut txn = map.transaction();
+            txn.insert(4, "d".to_string());
+            txn.remove(&1);
+            txn.commit()?;
+            map.remove(&4)?;
+            drop(map);
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(false))?;
+            assert!(map.replay_anomalies().is_empty());
+            drop(map);
+
+            let insert = |key: i32, value: &str| if text {
+                text_file_line_of_insert(&key, value, &mut None).map(String::into_bytes).map_err(Box::<dyn std::error::Error>::from)
+            } else {
+                bin_file_block_of_insert(&key, value, &mut None).map_err(Box::<dyn std::error::Error>::from)
+            };
+            let remove = |key: i32| if text {
+                file_line_of_remove(&key, &mut None).map(String::into_bytes).map_err(Box::<dyn std::error::Error>::from)
+            } else {
+                bin_file_block_of_remove(&key, &mut None).map_err(Box::<dyn std::error::Error>::from)
+            };
+            let marker = |marker: TransactionMarker| if text {
+                text_file_line_of_transaction_marker(marker, &mut None).into_bytes()
+            } else {
+                bin_file_block_of_transaction_marker(marker, &mut None)
+            };
+
+            let file = tmp_file()?;
+            let records = [
+                insert(1, "a")?,
+                remove(2)?,
+                insert(1, "a")?,
+                insert(1, "b")?,
+                marker(TransactionMarker::End),
+                marker(TransactionMarker::Begin),
+                insert(3, "c")?,
+                marker(TransactionMarker::Begin),
+                remove(3)?,
+                marker(TransactionMarker::End),
+            ];
+            std::fs::write(&file, records.concat())?;
+
+            let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(true));
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::ReplayAnomaly { line_num: 2, kind: ReplayAnomalyKind::RemoveOfAbsentKey })));
+
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(false))?;
+            assert_eq!(map.replay_anomalies(), &[
+                ReplayAnomaly { line_num: 2, kind: ReplayAnomalyKind::RemoveOfAbsentKey },
+                ReplayAnomaly { line_num: 3, kind: ReplayAnomalyKind::DuplicateNoOpInsert },
+                ReplayAnomaly { line_num: 5, kind: ReplayAnomalyKind::SequenceGap },
+                ReplayAnomaly { line_num: 8, kind: ReplayAnomalyKind::SequenceGap },
+                ReplayAnomaly { line_num: 9, kind: ReplayAnomalyKind::RemoveOfAbsentKey },
+            ]);
+            assert_eq!(map.get(&1), Some(&"b".to_string()));
+            drop(map);
+
+            // without check anomalies are not collected
+            let mut cfg = make_cfg(false);
+            cfg.collect_replay_anomalies = false;
+            assert!(BTreeMap::<i32, String>::open_or_create(&file, cfg)?.replay_anomalies().is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_all() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::open_all::OpenAllError;
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir)?;
+        for num in 0..5 {
+            let mut map = BTreeMap::open_or_create(dir.join(format!("{}.txt", num)).to_str().ok_or(TempDirError())?, Cfg::default())?;
+            map.insert(num, num.to_string())?;
+        }
+        std::fs::write(dir.join("corrupt.txt"), "ins not json\n")?;
+        std::fs::write(dir.join("notes.skip"), "not a map\n")?;
+        std::fs::create_dir(dir.join("subdir"))?;
+
+        let cfg_for = |path: &std::path::Path| match path.extension() {
+            Some(ext) if ext == "txt" => Some(Cfg::default()),
+            _ => None,
+        };
+
+        let report = crate::open_all_with::<i32, String>(&dir, 3, cfg_for)?;
+        let opened: Vec<_> = report.opened.iter()
+            .map(|(path, map)| (path.clone(), map.map().clone()))
+            .collect();
+        assert_eq!(opened, (0..5)
+            .map(|num| (dir.join(format!("{}.txt", num)), std::collections::BTreeMap::from([(num, num.to_string())])))
+            .collect::<Vec<_>>());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, dir.join("corrupt.txt"));
+        drop(report);
+
+        match crate::open_all::<i32, String>(&dir, cfg_for) {
+            Err(OpenAllError::OpenFilesError(failed)) => assert_eq!(failed.len(), 1),
+            _ => panic!("corrupt file must fail open_all"),
+        }
+
+        std::fs::remove_file(dir.join("corrupt.txt"))?;
+        assert_eq!(crate::open_all::<i32, String>(&dir, cfg_for)?.len(), 5);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn chain_anchor() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::chain_anchor::{ChainAnchor, ChainAnchorError};
+        use crate::format::IntegrityError;
+
+        let genesis = ChainAnchor::from_hex("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef")?;
+        assert_eq!(genesis.algorithm(), "sha256_chain");
+        assert_eq!(ChainAnchor::from_hex(&genesis.to_hex())?, genesis);
+        assert!(matches!(ChainAnchor::from_hex("0123"), Err(ChainAnchorError::WrongLength { len: 2 })));
+        assert!(matches!(ChainAnchor::from_hex("xyz"), Err(ChainAnchorError::HexError(_))));
+        assert_eq!(ChainAnchor::from_hex(&"ab".repeat(20))?, ChainAnchor::Sha1Chain([0xab; 20]));
+        assert!(matches!(Integrity::sha256_from_anchor(&ChainAnchor::Sha1Chain([0; 20])),
+            Err(ChainAnchorError::WrongAlgorithm { expected: "sha256_chain", found: "sha1_chain" })));
+
+        let genesis_file = tmp_file()?;
+        genesis.save(&genesis_file)?;
+        assert_eq!(std::fs::read_to_string(&genesis_file)?, format!("sha256_chain {}\n", genesis.to_hex()));
+        assert_eq!(ChainAnchor::load(&genesis_file)?, genesis);
+
+        let file = tmp_file()?;
+        let mut cfg = Cfg::default();
+        cfg.integrity = Some(Integrity::sha256_from_anchor(&ChainAnchor::load(&genesis_file)?)?);
+        let mut map = BTreeMap::open_or_create(&file, cfg)?;
+        assert_eq!(map.integrity_head(), Some(genesis.clone()));
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        map.flush()?;
+        let head_file = tmp_file()?;
+        map.integrity_head().ok_or("no head")?.save(&head_file)?;
+        let head = ChainAnchor::load(&head_file)?;
+        assert_ne!(head, genesis);
+        drop(map);
+
+        let mut cfg = Cfg::default();
+        cfg.integrity = Some(Integrity::sha256_from_anchor(&ChainAnchor::load(&genesis_file)?)?);
+        let map = BTreeMap::<i32, String>::open_or_create(&file, cfg)?;
+        assert_eq!(map.integrity_head(), Some(head.clone()));
+        assert_eq!(map.get(&2), Some(&"b".to_string()));
+        drop(map);
+
+        // head is not the genesis of the file
+        let mut cfg = Cfg::default();
+        cfg.integrity = Some(Integrity::sha256_from_anchor(&head)?);
+        assert!(matches!(BTreeMap::<i32, String>::open_or_create(&file, cfg).map_err(LoadFileError::into_inner),
+            Err(LoadFileError::IntegrityError(IntegrityError::Sha256ChainError { line_num: 1 }))));
+
+        std::fs::write(&head_file, format!("sha1_chain {}\n", head.to_hex()))?;
+        assert!(matches!(ChainAnchor::load(&head_file), Err(ChainAnchorError::WrongLength { len: 32 })));
+        std::fs::write(&head_file, format!("md5_chain {}\n", head.to_hex()))?;
+        assert!(matches!(ChainAnchor::load(&head_file), Err(ChainAnchorError::UnknownAlgorithm(_))));
+        std::fs::write(&head_file, "")?;
+        assert!(matches!(ChainAnchor::load(&head_file), Err(ChainAnchorError::WrongFileFormat)));
+
+        let mut cfg = Cfg::default();
+        cfg.integrity = Some(Integrity::Crc32);
+        let map = BTreeMap::<i32, String>::open_or_create(&tmp_file()?, cfg)?;
+        assert_eq!(map.integrity_head(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn current_integrity_state() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{blockchain_sha256, IntegrityError};
+        use std::convert::TryFrom;
+
+        let genesis = [7; 32];
+        let archived_file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&archived_file, Cfg { integrity: Some(Integrity::Sha256Chain(genesis)), ..Cfg::default() })?;
+        assert_eq!(map.current_integrity_state()?, Some(Integrity::Sha256Chain(genesis)));
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        let state = map.current_integrity_state()?.ok_or("no integrity")?;
+        drop(map);
+        let archived_content = std::fs::read_to_string(&archived_file)?;
+        let last_hash = archived_content.lines().last().and_then(|line| line.rsplit(' ').next()).ok_or("no hash")?;
+        assert_eq!(state, Integrity::Sha256Chain(<[u8; 32]>::try_from(hex::decode(last_hash)?.as_slice())?));
+
+        // the new file continues the chain from the last record of the archived one
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg { integrity: Some(state.clone()), ..Cfg::default() })?;
+        map.insert(3, "c".to_string())?;
+        map.insert(4, "d".to_string())?;
+        drop(map);
+        let content = std::fs::read_to_string(&file)?;
+        let mut first_hash = [0; 32];
+        blockchain_sha256(&hex::decode(last_hash)?, b"ins [3,\"c\"]", &mut first_hash);
+        assert!(content.starts_with(&format!("ins [3,\"c\"] {}\n", hex::encode(first_hash))));
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg { integrity: Some(state.clone()), ..Cfg::default() })?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+
+        // the new file is not valid from the genesis of the archived one, but both files are valid as one log
+        let res = BTreeMap::<i32, String>::open_or_create(&file, Cfg { integrity: Some(Integrity::Sha256Chain(genesis)), ..Cfg::default() });
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::Sha256ChainError { line_num: 1 }))));
+        let joined_file = tmp_file()?;
+        std::fs::write(&joined_file, archived_content + &content)?;
+        let map = BTreeMap::<i32, String>::open_or_create(&joined_file, Cfg { integrity: Some(Integrity::Sha256Chain(genesis)), ..Cfg::default() })?;
+        assert_eq!(map.map().len(), 4);
+
+        assert_eq!(BTreeMap::<i32, String>::open_or_create(&tmp_file()?, Cfg::default())?.current_integrity_state()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{FormatKind, IntegrityKind};
+        use crate::verify::detect_integrity;
+
+        let make_cfg = |integrity, format, recovery| Cfg { integrity, format, recovery, ..Cfg::default() };
+        let unexpected_hash = |res: Result<BTreeMap<i32, String>, LoadFileError>| match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::FileHasUnexpectedHashSuffix { line_num: 1, found })) => Some(found),
+            _ => None,
+        };
+
+        for &format_kind in [FormatKind::Text, FormatKind::Bin].iter() {
+            let format = || if format_kind == FormatKind::Text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            for (integrity, kind) in [(Integrity::Crc32, IntegrityKind::Crc32), (Integrity::XxHash64, IntegrityKind::XxHash64)].iter().cloned() {
+                let file = tmp_file()?;
+                let mut map = BTreeMap::open_or_create(&file, make_cfg(Some(integrity), format(), RecoveryMode::Fail))?;
+                map.insert(1, "a".to_string())?;
+                map.insert(2, "b".to_string())?;
+                drop(map);
+                assert_eq!(detect_integrity(&file, format_kind)?, Some(kind));
+                let len = std::fs::metadata(&file)?.len();
+
+                // file with checksums opened without integrity or with other one, it's not truncated by recovery
+                let res = BTreeMap::open_or_create(&file, make_cfg(None, format(), RecoveryMode::TruncateAtError));
+                assert_eq!(unexpected_hash(res), Some(kind));
+                let other = if kind == IntegrityKind::Crc32 { Integrity::XxHash64 } else { Integrity::Crc32 };
+                let res = BTreeMap::open_or_create(&file, make_cfg(Some(other), format(), RecoveryMode::SkipBadRecords));
+                assert_eq!(unexpected_hash(res), Some(kind));
+                assert_eq!(std::fs::metadata(&file)?.len(), len);
+            }
+
+            // file without integrity
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(None, format(), RecoveryMode::Fail))?;
+            map.insert(1, "a".to_string())?;
+            drop(map);
+            assert_eq!(detect_integrity(&file, format_kind)?, None);
+        }
+
+        // hashes of chain are detected by length in the text format
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(Some(Integrity::Sha256Chain([0; 32])), Format::Text(None, None), RecoveryMode::Fail))?;
+        map.insert(1, "a".to_string())?;
+        drop(map);
+        assert_eq!(detect_integrity(&file, FormatKind::Text)?, Some(IntegrityKind::Hash256Chain));
+        let res = BTreeMap::open_or_create(&file, make_cfg(None, Format::Text(None, None), RecoveryMode::Fail));
+        assert_eq!(unexpected_hash(res), Some(IntegrityKind::Hash256Chain));
+        let res = BTreeMap::open_or_create(&file, make_cfg(Some(Integrity::Sha1Chain([0; 20])), Format::Text(None, None), RecoveryMode::Fail));
+        assert_eq!(unexpected_hash(res), Some(IntegrityKind::Hash256Chain));
+        // wrong hash of the same kind is not mismatch
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some(Integrity::Sha256Chain([1; 32])), Format::Text(None, None), RecoveryMode::Fail));
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::Sha256ChainError { line_num: 1 }))));
+
+        // text file without integrity opened with integrity, it's not truncated by recovery
+        let file = tmp_file()?;
+        std::fs::write(&file, "ins [1,\"a\"]\nins [2,\"b c\"]\n")?;
+        for integrity in [Integrity::Crc32, Integrity::XxHash64, Integrity::Sha1Chain([0; 20])].iter() {
+            let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some(integrity.clone()), Format::Text(None, None), RecoveryMode::TruncateAtError));
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::FileMissingHashes { line_num: 1 }))));
+        }
+        assert_eq!(std::fs::read_to_string(&file)?, "ins [1,\"a\"]\nins [2,\"b c\"]\n");
+
+        // wrong checksum of the first line is not mismatch
+        let file = tmp_file()?;
+        std::fs::write(&file, "ins [1,\"a\"] 12345\n")?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Some(Integrity::Crc32), Format::Text(None, None), RecoveryMode::Fail));
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::Crc32Error { line_num: 1 }))));
+
+        // empty file
+        let file = tmp_file()?;
+        std::fs::write(&file, "")?;
+        assert_eq!(detect_integrity(&file, FormatKind::Text)?, None);
+        assert_eq!(detect_integrity(&file, FormatKind::Bin)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_or_create_expecting() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::chain_anchor::ChainAnchor;
+        use crate::format::IntegrityError;
+
+        let make_cfg = || Cfg { integrity: Some(Integrity::Sha1Chain([0; 20])), ..Cfg::default() };
+        let file = tmp_file()?;
+        // head of the empty file is the genesis
+        let map = BTreeMap::<i32, String>::open_or_create_expecting(&file, make_cfg(), &ChainAnchor::Sha1Chain([0; 20]))?;
+        drop(map);
+
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        map.insert(3, "c".to_string())?;
+        map.flush()?;
+        let head = map.integrity_head().ok_or("no head")?;
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create_expecting(&file, make_cfg(), &head)?;
+        assert_eq!(map.map().len(), 3);
+        drop(map);
+
+        // the last record is removed, the rest of the chain is valid
+        let content = std::fs::read_to_string(&file)?;
+        let without_last: String = content.lines().take(2).map(|line| format!("{}\n", line)).collect();
+        std::fs::write(&file, without_last)?;
+        assert_eq!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?.map().len(), 2);
+        let res = BTreeMap::<i32, String>::open_or_create_expecting(&file, make_cfg(), &head);
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::ChainTruncated { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        // the file is unlocked after error
+        drop(BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?);
+
+        // no head without chained integrity
+        let cfg = Cfg { integrity: Some(Integrity::Crc32), ..Cfg::default() };
+        let res = BTreeMap::<i32, String>::open_or_create_expecting(&tmp_file()?, cfg, &head);
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::ChainTruncated { .. }))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn chain_head_file() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::IntegrityError;
+
+        let make_cfg = |format| Cfg { format, integrity: Some(Integrity::Sha256Chain([0; 32])), chain_head_file: true, ..Cfg::default() };
+        let file = tmp_file()?;
+        let head_file = format!("{}.head", file);
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(Format::Text(None, None)))?;
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        map.insert(3, "c".to_string())?;
+        map.flush()?;
+        let head = map.integrity_head().ok_or("no head")?;
+        drop(map);
+        let content = std::fs::read_to_string(&file)?;
+        assert_eq!(std::fs::read_to_string(&head_file)?, format!("sha256_chain {} {}\n", head.to_hex(), content.len()));
+        assert_eq!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None)))?.map().len(), 3);
+
+        // records appended after the head file is saved, as after crash before its update
+        let mut map = BTreeMap::open_or_create(&file, Cfg { chain_head_file: false, ..make_cfg(Format::Text(None, None)) })?;
+        map.insert(4, "d".to_string())?;
+        drop(map);
+        assert_eq!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None)))?.map().len(), 4);
+
+        // the last records are removed
+        std::fs::write(&file, content.lines().take(2).map(|line| format!("{}\n", line)).collect::<String>())?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None)));
+        match res.map_err(LoadFileError::into_inner) {
+            Err(LoadFileError::IntegrityError(IntegrityError::ChainTruncated { line_num: 2 })) => {},
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        // other file of the same length
+        let other_file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&other_file, Cfg { chain_head_file: false, ..make_cfg(Format::Text(None, None)) })?;
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        map.insert(3, "x".to_string())?;
+        drop(map);
+        std::fs::copy(&other_file, &file)?;
+        assert!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None))).is_err());
+        // without the head file nothing is checked
+        std::fs::remove_file(&head_file)?;
+        assert_eq!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Text(None, None)))?.map().len(), 3);
+        std::fs::remove_file(&file)?;
+
+        // head of the compacted file is saved
+        let mut map = BTreeMap::open_or_create(&file, make_cfg(Format::Bin(None, None)))?;
+        for key in 0..5 {
+            map.insert(key, "a".to_string())?;
+        }
+        map.remove(&0)?;
+        map.compact()?;
+        drop(map);
+        let content = std::fs::read(&file)?;
+        assert!(std::fs::read_to_string(&head_file)?.ends_with(&format!(" {}\n", content.len())));
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Bin(None, None)))?;
+        assert_eq!(map.map().len(), 4);
+        map.remove(&1)?;
+        drop(map);
+
+        // the last block of remove is removed
+        std::fs::write(&file, &content)?;
+        let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(Format::Bin(None, None)));
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::ChainTruncated { .. }))));
+
+        // head of the empty log after checkpoint is the genesis
+        let file = tmp_file()?;
+        let make_cfg = || Cfg { snapshot: Some(crate::cfg::SnapshotCfg::default()), ..make_cfg(Format::Text(None, None)) };
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(1, "a".to_string())?;
+        map.checkpoint()?;
+        drop(map);
+        assert_eq!(std::fs::read_to_string(format!("{}.head", file))?, format!("sha256_chain {} 0\n", hex::encode([0; 32])));
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.get(&1), Some(&"a".to_string()));
+        map.insert(2, "b".to_string())?;
+        drop(map);
+        assert_eq!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?.map().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_codec() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::key_codec::{CodedKey, IpAddrCodec, KeyCodec, MillisCodec, UuidCodec};
+        use serde::de::DeserializeOwned;
+        use serde::Serialize;
+        use std::net::IpAddr;
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        fn round_trip<Key, Codec>(keys: &[Key], encoded: &[&str]) -> Result<(), Box<dyn std::error::Error>>
+        where
+            Key: Serialize + DeserializeOwned + Ord + Clone + Send + Sync + std::fmt::Debug + 'static,
+            Codec: KeyCodec<Key> + 'static,
+        {
+            for key in keys {
+                assert_eq!(&Codec::decode(&Codec::encode(key))?, key);
+            }
+
+            for format in [Format::Text(None, None), Format::Bin(None, None)] {
+                let text = matches!(format, Format::Text(..));
+                let file = tmp_file()?;
+                let mut cfg = Cfg::default();
+                cfg.format = format;
+                let mut map = BTreeMap::<CodedKey<Key, Codec>, u32>::open_or_create(&file, cfg)?;
+                for (num, key) in keys.iter().enumerate() {
+                    map.insert(CodedKey::new(key.clone()), num as u32)?;
+                }
+                drop(map);
+
+                if text {
+                    let content = std::fs::read_to_string(&file)?;
+                    let lines: Vec<_> = content.lines().collect();
+                    for (num, encoded) in encoded.iter().enumerate() {
+                        assert_eq!(lines[num], format!("ins [\"{}\",{}]", encoded, num));
+                    }
+                }
+
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                let map = BTreeMap::<CodedKey<Key, Codec>, u32>::open_or_create(&file, cfg)?;
+                let mut sorted_keys = keys.to_vec();
+                sorted_keys.sort();
+                // order of decoded keys, not of encoded strings
+                assert_eq!(map.map().keys().map(|key| key.key().clone()).collect::<Vec<_>>(), sorted_keys);
+            }
+
+            Ok(())
+        }
+
+        let times = [UNIX_EPOCH + Duration::from_millis(10), UNIX_EPOCH + Duration::from_millis(9), UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)];
+        round_trip::<SystemTime, MillisCodec>(&times, &["10", "9", "1700000000000"])?;
+        // time before epoch can't be serialized by serde, but can be encoded
+        let before_epoch = UNIX_EPOCH - Duration::from_millis(5);
+        assert_eq!(MillisCodec::encode(&before_epoch), "-5");
+        assert_eq!(MillisCodec::decode("-5"), Ok(before_epoch));
+        round_trip::<Duration, MillisCodec>(&[Duration::from_millis(100), Duration::from_millis(20)], &["100", "20"])?;
+        let uuid = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8")?;
+        round_trip::<Uuid, UuidCodec>(&[uuid, Uuid::nil()], &["67e55044-10b1-426f-9247-bb680e5fe0c8", "00000000-0000-0000-0000-000000000000"])?;
+        let ips: [IpAddr; 3] = ["10.0.0.2".parse()?, "9.0.0.1".parse()?, "::1".parse()?];
+        round_trip::<IpAddr, IpAddrCodec>(&ips, &["10.0.0.2", "9.0.0.1", "::1"])?;
+
+        assert!(<MillisCodec as KeyCodec<Duration>>::decode("abc").is_err());
+        assert!(IpAddrCodec::decode("1.2.3").is_err());
+        let file = tmp_file()?;
+        std::fs::write(&file, "ins [\"not uuid\",1]\n")?;
+        assert!(BTreeMap::<CodedKey<Uuid, UuidCodec>, u32>::open_or_create(&file, Cfg::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_map_try_operations() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::shared_map::{TryChangeError, WouldBlock};
+        use crate::SharedBTreeMap;
+        use std::sync::mpsc::channel;
+        use std::time::{Duration, Instant};
+
+        let file = tmp_file()?;
+        let map = SharedBTreeMap::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.try_insert(1, "a".to_string())?, None);
+        assert_eq!(map.try_get_cloned(&1), Ok(Some("a".to_string())));
+
+        // write lock is held by other thread until 'release' is sent
+        let (locked_sender, locked) = channel();
+        let (release, release_receiver) = channel::<()>();
+        let writer_map = map.clone();
+        let writer = std::thread::spawn(move || {
+            let _guard = writer_map.write();
+            let _ = locked_sender.send(());
+            let _ = release_receiver.recv();
+        });
+        locked.recv()?;
+
+        let start = Instant::now();
+        assert!(matches!(map.try_insert(2, "b".to_string()), Err(TryChangeError::WouldBlock)));
+        assert!(matches!(map.try_remove(&1), Err(TryChangeError::WouldBlock)));
+        assert_eq!(map.try_get_cloned(&1), Err(WouldBlock));
+        assert!(map.try_read().is_none());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        #[cfg(feature = "parking_lot")]
+        {
+            let start = Instant::now();
+            assert!(matches!(map.insert_timeout(2, "b".to_string(), Duration::from_millis(50)), Err(TryChangeError::WouldBlock)));
+            assert_eq!(map.get_cloned_timeout(&1, Duration::from_millis(50)), Err(WouldBlock));
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(2));
+        }
+
+        release.send(())?;
+        writer.join().map_err(|_| "writer thread panicked")?;
+
+        // failed tries didn't change the map and the file
+        assert_eq!(map.try_get_cloned(&2), Ok(None));
+        assert_eq!(map.try_remove(&1)?, Some("a".to_string()));
+        #[cfg(feature = "parking_lot")]
+        assert_eq!(map.insert_timeout(3, "c".to_string(), Duration::from_millis(50))?, None);
+        map.flush()?;
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.get(&2), None);
+        #[cfg(feature = "parking_lot")]
+        assert_eq!(map.get(&3), Some(&"c".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn testing_utilities() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::file_worker::WorkerFile;
+        use crate::testing::{load_operations, Clock, FailSwitch, FailingWriter, ManualClock, SharedBuffer, SlowWriter, SystemClock};
+        use std::io::Read;
+        use std::time::{Duration, Instant, UNIX_EPOCH};
+
+        let clock = ManualClock::new(UNIX_EPOCH);
+        let shared: std::sync::Arc<dyn Clock> = std::sync::Arc::new(clock.clone());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(shared.now(), UNIX_EPOCH + Duration::from_secs(5));
+        clock.set(UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(1));
+        // sleep doesn't wait
+        let start = Instant::now();
+        shared.sleep(Duration::from_secs(60));
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(shared.elapsed(UNIX_EPOCH), Duration::from_secs(61));
+        assert!(SystemClock.now() > UNIX_EPOCH);
+
+        let mut writer = FailingWriter::fail_every(Vec::new(), 3);
+        let results: Vec<_> = (0..6).map(|num| writer.write(&[num]).is_ok()).collect();
+        assert_eq!(results, vec![true, true, false, true, true, false]);
+        assert_eq!(writer.into_inner(), vec![0, 1, 3, 4]);
+
+        let mut writer = FailingWriter::fail_after_bytes(Vec::new(), 5);
+        assert!(writer.write_all(b"abc").is_ok());
+        assert!(writer.write_all(b"def").is_err());
+        assert!(writer.write_all(b"gh").is_ok());
+        assert!(writer.write_all(b"i").is_err());
+        assert_eq!(writer.into_inner(), b"abcgh");
+
+        let switch = FailSwitch::new();
+        let buffer = SharedBuffer::new();
+        let mut writer = FailingWriter::fail_when(buffer.clone(), switch.clone()).partial_writes();
+        writer.write_all(b"ab")?;
+        switch.fail_next(2);
+        assert!(writer.write_all(b"cdef").is_err());
+        assert_eq!(switch.remaining(), 1);
+        writer.truncate(2)?;
+        assert!(writer.write_all(b"cd").is_err());
+        writer.truncate(2)?;
+        writer.write_all(b"cd")?;
+        switch.on();
+        assert!(writer.write_all(b"e").is_err() && writer.write_all(b"e").is_err());
+        switch.off();
+        writer.sync_data()?;
+        assert_eq!((buffer.contents(), buffer.syncs()), (b"abcd".to_vec(), 1));
+
+        let mut writer = FailingWriter::fail_sync_when(SharedBuffer::new(), switch.clone());
+        switch.on();
+        writer.write_all(b"a")?;
+        assert!(writer.sync_data().is_err());
+
+        let mut writer = SlowWriter::new(Vec::new(), Duration::from_millis(20));
+        let start = Instant::now();
+        writer.write_all(b"a")?;
+        writer.write_all(b"b")?;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(writer.into_inner(), b"ab");
+
+        // reader sees bytes written after its creation
+        let mut buffer = SharedBuffer::new();
+        let mut reader = buffer.reader();
+        buffer.write_all(b"abc")?;
+        let mut read = String::new();
+        reader.read_to_string(&mut read)?;
+        buffer.write_all(b"de")?;
+        reader.read_to_string(&mut read)?;
+        assert_eq!(read, "abcde");
+
+        // records written by the map are loaded from the buffer
+        for format in [Format::Text(None, None), Format::Bin(None, None)] {
+            let text = matches!(format, Format::Text(..));
+            let sink = SharedBuffer::new();
+            let mut cfg = Cfg::default();
+            cfg.format = format;
+            cfg.integrity = Some(Integrity::Sha256Chain([1; 32]));
+            cfg.secondary_sink = Some(Box::new(sink.clone()));
+            let mut map = BTreeMap::open_or_create(&tmp_file()?, cfg)?;
+            map.insert(1, "a".to_string())?;
+            map.apply_batch(vec![MapOperation::Insert(2, "b".to_string()), MapOperation::Remove(1)])?;
+            drop(map);
+
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            cfg.integrity = Some(Integrity::Sha256Chain([1; 32]));
+            let operations = load_operations::<i32, String>(sink.reader(), cfg)?;
+            let operations: Vec<_> = operations.into_iter()
+                .map(|operation| match operation {
+                    MapOperation::Insert(key, value) => (key, Some(value)),
+                    MapOperation::Remove(key) => (key, None),
+                })
+                .collect();
+            assert_eq!(operations, vec![(1, Some("a".to_string())), (2, Some("b".to_string())), (1, None)]);
+
+            let mut cfg = Cfg::default();
+            cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+            cfg.integrity = Some(Integrity::Sha256Chain([2; 32]));
+            assert!(matches!(load_operations::<i32, String>(sink.reader(), cfg).map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(_))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_stats() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{LoadStats, RecordKind};
+
+        let integrities = [None, Some(Integrity::Crc32), Some(Integrity::XxHash64), Some(Integrity::Sha1Chain([0; 20])), Some(Integrity::Sha256Chain([0; 32])), Some(Integrity::HmacSha256Chain { key: b"key".to_vec(), prev: [0; 32] })];
+        for text in [true, false] {
+            for integrity in integrities.iter() {
+                let make_cfg = || {
+                    let mut cfg = Cfg::default();
+                    cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                    cfg.integrity = integrity.clone();
+                    cfg
+                };
+
+                let file = tmp_file()?;
+                let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+                for key in 0..20 {
+                    map.insert(key.to_string(), "v".repeat(key))?;
+                }
+                // record fits in block with one byte length
+                map.insert("big".to_string(), "v".repeat(200))?;
+                map.remove(&"1".to_string())?;
+                map.remove(&"2".to_string())?;
+                map.apply_batch(vec![MapOperation::Insert("3".to_string(), "b".to_string()), MapOperation::Remove("4".to_string())])?;
+                let mut txn = map.transaction();
+                txn.insert("5".to_string(), "t".to_string());
+                txn.commit()?;
+                drop(map);
+
+                let map = BTreeMap::<String, String>::open_or_create(&file, make_cfg())?;
+                let stats = map.load_stats();
+                let file_len = std::fs::metadata(&file)?.len();
+                assert_eq!(stats.total_bytes, file_len);
+                assert_eq!(stats.insert_bytes + stats.remove_bytes + stats.other_bytes, stats.total_bytes);
+                assert!(stats.remove_bytes > 0 && stats.other_bytes > 0);
+
+                // 25 records with integrity: 21 inserts, 2 removes, batch, 2 markers and insert of transaction
+                let records = 21 + 2 + 1 + 3;
+                let integrity_len = match (integrity, text) {
+                    (None, _) => Some(0),
+                    (Some(Integrity::Crc32), true) => None, // decimal checksum has variable length
+                    (Some(Integrity::Crc32), false) => Some(4),
+                    (Some(Integrity::XxHash64), true) => Some(17),
+                    (Some(Integrity::XxHash64), false) => Some(8),
+                    (Some(Integrity::Sha1Chain(_)), true) => Some(41),
+                    (Some(Integrity::Sha1Chain(_)), false) => Some(20),
+                    (Some(Integrity::Sha256Chain(_)), true) => Some(65),
+                    (Some(Integrity::Sha256Chain(_)), false) => Some(32),
+                    (Some(Integrity::HmacSha256Chain { .. }), true) => Some(65),
+                    (Some(Integrity::HmacSha256Chain { .. }), false) => Some(32),
+                    #[cfg(feature = "blake3")]
+                    (Some(Integrity::Blake3Chain(_)), true) => Some(65),
+                    #[cfg(feature = "blake3")]
+                    (Some(Integrity::Blake3Chain(_)), false) => Some(32),
+                };
+                match integrity_len {
+                    Some(len) => assert_eq!(stats.integrity_bytes, len * records),
+                    None => assert!(stats.integrity_bytes > records && stats.integrity_bytes <= 11 * records),
+                }
+
+                assert_eq!(stats.largest_records.len(), LoadStats::LARGEST_RECORDS_COUNT);
+                let largest = &stats.largest_records[0];
+                assert_eq!((largest.line_num, largest.kind, largest.key.as_deref()), (21, RecordKind::Insert, Some("\"big\"")));
+                assert!(stats.largest_records.iter().any(|record| record.kind == RecordKind::Batch && record.key.is_none()));
+                assert!(stats.largest_records.iter().any(|record| record.key.as_deref() == Some("\"19\"")));
+                assert!(stats.largest_records.windows(2).all(|pair| pair[0].size >= pair[1].size));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn callback_panics() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        for text in [true, false] {
+            let panic_on_write = Arc::new(AtomicBool::new(false));
+            let panic_on_read = Arc::new(AtomicBool::new(false));
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                let (panic_on_write, panic_on_read) = (panic_on_write.clone(), panic_on_read.clone());
+                cfg.format = if text {
+                    Format::Text(
+                        Some(Box::new(move |_: &mut String| if panic_on_write.load(Ordering::SeqCst) { panic!("before write") })),
+                        Some(Box::new(move |_: &mut String| if panic_on_read.load(Ordering::SeqCst) { panic!("after read") } else { Ok(()) })),
+                    )
+                } else {
+                    Format::Bin(
+                        Some(Box::new(move |_: &mut Vec<u8>| if panic_on_write.load(Ordering::SeqCst) { panic!("before write") })),
+                        Some(Box::new(move |_: &mut Vec<u8>| if panic_on_read.load(Ordering::SeqCst) { panic!("after read") } else { Ok(()) })),
+                    )
+                };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            let index = map.create_btree_index(|value: &String| if value == "panic" { panic!("make index key") } else { value.len() });
+            map.insert(1, "a".to_string())?;
+            map.insert(2, "bb".to_string())?;
+            map.flush()?;
+            let file_len = std::fs::metadata(&file)?.len();
+
+            // make index key callback
+            assert!(matches!(map.insert(3, "panic".to_string()), Err(SerializedError::CallbackPanicked)));
+            assert!(matches!(map.insert(1, "panic".to_string()), Err(SerializedError::CallbackPanicked)));
+            assert!(matches!(map.insert_with_meta(1, "panic".to_string(), &"author"), Err(SerializedError::CallbackPanicked)));
+            let batch = vec![MapOperation::Insert(3, "ccc".to_string()), MapOperation::Remove(2), MapOperation::Insert(1, "panic".to_string())];
+            assert!(matches!(map.apply_batch(batch), Err(SerializedError::CallbackPanicked)));
+            let mut txn = map.transaction();
+            txn.insert(3, "ccc".to_string());
+            txn.insert(1, "panic".to_string());
+            assert!(matches!(txn.commit(), Err(SerializedError::CallbackPanicked)));
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            assert_eq!(map.get(&2), Some(&"bb".to_string()));
+            assert_eq!(map.get(&3), None);
+            assert_eq!(index.get(&1), vec![1]);
+            assert_eq!(index.get(&2), vec![2]);
+            assert!(index.get(&3).is_empty());
+            map.flush()?;
+            assert_eq!(std::fs::metadata(&file)?.len(), file_len);
+
+            // before write callback
+            panic_on_write.store(true, Ordering::SeqCst);
+            assert!(matches!(map.insert(1, "ccc".to_string()), Err(SerializedError::CallbackPanicked)));
+            assert!(matches!(map.insert(3, "ccc".to_string()), Err(SerializedError::CallbackPanicked)));
+            assert!(matches!(map.remove(&2), Err(SerializedError::CallbackPanicked)));
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            assert_eq!(map.get(&2), Some(&"bb".to_string()));
+            assert_eq!(map.get(&3), None);
+            assert_eq!(index.get(&1), vec![1]);
+            assert_eq!(index.get(&2), vec![2]);
+            assert!(index.get(&3).is_empty());
+            map.flush()?;
+            assert_eq!(std::fs::metadata(&file)?.len(), file_len);
+
+            // integrity chain continues after rejected records
+            panic_on_write.store(false, Ordering::SeqCst);
+            map.insert(3, "ccc".to_string())?;
+            map.remove(&2)?;
+            drop(map);
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&1), Some(&"a".to_string()));
+            assert_eq!(map.get(&2), None);
+            assert_eq!(map.get(&3), Some(&"ccc".to_string()));
+            drop(map);
+
+            // after read callback
+            panic_on_read.store(true, Ordering::SeqCst);
+            let res = BTreeMap::<i32, String>::open_or_create(&file, make_cfg());
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::CallbackPanicked { .. })));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn bin_before_write_callback() -> Result<(), Box<dyn std::error::Error>> {
+        // not idempotent, so block changed twice can't be read
+        let make_cfg = || Cfg {
+            format: Format::Bin(
+                Some(Box::new(|block: &mut Vec<u8>| if let Some(last) = block.last_mut() { *last = last.wrapping_add(1) })),
+                Some(Box::new(|block: &mut Vec<u8>| { if let Some(last) = block.last_mut() { *last = last.wrapping_sub(1) } Ok(()) })),
+            ),
+            ..Cfg::default()
+        };
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "bb".to_string())?;
+        map.insert_with_meta(3, "ccc".to_string(), &"author")?;
+        map.remove(&2)?;
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.get(&1), Some(&"a".to_string()));
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.get(&3), Some(&"ccc".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn support_bundle() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::{export_support_bundle, records_in_range, RecordRange};
+        use crate::support_bundle::BundleRedaction;
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            for key in 0..3 {
+                map.insert(format!("key{}", key), format!("secret{}", key))?;
+            }
+            map.remove(&"key0".to_string())?;
+            map.insert_with_meta("key3".to_string(), "secret3".to_string(), &"secret author")?;
+            map.apply_batch(vec![MapOperation::Insert("key4".to_string(), "secret4".to_string())])?;
+            let mut txn = map.transaction();
+            txn.insert("key5".to_string(), "secret5".to_string());
+            txn.commit()?;
+            drop(map);
+
+            let export = |redaction| -> Result<_, Box<dyn std::error::Error>> {
+                let mut out = Vec::new();
+                let summary = export_support_bundle::<String>(&file, make_cfg(), &mut out, redaction)?;
+                Ok((summary, String::from_utf8(out)?))
+            };
+            let records_of = |bundle: &str| {
+                bundle.lines().filter(|line| !line.starts_with("# "))
+                    .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                    .collect::<Vec<_>>()
+            };
+            let contains_secret = |bundle: &str| bundle.contains("secret") || bundle.contains(&hex::encode("secret"));
+
+            let (summary, bundle) = export(BundleRedaction::None)?;
+            assert!(contains_secret(&bundle));
+            assert_eq!(records_of(&bundle).len(), summary.records);
+
+            // structure of the file is kept, values are hidden
+            let (summary, bundle) = export(BundleRedaction::Values)?;
+            assert!(!contains_secret(&bundle));
+            assert_eq!(summary.total_bytes, std::fs::metadata(&file)?.len());
+            assert_eq!(summary.first_failing_record, None);
+            assert!(bundle.contains("# integrity: sha256_chain\n") && bundle.contains("# first failing record: none\n"));
+            let records = records_of(&bundle);
+            let locations = records_in_range::<String>(&file, make_cfg(), RecordRange::Index(0..100))?;
+            assert_eq!(records.len(), locations.len());
+            for (record, location) in records.iter().zip(locations.iter()) {
+                assert_eq!(record["num"], location.index + 1);
+                assert_eq!(record["offset"], location.offset);
+                assert_eq!(record["len"], location.len);
+                assert_eq!(record["kind"], format!("{:?}", location.kind));
+                assert_eq!(record.get("key").map(|key| key.to_string()), location.key);
+                assert!(record["integrity"].is_string());
+            }
+            assert!(records[0]["value"]["sha256"].is_string());
+            assert!(records.iter().any(|record| record["meta"]["len"].is_number()));
+
+            // keys are hidden too
+            let (_, bundle) = export(BundleRedaction::ValuesAndKeys)?;
+            assert!(!contains_secret(&bundle));
+            assert!(!bundle.contains("key1"));
+            assert_eq!(records_of(&bundle).len(), locations.len());
+
+            // all records are exported after corrupted one
+            let mut content = std::fs::read(&file)?;
+            let secret_pos = content.windows(7).position(|window| window == b"secret1").unwrap();
+            content[secret_pos] = b'S';
+            std::fs::write(&file, content)?;
+            let (summary, bundle) = export(BundleRedaction::Values)?;
+            assert_eq!(summary.first_failing_record, Some(2));
+            assert_eq!(summary.records, locations.len());
+            assert!(bundle.contains("# first failing record: 2 "));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_migrating() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::key_migration::{KeyFormat, OpenMigratingError};
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+            let expected = [(10, "1".to_string()), (30, "3".to_string())].iter().cloned().collect::<std::collections::BTreeMap<u64, String>>();
+
+            // old format
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            for key in 1..4 {
+                map.insert(key.to_string(), key.to_string())?;
+            }
+            map.remove(&"2".to_string())?;
+            drop(map);
+            let key_format = if text { KeyFormat::Detect } else { KeyFormat::Old }; // bincode of string starts with u64 of length
+            let map = BTreeMap::<u64, String>::open_migrating_from(&file, make_cfg(), key_format, |key: String| key.parse::<u64>().unwrap() * 10)?;
+            assert_eq!(map.map(), &expected);
+            drop(map);
+            let map = BTreeMap::<u64, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.map(), &expected);
+            drop(map);
+            let dir = std::path::Path::new(&file).parent().unwrap();
+            let file_name = std::path::Path::new(&file).file_name().unwrap().to_str().unwrap().to_string();
+            assert!(!std::fs::read_dir(dir)?.any(|entry| entry.unwrap().file_name().to_str().unwrap().starts_with(&format!("{}.", file_name))));
+
+            // new format is opened as is
+            let content = std::fs::read(&file)?;
+            let map = BTreeMap::<u64, String>::open_migrating_from(&file, make_cfg(), KeyFormat::Detect, |key: String| key.parse::<u64>().unwrap())?;
+            assert_eq!(map.map(), &expected);
+            drop(map);
+            assert_eq!(std::fs::read(&file)?, content);
+
+            // empty file
+            let file = tmp_file()?;
+            let mut map = BTreeMap::<u64, String>::open_migrating::<String>(&file, make_cfg(), |key| key.parse().unwrap())?;
+            assert!(map.map().is_empty());
+            map.insert(1, "1".to_string())?;
+            drop(map);
+            let map = BTreeMap::<u64, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&1), Some(&"1".to_string()));
+            drop(map);
+
+            // keys readable as both types need explicit format
+            let file = tmp_file()?;
+            let mut map = BTreeMap::<u32, String>::open_or_create(&file, make_cfg())?;
+            map.insert(1, "1".to_string())?;
+            map.insert(3, "3".to_string())?;
+            drop(map);
+            let res = BTreeMap::<u64, String>::open_migrating(&file, make_cfg(), |key: u32| key as u64 * 10);
+            assert!(matches!(res, Err(OpenMigratingError::AmbiguousKeyFormat { line_num: 1 })));
+            let map = BTreeMap::<u64, String>::open_migrating_from(&file, make_cfg(), KeyFormat::Old, |key: u32| key as u64 * 10)?;
+            assert_eq!(map.map(), &expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn mirror() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::mirror::MirrorSink;
+        use std::sync::{Arc, Mutex};
+
+        /// Mirror rebuilding the map and counting operations.
+        #[derive(Default)]
+        struct Rebuilt {
+            map: std::collections::BTreeMap<i32, String>,
+            operations: usize,
+        }
+
+        struct RebuildingMirror(Arc<Mutex<Rebuilt>>);
+
+        impl MirrorSink<i32, String> for RebuildingMirror {
+            fn on_insert(&mut self, key: &i32, value: &String, old_value: Option<&String>) {
+                let mut rebuilt = self.0.lock().unwrap();
+                assert_eq!(rebuilt.map.insert(*key, value.clone()).as_ref(), old_value);
+                rebuilt.operations += 1;
+            }
+
+            fn on_remove(&mut self, key: &i32, old_value: &String) {
+                let mut rebuilt = self.0.lock().unwrap();
+                assert_eq!(rebuilt.map.remove(key).as_ref(), Some(old_value));
+                rebuilt.operations += 1;
+            }
+        }
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(0, "before attach".to_string())?;
+
+        // mirror is filled from the map before attaching
+        let rebuilt = Arc::new(Mutex::new(Rebuilt::default()));
+        rebuilt.lock().unwrap().map = map.map().clone();
+        let id = map.attach_mirror(Box::new(RebuildingMirror(rebuilt.clone())));
+        let detached = Arc::new(Mutex::new(Rebuilt::default()));
+        let detached_id = map.attach_mirror(Box::new(RebuildingMirror(detached.clone())));
+        assert_ne!(id, detached_id);
+
+        map.insert(1, "1".to_string())?;
+        map.insert(2, "2".to_string())?;
+        assert!(map.detach_mirror(detached_id).is_some());
+        assert!(map.detach_mirror(detached_id).is_none());
+        map.insert(1, "updated".to_string())?;
+        map.remove(&2)?;
+        map.remove(&100)?;
+        map.insert_with_meta(3, "3".to_string(), &"author")?;
+        map.remove_with_meta(&0, &"author")?;
+        map.apply_batch(vec![MapOperation::Insert(4, "4".to_string()), MapOperation::Remove(3)])?;
+        map.try_extend((5..8).map(|key| (key, key.to_string())))?;
+        let mut txn = map.transaction();
+        txn.insert(8, "8".to_string());
+        txn.remove(&5);
+        txn.commit()?;
+
+        assert_eq!(&rebuilt.lock().unwrap().map, map.map());
+        assert_eq!(rebuilt.lock().unwrap().operations, 13);
+        assert_eq!(detached.lock().unwrap().operations, 2);
+
+        // detached mirror is returned
+        let mut mirror = map.detach_mirror(id).unwrap();
+        mirror.on_insert(&9, &"9".to_string(), None);
+        assert_eq!(rebuilt.lock().unwrap().map.get(&9), Some(&"9".to_string()));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn diagnostics() -> Result<(), Box<dyn std::error::Error>> {
+        use std::path::PathBuf;
+
+        let files = [tmp_file()?, tmp_file()?, tmp_file()?];
+        // registry is global, other tests can open maps at the same time
+        let diagnostics_of_files = || {
+            crate::diagnostics::snapshot().into_iter()
+                .filter(|diagnostics| files.iter().any(|file| PathBuf::from(file) == diagnostics.path))
+                .collect::<Vec<_>>()
+        };
+
+        let mut first = BTreeMap::open_or_create(&files[0], Cfg::default())?;
+        let second = HashMap::<String, String>::open_or_create(&files[1], Cfg::default())?;
+        let third = BTreeMap::<String, String>::open_or_create(&files[2], Cfg::default())?;
+        first.insert("key".to_string(), "value".to_string())?;
+        first.flush()?;
+
+        let diagnostics = diagnostics_of_files();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics.iter().map(|diagnostics| diagnostics.path.clone()).collect::<Vec<_>>(), files.iter().map(PathBuf::from).collect::<Vec<_>>());
+        assert!(diagnostics[0].backend.contains("BTreeMap"));
+        assert!(diagnostics[1].backend.contains("HashMap"));
+        assert_eq!(diagnostics[0].bytes_written, first.bytes_written_since_open());
+        assert_eq!(diagnostics[0].pending_writes, 0);
+        assert!(diagnostics[0].last_flush_at.is_some());
+        assert_eq!(diagnostics[1].bytes_written, 0);
+        assert!(diagnostics[0].opened_at <= diagnostics[2].opened_at);
+
+        drop(second);
+        let diagnostics = diagnostics_of_files();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].path, PathBuf::from(&files[0]));
+        assert_eq!(diagnostics[1].path, PathBuf::from(&files[2]));
+
+        drop(first);
+        drop(third);
+        assert!(diagnostics_of_files().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_marker() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::open_report::OpenWarning;
+
+        let file = tmp_file()?;
+        let marker = format!("{}.dirty", file);
+        let cfg = || Cfg { dirty_marker: true, ..Cfg::default() };
+
+        // marker exists while the map is open and is removed by drop
+        let mut map = BTreeMap::open_or_create(&file, cfg())?;
+        assert!(map.open_warnings().is_empty());
+        assert!(std::fs::read_to_string(&marker)?.starts_with(&format!("{} ", std::process::id())));
+        map.insert(1, "1".to_string())?;
+        drop(map);
+        assert!(!std::path::Path::new(&marker).exists());
+
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, cfg())?;
+        assert!(map.open_warnings().is_empty());
+
+        // crash is simulated by leaking of the map, leaked map keeps the file locked, so copies are opened
+        let opened_at = std::time::SystemTime::now();
+        map.flush()?;
+        std::mem::forget(map);
+        let crashed_file = tmp_file()?;
+        let crashed_marker = format!("{}.dirty", crashed_file);
+        std::fs::copy(&file, &crashed_file)?;
+        std::fs::copy(&marker, &crashed_marker)?;
+
+        let mut map = BTreeMap::<i32, String>::open_or_create(&crashed_file, cfg())?;
+        assert_eq!(map.get(&1), Some(&"1".to_string()));
+        match map.open_warnings() {
+            [OpenWarning::UncleanShutdown { pid, at }] => {
+                assert_eq!(*pid, std::process::id());
+                assert!(opened_at.duration_since(*at)? < std::time::Duration::from_secs(60));
+            },
+            warnings => panic!("{:?}", warnings),
+        }
+
+        // second consecutive crash is reported by marker of the second process
+        std::mem::forget(map);
+        let twice_crashed_file = tmp_file()?;
+        std::fs::copy(&crashed_file, &twice_crashed_file)?;
+        std::fs::copy(&crashed_marker, format!("{}.dirty", twice_crashed_file))?;
+        let mut map = BTreeMap::<i32, String>::open_or_create(&twice_crashed_file, cfg())?;
+        assert!(matches!(map.open_warnings(), [OpenWarning::UncleanShutdown { .. }]));
+        drop(map);
+
+        // marker which couldn't be removed after clean close and partially written marker
+        let file = tmp_file()?;
+        let marker = format!("{}.dirty", file);
+        std::fs::write(&marker, "clean\n")?;
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, cfg())?;
+        assert!(map.open_warnings().is_empty());
+        drop(map);
+        std::fs::write(&marker, "12")?;
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, cfg())?;
+        assert!(matches!(map.open_warnings(), [OpenWarning::UnreadableDirtyMarker]));
+        drop(map);
+
+        // marker is not written without the option
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        drop(map);
+        assert!(!std::path::Path::new(&marker).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_with_observer() -> Result<(), Box<dyn std::error::Error>> {
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+            for i in 0..10 {
+                map.insert(i, i.to_string())?;
+            }
+            map.remove(&3)?;
+            map.insert(5, "five".to_string())?;
+            let mut txn = map.transaction();
+            txn.insert(20, "20".to_string());
+            txn.remove(&7);
+            txn.commit()?;
+            drop(map);
+
+            let mut indices = Vec::new();
+            let mut projection = std::collections::BTreeMap::new();
+            let map = BTreeMap::<i32, String>::open_or_create_with_observer(&file, make_cfg(), |index, map_operation| {
+                indices.push(index);
+                match map_operation {
+                    MapOperation::Insert(key, value) => projection.insert(*key, value.clone()),
+                    MapOperation::Remove(key) => projection.remove(key),
+                };
+            })?;
+            assert_eq!(indices, (1..=14).collect::<Vec<_>>());
+            assert_eq!(map.map(), &projection);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_with_report() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::open_report::OpenWarning;
+
+        let file = tmp_file()?;
+        let (mut map, report) = BTreeMap::open_or_create_with_report(&file, Cfg::default())?;
+        assert!(report.warnings.is_empty());
+        assert_eq!(report.records_loaded, 0);
+        assert_eq!(report.load_stats.total_bytes, 0);
+        for i in 0..5 {
+            map.insert(i, i.to_string())?;
+        }
+        map.remove(&0)?;
+        drop(map);
+
+        let (map, report) = BTreeMap::<i32, String>::open_or_create_with_report(&file, Cfg::default())?;
+        assert_eq!(report.records_loaded, 6);
+        assert_eq!(report.load_stats.total_bytes, std::fs::metadata(&file)?.len());
+        assert_eq!(&report.load_stats, map.load_stats());
+        assert!(report.load_duration < std::time::Duration::from_secs(60));
+        assert_eq!(map.map().len(), 4);
+        drop(map);
+
+        // warnings are moved to the report
+        std::fs::write(format!("{}.dirty", file), "1 2\n")?;
+        let (mut map, report) = BTreeMap::<i32, String>::open_or_create_with_report(&file, Cfg { dirty_marker: true, ..Cfg::default() })?;
+        assert!(matches!(report.warnings[..], [OpenWarning::UncleanShutdown { pid: 1, .. }]));
+        assert!(map.open_warnings().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_writer() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::log_writer::{LogWriter, LogWriterError};
+
+        for text in [true, false] {
+            let make_cfg = || {
+                let mut cfg = Cfg::default();
+                cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+                cfg.schema_fingerprint = Some("v1".to_string());
+                cfg
+            };
+
+            let file = tmp_file()?;
+            let mut writer = LogWriter::<i32, String>::create(&file, make_cfg())?;
+            for i in 0..5 {
+                writer.insert(&i, &i.to_string())?;
+            }
+            writer.remove(&2)?;
+            let anchor = writer.finish()?.unwrap();
+            assert!(matches!(LogWriter::<i32, String>::create(&file, make_cfg()), Err(LogWriterError::FileNotEmpty)));
+
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.map().len(), 4);
+            assert_eq!(map.get(&2), None);
+            assert_eq!(map.integrity_head(), Some(anchor));
+            drop(map);
+
+            // chain is resumed when appending
+            let mut writer = LogWriter::<i32, String>::append(&file, make_cfg())?;
+            writer.insert(&2, &"two".to_string())?;
+            writer.remove(&0)?;
+            writer.flush()?;
+            drop(writer);
+
+            let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.get(&2), Some(&"two".to_string()));
+            assert_eq!(map.get(&0), None);
+
+            // incomplete transaction at the end is aborted
+            let mut txn = map.transaction();
+            txn.insert(10, "10".to_string());
+            txn.commit()?;
+            drop(map);
+            let content = std::fs::read(&file)?;
+            if text {
+                let file_with_incomplete_transaction = tmp_file()?;
+                // cut the end marker of the transaction
+                let cut = content[..content.len() - 1].iter().rposition(|byte| *byte == b'\n').unwrap() + 1;
+                std::fs::write(&file_with_incomplete_transaction, &content[..cut])?;
+                let mut writer = LogWriter::<i32, String>::append(&file_with_incomplete_transaction, make_cfg())?;
+                writer.insert(&11, &"11".to_string())?;
+                drop(writer);
+                let map = BTreeMap::<i32, String>::open_or_create(&file_with_incomplete_transaction, make_cfg())?;
+                assert_eq!(map.get(&10), None);
+                assert_eq!(map.get(&11), Some(&"11".to_string()));
+            }
+
+            // wrong integrity of existing file is error
+            let wrong_cfg = Cfg { integrity: Some(Integrity::Sha256Chain([1; 32])), ..make_cfg() };
+            assert!(matches!(LogWriter::<i32, String>::append(&file, wrong_cfg), Err(LogWriterError::LoadFileError(_))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_reader() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::RecordKind;
+        use crate::log_reader::{LogReader, RawPayload, RecordIntegrity};
+
+        for text in [true, false] {
+            for integrity in [None, Some(Integrity::Crc32), Some(Integrity::Sha256Chain([0; 32]))] {
+                let make_cfg = || {
+                    let mut cfg = Cfg::default();
+                    cfg.format = if text { Format::Text(None, None) } else { Format::Bin(None, None) };
+                    cfg.integrity = integrity.clone();
+                    cfg
+                };
+
+                let file = tmp_file()?;
+                let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+                map.insert(1, "a".to_string())?;
+                map.insert_with_meta(2, "b".to_string(), &"author".to_string())?;
+                map.remove(&1)?;
+                let mut txn = map.transaction();
+                txn.insert(3, "c".to_string());
+                txn.commit()?;
+                drop(map);
+
+                let records = LogReader::open(&file, make_cfg())?.collect::<Result<Vec<_>, _>>()?;
+                let kinds = records.iter().map(|record| record.op_kind).collect::<Vec<_>>();
+                assert_eq!(kinds, vec![Some(RecordKind::Insert), Some(RecordKind::Insert), Some(RecordKind::Remove),
+                    Some(RecordKind::TransactionBegin), Some(RecordKind::Insert), Some(RecordKind::TransactionEnd)]);
+                assert_eq!(records.iter().map(|record| record.index).collect::<Vec<_>>(), (1..=6).collect::<Vec<_>>());
+                assert_eq!(records[0].offset, 0);
+                assert_eq!(records[5].offset + records[5].len, std::fs::metadata(&file)?.len());
+                assert!(records.iter().all(|record| match record.integrity {
+                    RecordIntegrity::Ok => integrity.is_some(),
+                    RecordIntegrity::Absent => integrity.is_none(),
+                    RecordIntegrity::Failed(_) => false,
+                }));
+                assert!(records[0].meta.is_none());
+                if text {
+                    assert_eq!(records[0].payload, RawPayload::Text("ins [1,\"a\"]".to_string()));
+                    assert_eq!(records[1].meta, Some(RawPayload::Text("\"author\"".to_string())));
+                } else {
+                    assert!(matches!(&records[1].meta, Some(RawPayload::Bin(_))));
+                }
+
+                if integrity.is_none() {
+                    continue;
+                }
+
+                // record with wrong integrity is returned and reading continues
+                let mut content = std::fs::read(&file)?;
+                // byte of metadata of the text format or the last byte of value of the binary format
+                let pos = if text {
+                    records[1].offset + 6
+                } else {
+                    records[1].offset + records[1].len - crate::bin_format::bin_integrity_len(integrity.as_ref().unwrap()) as u64 - 1
+                } as usize;
+                content[pos] ^= 1;
+                std::fs::write(&file, &content)?;
+                let records = LogReader::open(&file, make_cfg())?.collect::<Result<Vec<_>, _>>()?;
+                assert_eq!(records.len(), 6);
+                assert!(matches!(records[1].integrity, RecordIntegrity::Failed(_)));
+                assert!(records.iter().enumerate().all(|(i, record)| i == 1 || matches!(record.integrity, RecordIntegrity::Ok)));
+            }
+        }
+
+        // reading stops after error
+        let file = tmp_file()?;
+        std::fs::write(&file, "ins [1,\"a\"]\nins [2")?;
+        let mut reader = LogReader::open(&file, Cfg::default())?;
+        assert!(matches!(reader.next(), Some(Ok(_))));
+        assert!(matches!(reader.next(), Some(Err(LoadFileError::LastLineWithoutEndLine { line_num: 2 }))));
+        assert!(reader.next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_insert() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::map_with_file::TryOutcome;
+        use crate::testing::SlowWriter;
+        use std::time::Duration;
+
+        // slow sink keeps writes pending much longer than the test waits between operations
+        let make_cfg = || {
+            let mut cfg = Cfg::default();
+            cfg.integrity = Some(Integrity::Sha256Chain([0; 32]));
+            cfg.max_pending_writes = Some(2);
+            cfg.secondary_sink = Some(Box::new(SlowWriter::new(std::io::sink(), Duration::from_millis(300))));
+            cfg
+        };
+        let done = |outcome: TryOutcome<i32, String>| match outcome {
+            TryOutcome::Done(old_value) => old_value,
+            TryOutcome::Pending(token) => panic!("pending {}", token.key()),
+        };
+
+        // record made before pending is written as is
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        assert_eq!(done(map.try_insert(1, "1".to_string())?), None);
+        assert_eq!(done(map.try_insert(2, "2".to_string())?), None);
+        let token = match map.try_insert(3, "3".to_string())? {
+            TryOutcome::Pending(token) => token,
+            TryOutcome::Done(_) => panic!("done with full queue"),
+        };
+        assert_eq!(token.key(), &3);
+        assert_eq!(token.value(), "3");
+        assert_eq!(map.get(&3), None);
+        let token = match map.retry(token)? {
+            TryOutcome::Pending(token) => token,
+            TryOutcome::Done(_) => panic!("done with full queue"),
+        };
+        map.flush()?;
+        assert_eq!(done(map.retry(token)?), None);
+        assert_eq!(map.get(&3), Some(&"3".to_string()));
+        assert_eq!(done(map.try_insert(3, "three".to_string())?), Some("3".to_string()));
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.map().len(), 3);
+        assert_eq!(map.get(&3), Some(&"three".to_string()));
+        drop(map);
+
+        // record is made again if other record is written after pending
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(1, "1".to_string())?;
+        map.insert(2, "2".to_string())?;
+        let token = match map.try_insert(3, "3".to_string())? {
+            TryOutcome::Pending(token) => token,
+            TryOutcome::Done(_) => panic!("done with full queue"),
+        };
+        map.insert(4, "4".to_string())?;
+        map.flush()?;
+        assert_eq!(done(map.retry(token)?), None);
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.map().keys().copied().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        // load is shed by taking key and value of pending insert
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+        map.insert(1, "1".to_string())?;
+        map.insert(2, "2".to_string())?;
+        match map.try_insert(3, "3".to_string())? {
+            TryOutcome::Pending(token) => assert_eq!(token.into_inner(), (3, "3".to_string())),
+            TryOutcome::Done(_) => panic!("done with full queue"),
+        }
+        assert_eq!(map.map().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn extra_text_ops() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::RecordKind;
+        use crate::log_reader::LogReader;
+
+        let make_cfg = || Cfg {
+            extra_text_ops: vec![("insert".to_string(), RecordKind::Insert), ("remove".to_string(), RecordKind::Remove), ("del".to_string(), RecordKind::Remove)],
+            ..Cfg::default()
+        };
+
+        let file = tmp_file()?;
+        std::fs::write(&file, "insert [1,\"a\"]\nins [2,\"b\"]\ninsert [3,\"c\"]\nremove 1\ndel 2\n")?;
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+        assert_eq!(map.map().iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>(), vec![(3, "c")]);
+
+        // writing uses canonical names
+        map.insert(4, "d".to_string())?;
+        drop(map);
+        assert!(std::fs::read_to_string(&file)?.ends_with("ins [4,\"d\"]\n"));
+
+        let kinds = LogReader::open(&file, make_cfg())?.map(|record| record.map(|record| record.op_kind)).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(kinds, vec![Some(RecordKind::Insert), Some(RecordKind::Insert), Some(RecordKind::Insert), Some(RecordKind::Remove), Some(RecordKind::Remove), Some(RecordKind::Insert)]);
+
+        // aliases are not accepted without config, error contains the name
+        let result = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default());
+        assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::UnknownOperation { ref name, line_num: 1 }) if name == "insert"));
+
+        assert!(matches!(Cfg::builder().extra_text_op("met", RecordKind::Insert).build(), Err(crate::cfg_builder::CfgError::WrongTextOpAlias)));
+        assert!(matches!(Cfg::builder().extra_text_op("in s", RecordKind::Insert).build(), Err(crate::cfg_builder::CfgError::WrongTextOpAlias)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn on_deserialize_error() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::open_report::OpenWarning;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Serialize, Deserialize)]
+        struct OldValue {
+            a: i32,
+        }
+
+        // required field is added to the value type
+        #[derive(Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+        struct NewValue {
+            a: i32,
+            b: String,
+        }
+
+        for text in [true, false] {
+            let make_cfg = |policy| Cfg {
+                format: if text { Format::Text(None, None) } else { Format::Bin(None, None) },
+                on_deserialize_error: policy,
+                ..Cfg::default()
+            };
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, make_cfg(DeserializePolicy::Fail))?;
+            map.insert(1, OldValue { a: 1 })?;
+            map.insert(2, OldValue { a: 2 })?;
+            map.remove(&1)?;
+            drop(map);
+
+            let result = BTreeMap::<i32, NewValue>::open_or_create(&file, make_cfg(DeserializePolicy::Fail));
+            assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::DeserializeJsonError { line_num: 1, .. }) | Err(LoadFileError::DeserializeBincodeError { block_num: 1, .. })));
+
+            // default values require separate constructor
+            let result = BTreeMap::<i32, NewValue>::open_or_create(&file, make_cfg(DeserializePolicy::Default));
+            assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::DeserializeJsonError { .. }) | Err(LoadFileError::DeserializeBincodeError { .. })));
+
+            let mut map = BTreeMap::<i32, NewValue>::open_or_create(&file, make_cfg(DeserializePolicy::Skip))?;
+            assert!(map.map().is_empty());
+            assert!(matches!(map.open_warnings(), [OpenWarning::SkippedRecords { count: 2 }]));
+            map.insert(3, NewValue { a: 3, b: "c".to_string() })?;
+            drop(map);
+
+            let mut map = BTreeMap::<i32, NewValue>::open_or_create_with_default_values(&file, make_cfg(DeserializePolicy::Default))?;
+            assert_eq!(map.get(&2), Some(&NewValue::default()));
+            assert_eq!(map.get(&3), Some(&NewValue { a: 3, b: "c".to_string() }));
+            assert_eq!(map.map().len(), 2);
+            assert!(matches!(map.open_warnings(), [OpenWarning::DefaultValues { count: 2 }]));
+            drop(map);
+
+            if !text {
+                continue;
+            }
+
+            // remove with key of other type can only be skipped
+            std::fs::OpenOptions::new().append(true).open(&file)?.write_all(b"rem \"x\"\n")?;
+            let mut map = BTreeMap::<i32, NewValue>::open_or_create_with_default_values(&file, make_cfg(DeserializePolicy::Default))?;
+            assert_eq!(map.map().len(), 2);
+            assert!(matches!(map.open_warnings(), [OpenWarning::SkippedRecords { count: 1 }, OpenWarning::DefaultValues { count: 2 }]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ship_cursor() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::ship_cursor::{ShipCursor, ShippedRecord};
+
+        let render = |record: &ShippedRecord<i32, String>| {
+            let operations = record.operations.iter().map(|operation| match operation {
+                MapOperation::Insert(key, value) => format!("ins {} {}", key, value),
+                MapOperation::Remove(key) => format!("rem {}", key),
+            });
+            (record.seq, operations.collect::<Vec<_>>().join(", "))
+        };
+
+        for text in [true, false] {
+            for integrity in [None, Some(Integrity::Sha256Chain([0; 32]))] {
+                let make_cfg = || Cfg {
+                    format: if text { Format::Text(None, None) } else { Format::Bin(None, None) },
+                    integrity: integrity.clone(),
+                    ..Cfg::default()
+                };
+
+                let file = tmp_file()?;
+                let cursor_path = format!("{}.cursor", file);
+                let mut shipped = Vec::new();
+
+                let mut map = BTreeMap::open_or_create(&file, make_cfg())?;
+                map.insert(1, "a".to_string())?;
+                map.insert(2, "b".to_string())?;
+                let mut cursor = ShipCursor::load(&cursor_path)?;
+                for record in map.records_since(cursor.offset(), cursor.seq())? {
+                    shipped.push(render(&record));
+                    cursor.store(record.end_offset, record.seq + 1)?;
+                }
+
+                map.remove(&1)?;
+                map.apply_batch(vec![MapOperation::Insert(3, "c".to_string()), MapOperation::Remove(2)])?;
+                let mut txn = map.transaction();
+                txn.insert(4, "d".to_string());
+                txn.insert(5, "e".to_string());
+                txn.commit()?;
+
+                // shipping stops after the first record
+                let record = map.records_since(cursor.offset(), cursor.seq())?.next().unwrap();
+                shipped.push(render(&record));
+                cursor.store(record.end_offset, record.seq + 1)?;
+                drop(map);
+                drop(cursor);
+
+                // restart with new objects and the same files
+                let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+                map.insert(6, "f".to_string())?;
+                let mut cursor = ShipCursor::load(&cursor_path)?;
+                for record in map.records_since(cursor.offset(), cursor.seq())? {
+                    shipped.push(render(&record));
+                    cursor.store(record.end_offset, record.seq + 1)?;
+                }
+                assert_eq!(map.records_since(cursor.offset(), cursor.seq())?.count(), 0);
+
+                assert_eq!(shipped, vec![
+                    (0, "ins 1 a".to_string()),
+                    (1, "ins 2 b".to_string()),
+                    (2, "rem 1".to_string()),
+                    (3, "ins 3 c, rem 2".to_string()),
+                    (4, "ins 4 d, ins 5 e".to_string()),
+                    (5, "ins 6 f".to_string()),
+                ]);
+
+                // offset inside a record is detected by format or by continuity of the chain
+                if text || integrity.is_some() {
+                    assert!(map.records_since(cursor.offset() - 1, 0).is_err());
+                }
+                assert!(matches!(map.records_since(cursor.offset() + 1, 0), Err(LoadFileError::NotRecordBoundary { .. })));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_without_drop() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..5000 {
+            map.insert(i, i.to_string())?;
+        }
+        map.flush()?;
+
+        // file is read by other handle while the map is open
+        let content = std::fs::read_to_string(&file)?;
+        assert_eq!(content.lines().count(), 5000);
+        assert_eq!(content.lines().last(), Some("ins [4999,\"4999\"]"));
+        assert_eq!(map.metrics().pending_writes, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_write_mode() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailSwitch, FailingWriter};
+        use std::fs::OpenOptions;
+
+        let file = tmp_file()?;
+        let cfg = || Cfg { integrity: Some(Integrity::Sha256Chain([0; 32])), write_mode: WriteMode::Sync, ..Cfg::default() };
+        let mut map = BTreeMap::open_or_create(&file, cfg())?;
+        map.insert(1, 1)?;
+        map.insert(2, 2)?;
+        // written without flush
+        assert_eq!(std::fs::read_to_string(&file)?.lines().count(), 2);
+        assert_eq!(map.metrics().pending_writes, 0);
+
+        // failed write writes half of data to the file
+        let fail = FailSwitch::new();
+        fail.on();
+        let fail_truncate = FailSwitch::new();
+        let file_len = std::fs::metadata(&file)?.len();
+        let failing_file = FailingWriter::fail_when(OpenOptions::new().append(true).open(&file)?, fail.clone())
+            .partial_writes()
+            .fail_truncate_when(fail_truncate.clone());
+        let mut worker_cfg = FileWorkerCfg::take_from(&mut cfg(), file.clone().into(), file_len);
+        worker_cfg.write_mode = WriteMode::Sync;
+        map.file_worker = FileWorker::new(failing_file, worker_cfg);
+
+        assert!(matches!(map.insert(3, 3), Err(SerializedError::WriteError(_))));
+        assert!(matches!(map.remove(&1), Err(SerializedError::WriteError(_))));
+        assert!(matches!(map.try_extend(vec![(4, 4)]), Err(SerializedError::WriteError(_))));
+        assert_eq!(map.map().clone(), vec![(1, 1), (2, 2)].into_iter().collect());
+        assert_eq!(std::fs::metadata(&file)?.len(), file_len);
+        assert_eq!(map.metrics().write_errors, 3);
+
+        // writes fail while partially written record is not truncated
+        fail_truncate.on();
+        assert!(matches!(map.insert(3, 3), Err(SerializedError::WriteError(_))));
+        assert!(std::fs::metadata(&file)?.len() > file_len);
+        fail.off();
+        match map.insert(3, 3) {
+            Err(SerializedError::WriteError(err)) => assert_eq!(err.to_string(), "partially written data is not truncated: injected truncate failure"),
+            res => panic!("unexpected result {:?}", res.map_err(|err| err.to_string())),
+        }
+        assert_eq!(map.map().clone(), vec![(1, 1), (2, 2)].into_iter().collect());
+
+        // chain of integrity continues from the last written record
+        fail_truncate.off();
+        map.remove(&1)?;
+        map.insert(3, 3)?;
+        drop(map);
+
+        let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+        assert_eq!(map.map().clone(), vec![(2, 2), (3, 3)].into_iter().collect());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fsync_policy() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{WriteErrorContext, WriteOperation};
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailSwitch, FailingWriter, ManualClock, SharedBuffer};
+        use std::path::PathBuf;
+        use std::sync::atomic::Ordering;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let worker = |fsync_policy, write_mode, file: &SharedBuffer, cfg: &mut Cfg| {
+            cfg.fsync_policy = fsync_policy;
+            cfg.write_mode = write_mode;
+            FileWorker::new(file.clone(), FileWorkerCfg::take_from(cfg, PathBuf::from("db/map.txt"), 0))
+        };
+
+        for (fsync_policy, syncs_after_writes) in [(FsyncPolicy::Never, 0), (FsyncPolicy::EveryWrite, 5), (FsyncPolicy::EveryNOps(2), 2)] {
+            for write_mode in [WriteMode::Background, WriteMode::Sync] {
+                let file = SharedBuffer::new();
+                let file_worker = worker(fsync_policy, write_mode, &file, &mut Cfg::default());
+                // waiting of each write, so writes are not coalesced
+                for _ in 0..5 {
+                    file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+                    file_worker.flush().recv()??;
+                }
+                assert_eq!(file.syncs(), syncs_after_writes);
+                drop(file_worker);
+                // remaining writes are synced when dropped with any policy
+                assert_eq!(file.syncs(), if fsync_policy == FsyncPolicy::EveryWrite { 5 } else { syncs_after_writes + 1 });
+            }
+        }
+
+        // synced by the next write after the interval of the clock
+        for write_mode in [WriteMode::Background, WriteMode::Sync] {
+            let clock = ManualClock::new(UNIX_EPOCH);
+            let file = SharedBuffer::new();
+            let file_worker = worker(FsyncPolicy::Interval(Duration::from_secs(60)), write_mode, &file, &mut Cfg { clock: Arc::new(clock.clone()), ..Cfg::default() });
+            file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+            file_worker.flush().recv()??;
+            clock.advance(Duration::from_secs(59));
+            file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+            file_worker.flush().recv()??;
+            assert_eq!(file.syncs(), 0);
+            clock.advance(Duration::from_secs(1));
+            file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+            file_worker.flush().recv()??;
+            assert_eq!(file.syncs(), 1);
+        }
+
+        // synced by timer of the worker thread without next write
+        let file = SharedBuffer::new();
+        let file_worker = worker(FsyncPolicy::Interval(Duration::from_millis(50)), WriteMode::Background, &file, &mut Cfg::default());
+        file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+        file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(file.syncs(), 1);
+        drop(file_worker);
+        assert_eq!(file.syncs(), 1);
+
+        // error of sync is passed to the callback and returned by flush
+        let fail = FailSwitch::new();
+        fail.on();
+        let contexts = Arc::new(Mutex::new(Vec::new()));
+        let callback_contexts = contexts.clone();
+        let mut cfg = Cfg {
+            write_error_context_callback: Some(Box::new(move |context: WriteErrorContext| callback_contexts.lock().unwrap().push(context))),
+            ..Cfg::default()
+        };
+        cfg.fsync_policy = FsyncPolicy::EveryWrite;
+        let file_worker = FileWorker::new(FailingWriter::fail_sync_when(SharedBuffer::new(), fail), FileWorkerCfg::take_from(&mut cfg, PathBuf::from("db/map.txt"), 0));
+        file_worker.write_bytes(vec![0; 4], WriteOperation::Remove)?;
+        assert_eq!(file_worker.flush().recv()?.map_err(|err| err.to_string()), Err("injected sync failure".to_string()));
+        assert_eq!(file_worker.counters().write_errors.load(Ordering::Relaxed), 1);
+        drop(file_worker);
+        let contexts = contexts.lock().unwrap();
+        assert_eq!(contexts.len(), 1);
+        assert_eq!((contexts[0].sequence, contexts[0].operation, contexts[0].bytes), (1, WriteOperation::Fsync, 4));
+
+        assert!(matches!(Cfg::builder().fsync_policy(FsyncPolicy::EveryNOps(0)).build(), Err(crate::cfg_builder::CfgError::WrongFsyncPolicy)));
+        assert!(matches!(Cfg::builder().fsync_policy(FsyncPolicy::Interval(Duration::ZERO)).build(), Err(crate::cfg_builder::CfgError::WrongFsyncPolicy)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_retry() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::{RetryPolicy, WriteErrorContext, WriteOperation};
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{Clock, FailSwitch, FailingWriter, ManualClock, SharedBuffer};
+        use std::path::PathBuf;
+        use std::sync::atomic::Ordering;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        for write_mode in [WriteMode::Background, WriteMode::Sync] {
+            let contexts = Arc::new(Mutex::new(Vec::new()));
+            let callback_contexts = contexts.clone();
+            let clock = ManualClock::new(UNIX_EPOCH);
+            let mut cfg = Cfg {
+                write_error_context_callback: Some(Box::new(move |context: WriteErrorContext| callback_contexts.lock().unwrap().push(context))),
+                write_retry: Some(RetryPolicy { attempts: 2, backoff: Duration::from_millis(10) }),
+                write_mode,
+                clock: Arc::new(clock.clone()),
+                ..Cfg::default()
+            };
+            // failed write writes half of data
+            let file = SharedBuffer::new();
+            let fail = FailSwitch::new();
+            let file_worker = FileWorker::new(FailingWriter::fail_when(file.clone(), fail.clone()).partial_writes(), FileWorkerCfg::take_from(&mut cfg, PathBuf::from("db/map.txt"), 0));
+
+            // repeated after truncate of partially written data and delays of the clock, the next write waits of it
+            fail.fail_next(2);
+            file_worker.write_bytes(b"abc".to_vec(), WriteOperation::Insert)?;
+            file_worker.write_bytes(b"def".to_vec(), WriteOperation::Insert)?;
+            file_worker.flush().recv()??;
+            assert_eq!(file.contents(), b"abcdef");
+            assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_millis(10 + 20));
+            assert!(contexts.lock().unwrap().is_empty());
+            assert_eq!(file_worker.counters().write_errors.load(Ordering::Relaxed), 0);
+
+            // data is passed to the callback after the last attempt
+            fail.fail_next(3);
+            let result = file_worker.write_bytes(b"ghi".to_vec(), WriteOperation::Remove);
+            let flush_result = file_worker.flush().recv()?;
+            assert!(result.is_err() || flush_result.is_err());
+            assert_eq!(fail.remaining(), 0);
+            assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_millis(2 * (10 + 20)));
+            {
+                let contexts = contexts.lock().unwrap();
+                assert_eq!(contexts.len(), 1);
+                assert_eq!((contexts[0].operation, contexts[0].data.as_slice()), (WriteOperation::Remove, b"ghi".as_slice()));
+            }
+            assert_eq!(file_worker.counters().write_errors.load(Ordering::Relaxed), 1);
+
+            // partially written data of the last attempt is truncated
+            file_worker.write_bytes(b"jkl".to_vec(), WriteOperation::Insert)?;
+            file_worker.flush().recv()??;
+            assert_eq!(file.contents(), b"abcdefjkl");
+        }
+
+        assert!(matches!(Cfg::builder().write_retry(RetryPolicy { attempts: 0, backoff: Duration::ZERO }).build(), Err(crate::cfg_builder::CfgError::ZeroRetryAttempts)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_write_truncated() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::RetryPolicy;
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailSwitch, FailingWriter};
+        use std::fs::OpenOptions;
+        use std::time::Duration;
+
+        for write_retry in [None, Some(RetryPolicy { attempts: 2, backoff: Duration::ZERO })] {
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+            map.insert(1, 1)?;
+            map.insert(2, 2)?;
+            map.flush()?;
+
+            // failed write writes half of data to the file
+            let fail = FailSwitch::new();
+            let file_len = std::fs::metadata(&file)?.len();
+            let failing_file = FailingWriter::fail_when(OpenOptions::new().append(true).open(&file)?, fail.clone()).partial_writes();
+            map.file_worker = FileWorker::new(failing_file, FileWorkerCfg::take_from(&mut Cfg { write_retry, ..Cfg::default() }, file.clone().into(), file_len));
+
+            fail.on();
+            map.insert(3, 3)?;
+            assert!(map.flush().is_err());
+            assert_eq!(std::fs::metadata(&file)?.len(), file_len);
+
+            // the next record is readable after failed one
+            fail.off();
+            map.insert(4, 4)?;
+            map.flush()?;
+            drop(map);
+
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, Cfg::default())?;
+            assert_eq!(map.map().clone(), vec![(1, 1), (2, 2), (4, 4)].into_iter().collect());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn take_last_write_error() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailSwitch, FailingWriter, SharedBuffer};
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let fail = FailSwitch::new();
+        map.file_worker = FileWorker::new(FailingWriter::fail_when(SharedBuffer::new(), fail.clone()), FileWorkerCfg::take_from(&mut Cfg::default(), file.clone().into(), 0));
+
+        map.insert(1, 1)?;
+        map.flush()?;
+        assert!(map.take_last_write_error().is_none());
+        assert_eq!(map.pending_writes(), 0);
+
+        fail.on();
+        map.insert(2, 2)?;
+        map.insert(3, 3)?;
+        assert!(map.flush().is_err());
+        assert_eq!(map.take_last_write_error().map(|err| err.to_string()), Some("injected write failure".to_string()));
+        // taken once
+        assert!(map.take_last_write_error().is_none());
+        assert_eq!(map.pending_writes(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_ticket() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::file_worker::{FileWorker, FileWorkerCfg, MAX_COMPLETED_WRITE_ERRORS};
+        use crate::testing::{FailSwitch, FailingWriter, SharedBuffer};
+
+        for write_mode in [WriteMode::Background, WriteMode::Sync] {
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, Cfg { write_mode, ..Cfg::default() })?;
+            assert_eq!(map.last_write_ticket().sequence(), 0);
+            map.wait_for(map.last_write_ticket())?;
+
+            let (_, first) = map.insert_with_ticket(1, 1)?;
+            let (old_value, second) = map.insert_with_ticket(1, 2)?;
+            assert_eq!(old_value, Some(1));
+            assert!(first < second);
+            map.wait_for(second)?;
+            assert_eq!(std::fs::read_to_string(&file)?.lines().count(), 2);
+            // already written
+            map.wait_for(first)?;
+
+            let (old_value, third) = map.remove_with_ticket(&1)?;
+            assert_eq!((old_value, third.sequence()), (Some(2), 3));
+            // nothing is written
+            let (_, not_written) = map.remove_with_ticket(&1)?;
+            assert_eq!(not_written, third);
+            map.wait_for(third)?;
+        }
+
+        // failure is reported only by the ticket of the failed write
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let fail = FailSwitch::new();
+        map.file_worker = FileWorker::new(FailingWriter::fail_when(SharedBuffer::new(), fail.clone()), FileWorkerCfg::take_from(&mut Cfg::default(), file.clone().into(), 0));
+        let (_, first) = map.insert_with_ticket(1, 1)?;
+        map.wait_for(first)?;
+        fail.on();
+        let (_, second) = map.insert_with_ticket(2, 2)?;
+        assert_eq!(map.wait_for(second).map_err(|err| err.to_string()), Err("injected write failure".to_string()));
+        fail.off();
+        let (_, third) = map.insert_with_ticket(3, 3)?;
+        map.wait_for(third)?;
+        map.wait_for(first)?;
+        assert!(map.wait_for(second).is_err());
+
+        // errors of only the last failed writes are kept
+        fail.on();
+        for i in 0..MAX_COMPLETED_WRITE_ERRORS {
+            map.insert(i as i32, 0)?;
+        }
+        let (_, last) = map.insert_with_ticket(-1, 0)?;
+        assert!(map.wait_for(last).is_err());
+        map.wait_for(second)?;
+
+        // waiting is woken if the thread panicked before the write is completed
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut worker_cfg = Cfg::builder().write_error(|_| panic!("error callback panicked")).build()?;
+        map.file_worker = FileWorker::new(FailingWriter::fail_every(SharedBuffer::new(), 1), FileWorkerCfg::take_from(&mut worker_cfg, file.clone().into(), 0));
+        let (_, ticket) = map.insert_with_ticket(1, 1)?;
+        assert_eq!(map.wait_for(ticket).map_err(|err| err.to_string()), Err("file worker thread panicked".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn worker_failure() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::WorkerFailure;
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailingWriter, SharedBuffer, SlowWriter};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // all writes fail after the delay
+        let slow_failing_file = |delay| SlowWriter::new(FailingWriter::fail_every(SharedBuffer::new(), 1), delay);
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let cfg = || {
+            let callback_failures = failures.clone();
+            Cfg::builder()
+                .write_error(|_| panic!("error callback panicked"))
+                .on_worker_failure(move |failure| callback_failures.lock().unwrap().push(failure))
+        };
+
+        // panic of the error callback is reported when the map is dropped
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.file_worker = FileWorker::new(slow_failing_file(Duration::ZERO), FileWorkerCfg::take_from(&mut cfg().build()?, file.clone().into(), 0));
+        map.insert(1, 1)?;
+        assert!(map.flush().is_err());
+        drop(map);
+        assert_eq!(*failures.lock().unwrap(), vec![WorkerFailure::Panicked("error callback panicked".to_string())]);
+
+        // the thread is left writing after the timeout
+        failures.lock().unwrap().clear();
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        let mut worker_cfg = cfg().stop_timeout(Duration::from_millis(10)).build()?;
+        map.file_worker = FileWorker::new(slow_failing_file(Duration::from_millis(300)), FileWorkerCfg::take_from(&mut worker_cfg, file.clone().into(), 0));
+        map.insert(1, 1)?;
+        assert_eq!(map.close().map_err(|err| err.kind()), Err(std::io::ErrorKind::TimedOut));
+        assert_eq!(*failures.lock().unwrap(), vec![WorkerFailure::StopTimeout]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact() -> Result<(), Box<dyn std::error::Error>> {
+        for write_mode in [WriteMode::Background, WriteMode::Sync] {
+            let file = tmp_file()?;
+            let cfg = || Cfg { integrity: Some(Integrity::Sha256Chain([7; 32])), write_mode, ..Cfg::default() };
+            let mut map = HashMap::open_or_create(&file, cfg())?;
+            for i in 0..1000 {
+                map.insert(i % 100, i)?;
+                if i % 3 == 0 {
+                    map.remove(&(i % 50))?;
+                }
+            }
+            let expected = map.map().clone();
+
+            map.compact()?;
+            let content = std::fs::read_to_string(&file)?;
+            assert_eq!(content.lines().count(), map.map().len());
+            // in order of keys
+            let mut keys = expected.keys().copied().collect::<Vec<_>>();
+            keys.sort();
+            assert!(content.lines().next().unwrap().starts_with(&format!("ins [{},", keys[0])));
+            assert_eq!(map.metrics().dead_operation_estimate, 0);
+
+            // next changes are appended to the compacted file
+            map.insert(1000, 1000)?;
+            map.remove(&keys[0])?;
+            let expected = map.map().clone();
+            drop(map);
+
+            let map = HashMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            assert_eq!(std::fs::read_to_string(&file)?.lines().count(), expected.len() + 2);
+            // no tmp files are left
+            let dir = std::path::Path::new(&file).parent().unwrap();
+            let file_name = std::path::Path::new(&file).file_name().unwrap().to_str().unwrap().to_string();
+            assert!(!std::fs::read_dir(dir)?.any(|entry| entry.unwrap().file_name().to_str().unwrap().starts_with(&format!("{}.", file_name))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_compact() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::AutoCompact;
+
+        let file = tmp_file()?;
+        let cfg = || Cfg { auto_compact: Some(AutoCompact { min_ops: 100, garbage_ratio: 0.8 }), ..Cfg::default() };
+        let mut map = BTreeMap::open_or_create(&file, cfg())?;
+        for i in 0..1000 {
+            map.insert(i % 10, i)?;
+            // compacted when 100 operations are logged for 10 entries
+            assert!(map.ops_logged() < 100);
+            assert_eq!(map.live_len(), std::cmp::min(i + 1, 10) as usize);
+        }
+        map.remove(&0)?;
+        map.flush()?;
+        let ops_logged = map.ops_logged();
+        assert_eq!(std::fs::read_to_string(&file)?.lines().count() as u64, ops_logged);
+        let expected = map.map().clone();
+        drop(map);
+
+        // operations of the file are counted when opening
+        let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+        assert_eq!((map.ops_logged(), map.live_len()), (ops_logged, 9));
+        assert_eq!(*map.map(), expected);
+        while map.ops_logged() >= ops_logged {
+            map.insert(1, 1)?;
+        }
+        assert!(map.take_last_write_error().is_none());
+
+        // compacted after batches and transactions too, each of them logs 2 operations
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, cfg())?;
+        for i in 0..1000 {
+            map.apply_batch(vec![MapOperation::Insert(i % 10, i), MapOperation::Remove((i + 5) % 10)])?;
+            let mut txn = map.transaction();
+            txn.insert(i % 10, -i);
+            txn.insert((i + 1) % 10, i);
+            txn.commit()?;
+            assert!(map.ops_logged() < 102);
+        }
+        assert!(map.take_last_write_error().is_none());
+
+        assert!(matches!(Cfg::builder().auto_compact(AutoCompact { min_ops: 0, garbage_ratio: 0.0 }).build(), Err(crate::cfg_builder::CfgError::WrongAutoCompactRatio)));
+        assert!(matches!(Cfg::builder().auto_compact(AutoCompact { min_ops: 0, garbage_ratio: f64::NAN }).build(), Err(crate::cfg_builder::CfgError::WrongAutoCompactRatio)));
+        assert!(Cfg::builder().auto_compact(AutoCompact { min_ops: 0, garbage_ratio: 1.0 }).build().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_in_background() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::compaction::CompactError;
+        use std::sync::{Arc, Mutex};
+
+        for write_mode in [WriteMode::Background, WriteMode::Sync] {
+            let file = tmp_file()?;
+            let cfg = || Cfg { integrity: Some(Integrity::Sha256Chain([5; 32])), write_mode, ..Cfg::default() };
+            let mut map = BTreeMap::open_or_create(&file, cfg())?;
+            for i in 0..1000 {
+                map.insert(i % 100, i)?;
+            }
+
+            let results = Arc::new(Mutex::new(Vec::new()));
+            let results_of_callback = results.clone();
+            map.compact_in_background(move |result| results_of_callback.lock().unwrap().push(result.is_ok()))?;
+            assert!(map.is_compacting());
+            assert!(matches!(map.compact(), Err(CompactError::InProgress)));
+            assert!(matches!(map.compact_in_background(|_| {}), Err(CompactError::InProgress)));
+
+            // changes made while compaction runs are in the new file
+            map.remove(&0)?;
+            map.insert(1, -1)?;
+            map.insert(100, 100)?;
+            map.wait_compaction();
+            assert!(!map.is_compacting());
+            assert_eq!(*results.lock().unwrap(), vec![true]);
+            assert_eq!(map.ops_logged(), 100 + 3);
+            assert_eq!(std::fs::read_to_string(&file)?.lines().count(), 100 + 3);
+
+            // next changes are appended to the compacted file with continued chain of integrity
+            map.insert(2, -2)?;
+            assert!(!map.poll_compaction());
+            let expected = map.map().clone();
+            drop(map);
+
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            assert_eq!(map.get(&0), None);
+            assert_eq!(map.get(&1), Some(&-1));
+            drop(map);
+
+            // dropped map discards compaction
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            map.compact_in_background(|_| {})?;
+            drop(map);
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            drop(map);
+            let dir = std::path::Path::new(&file).parent().unwrap();
+            let file_name = std::path::Path::new(&file).file_name().unwrap().to_str().unwrap().to_string();
+            assert!(!std::fs::read_dir(dir)?.any(|entry| entry.unwrap().file_name().to_str().unwrap().starts_with(&format!("{}.", file_name))));
+        }
+
+        // before write callback can't be called from the thread of compaction
+        let file = tmp_file()?;
+        let mut map = BTreeMap::<i32, i32>::open_or_create(&file, Cfg { format: Format::Text(Some(Box::new(|_| {})), None), ..Cfg::default() })?;
+        assert!(matches!(map.compact_in_background(|_| {}), Err(CompactError::BackgroundNotSupported)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn history() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let cfg = || Cfg { integrity: Some(Integrity::Sha256Chain([2; 32])), ..Cfg::default() };
+        let mut map = BTreeMap::open_or_create(&file, cfg())?;
+        map.insert(1, "a".to_string())?;
+        map.try_extend(vec![(2, "b".to_string()), (3, "c".to_string())])?;
+        map.remove(&1)?;
+        drop(map);
+
+        let operations = BTreeMap::<i32, String>::history(&file, cfg())?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(operations, vec![
+            MapOperation::Insert(1, "a".to_string()),
+            MapOperation::Insert(2, "b".to_string()),
+            MapOperation::Insert(3, "c".to_string()),
+            MapOperation::Remove(1),
+        ]);
+
+        // iteration can be stopped
+        let mut history = BTreeMap::<i32, String>::history(&file, cfg())?;
+        assert!(matches!(history.next(), Some(Ok(MapOperation::Insert(1, _)))));
+        drop(history);
+
+        // integrity error is the last item
+        let content = std::fs::read_to_string(&file)?.replacen("\"b\"", "\"x\"", 1);
+        std::fs::write(&file, content)?;
+        let history = BTreeMap::<i32, String>::history(&file, cfg())?.collect::<Vec<_>>();
+        assert_eq!(history.len(), 2);
+        assert!(matches!(&history[1], Err(LoadFileError::AtRecord { path: Some(path), line_num: 2, err, .. })
+            if path.to_str() == Some(file.as_str()) && matches!(**err, LoadFileError::IntegrityError(_))));
+
+        assert!(BTreeMap::<i32, String>::history(&format!("{}_missing", file), cfg()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_to() -> Result<(), Box<dyn std::error::Error>> {
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..100 {
+            map.insert(i % 10, i.to_string())?;
+        }
+        map.remove(&0)?;
+        map.flush()?;
+        let content = std::fs::read_to_string(&file)?;
+
+        // copy is written in other format to created directories, the file of the map is not changed
+        let dir = format!("{}_copy", file);
+        let copy_path = format!("{}/backup/map.bin", dir);
+        let copy_cfg = || Cfg { format: Format::Bin(None, None), integrity: Some(Integrity::Sha256Chain([1; 32])), ..Cfg::default() };
+        map.compact_to(&copy_path, copy_cfg())?;
+        assert_eq!(std::fs::read_to_string(&file)?, content);
+        map.insert(100, "100".to_string())?;
+        let expected = map.map().clone();
+
+        // existing copy is replaced and unlocked after writing
+        map.compact_to(&copy_path, copy_cfg())?;
+        let copy = BTreeMap::<i32, String>::open_or_create(&copy_path, copy_cfg())?;
+        assert_eq!(*copy.map(), expected);
+        assert_eq!(copy.ops_logged(), expected.len() as u64);
+        drop(copy);
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(*map.map(), expected);
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_checkpoint() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::SnapshotCfg;
+        use crate::snapshot::{snapshot_path, CheckpointError};
+
+        for bin in [false, true] {
+            let file = tmp_file()?;
+            let snapshot_file = snapshot_path(&file);
+            let cfg = || Cfg { integrity: Some(Integrity::Sha256Chain([3; 32])), format: if bin { Format::Bin(None, None) } else { Format::Text(None, None) }, snapshot: Some(SnapshotCfg::default()), ..Cfg::default() };
+            let mut map = BTreeMap::open_or_create(&file, cfg())?;
+            for i in 0..100 {
+                map.insert(i % 10, i)?;
+            }
+            map.remove(&0)?;
+            map.flush()?;
+            let log_before_checkpoint = std::fs::read(&file)?;
+
+            map.checkpoint()?;
+            assert_eq!(std::fs::metadata(&file)?.len(), 0);
+            assert_eq!(map.ops_logged(), 0);
+            map.insert(10, 10)?;
+            map.remove(&1)?;
+            let expected = map.map().clone();
+            drop(map);
+
+            // the snapshot is loaded and only the log is replayed
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            assert_eq!(map.ops_logged(), 2);
+            drop(map);
+
+            // crash after renaming of the snapshot before clearing of the log, operations of the log are skipped
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            map.checkpoint()?;
+            drop(map);
+            let mut snapshot = std::fs::read(&snapshot_file)?;
+            snapshot[..30].copy_from_slice(format!("snapshot {:020}\n", log_before_checkpoint.len()).as_bytes());
+            std::fs::write(&snapshot_file, &snapshot)?;
+            std::fs::write(&file, &log_before_checkpoint)?;
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            assert_eq!(map.ops_logged(), 0);
+            // appended after skipped operations with continued chain of integrity
+            map.insert(11, 11)?;
+            drop(map);
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(map.get(&11), Some(&11));
+            assert_eq!(map.map().len(), expected.len() + 1);
+            drop(map);
+
+            // crash after clearing of the log before clearing of the header
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            map.checkpoint()?;
+            let expected = map.map().clone();
+            drop(map);
+            let mut snapshot = std::fs::read(&snapshot_file)?;
+            snapshot[..30].copy_from_slice(format!("snapshot {:020}\n", 1000).as_bytes());
+            std::fs::write(&snapshot_file, &snapshot)?;
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            assert!(std::fs::read(&snapshot_file)?.starts_with(format!("snapshot {:020}\n", 0).as_bytes()));
+            map.remove(&11)?;
+            drop(map);
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(map.get(&11), None);
+            drop(map);
+
+            // compaction is checkpoint with the snapshot, removed keys of the snapshot are not restored
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            map.remove(&2)?;
+            map.compact()?;
+            let expected = map.map().clone();
+            drop(map);
+            let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+            assert_eq!(*map.map(), expected);
+            assert_eq!(map.get(&2), None);
+            drop(map);
+
+            // without config of the snapshot the log is not cleared
+            let mut map = BTreeMap::<i32, i32>::open_or_create(&file, Cfg { snapshot: None, ..cfg() })?;
+            assert!(matches!(map.checkpoint(), Err(CheckpointError::NoSnapshotCfg)));
+            drop(map);
+
+            std::fs::remove_file(&snapshot_file)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_checkpoint() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::SnapshotCfg;
+
+        let file = tmp_file()?;
+        let cfg = || Cfg { snapshot: Some(SnapshotCfg { checkpoint_ops: Some(50) }), ..Cfg::default() };
+        let mut map = BTreeMap::open_or_create(&file, cfg())?;
+        for i in 0..1000 {
+            map.insert(i % 10, i)?;
+            assert!(map.ops_logged() < 50);
+        }
+        map.remove(&0)?;
+        assert!(map.take_last_write_error().is_none());
+        let expected = map.map().clone();
+        drop(map);
+
+        let map = BTreeMap::<i32, i32>::open_or_create(&file, cfg())?;
+        assert_eq!(*map.map(), expected);
+        assert!(std::fs::read_to_string(&file)?.lines().count() < 50);
+        std::fs::remove_file(crate::snapshot::snapshot_path(&file))?;
+
+        assert!(matches!(Cfg::builder().snapshot(SnapshotCfg { checkpoint_ops: Some(0) }).build(), Err(crate::cfg_builder::CfgError::ZeroCheckpointOps)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn close_map() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::WriteErrorContext;
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{FailingWriter, SharedBuffer};
+        use std::sync::{Arc, Mutex};
+
+        // all writes are done when dropped
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        for i in 0..10000 {
+            map.insert(i, i.to_string())?;
+        }
+        drop(map);
+        let content = std::fs::read_to_string(&file)?;
+        assert_eq!(content.lines().count(), 10000);
+        assert_eq!(content.lines().last(), Some("ins [9999,\"9999\"]"));
+
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        map.remove(&0)?;
+        map.close()?;
+        assert_eq!(std::fs::read_to_string(&file)?.lines().count(), 10001);
+
+        // error of the last write is returned by close
+        let contexts = Arc::new(Mutex::new(Vec::new()));
+        let callback_contexts = contexts.clone();
+        let mut cfg = Cfg {
+            write_error_context_callback: Some(Box::new(move |context: WriteErrorContext| callback_contexts.lock().unwrap().push(context.error.to_string()))),
+            ..Cfg::default()
+        };
+        let mut map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        map.file_worker = FileWorker::new(FailingWriter::fail_every(SharedBuffer::new(), 1), FileWorkerCfg::take_from(&mut cfg, file.clone().into(), 0));
+        map.insert(1, "1".to_string())?;
+        assert_eq!(map.close().map_err(|err| err.to_string()), Err("injected write failure".to_string()));
+        assert_eq!(*contexts.lock().unwrap(), vec!["injected write failure".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_pending_writes() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::WriteOperation;
+        use crate::file_worker::{FileWorker, FileWorkerCfg};
+        use crate::testing::{SharedBuffer, SlowWriter};
+        use std::path::PathBuf;
+        use std::time::{Duration, Instant};
+
+        for write_channel in write_channels() {
+            // the first write is in the thread and the second in the queue, so the third waits of writing of the first
+            let mut cfg = Cfg { max_pending_writes: Some(1), ..channel_cfg(write_channel) };
+            let file_worker = FileWorker::new(SlowWriter::new(SharedBuffer::new(), Duration::from_millis(20)), FileWorkerCfg::take_from(&mut cfg, PathBuf::from("db/map.txt"), 0));
+            let start = Instant::now();
+            for _ in 0..5 {
+                file_worker.write_bytes(vec![0; 4], WriteOperation::Insert)?;
+            }
+            assert!(start.elapsed() >= Duration::from_millis(20));
+            drop(file_worker);
+
+            let file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&file, Cfg { max_pending_writes: Some(2), ..channel_cfg(write_channel) })?;
+            for i in 0..1000 {
+                map.insert(i, i)?;
+                // queued, being written by one write and waiting of place in the queue
+                assert!(map.pending_writes() <= 5);
+            }
+            map.flush()?;
+            assert_eq!(map.pending_writes(), 0);
+            drop(map);
+            assert_eq!(BTreeMap::<i32, i32>::open_or_create(&file, Cfg::default())?.map().len(), 1000);
+        }
+
+        assert!(matches!(Cfg::builder().max_pending_writes(0).build(), Err(crate::cfg_builder::CfgError::ZeroMaxPendingWrites)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn coalesced_writes() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::cfg::WriteAck;
+        use crate::log_writer::LogWriter;
+        use std::sync::{Arc, Mutex};
+
+        for write_channel in write_channels() {
+            let cfg = || Cfg { integrity: Some(Integrity::Sha256Chain([0; 32])), ..channel_cfg(write_channel) };
+            let acks = Arc::new(Mutex::new(Vec::new()));
+            let callback_acks = acks.clone();
+            let map_file = tmp_file()?;
+            let mut map = BTreeMap::open_or_create(&map_file, Cfg {
+                write_ack_callback: Some(Box::new(move |ack: WriteAck| callback_acks.lock().unwrap().push(ack))),
+                ..cfg()
+            })?;
+            let writer_file = tmp_file()?;
+            let mut writer = LogWriter::create(&writer_file, cfg())?;
+            for i in 0..100_000 {
+                map.insert(i, i.to_string())?;
+                writer.insert(&i, &i.to_string())?;
+            }
+            map.close()?;
+            writer.finish()?;
+
+            // records and integrity chain are the same as written one by one
+            assert_eq!(std::fs::read(&map_file)?, std::fs::read(&writer_file)?);
+
+            // acks are for each operation with offsets of its record
+            let acks = acks.lock().unwrap();
+            assert_eq!(acks.len(), 100_000);
+            let mut offset = 0;
+            for (i, ack) in acks.iter().enumerate() {
+                assert_eq!((ack.sequence, ack.offset), (i as u64 + 1, offset));
+                offset += ack.bytes as u64;
+            }
+            assert_eq!(offset, std::fs::metadata(&map_file)?.len());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_truncate_at_error() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::open_report::OpenWarning;
+        use crate::Integrity;
+
+        for text in [true, false] {
+            for integrity in [None, Some(Integrity::Crc32), Some(Integrity::Sha256Chain([0; 32]))] {
+                let make_cfg = |recovery| Cfg {
+                    format: if text { Format::Text(None, None) } else { Format::Bin(None, None) },
+                    integrity: integrity.clone(),
+                    recovery,
+                    ..Cfg::default()
+                };
+
+                let file = tmp_file()?;
+                let mut map = BTreeMap::open_or_create(&file, make_cfg(RecoveryMode::Fail))?;
+                for i in 0..3 {
+                    map.insert(i, i.to_string())?;
+                }
+                drop(map);
+                let valid_len = std::fs::metadata(&file)?.len();
+
+                // last record written partially
+                let partial: &[u8] = if text { b"ins [3,\"" } else { &[0, 20, 1, 2] };
+                std::fs::OpenOptions::new().append(true).open(&file)?.write_all(partial)?;
+
+                assert!(BTreeMap::<i32, String>::open_or_create(&file, make_cfg(RecoveryMode::Fail)).is_err());
+
+                let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(RecoveryMode::TruncateAtError))?;
+                assert_eq!(map.map().len(), 3);
+                assert!(matches!(map.open_warnings(), [OpenWarning::TruncatedTail { len, dropped_bytes }] if *len == valid_len && *dropped_bytes == partial.len() as u64));
+                assert_eq!(std::fs::metadata(&file)?.len(), valid_len);
+                map.insert(3, "3".to_string())?;
+                drop(map);
+
+                let mut map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(RecoveryMode::Fail))?;
+                assert_eq!(map.get(&3), Some(&"3".to_string()));
+                assert!(map.open_warnings().is_empty());
+                drop(map);
+
+                if integrity.is_none() {
+                    continue;
+                }
+
+                // corruption not at the end is not truncated
+                let mut data = std::fs::read(&file)?;
+                data[5] ^= 1;
+                std::fs::write(&file, &data)?;
+                let result = BTreeMap::<i32, String>::open_or_create(&file, make_cfg(RecoveryMode::TruncateAtError));
+                assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(_))));
+                assert_eq!(std::fs::read(&file)?, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_skip_bad_records() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::open_report::OpenWarning;
+        use crate::Integrity;
+
+        let file = tmp_file()?;
+        let cfg = |recovery| Cfg { integrity: Some(Integrity::Crc32), recovery, ..Cfg::default() };
+        let mut map = BTreeMap::open_or_create(&file, cfg(RecoveryMode::Fail))?;
+        for i in 0..4 {
+            map.insert(i, i.to_string())?;
+        }
+        drop(map);
+
+        // value of the second line is changed, so its checksum is wrong
+        let content = std::fs::read_to_string(&file)?;
+        let second_line_offset = content.find('\n').unwrap() as u64 + 1;
+        std::fs::write(&file, content.replacen("[1,\"1\"]", "[1,\"x\"]", 1))?;
+
+        let result = BTreeMap::<i32, String>::open_or_create(&file, cfg(RecoveryMode::Fail));
+        assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::Crc32Error { line_num: 2 }))));
+        let result = BTreeMap::<i32, String>::open_or_create(&file, cfg(RecoveryMode::TruncateAtError));
+        assert!(matches!(result.map_err(LoadFileError::into_inner), Err(LoadFileError::IntegrityError(IntegrityError::Crc32Error { line_num: 2 }))));
+
+        let (mut map, report) = BTreeMap::<i32, String>::open_or_create_with_report(&file, cfg(RecoveryMode::SkipBadRecords))?;
+        assert_eq!(map.map().keys().copied().collect::<Vec<_>>(), vec![0, 2, 3]);
+        assert!(matches!(report.warnings.as_slice(), [OpenWarning::SkippedBadRecord {
+            record_num: 2,
+            offset,
+            error: LoadFileError::IntegrityError(IntegrityError::Crc32Error { line_num: 2 }),
+        }] if *offset == second_line_offset));
+        map.insert(4, "4".to_string())?;
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, cfg(RecoveryMode::SkipBadRecords))?;
+        assert_eq!(map.map().len(), 4);
+        drop(map);
+
+        // chain continues from hash of skipped record
+        for text in [true, false] {
+            let file = tmp_file()?;
+            let cfg = |recovery| Cfg {
+                format: if text { Format::Text(None, None) } else { Format::Bin(None, None) },
+                integrity: Some(Integrity::Sha256Chain([0; 32])),
+                recovery,
+                ..Cfg::default()
+            };
+            let mut map = BTreeMap::open_or_create(&file, cfg(RecoveryMode::Fail))?;
+            map.insert(0, "a".repeat(100))?;
+            map.flush()?;
+            let middle_offset = std::fs::metadata(&file)?.len();
+            map.insert(1, "b".repeat(100))?;
+            map.insert(2, "c".repeat(100))?;
+            drop(map);
+
+            let mut data = std::fs::read(&file)?;
+            data[middle_offset as usize + 50] = b'x';
+            std::fs::write(&file, &data)?;
+
+            let mut map = BTreeMap::<i32, String>::open_or_create(&file, cfg(RecoveryMode::SkipBadRecords))?;
+            assert_eq!(map.map().keys().copied().collect::<Vec<_>>(), vec![0, 2]);
+            assert!(matches!(map.open_warnings(), [OpenWarning::SkippedBadRecord { record_num: 2, .. }]));
+            map.insert(3, "d".to_string())?;
+            drop(map);
+
+            let mut map = BTreeMap::<i32, String>::open_or_create(&file, cfg(RecoveryMode::SkipBadRecords))?;
+            assert_eq!(map.map().keys().copied().collect::<Vec<_>>(), vec![0, 2, 3]);
+            assert_eq!(map.open_warnings().len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn repair_file() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::repair::{quarantine_path, repair_file};
+        use crate::Integrity;
+
+        for text in [true, false] {
+            let file = tmp_file()?;
+            let cfg = || Cfg {
+                format: if text { Format::Text(None, None) } else { Format::Bin(None, None) },
+                integrity: Some(Integrity::Crc32),
+                ..Cfg::default()
+            };
+            let mut map = BTreeMap::open_or_create(&file, cfg())?;
+            map.insert(0, "a".repeat(10))?;
+            map.flush()?;
+            let valid_len = std::fs::metadata(&file)?.len();
+            for i in 1..4 {
+                map.insert(i, "b".repeat(10))?;
+            }
+            drop(map);
+
+            let outcome = repair_file::<i32, String>(&file, cfg(), true)?;
+            assert_eq!((outcome.records_kept, outcome.bytes_cut), (4, 0));
+            assert!(outcome.error.is_none() && outcome.quarantine_path.is_none());
+
+            // value of the second record is damaged
+            let mut data = std::fs::read(&file)?;
+            data[valid_len as usize + 8] = b'x';
+            std::fs::write(&file, &data)?;
+            assert!(BTreeMap::<i32, String>::open_or_create(&file, cfg()).is_err());
+
+            let outcome = repair_file::<i32, String>(&file, cfg(), true)?;
+            assert_eq!((outcome.records_kept, outcome.bytes_cut), (1, data.len() as u64 - valid_len));
+            assert!(matches!(outcome.error, Some(LoadFileError::IntegrityError(IntegrityError::Crc32Error { line_num: 2 }))));
+            assert_eq!(outcome.quarantine_path, Some(quarantine_path(&file)));
+            assert_eq!(std::fs::read(quarantine_path(&file))?, &data[valid_len as usize..]);
+            assert_eq!(std::fs::metadata(&file)?.len(), valid_len);
+
+            let map = BTreeMap::<i32, String>::open_or_create(&file, cfg())?;
+            assert_eq!(map.map().len(), 1);
+
+            drop(map);
+            std::fs::remove_file(quarantine_path(&file))?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_error_position() -> Result<(), Box<dyn std::error::Error>> {
+        use std::error::Error;
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+        drop(map);
+        let offset = std::fs::metadata(&file)?.len();
+        std::fs::OpenOptions::new().append(true).open(&file)?.write_all(b"ins [3,x]\n")?;
+
+        let err = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default()).err().unwrap();
+        assert!(matches!(&err, LoadFileError::AtRecord { path: Some(path), line_num: 3, offset: err_offset, .. }
+            if path.to_str() == Some(file.as_str()) && *err_offset == offset));
+        assert_eq!(err.to_string(), format!("{}:3 (offset {}): invalid JSON", file, offset));
+        assert_eq!(err.line_num(), Some(3));
+        assert!(err.source().unwrap().is::<serde_json::Error>());
+
+        let err = err.into_inner();
+        assert!(matches!(err, LoadFileError::DeserializeJsonError { line_num: 3, .. }));
+        assert_eq!(err.to_string(), "record 3: invalid JSON");
+        assert!(err.source().unwrap().is::<serde_json::Error>());
+
+        assert_eq!(LoadFileError::from(IntegrityError::Crc32Error { line_num: 5 }).to_string(), "record 5: wrong crc32 checksum");
+        assert!(LoadFileError::from(IntegrityError::Crc32Error { line_num: 5 }).source().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_after_load_error() -> Result<(), Box<dyn std::error::Error>> {
+        use fs2::FileExt;
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, "a".to_string())?;
+        drop(map);
+        let len = std::fs::metadata(&file)?.len();
+        std::fs::OpenOptions::new().append(true).open(&file)?.write_all(b"ins [2,x]\n")?;
+
+        let res = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default());
+        assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::DeserializeJsonError { line_num: 2, .. })));
+        #[cfg(feature = "dashmap")]
+        {
+            let res = crate::concurrent_map::ConcurrentMapWithFile::<i32, String>::open_or_create(&file, Cfg::default());
+            assert!(matches!(res.map_err(LoadFileError::into_inner), Err(LoadFileError::DeserializeJsonError { line_num: 2, .. })));
+        }
+
+        // the file is not locked after the error, so it can be fixed and opened again by the same process
+        let fixed_file = std::fs::OpenOptions::new().write(true).open(&file)?;
+        fixed_file.try_lock_exclusive()?;
+        fixed_file.set_len(len)?;
+        fixed_file.unlock()?;
+        drop(fixed_file);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&1), Some(&"a".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_read_only() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::compaction::CompactError;
+        use fs2::FileExt;
+
+        let file = tmp_file()?;
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, "a".to_string())?;
+        map.insert(2, "b".to_string())?;
+
+        // the writer holds exclusive lock, so read-only opening fails without waiting
+        let res = BTreeMap::<i32, String>::open_read_only(&file, Cfg::default());
+        assert!(matches!(res, Err(LoadFileError::LockError(ref err)) if err.kind() == std::io::ErrorKind::WouldBlock));
+        drop(map);
+
+        let mut reader = BTreeMap::<i32, String>::open_read_only(&file, Cfg::default())?;
+        let other_reader = BTreeMap::<i32, String>::open_read_only(&file, Cfg::default())?;
+        assert_eq!(reader.get(&1), Some(&"a".to_string()));
+        assert_eq!(other_reader.map().len(), 2);
+
+        // readers hold shared lock, so exclusive lock of the writer can't be taken
+        let writer_file = std::fs::OpenOptions::new().read(true).append(true).open(&file)?;
+        assert!(writer_file.try_lock_exclusive().is_err());
+
+        let file_len = std::fs::metadata(&file)?.len();
+        assert!(matches!(reader.insert(3, "c".to_string()), Err(SerializedError::ReadOnly)));
+        assert!(matches!(reader.remove(&1), Err(SerializedError::ReadOnly)));
+        assert!(matches!(reader.try_extend(vec![(4, "d".to_string())]), Err(SerializedError::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(CompactError::ReadOnly)));
+        reader.flush()?;
+        assert_eq!(reader.map().len(), 2);
+        assert_eq!(std::fs::metadata(&file)?.len(), file_len);
+
+        drop(reader);
+        assert!(writer_file.try_lock_exclusive().is_err());
+        drop(other_reader);
+        writer_file.try_lock_exclusive()?;
+        writer_file.unlock()?;
+
+        let missing_file = tmp_file()?;
+        let res = BTreeMap::<i32, String>::open_read_only(&missing_file, Cfg::default());
+        assert!(matches!(res, Err(LoadFileError::FileError(ref err)) if err.kind() == std::io::ErrorKind::NotFound));
+        assert!(!std::path::Path::new(&missing_file).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_and_create_new() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = format!("{}/{}", std::env::temp_dir().to_str().ok_or(TempDirError())?, Uuid::new_v4());
+        let file = format!("{}/map.txt", dir);
+
+        // missing file is not created by 'open', directories too
+        let res = BTreeMap::<i32, String>::open(&file, Cfg::default());
+        assert!(matches!(res, Err(LoadFileError::FileNotFound)));
+        assert!(!std::path::Path::new(&dir).exists());
+
+        let mut map = BTreeMap::create_new(&file, Cfg::default())?;
+        map.insert(1, "a".to_string())?;
+        drop(map);
+
+        let res = BTreeMap::<i32, String>::create_new(&file, Cfg::default());
+        assert!(matches!(res, Err(LoadFileError::FileAlreadyExists)));
+
+        let mut map = BTreeMap::<i32, String>::open(&file, Cfg::default())?;
+        assert_eq!(map.get(&1), Some(&"a".to_string()));
+        map.insert(2, "b".to_string())?;
+        drop(map);
+
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.map().len(), 2);
+        drop(map);
+
+        let missing_file = format!("{}/other/map.txt", dir);
+        let map = BTreeMap::<i32, String>::open_or_create(&missing_file, Cfg::default())?;
+        assert!(map.map().is_empty());
+        drop(map);
+        assert!(std::path::Path::new(&missing_file).exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn open_with_path() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+
+        // nested directories are created
+        let file = dir.join("a").join("b").join("map.txt");
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, "a".to_string())?;
+        drop(map);
+        let map = BTreeMap::<i32, String>::open_or_create(file.as_path(), Cfg::default())?;
+        assert_eq!(map.get(&1), Some(&"a".to_string()));
+        drop(map);
+
+        // separator on Windows, part of the file name on other systems, the map is opened on both
+        let file = dir.join("c\\map.txt");
+        let mut map = BTreeMap::open_or_create(file.clone(), Cfg::default())?;
+        map.insert(2, "b".to_string())?;
+        drop(map);
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&2), Some(&"b".to_string()));
+        drop(map);
+        std::fs::remove_dir_all(&dir)?;
+
+        // file name without parent directory, nothing to create
+        let file = format!("{}.txt", Uuid::new_v4());
+        crate::format::create_dirs_to_path_if_not_exist(std::path::Path::new(&file))?;
+        let mut map = BTreeMap::open_or_create(file.clone(), Cfg::default())?;
+        map.insert(3, "c".to_string())?;
+        drop(map);
+        let map = BTreeMap::<i32, String>::open_or_create(&file, Cfg::default())?;
+        assert_eq!(map.get(&3), Some(&"c".to_string()));
+        drop(map);
+        std::fs::remove_file(&file)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_dirs_to_path() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::format::create_dirs_to_path_if_not_exist;
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir)?;
+
+        // backslash is separator on Windows only
+        let file = dir.join("db\\users\\map.txt");
+        create_dirs_to_path_if_not_exist(&file)?;
+        if cfg!(windows) {
+            assert!(dir.join("db").join("users").is_dir());
+        } else {
+            assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+        }
+        let mut map = BTreeMap::open_or_create(&file, Cfg::default())?;
+        map.insert(1, 1)?;
+        drop(map);
+        assert!(file.is_file());
+
+        // parent or other ancestor exists but it's a file
+        let not_dir = dir.join("not_dir");
+        std::fs::write(&not_dir, "")?;
+        for file in &[not_dir.join("map.txt"), not_dir.join("a").join("b").join("map.txt")] {
+            let err = create_dirs_to_path_if_not_exist(file).err().ok_or("dirs are created over the file")?;
+            assert_eq!(err.kind(), std::io::ErrorKind::NotADirectory);
+            assert!(err.to_string().contains(&*not_dir.to_string_lossy()));
+
+            match BTreeMap::<i32, i32>::open_or_create(file, Cfg::default()) {
+                Err(LoadFileError::FileError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotADirectory),
+                res => panic!("unexpected result {:?}", res.map(|_| ())),
+            }
+        }
+        assert!(not_dir.is_file());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        for (text, write_mode) in [(true, WriteMode::Background), (false, WriteMode::Sync)] {
+            let make_cfg = || Cfg {
+                format: if text { Format::Text(None, None) } else { Format::Bin(None, None) },
+                write_mode,
+                integrity: Some(Integrity::Sha256Chain([7; 32])),
+                ..Cfg::default()
+            };
+
+            // the same data as in the file
+            let file = dir.join(Uuid::new_v4().to_string());
+            let mut file_map = BTreeMap::open_or_create(&file, make_cfg())?;
+            let mut memory_map = BTreeMap::in_memory(make_cfg())?;
+            for map in [&mut file_map, &mut memory_map] {
+                map.create_btree_index(|value: &String| value.len());
+                map.insert(1, "a".to_string())?;
+                map.insert(2, "bb".to_string())?;
+                map.insert(3, "cc".to_string())?;
+                map.remove(&1)?;
+            }
+            assert_eq!(memory_map.map(), file_map.map());
+            let data = memory_map.bytes()?;
+            assert!(!data.is_empty());
+            assert_eq!(data, file_map.bytes()?);
+            assert_eq!(data, std::fs::read(&file)?);
+            drop(file_map);
+
+            // data of the map is loaded from the file
+            let mut memory_map = BTreeMap::in_memory(make_cfg())?;
+            let index = memory_map.create_btree_index(|value: &String| value.len());
+            memory_map.try_extend(vec![(2, "bb".to_string()), (3, "cc".to_string())])?;
+            assert_eq!(index.get(&2), vec![2, 3]);
+            memory_map.insert(4, "d".to_string())?;
+            memory_map.compact()?;
+            assert!(matches!(memory_map.compact_in_background(|_| {}), Err(crate::compaction::CompactError::BackgroundNotSupported)));
+            memory_map.insert(5, "e".to_string())?;
+            let expected = memory_map.map().clone();
+            std::fs::write(&file, memory_map.into_bytes()?)?;
+            let map = BTreeMap::<i32, String>::open_or_create(&file, make_cfg())?;
+            assert_eq!(map.map(), &expected);
+        }
+        std::fs::remove_dir_all(&dir)?;
+
+        // nothing is written to the file system
+        let map = BTreeMap::<i32, i32>::in_memory(Cfg::default())?;
+        assert!(map.bytes()?.is_empty());
+        assert!(map.into_bytes()?.is_empty());
+
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct TempDirError();
+
+    /// All implementations of the channel to the writing thread for running the same test with each.
+    fn write_channels() -> Vec<WriteChannel> {
+        vec![
+            WriteChannel::Std,
+            #[cfg(feature = "crossbeam")]
+            WriteChannel::Crossbeam,
+        ]
+    }
+
+    fn channel_cfg(write_channel: WriteChannel) -> Cfg {
+        Cfg { write_channel, ..Cfg::default() }
+    }
+
+    thread_local! {
+        /// Count of clones of 'CountingKey' in the current thread, so tests running in parallel don't affect each other.
+        static KEY_CLONES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// Key that counts its clones for checking that keys are shared instead of being cloned.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+    struct CountingKey(i32);
+
+    impl Clone for CountingKey {
+        fn clone(&self) -> Self {
+            KEY_CLONES.with(|clones| clones.set(clones.get() + 1));
+            CountingKey(self.0)
+        }
+    }
+
+    /// Count of clones of 'CountingKey' made in the current thread.
+    fn key_clones() -> usize {
+        KEY_CLONES.with(|clones| clones.get())
+    }
 
     fn tmp_file() -> Result<String, TempDirError> {
         let tempdir = std::env::temp_dir()
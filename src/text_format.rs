@@ -1,10 +1,17 @@
-use crate::format::{MapOperation, blockchain_sha1, blockchain_sha256, IntegrityError};
+use crate::format::{ItemOperation, LoadedOperation, MapOperation, MetaOperation, RawMeta, SetOperation, blockchain_sha1, blockchain_sha256, blockchain_hmac_sha256, IntegrityError, LoadedTail, TransactionBuffer, TransactionMarker, check_load_cancel, catch_callback_panic, with_record_meta, LoadLimits, LoadStats, RecordExtent, RecordKind, LoadRecovery, Recovered, RecoveredRecords, recover_record};
+#[cfg(feature = "blake3")]
+use crate::format::blockchain_blake3;
 use crate::map_trait::MapTrait;
-use serde::de::DeserializeOwned;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use crate::{LoadFileError, Integrity};
+use crate::cfg::{version_for_migration, Cfg, IntegrityKind, MigrationError, RawValue, ValueMigrator};
+use crate::replay_check::ReplayChecker;
+use crate::value_compression::{decompress_text_value, text_may_contain_compressed_value, text_value_json};
 use serde::Serialize;
 use std::io::{BufReader, BufRead};
 use crc::crc32;
+use crypto::util::fixed_time_eq;
+use xxhash_rust::xxh64::xxh64;
 
 /// Make line with insert operation for write to file.
 pub fn text_file_line_of_insert<Key, Value>(key: &Key, value: Value, integrity: &mut Option<Integrity>)
@@ -13,8 +20,28 @@ where
     Key: Serialize,
     Value: Serialize
 {
-    let key_val_json = serde_json::to_string(&(&key, &value))?;
-    let mut line = "ins ".to_string() + &key_val_json;
+    text_file_line_of_versioned_insert(key, value, None, integrity)
+}
+
+/// Make line with insert operation for write to file.
+/// If 'value_schema_version' is set, then line starts with "insV{version} ".
+pub fn text_file_line_of_versioned_insert<Key, Value>(key: &Key, value: Value, value_schema_version: Option<u32>, integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    text_file_line_of_compressible_insert(key, value, value_schema_version, None, integrity)
+}
+
+/// Same as 'text_file_line_of_versioned_insert' but value longer than 'compress_values_over' is compressed.
+pub(crate) fn text_file_line_of_compressible_insert<Key, Value>(key: &Key, value: Value, value_schema_version: Option<u32>, compress_values_over: Option<usize>, integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let mut line = text_line_data_of_insert(key, value, value_schema_version, compress_values_over)?;
     post_process_text_file_line(&mut line, integrity);
     Ok(line)
 }
@@ -25,12 +52,146 @@ pub fn file_line_of_remove<Key>(key: &Key, integrity: &mut Option<Integrity>)
 where
     Key: Serialize
 {
-    let key_json = serde_json::to_string(key)?;
-    let mut line = "rem ".to_string() + &key_json;
+    let mut line = text_line_data_of_remove(key)?;
+    post_process_text_file_line(&mut line, integrity);
+    Ok(line)
+}
+
+/// Make line with batch of operations for write to file.
+/// Line starts with "bat " followed by JSON array of operations as ["ins",[key,value]] or ["rem",key],
+/// if 'value_schema_version' is set, then name of insert operation is "insV{version}".
+/// Values longer than 'compress_values_over' are compressed.
+pub(crate) fn text_file_line_of_batch<Key, Value>(operations: &[MapOperation<Key, Value>], value_schema_version: Option<u32>, compress_values_over: Option<usize>, integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let mut line = "bat [".to_string();
+    for (i, map_operation) in operations.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        match map_operation {
+            MapOperation::Insert(key, value) => {
+                let name = match value_schema_version {
+                    Some(version) => format!("insV{}", version),
+                    None => "ins".to_string(),
+                };
+                line += &format!("[\"{}\",[{},{}]]", name, serde_json::to_string(key)?, text_value_json(value, compress_values_over)?);
+            },
+            MapOperation::Remove(key) => {
+                line += &format!("[\"rem\",{}]", serde_json::to_string(key)?);
+            },
+        }
+    }
+    line.push(']');
+    post_process_text_file_line(&mut line, integrity);
+    Ok(line)
+}
+
+/// Make line with transaction marker for write to file.
+pub(crate) fn text_file_line_of_transaction_marker(marker: TransactionMarker, integrity: &mut Option<Integrity>) -> String {
+    let mut line = match marker {
+        TransactionMarker::Begin => "txb",
+        TransactionMarker::End => "txe",
+        TransactionMarker::Abort => "txa",
+    }.to_string();
+    post_process_text_file_line(&mut line, integrity);
+    line
+}
+
+/// Make line with operation with items of collection value for write to file.
+/// Line starts with "psh " or "rmi " followed by JSON array [key,[items]].
+pub(crate) fn text_file_line_of_item_operation<Key, Item>(item_operation: ItemOperation, key: &Key, items: &[Item], integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize,
+    Item: Serialize
+{
+    let mut line = match item_operation {
+        ItemOperation::Push => "psh ",
+        ItemOperation::Remove => "rmi ",
+    }.to_string();
+    line += &serde_json::to_string(&(key, items))?;
+    post_process_text_file_line(&mut line, integrity);
+    Ok(line)
+}
+
+/// Make line with operation with element of set for write to file.
+/// Line starts with "add " or "del " followed by JSON of element.
+pub(crate) fn text_file_line_of_set_operation<Key>(set_operation: SetOperation, key: &Key, integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize
+{
+    let mut line = match set_operation {
+        SetOperation::Add => "add ",
+        SetOperation::Delete => "del ",
+    }.to_string();
+    line += &serde_json::to_string(key)?;
+    post_process_text_file_line(&mut line, integrity);
+    Ok(line)
+}
+
+/// Make line with increment of counter value for write to file.
+/// Line is "inc " followed by JSON of key, space and delta.
+pub(crate) fn text_file_line_of_increment<Key>(key: &Key, delta: i64, integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize
+{
+    let mut line = format!("inc {} {}", serde_json::to_string(key)?, delta);
+    post_process_text_file_line(&mut line, integrity);
+    Ok(line)
+}
+
+/// Make line with schema fingerprint for write to file.
+/// Line is "sch " followed by JSON string of fingerprint.
+pub(crate) fn text_file_line_of_schema(schema_fingerprint: &str, integrity: &mut Option<Integrity>) -> String {
+    let mut line = "sch ".to_string() + &serde_json::Value::from(schema_fingerprint).to_string();
+    post_process_text_file_line(&mut line, integrity);
+    line
+}
+
+/// Make line with metadata of record for write to file.
+/// Line is "met " followed by JSON of metadata, space and data of insert or remove line without integrity.
+pub(crate) fn text_file_line_with_meta<Meta>(meta: &Meta, data: &str, integrity: &mut Option<Integrity>)
+    -> Result<String, serde_json::Error>
+where
+    Meta: Serialize
+{
+    let mut line = format!("met {} {}", serde_json::to_string(meta)?, data);
     post_process_text_file_line(&mut line, integrity);
     Ok(line)
 }
 
+/// Make line with insert operation without integrity and '\n'.
+/// Value longer than 'compress_values_over' is compressed.
+pub(crate) fn text_line_data_of_insert<Key, Value>(key: &Key, value: Value, value_schema_version: Option<u32>, compress_values_over: Option<usize>)
+    -> Result<String, serde_json::Error>
+where
+    Key: Serialize,
+    Value: Serialize
+{
+    let key_val_json = format!("[{},{}]", serde_json::to_string(key)?, text_value_json(&value, compress_values_over)?);
+    let mut line = match value_schema_version {
+        Some(version) => format!("insV{} ", version),
+        None => "ins ".to_string(),
+    };
+    line += &key_val_json;
+    Ok(line)
+}
+
+/// Make line with remove operation without integrity and '\n'.
+pub(crate) fn text_line_data_of_remove<Key>(key: &Key) -> Result<String, serde_json::Error>
+where
+    Key: Serialize
+{
+    let key_json = serde_json::to_string(key)?;
+    Ok("rem ".to_string() + &key_json)
+}
+
 /// Load from text format file all operations and make actual map.
 pub fn map_from_text_file<Map, Key, Value, ReadCallback, Reader>(
     file: &mut Reader,
@@ -41,7 +202,7 @@ pub fn map_from_text_file<Map, Key, Value, ReadCallback, Reader>(
         Key: std::cmp::Ord + DeserializeOwned,
         Value: DeserializeOwned,
         Map: MapTrait<Key, Value> + Default,
-        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error>>,
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
         Reader: std::io::Read,
 {
     let mut map = Map::default();
@@ -61,25 +222,385 @@ pub fn map_from_text_file<Map, Key, Value, ReadCallback, Reader>(
 pub fn load_from_text_file<Key, Value, ReadCallback, ProcessedCallback, Reader>(
     file: &mut Reader,
     integrity: &mut Option<Integrity>,
-    mut after_read_callback: Option<ReadCallback>,
-    mut processed_callback: ProcessedCallback
+    after_read_callback: Option<ReadCallback>,
+    processed_callback: ProcessedCallback
 ) -> Result<(), LoadFileError>
     where
         Key: DeserializeOwned,
         Value: DeserializeOwned,
         ProcessedCallback: FnMut(MapOperation<Key, Value>) -> Result<(), ()>,
-        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error>>,
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
         Reader: std::io::Read,
 {
-    let mut reader = BufReader::new(file);
-    let mut line = String::with_capacity(150);
-    let mut line_num = 1;
-    while reader.read_line(&mut line)? > 0 {
-        if let Some(callback) = &mut after_read_callback {
-            callback(&mut line)
+    load_from_text_file_migrating(file, integrity, after_read_callback, None, None, processed_callback)
+}
+
+/// Same as 'load_from_text_file' but values of records with version older than 'value_schema_version'
+/// are converted by 'value_migrator' before deserialization.
+pub fn load_from_text_file_migrating<Key, Value, ReadCallback, ProcessedCallback, Reader>(
+    file: &mut Reader,
+    integrity: &mut Option<Integrity>,
+    after_read_callback: Option<ReadCallback>,
+    value_schema_version: Option<u32>,
+    value_migrator: Option<&mut ValueMigrator>,
+    processed_callback: ProcessedCallback
+) -> Result<(), LoadFileError>
+    where
+        Key: DeserializeOwned,
+        Value: DeserializeOwned,
+        ProcessedCallback: FnMut(MapOperation<Key, Value>) -> Result<(), ()>,
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
+        Reader: std::io::Read,
+{
+    load_text_file_records(file, integrity, after_read_callback, value_schema_version, value_migrator, LoadLimits::default(), processed_callback)?;
+    Ok(())
+}
+
+/// Same as 'load_from_text_file' but metadata of records written by 'MapWithFile::insert_with_meta'
+/// and 'MapWithFile::remove_with_meta' is passed to 'processed_callback', None for records without metadata.
+pub fn load_from_text_file_with_meta<Key, Value, Meta, ReadCallback, ProcessedCallback, Reader>(
+    file: &mut Reader,
+    integrity: &mut Option<Integrity>,
+    after_read_callback: Option<ReadCallback>,
+    mut processed_callback: ProcessedCallback
+) -> Result<(), LoadFileError>
+    where
+        Key: DeserializeOwned,
+        Value: DeserializeOwned,
+        Meta: DeserializeOwned,
+        ProcessedCallback: FnMut(MapOperation<Key, Value>, Option<Meta>) -> Result<(), ()>,
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
+        Reader: std::io::Read,
+{
+    load_text_file_records(file, integrity, after_read_callback, None, None, LoadLimits::default(), |operation: MetaOperation<Key, Value, Meta>| {
+        processed_callback(operation.map_operation, operation.meta)
+    })?;
+    Ok(())
+}
+
+/// Same as 'load_from_text_file_migrating' but returns information about incomplete transaction at the end of the file.
+/// Records of transaction are passed to 'processed_callback' only after end marker of transaction.
+/// Loading stops with 'LoadFileError::Cancelled' when cancel flag of 'limits' is set
+/// and with 'LoadFileError::RecordTooLarge' on record longer than max record size of 'limits'.
+/// Item records ("psh", "rmi") and set records ("add", "del") are passed only if 'Op' supports them,
+/// otherwise it's 'LoadFileError::UnexpectedItemOperation' or 'LoadFileError::UnexpectedSetOperation',
+/// same for increment records ("inc") and 'LoadFileError::UnexpectedIncrementOperation'.
+/// Schema record ("sch") is checked by 'limits' and not passed.
+pub(crate) fn load_text_file_records<Key, Value, Op, ReadCallback, ProcessedCallback, Reader>(
+    file: &mut Reader,
+    integrity: &mut Option<Integrity>,
+    mut after_read_callback: Option<ReadCallback>,
+    value_schema_version: Option<u32>,
+    mut value_migrator: Option<&mut ValueMigrator>,
+    mut limits: LoadLimits,
+    mut processed_callback: ProcessedCallback
+) -> Result<LoadedTail, LoadFileError>
+    where
+        Key: DeserializeOwned,
+        Value: DeserializeOwned,
+        Op: LoadedOperation<Key, Value>,
+        ProcessedCallback: FnMut(Op) -> Result<(), ()>,
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
+        Reader: std::io::Read,
+{
+    let mut reader = TextRecordReader::new(file);
+    let mut transaction = TransactionBuffer::new();
+    let mut replay = limits.replay_check.map(ReplayChecker::new);
+    let mut load_stats = LoadStats::default();
+    let mut recovered = RecoveredRecords::default();
+    let mut recovery = LoadRecovery::new(&limits, integrity);
+    loop {
+        let (record_num, record_offset) = reader.position();
+        // record is loaded by closure for recovery from its error by 'LoadLimits::recovery'
+        let loaded = (|| -> Result<bool, LoadFileError> {
+            let record = match reader.next_record(integrity, &mut after_read_callback, &limits)? {
+                Some(record) => record,
+                None => return Ok(false),
+            };
+            let TextRecord { extent, integrity_before_marker, meta, data: line_data, integrity_len } = record;
+            let line_num = extent.num;
+            limits.set_record_end(&extent);
+
+            let kind = text_record_kind(line_data).ok_or_else(|| unknown_text_operation(line_data, line_num))?;
+            let key = || text_record_kind_and_key::<serde_json::Value>(line_data, line_num).ok()?.1.map(|key| key.to_string());
+            load_stats.add(&extent, kind, integrity_len, key);
+
+            if let Some(replay) = &mut replay {
+                replay_text_record(replay, line_data, meta.is_some(), line_num)?;
+            }
+
+            // data of all records except transaction markers is after name of operation with space, names have 3 letters
+            match kind {
+                RecordKind::TransactionBegin => transaction.marker(TransactionMarker::Begin, &integrity_before_marker, &mut processed_callback)?,
+                RecordKind::TransactionEnd => transaction.marker(TransactionMarker::End, &integrity_before_marker, &mut processed_callback)?,
+                RecordKind::TransactionAbort => transaction.marker(TransactionMarker::Abort, &integrity_before_marker, &mut processed_callback)?,
+                RecordKind::Insert => {
+                    let (record_version, data) = split_insert_version(line_data).ok_or(LoadFileError::NoLineDefinition { line_num })?;
+                    let map_operation = if text_may_contain_compressed_value(data) || (version_for_migration(record_version, value_schema_version).is_some() && value_migrator.is_some()) {
+                        // value is parsed to JSON before deserialization for decompression or migration
+                        serde_json::from_str(data)
+                            .map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })
+                            .and_then(|args| text_insert_operation(args, record_version, line_num, value_schema_version, &mut value_migrator))
+                    } else {
+                        serde_json::from_str(data)
+                            .map(|(key, val)| MapOperation::Insert(key, val))
+                            .map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })
+                    };
+                    let insert_key = || serde_json::from_str::<(Key, IgnoredAny)>(data).ok().map(|(key, _)| key);
+                    if let Some(map_operation) = recover_record::<Key, Value, Op>(map_operation, limits.deserialize_policy, insert_key, &mut recovered)? {
+                        transaction.push(with_record_meta(Op::from_map_operation(map_operation), meta, line_num)?, &mut processed_callback)?;
+                    }
+                },
+                RecordKind::Remove => {
+                    let map_operation = serde_json::from_str(&line_data[4..])
+                        .map(MapOperation::Remove)
+                        .map_err(|err| LoadFileError::DeserializeJsonError { err, line_num });
+                    if let Some(map_operation) = recover_record::<Key, Value, Op>(map_operation, limits.deserialize_policy, || None, &mut recovered)? {
+                        transaction.push(with_record_meta(Op::from_map_operation(map_operation), meta, line_num)?, &mut processed_callback)?;
+                    }
+                },
+                RecordKind::PushItems | RecordKind::RemoveItems => {
+                    let item_operation = if kind == RecordKind::PushItems { ItemOperation::Push } else { ItemOperation::Remove };
+                    let (key, items) = serde_json::from_str(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+                    let operation = Op::from_item_operation(item_operation, key, items).ok_or(LoadFileError::UnexpectedItemOperation { line_num })?;
+                    transaction.push(operation, &mut processed_callback)?;
+                },
+                RecordKind::Increment => {
+                    // key can contain spaces, delta is after the last one
+                    let data = line_data[4..].trim_end();
+                    let space_index = data.rfind(' ').ok_or(LoadFileError::NoLineDefinition { line_num })?;
+                    let key = serde_json::from_str(&data[..space_index]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+                    let delta = data[space_index + 1..].parse().map_err(|_| LoadFileError::NoLineDefinition { line_num })?;
+                    let operation = Op::from_increment(key, delta).ok_or(LoadFileError::UnexpectedIncrementOperation { line_num })?;
+                    transaction.push(operation, &mut processed_callback)?;
+                },
+                RecordKind::SetAdd | RecordKind::SetDelete => {
+                    let set_operation = if kind == RecordKind::SetAdd { SetOperation::Add } else { SetOperation::Delete };
+                    let key = serde_json::from_str(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+                    let operation = Op::from_set_operation(set_operation, key).ok_or(LoadFileError::UnexpectedSetOperation { line_num })?;
+                    transaction.push(operation, &mut processed_callback)?;
+                },
+                RecordKind::Schema => {
+                    let found: String = serde_json::from_str(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+                    limits.check_schema(&found)?;
+                },
+                RecordKind::Batch => {
+                    // all operations are deserialized before applying
+                    let batch = serde_json::from_str::<Vec<(String, serde_json::Value)>>(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+                    let mut operations = Vec::with_capacity(batch.len());
+                    for (name, args) in batch {
+                        operations.push(text_batch_operation(&name, args, line_num, value_schema_version, &mut value_migrator)?);
+                    }
+                    for map_operation in operations {
+                        transaction.push(Op::from_map_operation(map_operation), &mut processed_callback)?;
+                    }
+                },
+            }
+
+            transaction.record_end(extent.end());
+            Ok(true)
+        })();
+
+        match loaded {
+            Ok(true) => recovery.record_end(integrity),
+            Ok(false) => break,
+            Err(err) => {
+                let at_end = reader.at_end();
+                let recovered = recovery.recover(err, at_end, record_num, record_offset, integrity)
+                    .map_err(|err| limits.record_error(err, record_num, record_offset))?;
+                match recovered {
+                    Recovered::Skipped { resync_chain } => {
+                        if resync_chain {
+                            reader.resync_chain(integrity);
+                        }
+                        recovery.record_skipped(integrity);
+                    },
+                    Recovered::Truncated => break,
+                }
+            },
+        }
+
+        limits.report_progress(reader.position(), false)?;
+    }
+    limits.report_progress(reader.position(), true)?;
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(records = reader.line_num - 1, "text history file loaded");
+
+    let mut loaded_tail = transaction.finish();
+    loaded_tail.replay_anomalies = replay.map(ReplayChecker::into_anomalies).unwrap_or_default();
+    loaded_tail.load_stats = load_stats;
+    loaded_tail.recovered = recovered;
+    recovery.finish(&mut loaded_tail);
+    Ok(loaded_tail)
+}
+
+/// Check record by shadow state of the map for 'Cfg::strict_replay', keys and values are compared as JSON.
+fn replay_text_record(replay: &mut ReplayChecker, line_data: &str, has_meta: bool, line_num: usize) -> Result<(), LoadFileError> {
+    if let Some(marker) = text_transaction_marker(line_data) {
+        return replay.marker(marker, line_num);
+    }
+
+    let json = |data: &str| serde_json::from_str::<serde_json::Value>(data)
+        .map(|value| value.to_string().into_bytes())
+        .map_err(|err| LoadFileError::DeserializeJsonError { err, line_num });
+    let pair = |data: &str| serde_json::from_str::<(serde_json::Value, serde_json::Value)>(data)
+        .map(|(key, value)| (key.to_string().into_bytes(), value.to_string().into_bytes()))
+        .map_err(|err| LoadFileError::DeserializeJsonError { err, line_num });
+
+    match line_data.get(..4) {
+        Some("ins ") | Some("insV") => {
+            let (_, data) = split_insert_version(line_data).ok_or(LoadFileError::NoLineDefinition { line_num })?;
+            let (key, value) = pair(data)?;
+            if has_meta {
+                replay.apply(key, Some(Some(value)));
+            } else {
+                replay.insert(key, value, line_num)?;
+            }
+        },
+        Some("rem ") => replay.remove(json(&line_data[4..])?, line_num)?,
+        Some("psh ") | Some("rmi ") => replay.apply(pair(&line_data[4..])?.0, Some(None)),
+        Some("add ") => replay.apply(json(&line_data[4..])?, Some(None)),
+        Some("del ") => replay.apply(json(&line_data[4..])?, None),
+        Some("inc ") => {
+            let data = line_data[4..].trim_end();
+            let space_index = data.rfind(' ').ok_or(LoadFileError::NoLineDefinition { line_num })?;
+            replay.apply(json(&data[..space_index])?, Some(None));
+        },
+        Some("bat ") => {
+            let batch = serde_json::from_str::<Vec<(String, serde_json::Value)>>(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+            for (name, args) in batch {
+                match args {
+                    serde_json::Value::Array(pair) if name.starts_with("ins") && pair.len() == 2 => replay.apply(pair[0].to_string().into_bytes(), Some(Some(pair[1].to_string().into_bytes()))),
+                    key if name == "rem" => replay.apply(key.to_string().into_bytes(), None),
+                    _ => return Err(LoadFileError::WrongBatch { line_num }),
+                }
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+/// Line of text file read by 'TextRecordReader'.
+pub(crate) struct TextRecord<'a> {
+    /// Position of the line in the file.
+    pub extent: RecordExtent,
+    /// Integrity state before the line if it's transaction marker, needed if transaction is incomplete.
+    pub integrity_before_marker: Option<Integrity>,
+    /// Metadata of the record if line has it.
+    pub meta: Option<RawMeta<'a>>,
+    /// Data of the line without integrity and metadata.
+    pub data: &'a str,
+    /// Length of integrity of the line with separator.
+    pub integrity_len: u64,
+}
+
+/// Reads lines of text file one by one with checks of integrity, size and cancel flag.
+/// Used by all loading functions of the text format.
+pub(crate) struct TextRecordReader<Reader> {
+    /// Buffered file.
+    reader: BufReader<Reader>,
+    /// Buffer of the current line.
+    line: String,
+    /// Number of the next line, starts from 1.
+    line_num: usize,
+    /// Count of read bytes.
+    read_len: u64,
+    /// Data of the current line with canonical operation name if the line has alias of 'Cfg::extra_text_ops'.
+    canonical_data: String,
+}
+
+impl<Reader: std::io::Read> TextRecordReader<Reader> {
+    /// Reader from the start of the file.
+    pub fn new(file: Reader) -> Self {
+        TextRecordReader { reader: BufReader::new(file), line: String::with_capacity(150), line_num: 1, read_len: 0, canonical_data: String::new() }
+    }
+
+    /// Returns the next line or None at the end of file.
+    pub fn next_record<ReadCallback>(&mut self, integrity: &mut Option<Integrity>, after_read_callback: &mut Option<ReadCallback>, limits: &LoadLimits)
+        -> Result<Option<TextRecord<'_>>, LoadFileError>
+    where
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
+    {
+        let extent = match self.read_line(after_read_callback, limits)? {
+            Some(extent) => extent,
+            None => return Ok(None),
+        };
+        let line_num = extent.num;
+        let line = &self.line;
+
+        // integrity state before transaction begin is needed if transaction is incomplete,
+        // marker can be known only after replacing of alias
+        let integrity_before = if line.starts_with("tx") || limits.text_ops.has_aliases() { integrity.clone() } else { None };
+
+        let line_data = if let Some(integrity) = integrity {
+            process_line_integrity(line, integrity, line_num).map_err(|err| first_line_integrity_error(err, line, integrity, line_num))?
+        } else {
+            // checksum or hash at the end of the first line is mismatch of integrity configuration
+            if line_num == 1 {
+                if let Some(found) = text_line_integrity(line) {
+                    return Err(IntegrityError::FileHasUnexpectedHashSuffix { line_num, found }.into());
+                }
+            }
+            line
+        };
+
+        // line without integrity ends with '\n', it's checked by 'read_line'
+        let integrity_len = (line.len() - line_data.len()).saturating_sub(1) as u64;
+        let (meta, data) = split_text_record_meta(line_data, line_num)?;
+        let data = match limits.text_ops.canonical(data) {
+            Some(canonical_data) => {
+                self.canonical_data = canonical_data;
+                &self.canonical_data
+            },
+            None => data,
+        };
+        let integrity_before_marker = if data.starts_with("tx") { integrity_before } else { None };
+        Ok(Some(TextRecord { extent, integrity_before_marker, meta, data, integrity_len }))
+    }
+
+    /// Returns the next line with integrity and end of line without check of integrity, or None at the end of file.
+    /// Line is checked to be complete and not shorter than minimum.
+    pub fn next_line<ReadCallback>(&mut self, after_read_callback: &mut Option<ReadCallback>, limits: &LoadLimits)
+        -> Result<Option<(RecordExtent, &str)>, LoadFileError>
+    where
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
+    {
+        let extent = self.read_line(after_read_callback, limits)?;
+        let line = self.line.as_str();
+        Ok(extent.map(|extent| (extent, line)))
+    }
+
+    /// Read the next line to the buffer, returns None at the end of file.
+    fn read_line<ReadCallback>(&mut self, after_read_callback: &mut Option<ReadCallback>, limits: &LoadLimits)
+        -> Result<Option<RecordExtent>, LoadFileError>
+    where
+        ReadCallback: FnMut(&mut String) -> Result<(), Box<dyn std::error::Error + Send>>,
+    {
+        let line_num = self.line_num;
+        check_load_cancel(limits.cancel.as_deref(), line_num)?;
+
+        // line is read as bytes, so line with invalid UTF-8 is counted and can be skipped by 'RecoveryMode::SkipBadRecords'
+        let mut line = std::mem::take(&mut self.line).into_bytes();
+        line.clear();
+        let line_len = self.reader.read_until(b'\n', &mut line)?;
+        if line_len == 0 {
+            return Ok(None);
+        }
+        let extent = RecordExtent { num: line_num, offset: self.read_len, len: line_len as u64 };
+        self.read_len += line_len as u64;
+        self.line_num += 1;
+        self.line = String::from_utf8(line).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        limits.check_size(line_len, line_num)?;
+
+        if let Some(callback) = after_read_callback {
+            catch_callback_panic(|| callback(&mut self.line))
+                .ok_or(LoadFileError::CallbackPanicked { line_num })?
                 .map_err(|err| LoadFileError::InterruptedWithBeforeReadCallback(err))?;
         }
 
+        let line = &self.line;
         if !line.ends_with('\n') {
             return Err(LoadFileError::LastLineWithoutEndLine { line_num });
         }
@@ -89,31 +610,271 @@ pub fn load_from_text_file<Key, Value, ReadCallback, ProcessedCallback, Reader>(
             return Err(LoadFileError::FileLineLengthLessThenMinimum { line_num });
         }
 
-        let line_data = if let Some(integrity) = integrity {
-            process_line_integrity(&line, integrity, line_num)?
-        } else {
-            &line[..]
-        };
+        Ok(Some(extent))
+    }
 
-        match &line_data[..4] {
-            "ins " => {
-                let (key, val) = serde_json::from_str(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
-                processed_callback(MapOperation::Insert(key, val)).map_err(|()| LoadFileError::Interrupted)?;
-            },
-            "rem " => {
-                let key = serde_json::from_str(&line_data[4..]).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
-                processed_callback(MapOperation::Remove(key)).map_err(|()| LoadFileError::Interrupted)?;
-            },
-            _ => {
-                return Err(LoadFileError::NoLineDefinition { line_num });
-            }
+    /// Returns true if all bytes of the file are read, false on error of reading.
+    pub fn at_end(&mut self) -> bool {
+        self.reader.fill_buf().map(|buf| buf.is_empty()).unwrap_or(false)
+    }
+
+    /// Returns number of the next line and count of read bytes.
+    pub fn position(&self) -> (usize, u64) {
+        (self.line_num, self.read_len)
+    }
+
+    /// Continue chain of integrity from hash of the current line without check, used after skipped line
+    /// of 'RecoveryMode::SkipBadRecords', so the next line is checked by hash written with the skipped one.
+    pub fn resync_chain(&self, integrity: &mut Option<Integrity>) {
+        let hash = self.line.trim_end().rsplit(' ').next().and_then(|hash| hex::decode(hash).ok());
+        if let (Some(integrity), Some(hash)) = (integrity, hash) {
+            integrity.resync_chain(&hash);
         }
+    }
+}
 
-        line_num += 1;
-        line.clear();
+/// Returns kind of checksum or hash at the end of the line, None if there is no one.
+/// Checksums are checked, hashes of chain are recognized by length because the previous hash is unknown.
+pub(crate) fn text_line_integrity(line: &str) -> Option<IntegrityKind> {
+    let line = line.trim_end_matches('\n');
+    let data_index = line.rfind(' ').filter(|data_index| *data_index > 0)?;
+    let (line_data, hash_in_file) = (&line[..data_index], &line[data_index + 1..]);
+    match text_hash_kind(hash_in_file)? {
+        IntegrityKind::Crc32 => (crc32::checksum_ieee(line_data.as_bytes()).to_string() == hash_in_file).then_some(IntegrityKind::Crc32),
+        IntegrityKind::XxHash64 => (format!("{:016x}", xxh64(line_data.as_bytes(), 0)) == hash_in_file).then_some(IntegrityKind::XxHash64),
+        kind => Some(kind),
+    }
+}
+
+/// Returns kind of checksum or hash written as 'hash' by its length and digits, without check.
+fn text_hash_kind(hash: &str) -> Option<IntegrityKind> {
+    let is_hex = hash.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+    match hash.len() {
+        1..=10 if hash.bytes().all(|byte| byte.is_ascii_digit()) && hash.parse::<u32>().is_ok() => Some(IntegrityKind::Crc32),
+        16 if is_hex => Some(IntegrityKind::XxHash64),
+        40 if is_hex => Some(IntegrityKind::Sha1Chain),
+        64 if is_hex => Some(IntegrityKind::Hash256Chain),
+        _ => None,
     }
+}
 
-    Ok(())
+/// Error of integrity check of the line, mismatch of integrity configuration if it's the first line
+/// without checksum or hash of 'integrity' at the end, otherwise 'err'.
+fn first_line_integrity_error(err: IntegrityError, line: &str, integrity: &Integrity, line_num: usize) -> IntegrityError {
+    let line = line.trim_end_matches('\n');
+    let hash_kind = line.rfind(' ').and_then(|data_index| text_hash_kind(&line[data_index + 1..]));
+    if line_num != 1 || hash_kind == Some(integrity.kind()) {
+        return err;
+    }
+    match text_line_integrity(line) {
+        Some(found) => IntegrityError::FileHasUnexpectedHashSuffix { line_num, found },
+        None => IntegrityError::FileMissingHashes { line_num },
+    }
+}
+
+/// Returns metadata and data of the record if line data starts with "met ", otherwise line data without metadata.
+pub(crate) fn split_text_record_meta(line_data: &str, line_num: usize) -> Result<(Option<RawMeta<'_>>, &str), LoadFileError> {
+    let data = match line_data.strip_prefix("met ") {
+        Some(data) => data,
+        None => return Ok((None, line_data)),
+    };
+
+    // end of JSON of metadata is found by parsing, JSON can contain spaces in strings
+    let mut stream = serde_json::Deserializer::from_str(data).into_iter::<serde::de::IgnoredAny>();
+    match stream.next() {
+        Some(Ok(_)) => {},
+        Some(Err(err)) => return Err(LoadFileError::DeserializeJsonError { err, line_num }),
+        None => return Err(LoadFileError::NoLineDefinition { line_num }),
+    }
+    let meta_len = stream.byte_offset();
+
+    match data[meta_len..].strip_prefix(' ') {
+        Some(record_data) if record_data.len() >= 4 => Ok((Some(RawMeta::Json(&data[..meta_len])), record_data)),
+        _ => Err(LoadFileError::NoLineDefinition { line_num }),
+    }
+}
+
+/// Returns kind of the record and its key for 'records_in_range', key is None for records without one key.
+pub(crate) fn text_record_kind_and_key<Key>(line_data: &str, line_num: usize) -> Result<(RecordKind, Option<Key>), LoadFileError>
+where
+    Key: DeserializeOwned,
+{
+    let json_error = |err| LoadFileError::DeserializeJsonError { err, line_num };
+    let kind = text_record_kind(line_data).ok_or_else(|| unknown_text_operation(line_data, line_num))?;
+    let key = match kind {
+        RecordKind::Batch | RecordKind::Schema | RecordKind::TransactionBegin | RecordKind::TransactionEnd | RecordKind::TransactionAbort => return Ok((kind, None)),
+        RecordKind::Insert => {
+            let (_, data) = split_insert_version(line_data).ok_or(LoadFileError::NoLineDefinition { line_num })?;
+            serde_json::from_str::<(Key, IgnoredAny)>(data).map_err(json_error)?.0
+        },
+        RecordKind::PushItems | RecordKind::RemoveItems => serde_json::from_str::<(Key, IgnoredAny)>(&line_data[4..]).map_err(json_error)?.0,
+        RecordKind::Increment => {
+            // key can contain spaces, delta is after the last one
+            let data = line_data[4..].trim_end();
+            let space_index = data.rfind(' ').ok_or(LoadFileError::NoLineDefinition { line_num })?;
+            serde_json::from_str(&data[..space_index]).map_err(json_error)?
+        },
+        _ => serde_json::from_str(&line_data[4..]).map_err(json_error)?,
+    };
+    Ok((kind, Some(key)))
+}
+
+/// Names of operations of the text format accepted when loading in addition to the canonical names, see 'Cfg::extra_text_ops'.
+/// Line with alias is passed to loading functions with the canonical name, so they dispatch only canonical names.
+#[derive(Clone, Default)]
+pub(crate) struct TextOps {
+    /// Aliases with kind of record, checked before the canonical names.
+    aliases: Vec<(String, RecordKind)>,
+}
+
+impl TextOps {
+    /// Aliases of config.
+    pub fn of(cfg: &Cfg) -> Self {
+        TextOps { aliases: cfg.extra_text_ops.clone() }
+    }
+
+    /// Returns true if there are aliases.
+    pub fn has_aliases(&self) -> bool {
+        !self.aliases.is_empty()
+    }
+
+    /// Returns line data with the canonical name of operation if it starts with alias, otherwise None.
+    pub fn canonical(&self, line_data: &str) -> Option<String> {
+        if self.aliases.is_empty() {
+            return None;
+        }
+
+        let (name, args) = match line_data.split_once(' ') {
+            Some((name, args)) => (name, Some(args)),
+            None => (line_data.trim_end(), None),
+        };
+        let kind = self.aliases.iter().find(|(alias, _)| alias == name)?.1;
+        let canonical_name = text_op_name(kind);
+        Some(match args {
+            Some(args) => format!("{} {}", canonical_name, args),
+            None => canonical_name.to_string(),
+        })
+    }
+}
+
+/// Canonical name of operation of the record kind, it's always used for writing.
+pub(crate) fn text_op_name(kind: RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Insert => "ins",
+        RecordKind::Remove => "rem",
+        RecordKind::Batch => "bat",
+        RecordKind::TransactionBegin => "txb",
+        RecordKind::TransactionEnd => "txe",
+        RecordKind::TransactionAbort => "txa",
+        RecordKind::PushItems => "psh",
+        RecordKind::RemoveItems => "rmi",
+        RecordKind::SetAdd => "add",
+        RecordKind::SetDelete => "del",
+        RecordKind::Increment => "inc",
+        RecordKind::Schema => "sch",
+    }
+}
+
+/// Error of line with unknown name of operation, the name is the first word of the line.
+fn unknown_text_operation(line_data: &str, line_num: usize) -> LoadFileError {
+    let name = line_data.split(' ').next().unwrap_or_default().trim_end();
+    LoadFileError::UnknownOperation { name: name.to_string(), line_num }
+}
+
+/// Returns kind of the record by beginning of line data, None for unknown line.
+pub(crate) fn text_record_kind(line_data: &str) -> Option<RecordKind> {
+    if let Some(marker) = text_transaction_marker(line_data) {
+        return Some(RecordKind::from(marker));
+    }
+
+    match line_data.get(..4)? {
+        "ins " | "insV" => Some(RecordKind::Insert),
+        "rem " => Some(RecordKind::Remove),
+        "psh " => Some(RecordKind::PushItems),
+        "rmi " => Some(RecordKind::RemoveItems),
+        "add " => Some(RecordKind::SetAdd),
+        "del " => Some(RecordKind::SetDelete),
+        "inc " => Some(RecordKind::Increment),
+        "bat " => Some(RecordKind::Batch),
+        "sch " => Some(RecordKind::Schema),
+        _ => None,
+    }
+}
+
+/// Returns transaction marker if line data is marker.
+fn text_transaction_marker(line_data: &str) -> Option<TransactionMarker> {
+    match line_data.trim_end() {
+        "txb" => Some(TransactionMarker::Begin),
+        "txe" => Some(TransactionMarker::End),
+        "txa" => Some(TransactionMarker::Abort),
+        _ => None,
+    }
+}
+
+/// Deserialize operation of batch line with name of operation and its arguments.
+fn text_batch_operation<Key, Value>(name: &str, args: serde_json::Value, line_num: usize, value_schema_version: Option<u32>, value_migrator: &mut Option<&mut ValueMigrator>)
+    -> Result<MapOperation<Key, Value>, LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+{
+    if name == "rem" {
+        let key = serde_json::from_value(args).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+        return Ok(MapOperation::Remove(key));
+    }
+
+    let record_version = match name.strip_prefix("ins") {
+        Some("") => None,
+        Some(versioned) => Some(versioned.strip_prefix('V').and_then(|version| version.parse().ok()).ok_or(LoadFileError::WrongBatch { line_num })?),
+        None => return Err(LoadFileError::WrongBatch { line_num }),
+    };
+
+    text_insert_operation(args, record_version, line_num, value_schema_version, value_migrator)
+}
+
+/// Deserialize insert operation from JSON array [key,value] with decompression and migration of the value.
+fn text_insert_operation<Key, Value>(mut args: serde_json::Value, record_version: Option<u32>, line_num: usize, value_schema_version: Option<u32>, value_migrator: &mut Option<&mut ValueMigrator>)
+    -> Result<MapOperation<Key, Value>, LoadFileError>
+where
+    Key: DeserializeOwned,
+    Value: DeserializeOwned,
+{
+    if let Some(value) = args.get_mut(1) {
+        decompress_text_value(value).map_err(|err| LoadFileError::ValueDecompressionError { err, line_num })?;
+    }
+
+    let (key, val) = match (version_for_migration(record_version, value_schema_version), value_migrator) {
+        (Some(record_version), Some(migrator)) => {
+            let (key, raw_val) = serde_json::from_value::<(Key, serde_json::Value)>(args).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?;
+            let val = match migrator(record_version, RawValue::Json(raw_val)).map_err(|err| LoadFileError::MigrationError { err, line_num })? {
+                RawValue::Json(val) => serde_json::from_value(val).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?,
+                RawValue::Bin(_) => return Err(LoadFileError::MigrationError { err: MigrationError("binary value returned for the text format".to_string()), line_num }),
+            };
+            (key, val)
+        },
+        _ => serde_json::from_value(args).map_err(|err| LoadFileError::DeserializeJsonError { err, line_num })?,
+    };
+
+    Ok(MapOperation::Insert(key, val))
+}
+
+/// Returns JSON of value of insert line data, None for other lines.
+pub(crate) fn text_insert_value(line_data: &str) -> Option<serde_json::Value> {
+    let (_, data) = split_insert_version(line_data)?;
+    let (_, value) = serde_json::from_str::<(IgnoredAny, serde_json::Value)>(data).ok()?;
+    Some(value)
+}
+
+/// Returns version of schema of the value and data of the insert line that starts with "ins " or "insV{version} ".
+fn split_insert_version(line_data: &str) -> Option<(Option<u32>, &str)> {
+    if let Some(data) = line_data.strip_prefix("ins ") {
+        return Some((None, data));
+    }
+
+    let versioned = line_data.strip_prefix("insV")?;
+    let space_index = versioned.find(' ')?;
+    let version = versioned[..space_index].parse().ok()?;
+    Some((Some(version), &versioned[space_index + 1..]))
 }
 
 /// Check data integrity after read from file.
@@ -129,6 +890,12 @@ pub fn process_line_integrity<'a>(line: &'a str, integrity: &mut Integrity, line
                 return Err(IntegrityError::Crc32Error { line_num });
             }
         },
+        Integrity::XxHash64 => {
+            let hash = xxh64(line_data.as_bytes(), 0);
+            if format!("{:016x}", hash) != hash_in_file {
+                return Err(IntegrityError::XxHash64Error { line_num });
+            }
+        },
         Integrity::Sha1Chain(hash_of_prev) => {
             let mut current_hash: [u8; 20]  = [0; 20];
             blockchain_sha1(&hash_of_prev[..], line_data.as_bytes(), &mut current_hash);
@@ -145,6 +912,25 @@ pub fn process_line_integrity<'a>(line: &'a str, integrity: &mut Integrity, line
             }
             *hash_of_prev = current_hash;
         },
+        Integrity::HmacSha256Chain { key, prev } => {
+            let mut current_tag: [u8; 32] = [0; 32];
+            blockchain_hmac_sha256(key, &prev[..], line_data.as_bytes(), &mut current_tag);
+            // tag is compared in constant time, so it can't be guessed by time of check
+            let tag_is_correct = hex::decode(hash_in_file).is_ok_and(|tag_in_file| fixed_time_eq(&tag_in_file, &current_tag));
+            if !tag_is_correct {
+                return Err(IntegrityError::HmacError { line_num });
+            }
+            *prev = current_tag;
+        },
+        #[cfg(feature = "blake3")]
+        Integrity::Blake3Chain(hash_of_prev) => {
+            let mut current_hash: [u8; 32]  = [0; 32];
+            blockchain_blake3(&hash_of_prev[..], line_data.as_bytes(), &mut current_hash);
+            if hex::encode(current_hash) != hash_in_file {
+                return Err(IntegrityError::Blake3ChainError { line_num });
+            }
+            *hash_of_prev = current_hash;
+        },
     }
 
     Ok(line_data)
@@ -158,6 +944,10 @@ pub fn post_process_text_file_line(line: &mut String, integrity: &mut Option<Int
                 let crc = crc32::checksum_ieee(line.as_bytes());
                 *line += &format!(" {}", crc);
             },
+            Integrity::XxHash64 => {
+                let hash = xxh64(line.as_bytes(), 0);
+                *line += &format!(" {:016x}", hash);
+            },
             Integrity::Sha1Chain(prev_hash) => {
                 let mut hash: [u8; 20] = [0; 20];
                 blockchain_sha1(&prev_hash[..], line.as_bytes(), &mut hash);
@@ -170,6 +960,19 @@ pub fn post_process_text_file_line(line: &mut String, integrity: &mut Option<Int
                 *line += &format!(" {}", hex::encode(&hash[..]));
                 *prev_hash = hash;
             },
+            Integrity::HmacSha256Chain { key, prev } => {
+                let mut tag: [u8; 32] = [0; 32];
+                blockchain_hmac_sha256(key, &prev[..], line.as_bytes(), &mut tag);
+                *line += &format!(" {}", hex::encode(&tag[..]));
+                *prev = tag;
+            },
+            #[cfg(feature = "blake3")]
+            Integrity::Blake3Chain(prev_hash) => {
+                let mut hash: [u8; 32] = [0; 32];
+                blockchain_blake3(&prev_hash[..], line.as_bytes(), &mut hash);
+                *line += &format!(" {}", hex::encode(&hash[..]));
+                *prev_hash = hash;
+            },
         }
     }
 
@@ -0,0 +1,148 @@
+use crate::format::{check_write, file_record_of_insert, file_record_of_remove, file_record_of_transaction_marker, TransactionMarker};
+use crate::cfg::WriteOperation;
+use crate::map_trait::MapTrait;
+use crate::map_with_file::{prepare_index_insert, MapWithFile, SerializedError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Transaction of file based map, see 'MapWithFile::transaction'.
+/// Changes are visible only inside the transaction until 'commit'.
+/// Dropping without 'commit' discards the changes.
+pub struct Txn<'a, Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Map of the transaction.
+    map: &'a mut MapWithFile<Key, Value, Map>,
+    /// Changed keys, None if key is removed.
+    overlay: std::collections::BTreeMap<Key, Option<Value>>,
+}
+
+impl<Key, Value: 'static, Map> MapWithFile<Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Start transaction for several changes that will be written to the file at once and
+    /// restored after crash all or none of them.
+    /// In the file the records of transaction are between "txb" and "txe" lines (codes 3 and 4 of binary format),
+    /// records after "txb" without "txe" at the end of file are discarded when loading.
+    pub fn transaction(&mut self) -> Txn<'_, Key, Value, Map> {
+        Txn { map: self, overlay: std::collections::BTreeMap::new() }
+    }
+}
+
+impl<'a, Key, Value: 'static, Map> Txn<'a, Key, Value, Map>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone,
+    Map: MapTrait<Key, Value> + Default {
+
+    /// Returns a reference to the value corresponding to the key with changes of this transaction.
+    /// The key is canonicalized by canonicalizer of the map, see 'MapWithFile::set_key_canonicalizer'.
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.get_canonical(&self.map.canonical_key(key))
+    }
+
+    /// Inserts a key-value pair in the transaction, returns clone of the previous value.
+    /// The key is canonicalized by canonicalizer of the map.
+    pub fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        let key = self.map.canonical_key(&key).into_owned();
+        let old_value = self.get_canonical(&key).cloned();
+        self.overlay.insert(key, Some(value));
+        old_value
+    }
+
+    /// Remove value by key in the transaction, returns clone of the removed value.
+    /// The key is canonicalized by canonicalizer of the map.
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        let key = self.map.canonical_key(key).into_owned();
+        let old_value = self.get_canonical(&key).cloned();
+        if old_value.is_some() {
+            self.overlay.insert(key, None);
+        }
+        old_value
+    }
+
+    /// Write all changes of the transaction to the file by one write and apply them to the map and indexes.
+    /// On serialization error or panic of make index key callback nothing is written and the map is not changed.
+    pub fn commit(self) -> Result<(), SerializedError> {
+        let Txn { map, overlay } = self;
+
+        // records of the changes, integrity state is restored if serialization fails
+        let integrity = map.cfg.get_mut().integrity.clone();
+        let mut changes = Vec::with_capacity(overlay.len());
+        let mut index_updates = Vec::with_capacity(overlay.len());
+        let mut records = file_record_of_transaction_marker(TransactionMarker::Begin, map.cfg.get_mut())?;
+        for (key, value) in overlay {
+            let record = match &value {
+                Some(value) if map.cfg.get_mut().skip_identical_inserts && map.is_identical(&key, value) => continue,
+                Some(value) => file_record_of_insert(&key, value, map.cfg.get_mut()),
+                None if map.map.get(&key).is_none() => continue,
+                None => file_record_of_remove(&key, map.cfg.get_mut()),
+            };
+
+            // index keys are made before any change, so panic of make index key callback changes nothing
+            let updates = match &value {
+                Some(value) => prepare_index_insert(&map.indexes, &key, value, map.map.get(&key)),
+                None => Ok(Vec::new()),
+            };
+
+            match record.and_then(|record| updates.map(|updates| (record, updates))) {
+                Ok((record, updates)) => {
+                    records.extend_from_slice(&record);
+                    index_updates.push(updates);
+                },
+                Err(err) => {
+                    map.cfg.get_mut().integrity = integrity;
+                    return Err(err);
+                },
+            }
+
+            changes.push((key, value));
+        }
+
+        if changes.is_empty() {
+            map.cfg.get_mut().integrity = integrity;
+            return Ok(());
+        }
+
+        match file_record_of_transaction_marker(TransactionMarker::End, map.cfg.get_mut()) {
+            Ok(record) => records.extend_from_slice(&record),
+            Err(err) => {
+                map.cfg.get_mut().integrity = integrity;
+                return Err(err);
+            },
+        }
+        check_write(map.file_worker.write_bytes(records, WriteOperation::Transaction), &mut map.cfg.get_mut().integrity, integrity)?;
+        map.operations_since_open += changes.len() as u64;
+
+        for ((key, value), updates) in changes.into_iter().zip(index_updates) {
+            match value {
+                Some(value) => {
+                    let old_value = map.map.insert(key.clone(), value.clone());
+                    updates.into_iter().for_each(|update| update());
+                    map.notify_when_insert(&key, &value, &old_value);
+                },
+                None => {
+                    if let Some(old_value) = map.map.remove(&key) {
+                        map.update_index_when_remove(&key, &old_value);
+                    }
+                },
+            }
+        }
+
+        map.after_write();
+        Ok(())
+    }
+
+    /// Discard all changes of the transaction, same as drop.
+    pub fn rollback(self) {}
+
+    /// Returns value of already canonicalized key with changes of this transaction.
+    fn get_canonical(&self, key: &Key) -> Option<&Value> {
+        match self.overlay.get(key) {
+            Some(value) => value.as_ref(),
+            None => self.map.map.get(key),
+        }
+    }
+}
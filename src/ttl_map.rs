@@ -0,0 +1,155 @@
+use crate::cfg::Cfg;
+use crate::clock::Clock;
+use crate::format::MapOperation;
+use crate::map_with_file::{MapWithFile, SerializedError};
+use crate::LoadFileError;
+use serde::de::{Deserialize, DeserializeOwned, Deserializer};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Value of 'TtlMap' with time of expiry.
+/// Serialized as pair of expiry time in milliseconds since unix epoch and value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expiring<Value> {
+    /// Time of expiry in milliseconds since unix epoch.
+    pub expires_at: u64,
+    /// Value.
+    pub value: Value,
+}
+
+impl<Value> Expiring<Value> {
+    /// Returns true if the value is expired at time 'now'.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at <= unix_millis(now)
+    }
+}
+
+impl<Value: Serialize> Serialize for Expiring<Value> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.expires_at, &self.value).serialize(serializer)
+    }
+}
+
+impl<'de, Value: Deserialize<'de>> Deserialize<'de> for Expiring<Value> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (expires_at, value) = <(u64, Value)>::deserialize(deserializer)?;
+        Ok(Expiring { expires_at, value })
+    }
+}
+
+/// File based map where entries expire after time to live.
+/// Expired entries are absent for 'get' but stay in the map and in the file until 'purge_expired'
+/// writes their removes. Time of expiry is stored in the insert records, so entries expired
+/// at the moment of loading are not restored.
+pub struct TtlMap<Key, Value>
+where Key: Ord {
+    /// Wrapped map with values with time of expiry.
+    inner: MapWithFile<Key, Expiring<Value>, BTreeMap<Key, Expiring<Value>>>,
+    /// Time to live of inserted values.
+    ttl: Duration,
+    /// Source of current time, 'Cfg::clock' of the map.
+    clock: Arc<dyn Clock>,
+}
+
+impl<Key, Value: 'static> TtlMap<Key, Value>
+where
+    Key: Serialize + DeserializeOwned + Ord + Clone + 'static,
+    Value: Serialize + DeserializeOwned + Clone {
+
+    /// Open/create file and loads the entire history of changes, same as 'MapWithFile::open_or_create'.
+    /// Values inserted by 'insert' live 'ttl', current time is taken from 'Cfg::clock'.
+    pub fn open_or_create(file_path: impl AsRef<Path>, cfg: Cfg, ttl: Duration) -> Result<Self, LoadFileError> {
+        let clock = cfg.clock.clone();
+        let now = clock.now();
+        let inner = MapWithFile::open_with(file_path.as_ref(), cfg, |map: &mut BTreeMap<Key, Expiring<Value>>, map_operation| {
+            match map_operation {
+                MapOperation::Insert(key, value) => {
+                    // previous value is replaced even by expired one
+                    if value.is_expired(now) {
+                        map.remove(&key);
+                    } else {
+                        map.insert(key, value);
+                    }
+                },
+                MapOperation::Remove(key) => { map.remove(&key); },
+            }
+        })?;
+
+        Ok(TtlMap { inner, ttl, clock })
+    }
+
+    /// Same as 'open_or_create' but current time is taken from 'clock' instead of 'Cfg::clock'.
+    pub fn open_or_create_with_clock(file_path: impl AsRef<Path>, mut cfg: Cfg, ttl: Duration, clock: impl Clock + 'static) -> Result<Self, LoadFileError> {
+        cfg.clock = Arc::new(clock);
+        Self::open_or_create(file_path, cfg, ttl)
+    }
+
+    /// Inserts a key-value pair that expires after time to live of the map.
+    /// Returns previous value if it's not expired.
+    pub fn insert(&mut self, key: Key, value: Value) -> Result<Option<Value>, SerializedError> {
+        self.insert_with_ttl(key, value, self.ttl)
+    }
+
+    /// Inserts a key-value pair that expires after 'ttl'.
+    /// Returns previous value if it's not expired.
+    pub fn insert_with_ttl(&mut self, key: Key, value: Value, ttl: Duration) -> Result<Option<Value>, SerializedError> {
+        let now = self.clock.now();
+        let expires_at = unix_millis(now).saturating_add(ttl.as_millis() as u64);
+        let old_value = self.inner.insert(key, Expiring { expires_at, value })?;
+        Ok(old_value.filter(|old_value| !old_value.is_expired(now)).map(|old_value| old_value.value))
+    }
+
+    /// Returns a reference to the value corresponding to the key, None if the value is expired.
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        let now = self.clock.now();
+        self.inner.get(key)
+            .filter(|value| !value.is_expired(now))
+            .map(|value| &value.value)
+    }
+
+    /// Remove value by key. Returns removed value if it's not expired.
+    pub fn remove(&mut self, key: &Key) -> Result<Option<Value>, SerializedError> {
+        let now = self.clock.now();
+        let old_value = self.inner.remove(key)?;
+        Ok(old_value.filter(|old_value| !old_value.is_expired(now)).map(|old_value| old_value.value))
+    }
+
+    /// Remove all values expired at time 'now' with writing of their removes to the file by one record.
+    /// Returns count of removed values.
+    pub fn purge_expired(&mut self, now: SystemTime) -> Result<usize, SerializedError> {
+        let expired = self.inner.find_keys(|_, value| value.is_expired(now));
+        let count = expired.len();
+        self.inner.apply_batch(expired.into_iter().map(MapOperation::Remove).collect())?;
+        Ok(count)
+    }
+
+    /// Returns the number of entries in the map including expired but not purged.
+    pub fn len(&self) -> usize {
+        self.inner.map.len()
+    }
+
+    /// Returns true if the map contains no entries including expired but not purged.
+    pub fn is_empty(&self) -> bool {
+        self.inner.map.is_empty()
+    }
+
+    /// Returns reference to the wrapped file based map.
+    pub fn inner(&self) -> &MapWithFile<Key, Expiring<Value>, BTreeMap<Key, Expiring<Value>>> {
+        &self.inner
+    }
+
+    /// Waits until all changes made before are written to the file.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Milliseconds since unix epoch, 0 for time before epoch.
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,63 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+
+/// Name of the only field of JSON object that replaces compressed value in the text format.
+pub(crate) const COMPRESSED_VALUE_MARKER: &str = "__dkmz";
+
+/// Level of zstd compression of values.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// JSON of value for record of the text format. If JSON is longer than 'compress_values_over',
+/// then it's replaced with object {"__dkmz":"<base64 of zstd of JSON>"}.
+pub(crate) fn text_value_json<Value>(value: &Value, compress_values_over: Option<usize>) -> Result<String, serde_json::Error>
+where Value: Serialize {
+    let json = serde_json::to_string(value)?;
+    match compress_values_over {
+        Some(max_len) if json.len() > max_len => Ok(format!("{{\"{}\":\"{}\"}}", COMPRESSED_VALUE_MARKER, BASE64.encode(compress(json.as_bytes())))),
+        _ => Ok(json),
+    }
+}
+
+/// Returns true if record data of the text format can contain compressed value.
+pub(crate) fn text_may_contain_compressed_value(data: &str) -> bool {
+    data.contains(COMPRESSED_VALUE_MARKER)
+}
+
+/// Replace value compressed by 'text_value_json' with original value, other values are not changed.
+pub(crate) fn decompress_text_value(value: &mut serde_json::Value) -> Result<(), std::io::Error> {
+    let compressed = match value {
+        serde_json::Value::Object(object) if object.len() == 1 => match object.get(COMPRESSED_VALUE_MARKER) {
+            Some(serde_json::Value::String(compressed)) => compressed,
+            _ => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+
+    let compressed = BASE64.decode(compressed)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    *value = serde_json::from_slice(&decompress(&compressed)?)?;
+    Ok(())
+}
+
+/// Data of value for record of the binary format and flag of compression.
+/// If serialized value is longer than 'compress_values_over', then it's compressed by zstd.
+pub(crate) fn bin_value_data<Value>(value: &Value, compress_values_over: Option<usize>) -> Result<(Vec<u8>, bool), bincode2::Error>
+where Value: Serialize {
+    let data = bincode2::serialize(value)?;
+    match compress_values_over {
+        Some(max_len) if data.len() > max_len => Ok((compress(&data), true)),
+        _ => Ok((data, false)),
+    }
+}
+
+/// Compress data by zstd.
+fn compress(data: &[u8]) -> Vec<u8> {
+    zstd::encode_all(data, COMPRESSION_LEVEL)
+        .unwrap_or_else(|err| unreachable!(err)) // unreachable because reading from slice and writing to vec don't fail
+}
+
+/// Decompress data compressed by 'compress'.
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::decode_all(data)
+}
@@ -0,0 +1,159 @@
+use crate::bin_format::{bin_block_integrity, process_block_integrity, read_bin_block_len, CountingReader};
+use crate::cfg::{Format, FormatKind, Integrity, IntegrityKind};
+use crate::map_trait::MapTrait;
+use crate::map_with_file::MapWithFile;
+use crate::text_format::{process_line_integrity, text_line_integrity};
+use crate::LoadFileError;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+/// Error found by background verification of the file.
+#[derive(Debug)]
+pub struct VerifyError {
+    /// Offset in the file of the record with error.
+    pub offset: u64,
+    /// Error of the record.
+    pub error: LoadFileError,
+}
+
+impl std::error::Error for VerifyError {}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Handle of background verification started by 'MapWithFile::start_background_verify'.
+/// Verification thread is stopped when the handle is dropped.
+pub struct VerifyHandle {
+    stop_sender: Option<Sender<()>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl<Key, Value, Map> MapWithFile<Key, Value, Map>
+where Map: MapTrait<Key, Value> {
+    /// Start thread that re-reads the file every 'interval' and checks integrity of records
+    /// for detection of disk corruption while the map is open.
+    /// The file is read by separate read-only handle up to the end of data written by the map at the moment of check.
+    /// Errors are passed to 'error_callback' with offset of the record, check is repeated after next interval.
+    /// Records are checked as stored in the file, so it can't be used with the format callbacks that transform records.
+    pub fn start_background_verify(&self, interval: Duration, mut error_callback: impl FnMut(VerifyError) + Send + 'static) -> VerifyHandle {
+        let file_path = self.file_path.clone();
+        let text = matches!(self.cfg.lock().format, Format::Text(..));
+        let integrity_at_file_start = self.integrity_at_file_start.clone();
+        let counters = self.file_worker.shared_counters();
+
+        let (stop_sender, stop_receiver) = channel();
+        let join_handle = spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                // chained integrity is computed from the start of the file on each check
+                let len = counters.file_len.load(Ordering::Acquire);
+                if let Err(err) = verify_file_records(&file_path, text, integrity_at_file_start.clone(), len) {
+                    error_callback(err);
+                }
+            }
+        });
+
+        VerifyHandle { stop_sender: Some(stop_sender), join_handle: Some(join_handle) }
+    }
+}
+
+impl Drop for VerifyHandle {
+    fn drop(&mut self) {
+        // thread stops when channel is disconnected
+        self.stop_sender.take();
+        self.join_handle.take().map(JoinHandle::join);
+    }
+}
+
+/// Returns kind of integrity of the file by its first record, for check of 'Cfg::integrity' before opening.
+/// None if the file is empty or the first record has no checksum or hash. In the binary format only checksums
+/// of 'Integrity::Crc32' and 'Integrity::XxHash64' are detected, hashes of chain can't be told apart from data.
+/// Records are read as stored in the file, so it can't be used with the format callbacks that transform records.
+pub fn detect_integrity(file_path: impl AsRef<Path>, format: FormatKind) -> Result<Option<IntegrityKind>, LoadFileError> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    match format {
+        FormatKind::Text => {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            Ok(text_line_integrity(&line))
+        },
+        FormatKind::Bin => {
+            let block_len = read_bin_block_len(&mut reader)?;
+            let mut data_block = vec![0; block_len];
+            reader.read_exact(&mut data_block)?;
+            Ok(bin_block_integrity(&data_block))
+        },
+    }
+}
+
+/// Check integrity of records of the file up to 'len'. 'integrity' is state before the first record.
+fn verify_file_records(file_path: &Path, text: bool, mut integrity: Option<Integrity>, len: u64) -> Result<(), VerifyError> {
+    let file = File::open(file_path)
+        .map_err(|err| VerifyError { offset: 0, error: LoadFileError::FileError(err) })?;
+    let reader = BufReader::new(file.take(len));
+
+    if text {
+        verify_text_records(reader, &mut integrity)
+    } else {
+        verify_bin_records(reader, &mut integrity)
+    }
+}
+
+/// Check integrity of lines of the text format.
+fn verify_text_records(mut reader: impl BufRead, integrity: &mut Option<Integrity>) -> Result<(), VerifyError> {
+    let mut line = String::new();
+    let mut line_num = 1;
+    let mut offset = 0;
+    loop {
+        line.clear();
+        let line_len = reader.read_line(&mut line)
+            .map_err(|err| VerifyError { offset, error: LoadFileError::FileError(err) })?;
+        if line_len == 0 {
+            return Ok(());
+        }
+
+        if !line.ends_with('\n') {
+            return Err(VerifyError { offset, error: LoadFileError::LastLineWithoutEndLine { line_num } });
+        }
+
+        if let Some(integrity) = integrity {
+            process_line_integrity(&line, integrity, line_num)
+                .map_err(|err| VerifyError { offset, error: LoadFileError::IntegrityError(err) })?;
+        }
+
+        offset += line_len as u64;
+        line_num += 1;
+    }
+}
+
+/// Check integrity of blocks of the binary format.
+fn verify_bin_records(reader: impl Read, integrity: &mut Option<Integrity>) -> Result<(), VerifyError> {
+    let mut reader = CountingReader { reader, read_len: 0 };
+    let mut block_num = 1;
+    loop {
+        let offset = reader.read_len;
+        let block_len = read_bin_block_len(&mut reader)
+            .map_err(|error| VerifyError { offset, error })?;
+        if block_len == 0 {
+            return Ok(());
+        }
+
+        let mut data_block = vec![0; block_len];
+        reader.read_exact(&mut data_block)
+            .map_err(|err| VerifyError { offset, error: LoadFileError::FileError(err) })?;
+
+        if let Some(integrity) = integrity {
+            process_block_integrity(&mut data_block, integrity, block_num)
+                .map_err(|err| VerifyError { offset, error: LoadFileError::IntegrityError(err) })?;
+        }
+
+        block_num += 1;
+    }
+}